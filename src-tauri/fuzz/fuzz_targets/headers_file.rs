@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|content: &str| {
+    if let Err(err) = app_lib::parsers::parse_headers(content) {
+        let _ = err.to_string();
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (&str, Option<&str>, Option<&str>)| {
+    let (proxy, login, password) = input;
+    if let Err(err) = app_lib::parsers::parse_proxy_url(proxy, login, password) {
+        let _ = err.to_string();
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|spec: &str| {
+    for reverse in [false, true] {
+        if let Err(err) = app_lib::parsers::parse_tunnel_spec(spec, reverse) {
+            // Rendering the error must not panic either, spans included
+            let _ = err.to_string();
+        }
+    }
+});
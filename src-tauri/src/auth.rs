@@ -0,0 +1,213 @@
+use crate::client::profile::{HttpHeader, Profile};
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+/// Sent to the frontend so the user enters the code on the identity provider page
+pub const DEVICE_CODE_EVENT: &str = "oauth-device-code";
+const KEYCHAIN_SERVICE: &str = "wstunnel-desktop";
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// Polling interval when the identity provider does not tell one, as advised by RFC 8628
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Identity provider protecting the server, authenticated against with the OAuth2 device authorization flow (RFC 8628)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OAuthConfig {
+    pub device_authorization_url: String,
+    pub token_url: String,
+    pub client_id: String,
+    pub scope: Option<String>,
+}
+
+/// What the user must do to authorize the app
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceCodePrompt {
+    pub profile_id: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    /// Verification page with the code already filled in, when the identity provider supports it
+    pub verification_uri_complete: Option<String>,
+    pub expires_in_sec: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    interval: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Copy of the profile sending a bearer token in the upgrade request, when the profile requires OAuth2.
+/// A refresh token saved in the keychain is used first, the device flow only runs when there is none or it is rejected.
+pub async fn authorize(
+    profile: &Profile,
+    prompt: impl Fn(DeviceCodePrompt),
+) -> anyhow::Result<Profile> {
+    let Some(config) = &profile.oauth else {
+        return Ok(profile.clone());
+    };
+
+    let http = reqwest::Client::new();
+    let access_token = match refresh(&http, config, &profile.name).await {
+        Ok(Some(token)) => token,
+        Ok(None) => device_flow(&http, config, &profile.name, prompt).await?,
+        Err(err) => {
+            warn!(
+                "Cannot refresh token of profile {}, asking for authorization again: {:#}",
+                profile.name, err
+            );
+            device_flow(&http, config, &profile.name, prompt).await?
+        }
+    };
+
+    let mut profile = profile.clone();
+    profile
+        .http_headers
+        .retain(|h| !h.name.eq_ignore_ascii_case("authorization"));
+    profile.http_headers.push(HttpHeader {
+        name: "Authorization".to_string(),
+        value: format!("Bearer {}", access_token),
+    });
+    Ok(profile)
+}
+
+/// Forget the refresh token of a profile, the next connection asks for authorization again
+pub fn sign_out(profile_id: &str) -> anyhow::Result<()> {
+    match keychain_entry(profile_id)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn refresh(
+    http: &reqwest::Client,
+    config: &OAuthConfig,
+    profile_id: &str,
+) -> anyhow::Result<Option<String>> {
+    let refresh_token = match keychain_entry(profile_id)?.get_password() {
+        Ok(token) => token,
+        Err(keyring::Error::NoEntry) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let response = http
+        .post(&config.token_url)
+        .form(&[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token.as_str()),
+            ("client_id", config.client_id.as_str()),
+        ])
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let error: TokenError = response.json().await?;
+        return Err(token_error(error));
+    }
+
+    let token: TokenResponse = response.json().await?;
+    // Identity providers rotating refresh tokens invalidate the one just used
+    if let Some(refresh_token) = &token.refresh_token {
+        keychain_entry(profile_id)?.set_password(refresh_token)?;
+    }
+    Ok(Some(token.access_token))
+}
+
+async fn device_flow(
+    http: &reqwest::Client,
+    config: &OAuthConfig,
+    profile_id: &str,
+    prompt: impl Fn(DeviceCodePrompt),
+) -> anyhow::Result<String> {
+    let mut form = vec![("client_id", config.client_id.as_str())];
+    if let Some(scope) = &config.scope {
+        form.push(("scope", scope.as_str()));
+    }
+    let authorization: DeviceAuthorization = http
+        .post(&config.device_authorization_url)
+        .form(&form)
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| "Device authorization request rejected")?
+        .json()
+        .await?;
+
+    info!(
+        "Waiting for the user to authorize profile {} at {}",
+        profile_id, authorization.verification_uri
+    );
+    prompt(DeviceCodePrompt {
+        profile_id: profile_id.to_string(),
+        user_code: authorization.user_code.clone(),
+        verification_uri: authorization.verification_uri.clone(),
+        verification_uri_complete: authorization.verification_uri_complete.clone(),
+        expires_in_sec: authorization.expires_in,
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(authorization.expires_in);
+    let mut interval = authorization
+        .interval
+        .map_or(DEFAULT_POLL_INTERVAL, Duration::from_secs);
+    loop {
+        tokio::time::sleep(interval).await;
+        if Instant::now() > deadline {
+            return Err(anyhow!(
+                "Authorization code expired before the user entered it"
+            ));
+        }
+
+        let response = http
+            .post(&config.token_url)
+            .form(&[
+                ("grant_type", DEVICE_CODE_GRANT),
+                ("device_code", authorization.device_code.as_str()),
+                ("client_id", config.client_id.as_str()),
+            ])
+            .send()
+            .await?;
+        if response.status().is_success() {
+            let token: TokenResponse = response.json().await?;
+            if let Some(refresh_token) = &token.refresh_token {
+                keychain_entry(profile_id)?.set_password(refresh_token)?;
+            }
+            info!("Profile {} authorized", profile_id);
+            return Ok(token.access_token);
+        }
+
+        let error: TokenError = response.json().await?;
+        match error.error.as_str() {
+            "authorization_pending" => {}
+            "slow_down" => interval += Duration::from_secs(5),
+            _ => return Err(token_error(error)),
+        }
+    }
+}
+
+fn token_error(error: TokenError) -> anyhow::Error {
+    match error.error_description {
+        Some(description) => anyhow!("Authorization failed: {} ({})", error.error, description),
+        None => anyhow!("Authorization failed: {}", error.error),
+    }
+}
+
+fn keychain_entry(profile_id: &str) -> anyhow::Result<keyring::Entry> {
+    keyring::Entry::new(KEYCHAIN_SERVICE, profile_id).with_context(|| "Cannot access the keychain")
+}
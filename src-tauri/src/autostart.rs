@@ -0,0 +1,85 @@
+use crate::client::chain;
+use crate::client::manager::ClientManager;
+use crate::client::profile::Profile;
+use crate::commands;
+use crate::pac::PacServer;
+use crate::profile_store;
+use crate::system_proxy::SystemProxy;
+use log::{error, info};
+use serde::Serialize;
+use tauri::plugin::TauriPlugin;
+use tauri::{AppHandle, Emitter, Manager, Runtime};
+use tauri_plugin_autostart::MacosLauncher;
+
+/// Given to the app when launched by the OS at login
+pub const AUTOSTART_ARG: &str = "--autostart";
+pub const AUTOCONNECT_FAILED_EVENT: &str = "autoconnect-failed";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AutoconnectFailure {
+    pub profile_id: String,
+    pub error: String,
+}
+
+/// Register the app in the login items (macOS), the Run registry key (Windows) or XDG autostart (Linux)
+pub fn plugin<R: Runtime>() -> TauriPlugin<R> {
+    tauri_plugin_autostart::init(MacosLauncher::LaunchAgent, Some(vec![AUTOSTART_ARG]))
+}
+
+pub fn launched_at_login() -> bool {
+    std::env::args().any(|arg| arg == AUTOSTART_ARG)
+}
+
+/// Connect every saved profile marked for autoconnect, reporting failures to the frontend
+pub fn connect_marked_profiles(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let profiles = match app
+            .path()
+            .app_data_dir()
+            .map_err(anyhow::Error::from)
+            .and_then(|dir| profile_store::load_profiles(&dir))
+        {
+            Ok(profiles) => profiles,
+            Err(err) => {
+                error!("Cannot load profiles to autoconnect: {:?}", err);
+                return;
+            }
+        };
+
+        let marked = profiles.into_iter().filter(|p| p.autoconnect).collect();
+        connect_profiles(&app, marked).await;
+    });
+}
+
+/// Connect the profiles one after the other, reporting failures to the frontend without stopping at the first one
+pub async fn connect_profiles(app: &AppHandle, profiles: Vec<Profile>) {
+    // A profile going through another one is connected after it
+    let profiles = match chain::startup_order(profiles) {
+        Ok(profiles) => profiles,
+        Err(err) => {
+            error!("Cannot order profiles to autoconnect: {:?}", err);
+            return;
+        }
+    };
+    for profile in profiles {
+        info!("Autoconnecting profile {}", profile.name);
+        let profile_id = profile.name.clone();
+        let result = commands::connect(
+            profile,
+            app.clone(),
+            app.state::<ClientManager>(),
+            app.state::<SystemProxy>(),
+            app.state::<PacServer>(),
+        )
+        .await;
+
+        if let Err(error) = result {
+            error!("Cannot autoconnect profile {}: {}", profile_id, error);
+            let failure = AutoconnectFailure { profile_id, error };
+            if let Err(err) = app.emit(AUTOCONNECT_FAILED_EVENT, failure) {
+                error!("Cannot report autoconnect failure: {:?}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use crate::client::chain;
+use crate::client::manager::ClientManager;
+use crate::client::placeholders;
+use crate::client::profile::Profile;
+use crate::commands::{self, ConnectionInfo};
+use crate::profile_store::{PROFILE_STORE, PROFILE_STORE_KEY};
+use crate::relay::RelayProcesses;
+use anyhow::anyhow;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashSet;
+use tauri::{AppHandle, Manager};
+use tauri_plugin_store::StoreExt;
+
+/// Bulk operations run one at a time, so a rollback never undoes the changes of another one
+static BULK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// Outcome of a bulk operation, which either applied to every profile or left them all as they were
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkReport {
+    /// Whether every profile got the change, the ones already changed have been put back otherwise
+    pub committed: bool,
+    pub profiles: Vec<BulkProfileReport>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BulkProfileReport {
+    pub profile_id: String,
+    #[serde(flatten)]
+    pub outcome: BulkOutcome,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BulkOutcome {
+    Connected {
+        connection: ConnectionInfo,
+    },
+    Disconnected,
+    /// Saved in the profile store, without being connected
+    Saved,
+    /// The change failed for this profile, so the whole operation has been rolled back
+    Failed {
+        error: String,
+    },
+    /// Not attempted, as the change of a previous profile failed
+    Skipped,
+    /// Changed, then put back as it was
+    RolledBack,
+    /// Changed, but could not be put back as it was
+    RollbackFailed {
+        error: String,
+    },
+}
+
+/// A profile changed by a bulk operation, with how to put it back
+struct Change {
+    index: usize,
+    /// Configuration the profile was connected with, if it was
+    previous: Option<Profile>,
+}
+
+impl BulkReport {
+    fn new(profile_ids: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            committed: false,
+            profiles: profile_ids
+                .into_iter()
+                .map(|profile_id| BulkProfileReport {
+                    profile_id,
+                    outcome: BulkOutcome::Skipped,
+                })
+                .collect(),
+        }
+    }
+
+    fn set(&mut self, index: usize, outcome: BulkOutcome) {
+        self.profiles[index].outcome = outcome;
+    }
+
+    /// Put the changed profiles back as they were, the last changed first
+    async fn roll_back(mut self, app: &AppHandle, changes: Vec<Change>) -> Self {
+        for change in changes.into_iter().rev() {
+            let profile_id = self.profiles[change.index].profile_id.clone();
+            let outcome = match restore(app, &profile_id, change.previous).await {
+                Ok(()) => BulkOutcome::RolledBack,
+                Err(error) => {
+                    warn!("Cannot roll profile {} back: {}", profile_id, error);
+                    BulkOutcome::RollbackFailed { error }
+                }
+            };
+            self.set(change.index, outcome);
+        }
+        self
+    }
+
+    fn commit(mut self) -> Self {
+        self.committed = true;
+        self
+    }
+}
+
+/// Connect every profile, or none of them. Profiles already connected are connected again with the given configuration.
+/// The report lists the profiles in the order they are connected, the ones chained to another profile after it.
+pub async fn connect_many(app: &AppHandle, profiles: Vec<Profile>) -> anyhow::Result<BulkReport> {
+    let _guard = BULK.lock().await;
+    check_profiles(&profiles)?;
+    // A profile going through another one is connected after it
+    let profiles = chain::startup_order(profiles)?;
+    let mut report = BulkReport::new(profiles.iter().map(|p| p.name.clone()));
+    if let Some((index, error)) = invalid_profile(&profiles) {
+        report.set(index, BulkOutcome::Failed { error });
+        return Ok(report);
+    }
+
+    let mut changes = vec![];
+    for (index, profile) in profiles.into_iter().enumerate() {
+        let previous = connected_profile(app, &profile.name);
+        let result = connect(app, profile).await;
+        changes.push(Change { index, previous });
+        match result {
+            Ok(connection) => report.set(index, BulkOutcome::Connected { connection }),
+            Err(error) => {
+                // A failed connection may have dropped the previous one, so it is put back as well
+                let mut report = report.roll_back(app, changes).await;
+                report.set(index, BulkOutcome::Failed { error });
+                return Ok(report);
+            }
+        }
+    }
+    info!("Connected {} profiles", changes.len());
+    Ok(report.commit())
+}
+
+/// Disconnect every connected profile, isolated ones included, or none of them
+pub async fn disconnect_all(app: &AppHandle) -> anyhow::Result<BulkReport> {
+    let _guard = BULK.lock().await;
+    let connected: Vec<Profile> = app
+        .state::<ClientManager>()
+        .list()
+        .into_iter()
+        .map(|managed| managed.profile)
+        .chain(
+            app.state::<RelayProcesses>()
+                .list()
+                .into_iter()
+                .map(|status| status.profile),
+        )
+        .collect();
+    // A profile going through another one is disconnected before it
+    let mut connected = chain::startup_order(connected)?;
+    connected.reverse();
+    let mut report = BulkReport::new(connected.iter().map(|p| p.name.clone()));
+
+    let mut changes = vec![];
+    for (index, profile) in connected.into_iter().enumerate() {
+        let result = disconnect(app, &profile.name);
+        changes.push(Change {
+            index,
+            previous: Some(profile),
+        });
+        match result {
+            Ok(()) => report.set(index, BulkOutcome::Disconnected),
+            Err(error) => {
+                // The profile itself may be disconnected already, only restoring the system proxy failed
+                let mut report = report.roll_back(app, changes).await;
+                report.set(index, BulkOutcome::Failed { error });
+                return Ok(report);
+            }
+        }
+    }
+    info!("Disconnected {} profiles", changes.len());
+    Ok(report.commit())
+}
+
+/// Save the profiles of the bundle in the profile store, replacing the saved profiles of the same name,
+/// and connect again the ones currently connected. Either all of it applies, or the store and the
+/// connections are left as they were.
+pub async fn apply_profiles_bundle(
+    app: &AppHandle,
+    bundle: Vec<Profile>,
+) -> anyhow::Result<BulkReport> {
+    let _guard = BULK.lock().await;
+    check_profiles(&bundle)?;
+    let mut report = BulkReport::new(bundle.iter().map(|p| p.name.clone()));
+    if let Some((index, error)) = invalid_profile(&bundle) {
+        report.set(index, BulkOutcome::Failed { error });
+        return Ok(report);
+    }
+
+    let store = app.store(PROFILE_STORE)?;
+    let saved = store.get(PROFILE_STORE_KEY);
+    let mut profiles = match &saved {
+        Some(serde_json::Value::Array(profiles)) => profiles.clone(),
+        Some(_) => return Err(anyhow!("Invalid profiles in {}", PROFILE_STORE)),
+        None => vec![],
+    };
+    for profile in &bundle {
+        let value = serde_json::to_value(profile)?;
+        match profiles
+            .iter_mut()
+            .find(|entry| entry.get("name").and_then(|n| n.as_str()) == Some(profile.name.as_str()))
+        {
+            Some(saved) => *saved = value,
+            None => profiles.push(value),
+        }
+    }
+    store.set(PROFILE_STORE_KEY, serde_json::Value::Array(profiles));
+    store.save()?;
+
+    let mut changes = vec![];
+    for (index, profile) in bundle.into_iter().enumerate() {
+        let Some(previous) = connected_profile(app, &profile.name) else {
+            report.set(index, BulkOutcome::Saved);
+            continue;
+        };
+        let result = update(app, profile).await;
+        changes.push(Change {
+            index,
+            previous: Some(previous),
+        });
+        match result {
+            Ok(connection) => report.set(index, BulkOutcome::Connected { connection }),
+            Err(error) => {
+                let mut report = report.roll_back(app, changes).await;
+                report.set(index, BulkOutcome::Failed { error });
+                for entry in report.profiles.iter_mut() {
+                    if matches!(entry.outcome, BulkOutcome::Saved) {
+                        entry.outcome = BulkOutcome::RolledBack;
+                    }
+                }
+                match saved {
+                    Some(saved) => store.set(PROFILE_STORE_KEY, saved),
+                    None => {
+                        store.delete(PROFILE_STORE_KEY);
+                    }
+                }
+                if let Err(err) = store.save() {
+                    warn!("Cannot restore saved profiles: {:?}", err);
+                }
+                return Ok(report);
+            }
+        }
+    }
+    info!("Applied a bundle of {} profiles", report.profiles.len());
+    Ok(report.commit())
+}
+
+fn check_profiles(profiles: &[Profile]) -> anyhow::Result<()> {
+    let mut names = HashSet::new();
+    for profile in profiles {
+        if !names.insert(profile.name.as_str()) {
+            return Err(anyhow!("Profile {} is given more than once", profile.name));
+        }
+    }
+    Ok(())
+}
+
+/// Check every profile before changing anything, returning the first invalid one
+fn invalid_profile(profiles: &[Profile]) -> Option<(usize, String)> {
+    profiles.iter().enumerate().find_map(|(index, profile)| {
+        placeholders::expand(profile)
+            .and_then(|expanded| expanded.to_client())
+            .err()
+            .map(|err| (index, format!("{:?}", err)))
+    })
+}
+
+/// Configuration a profile is currently connected with, in the app or in its relay process
+fn connected_profile(app: &AppHandle, profile_id: &str) -> Option<Profile> {
+    app.state::<ClientManager>()
+        .get(profile_id)
+        .map(|managed| managed.profile)
+        .or_else(|| {
+            app.state::<RelayProcesses>()
+                .list()
+                .into_iter()
+                .find(|status| status.profile.name == profile_id)
+                .map(|status| status.profile)
+        })
+}
+
+/// Put a profile back to how it was: connected with its previous configuration, or disconnected
+async fn restore(
+    app: &AppHandle,
+    profile_id: &str,
+    previous: Option<Profile>,
+) -> Result<(), String> {
+    match previous {
+        Some(profile) => connect(app, profile).await.map(|_| ()),
+        None if connected_profile(app, profile_id).is_some() => disconnect(app, profile_id),
+        None => Ok(()),
+    }
+}
+
+async fn connect(app: &AppHandle, profile: Profile) -> Result<ConnectionInfo, String> {
+    commands::connect(profile, app.clone(), app.state(), app.state(), app.state()).await
+}
+
+async fn update(app: &AppHandle, profile: Profile) -> Result<ConnectionInfo, String> {
+    commands::update_profile(profile, app.clone(), app.state(), app.state(), app.state()).await
+}
+
+fn disconnect(app: &AppHandle, profile_id: &str) -> Result<(), String> {
+    commands::disconnect(
+        profile_id.to_string(),
+        app.state(),
+        app.state(),
+        app.state(),
+        app.state(),
+        app.state(),
+        app.state(),
+    )
+}
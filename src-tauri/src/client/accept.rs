@@ -0,0 +1,26 @@
+use std::time::Duration;
+
+const MIN_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Wait after a listener failed to accept a connection. Accepting fails again right away on errors such as
+/// running out of file descriptors, so a loop going on at once would spin on them
+#[derive(Debug, Default)]
+pub struct AcceptBackoff {
+    failures: u32,
+}
+
+impl AcceptBackoff {
+    /// Wait longer with each consecutive failure, up to a second
+    pub async fn failed(&mut self) {
+        let wait = MIN_BACKOFF
+            .saturating_mul(1 << self.failures.min(7))
+            .min(MAX_BACKOFF);
+        self.failures = self.failures.saturating_add(1);
+        tokio::time::sleep(wait).await;
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+}
@@ -0,0 +1,525 @@
+use crate::client::accept::AcceptBackoff;
+use crate::client::app_rules::{AppAction, AppGate};
+use crate::client::buffers::BufferTuning;
+use crate::client::listener_sockets::listener_sockets;
+use crate::client::proxy_protocol::ProxyHeader;
+use crate::client::relay;
+use crate::client::tasks::TaskGroup;
+use anyhow::{anyhow, Context};
+use futures_util::{stream, Stream};
+use ipnet::IpNet;
+use log::{debug, error, warn};
+use parking_lot::Mutex;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use url::Host;
+use wstunnel::tunnel::{LocalProtocol, RemoteAddr};
+
+/// Restrictions on where a local listener binds and who is allowed to connect to it
+#[derive(Clone, Debug, Default)]
+pub struct AccessPolicy {
+    /// Name of the network interface to bind to (i.e: eth0, en0). Its address replaces the ip of the tunnel local address
+    pub bind_interface: Option<String>,
+    /// Only accept connections whose source address is in one of these networks. Everything is accepted if empty
+    pub allowed_sources: Vec<IpNet>,
+    /// Listen on every ipv4 and ipv6 address with a single socket, ipv4 clients being seen as mapped ipv6 addresses
+    pub dual_stack: bool,
+}
+
+impl AccessPolicy {
+    pub fn is_allowed(&self, source: IpAddr) -> bool {
+        let source = match source {
+            IpAddr::V6(ip) => ip.to_canonical(),
+            ip => ip,
+        };
+        self.allowed_sources.is_empty()
+            || self.allowed_sources.iter().any(|net| net.contains(&source))
+    }
+
+    /// Replace the ip of `local` by the one of the configured interface, matching its address family when possible
+    pub fn bind_addr(&self, local: SocketAddr) -> anyhow::Result<SocketAddr> {
+        let Some(name) = &self.bind_interface else {
+            return Ok(local);
+        };
+
+        let ips: Vec<IpAddr> = if_addrs::get_if_addrs()
+            .with_context(|| "Cannot list network interfaces")?
+            .into_iter()
+            .filter(|iface| iface.name == *name)
+            .map(|iface| iface.ip())
+            .collect();
+
+        let ip = ips
+            .iter()
+            .find(|ip| ip.is_ipv4() == local.is_ipv4())
+            .or_else(|| ips.first())
+            .ok_or_else(|| anyhow!("No address found for network interface {}", name))?;
+
+        Ok(SocketAddr::new(*ip, local.port()))
+    }
+
+    /// Bind the publicly reachable side of a tcp based listener.
+    /// A gate is bound on the public address, filtering incoming connections before relaying them
+    /// to a loopback listener, which the tunnel listener must accept them from.
+    /// Owning the public socket lets it be handed to a newer version of the app during an upgrade,
+    /// a socket inherited that way is used instead of binding a new one.
+    /// A port 0 is resolved to a free port chosen by the OS.
+    /// With a proxy header, the gate writes it ahead of each connection, the source being the client it accepted.
+    pub async fn bind_tcp(
+        &self,
+        local: SocketAddr,
+        buffers: BufferTuning,
+        proxy_header: Option<ProxyHeader>,
+        tasks: &TaskGroup,
+    ) -> anyhow::Result<BoundAddr> {
+        self.bind_gate(local, None, buffers, proxy_header, tasks)
+            .await
+    }
+
+    /// Same as `bind_tcp`, the gate also relaying each connection according to the application that made it
+    pub async fn bind_tcp_routed(
+        &self,
+        local: SocketAddr,
+        apps: Option<AppGate>,
+        buffers: BufferTuning,
+        tasks: &TaskGroup,
+    ) -> anyhow::Result<BoundAddr> {
+        self.bind_gate(local, apps, buffers, None, tasks).await
+    }
+
+    async fn bind_gate(
+        &self,
+        local: SocketAddr,
+        apps: Option<AppGate>,
+        buffers: BufferTuning,
+        proxy_header: Option<ProxyHeader>,
+        tasks: &TaskGroup,
+    ) -> anyhow::Result<BoundAddr> {
+        let public_addr = if self.dual_stack {
+            SocketAddr::from((Ipv6Addr::UNSPECIFIED, local.port()))
+        } else {
+            self.bind_addr(local)?
+        };
+        let gate = match listener_sockets().take_inherited(public_addr) {
+            Some(socket) => {
+                debug!("Reusing inherited socket for {}", public_addr);
+                socket.set_nonblocking(true)?;
+                TcpListener::from_std(socket)?
+            }
+            None if self.dual_stack => bind_dual_stack(public_addr)
+                .with_context(|| format!("Cannot bind dual stack listener on {}", public_addr))?,
+            None => TcpListener::bind(public_addr)
+                .await
+                .with_context(|| format!("Cannot bind local listener on {}", public_addr))?,
+        };
+        buffers.apply_to_listener(&gate)?;
+        let public_addr = gate.local_addr()?;
+        let internal = bind_loopback(public_addr).await?;
+        let internal_addr = internal.local_addr()?;
+        let registration = listener_sockets().register(public_addr, &gate)?;
+
+        let policy = Arc::new(self.clone());
+        tasks.spawn(async move {
+            let _registration = registration;
+            let mut backoff = AcceptBackoff::default();
+            loop {
+                let (stream, peer) = match gate.accept().await {
+                    Ok(cnx) => {
+                        backoff.succeeded();
+                        cnx
+                    }
+                    Err(err) => {
+                        error!("Cannot accept connection on {}: {:?}", public_addr, err);
+                        backoff.failed().await;
+                        continue;
+                    }
+                };
+
+                if !policy.is_allowed(peer.ip()) {
+                    warn!(
+                        "Rejecting connection from {} on {}: source not allowed",
+                        peer, public_addr
+                    );
+                    continue;
+                }
+
+                let Some(apps) = apps.clone() else {
+                    let header = proxy_header.map(|header| header.encode(peer, public_addr));
+                    tokio::spawn(relay(stream, peer, internal_addr, buffers, header));
+                    continue;
+                };
+                tokio::spawn(async move {
+                    let target = match apps.action(peer, public_addr).await {
+                        AppAction::Tunnel => internal_addr,
+                        AppAction::Direct => apps.direct,
+                        AppAction::Block => {
+                            debug!("Blocking connection from {} on {}", peer, public_addr);
+                            return;
+                        }
+                    };
+                    relay(stream, peer, target, buffers, None).await;
+                });
+            }
+        });
+
+        Ok(BoundAddr {
+            public: public_addr,
+            internal,
+        })
+    }
+
+    /// Bind an udp listener with `bind`, a port 0 being resolved to a free port chosen by the OS.
+    /// wstunnel binds the udp listeners by itself: the port is found free then bound by `bind`, which another
+    /// process may take in between. It then fails to bind, and another port is tried.
+    /// With source restrictions, a gate is bound on the public address instead of the listener, which is bound on
+    /// loopback
+    pub async fn bind_udp<T, F, Fut>(
+        &self,
+        local: SocketAddr,
+        bind: F,
+        tasks: &TaskGroup,
+    ) -> anyhow::Result<(SocketAddr, T)>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let local = self.bind_addr(local)?;
+        if !self.allowed_sources.is_empty() {
+            return self.bind_udp_gate(local, bind, tasks).await;
+        }
+        bind_udp_port(local, bind).await
+    }
+
+    /// Gate of an udp listener, only relaying the datagrams of the allowed sources to the listener bound by `bind`
+    /// on loopback. wstunnel cannot filter the clients of its udp listeners. Each source gets its own socket to the
+    /// listener, for the tunnel to tell the clients apart and send their answers back through the right one
+    async fn bind_udp_gate<T, F, Fut>(
+        &self,
+        local: SocketAddr,
+        bind: F,
+        tasks: &TaskGroup,
+    ) -> anyhow::Result<(SocketAddr, T)>
+    where
+        F: Fn(SocketAddr) -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        let gate = Arc::new(
+            UdpSocket::bind(local)
+                .await
+                .with_context(|| format!("Cannot bind local listener on {}", local))?,
+        );
+        let public_addr = gate.local_addr()?;
+        let (internal_addr, listener) =
+            bind_udp_port(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), bind).await?;
+
+        let policy = self.clone();
+        tasks.spawn(async move {
+            let flows: Arc<GateFlows> = Arc::default();
+            let mut backoff = AcceptBackoff::default();
+            let mut datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+            loop {
+                let (len, peer) = match gate.recv_from(&mut datagram).await {
+                    Ok(received) => {
+                        backoff.succeeded();
+                        received
+                    }
+                    Err(err) => {
+                        debug!("Cannot receive datagram on {}: {:?}", public_addr, err);
+                        backoff.failed().await;
+                        continue;
+                    }
+                };
+                if !policy.is_allowed(peer.ip()) {
+                    debug!(
+                        "Dropping datagram from {} on {}: source not allowed",
+                        peer, public_addr
+                    );
+                    continue;
+                }
+                let socket = flows.lock().get(&peer).map(|flow| flow.socket.clone());
+                let socket = match socket {
+                    Some(socket) => socket,
+                    None => match open_gate_flow(&gate, &flows, peer, internal_addr).await {
+                        Ok(socket) => socket,
+                        Err(err) => {
+                            error!(
+                                "Cannot reach tunnel listener on {}: {:?}",
+                                internal_addr, err
+                            );
+                            continue;
+                        }
+                    },
+                };
+                if let Err(err) = socket.send(&datagram[..len]).await {
+                    debug!("Cannot relay datagram from {}: {:?}", peer, err);
+                }
+            }
+        });
+        Ok((public_addr, listener))
+    }
+}
+
+/// Ports tried for an udp listener asking for any free port
+const UDP_BIND_ATTEMPTS: usize = 5;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/// A source of a gated udp listener getting no answer for that long gets a new socket to the listener for its next
+/// datagram
+const GATE_FLOW_IDLE: Duration = Duration::from_secs(5 * 60);
+
+async fn bind_udp_port<T, F, Fut>(local: SocketAddr, bind: F) -> anyhow::Result<(SocketAddr, T)>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    if local.port() != 0 {
+        return Ok((local, bind(local).await?));
+    }
+    let mut attempts = 0;
+    loop {
+        let free = UdpSocket::bind(local).await?.local_addr()?;
+        match bind(free).await {
+            Ok(listener) => return Ok((free, listener)),
+            Err(err) if attempts < UDP_BIND_ATTEMPTS && is_addr_in_use(&err) => {
+                debug!("Udp port {} taken before being bound, trying another", free);
+                attempts += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Socket relaying the datagrams of a source of a gated udp listener, and the task sending the answers back
+struct GateFlow {
+    socket: Arc<UdpSocket>,
+    answers: JoinHandle<()>,
+}
+
+impl Drop for GateFlow {
+    fn drop(&mut self) {
+        self.answers.abort();
+    }
+}
+
+type GateFlows = Mutex<HashMap<SocketAddr, GateFlow>>;
+
+async fn open_gate_flow(
+    gate: &Arc<UdpSocket>,
+    flows: &Arc<GateFlows>,
+    peer: SocketAddr,
+    internal_addr: SocketAddr,
+) -> anyhow::Result<Arc<UdpSocket>> {
+    let socket = Arc::new(UdpSocket::bind((internal_addr.ip(), 0)).await?);
+    socket.connect(internal_addr).await?;
+    let answers = tokio::spawn(answer_gate_flow(
+        socket.clone(),
+        gate.clone(),
+        Arc::downgrade(flows),
+        peer,
+    ));
+    flows.lock().insert(
+        peer,
+        GateFlow {
+            socket: socket.clone(),
+            answers,
+        },
+    );
+    Ok(socket)
+}
+
+/// Send the answers of the tunnel back to the source until the flow is idle. The flows go away with the gate
+async fn answer_gate_flow(
+    socket: Arc<UdpSocket>,
+    gate: Arc<UdpSocket>,
+    flows: Weak<GateFlows>,
+    peer: SocketAddr,
+) {
+    let mut datagram = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        match tokio::time::timeout(GATE_FLOW_IDLE, socket.recv(&mut datagram)).await {
+            Ok(Ok(len)) => {
+                if let Err(err) = gate.send_to(&datagram[..len], peer).await {
+                    debug!("Cannot send datagram to {}: {:?}", peer, err);
+                }
+            }
+            Ok(Err(err)) => {
+                debug!("Gated udp flow of {} closed: {:?}", peer, err);
+                break;
+            }
+            Err(_) => break,
+        }
+    }
+    if let Some(flows) = flows.upgrade() {
+        flows.lock().remove(&peer);
+    }
+}
+
+fn is_addr_in_use(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|err| err.kind() == std::io::ErrorKind::AddrInUse)
+}
+
+/// Address a local listener is reachable at, and the loopback listener the gate relays its connections to
+#[derive(Debug)]
+pub struct BoundAddr {
+    pub public: SocketAddr,
+    pub internal: GatedListener,
+}
+
+/// Loopback listener the gate relays its connections to. Any process of the machine can connect to a loopback
+/// port, getting past the sources allowed, the application rules and the proxy header of the gate: only the
+/// connections made by the gate are accepted
+#[derive(Debug)]
+pub struct GatedListener {
+    listener: TcpListener,
+}
+
+impl GatedListener {
+    /// Next connection made by the gate, along with the loopback address it comes from
+    pub async fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        loop {
+            let (stream, from) = self.listener.accept().await?;
+            if gated_peers().lock().contains_key(&from) {
+                return Ok((stream, from));
+            }
+            warn!(
+                "Refusing connection from {} to {}, it did not go through the gate",
+                from,
+                self.listener.local_addr()?
+            );
+        }
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+/// Tunnel listener of a tcp tunnel, accepting the connections relayed by the gate on its loopback listener
+pub fn tunnel_listener(
+    listener: GatedListener,
+    remote: (Host, u16),
+    proxy_protocol: bool,
+) -> impl Stream<Item = anyhow::Result<((OwnedReadHalf, OwnedWriteHalf), RemoteAddr)>> {
+    stream::unfold(
+        (listener, AcceptBackoff::default()),
+        move |(listener, mut backoff)| {
+            let (host, port) = remote.clone();
+            async move {
+                loop {
+                    match listener.accept().await {
+                        Ok((stream, _)) => {
+                            backoff.succeeded();
+                            let _ = stream.set_nodelay(true);
+                            let remote = RemoteAddr {
+                                protocol: LocalProtocol::Tcp { proxy_protocol },
+                                host,
+                                port,
+                            };
+                            return Some((Ok((stream.into_split(), remote)), (listener, backoff)));
+                        }
+                        Err(err) => {
+                            warn!("Cannot accept gated connection: {:?}", err);
+                            backoff.failed().await;
+                        }
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// Ipv6 listener accepting ipv4 clients as well, which most systems allow but not all
+fn bind_dual_stack(addr: SocketAddr) -> anyhow::Result<TcpListener> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket
+        .set_only_v6(false)
+        .with_context(|| "This system does not support dual stack sockets")?;
+    // As done by tokio when binding, so the port can be bound again right after the listener closed
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+/// Listener on a free loopback port of the family of `public_addr`, kept bound until the tunnel listener accepts
+/// from it
+pub(crate) async fn bind_loopback(public_addr: SocketAddr) -> anyhow::Result<GatedListener> {
+    let loopback: IpAddr = if public_addr.is_ipv4() {
+        [127, 0, 0, 1].into()
+    } else {
+        [0, 0, 0, 0, 0, 0, 0, 1].into()
+    };
+    Ok(GatedListener {
+        listener: TcpListener::bind((loopback, 0)).await?,
+    })
+}
+
+/// Clients of the gated connections, by the loopback address the gate connects to the tunnel listener from
+fn gated_peers() -> &'static Mutex<HashMap<SocketAddr, SocketAddr>> {
+    static PEERS: OnceLock<Mutex<HashMap<SocketAddr, SocketAddr>>> = OnceLock::new();
+    PEERS.get_or_init(Mutex::default)
+}
+
+/// Client of a connection accepted by a tunnel listener from the gate, given the address it comes from
+pub fn gated_peer(from: SocketAddr) -> Option<SocketAddr> {
+    gated_peers().lock().get(&from).copied()
+}
+
+async fn relay(
+    mut inbound: TcpStream,
+    peer: SocketAddr,
+    internal_addr: SocketAddr,
+    buffers: BufferTuning,
+    header: Option<Vec<u8>>,
+) {
+    let mut from = None;
+    let outbound = buffers
+        .connect_announced(internal_addr, |addr| {
+            gated_peers().lock().insert(addr, peer);
+            from = Some(addr);
+        })
+        .await;
+    let _unregister = Unregister(from);
+    let mut outbound = match outbound {
+        Ok(stream) => stream,
+        Err(err) => {
+            error!(
+                "Cannot reach tunnel listener on {}: {:?}",
+                internal_addr, err
+            );
+            return;
+        }
+    };
+    if let Some(header) = header {
+        if let Err(err) = outbound.write_all(&header).await {
+            debug!("Cannot write proxy protocol header: {:?}", err);
+            return;
+        }
+    }
+
+    if let Err(err) = relay::relay(&mut inbound, &mut outbound, &buffers).await {
+        debug!("Gated connection closed with error: {:?}", err);
+    }
+}
+
+/// Forget the client of a gated connection once it is closed
+struct Unregister(Option<SocketAddr>);
+
+impl Drop for Unregister {
+    fn drop(&mut self) {
+        if let Some(from) = self.0 {
+            gated_peers().lock().remove(&from);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use crate::client::connections::ActiveConnection;
+use anyhow::{anyhow, Context};
+use log::warn;
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use wstunnel::tunnel::LocalProtocol;
+
+/// Connections closed faster than the log is written are dropped past that many
+const CHANNEL_CAPACITY: usize = 1024;
+const ACCESS_LOG_FILE: &str = "access.log";
+/// The access log is moved to `access.log.1` once that big, replacing the previous one
+const MAX_ACCESS_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Connection of a tunnel logging its accesses, written once closed
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessLogEntry {
+    pub tunnel_id: String,
+    /// Unix timestamp in milliseconds
+    pub started_at_ms: u128,
+    pub duration_ms: u64,
+    /// Client of the connection, when known
+    pub source: Option<String>,
+    pub destination: String,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// Login the client authenticated with, for socks5 and http proxy tunnels requiring one
+    pub user: Option<String>,
+}
+
+/// Connections closed on the tunnels of a profile logging their accesses
+#[derive(Debug)]
+pub struct AccessLog {
+    sender: broadcast::Sender<AccessLogEntry>,
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+        }
+    }
+}
+
+impl AccessLog {
+    pub fn subscribe(&self) -> broadcast::Receiver<AccessLogEntry> {
+        self.sender.subscribe()
+    }
+
+    /// Called by the connection registry with the last snapshot of each connection of a tunnel logging its accesses
+    pub fn recorder(
+        self: &Arc<Self>,
+        local_protocol: &LocalProtocol,
+    ) -> Arc<dyn Fn(ActiveConnection) + Send + Sync> {
+        // Login of the spec, the one every client authenticates with on the proxies of wstunnel, which do not tell
+        // who connected
+        let login = match local_protocol {
+            LocalProtocol::Socks5 {
+                credentials: Some((login, _)),
+                ..
+            }
+            | LocalProtocol::HttpProxy {
+                credentials: Some((login, _)),
+                ..
+            } => Some(login.clone()),
+            _ => None,
+        };
+        let log = self.clone();
+        Arc::new(move |connection: ActiveConnection| {
+            // Nobody listening, the profile is being disconnected
+            let _ = log.sender.send(AccessLogEntry {
+                tunnel_id: connection.tunnel_id,
+                started_at_ms: connection.started_at_ms,
+                duration_ms: connection.duration_ms,
+                source: connection.peer,
+                destination: connection.destination,
+                bytes_up: connection.bytes_up,
+                bytes_down: connection.bytes_down,
+                user: connection.user.or_else(|| login.clone()),
+            });
+        })
+    }
+}
+
+/// Writer of the access log of the app, the only one to open and rotate the file: the profiles hand it their lines,
+/// written one after the other on a thread of its own
+pub struct AccessLogWriter {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl AccessLogWriter {
+    pub fn spawn(dir: PathBuf) -> Self {
+        let (sender, lines) = mpsc::sync_channel(CHANNEL_CAPACITY);
+        std::thread::spawn(move || write_lines(&dir, lines));
+        Self { sender }
+    }
+
+    /// Queue a line of JSON, `line` being the entry along with its profile. The line is dropped when the writer
+    /// is that far behind
+    pub fn append(&self, line: &impl Serialize) -> anyhow::Result<()> {
+        let mut json = serde_json::to_vec(line)?;
+        json.push(b'\n');
+        self.sender
+            .try_send(json)
+            .map_err(|_| anyhow!("The access log writer is behind, entry dropped"))
+    }
+}
+
+/// Access log being appended to, and its size
+struct OpenLog {
+    file: File,
+    len: u64,
+}
+
+fn write_lines(dir: &Path, lines: Receiver<Vec<u8>>) {
+    let mut log = None;
+    for line in lines {
+        if let Err(err) = write_line(dir, &mut log, &line) {
+            warn!("Cannot write access log: {:?}", err);
+            // Opened again for the next line
+            log = None;
+        }
+    }
+}
+
+fn write_line(dir: &Path, log: &mut Option<OpenLog>, line: &[u8]) -> anyhow::Result<()> {
+    let path = dir.join(ACCESS_LOG_FILE);
+    if log
+        .as_ref()
+        .is_some_and(|log| log.len >= MAX_ACCESS_LOG_SIZE)
+    {
+        *log = None;
+        std::fs::rename(&path, path.with_extension("log.1"))?;
+    }
+    let open = match log {
+        Some(open) => open,
+        None => log.insert(open_log(dir, &path)?),
+    };
+    open.file.write_all(line)?;
+    open.len += line.len() as u64;
+    Ok(())
+}
+
+/// Open the access log for appending, readable by the user only as it tells who connected where
+fn open_log(dir: &Path, path: &Path) -> anyhow::Result<OpenLog> {
+    std::fs::create_dir_all(dir)?;
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options
+            .open(path)
+            .with_context(|| format!("Cannot open {}", path.display()))?;
+        // Created before it was restricted
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        let len = file.metadata()?.len();
+        Ok(OpenLog { file, len })
+    }
+    #[cfg(not(unix))]
+    {
+        let file = options
+            .open(path)
+            .with_context(|| format!("Cannot open {}", path.display()))?;
+        let len = file.metadata()?.len();
+        Ok(OpenLog { file, len })
+    }
+}
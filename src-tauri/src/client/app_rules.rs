@@ -0,0 +1,340 @@
+use crate::client::access::{bind_loopback, GatedListener};
+use crate::client::net_admin;
+use crate::client::platform::Capability;
+use crate::client::split_tunnel::connect_directly;
+use crate::client::stats::ProfileStats;
+use crate::client::tasks::TaskGroup;
+use anyhow::anyhow;
+use futures_util::{Stream, StreamExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use wstunnel::tunnel::{LocalProtocol, RemoteAddr};
+
+/// What becomes of the connections an application makes to the local proxy tunnels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AppAction {
+    Tunnel,
+    /// Connected from this machine, outside of the tunnel
+    Direct,
+    /// Closed right away
+    Block,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppRule {
+    /// Executable name, i.e: `firefox.exe`, full path of the executable, or macOS bundle, i.e: `Firefox.app`
+    pub app: String,
+    pub action: AppAction,
+}
+
+/// Routing of the local proxy tunnels by the application connecting to them, i.e: only Firefox through the tunnel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppRouting {
+    #[serde(default)]
+    pub rules: Vec<AppRule>,
+    /// Action for the applications no rule matches, and the ones that cannot be identified
+    #[serde(default = "default_action")]
+    pub default_action: AppAction,
+}
+
+fn default_action() -> AppAction {
+    AppAction::Tunnel
+}
+
+impl AppRouting {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        Capability::AppRouting.require()?;
+        if let Some(rule) = self.rules.iter().find(|rule| rule.app.trim().is_empty()) {
+            return Err(anyhow!("Invalid application rule {:?}", rule.app));
+        }
+        Ok(())
+    }
+
+    /// Action of the first rule matching the executable, the default one otherwise
+    fn action(&self, executable: &Path) -> AppAction {
+        self.rules
+            .iter()
+            .find(|rule| matches(&rule.app, executable))
+            .map_or(self.default_action, |rule| rule.action)
+    }
+}
+
+fn matches(app: &str, executable: &Path) -> bool {
+    let app = app.trim();
+    let eq = |name: &str| name.eq_ignore_ascii_case(app);
+    if app.contains(['/', '\\']) {
+        return eq(&executable.to_string_lossy());
+    }
+    // Executables of a macOS bundle are within it, i.e: /Applications/Firefox.app/Contents/MacOS/firefox
+    if app.to_ascii_lowercase().ends_with(".app") {
+        return executable
+            .ancestors()
+            .filter_map(Path::file_name)
+            .any(|name| eq(&name.to_string_lossy()));
+    }
+    let name = executable.file_name().unwrap_or_default().to_string_lossy();
+    eq(&name) || eq(name.strip_suffix(".exe").unwrap_or(&name))
+}
+
+/// Gate of a proxy tunnel telling where to send each accepted connection from the application that made it.
+/// Connections routed directly go to a twin of the proxy listener, bound on `direct`, which connects their
+/// destinations from this machine.
+#[derive(Clone)]
+pub struct AppGate {
+    stats: Arc<ProfileStats>,
+    pub direct: SocketAddr,
+}
+
+impl AppGate {
+    /// Gate of a proxy tunnel of the profile, along with the listener of its direct twin. None when the profile does
+    /// not route per application, an error when the platform cannot tell the applications apart
+    pub async fn new(
+        stats: &Arc<ProfileStats>,
+        local: SocketAddr,
+    ) -> anyhow::Result<Option<(Self, GatedListener)>> {
+        if stats.app_routing.lock().is_none() {
+            return Ok(None);
+        }
+        net_admin::require(Capability::AppRouting)?;
+        let listener = bind_loopback(local).await?;
+        let gate = Self {
+            stats: stats.clone(),
+            direct: listener.local_addr()?,
+        };
+        Ok(Some((gate, listener)))
+    }
+
+    /// Action for the connection from `peer` to the proxy listening on `local`, as set by the current rules
+    pub async fn action(&self, peer: SocketAddr, local: SocketAddr) -> AppAction {
+        let Some(routing) = self.stats.app_routing.lock().clone() else {
+            return AppAction::Tunnel;
+        };
+        let lookup =
+            tokio::task::spawn_blocking(move || platform::executable_of(peer, local)).await;
+        match lookup {
+            Ok(Ok(executable)) => {
+                let action = routing.action(&executable);
+                debug!(
+                    "Connection from {} is {}, {:?}",
+                    peer,
+                    executable.display(),
+                    action
+                );
+                action
+            }
+            Ok(Err(err)) => {
+                debug!(
+                    "Cannot tell the application connecting from {}: {:#}",
+                    peer, err
+                );
+                routing.default_action
+            }
+            Err(_) => routing.default_action,
+        }
+    }
+}
+
+/// Connect the destinations requested on the twin of a proxy tunnel from this machine, until it stops
+pub fn serve_direct<L, R, W>(listener: L, tasks: &TaskGroup)
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>> + Send + 'static,
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+    W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    tasks.spawn(async move {
+        let mut listener = Box::pin(listener);
+        let mut direct = JoinSet::new();
+        while let Some(item) = listener.next().await {
+            while direct.try_join_next().is_some() {}
+            match item {
+                Ok(((reader, writer), remote)) => match remote.protocol {
+                    LocalProtocol::Tcp { .. } => {
+                        direct.spawn(connect_directly(reader, writer, remote));
+                    }
+                    _ => warn!(
+                        "Udp to {}:{} is not routed directly, only tcp is",
+                        remote.host, remote.port
+                    ),
+                },
+                Err(err) => warn!("Direct proxy request failed: {:?}", err),
+            }
+        }
+    });
+}
+
+#[cfg(windows)]
+mod platform {
+    use anyhow::anyhow;
+    use std::ffi::OsString;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::os::windows::ffi::OsStringExt;
+    use std::path::PathBuf;
+    use windows_sys::Win32::Foundation::{CloseHandle, ERROR_INSUFFICIENT_BUFFER, NO_ERROR};
+    use windows_sys::Win32::NetworkManagement::IpHelper::{
+        GetExtendedTcpTable, MIB_TCP6TABLE_OWNER_PID, MIB_TCPTABLE_OWNER_PID,
+        TCP_TABLE_OWNER_PID_ALL,
+    };
+    use windows_sys::Win32::Networking::WinSock::{AF_INET, AF_INET6};
+    use windows_sys::Win32::System::Threading::{
+        OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32,
+        PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    /// Executable of the process owning the tcp connection from `peer` to `local`, found in the tcp table
+    pub fn executable_of(peer: SocketAddr, local: SocketAddr) -> anyhow::Result<PathBuf> {
+        let pid = match peer.ip() {
+            IpAddr::V4(_) => owner_v4(peer, local)?,
+            IpAddr::V6(_) => owner_v6(peer, local)?,
+        }
+        .ok_or_else(|| anyhow!("No process owns the connection from {}", peer))?;
+        image_name(pid)
+    }
+
+    /// Tcp table of the address family, in a buffer aligned for its rows
+    fn tcp_table(family: u16) -> anyhow::Result<Vec<u64>> {
+        let mut size = 0u32;
+        let mut buffer: Vec<u64> = vec![];
+        loop {
+            // SAFETY: the buffer holds `size` bytes, or is not written when too small
+            let status = unsafe {
+                GetExtendedTcpTable(
+                    buffer.as_mut_ptr().cast(),
+                    &mut size,
+                    0,
+                    family as u32,
+                    TCP_TABLE_OWNER_PID_ALL,
+                    0,
+                )
+            };
+            match status {
+                NO_ERROR => return Ok(buffer),
+                ERROR_INSUFFICIENT_BUFFER => buffer = vec![0; (size as usize).div_ceil(8)],
+                err => return Err(anyhow!("Cannot read the tcp table: error {}", err)),
+            }
+        }
+    }
+
+    fn port(port: u32) -> u16 {
+        u16::from_be(port as u16)
+    }
+
+    fn owner_v4(peer: SocketAddr, local: SocketAddr) -> anyhow::Result<Option<u32>> {
+        let buffer = tcp_table(AF_INET)?;
+        // SAFETY: the buffer has been filled with a table holding dwNumEntries rows
+        let rows = unsafe {
+            let table = &*(buffer.as_ptr() as *const MIB_TCPTABLE_OWNER_PID);
+            std::slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize)
+        };
+        Ok(rows
+            .iter()
+            .find(|row| {
+                IpAddr::V4(Ipv4Addr::from(row.dwLocalAddr.to_ne_bytes())) == peer.ip()
+                    && port(row.dwLocalPort) == peer.port()
+                    && port(row.dwRemotePort) == local.port()
+            })
+            .map(|row| row.dwOwningPid))
+    }
+
+    fn owner_v6(peer: SocketAddr, local: SocketAddr) -> anyhow::Result<Option<u32>> {
+        let buffer = tcp_table(AF_INET6)?;
+        // SAFETY: the buffer has been filled with a table holding dwNumEntries rows
+        let rows = unsafe {
+            let table = &*(buffer.as_ptr() as *const MIB_TCP6TABLE_OWNER_PID);
+            std::slice::from_raw_parts(table.table.as_ptr(), table.dwNumEntries as usize)
+        };
+        Ok(rows
+            .iter()
+            .find(|row| {
+                IpAddr::V6(Ipv6Addr::from(row.ucLocalAddr)) == peer.ip()
+                    && port(row.dwLocalPort) == peer.port()
+                    && port(row.dwRemotePort) == local.port()
+            })
+            .map(|row| row.dwOwningPid))
+    }
+
+    fn image_name(pid: u32) -> anyhow::Result<PathBuf> {
+        // SAFETY: the handle is checked and closed, the name written within the given size
+        unsafe {
+            let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+            if process.is_null() {
+                return Err(anyhow!("Cannot open process {}", pid));
+            }
+            let mut name = [0u16; 1024];
+            let mut size = name.len() as u32;
+            let ok = QueryFullProcessImageNameW(
+                process,
+                PROCESS_NAME_WIN32,
+                name.as_mut_ptr(),
+                &mut size,
+            );
+            CloseHandle(process);
+            if ok == 0 {
+                return Err(anyhow!("Cannot get the executable of process {}", pid));
+            }
+            Ok(PathBuf::from(OsString::from_wide(&name[..size as usize])))
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use anyhow::anyhow;
+    use libproc::bsd_info::BSDInfo;
+    use libproc::file_info::{pidfdinfo, ListFDs, ProcFDType};
+    use libproc::net_info::{SocketFDInfo, SocketInfoKind};
+    use libproc::proc_pid::{listpidinfo, pidinfo, pidpath};
+    use libproc::processes::{pids_by_type, ProcFilter};
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    /// Executable of the process owning the tcp connection from `peer` to `local`, found among the sockets of
+    /// every process the user can inspect
+    pub fn executable_of(peer: SocketAddr, local: SocketAddr) -> anyhow::Result<PathBuf> {
+        let pids = pids_by_type(ProcFilter::All)?;
+        let owner = pids
+            .into_iter()
+            .map(|pid| pid as i32)
+            .find(|pid| owns(*pid, peer, local))
+            .ok_or_else(|| anyhow!("No process owns the connection from {}", peer))?;
+        pidpath(owner)
+            .map(PathBuf::from)
+            .map_err(|err| anyhow!("Cannot get the executable of process {}: {}", owner, err))
+    }
+
+    fn owns(pid: i32, peer: SocketAddr, local: SocketAddr) -> bool {
+        let Ok(info) = pidinfo::<BSDInfo>(pid, 0) else {
+            return false;
+        };
+        let Ok(fds) = listpidinfo::<ListFDs>(pid, info.pbi_nfiles as usize) else {
+            return false;
+        };
+        fds.iter()
+            .filter(|fd| ProcFDType::from(fd.proc_fdtype) == ProcFDType::Socket)
+            .filter_map(|fd| pidfdinfo::<SocketFDInfo>(pid, fd.proc_fd).ok())
+            .filter(|socket| SocketInfoKind::from(socket.psi.soi_kind) == SocketInfoKind::Tcp)
+            .any(|socket| {
+                // SAFETY: the protocol info of a tcp socket is the tcp one
+                let ports = unsafe { socket.psi.soi_proto.pri_tcp.tcpsi_ini };
+                u16::from_be(ports.insi_lport as u16) == peer.port()
+                    && u16::from_be(ports.insi_fport as u16) == local.port()
+            })
+    }
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+mod platform {
+    use crate::client::platform::Capability;
+    use std::net::SocketAddr;
+    use std::path::PathBuf;
+
+    pub fn executable_of(_peer: SocketAddr, _local: SocketAddr) -> anyhow::Result<PathBuf> {
+        Err(Capability::AppRouting.unavailable())
+    }
+}
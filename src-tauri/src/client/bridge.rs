@@ -0,0 +1,282 @@
+use crate::client::accept::AcceptBackoff;
+use crate::client::proxy_auth::read_head;
+use crate::client::stats::ProfileStats;
+use anyhow::anyhow;
+use base64::Engine;
+use log::{debug, warn};
+use std::fmt;
+use std::future::Future;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+/// Time given to wstunnel to send its CONNECT request once connected to the bridge
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Login of the bridge in the proxy url given to wstunnel, only the password is checked
+const BRIDGE_LOGIN: &str = "bridge";
+/// Default ports of the dns resolvers wstunnel reaches over https and tls
+const DNS_OVER_HTTPS_PORT: u16 = 443;
+const DNS_OVER_TLS_PORT: u16 = 853;
+
+/// Host and port wstunnel connects to through a bridge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Target {
+    pub fn of_url(url: &Url) -> Option<Self> {
+        Some(Self {
+            host: host_of(url)?,
+            port: url.port_or_known_default()?,
+        })
+    }
+
+    /// Target of a dns resolver reached over tcp, i.e: `dns+https://1.1.1.1/dns-query` or `dns+tls://dns.google`
+    fn of_resolver(url: &Url) -> Option<Self> {
+        let default_port = match url.scheme() {
+            "dns+https" => DNS_OVER_HTTPS_PORT,
+            "dns+tls" => DNS_OVER_TLS_PORT,
+            _ => return None,
+        };
+        Some(Self {
+            host: host_of(url)?,
+            port: url.port().unwrap_or(default_port),
+        })
+    }
+
+    /// Target of a CONNECT request, i.e: `example.com:443` or `[::1]:443`
+    fn parse(target: &str) -> Option<Self> {
+        let (host, port) = target.rsplit_once(':')?;
+        Some(Self {
+            host: host.trim_matches(['[', ']']).to_ascii_lowercase(),
+            port: port.parse().ok()?,
+        })
+    }
+}
+
+fn host_of(url: &Url) -> Option<String> {
+    Some(
+        url.host_str()?
+            .trim_matches(['[', ']'])
+            .to_ascii_lowercase(),
+    )
+}
+
+/// Targets of the bridges of a profile: wstunnel only goes through a bridge to the server, and to the dns
+/// resolvers over https or tls
+pub fn targets(server: &Url, resolvers: &[Url]) -> Vec<Target> {
+    Target::of_url(server)
+        .into_iter()
+        .chain(resolvers.iter().filter_map(Target::of_resolver))
+        .collect()
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Loopback http proxy standing between wstunnel and the server, wstunnel only reaching its server directly or
+/// through an http proxy. Any process of the machine can reach a loopback port: the bridge only answers the
+/// connections presenting its token, which wstunnel sends as the password of its proxy, and only connects to the
+/// targets it was made for.
+pub struct Bridge {
+    listener: TcpListener,
+    token: String,
+    targets: Arc<Vec<Target>>,
+}
+
+impl Bridge {
+    pub async fn bind(targets: Vec<Target>) -> anyhow::Result<Self> {
+        if targets.is_empty() {
+            return Err(anyhow!("A bridge needs a target"));
+        }
+        let mut token = [0u8; 32];
+        getrandom::getrandom(&mut token)?;
+        Ok(Self {
+            listener: TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?,
+            token: base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(token),
+            targets: Arc::new(targets),
+        })
+    }
+
+    /// Proxy url for wstunnel, holding the token of the bridge
+    pub fn url(&self) -> anyhow::Result<Url> {
+        let mut url = Url::parse(&format!("http://{}", self.listener.local_addr()?))?;
+        url.set_username(BRIDGE_LOGIN)
+            .and_then(|_| url.set_password(Some(&self.token)))
+            .map_err(|_| anyhow!("Invalid bridge url {}", url))?;
+        Ok(url)
+    }
+
+    /// Serve until the profile is disconnected, handing `connect` the connections of wstunnel along with the
+    /// target of their CONNECT request. The tasks of the bridge belong to the profile, stopped along with it
+    pub fn serve<F, Fut>(self, stats: &Arc<ProfileStats>, connect: F)
+    where
+        F: Fn(TcpStream, Target) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        let connect = Arc::new(connect);
+        let profile = Arc::downgrade(stats);
+        stats.bridges.spawn(async move {
+            let mut backoff = AcceptBackoff::default();
+            loop {
+                let (stream, _) = match self.listener.accept().await {
+                    Ok(accepted) => {
+                        backoff.succeeded();
+                        accepted
+                    }
+                    Err(err) => {
+                        warn!("Cannot accept bridge connection: {:?}", err);
+                        backoff.failed().await;
+                        continue;
+                    }
+                };
+                let Some(stats) = profile.upgrade() else {
+                    return;
+                };
+                let token = self.token.clone();
+                let targets = self.targets.clone();
+                let connect = connect.clone();
+                stats.bridges.spawn(async move {
+                    let mut stream = stream;
+                    let target = match request(&mut stream, &token, &targets).await {
+                        Ok(target) => target,
+                        Err(err) => {
+                            debug!("Bridge request refused: {:?}", err);
+                            return;
+                        }
+                    };
+                    if let Err(err) = connect(stream, target.clone()).await {
+                        warn!("Cannot connect to {}: {:?}", target, err);
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// CONNECT request of wstunnel, answered with an error when it lacks the token or goes elsewhere than a target
+async fn request(
+    stream: &mut TcpStream,
+    token: &str,
+    targets: &[Target],
+) -> anyhow::Result<Target> {
+    let head = tokio::time::timeout(REQUEST_TIMEOUT, read_head(stream))
+        .await
+        .map_err(|_| anyhow!("No CONNECT request received"))??;
+    let authorized = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+        .any(|(_, value)| presents_token(value.trim(), token));
+    if !authorized {
+        stream
+            .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Err(anyhow!("Connection without the token of the bridge"));
+    }
+    let target = head
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("CONNECT "))
+        .and_then(|rest| rest.split(' ').next())
+        .ok_or_else(|| anyhow!("Expected a CONNECT request"))?;
+    match Target::parse(target) {
+        Some(target) if targets.contains(&target) => Ok(target),
+        _ => {
+            stream
+                .write_all(b"HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n")
+                .await?;
+            Err(anyhow!(
+                "CONNECT to {} is not a target of the bridge",
+                target
+            ))
+        }
+    }
+}
+
+/// Whether a `Basic` authorization holds the token as its password
+fn presents_token(authorization: &str, token: &str) -> bool {
+    let Some((scheme, credentials)) = authorization.split_once(' ') else {
+        return false;
+    };
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return false;
+    }
+    let Ok(decoded) = base64::engine::general_purpose::STANDARD.decode(credentials.trim()) else {
+        return false;
+    };
+    let password = decoded
+        .iter()
+        .position(|b| *b == b':')
+        .map(|at| &decoded[at + 1..])
+        .unwrap_or_default();
+    // Same time whatever the first differing byte, the token being guessed from another local process otherwise
+    password.len() == token.len()
+        && password
+            .iter()
+            .zip(token.as_bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_the_server_and_the_resolvers_over_tcp() {
+        let cases = [
+            ("wss://example.com", vec![], vec!["example.com:443"]),
+            ("ws://example.com:8080/", vec![], vec!["example.com:8080"]),
+            (
+                "wss://Example.com",
+                vec!["dns+https://1.1.1.1/dns-query", "dns+tls://dns.google"],
+                vec!["example.com:443", "1.1.1.1:443", "dns.google:853"],
+            ),
+            (
+                "wss://example.com",
+                vec![
+                    "dns+tls://[2606:4700::1111]:8853",
+                    "dns+https://doh.example:8443/q",
+                ],
+                vec![
+                    "example.com:443",
+                    "[2606:4700::1111]:8853",
+                    "doh.example:8443",
+                ],
+            ),
+            (
+                "wss://example.com",
+                vec![
+                    "dns://8.8.8.8",
+                    "system://0.0.0.0",
+                    "https://1.1.1.1/dns-query",
+                ],
+                vec!["example.com:443"],
+            ),
+        ];
+        for (server, resolvers, expected) in cases {
+            let resolvers: Vec<Url> = resolvers
+                .iter()
+                .map(|resolver| Url::parse(resolver).unwrap())
+                .collect();
+            let found: Vec<String> = targets(&Url::parse(server).unwrap(), &resolvers)
+                .iter()
+                .map(Target::to_string)
+                .collect();
+            assert_eq!(found, expected, "targets of {} and {:?}", server, resolvers);
+        }
+    }
+}
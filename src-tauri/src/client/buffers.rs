@@ -0,0 +1,274 @@
+use crate::client::relay;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use socket2::SockRef;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+const MIN_BUFFER: usize = 4 * 1024;
+const MAX_BUFFER: usize = 64 * 1024 * 1024;
+const BENCHMARK_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Buffers of the local side of a tcp based tunnel: the sockets the applications connect to and the copy
+/// between them and the tunnel listener. Larger buffers keep a link with a high bandwidth-delay product busy,
+/// at the cost of memory per connection.
+/// The websocket frames are sized by wstunnel itself. The same settings apply to the connection to the server
+/// through `upstream_socket` of the profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BufferTuning {
+    /// Receive buffer of the sockets (SO_RCVBUF) in bytes, chosen by the OS when not set
+    pub recv_buffer: Option<usize>,
+    /// Send buffer of the sockets (SO_SNDBUF) in bytes, chosen by the OS when not set
+    pub send_buffer: Option<usize>,
+    /// Bytes copied at once in each direction, and capacity of the pipes on Linux, 64KiB when not set
+    pub copy_buffer: Option<usize>,
+    /// Send small writes right away instead of coalescing them (TCP_NODELAY)
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    /// Keepalive probes of the connections, the ones of the OS when not set
+    #[serde(default)]
+    pub keepalive: Option<TcpKeepalive>,
+}
+
+/// Keepalive probes sent on an idle connection, so stateful firewalls do not silently drop it
+/// and a dead peer is noticed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TcpKeepalive {
+    /// Idle time before the first probe
+    pub idle_sec: u64,
+    /// Time between two probes, chosen by the OS when not set
+    pub interval_sec: Option<u64>,
+    /// Unanswered probes after which the connection is dropped, chosen by the OS when not set.
+    /// Windows always sends 10 probes
+    pub count: Option<u32>,
+}
+
+impl TcpKeepalive {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.idle_sec == 0 || self.interval_sec == Some(0) || self.count == Some(0) {
+            return Err(anyhow!(
+                "The keepalive idle time, interval and count must be greater than 0"
+            ));
+        }
+        Ok(())
+    }
+
+    fn apply(&self, socket: &SockRef) -> std::io::Result<()> {
+        let mut keepalive =
+            socket2::TcpKeepalive::new().with_time(Duration::from_secs(self.idle_sec));
+        if let Some(interval) = self.interval_sec {
+            keepalive = keepalive.with_interval(Duration::from_secs(interval));
+        }
+        #[cfg(not(windows))]
+        if let Some(count) = self.count {
+            keepalive = keepalive.with_retries(count);
+        }
+        socket.set_tcp_keepalive(&keepalive)
+    }
+}
+
+fn default_nodelay() -> bool {
+    true
+}
+
+impl Default for BufferTuning {
+    fn default() -> Self {
+        BufferTuning {
+            recv_buffer: None,
+            send_buffer: None,
+            copy_buffer: None,
+            nodelay: default_nodelay(),
+            keepalive: None,
+        }
+    }
+}
+
+impl BufferTuning {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for size in [self.recv_buffer, self.send_buffer, self.copy_buffer]
+            .into_iter()
+            .flatten()
+        {
+            if !(MIN_BUFFER..=MAX_BUFFER).contains(&size) {
+                return Err(anyhow!(
+                    "Buffer size {} is out of range, it must be between {} and {} bytes",
+                    size,
+                    MIN_BUFFER,
+                    MAX_BUFFER
+                ));
+            }
+        }
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Set the options of an established connection, the ones its buffers do not depend on
+    pub fn apply_to_stream(&self, stream: &TcpStream) -> std::io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.apply(&SockRef::from(stream))?;
+        }
+        Ok(())
+    }
+
+    /// Size the socket buffers of a listener, inherited by the connections it accepts
+    pub fn apply_to_listener(&self, listener: &TcpListener) -> anyhow::Result<()> {
+        self.set_buffers(&SockRef::from(listener))
+    }
+
+    /// Connect with the socket buffers sized before the handshake, so the window scale can make use of them
+    pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<TcpStream> {
+        let stream = self.socket(addr)?.connect(addr).await?;
+        self.apply_to_stream(&stream)?;
+        Ok(stream)
+    }
+
+    /// Same as `connect`, telling the local address of the connection before it is made,
+    /// so the side accepting it can find out where it comes from
+    pub async fn connect_announced(
+        &self,
+        addr: SocketAddr,
+        announce: impl FnOnce(SocketAddr),
+    ) -> anyhow::Result<TcpStream> {
+        let socket = self.socket(addr)?;
+        socket.bind(SocketAddr::new(addr.ip(), 0))?;
+        announce(socket.local_addr()?);
+        let stream = socket.connect(addr).await?;
+        self.apply_to_stream(&stream)?;
+        Ok(stream)
+    }
+
+    /// Same as `connect`, the socket being set up by `prepare` first, i.e: to go out of a given interface
+    pub async fn connect_with(
+        &self,
+        addr: SocketAddr,
+        prepare: impl FnOnce(&TcpSocket) -> anyhow::Result<()>,
+    ) -> anyhow::Result<TcpStream> {
+        let socket = self.socket(addr)?;
+        prepare(&socket)?;
+        let stream = socket.connect(addr).await?;
+        self.apply_to_stream(&stream)?;
+        Ok(stream)
+    }
+
+    fn socket(&self, addr: SocketAddr) -> anyhow::Result<TcpSocket> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        self.set_buffers(&SockRef::from(&socket))?;
+        Ok(socket)
+    }
+
+    fn set_buffers(&self, socket: &SockRef) -> anyhow::Result<()> {
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BufferBenchmark {
+    pub tuning: BufferTuning,
+    pub bytes_per_sec: u64,
+}
+
+/// Settings worth comparing when none are given: the defaults, then larger copies and socket buffers
+fn default_candidates() -> Vec<BufferTuning> {
+    let large_copy = BufferTuning {
+        copy_buffer: Some(256 * 1024),
+        ..BufferTuning::default()
+    };
+    vec![
+        BufferTuning::default(),
+        large_copy,
+        BufferTuning {
+            recv_buffer: Some(4 * 1024 * 1024),
+            send_buffer: Some(4 * 1024 * 1024),
+            ..large_copy
+        },
+    ]
+}
+
+/// Throughput of the local relay with each of the settings, pushing data through it over loopback.
+/// It measures what the relay costs on this machine, the link to the server is not part of it.
+pub async fn benchmark(candidates: Vec<BufferTuning>) -> anyhow::Result<Vec<BufferBenchmark>> {
+    let candidates = if candidates.is_empty() {
+        default_candidates()
+    } else {
+        candidates
+    };
+    let mut results = vec![];
+    for tuning in candidates {
+        tuning.validate()?;
+        let elapsed = transfer(&tuning, BENCHMARK_BYTES).await?;
+        results.push(BufferBenchmark {
+            tuning,
+            bytes_per_sec: (BENCHMARK_BYTES as f64 / elapsed.max(1e-6)) as u64,
+        });
+    }
+    Ok(results)
+}
+
+/// Seconds taken to send `bytes` through a relay tuned that way, from a client to a sink
+async fn transfer(tuning: &BufferTuning, bytes: u64) -> anyhow::Result<f64> {
+    let sink = TcpListener::bind("127.0.0.1:0").await?;
+    let sink_addr = sink.local_addr()?;
+    let front = TcpListener::bind("127.0.0.1:0").await?;
+    tuning.apply_to_listener(&front)?;
+    let front_addr = front.local_addr()?;
+
+    let tuning = *tuning;
+    let relay = tokio::spawn(async move {
+        let (mut inbound, _) = front.accept().await?;
+        let mut outbound = tuning.connect(sink_addr).await?;
+        relay::relay(&mut inbound, &mut outbound, &tuning).await?;
+        Ok::<_, anyhow::Error>(())
+    });
+    let received = tokio::spawn(async move {
+        let (mut stream, _) = sink.accept().await?;
+        let mut buf = vec![0; 256 * 1024];
+        let mut received = 0u64;
+        loop {
+            match stream.read(&mut buf).await? {
+                0 => return Ok::<_, anyhow::Error>(received),
+                n => received += n as u64,
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let mut client = TcpStream::connect(front_addr).await?;
+    let chunk = vec![0; 256 * 1024];
+    let mut sent = 0u64;
+    while sent < bytes {
+        let n = chunk.len().min((bytes - sent) as usize);
+        client.write_all(&chunk[..n]).await?;
+        sent += n as u64;
+    }
+    client.shutdown().await?;
+    let received = received.await??;
+    let elapsed = start.elapsed().as_secs_f64();
+    drop(client);
+    relay.await??;
+    if received != bytes {
+        return Err(anyhow!(
+            "Relay delivered {} bytes out of {}",
+            received,
+            bytes
+        ));
+    }
+    Ok(elapsed)
+}
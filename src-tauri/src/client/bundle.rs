@@ -0,0 +1,172 @@
+use crate::client::credentials;
+use crate::client::profile::Profile;
+use anyhow::{anyhow, Context};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Start of every bundle, the last byte being the version of the format
+const MAGIC: &[u8] = b"WSTBNDL\x01";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+/// Passphrases shorter than that are too easy to guess offline, once the file has been shared
+const MIN_PASSPHRASE_LEN: usize = 8;
+/// Directory of the data directory the embedded certificates are written to on import
+const CERTIFICATES_DIR: &str = "bundled-certificates";
+
+/// Content of a bundle once decrypted
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BundleContent {
+    profiles: Vec<BundledProfile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BundledProfile {
+    profile: Profile,
+    /// Client certificate and key of the profile in PEM, when they are files and have been embedded
+    #[serde(default)]
+    certificate: Option<String>,
+    #[serde(default)]
+    private_key: Option<String>,
+}
+
+/// Write the profiles into a file encrypted with a key derived from the passphrase (Argon2id, XChaCha20-Poly1305).
+/// With `embed_certificates`, the client certificate and key files of the profiles travel along, otherwise the
+/// profiles keep pointing to files of this machine.
+pub fn export(
+    profiles: Vec<Profile>,
+    passphrase: &str,
+    embed_certificates: bool,
+    path: &Path,
+) -> anyhow::Result<()> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(anyhow!(
+            "The passphrase must have at least {} characters",
+            MIN_PASSPHRASE_LEN
+        ));
+    }
+    let mut bundled = vec![];
+    for profile in profiles {
+        let read = |file: &Option<PathBuf>| -> anyhow::Result<Option<String>> {
+            match file {
+                Some(file) if embed_certificates => {
+                    Ok(Some(std::fs::read_to_string(file).with_context(|| {
+                        format!("Cannot read {}", file.display())
+                    })?))
+                }
+                _ => Ok(None),
+            }
+        };
+        bundled.push(BundledProfile {
+            certificate: read(&profile.tls_certificate)?,
+            private_key: read(&profile.tls_private_key)?,
+            profile,
+        });
+    }
+    let plaintext = serde_json::to_vec(&BundleContent { profiles: bundled })?;
+
+    let mut salt = [0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut salt)?;
+    getrandom::getrandom(&mut nonce)?;
+    let ciphertext = cipher(passphrase, &salt)?
+        .encrypt(XNonce::from_slice(&nonce), plaintext.as_slice())
+        .map_err(|_| anyhow!("Cannot encrypt the bundle"))?;
+
+    let mut file = Vec::with_capacity(MAGIC.len() + SALT_LEN + NONCE_LEN + ciphertext.len());
+    file.extend_from_slice(MAGIC);
+    file.extend_from_slice(&salt);
+    file.extend_from_slice(&nonce);
+    file.extend_from_slice(&ciphertext);
+    std::fs::write(path, file).with_context(|| format!("Cannot write {}", path.display()))?;
+    Ok(())
+}
+
+/// Read the profiles of a bundle. Embedded certificates and keys are written to the data directory, readable by
+/// the user only, and the profiles are pointed to them.
+pub fn import(path: &Path, passphrase: &str, data_dir: &Path) -> anyhow::Result<Vec<Profile>> {
+    let file = std::fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let rest = file
+        .strip_prefix(MAGIC)
+        .ok_or_else(|| anyhow!("{} is not a profile bundle", path.display()))?;
+    if rest.len() < SALT_LEN + NONCE_LEN {
+        return Err(anyhow!("The bundle is truncated"));
+    }
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let plaintext = cipher(passphrase, salt)?
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Wrong passphrase, or the bundle has been altered"))?;
+    let content: BundleContent =
+        serde_json::from_slice(&plaintext).with_context(|| "Invalid bundle content")?;
+
+    let mut profiles = vec![];
+    for bundled in content.profiles {
+        let mut profile = bundled.profile;
+        if bundled.certificate.is_none() && bundled.private_key.is_none() {
+            profiles.push(profile);
+            continue;
+        }
+        let dir = certificates_dir(data_dir, &profile.name)?;
+        if let Some(certificate) = bundled.certificate {
+            profile.tls_certificate = Some(write_private(&dir, "certificate.pem", &certificate)?);
+        }
+        if let Some(private_key) = bundled.private_key {
+            profile.tls_private_key = Some(write_private(&dir, "private-key.pem", &private_key)?);
+        }
+        profiles.push(profile);
+    }
+    Ok(profiles)
+}
+
+fn cipher(passphrase: &str, salt: &[u8]) -> anyhow::Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|err| anyhow!("Cannot derive the bundle key: {}", err))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+fn file_name(profile_name: &str) -> String {
+    profile_name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// New directory for the certificates of an imported profile, only reachable by the user. Each import gets a directory
+/// of its own, the certificates of the profiles imported before are never overwritten
+fn certificates_dir(data_dir: &Path, profile_name: &str) -> anyhow::Result<PathBuf> {
+    let parent = data_dir.join(CERTIFICATES_DIR);
+    std::fs::create_dir_all(&parent)?;
+    let mut suffix = [0u8; 6];
+    getrandom::getrandom(&mut suffix)?;
+    let suffix: String = suffix.iter().map(|b| format!("{:02x}", b)).collect();
+    let dir = parent.join(format!("{}-{}", file_name(profile_name), suffix));
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder
+        .create(&dir)
+        .with_context(|| format!("Cannot create {}", dir.display()))?;
+    Ok(dir)
+}
+
+fn write_private(dir: &Path, name: &str, content: &str) -> anyhow::Result<PathBuf> {
+    let path = dir.join(name);
+    credentials::write_private(&path, content)?;
+    Ok(path)
+}
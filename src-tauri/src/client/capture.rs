@@ -0,0 +1,418 @@
+use crate::client::stats::ProfileStats;
+use anyhow::{anyhow, Context};
+use futures_util::{Stream, StreamExt};
+use log::warn;
+use parking_lot::{Mutex, RwLock};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{ready, Context as TaskContext, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use url::Host;
+use wstunnel::tunnel::RemoteAddr;
+
+/// Raw ip packets, without link layer
+const LINKTYPE_RAW: u16 = 101;
+/// Payload carried by a single synthetic packet, keeping it under the maximum size of an ipv4 packet
+const MAX_SEGMENT: usize = 65_000;
+/// Synthetic address of the local clients, the one of the remote being used when it is an ipv4 address
+const CLIENT_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const TCP_SYN: u8 = 0x02;
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH_ACK: u8 = 0x18;
+const TCP_ACK: u8 = 0x10;
+/// Packets waiting for the writer of a capture, the ones past that are left out rather than slowing the tunnel
+const QUEUED_PACKETS: usize = 4096;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CaptureSummary {
+    pub tunnel_id: String,
+    pub path: PathBuf,
+    pub packets: u64,
+    /// Payload bytes captured, without the synthetic headers
+    pub bytes: u64,
+    /// Packets left out of the file, the traffic going faster than it is written
+    pub dropped: u64,
+}
+
+/// A pcapng file the traffic of a tunnel is written to. The connections only queue their packets, a task of
+/// its own writing them to the file
+struct Capture {
+    path: PathBuf,
+    blocks: mpsc::Sender<Vec<u8>>,
+    writer: Mutex<Option<JoinHandle<io::Result<()>>>>,
+    packets: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Capture {
+    fn create(path: &Path) -> anyhow::Result<Self> {
+        let file = create_private(path)
+            .with_context(|| format!("Cannot create capture file {}", path.display()))?;
+        let mut file = BufWriter::new(file);
+        // Section header block, then the description of the single interface
+        write_block(&mut file, 0x0A0D0D0A, &{
+            let mut body = vec![];
+            body.extend_from_slice(&0x1A2B3C4Du32.to_le_bytes());
+            body.extend_from_slice(&1u16.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&(-1i64).to_le_bytes());
+            body
+        })?;
+        write_block(&mut file, 1, &{
+            let mut body = vec![];
+            body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+            body.extend_from_slice(&0u16.to_le_bytes());
+            body.extend_from_slice(&0u32.to_le_bytes());
+            body
+        })?;
+        let (blocks, mut queued) = mpsc::channel::<Vec<u8>>(QUEUED_PACKETS);
+        // Runs until the capture is dropped, along with the sender of the blocks
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(body) = queued.blocking_recv() {
+                write_block(&mut file, 6, &body)?;
+            }
+            file.flush()
+        });
+        Ok(Self {
+            path: path.to_path_buf(),
+            blocks,
+            writer: Mutex::new(Some(writer)),
+            packets: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queue an enhanced packet block holding the packet, timestamped in microseconds
+    fn write_packet(&self, packet: &[u8], payload_len: usize) {
+        let micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or_default();
+        let mut body = Vec::with_capacity(20 + packet.len() + 3);
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+        body.extend_from_slice(packet);
+        body.resize(body.len().next_multiple_of(4), 0);
+        if self.blocks.try_send(body).is_err() {
+            // Full, or the writer stopped on an error it reports once the capture is stopped
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.packets.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(payload_len as u64, Ordering::Relaxed);
+    }
+
+    fn summary(&self, tunnel_id: &str) -> CaptureSummary {
+        CaptureSummary {
+            tunnel_id: tunnel_id.to_string(),
+            path: self.path.clone(),
+            packets: self.packets.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Capture file readable by the user only, the traffic of the tunnel being in clear
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let file = options.open(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // An existing file keeps its mode when truncated
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
+
+fn write_block(out: &mut impl Write, block_type: u32, body: &[u8]) -> io::Result<()> {
+    let total = (12 + body.len()) as u32;
+    out.write_all(&block_type.to_le_bytes())?;
+    out.write_all(&total.to_le_bytes())?;
+    out.write_all(body)?;
+    out.write_all(&total.to_le_bytes())
+}
+
+/// Capture of a tunnel, shared by its connections so the ones already open are captured too
+type CaptureSlot = Arc<RwLock<Option<Arc<Capture>>>>;
+
+/// Captures running on the tunnels of a profile
+#[derive(Default)]
+pub struct CaptureRegistry {
+    slots: Mutex<HashMap<String, CaptureSlot>>,
+}
+
+impl std::fmt::Debug for CaptureRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CaptureRegistry").finish_non_exhaustive()
+    }
+}
+
+impl CaptureRegistry {
+    fn slot(&self, tunnel_id: &str) -> CaptureSlot {
+        self.slots
+            .lock()
+            .entry(tunnel_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Write the payloads relayed by the tunnel to a pcapng file, replacing the running capture of the tunnel.
+    /// The file of the previous one is completed by its writer once dropped
+    pub fn start(&self, tunnel_id: &str, path: &Path) -> anyhow::Result<()> {
+        let capture = Arc::new(Capture::create(path)?);
+        self.slot(tunnel_id).write().replace(capture);
+        Ok(())
+    }
+
+    /// Stop the capture of the tunnel, once the packets queued are written
+    pub async fn stop(&self, tunnel_id: &str) -> anyhow::Result<CaptureSummary> {
+        let capture = self
+            .slot(tunnel_id)
+            .write()
+            .take()
+            .ok_or_else(|| anyhow!("Tunnel {} is not captured", tunnel_id))?;
+        let summary = capture.summary(tunnel_id);
+        let writer = capture.writer.lock().take();
+        // The connections only borrow the capture from the slot, dropping it closes the queue of the writer
+        drop(capture);
+        if let Some(writer) = writer {
+            writer
+                .await?
+                .with_context(|| format!("Cannot write capture {}", summary.path.display()))?;
+        }
+        Ok(summary)
+    }
+}
+
+/// Synthetic flow of a connection, so its packets read as a tcp or udp conversation in Wireshark
+struct Flow {
+    udp: bool,
+    client: (Ipv4Addr, u16),
+    remote: (Ipv4Addr, u16),
+    /// Next sequence numbers, from the client and from the remote
+    seq_up: u32,
+    seq_down: u32,
+    /// Capture the handshake was written to, written again when the capture changes
+    opened: Weak<Capture>,
+}
+
+impl Flow {
+    fn packet(&mut self, capture: &Capture, upload: bool, flags: u8, payload: &[u8]) {
+        let ((src, sport), (dst, dport)) = if upload {
+            (self.client, self.remote)
+        } else {
+            (self.remote, self.client)
+        };
+        let transport = if self.udp {
+            let mut header = Vec::with_capacity(8 + payload.len());
+            header.extend_from_slice(&sport.to_be_bytes());
+            header.extend_from_slice(&dport.to_be_bytes());
+            header.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+            // A zero checksum means none over ipv4
+            header.extend_from_slice(&0u16.to_be_bytes());
+            header.extend_from_slice(payload);
+            header
+        } else {
+            let (seq, ack) = if upload {
+                (self.seq_up, self.seq_down)
+            } else {
+                (self.seq_down, self.seq_up)
+            };
+            let mut header = Vec::with_capacity(20 + payload.len());
+            header.extend_from_slice(&sport.to_be_bytes());
+            header.extend_from_slice(&dport.to_be_bytes());
+            header.extend_from_slice(&seq.to_be_bytes());
+            header.extend_from_slice(&ack.to_be_bytes());
+            header.push(5 << 4);
+            header.push(flags);
+            header.extend_from_slice(&u16::MAX.to_be_bytes());
+            // Checksum left empty, Wireshark does not verify it by default
+            header.extend_from_slice(&[0, 0, 0, 0]);
+            header.extend_from_slice(payload);
+            let consumed = payload.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+            if upload {
+                self.seq_up = self.seq_up.wrapping_add(consumed);
+            } else {
+                self.seq_down = self.seq_down.wrapping_add(consumed);
+            }
+            header
+        };
+        let mut packet = ipv4_header(src, dst, if self.udp { 17 } else { 6 }, transport.len());
+        packet.extend_from_slice(&transport);
+        capture.write_packet(&packet, payload.len());
+    }
+
+    fn record(&mut self, capture: &Arc<Capture>, upload: bool, payload: &[u8]) {
+        if !self.udp && !Weak::ptr_eq(&self.opened, &Arc::downgrade(capture)) {
+            self.opened = Arc::downgrade(capture);
+            self.packet(capture, true, TCP_SYN, &[]);
+            self.packet(capture, false, TCP_SYN | TCP_ACK, &[]);
+            self.packet(capture, true, TCP_ACK, &[]);
+        }
+        for segment in payload.chunks(MAX_SEGMENT) {
+            let flags = if self.udp { 0 } else { TCP_PSH_ACK };
+            self.packet(capture, upload, flags, segment);
+        }
+    }
+
+    fn close(&mut self, capture: &Arc<Capture>, upload: bool) {
+        if !self.udp && Weak::ptr_eq(&self.opened, &Arc::downgrade(capture)) {
+            self.packet(capture, upload, TCP_FIN | TCP_ACK, &[]);
+        }
+    }
+}
+
+fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, transport_len: usize) -> Vec<u8> {
+    let mut header = Vec::with_capacity(20 + transport_len);
+    header.extend_from_slice(&[0x45, 0]);
+    header.extend_from_slice(&((20 + transport_len) as u16).to_be_bytes());
+    header.extend_from_slice(&[0, 0, 0x40, 0, 64, protocol, 0, 0]);
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+    let sum = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    let sum = (sum & 0xffff) + (sum >> 16);
+    let checksum = !((sum & 0xffff) + (sum >> 16)) as u16;
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    header
+}
+
+/// Half of a connection of a tunnel that may be captured, writing what it reads (sent up)
+/// or writes (sent down) to the capture of the tunnel while there is one
+pub struct Captured<S> {
+    inner: S,
+    slot: CaptureSlot,
+    flow: Arc<Mutex<Flow>>,
+    upload: bool,
+}
+
+impl<S> Captured<S> {
+    fn record(&self, payload: &[u8]) {
+        if payload.is_empty() {
+            return;
+        }
+        if let Some(capture) = &*self.slot.read() {
+            self.flow.lock().record(capture, self.upload, payload);
+        }
+    }
+}
+
+impl<S> Drop for Captured<S> {
+    fn drop(&mut self) {
+        if let Some(capture) = &*self.slot.read() {
+            self.flow.lock().close(capture, self.upload);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Captured<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.record(&buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Captured<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.record(&buf[..written]);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Let the connections accepted by a tunnel listener be captured. The payloads are the ones relayed by the
+/// tunnel, before the websocket framing, carried by synthetic packets between made up local addresses
+pub fn capture_listener<L, R, W>(
+    listener: L,
+    tunnel_id: &str,
+    udp: bool,
+    stats: &ProfileStats,
+) -> impl Stream<Item = anyhow::Result<((Captured<R>, Captured<W>), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+{
+    let slot = stats.captures.slot(tunnel_id);
+    // Each connection gets its own client port, for Wireshark to tell the conversations apart
+    let mut connections: u16 = 0;
+    listener.map(move |item| {
+        item.map(|((reader, writer), remote)| {
+            connections = connections.wrapping_add(1);
+            let remote_ip = match &remote.host {
+                Host::Ipv4(ip) => *ip,
+                _ => REMOTE_IP,
+            };
+            let flow = Arc::new(Mutex::new(Flow {
+                udp,
+                client: (CLIENT_IP, 1024 + connections % 64_000),
+                remote: (remote_ip, remote.port),
+                seq_up: 1,
+                seq_down: 1,
+                opened: Weak::new(),
+            }));
+            (
+                (
+                    Captured {
+                        inner: reader,
+                        slot: slot.clone(),
+                        flow: flow.clone(),
+                        upload: true,
+                    },
+                    Captured {
+                        inner: writer,
+                        slot: slot.clone(),
+                        flow,
+                        upload: false,
+                    },
+                ),
+                remote,
+            )
+        })
+    })
+}
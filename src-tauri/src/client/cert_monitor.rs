@@ -0,0 +1,166 @@
+use crate::client::events::ClientEvent;
+use crate::client::stats::ProfileStats;
+use anyhow::{anyhow, Context};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use wstunnel::protocols::tls;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+const SECS_PER_DAY: i64 = 86400;
+
+/// Warn about client certificates expiring in less than that, unless the profile says otherwise
+pub const DEFAULT_WARNING_DAYS: u64 = 14;
+
+/// How to renew the client certificate of a profile before it expires, i.e: with the cli of an internal CA
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateRenewal {
+    /// Command writing the renewed certificate and key over the files of the profile, program first.
+    /// wstunnel reloads them on its own, the tunnels keep running.
+    pub command: Vec<String>,
+    /// Run the command that many days before the certificate expires
+    #[serde(default = "default_renew_before_days")]
+    pub renew_before_days: u64,
+}
+
+fn default_renew_before_days() -> u64 {
+    7
+}
+
+impl CertificateRenewal {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self
+            .command
+            .first()
+            .map(String::as_str)
+            .unwrap_or_default()
+            .is_empty()
+        {
+            return Err(anyhow!("Empty certificate renewal command"));
+        }
+        Ok(())
+    }
+
+    fn run(&self) -> anyhow::Result<()> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty certificate renewal command"))?;
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .with_context(|| format!("Cannot execute {}", program))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "Certificate renewal command failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Days left before the leaf certificate of the file expires, negative once expired
+pub fn days_left(cert: &Path) -> anyhow::Result<i64> {
+    let certificates = tls::load_certificates_from_pem(cert)
+        .with_context(|| format!("Cannot load certificate {}", cert.display()))?;
+    let leaf = tls::find_leaf_certificate(certificates.as_slice())
+        .ok_or_else(|| anyhow!("No certificate in {}", cert.display()))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    Ok((leaf.validity().not_after.timestamp() - now).div_euclid(SECS_PER_DAY))
+}
+
+/// Check the expiry of the client certificate for as long as the profile is connected, warning once a day as it
+/// gets close and renewing it when the profile tells how. The expiry at connection time is left to the caller.
+pub fn spawn_monitor(
+    cert: PathBuf,
+    warning_days: u64,
+    renewal: Option<CertificateRenewal>,
+    stats: &Arc<ProfileStats>,
+) {
+    let stats: Weak<ProfileStats> = Arc::downgrade(stats);
+    tokio::spawn(async move {
+        let mut warned = days_left(&cert).ok();
+        loop {
+            let days = match days_left(&cert) {
+                Ok(days) => Some(days),
+                Err(err) => {
+                    error!("Cannot check client certificate expiry: {:?}", err);
+                    None
+                }
+            };
+
+            let renewing = renewal
+                .as_ref()
+                .zip(days)
+                .filter(|(renewal, days)| *days < renewal.renew_before_days as i64);
+            if let Some((renewal, days)) = renewing {
+                info!(
+                    "Renewing client certificate {}, {} days left",
+                    cert.display(),
+                    days
+                );
+                let event = match renew(renewal.clone(), cert.clone(), days).await {
+                    Ok(days_left) => {
+                        info!("Client certificate renewed, {} days left", days_left);
+                        warned = Some(days_left);
+                        ClientEvent::CertificateRenewed { days_left }
+                    }
+                    Err(err) => {
+                        warn!("Cannot renew client certificate: {:?}", err);
+                        ClientEvent::CertificateRenewalFailed {
+                            error: format!("{:#}", err),
+                        }
+                    }
+                };
+                let Some(current) = stats.upgrade() else {
+                    return;
+                };
+                current.publish(event);
+            }
+
+            if let Some(days) =
+                days.filter(|days| warned != Some(*days) && *days < warning_days as i64)
+            {
+                let Some(current) = stats.upgrade() else {
+                    return;
+                };
+                debug!("Client certificate expires in {} days", days);
+                current.publish(if days < 0 {
+                    ClientEvent::CertificateExpired
+                } else {
+                    ClientEvent::CertificateExpiring { days_left: days }
+                });
+                warned = Some(days);
+            }
+
+            tokio::time::sleep(CHECK_INTERVAL).await;
+            if stats.upgrade().is_none() {
+                return;
+            }
+        }
+    });
+}
+
+/// Run the renewal command, returning the days left of the renewed certificate
+async fn renew(renewal: CertificateRenewal, cert: PathBuf, days: i64) -> anyhow::Result<i64> {
+    let renewed = tokio::task::spawn_blocking(move || {
+        renewal.run()?;
+        days_left(&cert)
+    })
+    .await??;
+    if renewed <= days {
+        return Err(anyhow!(
+            "Renewal command succeeded but the certificate still expires in {} days",
+            renewed
+        ));
+    }
+    Ok(renewed)
+}
@@ -0,0 +1,167 @@
+use crate::client::client_api::BoundListener;
+use crate::client::profile::Profile;
+use crate::system_proxy;
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use wstunnel::tunnel::LocalProtocol;
+
+/// Reach the server of a profile through a local proxy tunnel of another profile, for a two-hop setup:
+/// the traffic goes to the server of the other profile first, which connects to the server of this one
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileChain {
+    /// Profile to go through, it must be connected first
+    pub profile_id: String,
+    /// Socks5 or http proxy tunnel of that profile, its first one when not set
+    pub tunnel_id: Option<String>,
+}
+
+/// A connected profile, along with the addresses its tunnels listen on
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub profile: Profile,
+    pub listeners: Vec<BoundListener>,
+}
+
+/// Copy of the profile reaching its server through the proxy tunnel of the profile it is chained to
+pub fn resolve(profile: &Profile, connected: &[Upstream]) -> anyhow::Result<Profile> {
+    let Some(chain) = &profile.chain else {
+        return Ok(profile.clone());
+    };
+
+    // The profile may be connected already, chained profiles it is an upstream of would then go through itself
+    let mut path = vec![profile.name.as_str()];
+    let mut next = Some(chain);
+    while let Some(link) = next {
+        if path.contains(&link.profile_id.as_str()) {
+            path.push(&link.profile_id);
+            return Err(anyhow!(
+                "Profiles are chained in a loop: {}",
+                path.join(" -> ")
+            ));
+        }
+        path.push(&link.profile_id);
+        next = connected
+            .iter()
+            .find(|upstream| upstream.profile.name == link.profile_id)
+            .and_then(|upstream| upstream.profile.chain.as_ref());
+    }
+
+    let upstream = connected
+        .iter()
+        .find(|upstream| upstream.profile.name == chain.profile_id)
+        .ok_or_else(|| {
+            anyhow!(
+                "Profile {} goes through profile {}, which must be connected first",
+                profile.name,
+                chain.profile_id
+            )
+        })?;
+    let tunnel = upstream
+        .profile
+        .enabled_tunnels()
+        .filter(|config| !config.reverse)
+        .filter_map(|config| config.to_tunnel().ok())
+        .find(|tunnel| {
+            matches!(
+                tunnel.local_protocol,
+                LocalProtocol::Socks5 { .. } | LocalProtocol::HttpProxy { .. }
+            ) && !chain.tunnel_id.as_ref().is_some_and(|id| *id != tunnel.id)
+        })
+        .ok_or_else(|| match &chain.tunnel_id {
+            Some(id) => anyhow!(
+                "Profile {} has no socks5 nor http proxy tunnel {}",
+                chain.profile_id,
+                id
+            ),
+            None => anyhow!(
+                "Profile {} has no socks5 nor http proxy tunnel",
+                chain.profile_id
+            ),
+        })?;
+    let listener = upstream
+        .listeners
+        .iter()
+        .find(|listener| listener.tunnel_id == tunnel.id)
+        .ok_or_else(|| {
+            anyhow!(
+                "Tunnel {} of profile {} is not listening",
+                tunnel.id,
+                chain.profile_id
+            )
+        })?;
+    let addr = system_proxy::reachable_addr(listener.bound);
+
+    let mut resolved = profile.clone();
+    resolved.chain = None;
+    match tunnel.local_protocol {
+        LocalProtocol::HttpProxy { credentials, .. } => {
+            resolved.http_proxy = Some(addr.to_string());
+            if let Some((login, password)) = credentials {
+                resolved.http_proxy_login = Some(login);
+                resolved.http_proxy_password = Some(password);
+            }
+        }
+        LocalProtocol::Socks5 { credentials, .. } => {
+            let mut proxy = Url::parse(&format!("socks5://{}", addr))?;
+            if let Some((login, password)) = credentials {
+                let _ = proxy.set_username(&login);
+                let _ = proxy.set_password(Some(&password));
+            }
+            resolved.socks5_proxy = Some(proxy);
+        }
+        _ => {
+            return Err(anyhow!(
+                "Tunnel {} of profile {} is not a socks5 or http proxy, a profile is only chained through a proxy",
+                tunnel.id,
+                chain.profile_id
+            ))
+        }
+    }
+    Ok(resolved)
+}
+
+/// Order the profiles so every one comes after the profile it is chained to, when both are given
+pub fn startup_order(profiles: Vec<Profile>) -> anyhow::Result<Vec<Profile>> {
+    let mut pending = profiles;
+    let mut ordered: Vec<Profile> = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let ready = pending.iter().position(|profile| {
+            !profile
+                .chain
+                .as_ref()
+                .is_some_and(|chain| pending.iter().any(|other| other.name == chain.profile_id))
+        });
+        let Some(ready) = ready else {
+            let names: Vec<&str> = pending.iter().map(|p| p.name.as_str()).collect();
+            return Err(anyhow!(
+                "Profiles are chained in a loop: {}",
+                names.join(", ")
+            ));
+        };
+        ordered.push(pending.remove(ready));
+    }
+    Ok(ordered)
+}
+
+/// Connected profiles going through the given one, directly or not, the farthest first so they are disconnected before it
+pub fn dependents(profile_id: &str, connected: &[Profile]) -> Vec<String> {
+    let mut found: Vec<String> = vec![];
+    let mut reached = vec![profile_id.to_string()];
+    while let Some(upstream) = reached.pop() {
+        for profile in connected {
+            let chained = profile
+                .chain
+                .as_ref()
+                .is_some_and(|chain| chain.profile_id == upstream);
+            if chained && profile.name != profile_id && !found.contains(&profile.name) {
+                found.push(profile.name.clone());
+                reached.push(profile.name.clone());
+            }
+        }
+    }
+    // Every profile is found after the one it goes through
+    found.reverse();
+    found
+}
@@ -0,0 +1,391 @@
+use crate::client::profile::Profile;
+use anyhow::{anyhow, Context};
+use serde_json::{Map, Value};
+use std::path::{Path, PathBuf};
+
+/// Extension of the exported profiles, which are shell scripts running the upstream wstunnel cli
+pub const EXPORT_EXTENSION: &str = "sh";
+/// Comment line holding the settings the upstream cli does not know about, so they survive a round trip
+const DESKTOP_SETTINGS_PREFIX: &str = "# wstunnel-desktop: ";
+
+#[derive(Clone, Copy)]
+enum FlagKind {
+    Text,
+    Number,
+    Switch,
+    List,
+}
+
+/// Profile fields having an equivalent flag in `wstunnel client`
+const FLAGS: [(&str, &str, FlagKind); 19] = [
+    ("socketSoMark", "--socket-so-mark", FlagKind::Number),
+    (
+        "connectionMinIdle",
+        "--connection-min-idle",
+        FlagKind::Number,
+    ),
+    (
+        "connectionRetryMaxBackoffSec",
+        "--connection-retry-max-backoff-sec",
+        FlagKind::Number,
+    ),
+    ("tlsSniOverride", "--tls-sni-override", FlagKind::Text),
+    ("tlsSniDisable", "--tls-sni-disable", FlagKind::Switch),
+    (
+        "tlsVerifyCertificate",
+        "--tls-verify-certificate",
+        FlagKind::Switch,
+    ),
+    ("tlsCertificate", "--tls-certificate", FlagKind::Text),
+    ("tlsPrivateKey", "--tls-private-key", FlagKind::Text),
+    ("httpProxy", "--http-proxy", FlagKind::Text),
+    ("httpProxyLogin", "--http-proxy-login", FlagKind::Text),
+    ("httpProxyPassword", "--http-proxy-password", FlagKind::Text),
+    (
+        "httpUpgradePathPrefix",
+        "--http-upgrade-path-prefix",
+        FlagKind::Text,
+    ),
+    (
+        "httpUpgradeCredentials",
+        "--http-upgrade-credentials",
+        FlagKind::Text,
+    ),
+    ("httpHeadersFile", "--http-headers-file", FlagKind::Text),
+    (
+        "websocketPingFrequencySec",
+        "--websocket-ping-frequency-sec",
+        FlagKind::Number,
+    ),
+    (
+        "websocketMaskFrame",
+        "--websocket-mask-frame",
+        FlagKind::Switch,
+    ),
+    ("dnsResolver", "--dns-resolver", FlagKind::List),
+    (
+        "dnsResolverPreferIpv4",
+        "--dns-resolver-prefer-ipv4",
+        FlagKind::Switch,
+    ),
+    ("httpHeaders", "--http-headers", FlagKind::List),
+];
+
+/// Short flags of the upstream cli, and their long equivalent
+const SHORT_FLAGS: [(&str, &str); 6] = [
+    ("-L", "--local-to-remote"),
+    ("-R", "--remote-to-local"),
+    ("-c", "--connection-min-idle"),
+    ("-p", "--http-proxy"),
+    ("-P", "--http-upgrade-path-prefix"),
+    ("-H", "--http-headers"),
+];
+
+/// Write a profile as a shell script running the upstream wstunnel cli with the same configuration.
+/// Scripts are plain text: the secrets of the profile are redacted, an encrypted bundle carries them
+pub fn export_profile(profile: &Profile) -> anyhow::Result<String> {
+    let profile = &profile.redacted();
+    let Value::Object(mut fields) = serde_json::to_value(profile)? else {
+        return Err(anyhow!("Profile is not an object"));
+    };
+
+    let mut args = vec!["wstunnel".to_string(), "client".to_string()];
+    for tunnel in profile.enabled_tunnels() {
+        let flag = if tunnel.reverse { "-R" } else { "-L" };
+        args.extend([flag.to_string(), tunnel.spec.clone()]);
+    }
+    for (field, flag, kind) in FLAGS {
+        let Some(value) = fields.remove(field) else {
+            continue;
+        };
+        match (kind, value) {
+            (_, Value::Null) | (FlagKind::Switch, Value::Bool(false)) => {}
+            (FlagKind::Switch, _) => args.push(flag.to_string()),
+            (FlagKind::List, Value::Array(values)) => {
+                for value in values {
+                    let value = match value {
+                        Value::Object(header) => format!(
+                            "{}: {}",
+                            header
+                                .get("name")
+                                .and_then(Value::as_str)
+                                .unwrap_or_default(),
+                            header
+                                .get("value")
+                                .and_then(Value::as_str)
+                                .unwrap_or_default()
+                        ),
+                        Value::String(value) => value,
+                        value => value.to_string(),
+                    };
+                    args.extend([flag.to_string(), value]);
+                }
+            }
+            (_, Value::String(value)) => args.extend([flag.to_string(), value]),
+            (_, value) => args.extend([flag.to_string(), value.to_string()]),
+        }
+    }
+    args.push(profile.server_addr.to_string());
+    fields.remove("serverAddr");
+
+    let mut script = String::from("#!/bin/sh\n");
+    script.push_str(&format!(
+        "{}{}\n",
+        DESKTOP_SETTINGS_PREFIX,
+        serde_json::to_string(&fields)?
+    ));
+    let args: Vec<String> = args.iter().map(|arg| shell_quote(arg)).collect();
+    script.push_str(&format!("exec {}\n", args.join(" \\\n  ")));
+    Ok(script)
+}
+
+/// Read a profile from a script running `wstunnel client`, named after the file when it was not exported by the app
+pub fn import_profile(script: &str, default_name: &str) -> anyhow::Result<Profile> {
+    let mut desktop = Map::new();
+    let mut command = String::new();
+    for line in script.lines() {
+        if let Some(settings) = line.strip_prefix(DESKTOP_SETTINGS_PREFIX) {
+            desktop = serde_json::from_str(settings).with_context(|| "Invalid desktop settings")?;
+        } else if !line.trim_start().starts_with('#') {
+            // Continuation lines are joined back as the shell would do
+            match line.strip_suffix('\\') {
+                Some(line) => command.push_str(line),
+                None => {
+                    command.push_str(line);
+                    command.push('\n');
+                }
+            }
+        }
+    }
+
+    let words = shell_split(&command)?;
+    let client = words
+        .iter()
+        .position(|w| w == "client")
+        .filter(|i| words[..*i].iter().any(|w| w.ends_with("wstunnel")))
+        .ok_or_else(|| anyhow!("No wstunnel client command found"))?;
+    let fields = parse_args(&words[client + 1..], desktop, default_name)?;
+    serde_json::from_value(Value::Object(fields)).with_context(|| "Invalid profile")
+}
+
+fn parse_args(
+    args: &[String],
+    mut desktop: Map<String, Value>,
+    default_name: &str,
+) -> anyhow::Result<Map<String, Value>> {
+    let desktop_tunnels = match desktop.remove("tunnels") {
+        Some(Value::Array(tunnels)) => tunnels,
+        _ => vec![],
+    };
+    let mut fields = Map::new();
+    let mut tunnels = vec![];
+    let mut server_addr = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            server_addr = Some(arg.clone());
+            continue;
+        }
+
+        let (flag, inline_value) = match arg.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let flag = SHORT_FLAGS
+            .iter()
+            .find(|(short, _)| *short == flag)
+            .map_or(flag, |(_, long)| long);
+        let mut value = || {
+            inline_value
+                .clone()
+                .or_else(|| args.next().cloned())
+                .ok_or_else(|| anyhow!("Missing value of {}", flag))
+        };
+
+        if flag == "--local-to-remote" || flag == "--remote-to-local" {
+            let spec = value()?;
+            let reverse = flag == "--remote-to-local";
+            // Keep the desktop only settings of the tunnel, i.e: rate limits
+            let tunnel = desktop_tunnels
+                .iter()
+                .find(|t| {
+                    t.get("spec").and_then(Value::as_str) == Some(spec.as_str())
+                        && t.get("reverse").and_then(Value::as_bool).unwrap_or(false) == reverse
+                })
+                .cloned()
+                .unwrap_or_else(|| serde_json::json!({ "spec": spec, "reverse": reverse }));
+            tunnels.push(tunnel);
+            continue;
+        }
+
+        let (field, _, kind) = FLAGS
+            .iter()
+            .find(|(_, f, _)| *f == flag)
+            .ok_or_else(|| anyhow!("Unsupported wstunnel client option {}", flag))?;
+        let value = match kind {
+            FlagKind::Switch => Value::Bool(true),
+            FlagKind::Text => Value::String(value()?),
+            FlagKind::Number => {
+                let value = value()?;
+                Value::Number(
+                    value
+                        .parse::<u64>()
+                        .with_context(|| format!("Invalid number {} for {}", value, flag))?
+                        .into(),
+                )
+            }
+            FlagKind::List if *field == "httpHeaders" => {
+                let header = value()?;
+                let (name, value) = header
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Invalid header {}", header))?;
+                serde_json::json!({ "name": name.trim(), "value": value.trim() })
+            }
+            FlagKind::List => Value::String(value()?),
+        };
+        match (kind, fields.get_mut(*field)) {
+            (FlagKind::List, Some(Value::Array(values))) => values.push(value),
+            (FlagKind::List, _) => {
+                fields.insert(field.to_string(), Value::Array(vec![value]));
+            }
+            _ => {
+                fields.insert(field.to_string(), value);
+            }
+        }
+    }
+
+    fields.insert(
+        "serverAddr".to_string(),
+        Value::String(server_addr.ok_or_else(|| anyhow!("Missing server address"))?),
+    );
+    // The command line only has the enabled tunnels, the disabled ones of the profile are kept as they are
+    tunnels.extend(
+        desktop_tunnels
+            .into_iter()
+            .filter(|t| t.get("enabled").and_then(Value::as_bool) == Some(false)),
+    );
+    fields.insert("tunnels".to_string(), Value::Array(tunnels));
+    for (key, value) in desktop {
+        fields.entry(key).or_insert(value);
+    }
+    fields
+        .entry("name")
+        .or_insert_with(|| Value::String(default_name.to_string()));
+    Ok(fields)
+}
+
+/// Write every profile in its own file of the directory, returning the files written
+pub fn export_profiles(profiles: &[Profile], directory: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(directory)?;
+    let mut files = vec![];
+    for profile in profiles {
+        let file_name: String = profile
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let path = directory.join(format!("{}.{}", file_name, EXPORT_EXTENSION));
+        std::fs::write(&path, export_profile(profile)?)
+            .with_context(|| format!("Cannot write {}", path.display()))?;
+        files.push(path);
+    }
+    Ok(files)
+}
+
+/// Read every exported profile of the directory
+pub fn import_profiles(directory: &Path) -> anyhow::Result<Vec<Profile>> {
+    let mut profiles = vec![];
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXPORT_EXTENSION) {
+            continue;
+        }
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let script = std::fs::read_to_string(&path)?;
+        profiles.push(
+            import_profile(&script, &name)
+                .with_context(|| format!("Cannot import {}", path.display()))?,
+        );
+    }
+    Ok(profiles)
+}
+
+fn shell_quote(arg: &str) -> String {
+    let is_safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=@,+%".contains(c));
+    if is_safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// Split a command line in words like a posix shell, without any expansion
+fn shell_split(command: &str) -> anyhow::Result<Vec<String>> {
+    let (mut words, mut word, mut in_word) = (vec![], String::new(), false);
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("Unterminated single quote")),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(anyhow!("Unterminated double quote")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("Unterminated double quote")),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                if let Some(c) = chars.next() {
+                    word.push(c);
+                }
+            }
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
@@ -1,45 +1,312 @@
+use crate::client::access::{self, AccessPolicy};
+use crate::client::app_rules::{self, AppGate, AppRouting};
+use crate::client::bridge;
+use crate::client::buffers::BufferTuning;
+use crate::client::capture::capture_listener;
+use crate::client::cert_monitor::{self, CertificateRenewal};
+use crate::client::client_key::ClientKeySource;
+use crate::client::concurrency::{concurrency_listener, ConnectionOverflow, Limited};
+use crate::client::connections::{track_listener, ConnectionClient};
+use crate::client::credentials::{self, CredentialsFile, CredentialsProvider};
+use crate::client::datagrams::{datagram_listener, DatagramOptions};
+use crate::client::dns_cache;
+use crate::client::engine::{listener_runner, ClientEngine, PreparedTunnel, TunnelRunner};
+use crate::client::events::{ClientEvent, ConnectProgress};
+use crate::client::fallback;
+use crate::client::faults::fault_listener;
+use crate::client::host_header::{rotate_host_listener, HostRotation, HostTemplate};
+use crate::client::http_proxy::http_proxy_listener;
+use crate::client::listener_auth::{self, ListenerAuth};
+use crate::client::multipath::{self, Multipath};
+use crate::client::net_admin;
+use crate::client::platform::{Capability, NativePlatform, PlatformListeners};
+use crate::client::port_mapping::{self, MappingProtocol};
+use crate::client::proxy_auth::{self, HttpProxyAuth};
+use crate::client::proxy_detect::{self, ProxyDetection};
+use crate::client::proxy_protocol::{ProxyHeader, RewriteHeaderConnector};
+use crate::client::rate_limit::rate_limit_listener;
+use crate::client::retry_policy::RetryPolicy;
+use crate::client::reverse_status::Reported;
+use crate::client::server_select::{self, ProbeSettings, ServerProbe};
+use crate::client::socks5;
+use crate::client::split_tunnel::{split_listener, SplitRules};
+use crate::client::static_hosts;
+use crate::client::stats::{self, meter_listener, HopRoute, LinkInfo, ProfileStats};
+use crate::client::tasks::TaskGroup;
+use crate::client::temp_tunnels::TempTunnel;
+use crate::client::tls_fingerprint::TlsFingerprint;
+use crate::client::tls_resumption;
+use crate::client::trace::trace_listener;
+use crate::client::transport::{self, TlsSettings, TlsVersion};
+use crate::client::tun::{self, TunDevice};
+use crate::client::upgrade_failures::UpgradeFailureRule;
+use crate::client::upstream_socket;
+use crate::parsers;
 use anyhow::{anyhow, Context};
-use log::{error, info};
-use parking_lot::{Mutex, RwLock};
-use std::net::SocketAddr;
+use futures_util::future::join_all;
+use futures_util::{stream, Stream, StreamExt};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
-use std::str::FromStr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use tauri::http::header::HOST;
 use tauri::http::{HeaderName, HeaderValue};
 use tauri::Url;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::select;
+use tokio::sync::watch;
 use tokio_rustls::rustls::pki_types::DnsName;
 use url::Host;
 use wstunnel::protocols::dns::DnsResolver;
 use wstunnel::protocols::tls;
-use wstunnel::tunnel::client::{TlsClientConfig, WsClient, WsClientConfig};
+use wstunnel::tunnel::client::{WsClient, WsClientConfig};
 use wstunnel::tunnel::connectors::{Socks5TunnelConnector, TcpTunnelConnector, UdpTunnelConnector};
-use wstunnel::tunnel::listeners::{
-    new_stdio_listener, HttpProxyTunnelListener, Socks5TunnelListener, TcpTunnelListener,
-    UdpTunnelListener,
-};
-use wstunnel::tunnel::transport::{TransportAddr, TransportScheme};
+use wstunnel::tunnel::listeners::{new_stdio_listener, UdpTunnelListener};
 use wstunnel::tunnel::{client, to_host_port, LocalProtocol, RemoteAddr};
 
-const DEFAULT_CLIENT_UPGRADE_PATH_PREFIX: &str = "v1";
+pub const DEFAULT_CLIENT_UPGRADE_PATH_PREFIX: &str = "v1";
+/// Listeners bound at the same time while a profile connects
+const MAX_PARALLEL_BRING_UP: usize = 8;
 
 pub struct WsClientApi {}
 
+/// Outcome of a successful connection
+#[derive(Debug, Clone)]
+pub struct ConnectedClient {
+    /// Server url that ended up being used, which may differ from the configured one after a transport fallback
+    pub remote_addr: Url,
+    pub stats: Arc<ProfileStats>,
+    /// Addresses the local listeners are actually bound to, which differ from the requested ones for ephemeral ports
+    pub listeners: Vec<BoundListener>,
+    /// Runs the tunnels, whose local listeners stay bound until the profile is disconnected
+    pub engine: Arc<ClientEngine>,
+    /// Tunnels started on demand for this connection only, on top of the ones of the profile
+    pub temp_tunnels: Vec<TempTunnel>,
+}
+
+impl ConnectedClient {
+    pub fn shutdown(&self) {
+        self.engine.shutdown();
+    }
+
+    /// Shut down, returning once the local listeners are released
+    pub async fn stop(&self) {
+        self.engine.stop().await;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundListener {
+    pub tunnel_id: String,
+    pub requested: SocketAddr,
+    pub bound: SocketAddr,
+}
+
+impl BoundListener {
+    fn new(tunnel: &LocalToRemote, bound: SocketAddr) -> Self {
+        Self {
+            tunnel_id: tunnel.id.clone(),
+            requested: tunnel.local,
+            bound,
+        }
+    }
+}
+
 impl WsClientApi {
-    pub async fn connect(args: Box<Client>) -> anyhow::Result<()> {
+    /// How the handshakes with the server are done, loading the client certificate and key of the profile
+    fn tls_settings(args: &mut Client) -> anyhow::Result<TlsSettings> {
         let (tls_certificate, tls_key) = if let (Some(cert), Some(key)) =
             (args.tls_certificate.as_ref(), args.tls_private_key.as_ref())
         {
             let tls_certificate = tls::load_certificates_from_pem(cert)
-                .expect("Cannot load client TLS certificate (mTLS)");
+                .with_context(|| "Cannot load client TLS certificate (mTLS)")?;
             let tls_key = tls::load_private_key_from_file(key)
-                .expect("Cannot load client TLS private key (mTLS)");
+                .with_context(|| "Cannot load client TLS private key (mTLS)")?;
             (Some(tls_certificate), Some(tls_key))
         } else {
             (None, None)
         };
+        let identity = args
+            .tls_private_key_source
+            .as_ref()
+            .map(|source| source.load(args.tls_certificate.as_deref()))
+            .transpose()
+            .with_context(|| "Cannot load client TLS key (mTLS)")?;
+
+        Ok(TlsSettings {
+            verify_certificate: args.tls_verify_certificate,
+            pinned_certificates: std::mem::take(&mut args.tls_pinned_certificates),
+            sni_disable: args.tls_sni_disable,
+            sni_override: args.tls_sni_override.clone(),
+            certificate: tls_certificate.or_else(|| identity.as_ref().map(|id| id.cert.clone())),
+            key: tls_key,
+            identity,
+            certificate_path: args.tls_certificate.clone(),
+            key_path: args.tls_private_key.clone(),
+            min_version: args.tls_min_version,
+            max_version: args.tls_max_version,
+            alpn: args.tls_alpn.take(),
+            fingerprint: args.tls_fingerprint,
+            session_key: None,
+        })
+    }
+
+    /// Everything the handshakes of a profile depend on, the TLS sessions being kept under it: a session is only
+    /// resumed by the profiles reaching the same server as the same client and accepting the server the same way
+    fn session_key(server: &Url, tls: &TlsSettings) -> String {
+        let client = tls
+            .certificate
+            .as_ref()
+            .and_then(|certs| certs.first())
+            .map(|cert| format!("{:x}", Sha256::digest(cert)))
+            .unwrap_or_default();
+        let mut pins: Vec<String> = tls
+            .pinned_certificates
+            .iter()
+            .map(|pin| pin.to_ascii_lowercase())
+            .collect();
+        pins.sort();
+        format!(
+            "{}:{}|{:?}|sni_disable={}|verify={}|pins={}|client={}|keystore={}|versions={:?}..{:?}|alpn={:?}|{:?}",
+            server.host_str().unwrap_or_default(),
+            server.port_or_known_default().unwrap_or_default(),
+            tls.sni_override,
+            tls.sni_disable,
+            tls.verify_certificate,
+            pins.join(","),
+            client,
+            tls.identity.is_some(),
+            tls.min_version,
+            tls.max_version,
+            tls.alpn,
+            tls.fingerprint,
+        )
+    }
+
+    fn probe_settings<'a>(args: &'a Client, tls: &'a TlsSettings) -> ProbeSettings<'a> {
+        ProbeSettings {
+            tls,
+            upgrade_path_prefix: &args.http_upgrade_path_prefix,
+            static_hosts: &args.static_hosts,
+        }
+    }
+
+    /// Time each server takes to answer an upgrade request, reached as the profile would reach its own
+    pub async fn probe_servers(
+        mut args: Box<Client>,
+        servers: &[Url],
+    ) -> anyhow::Result<Vec<ServerProbe>> {
+        let tls_settings = Self::tls_settings(&mut args)?;
+        Ok(server_select::probe_all(servers, Self::probe_settings(&args, &tls_settings)).await)
+    }
+
+    /// Connect a profile, reporting each step of its bring up to `progress`
+    pub async fn connect(
+        mut args: Box<Client>,
+        progress: impl Fn(ConnectProgress) + Send + Sync,
+    ) -> anyhow::Result<ConnectedClient> {
+        if args.socket_so_mark.is_some() {
+            net_admin::require(Capability::SocketMark)?;
+        }
+        let mut tls_settings = Self::tls_settings(&mut args)?;
+
+        // Probing servers through a proxy would measure the proxy, so the configured server is kept in this case.
+        // An on demand profile does not reach the server before it is used, it keeps the configured one as well
+        if !args.server_candidates.is_empty()
+            && !args.on_demand
+            && args.http_proxy.is_none()
+            && args.socks5_proxy.is_none()
+        {
+            let servers: Vec<Url> = std::iter::once(args.remote_addr.clone())
+                .chain(args.server_candidates.iter().cloned())
+                .collect();
+            args.remote_addr =
+                server_select::fastest(&servers, Self::probe_settings(&args, &tls_settings))
+                    .await?;
+        }
+
+        if args.http_proxy.is_none() && args.socks5_proxy.is_none() {
+            // Off the corporate network the PAC file is usually unreachable, and the server reached directly
+            args.http_proxy =
+                match proxy_detect::detect(&args.http_proxy_detection, &args.remote_addr).await {
+                    Ok(proxy) => proxy,
+                    Err(err) => {
+                        warn!("Cannot detect http proxy, connecting directly: {:?}", err);
+                        None
+                    }
+                };
+        }
+
+        let configured_addr = args.remote_addr.clone();
+        // http2 cannot be probed reliably through a proxy, so let it be in this case
+        if args.transport_fallback
+            && !args.on_demand
+            && args.http_proxy.is_none()
+            && args.socks5_proxy.is_none()
+        {
+            args.remote_addr = transport::for_url(&args.remote_addr)?
+                .fallback(
+                    &args.remote_addr,
+                    Self::probe_settings(&args, &tls_settings),
+                )
+                .await;
+        }
+        let remote_addr = args.remote_addr.clone();
+        progress(ConnectProgress::TransportSelected {
+            remote_addr: remote_addr.to_string(),
+        });
+        let mut transport_url = remote_addr.clone();
+        let server_host = remote_addr.host().map(|h| h.to_owned());
+        let static_addr = server_host
+            .as_ref()
+            .and_then(|host| static_hosts::lookup(&args.static_hosts, host));
+        if let (Some(Host::Domain(name)), Some(addr)) = (&server_host, &static_addr) {
+            debug!("Server {} resolved to {} by the static hosts", name, addr);
+            if tls_settings.sni_override.is_none() {
+                tls_settings.sni_override = DnsName::try_from(name.clone()).ok();
+            }
+            let _ = transport_url.set_host(Some(&addr.to_string()));
+        }
+        if args.dns_cache {
+            // Reached directly, as the bridges to the server do not exist yet
+            let resolver = DnsResolver::new_from_urls(
+                &args.dns_resolver,
+                None,
+                args.socket_so_mark,
+                !args.dns_resolver_prefer_ipv4,
+            )
+            .with_context(|| "Cannot create dns resolver")?;
+            if let Some(host) = server_host.filter(|_| static_addr.is_none()) {
+                if let Some(pinned) = dns_cache::pinned_addr(&host, &resolver).await {
+                    // The certificate is still checked against the name, only the lookup is skipped
+                    if let (Host::Domain(name), None) = (&host, &tls_settings.sni_override) {
+                        tls_settings.sni_override = DnsName::try_from(name.clone()).ok();
+                    }
+                    let _ = transport_url.set_host(Some(&pinned.to_string()));
+                }
+            }
+            let pinned = join_all(
+                args.remote_to_local
+                    .iter()
+                    .map(|tunnel| dns_cache::pinned_addr(&tunnel.remote.0, &resolver)),
+            )
+            .await;
+            for (tunnel, pinned) in args.remote_to_local.iter_mut().zip(pinned) {
+                if let Some(host) = pinned {
+                    tunnel.remote.0 = host;
+                }
+            }
+        }
+        let session_key = Self::session_key(&transport_url, &tls_settings);
+        let handshakes = Arc::new(tls_resumption::counters(&session_key));
+        tls_settings.session_key = Some(session_key);
+        let transport_addr =
+            transport::for_url(&remote_addr)?.transport_addr(&transport_url, &tls_settings)?;
 
         let http_upgrade_path_prefix = if args
             .http_upgrade_path_prefix
@@ -47,7 +314,8 @@ impl WsClientApi {
         {
             // When using mTLS and no manual http upgrade path is specified configure the HTTP upgrade path
             // to be the common name (CN) of the client's certificate.
-            tls_certificate
+            tls_settings
+                .certificate
                 .as_ref()
                 .and_then(|certs| tls::find_leaf_certificate(certs.as_slice()))
                 .and_then(|leaf_cert| tls::cn_from_certificate(&leaf_cert))
@@ -56,59 +324,176 @@ impl WsClientApi {
             args.http_upgrade_path_prefix
         };
 
-        let transport_scheme = TransportScheme::from_str(args.remote_addr.scheme())
-            .expect("invalid scheme in server url");
-        let tls = match transport_scheme {
-            TransportScheme::Ws | TransportScheme::Http => None,
-            TransportScheme::Wss | TransportScheme::Https => Some(TlsClientConfig {
-                tls_connector: Arc::new(RwLock::new(
-                    tls::tls_connector(
-                        args.tls_verify_certificate,
-                        transport_scheme.alpn_protocols(),
-                        !args.tls_sni_disable,
-                        tls_certificate,
-                        tls_key,
-                    )
-                    .expect("Cannot create tls connector"),
-                )),
-                tls_sni_override: args.tls_sni_override,
-                tls_verify_certificate: args.tls_verify_certificate,
-                tls_sni_disabled: args.tls_sni_disable,
-                tls_certificate_path: args.tls_certificate.clone(),
-                tls_key_path: args.tls_private_key.clone(),
-            }),
-        };
+        let host_template = args.http_host_template.take().map(|template| HostTemplate {
+            template,
+            sni: tls_settings
+                .sni_override
+                .as_ref()
+                .map(|sni| sni.as_ref().to_string())
+                .or_else(|| args.remote_addr.host_str().map(str::to_string))
+                .unwrap_or_default(),
+            server: args.remote_addr.host_str().unwrap_or_default().to_string(),
+            hostnames: std::mem::take(&mut args.http_host_rotation),
+        });
 
         // Extract host header from http_headers
-        let host_header =
-            if let Some((_, host_val)) = args.http_headers.iter().find(|(h, _)| *h == HOST) {
-                host_val.clone()
-            } else {
-                let host = match args.remote_addr.port_or_known_default() {
-                    None | Some(80) | Some(443) => args.remote_addr.host().unwrap().to_string(),
-                    Some(port) => format!("{}:{}", args.remote_addr.host().unwrap(), port),
-                };
-                HeaderValue::from_str(&host)?
+        let host_header = if let Some(template) = &host_template {
+            template.render(0)?
+        } else if let Some((_, host_val)) = args.http_headers.iter().find(|(h, _)| *h == HOST) {
+            host_val.clone()
+        } else {
+            let remote_host = args
+                .remote_addr
+                .host()
+                .ok_or_else(|| anyhow!("Server address {} has no host", args.remote_addr))?;
+            let host = match args.remote_addr.port_or_known_default() {
+                None | Some(80) | Some(443) => remote_host.to_string(),
+                Some(port) => format!("{}:{}", remote_host, port),
             };
+            HeaderValue::from_str(&host)?
+        };
+        // The file is read again by wstunnel on every connection, only its validity is checked here
         if let Some(path) = &args.http_headers_file {
-            if !path.exists() {
-                panic!("http headers file does not exists: {}", path.display());
-            }
+            parsers::read_headers_file(path)?;
         }
+        let credentials = match args.http_upgrade_credentials_provider.take() {
+            Some(provider) => {
+                let token = provider.fetch()?;
+                let file = CredentialsFile::new(args.http_headers_file.take())?;
+                file.write(&provider.scheme, &token)?;
+                args.http_headers_file = Some(file.path().to_path_buf());
+                Some((provider, file, token))
+            }
+            None => None,
+        };
+        // wstunnel sends the same Host to every connection, a rotating one goes through a headers file instead
+        let host_rotation = match host_template.filter(HostTemplate::rotates) {
+            Some(template) => {
+                let rotation = HostRotation::new(template, args.http_headers_file.take())?;
+                args.http_headers_file = Some(rotation.path().to_path_buf());
+                Some(rotation)
+            }
+            None => None,
+        };
 
-        let http_proxy = Self::mk_http_proxy(
-            args.http_proxy,
-            args.http_proxy_login,
-            args.http_proxy_password,
-        )?;
-        let client_config = WsClientConfig {
-            remote_addr: TransportAddr::new(
-                TransportScheme::from_str(args.remote_addr.scheme()).unwrap(),
-                args.remote_addr.host().unwrap().to_owned(),
-                args.remote_addr.port_or_known_default().unwrap(),
-                tls,
+        let http_proxy = args
+            .http_proxy
+            .as_deref()
+            .map(|proxy| {
+                parsers::parse_proxy_url(
+                    proxy,
+                    args.http_proxy_login.as_deref(),
+                    args.http_proxy_password.as_deref(),
+                )
+            })
+            .transpose()?;
+        let first_hops = match (&http_proxy, &args.socks5_proxy) {
+            (Some(proxy), _) => vec![proxy.clone()],
+            (None, Some(socks5)) => vec![socks5.clone()],
+            (None, None) => vec![transport_url.clone()],
+        };
+
+        let websocket_ping_frequency = args
+            .websocket_ping_frequency_sec
+            .or(Some(Duration::from_secs(30)))
+            .filter(|d| d.as_secs() > 0);
+        let stats = ProfileStats::new(LinkInfo {
+            remote_addr: remote_addr.clone(),
+            first_hops,
+            websocket_mask_frame: args.websocket_mask_frame,
+            connection_min_idle: args.connection_min_idle,
+            upgrade_timeout: args.upgrade_timeout_sec,
+            ping_frequency: websocket_ping_frequency,
+        });
+        if let Some(rotation) = host_rotation {
+            let _ = stats.host_rotation.set(rotation);
+        }
+        // Resumed by its first local connection
+        stats.suspended.store(args.on_demand, Ordering::Relaxed);
+        let _ = stats.handshakes.set(handshakes);
+        *stats.app_routing.lock() = args.app_routing.take();
+        let _ = stats.first_hop.set(Arc::new(HopRoute {
+            // Reached directly, like the first hop
+            resolver: DnsResolver::new_from_urls(
+                &args.dns_resolver,
+                None,
+                args.socket_so_mark,
+                !args.dns_resolver_prefer_ipv4,
             )
-            .unwrap(),
+            .with_context(|| "Cannot create dns resolver")?,
+            static_hosts: args.static_hosts.clone(),
+            so_mark: args.socket_so_mark,
+            tuning: args.upstream_socket.unwrap_or_default(),
+        }));
+        let bridge_targets = bridge::targets(&transport_url, &args.dns_resolver);
+        if args.upstream_socket.is_some()
+            && http_proxy.is_some()
+            && args.http_proxy_auth == HttpProxyAuth::Basic
+        {
+            warn!("Reaching the server through an http proxy, its socket options are not used");
+        }
+        let http_proxy = match (http_proxy, args.http_proxy_auth) {
+            (Some(proxy), HttpProxyAuth::Ntlm | HttpProxyAuth::Negotiate) => Some(
+                proxy_auth::spawn_bridge(
+                    proxy,
+                    args.http_proxy_auth,
+                    args.upstream_socket,
+                    bridge_targets.clone(),
+                    &stats,
+                )
+                .await?,
+            ),
+            (proxy, _) => proxy,
+        };
+        let http_proxy = match (http_proxy, args.socks5_proxy.take()) {
+            (None, Some(socks5)) => Some(
+                socks5::spawn_upstream_bridge(
+                    socks5,
+                    args.upstream_socket,
+                    bridge_targets.clone(),
+                    &stats,
+                )
+                .await?,
+            ),
+            (proxy, _) => proxy,
+        };
+        let http_proxy = match (http_proxy, args.multipath.take()) {
+            (None, Some(multipath)) => {
+                let (bridge, paths) = multipath::spawn_bridge(
+                    multipath,
+                    args.upstream_socket.unwrap_or_default(),
+                    bridge_targets.clone(),
+                    &stats,
+                )
+                .await?;
+                let _ = stats.multipath.set(paths);
+                Some(bridge)
+            }
+            (Some(proxy), Some(_)) => {
+                warn!("Reaching the server through a proxy, multipath is not used");
+                Some(proxy)
+            }
+            (proxy, None) => proxy,
+        };
+        let http_proxy = match (http_proxy, args.upstream_socket) {
+            (None, Some(tuning)) => {
+                // Its own resolver, the one of wstunnel going through the bridge for https and tls
+                let resolver = DnsResolver::new_from_urls(
+                    &args.dns_resolver,
+                    None,
+                    args.socket_so_mark,
+                    !args.dns_resolver_prefer_ipv4,
+                )
+                .with_context(|| "Cannot create dns resolver")?;
+                Some(
+                    upstream_socket::spawn_tuned_bridge(tuning, resolver, bridge_targets, &stats)
+                        .await?,
+                )
+            }
+            (proxy, _) => proxy,
+        };
+        let client_config = WsClientConfig {
+            remote_addr: transport_addr,
             socket_so_mark: args.socket_so_mark,
             http_upgrade_path_prefix,
             http_upgrade_credentials: args.http_upgrade_credentials,
@@ -119,11 +504,8 @@ impl WsClientApi {
                 .collect(),
             http_headers_file: args.http_headers_file,
             http_header_host: host_header,
-            timeout_connect: Duration::from_secs(10),
-            websocket_ping_frequency: args
-                .websocket_ping_frequency_sec
-                .or(Some(Duration::from_secs(30)))
-                .filter(|d| d.as_secs() > 0),
+            timeout_connect: args.connect_timeout_sec,
+            websocket_ping_frequency,
             websocket_mask_frame: args.websocket_mask_frame,
             dns_resolver: DnsResolver::new_from_urls(
                 &args.dns_resolver,
@@ -131,24 +513,209 @@ impl WsClientApi {
                 args.socket_so_mark,
                 !args.dns_resolver_prefer_ipv4,
             )
-            .expect("cannot create dns resolver"),
+            .with_context(|| "Cannot create dns resolver")?,
             http_proxy,
         };
 
-        let client = WsClient::new(
-            client_config,
+        info!("Starting wstunnel client v{}", env!("CARGO_PKG_VERSION"),);
+
+        // The pool connects to the server while the listeners are bound, neither depends on the other
+        let (min_idle, max_backoff) = (
             args.connection_min_idle,
             args.connection_retry_max_backoff_sec,
-        )
-        .await?;
-        info!("Starting wstunnel client v{}", env!("CARGO_PKG_VERSION"),);
+        );
+        // An on demand profile connects with its first local connection, the pool is filled once it resumes
+        let pool_min_idle = if args.on_demand { 0 } else { min_idle };
+        let pool = async {
+            let client = WsClient::new(client_config, pool_min_idle, max_backoff)
+                .await
+                // The transport selected may be the reason, it is probed again on the next attempt
+                .inspect_err(|_| fallback::forget(&configured_addr))?;
+            progress(ConnectProgress::PoolReady);
+            Ok::<_, anyhow::Error>(client)
+        };
+        let bring_up = Self::prepare_tunnels(
+            args.local_to_remote,
+            args.remote_to_local,
+            stats.clone(),
+            &progress,
+        );
+        let (client, (tunnels, listeners)) = tokio::try_join!(pool, bring_up)?;
+
+        stats::spawn_link_prober(stats.clone());
+        if let (Some(cert), true) = (&args.tls_certificate, args.tls_private_key.is_some()) {
+            cert_monitor::spawn_monitor(
+                cert.clone(),
+                args.tls_certificate_warning_days,
+                args.tls_certificate_renewal.take(),
+                &stats,
+            );
+        }
+        if let Some((provider, file, token)) = credentials {
+            credentials::spawn_refresher(provider, file, token, &stats);
+        }
 
         // Start tunnels
-        for tunnel in args.remote_to_local.into_iter() {
-            let client = client.clone();
-            match &tunnel.local_protocol {
-                LocalProtocol::ReverseTcp { .. } => {
-                    tokio::spawn(async move {
+        let engine = ClientEngine::new(
+            client,
+            min_idle,
+            max_backoff,
+            args.retry_policy,
+            args.upgrade_failure_rules,
+            tunnels,
+            stats.clone(),
+        );
+        engine.start();
+        progress(ConnectProgress::Started);
+
+        Ok(ConnectedClient {
+            remote_addr,
+            stats,
+            listeners,
+            engine,
+            temp_tunnels: vec![],
+        })
+    }
+
+    /// Bind the listeners of the tunnels, a few at a time, reporting each tunnel once ready.
+    /// The tunnels are returned in the order of the profile, whichever got ready first.
+    async fn prepare_tunnels(
+        local_to_remote: Vec<LocalToRemote>,
+        remote_to_local: Vec<LocalToRemote>,
+        stats: Arc<ProfileStats>,
+        progress: &(impl Fn(ConnectProgress) + Send + Sync),
+    ) -> anyhow::Result<(Vec<PreparedTunnel>, Vec<BoundListener>)> {
+        let total = local_to_remote.len() + remote_to_local.len();
+        let mut ready = 0;
+        let mut tunnel_ready = |tunnel_id: String| {
+            ready += 1;
+            progress(ConnectProgress::TunnelReady {
+                tunnel_id,
+                ready,
+                total,
+            });
+        };
+        let mut listeners = Vec::with_capacity(local_to_remote.len());
+        let mut tunnels = vec![];
+
+        for tunnel in remote_to_local.into_iter() {
+            let id = tunnel.id.clone();
+            tunnels.extend(Self::prepare_reverse_tunnel(tunnel, &stats)?);
+            tunnel_ready(id);
+        }
+
+        let (stdio, local_to_remote): (Vec<_>, Vec<_>) = local_to_remote
+            .into_iter()
+            .partition(|t| matches!(t.local_protocol, LocalProtocol::Stdio { .. }));
+        for tunnel in stdio {
+            let id = tunnel.id.clone();
+            tunnels.push(Self::prepare_stdio_tunnel(tunnel, &stats).await?);
+            tunnel_ready(id);
+        }
+
+        let mut prepared = stream::iter(local_to_remote)
+            .map(|tunnel| {
+                let stats = stats.clone();
+                let id = tunnel.id.clone();
+                async move {
+                    let prepared = Self::prepare_local_tunnel(tunnel, stats).await?;
+                    Ok::<_, anyhow::Error>((id, prepared))
+                }
+            })
+            .buffered(MAX_PARALLEL_BRING_UP);
+        while let Some(result) = prepared.next().await {
+            let (id, (tunnel, listener)) = result?;
+            listeners.extend(listener);
+            tunnels.push(tunnel);
+            tunnel_ready(id);
+        }
+        Ok((tunnels, listeners))
+    }
+
+    /// Relay stdio until its input is closed. The tunnel then stops for good and tells it with an event,
+    /// for whoever runs the profile to decide what to do, the other tunnels of the profile keep running.
+    async fn prepare_stdio_tunnel(
+        tunnel: LocalToRemote,
+        stats: &Arc<ProfileStats>,
+    ) -> anyhow::Result<PreparedTunnel> {
+        let LocalProtocol::Stdio { proxy_protocol } = tunnel.local_protocol else {
+            return Err(anyhow!("Tunnel {} is not a stdio tunnel", tunnel.id));
+        };
+        let tasks = TaskGroup::default();
+        let (server, mut handle) =
+            new_stdio_listener(tunnel.remote.clone(), proxy_protocol).await?;
+
+        let (closed_tx, closed) = watch::channel(false);
+        let upgrade_timeout = stats.link.upgrade_timeout;
+        let tunnel_id = tunnel.id.clone();
+        let stats = Arc::downgrade(stats);
+        tasks.spawn(async move {
+            handle.closed().await;
+            info!("Input of stdio tunnel {} closed", tunnel_id);
+            let _ = closed_tx.send(true);
+            if let Some(stats) = stats.upgrade() {
+                stats.publish(ClientEvent::StdioClosed { tunnel_id });
+            }
+        });
+
+        let runner = listener_runner(server, false, upgrade_timeout, &tasks);
+        Ok(PreparedTunnel {
+            id: tunnel.id,
+            reverse: false,
+            runner: Box::new(move |client| {
+                let run = runner(client);
+                let mut closed = closed.clone();
+                Box::pin(async move {
+                    // Ending without error, the engine does not restart it
+                    select! {
+                        res = run => res,
+                        _ = closed.wait_for(|closed| *closed) => Ok(()),
+                    }
+                })
+            }),
+            tasks,
+        })
+    }
+
+    /// Bind the local listener of a tunnel, or prepare it to listen on the server for a reverse tunnel
+    pub async fn prepare_tunnel(
+        tunnel: LocalToRemote,
+        reverse: bool,
+        stats: Arc<ProfileStats>,
+    ) -> anyhow::Result<(PreparedTunnel, Option<BoundListener>)> {
+        if !reverse {
+            return Self::prepare_local_tunnel(tunnel, stats).await;
+        }
+        let id = tunnel.id.clone();
+        let prepared = Self::prepare_reverse_tunnel(tunnel, &stats)?
+            .ok_or_else(|| anyhow!("Invalid protocol for reverse tunnel {}", id))?;
+        Ok((prepared, None))
+    }
+
+    fn prepare_reverse_tunnel(
+        tunnel: LocalToRemote,
+        stats: &Arc<ProfileStats>,
+    ) -> anyhow::Result<Option<PreparedTunnel>> {
+        let id = tunnel.id.clone();
+        // The remote port of a unix socket tunnel is a path on the server, there is nothing to show of it
+        if matches!(
+            tunnel.local_protocol,
+            LocalProtocol::ReverseTcp
+                | LocalProtocol::ReverseUdp { .. }
+                | LocalProtocol::ReverseSocks5 { .. }
+                | LocalProtocol::ReverseHttpProxy { .. }
+        ) {
+            stats
+                .reverse_tunnels
+                .register(&id, tunnel.local, &stats.link.remote_addr);
+        }
+        let runner: TunnelRunner = match &tunnel.local_protocol {
+            LocalProtocol::ReverseTcp { .. } => {
+                let stats = stats.clone();
+                Box::new(move |client: WsClient| {
+                    let tunnel = tunnel.clone();
+                    let stats = stats.clone();
+                    Box::pin(async move {
                         let cfg = client.config.clone();
                         let tcp_connector = TcpTunnelConnector::new(
                             &tunnel.remote.0,
@@ -163,15 +730,29 @@ impl WsClientApi {
                             host,
                             port,
                         };
-                        if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
-                            error!("{:?}", err);
+                        match tunnel.proxy_header {
+                            Some(header) => {
+                                let tcp_connector =
+                                    RewriteHeaderConnector::new(tcp_connector, header);
+                                let tcp_connector = Reported::new(tcp_connector, tunnel.id, stats);
+                                client.run_reverse_tunnel(remote, tcp_connector).await
+                            }
+                            None => {
+                                let tcp_connector = Reported::new(tcp_connector, tunnel.id, stats);
+                                client.run_reverse_tunnel(remote, tcp_connector).await
+                            }
                         }
-                    });
-                }
-                LocalProtocol::ReverseUdp { timeout } => {
-                    let timeout = *timeout;
+                    })
+                })
+            }
+            LocalProtocol::ReverseUdp { timeout } => {
+                let timeout = *timeout;
+                let stats = stats.clone();
 
-                    tokio::spawn(async move {
+                Box::new(move |client: WsClient| {
+                    let tunnel = tunnel.clone();
+                    let stats = stats.clone();
+                    Box::pin(async move {
                         let cfg = client.config.clone();
                         let (host, port) = to_host_port(tunnel.local);
                         let remote = RemoteAddr {
@@ -186,22 +767,26 @@ impl WsClientApi {
                             cfg.timeout_connect,
                             &cfg.dns_resolver,
                         );
+                        let udp_connector = Reported::new(udp_connector, tunnel.id, stats);
 
-                        if let Err(err) = client
+                        client
                             .run_reverse_tunnel(remote.clone(), udp_connector)
                             .await
-                        {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                LocalProtocol::ReverseSocks5 {
-                    timeout,
-                    credentials,
-                } => {
+                    })
+                })
+            }
+            LocalProtocol::ReverseSocks5 {
+                timeout,
+                credentials,
+            } => {
+                let credentials = credentials.clone();
+                let timeout = *timeout;
+                let stats = stats.clone();
+                Box::new(move |client: WsClient| {
+                    let tunnel = tunnel.clone();
                     let credentials = credentials.clone();
-                    let timeout = *timeout;
-                    tokio::spawn(async move {
+                    let stats = stats.clone();
+                    Box::pin(async move {
                         let cfg = client.config.clone();
                         let (host, port) = to_host_port(tunnel.local);
                         let remote = RemoteAddr {
@@ -217,19 +802,24 @@ impl WsClientApi {
                             cfg.timeout_connect,
                             &cfg.dns_resolver,
                         );
+                        let socks_connector = Reported::new(socks_connector, tunnel.id, stats);
 
-                        if let Err(err) = client.run_reverse_tunnel(remote, socks_connector).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                LocalProtocol::ReverseHttpProxy {
-                    timeout,
-                    credentials,
-                } => {
+                        client.run_reverse_tunnel(remote, socks_connector).await
+                    })
+                })
+            }
+            LocalProtocol::ReverseHttpProxy {
+                timeout,
+                credentials,
+            } => {
+                let credentials = credentials.clone();
+                let timeout = *timeout;
+                let stats = stats.clone();
+                Box::new(move |client: WsClient| {
+                    let tunnel = tunnel.clone();
                     let credentials = credentials.clone();
-                    let timeout = *timeout;
-                    tokio::spawn(async move {
+                    let stats = stats.clone();
+                    Box::pin(async move {
                         let cfg = client.config.clone();
                         let (host, port) = to_host_port(tunnel.local);
                         let remote = RemoteAddr {
@@ -247,18 +837,20 @@ impl WsClientApi {
                             cfg.timeout_connect,
                             &cfg.dns_resolver,
                         );
+                        let tcp_connector = Reported::new(tcp_connector, tunnel.id, stats);
 
-                        if let Err(err) = client
+                        client
                             .run_reverse_tunnel(remote.clone(), tcp_connector)
                             .await
-                        {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                LocalProtocol::ReverseUnix { path } => {
+                    })
+                })
+            }
+            LocalProtocol::ReverseUnix { path } => {
+                let path = path.clone();
+                Box::new(move |client: WsClient| {
+                    let tunnel = tunnel.clone();
                     let path = path.clone();
-                    tokio::spawn(async move {
+                    Box::pin(async move {
                         let cfg = client.config.clone();
                         let tcp_connector = TcpTunnelConnector::new(
                             &tunnel.remote.0,
@@ -274,190 +866,306 @@ impl WsClientApi {
                             host,
                             port,
                         };
-                        if let Err(err) = client.run_reverse_tunnel(remote, tcp_connector).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                LocalProtocol::Stdio { .. }
-                | LocalProtocol::TProxyTcp
-                | LocalProtocol::TProxyUdp { .. }
-                | LocalProtocol::Tcp { .. }
-                | LocalProtocol::Udp { .. }
-                | LocalProtocol::Socks5 { .. }
-                | LocalProtocol::HttpProxy { .. } => {}
-                LocalProtocol::Unix { .. } => {
-                    panic!("Invalid protocol for reverse tunnel");
-                }
+                        client.run_reverse_tunnel(remote, tcp_connector).await
+                    })
+                })
             }
-        }
-
-        for tunnel in args.local_to_remote.into_iter() {
-            let client = client.clone();
+            LocalProtocol::Stdio { .. }
+            | LocalProtocol::TProxyTcp
+            | LocalProtocol::TProxyUdp { .. }
+            | LocalProtocol::Tcp { .. }
+            | LocalProtocol::Udp { .. }
+            | LocalProtocol::Socks5 { .. }
+            | LocalProtocol::HttpProxy { .. } => return Ok(None),
+            LocalProtocol::Unix { .. } => {
+                return Err(anyhow!("Invalid protocol for reverse tunnel"));
+            }
+        };
+        Ok(Some(PreparedTunnel {
+            id,
+            reverse: true,
+            runner,
+            tasks: TaskGroup::default(),
+        }))
+    }
 
-            match &tunnel.local_protocol {
-                LocalProtocol::Tcp { proxy_protocol } => {
-                    let server = TcpTunnelListener::new(
-                        tunnel.local,
-                        tunnel.remote.clone(),
-                        *proxy_protocol,
-                    )
+    async fn prepare_local_tunnel(
+        tunnel: LocalToRemote,
+        stats: Arc<ProfileStats>,
+    ) -> anyhow::Result<(PreparedTunnel, Option<BoundListener>)> {
+        let tasks = TaskGroup::default();
+        let mut listener = None;
+        if let Some(device) = tunnel.tun {
+            let route = stats
+                .first_hop
+                .get()
+                .cloned()
+                .ok_or_else(|| anyhow!("The way to the server is not known yet"))?;
+            let server = tun::tun_listener(device, &stats.link.first_hops, route, &tasks).await?;
+            let prepared = PreparedTunnel {
+                id: tunnel.id.clone(),
+                reverse: false,
+                runner: Self::instrumented_runner(server, &tunnel, stats, &tasks),
+                tasks,
+            };
+            return Ok((prepared, None));
+        }
+        if let Some(name) = &tunnel.named_pipe {
+            let server = NativePlatform::named_pipe(name, tunnel.remote.clone()).await?;
+            let prepared = PreparedTunnel {
+                id: tunnel.id.clone(),
+                reverse: false,
+                runner: Self::instrumented_runner(server, &tunnel, stats, &tasks),
+                tasks,
+            };
+            return Ok((prepared, None));
+        }
+        let events = Arc::downgrade(&stats);
+        let runner = match &tunnel.local_protocol {
+            LocalProtocol::Tcp { proxy_protocol } => {
+                let local = tunnel
+                    .access
+                    .bind_tcp(tunnel.local, tunnel.buffers, tunnel.proxy_header, &tasks)
                     .await?;
-                    tokio::spawn(async move {
-                        if let Err(err) = client.run_tunnel(server).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                #[cfg(target_os = "linux")]
-                LocalProtocol::TProxyTcp => {
-                    use crate::tunnel::listeners::TproxyTcpTunnelListener;
-                    let server = TproxyTcpTunnelListener::new(tunnel.local, false).await?;
-
-                    tokio::spawn(async move {
-                        if let Err(err) = client.run_tunnel(server).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                #[cfg(unix)]
-                LocalProtocol::Unix {
-                    path,
-                    proxy_protocol,
-                } => {
-                    use crate::tunnel::listeners::UnixTunnelListener;
-                    let server =
-                        UnixTunnelListener::new(path, tunnel.remote.clone(), *proxy_protocol)
-                            .await?;
-                    tokio::spawn(async move {
-                        if let Err(err) = client.run_tunnel(server).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                #[cfg(not(unix))]
-                LocalProtocol::Unix { .. } => {
-                    panic!("Unix socket is not available for non Unix platform")
-                }
-
-                #[cfg(target_os = "linux")]
-                LocalProtocol::TProxyUdp { timeout } => {
-                    use crate::tunnel::listeners::new_tproxy_udp;
-                    let server = new_tproxy_udp(tunnel.local, *timeout).await?;
-                    tokio::spawn(async move {
-                        if let Err(err) = client.run_tunnel(server).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                #[cfg(not(target_os = "linux"))]
-                LocalProtocol::TProxyTcp | LocalProtocol::TProxyUdp { .. } => {
-                    panic!("Transparent proxy is not available for non Linux platform")
-                }
-                LocalProtocol::Udp { timeout } => {
-                    let server =
-                        UdpTunnelListener::new(tunnel.local, tunnel.remote.clone(), *timeout)
-                            .await?;
-
-                    tokio::spawn(async move {
-                        if let Err(err) = client.run_tunnel(server).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                LocalProtocol::Socks5 {
-                    timeout,
-                    credentials,
-                } => {
-                    let server =
-                        Socks5TunnelListener::new(tunnel.local, *timeout, credentials.clone())
-                            .await?;
-                    tokio::spawn(async move {
-                        if let Err(err) = client.run_tunnel(server).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-                LocalProtocol::HttpProxy {
-                    timeout,
-                    credentials,
-                    proxy_protocol,
-                } => {
-                    let server = HttpProxyTunnelListener::new(
+                listener = Some(BoundListener::new(&tunnel, local.public));
+                let server =
+                    access::tunnel_listener(local.internal, tunnel.remote.clone(), *proxy_protocol);
+                // Every connection comes from the gate, which knows the actual client
+                Self::instrumented_runner_with_client(server, &tunnel, stats, &tasks, |reader| {
+                    ConnectionClient {
+                        peer: reader.peer_addr().ok().and_then(access::gated_peer),
+                        user: None,
+                    }
+                })
+            }
+            LocalProtocol::TProxyTcp => {
+                net_admin::require(Capability::TransparentProxy)?;
+                let server = NativePlatform::tproxy_tcp(tunnel.local).await?;
+                Self::instrumented_runner(server, &tunnel, stats, &tasks)
+            }
+            LocalProtocol::Unix {
+                path,
+                proxy_protocol,
+            } => {
+                let server =
+                    NativePlatform::unix(path, tunnel.remote.clone(), *proxy_protocol).await?;
+                Self::instrumented_runner(server, &tunnel, stats, &tasks)
+            }
+            LocalProtocol::TProxyUdp { timeout } => {
+                net_admin::require(Capability::TransparentProxy)?;
+                let settings = stats
+                    .datagrams
+                    .register(&tunnel.id, tunnel.datagrams, *timeout);
+                let server = NativePlatform::tproxy_udp(tunnel.local, None).await?;
+                let server = datagram_listener(server, settings, stats.tunnel_metrics(&tunnel.id));
+                Self::instrumented_runner(server, &tunnel, stats, &tasks)
+            }
+            LocalProtocol::Udp { timeout } => {
+                // The flows are closed by the datagram listener, whose timeout can be changed on the fly
+                let remote = tunnel.remote.clone();
+                let (local, server) = tunnel
+                    .access
+                    .bind_udp(
                         tunnel.local,
-                        *timeout,
-                        credentials.clone(),
-                        *proxy_protocol,
+                        |local| {
+                            let remote = remote.clone();
+                            async move { Ok(UdpTunnelListener::new(local, remote, None).await?) }
+                        },
+                        &tasks,
                     )
                     .await?;
-                    tokio::spawn(async move {
-                        if let Err(err) = client.run_tunnel(server).await {
-                            error!("{:?}", err);
-                        }
-                    });
-                }
-
-                LocalProtocol::Stdio { proxy_protocol } => {
-                    let (server, mut handle) =
-                        new_stdio_listener(tunnel.remote.clone(), *proxy_protocol).await?;
-                    tokio::spawn(async move {
-                        if let Err(err) = client.run_tunnel(server).await {
-                            error!("{:?}", err);
-                        }
-                    });
-
-                    // We need to wait for either a ctrl+c of that the stdio tunnel is closed
-                    // to force exit the program
-                    select! {
-                       _ = handle.closed() => {},
-                       _ = tokio::signal::ctrl_c() => {}
+                listener = Some(BoundListener::new(&tunnel, local));
+                let settings = stats
+                    .datagrams
+                    .register(&tunnel.id, tunnel.datagrams, *timeout);
+                let server = datagram_listener(server, settings, stats.tunnel_metrics(&tunnel.id));
+                Self::instrumented_runner(server, &tunnel, stats, &tasks)
+            }
+            LocalProtocol::Socks5 {
+                timeout,
+                credentials,
+            } => {
+                let auth =
+                    listener_auth::validator(tunnel.listener_auth.as_ref(), credentials.as_ref())?;
+                let apps = match AppGate::new(&stats, tunnel.local).await? {
+                    Some((apps, direct)) => {
+                        let direct = socks5::socks5_listener(
+                            direct,
+                            apps.direct,
+                            *timeout,
+                            auth.clone(),
+                            None,
+                            &tasks,
+                        )
+                        .await?;
+                        app_rules::serve_direct(direct, &tasks);
+                        Some(apps)
                     }
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-                    std::process::exit(0);
-                }
-                LocalProtocol::ReverseTcp => {}
-                LocalProtocol::ReverseUdp { .. } => {}
-                LocalProtocol::ReverseSocks5 { .. } => {}
-                LocalProtocol::ReverseUnix { .. } => {}
-                LocalProtocol::ReverseHttpProxy { .. } => {}
+                    None => None,
+                };
+                let local = tunnel
+                    .access
+                    .bind_tcp_routed(tunnel.local, apps, tunnel.buffers, &tasks)
+                    .await?;
+                listener = Some(BoundListener::new(&tunnel, local.public));
+                let remote_dns = tunnel
+                    .force_remote_dns
+                    .then(|| socks5::RemoteDnsOnly::new(tunnel.id.clone(), stats.clone()));
+                let server = socks5::socks5_listener(
+                    local.internal,
+                    local.public,
+                    *timeout,
+                    auth,
+                    remote_dns,
+                    &tasks,
+                )
+                .await?;
+                Self::proxy_runner(server, &tunnel, stats, &tasks, |reader| reader.client())
+            }
+            LocalProtocol::HttpProxy {
+                timeout,
+                credentials,
+                proxy_protocol,
+            } => {
+                let auth =
+                    listener_auth::validator(tunnel.listener_auth.as_ref(), credentials.as_ref())?;
+                let apps = match AppGate::new(&stats, tunnel.local).await? {
+                    Some((apps, direct)) => {
+                        let direct = http_proxy_listener(
+                            direct,
+                            *timeout,
+                            auth.clone(),
+                            *proxy_protocol,
+                            &tasks,
+                        )?;
+                        app_rules::serve_direct(direct, &tasks);
+                        Some(apps)
+                    }
+                    None => None,
+                };
+                let local = tunnel
+                    .access
+                    .bind_tcp_routed(tunnel.local, apps, tunnel.buffers, &tasks)
+                    .await?;
+                listener = Some(BoundListener::new(&tunnel, local.public));
+                let server =
+                    http_proxy_listener(local.internal, *timeout, auth, *proxy_protocol, &tasks)?;
+                Self::proxy_runner(server, &tunnel, stats, &tasks, |reader| reader.client())
+            }
+            LocalProtocol::Stdio { .. } => {
+                return Err(anyhow!(
+                    "Stdio tunnels can only be started with the profile"
+                ));
+            }
+            LocalProtocol::ReverseTcp
+            | LocalProtocol::ReverseUdp { .. }
+            | LocalProtocol::ReverseSocks5 { .. }
+            | LocalProtocol::ReverseUnix { .. }
+            | LocalProtocol::ReverseHttpProxy { .. } => {
+                return Err(anyhow!("Invalid protocol for local tunnel"));
             }
-        }
-        Ok(())
-    }
-
-    fn mk_http_proxy(
-        http_proxy: Option<String>,
-        proxy_login: Option<String>,
-        proxy_password: Option<String>,
-    ) -> anyhow::Result<Option<Url>> {
-        let Some(proxy) = http_proxy else {
-            return Ok(None);
         };
-
-        let mut proxy = if proxy.starts_with("http://") {
-            Url::parse(&proxy).with_context(|| "Invalid http proxy url")?
-        } else {
-            Url::parse(&format!("http://{}", proxy)).with_context(|| "Invalid http proxy url")?
+        if let (true, Some(listener)) = (tunnel.port_mapping, &listener) {
+            let protocol = match tunnel.local_protocol {
+                LocalProtocol::Udp { .. } => MappingProtocol::Udp,
+                _ => MappingProtocol::Tcp,
+            };
+            port_mapping::keep_mapped(tunnel.id.clone(), protocol, listener.bound, events, &tasks);
+        }
+        let prepared = PreparedTunnel {
+            id: tunnel.id.clone(),
+            reverse: false,
+            runner,
+            tasks,
         };
+        Ok((prepared, listener))
+    }
 
-        if let Some(login) = proxy_login {
-            proxy
-                .set_username(login.as_str())
-                .map_err(|_| anyhow!("Cannot set http proxy login"))?;
+    /// Runner of a proxy tunnel, which only carries the destinations its split tunneling rules leave to it
+    fn proxy_runner<L, R, W>(
+        listener: L,
+        tunnel: &LocalToRemote,
+        stats: Arc<ProfileStats>,
+        tasks: &TaskGroup,
+        client_of: fn(&R) -> ConnectionClient,
+    ) -> TunnelRunner
+    where
+        L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>> + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        match &tunnel.split {
+            Some(rules) => {
+                let listener = split_listener(listener, rules.clone());
+                Self::instrumented_runner_with_client(listener, tunnel, stats, tasks, client_of)
+            }
+            None => {
+                Self::instrumented_runner_with_client(listener, tunnel, stats, tasks, client_of)
+            }
         }
+    }
 
-        if let Some(password) = proxy_password {
-            proxy
-                .set_password(Some(password.as_str()))
-                .map_err(|_| anyhow!("Cannot set http proxy password"))?;
-        }
+    fn instrumented_runner<L, R, W>(
+        listener: L,
+        tunnel: &LocalToRemote,
+        stats: Arc<ProfileStats>,
+        tasks: &TaskGroup,
+    ) -> TunnelRunner
+    where
+        L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>> + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        Self::instrumented_runner_with_client(listener, tunnel, stats, tasks, |_| {
+            ConnectionClient::default()
+        })
+    }
 
-        Ok(Some(proxy))
+    /// Same as `instrumented_runner`, `client_of` telling the client of each connection from its reader
+    fn instrumented_runner_with_client<L, R, W>(
+        listener: L,
+        tunnel: &LocalToRemote,
+        stats: Arc<ProfileStats>,
+        tasks: &TaskGroup,
+        client_of: fn(&R) -> ConnectionClient,
+    ) -> TunnelRunner
+    where
+        L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>> + Send + 'static,
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let listener = concurrency_listener(
+            listener,
+            &tunnel.id,
+            tunnel.max_concurrent_connections,
+            tunnel.connection_overflow,
+        );
+        let on_closed = tunnel
+            .access_log
+            .then(|| stats.access_log.recorder(&tunnel.local_protocol));
+        let listener = track_listener(
+            listener,
+            &tunnel.id,
+            stats.connections.clone(),
+            move |reader: &Limited<R>| client_of(reader.get_ref()),
+            on_closed,
+        );
+        let listener = rate_limit_listener(listener, tunnel.rate_limit_up, tunnel.rate_limit_down);
+        let listener = trace_listener(listener, tunnel.id.clone(), stats.clone());
+        let udp = matches!(
+            tunnel.local_protocol,
+            LocalProtocol::Udp { .. } | LocalProtocol::TProxyUdp { .. }
+        );
+        let listener = capture_listener(listener, &tunnel.id, udp, &stats);
+        let listener = fault_listener(listener, stats.clone());
+        let listener = rotate_host_listener(listener, stats.clone());
+        let listener = meter_listener(listener, &tunnel.id, stats);
+        listener_runner(listener, tunnel.lazy, stats.link.upgrade_timeout, tasks)
     }
 }
 
 #[derive(Debug)]
-struct Client {
+pub struct Client {
     /// Listen on local and forwards traffic from remote. Can be specified multiple times
     /// examples:
     /// 'tcp://1212:google.com:443'      =>       listen locally on tcp on port 1212 and forward to google.com on port 443
@@ -480,7 +1188,10 @@ struct Client {
     /// 'stdio://google.com:443'         =>       listen for data from stdio, mainly for `ssh -o ProxyCommand="wstunnel client -L stdio://%h:%p ws://localhost:8080" my-server`
     ///
     /// 'unix:///tmp/wstunnel.sock:g.com:443' =>  listen for data from unix socket of path /tmp/wstunnel.sock and forward to g.com:443
-    local_to_remote: Vec<LocalToRemote>,
+    ///
+    /// 'npipe://docker_engine:localhost:2375' => listen for data from the windows named pipe \\.\pipe\docker_engine and forward to localhost:2375
+    ///                                           windows only
+    pub local_to_remote: Vec<LocalToRemote>,
 
     /// Listen on remote and forwards traffic from local. Can be specified multiple times. Only tcp is supported
     /// examples:
@@ -489,69 +1200,116 @@ struct Client {
     /// 'socks5://[::1]:1212'            =>     listen on server for incoming socks5 request on port 1212 and forward dynamically request from local machine (login/password is supported)
     /// 'http://[::1]:1212'         =>     listen on server for incoming http proxy request on port 1212 and forward dynamically request from local machine (login/password is supported)
     /// 'unix://wstunnel.sock:g.com:443' =>     listen on server for incoming data from unix socket of path wstunnel.sock and forward to g.com:443 from local machine
-    remote_to_local: Vec<LocalToRemote>,
+    pub remote_to_local: Vec<LocalToRemote>,
 
     /// (linux only) Mark network packet with SO_MARK sockoption with the specified value.
     /// You need to use {root, sudo, capabilities} to run wstunnel when using this option
-    socket_so_mark: Option<u32>,
+    pub socket_so_mark: Option<u32>,
 
     /// Client will maintain a pool of open connection to the server, in order to speed up the connection process.
     /// This option set the maximum number of connection that will be kept open.
     /// This is useful if you plan to create/destroy a lot of tunnel (i.e: with socks5 to navigate with a browser)
     /// It will avoid the latency of doing tcp + tls handshake with the server
-    connection_min_idle: u32,
+    pub connection_min_idle: u32,
+
+    /// Start with an empty pool, the connections to the server being opened by the first local connection
+    pub on_demand: bool,
 
     /// The maximum of time in seconds while we are going to try to connect to the server before failing the connection/tunnel request
-    connection_retry_max_backoff_sec: Duration,
+    pub connection_retry_max_backoff_sec: Duration,
+
+    /// How failing tunnels are restarted, their backoff being capped by `connection_retry_max_backoff_sec`
+    pub retry_policy: RetryPolicy,
+
+    /// Maximum time to establish the tcp connection to the server, or to its http proxy
+    pub connect_timeout_sec: Duration,
+
+    /// Maximum time for the server to answer the http upgrade request, once connected. Unbounded if not set
+    pub upgrade_timeout_sec: Option<Duration>,
 
     /// Domain name that will be used as SNI during TLS handshake
     /// Warning: If you are behind a CDN (i.e: Cloudflare) you must set this domain also in the http HOST header.
     ///          or it will be flagged as fishy and your request rejected
-    tls_sni_override: Option<DnsName<'static>>,
+    pub tls_sni_override: Option<DnsName<'static>>,
 
     /// Disable sending SNI during TLS handshake
     /// Warning: Most reverse proxies rely on it
-    tls_sni_disable: bool,
+    pub tls_sni_disable: bool,
 
     /// Enable TLS certificate verification.
     /// Disabled by default. The client will happily connect to any server with self-signed certificate.
-    tls_verify_certificate: bool,
+    pub tls_verify_certificate: bool,
+    /// Certificates accepted when they are not verified, any when empty
+    pub tls_pinned_certificates: Vec<String>,
 
     /// If set, will use this http proxy to connect to the server
-    http_proxy: Option<String>,
+    pub http_proxy: Option<String>,
 
     /// If set, will use this login to connect to the http proxy. Override the one from --http-proxy
-    http_proxy_login: Option<String>,
+    pub http_proxy_login: Option<String>,
 
     /// If set, will use this password to connect to the http proxy. Override the one from --http-proxy
-    http_proxy_password: Option<String>,
+    pub http_proxy_password: Option<String>,
+
+    /// How to authenticate against the http proxy. NTLM and Negotiate go through a local bridge
+    /// doing the handshake wstunnel cannot do, wstunnel being given the bridge as its proxy
+    pub http_proxy_auth: HttpProxyAuth,
+
+    /// Where to look the http proxy up when it is not set, for every connection
+    pub http_proxy_detection: ProxyDetection,
+
+    /// If set, will use this socks5 proxy to connect to the server, through a loopback http proxy
+    /// bridging the CONNECT requests of wstunnel to it
+    pub socks5_proxy: Option<Url>,
+
+    /// If set, the connection to the server is made by a loopback http proxy with these socket options,
+    /// when no other proxy is used
+    pub upstream_socket: Option<BufferTuning>,
+
+    /// Experimental: if set, the connections to the server go out of several network interfaces through a
+    /// loopback http proxy, when no other proxy is used. The socket options of upstream_socket apply to them
+    pub multipath: Option<Multipath>,
 
     /// Use a specific prefix that will show up in the http path during the upgrade request.
     /// Useful if you need to route requests server side but don't have vhosts
     /// When using mTLS this option overrides the default behavior of using the common name of the
     /// client's certificate. This will likely result in the wstunnel server rejecting the connection.
-    http_upgrade_path_prefix: String,
+    pub http_upgrade_path_prefix: String,
 
     /// Pass authorization header with basic auth credentials during the upgrade request.
     /// If you need more customization, you can use the http_headers option.
-    http_upgrade_credentials: Option<HeaderValue>,
+    pub http_upgrade_credentials: Option<HeaderValue>,
+
+    /// Fetch the token of the authorization header from a file or a command, and keep it fresh while connected.
+    /// It goes through a generated headers file, as wstunnel reads it again for every new connection
+    pub http_upgrade_credentials_provider: Option<CredentialsProvider>,
+
+    /// What to do when the server rejects the upgrade request with a given status, instead of retrying with a backoff
+    pub upgrade_failure_rules: Vec<UpgradeFailureRule>,
 
     /// Frequency at which the client will send websocket pings to the server.
     /// Set to zero to disable.
-    websocket_ping_frequency_sec: Option<Duration>,
+    pub websocket_ping_frequency_sec: Option<Duration>,
 
     /// Enable the masking of websocket frames. Default is false
     /// Enable this option only if you use unsecure (non TLS) websocket server, and you see some issues. Otherwise, it is just overhead.
-    websocket_mask_frame: bool,
+    pub websocket_mask_frame: bool,
 
     /// Send custom headers in the upgrade request
     /// Can be specified multiple time
-    http_headers: Vec<(HeaderName, HeaderValue)>,
+    pub http_headers: Vec<(HeaderName, HeaderValue)>,
 
     /// Send custom headers in the upgrade request reading them from a file.
     /// It overrides http_headers specified from command line.
     /// File is read everytime and file format must contain lines with `HEADER_NAME: HEADER_VALUE`
-    http_headers_file: Option<PathBuf>,
+    pub http_headers_file: Option<PathBuf>,
+
+    /// Compute the Host header of the upgrade request from this template instead of the server address.
+    /// It overrides the Host of http_headers and http_headers_file
+    pub http_host_template: Option<String>,
+
+    /// Hostnames substituted to `{host}` in the template, the next one for every connection
+    pub http_host_rotation: Vec<String>,
 
     /// Address of the wstunnel server
     /// You can either use websocket or http2 as transport protocol. Use websocket if you are unsure.
@@ -564,17 +1322,41 @@ struct Client {
     ///   - if you have wstunnel behind a reverse proxy, most of them (i.e: nginx) are going to turn http2 request into http1
     ///     This is not going to work, because http1 does not support streaming naturally
     ///   - The only way to make it works with http2 is to have wstunnel directly exposed to the internet without any reverse proxy in front of it
-    remote_addr: Url,
+    pub remote_addr: Url,
+
+    /// When using http2 as transport, check that it actually works to reach the server (i.e: no reverse proxy
+    /// buffering requests or downgrading them to http1) and switch to websocket otherwise. Disabled by default
+    pub transport_fallback: bool,
 
     /// [Optional] Certificate (pem) to present to the server when connecting over TLS (HTTPS).
     /// Used when the server requires clients to authenticate themselves with a certificate (i.e. mTLS).
     /// Unless overridden, the HTTP upgrade path will be configured to be the common name (CN) of the certificate.
     /// The certificate will be automatically reloaded if it changes
-    tls_certificate: Option<PathBuf>,
+    pub tls_certificate: Option<PathBuf>,
 
     /// [Optional] The private key for the corresponding certificate used with mTLS.
     /// The certificate will be automatically reloaded if it changes
-    tls_private_key: Option<PathBuf>,
+    pub tls_private_key: Option<PathBuf>,
+
+    /// [Optional] Token or OS keystore signing with the private key of the client certificate, when the key
+    /// cannot be exported to a file. The certificate is taken from it unless tls_certificate is set
+    pub tls_private_key_source: Option<ClientKeySource>,
+
+    /// Warn that many days before the client certificate expires, while connected
+    pub tls_certificate_warning_days: u64,
+
+    /// Oldest and newest versions of TLS offered to the server, every version rustls supports when not set
+    pub tls_min_version: Option<TlsVersion>,
+    pub tls_max_version: Option<TlsVersion>,
+
+    /// Protocols offered in the TLS handshake (ALPN) instead of the ones of the transport
+    pub tls_alpn: Option<Vec<String>>,
+
+    /// Cipher suites and key exchange groups offered in the ClientHello, the rest of it being the one of rustls
+    pub tls_fingerprint: TlsFingerprint,
+
+    /// Renew the client certificate before it expires. wstunnel reloads the renewed files without dropping the tunnels
+    pub tls_certificate_renewal: Option<CertificateRenewal>,
 
     /// Dns resolver to use to lookup ips of domain name. Can be specified multiple time
     /// Example:
@@ -586,17 +1368,60 @@ struct Client {
     /// system://0.0.0.0
     ///
     /// **WARN** On windows you may want to specify explicitly the DNS resolver to avoid excessive DNS queries
-    dns_resolver: Vec<Url>,
+    pub dns_resolver: Vec<Url>,
 
     /// Enable if you prefer the dns resolver to prioritize IPv4 over IPv6
     /// This is useful if you have a broken IPv6 connection, and want to avoid the delay of trying to connect to IPv6
     /// If you don't have any IPv6 this does not change anything.
-    dns_resolver_prefer_ipv4: bool,
+    pub dns_resolver_prefer_ipv4: bool,
+    /// Fall back to the addresses resolved by a previous run when the server name cannot be resolved quickly
+    pub dns_cache: bool,
+    /// Addresses of the names overridden in the profile, used for the server instead of resolving its name
+    pub static_hosts: Vec<(String, IpAddr)>,
+    /// Routing of the local proxy tunnels by the application connecting to them
+    pub app_routing: Option<AppRouting>,
+    /// Other servers to connect to instead of `remote_addr` when they answer faster
+    pub server_candidates: Vec<Url>,
 }
 
 #[derive(Clone, Debug)]
 pub struct LocalToRemote {
-    local_protocol: LocalProtocol,
-    local: SocketAddr,
-    remote: (Host, u16),
+    /// Identify the tunnel within its profile
+    pub id: String,
+    pub local_protocol: LocalProtocol,
+    pub local: SocketAddr,
+    pub remote: (Host, u16),
+    /// Maximum throughput in bytes/sec of the traffic sent through the tunnel. Unlimited if not set
+    pub rate_limit_up: Option<u64>,
+    /// Maximum throughput in bytes/sec of the traffic received from the tunnel. Unlimited if not set
+    pub rate_limit_down: Option<u64>,
+    /// Interface to bind the local listener on and sources allowed to connect to it
+    pub access: AccessPolicy,
+    /// Buffers of the local sockets of tcp based tunnels
+    pub buffers: BufferTuning,
+    /// Wait for the first local connection before starting the tunnel, the listener is bound right away
+    pub lazy: bool,
+    /// Socks5 only, refuse the destinations the application resolved by itself so dns requests do not leak
+    pub force_remote_dns: bool,
+    /// Socks5 and http proxy only, destinations connected directly instead of through the tunnel
+    pub split: Option<SplitRules>,
+    /// Tcp and reverse tcp only, proxy protocol header written by the app ahead of each connection
+    pub proxy_header: Option<ProxyHeader>,
+    /// Ask the router to forward a port of its public address to the local listener
+    pub port_mapping: bool,
+    /// Udp and tproxy udp only, size limit and idle timeout of the datagrams
+    pub datagrams: DatagramOptions,
+    /// Record each connection once closed, to the access log of the app
+    pub access_log: bool,
+    /// Socks5 and http proxy only, users allowed instead of the login of the spec
+    pub listener_auth: Option<ListenerAuth>,
+    /// Connections open at once, unlimited if not set
+    pub max_concurrent_connections: Option<u32>,
+    /// What becomes of the connections over the limit
+    pub connection_overflow: ConnectionOverflow,
+    /// Windows named pipe a npipe tunnel listens on instead of a tcp port, i.e: `\\.\pipe\docker_engine`
+    pub named_pipe: Option<String>,
+    /// Interface of a tun tunnel, capturing the traffic of the whole machine.
+    /// wstunnel has no protocol for it, each flow read from the interface carries its own tcp or udp one
+    pub tun: Option<TunDevice>,
 }
@@ -0,0 +1,584 @@
+use crate::client::placeholders;
+use anyhow::{anyhow, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio_rustls::rustls::client::ResolvesClientCert;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::sign::{CertifiedKey, Signer, SigningKey};
+use tokio_rustls::rustls::{self, SignatureAlgorithm, SignatureScheme};
+use wstunnel::protocols::tls;
+
+/// Where the private key of the client certificate lives, when it cannot be exported to a PEM file (i.e: a smartcard).
+/// The key never leaves it, the TLS handshake asks it to sign.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ClientKeySource {
+    /// Key of a PKCS#11 token, through the module of its vendor (i.e: `/usr/lib/opensc-pkcs11.so`), which has to
+    /// be installed in a system directory. The certificate is read from the token, under the same label, unless
+    /// `tls_certificate` is set.
+    #[serde(rename_all = "camelCase")]
+    Pkcs11 {
+        module: PathBuf,
+        /// Token holding the key, the first one with a key of that label when not set
+        token_label: Option<String>,
+        key_label: String,
+        /// Secret of the keychain holding the PIN, as saved for the `${secret:NAME}` placeholders
+        pin_secret: Option<String>,
+    },
+    /// Identity of the macOS Keychain, by label
+    Keychain { label: String },
+    /// Certificate of the personal store of the Windows user, by SHA-1 thumbprint, with its key in CNG
+    Cng { thumbprint: String },
+}
+
+impl ClientKeySource {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        match self {
+            ClientKeySource::Pkcs11 { module, .. } => pkcs11::allowed_module(module).map(|_| ()),
+            ClientKeySource::Keychain { .. } if cfg!(target_os = "macos") => Ok(()),
+            ClientKeySource::Cng { thumbprint } if cfg!(windows) => {
+                hex_decode(thumbprint).map(|_| ())
+            }
+            ClientKeySource::Keychain { .. } => {
+                Err(anyhow!("The Keychain is only available on macOS"))
+            }
+            ClientKeySource::Cng { .. } => Err(anyhow!("CNG is only available on Windows")),
+        }
+    }
+
+    /// Open the key, along with the certificate chain it goes with
+    pub fn load(&self, certificate: Option<&Path>) -> anyhow::Result<Arc<CertifiedKey>> {
+        let from_file = certificate
+            .map(|path| {
+                tls::load_certificates_from_pem(path)
+                    .with_context(|| "Cannot load client TLS certificate (mTLS)")
+            })
+            .transpose()?;
+        let (chain, key): (Vec<CertificateDer<'static>>, Arc<dyn SigningKey>) = match self {
+            ClientKeySource::Pkcs11 {
+                module,
+                token_label,
+                key_label,
+                pin_secret,
+            } => {
+                let pin = pin_secret
+                    .as_deref()
+                    .map(placeholders::secret)
+                    .transpose()?;
+                blocking(|| {
+                    pkcs11::open(
+                        module,
+                        token_label.as_deref(),
+                        key_label,
+                        pin.as_deref(),
+                        from_file,
+                    )
+                })?
+            }
+            #[cfg(target_os = "macos")]
+            ClientKeySource::Keychain { label } => blocking(|| keychain::open(label, from_file))?,
+            #[cfg(windows)]
+            ClientKeySource::Cng { thumbprint } => blocking(|| cng::open(thumbprint, from_file))?,
+            #[allow(unreachable_patterns)]
+            _ => return Err(anyhow!("Unsupported client key source on this platform")),
+        };
+        info!("Client key loaded from {}", self);
+        Ok(Arc::new(CertifiedKey::new(chain, key)))
+    }
+}
+
+impl fmt::Display for ClientKeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientKeySource::Pkcs11 {
+                module, key_label, ..
+            } => write!(f, "PKCS#11 key {} of {}", key_label, module.display()),
+            ClientKeySource::Keychain { label } => write!(f, "Keychain identity {}", label),
+            ClientKeySource::Cng { thumbprint } => write!(f, "CNG certificate {}", thumbprint),
+        }
+    }
+}
+
+/// Present the client certificate whenever the server asks for one, as a PEM key would
+#[derive(Debug)]
+pub struct ClientIdentity(pub Arc<CertifiedKey>);
+
+impl ResolvesClientCert for ClientIdentity {
+    fn resolve(
+        &self,
+        _root_hint_subjects: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<CertifiedKey>> {
+        self.0.key.choose_scheme(sigschemes).map(|_| self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+const RSA_OID: &str = "1.2.840.113549.1.1.1";
+const EC_OID: &str = "1.2.840.10045.2.1";
+
+/// Kind of key, told by the public key of the certificate, as tokens and keystores sign with each kind differently
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyKind {
+    Rsa,
+    EcP256,
+    EcP384,
+}
+
+impl KeyKind {
+    fn of(chain: &[CertificateDer<'static>]) -> anyhow::Result<Self> {
+        let leaf = tls::find_leaf_certificate(chain)
+            .ok_or_else(|| anyhow!("No client certificate to go with the key"))?;
+        let public_key = leaf.public_key();
+        // Uncompressed points: 1 + 2 * 32 bytes for P-256, 1 + 2 * 48 for P-384
+        match (
+            public_key.algorithm.algorithm.to_id_string().as_str(),
+            public_key.subject_public_key.data.len(),
+        ) {
+            (RSA_OID, _) => Ok(KeyKind::Rsa),
+            (EC_OID, 65) => Ok(KeyKind::EcP256),
+            (EC_OID, 97) => Ok(KeyKind::EcP384),
+            _ => Err(anyhow!(
+                "Unsupported client key, only RSA, P-256 and P-384 keys are"
+            )),
+        }
+    }
+
+    /// Signature schemes the key can sign with, preferred first
+    fn schemes(self) -> &'static [SignatureScheme] {
+        match self {
+            KeyKind::Rsa => &[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA256,
+            ],
+            KeyKind::EcP256 => &[SignatureScheme::ECDSA_NISTP256_SHA256],
+            KeyKind::EcP384 => &[SignatureScheme::ECDSA_NISTP384_SHA384],
+        }
+    }
+
+    fn algorithm(self) -> SignatureAlgorithm {
+        match self {
+            KeyKind::Rsa => SignatureAlgorithm::RSA,
+            KeyKind::EcP256 | KeyKind::EcP384 => SignatureAlgorithm::ECDSA,
+        }
+    }
+}
+
+/// A key signing in a token or a keystore
+trait KeyBackend: Send + Sync + fmt::Debug {
+    fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+#[derive(Debug)]
+struct ExternalKey {
+    kind: KeyKind,
+    backend: Arc<dyn KeyBackend>,
+}
+
+impl SigningKey for ExternalKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        let scheme = self
+            .kind
+            .schemes()
+            .iter()
+            .find(|scheme| offered.contains(scheme))?;
+        Some(Box::new(ExternalSigner {
+            scheme: *scheme,
+            backend: self.backend.clone(),
+        }))
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        self.kind.algorithm()
+    }
+}
+
+#[derive(Debug)]
+struct ExternalSigner {
+    scheme: SignatureScheme,
+    backend: Arc<dyn KeyBackend>,
+}
+
+impl Signer for ExternalSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, rustls::Error> {
+        blocking(|| self.backend.sign(self.scheme, message)).map_err(|err| {
+            rustls::Error::General(format!("Cannot sign with client key: {:#}", err))
+        })
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        self.scheme
+    }
+}
+
+/// Run a call to a token or a keystore, which may wait for the device or for the user. rustls signs from within the
+/// handshake, where nothing can be awaited: the worker thread hands its other tasks over to the rest of the runtime
+/// for the time of the call, as `spawn_blocking` would
+fn blocking<T>(call: impl FnOnce() -> T) -> T {
+    match tokio::runtime::Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == tokio::runtime::RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(call)
+        }
+        _ => call(),
+    }
+}
+
+fn hex_decode(hex: &str) -> anyhow::Result<Vec<u8>> {
+    let hex: String = hex
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    if hex.len() % 2 != 0 {
+        return Err(anyhow!("Invalid thumbprint {}", hex));
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| anyhow!("Invalid thumbprint {}", hex))
+        })
+        .collect()
+}
+
+mod pkcs11 {
+    use super::{ExternalKey, KeyBackend, KeyKind};
+    use anyhow::{anyhow, Context};
+    use cryptoki::context::{CInitializeArgs, Pkcs11};
+    use cryptoki::error::{Error, RvError};
+    use cryptoki::mechanism::rsa::{PkcsMgfType, PkcsPssParams};
+    use cryptoki::mechanism::{Mechanism, MechanismType};
+    use cryptoki::object::{Attribute, AttributeType, ObjectClass, ObjectHandle};
+    use cryptoki::session::{Session, UserType};
+    use cryptoki::slot::Slot;
+    use cryptoki::types::AuthPin;
+    use parking_lot::Mutex;
+    use sha2::{Digest, Sha256, Sha384};
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+    use tokio_rustls::rustls::sign::SigningKey;
+    use tokio_rustls::rustls::SignatureScheme;
+
+    /// Session of the token, which only signs one message at a time
+    struct TokenKey {
+        session: Mutex<Session>,
+        key: ObjectHandle,
+    }
+
+    impl std::fmt::Debug for TokenKey {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("TokenKey").finish_non_exhaustive()
+        }
+    }
+
+    impl KeyBackend for TokenKey {
+        fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+            let session = self.session.lock();
+            let signature = match scheme {
+                SignatureScheme::RSA_PSS_SHA256 => session.sign(
+                    &Mechanism::Sha256RsaPkcsPss(PkcsPssParams {
+                        hash_alg: MechanismType::SHA256,
+                        mgf: PkcsMgfType::MGF1_SHA256,
+                        s_len: 32.into(),
+                    }),
+                    self.key,
+                    message,
+                )?,
+                SignatureScheme::RSA_PKCS1_SHA256 => {
+                    session.sign(&Mechanism::Sha256RsaPkcs, self.key, message)?
+                }
+                // Tokens sign the digest with ECDSA, and give the raw r || s of the signature
+                SignatureScheme::ECDSA_NISTP256_SHA256 => der_signature(&session.sign(
+                    &Mechanism::Ecdsa,
+                    self.key,
+                    &Sha256::digest(message),
+                )?),
+                SignatureScheme::ECDSA_NISTP384_SHA384 => der_signature(&session.sign(
+                    &Mechanism::Ecdsa,
+                    self.key,
+                    &Sha384::digest(message),
+                )?),
+                _ => return Err(anyhow!("Unsupported signature scheme {:?}", scheme)),
+            };
+            Ok(signature)
+        }
+    }
+
+    /// Directories the packages of the OS and of the token vendors install the modules in. A module is code run by
+    /// the app, a profile cannot have it loaded from anywhere else (i.e: the downloads of the user)
+    #[cfg(target_os = "linux")]
+    fn module_dirs() -> Vec<PathBuf> {
+        [
+            "/usr/lib",
+            "/usr/lib64",
+            "/usr/local/lib",
+            "/lib",
+            "/lib64",
+            "/opt",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect()
+    }
+
+    #[cfg(target_os = "macos")]
+    fn module_dirs() -> Vec<PathBuf> {
+        [
+            "/usr/lib",
+            "/usr/local/lib",
+            "/opt/homebrew/lib",
+            "/Library",
+        ]
+        .into_iter()
+        .map(PathBuf::from)
+        .collect()
+    }
+
+    #[cfg(windows)]
+    fn module_dirs() -> Vec<PathBuf> {
+        let system =
+            std::env::var_os("SystemRoot").map(|root| PathBuf::from(root).join("System32"));
+        ["ProgramFiles", "ProgramFiles(x86)"]
+            .into_iter()
+            .filter_map(std::env::var_os)
+            .map(PathBuf::from)
+            .chain(system)
+            .collect()
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    fn module_dirs() -> Vec<PathBuf> {
+        vec![]
+    }
+
+    /// Path of the module once its links are resolved, when it lives in one of the system directories
+    pub fn allowed_module(module: &Path) -> anyhow::Result<PathBuf> {
+        let resolved = module
+            .canonicalize()
+            .with_context(|| format!("Cannot find PKCS#11 module {}", module.display()))?;
+        let allowed = module_dirs()
+            .iter()
+            .filter_map(|dir| dir.canonicalize().ok())
+            .any(|dir| resolved.starts_with(dir));
+        if !allowed {
+            return Err(anyhow!(
+                "PKCS#11 module {} is not installed in a system directory",
+                module.display()
+            ));
+        }
+        Ok(resolved)
+    }
+
+    pub fn open(
+        module: &Path,
+        token_label: Option<&str>,
+        key_label: &str,
+        pin: Option<&str>,
+        certificate: Option<Vec<CertificateDer<'static>>>,
+    ) -> anyhow::Result<(Vec<CertificateDer<'static>>, Arc<dyn SigningKey>)> {
+        // Checked again once the link is resolved, the one given may have changed since the profile was validated
+        let module = allowed_module(module)?;
+        let pkcs11 = Pkcs11::new(&module)
+            .with_context(|| format!("Cannot load PKCS#11 module {}", module.display()))?;
+        // The module stays initialized once a profile used it
+        match pkcs11.initialize(CInitializeArgs::OsThreads) {
+            Ok(()) | Err(Error::Pkcs11(RvError::CryptokiAlreadyInitialized, _)) => {}
+            Err(err) => return Err(err).with_context(|| "Cannot initialize PKCS#11 module"),
+        }
+
+        let label = Attribute::Label(key_label.as_bytes().to_vec());
+        let mut found = None;
+        for slot in pkcs11.get_slots_with_token()? {
+            if !token_matches(&pkcs11, slot, token_label) {
+                continue;
+            }
+            let session = pkcs11.open_ro_session(slot)?;
+            if let Some(pin) = pin {
+                session
+                    .login(UserType::User, Some(&AuthPin::new(pin.into())))
+                    .with_context(|| "Cannot log in the PKCS#11 token")?;
+            }
+            let keys = session
+                .find_objects(&[Attribute::Class(ObjectClass::PRIVATE_KEY), label.clone()])?;
+            if let Some(key) = keys.first().copied() {
+                found = Some((session, key));
+                break;
+            }
+        }
+        let (session, key) =
+            found.ok_or_else(|| anyhow!("No PKCS#11 private key labeled {}", key_label))?;
+
+        let chain = match certificate {
+            Some(chain) => chain,
+            None => {
+                let certificate = session
+                    .find_objects(&[Attribute::Class(ObjectClass::CERTIFICATE), label])?
+                    .first()
+                    .copied()
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "No PKCS#11 certificate labeled {}, set the client certificate of the profile",
+                            key_label
+                        )
+                    })?;
+                let der = session
+                    .get_attributes(certificate, &[AttributeType::Value])?
+                    .into_iter()
+                    .find_map(|attribute| match attribute {
+                        Attribute::Value(der) => Some(der),
+                        _ => None,
+                    })
+                    .ok_or_else(|| anyhow!("Empty PKCS#11 certificate {}", key_label))?;
+                vec![CertificateDer::from(der)]
+            }
+        };
+        let key = ExternalKey {
+            kind: KeyKind::of(&chain)?,
+            backend: Arc::new(TokenKey {
+                session: Mutex::new(session),
+                key,
+            }),
+        };
+        Ok((chain, Arc::new(key)))
+    }
+
+    fn token_matches(pkcs11: &Pkcs11, slot: Slot, token_label: Option<&str>) -> bool {
+        let Some(token_label) = token_label else {
+            return true;
+        };
+        pkcs11
+            .get_token_info(slot)
+            .is_ok_and(|info| info.label().trim() == token_label)
+    }
+
+    /// DER encoding of an ECDSA signature given as r || s, as TLS expects it
+    fn der_signature(raw: &[u8]) -> Vec<u8> {
+        let (r, s) = raw.split_at(raw.len() / 2);
+        let integer = |value: &[u8]| {
+            let value = match value.iter().position(|b| *b != 0) {
+                Some(start) => &value[start..],
+                None => &[0u8][..],
+            };
+            let mut der = vec![0x02];
+            // Integers are signed, a leading bit set needs a zero before it
+            if value[0] & 0x80 != 0 {
+                der.push(value.len() as u8 + 1);
+                der.push(0);
+            } else {
+                der.push(value.len() as u8);
+            }
+            der.extend_from_slice(value);
+            der
+        };
+        let (r, s) = (integer(r), integer(s));
+        let mut der = vec![0x30, (r.len() + s.len()) as u8];
+        der.extend(r);
+        der.extend(s);
+        der
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod keychain {
+    use super::{ExternalKey, KeyBackend, KeyKind};
+    use anyhow::anyhow;
+    use security_framework::item::{ItemClass, ItemSearchOptions, Reference, SearchResult};
+    use security_framework::key::{Algorithm, SecKey};
+    use std::sync::Arc;
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+    use tokio_rustls::rustls::sign::SigningKey;
+    use tokio_rustls::rustls::SignatureScheme;
+
+    #[derive(Debug)]
+    struct KeychainKey(SecKey);
+
+    impl KeyBackend for KeychainKey {
+        fn sign(&self, scheme: SignatureScheme, message: &[u8]) -> anyhow::Result<Vec<u8>> {
+            // The Keychain gives ECDSA signatures DER encoded already
+            let algorithm = match scheme {
+                SignatureScheme::RSA_PSS_SHA256 => Algorithm::RSASignatureMessagePSSSHA256,
+                SignatureScheme::RSA_PKCS1_SHA256 => Algorithm::RSASignatureMessagePKCS1v15SHA256,
+                SignatureScheme::ECDSA_NISTP256_SHA256 => {
+                    Algorithm::ECDSASignatureMessageX962SHA256
+                }
+                SignatureScheme::ECDSA_NISTP384_SHA384 => {
+                    Algorithm::ECDSASignatureMessageX962SHA384
+                }
+                _ => return Err(anyhow!("Unsupported signature scheme {:?}", scheme)),
+            };
+            self.0
+                .create_signature(algorithm, message)
+                .map_err(|err| anyhow!("Keychain refused to sign: {}", err))
+        }
+    }
+
+    pub fn open(
+        label: &str,
+        certificate: Option<Vec<CertificateDer<'static>>>,
+    ) -> anyhow::Result<(Vec<CertificateDer<'static>>, Arc<dyn SigningKey>)> {
+        let results = ItemSearchOptions::new()
+            .class(ItemClass::identity())
+            .label(label)
+            .load_refs(true)
+            .search()
+            .map_err(|err| anyhow!("Cannot search the Keychain: {}", err))?;
+        let identity = results
+            .into_iter()
+            .find_map(|result| match result {
+                SearchResult::Ref(Reference::Identity(identity)) => Some(identity),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow!("No Keychain identity labeled {}", label))?;
+
+        let chain = match certificate {
+            Some(chain) => chain,
+            None => vec![CertificateDer::from(identity.certificate()?.to_der())],
+        };
+        let key = ExternalKey {
+            kind: KeyKind::of(&chain)?,
+            backend: Arc::new(KeychainKey(identity.private_key()?)),
+        };
+        Ok((chain, Arc::new(key)))
+    }
+}
+
+#[cfg(windows)]
+mod cng {
+    use super::hex_decode;
+    use anyhow::{anyhow, Context};
+    use rustls_cng::signer::CngSigningKey;
+    use rustls_cng::store::{CertStore, CertStoreType};
+    use std::sync::Arc;
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+    use tokio_rustls::rustls::sign::SigningKey;
+
+    pub fn open(
+        thumbprint: &str,
+        certificate: Option<Vec<CertificateDer<'static>>>,
+    ) -> anyhow::Result<(Vec<CertificateDer<'static>>, Arc<dyn SigningKey>)> {
+        let store = CertStore::open(CertStoreType::CurrentUser, "my")
+            .with_context(|| "Cannot open the certificate store of the user")?;
+        let context = store
+            .find_by_sha1(hex_decode(thumbprint)?)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("No certificate with thumbprint {}", thumbprint))?;
+        let key = CngSigningKey::new(context.acquire_key()?)?;
+
+        let chain = match certificate {
+            Some(chain) => chain,
+            None => context
+                .as_chain_der()?
+                .into_iter()
+                .map(CertificateDer::from)
+                .collect(),
+        };
+        Ok((chain, Arc::new(key)))
+    }
+}
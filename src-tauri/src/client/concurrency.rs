@@ -0,0 +1,155 @@
+use anyhow::anyhow;
+use futures_util::{future, Stream, StreamExt};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use wstunnel::tunnel::RemoteAddr;
+
+const DEFAULT_QUEUE_TIMEOUT_SEC: u64 = 10;
+/// Connections of a tunnel waiting for a slot at once, past which the listener stops accepting
+const QUEUED_CONNECTIONS: usize = 256;
+
+/// What becomes of a connection accepted while the tunnel already has as many as it allows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ConnectionOverflow {
+    /// Closed right away
+    Reject,
+    /// Held until another connection of the tunnel closes, and closed if none does in time
+    #[serde(rename_all = "camelCase")]
+    Queue { timeout_sec: u64 },
+}
+
+impl Default for ConnectionOverflow {
+    fn default() -> Self {
+        ConnectionOverflow::Queue {
+            timeout_sec: DEFAULT_QUEUE_TIMEOUT_SEC,
+        }
+    }
+}
+
+impl ConnectionOverflow {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let ConnectionOverflow::Queue { timeout_sec: 0 } = self {
+            return Err(anyhow!(
+                "The queue timeout of the connections must be at least a second"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Half of a connection holding its slot in the tunnel, until both halves are dropped
+pub struct Limited<S> {
+    inner: S,
+    _permit: Option<Arc<OwnedSemaphorePermit>>,
+}
+
+impl<S> Limited<S> {
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Limited<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Limited<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Cap the connections of a tunnel open at once, so a misbehaving application cannot open thousands of streams
+/// to the server. The connections over the limit are dropped, or wait for a slot, before reaching the tunnel.
+/// Waiting connections do not hold up the others: the listener keeps accepting while up to `QUEUED_CONNECTIONS`
+/// of them wait. Without limit, the connections are passed through untouched.
+pub fn concurrency_listener<L, R, W>(
+    listener: L,
+    tunnel_id: &str,
+    max_connections: Option<u32>,
+    overflow: ConnectionOverflow,
+) -> impl Stream<Item = anyhow::Result<((Limited<R>, Limited<W>), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let semaphore = max_connections.map(|max| Arc::new(Semaphore::new(max as usize)));
+    let tunnel_id = tunnel_id.to_string();
+    listener
+        .map(move |item| {
+            let semaphore = semaphore.clone();
+            let tunnel_id = tunnel_id.clone();
+            async move {
+                let ((reader, writer), remote) = match item {
+                    Ok(item) => item,
+                    Err(err) => return Some(Err(err)),
+                };
+                let permit = match semaphore {
+                    None => None,
+                    Some(semaphore) => {
+                        let permit = match overflow {
+                            ConnectionOverflow::Reject => semaphore.try_acquire_owned().ok(),
+                            ConnectionOverflow::Queue { timeout_sec } => tokio::time::timeout(
+                                Duration::from_secs(timeout_sec),
+                                semaphore.acquire_owned(),
+                            )
+                            .await
+                            .ok()
+                            .and_then(Result::ok),
+                        };
+                        if permit.is_none() {
+                            // Dropping the halves closes the local connection
+                            warn!(
+                                "Tunnel {} has too many connections, closing the one to {}:{}",
+                                tunnel_id, remote.host, remote.port
+                            );
+                            return None;
+                        }
+                        permit.map(Arc::new)
+                    }
+                };
+                Some(Ok((
+                    (
+                        Limited {
+                            inner: reader,
+                            _permit: permit.clone(),
+                        },
+                        Limited {
+                            inner: writer,
+                            _permit: permit,
+                        },
+                    ),
+                    remote,
+                )))
+            }
+        })
+        .buffer_unordered(QUEUED_CONNECTIONS)
+        .filter_map(future::ready)
+}
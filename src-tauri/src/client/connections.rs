@@ -0,0 +1,313 @@
+use futures_util::{Stream, StreamExt};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll, Waker};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use wstunnel::tunnel::RemoteAddr;
+
+/// Ids are unique across profiles, so a connection can be killed by its id alone
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A connection relayed by a tunnel, as shown to the user. Local tunnels list the client connected to their
+/// listener when known, reverse tunnels the local destination they connected to
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveConnection {
+    pub connection_id: u64,
+    pub tunnel_id: String,
+    /// Client of the connection, when known. wstunnel servers do not tell the client of a reverse tunnel
+    pub peer: Option<String>,
+    /// Login the client authenticated with, on the proxy tunnels requiring one
+    pub user: Option<String>,
+    pub destination: String,
+    /// Unix timestamp in milliseconds
+    pub started_at_ms: u128,
+    pub duration_ms: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    connection_id: u64,
+    tunnel_id: String,
+    peer: Option<String>,
+    user: Option<String>,
+    destination: String,
+    started_at_ms: u128,
+    started: Instant,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    killed: AtomicBool,
+    /// Tasks polling the reader and the writer, woken up when the connection is killed
+    wakers: Mutex<[Option<Waker>; 2]>,
+}
+
+impl Entry {
+    fn kill(&self) {
+        self.killed.store(true, Ordering::Relaxed);
+        for waker in self.wakers.lock().iter_mut() {
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Fail the half once the connection has been killed, otherwise remember who to wake up if it is
+    fn check(&self, half: usize, cx: &Context<'_>) -> io::Result<()> {
+        if self.killed.load(Ordering::Relaxed) {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Connection killed",
+            ));
+        }
+        let mut wakers = self.wakers.lock();
+        if !wakers[half]
+            .as_ref()
+            .is_some_and(|waker| waker.will_wake(cx.waker()))
+        {
+            wakers[half] = Some(cx.waker().clone());
+        }
+        Ok(())
+    }
+
+    fn snapshot(&self) -> ActiveConnection {
+        ActiveConnection {
+            connection_id: self.connection_id,
+            tunnel_id: self.tunnel_id.clone(),
+            peer: self.peer.clone(),
+            user: self.user.clone(),
+            destination: self.destination.clone(),
+            started_at_ms: self.started_at_ms,
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            bytes_up: self.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.bytes_down.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Live connections of the tunnels of a profile
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    live: Mutex<HashMap<u64, Arc<Entry>>>,
+}
+
+impl ConnectionRegistry {
+    /// Register a new connection, it stays listed until both of its halves are dropped.
+    /// `closed` is called with the last snapshot of the connection once it is.
+    pub fn open<R, W>(
+        self: &Arc<Self>,
+        tunnel_id: &str,
+        client: ConnectionClient,
+        destination: String,
+        (reader, writer): (R, W),
+        closed: impl FnOnce(ActiveConnection) + Send + Sync + 'static,
+    ) -> (ActiveConnection, (Tracked<R>, Tracked<W>)) {
+        let entry = Arc::new(Entry {
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            tunnel_id: tunnel_id.to_string(),
+            peer: client.peer.map(|peer| peer.to_string()),
+            user: client.user,
+            destination,
+            started_at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default(),
+            started: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            killed: AtomicBool::new(false),
+            wakers: Mutex::default(),
+        });
+        self.live.lock().insert(entry.connection_id, entry.clone());
+        let guard = Arc::new(CloseGuard {
+            registry: self.clone(),
+            entry: entry.clone(),
+            closed: Mutex::new(Some(Box::new(closed))),
+        });
+        let tracked = (
+            Tracked {
+                inner: Box::pin(reader),
+                entry: entry.clone(),
+                half: 0,
+                _guard: guard.clone(),
+            },
+            Tracked {
+                inner: Box::pin(writer),
+                entry: entry.clone(),
+                half: 1,
+                _guard: guard,
+            },
+        );
+        (entry.snapshot(), tracked)
+    }
+
+    /// Live connections of a tunnel, or of every tunnel, the oldest first
+    pub fn list(&self, tunnel_id: Option<&str>) -> Vec<ActiveConnection> {
+        let mut connections: Vec<_> = self
+            .live
+            .lock()
+            .values()
+            .filter(|entry| !tunnel_id.is_some_and(|id| entry.tunnel_id != id))
+            .map(|entry| entry.snapshot())
+            .collect();
+        connections.sort_by_key(|connection| connection.connection_id);
+        connections
+    }
+
+    /// Drop a connection without touching the other ones of its tunnel, false when it is not one of this registry
+    pub fn kill(&self, connection_id: u64) -> bool {
+        match self.live.lock().get(&connection_id) {
+            Some(entry) => {
+                entry.kill();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Who opened a connection, as far as the listener of its tunnel knows
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionClient {
+    pub peer: Option<SocketAddr>,
+    /// Login the client authenticated with to a proxy listener
+    pub user: Option<String>,
+}
+
+/// Reading side of a connection accepted by a listener knowing its client, which a boxed reader would hide
+pub struct ClientReader<R> {
+    inner: R,
+    client: ConnectionClient,
+}
+
+impl<R> ClientReader<R> {
+    pub fn new(inner: R, client: ConnectionClient) -> Self {
+        Self { inner, client }
+    }
+
+    pub fn client(&self) -> ConnectionClient {
+        self.client.clone()
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ClientReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+/// Register every connection accepted by a local tunnel listener, `client_of` telling the client from the reader.
+/// `on_closed` is given each connection once closed, for the tunnels logging their accesses
+pub fn track_listener<L, R, W>(
+    listener: L,
+    tunnel_id: &str,
+    registry: Arc<ConnectionRegistry>,
+    client_of: impl Fn(&R) -> ConnectionClient,
+    on_closed: Option<Arc<dyn Fn(ActiveConnection) + Send + Sync>>,
+) -> impl Stream<Item = anyhow::Result<((Tracked<R>, Tracked<W>), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+{
+    let tunnel_id = tunnel_id.to_string();
+    listener.map(move |item| {
+        item.map(|((reader, writer), remote)| {
+            let client = client_of(&reader);
+            let destination = format!("{}:{}", remote.host, remote.port);
+            let on_closed = on_closed.clone();
+            let (_, streams) = registry.open(
+                &tunnel_id,
+                client,
+                destination,
+                (reader, writer),
+                move |connection| {
+                    if let Some(on_closed) = on_closed {
+                        on_closed(connection);
+                    }
+                },
+            );
+            (streams, remote)
+        })
+    })
+}
+
+type OnClosed = Box<dyn FnOnce(ActiveConnection) + Send + Sync>;
+
+/// Shared by both halves of a connection, it unregisters the connection once they are both dropped
+struct CloseGuard {
+    registry: Arc<ConnectionRegistry>,
+    entry: Arc<Entry>,
+    closed: Mutex<Option<OnClosed>>,
+}
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        self.registry.live.lock().remove(&self.entry.connection_id);
+        if let Some(closed) = self.closed.lock().take() {
+            closed(self.entry.snapshot());
+        }
+    }
+}
+
+/// Half of a registered connection, counting the bytes it reads (sent up) or writes (sent down).
+/// Both halves fail once the connection is killed.
+pub struct Tracked<S> {
+    inner: Pin<Box<S>>,
+    entry: Arc<Entry>,
+    half: usize,
+    _guard: Arc<CloseGuard>,
+}
+
+impl<S: AsyncRead> AsyncRead for Tracked<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.entry.check(this.half, cx)?;
+        let before = buf.filled().len();
+        ready!(this.inner.as_mut().poll_read(cx, buf))?;
+        this.entry
+            .bytes_up
+            .fetch_add((buf.filled().len() - before) as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.entry.check(this.half, cx)?;
+        let written = ready!(this.inner.as_mut().poll_write(cx, buf))?;
+        this.entry
+            .bytes_down
+            .fetch_add(written as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.entry.check(this.half, cx)?;
+        this.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.as_mut().poll_shutdown(cx)
+    }
+}
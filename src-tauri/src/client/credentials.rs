@@ -0,0 +1,263 @@
+use crate::client::stats::ProfileStats;
+use crate::parsers;
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const FILE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// A token is renewed that long before it expires
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+static NEXT_HEADERS_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Where the token sent in the upgrade request comes from, for servers requiring short-lived credentials (i.e: a JWT)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CredentialsSource {
+    /// File holding the token, renewed by another program
+    File { path: PathBuf },
+    /// Command printing a fresh token on its standard output, program first
+    Command { command: Vec<String> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialsProvider {
+    pub source: CredentialsSource,
+    /// Authorization scheme the token is sent with
+    #[serde(default = "default_scheme")]
+    pub scheme: String,
+    /// How often the command is run again when the token does not tell when it expires
+    #[serde(default = "default_refresh_interval_sec")]
+    pub refresh_interval_sec: u64,
+}
+
+fn default_scheme() -> String {
+    "Bearer".to_string()
+}
+
+fn default_refresh_interval_sec() -> u64 {
+    300
+}
+
+impl CredentialsProvider {
+    pub fn fetch(&self) -> anyhow::Result<String> {
+        let token = match &self.source {
+            CredentialsSource::File { path } => std::fs::read_to_string(path)
+                .with_context(|| format!("Cannot read token from {}", path.display()))?,
+            CredentialsSource::Command { command } => {
+                let (program, args) = command
+                    .split_first()
+                    .ok_or_else(|| anyhow!("Empty token refresh command"))?;
+                let output = Command::new(program)
+                    .args(args)
+                    .output()
+                    .with_context(|| format!("Cannot execute {}", program))?;
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "Token refresh command failed: {}",
+                        String::from_utf8_lossy(&output.stderr).trim()
+                    ));
+                }
+                String::from_utf8(output.stdout)
+                    .with_context(|| "Token refresh command printed an invalid token")?
+            }
+        };
+
+        let token = token.trim();
+        if token.is_empty() || token.contains(['\r', '\n']) {
+            return Err(anyhow!("Invalid token, expected a single line"));
+        }
+        Ok(token.to_string())
+    }
+
+    /// Time to wait before fetching the token again
+    fn next_refresh(&self, token: &str) -> Duration {
+        if let CredentialsSource::File { .. } = self.source {
+            return FILE_POLL_INTERVAL;
+        }
+        let interval = Duration::from_secs(self.refresh_interval_sec);
+        let Some(expiry) = jwt_expiry(token) else {
+            return interval.max(MIN_REFRESH_INTERVAL);
+        };
+        expiry
+            .checked_sub(EXPIRY_MARGIN)
+            .and_then(|refresh_at| refresh_at.duration_since(SystemTime::now()).ok())
+            .unwrap_or_default()
+            .min(interval)
+            .max(MIN_REFRESH_INTERVAL)
+    }
+}
+
+/// Expiry of a JWT, taken from its `exp` claim
+fn jwt_expiry(token: &str) -> Option<SystemTime> {
+    let payload = token.split('.').nth(1)?;
+    let payload = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(payload.trim_end_matches('='))
+        .ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&payload).ok()?;
+    let exp = claims.get("exp")?.as_u64()?;
+    Some(UNIX_EPOCH + Duration::from_secs(exp))
+}
+
+/// Headers file given to wstunnel in place of the configured one, which it reads again for every new connection.
+/// It holds the configured headers along with the latest token, so each new connection authenticates with a fresh one.
+pub struct CredentialsFile {
+    path: PathBuf,
+    headers_file: Option<PathBuf>,
+}
+
+impl CredentialsFile {
+    pub fn new(headers_file: Option<PathBuf>) -> anyhow::Result<Self> {
+        let path = private_dir()?.join(format!(
+            "upgrade-headers-{}-{}",
+            std::process::id(),
+            NEXT_HEADERS_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        Ok(Self { path, headers_file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&self, scheme: &str, token: &str) -> anyhow::Result<()> {
+        let mut content = String::new();
+        if let Some(headers_file) = &self.headers_file {
+            for (name, value) in parsers::read_headers_file(headers_file)? {
+                if name != "authorization" {
+                    content.push_str(&format!("{}: {}\n", name, value.to_str()?));
+                }
+            }
+        }
+        content.push_str(&format!("Authorization: {} {}\n", scheme, token));
+
+        // Write then rename, so wstunnel never reads a partial file
+        let tmp = self.path.with_extension("tmp");
+        // Left behind by a write which failed midway
+        let _ = std::fs::remove_file(&tmp);
+        write_private(&tmp, &content)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
+
+impl Drop for CredentialsFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Directory of the files handed to wstunnel, reachable by the user only. The temp dir of unix is shared by every
+/// user, who may have created the directory first: it is only used when it belongs to the user and nobody else can
+/// enter it
+#[cfg(unix)]
+pub fn private_dir() -> anyhow::Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+
+    // SAFETY: getuid cannot fail
+    let uid = unsafe { libc::getuid() };
+    let dir = std::env::temp_dir().join(format!("wstunnel-desktop-{}", uid));
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(err) => {
+            return Err(err).with_context(|| format!("Cannot create {}", dir.display()));
+        }
+    }
+    let metadata = std::fs::symlink_metadata(&dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.permissions().mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "{} is not a private directory of this user",
+            dir.display()
+        ));
+    }
+    Ok(dir)
+}
+
+/// Directory of the files handed to wstunnel, in the temp dir of the user
+#[cfg(not(unix))]
+pub fn private_dir() -> anyhow::Result<PathBuf> {
+    let dir = std::env::temp_dir().join("wstunnel-desktop");
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+/// Write a new file only the user can read, failing when `path` already exists rather than following a link
+#[cfg(unix)]
+pub fn write_private(path: &Path, content: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Cannot create {}", path.display()))?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Write a new file, failing when `path` already exists. The temp dir of the user is not shared on Windows
+#[cfg(not(unix))]
+pub fn write_private(path: &Path, content: &str) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Cannot create {}", path.display()))?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
+/// Keep the token of the credentials file fresh for as long as the profile is connected
+pub fn spawn_refresher(
+    provider: CredentialsProvider,
+    file: CredentialsFile,
+    token: String,
+    stats: &Arc<ProfileStats>,
+) {
+    let stale = stats.credentials_stale.clone();
+    let stats: Weak<ProfileStats> = Arc::downgrade(stats);
+    tokio::spawn(async move {
+        let mut token = token;
+        loop {
+            // Fetched early when the server rejected the token
+            tokio::select! {
+                _ = tokio::time::sleep(provider.next_refresh(&token)) => {}
+                _ = stale.notified() => debug!("Upgrade token rejected, fetching a new one"),
+            }
+            if stats.upgrade().is_none() {
+                return;
+            }
+
+            let fetcher = provider.clone();
+            let fetched = tokio::task::spawn_blocking(move || fetcher.fetch())
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|res| res);
+            match fetched {
+                Ok(fresh) if fresh == token => debug!("Upgrade token unchanged"),
+                Ok(fresh) => match file.write(&provider.scheme, &fresh) {
+                    Ok(()) => {
+                        info!("Upgrade token refreshed");
+                        token = fresh;
+                    }
+                    Err(err) => error!("Cannot write refreshed upgrade token: {:?}", err),
+                },
+                // The current token is kept, it may still be valid
+                Err(err) => error!("Cannot refresh upgrade token: {:?}", err),
+            }
+        }
+    });
+}
@@ -0,0 +1,306 @@
+use crate::client::stats::TunnelMetrics;
+use anyhow::anyhow;
+use futures_util::{Stream, StreamExt};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use wstunnel::tunnel::RemoteAddr;
+
+/// Smallest datagram size a tunnel can be limited to, below it not even a dns query fits
+const MIN_DATAGRAM_SIZE: usize = 64;
+/// Largest payload of an ipv4 udp datagram
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+/// A flow idle for longer than its timeout is closed within that time, the timeout being changed on the fly
+const TIMEOUT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What becomes of a datagram larger than the maximum size of its tunnel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizedDatagrams {
+    /// Dropped and counted, so an application probing the path MTU, like WireGuard, settles on a size that fits
+    #[default]
+    Drop,
+    /// Relayed anyway and counted, leaving it to the network to fragment it
+    Fragment,
+}
+
+/// Datagram handling of a udp tunnel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatagramOptions {
+    /// Largest datagram relayed in either direction, in bytes. Unlimited when not set
+    pub max_datagram_size: Option<usize>,
+    #[serde(default)]
+    pub oversized: OversizedDatagrams,
+    /// Seconds a local client can stay idle before its flow is closed, 0 to never close it.
+    /// Replaces the `timeout_sec` option of the spec, and can be changed while the profile is connected
+    pub timeout_sec: Option<u64>,
+}
+
+impl DatagramOptions {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if let Some(size) = self.max_datagram_size {
+            if !(MIN_DATAGRAM_SIZE..=MAX_DATAGRAM_SIZE).contains(&size) {
+                return Err(anyhow!(
+                    "The maximum datagram size must be between {} and {} bytes",
+                    MIN_DATAGRAM_SIZE,
+                    MAX_DATAGRAM_SIZE
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Settings of a running udp tunnel, shared by its flows
+#[derive(Debug)]
+pub struct DatagramSettings {
+    options: DatagramOptions,
+    /// 0 when the flows are never closed
+    timeout_sec: AtomicU64,
+}
+
+impl DatagramSettings {
+    fn is_oversized(&self, size: usize) -> bool {
+        self.options.max_datagram_size.is_some_and(|max| size > max)
+    }
+
+    fn drops_oversized(&self) -> bool {
+        self.options.oversized == OversizedDatagrams::Drop
+    }
+}
+
+/// Datagram handling of a udp tunnel, as running
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DatagramStatus {
+    pub max_datagram_size: Option<usize>,
+    pub oversized: OversizedDatagrams,
+    pub timeout_sec: u64,
+    /// Datagrams larger than the maximum size, dropped or fragmented depending on `oversized`
+    pub oversized_datagrams: u64,
+}
+
+/// Settings of the udp tunnels of a profile, by tunnel id
+#[derive(Debug, Default)]
+pub struct DatagramRegistry {
+    tunnels: Mutex<HashMap<String, Arc<DatagramSettings>>>,
+}
+
+impl DatagramRegistry {
+    /// Settings of a tunnel being started, `default_timeout` being the one of its spec
+    pub fn register(
+        &self,
+        tunnel_id: &str,
+        options: DatagramOptions,
+        default_timeout: Option<Duration>,
+    ) -> Arc<DatagramSettings> {
+        let timeout_sec = options
+            .timeout_sec
+            .unwrap_or_else(|| default_timeout.map_or(0, |timeout| timeout.as_secs()));
+        let settings = Arc::new(DatagramSettings {
+            options,
+            timeout_sec: AtomicU64::new(timeout_sec),
+        });
+        self.tunnels
+            .lock()
+            .insert(tunnel_id.to_string(), settings.clone());
+        settings
+    }
+
+    /// Change the idle timeout of the flows of a tunnel, the open ones included
+    pub fn set_timeout(&self, tunnel_id: &str, timeout_sec: u64) -> anyhow::Result<()> {
+        let tunnels = self.tunnels.lock();
+        let settings = tunnels
+            .get(tunnel_id)
+            .ok_or_else(|| anyhow!("Tunnel {} is not a udp tunnel", tunnel_id))?;
+        settings.timeout_sec.store(timeout_sec, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn status(&self, tunnel_id: &str, metrics: &TunnelMetrics) -> Option<DatagramStatus> {
+        let tunnels = self.tunnels.lock();
+        let settings = tunnels.get(tunnel_id)?;
+        Some(DatagramStatus {
+            max_datagram_size: settings.options.max_datagram_size,
+            oversized: settings.options.oversized,
+            timeout_sec: settings.timeout_sec.load(Ordering::Relaxed),
+            oversized_datagrams: metrics.oversized_datagrams.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Apply the datagram settings of a udp tunnel to the flows accepted by its listener. The flows are closed
+/// here once idle, wstunnel's own timeout cannot be changed after the listener is created
+pub fn datagram_listener<L, R, W>(
+    listener: L,
+    settings: Arc<DatagramSettings>,
+    metrics: Arc<TunnelMetrics>,
+) -> impl Stream<Item = anyhow::Result<((Datagrams<R>, Datagrams<W>), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+{
+    listener.map(move |item| {
+        item.map(|((reader, writer), remote)| {
+            let flow = Arc::new(Flow {
+                settings: settings.clone(),
+                metrics: metrics.clone(),
+                last_activity: Mutex::new(Instant::now()),
+                warned: AtomicBool::new(false),
+            });
+            (
+                (
+                    Datagrams {
+                        inner: reader,
+                        flow: flow.clone(),
+                        idle: None,
+                    },
+                    Datagrams {
+                        inner: writer,
+                        flow,
+                        idle: None,
+                    },
+                ),
+                remote,
+            )
+        })
+    })
+}
+
+/// Datagrams exchanged with a local client of a udp tunnel
+#[derive(Debug)]
+struct Flow {
+    settings: Arc<DatagramSettings>,
+    metrics: Arc<TunnelMetrics>,
+    last_activity: Mutex<Instant>,
+    /// The first oversized datagram of the flow is logged, the next ones only counted
+    warned: AtomicBool,
+}
+
+impl Flow {
+    fn count_oversized(&self, size: usize) {
+        self.metrics
+            .oversized_datagrams
+            .fetch_add(1, Ordering::Relaxed);
+        if !self.warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                "Datagram of {} bytes is larger than the {} bytes of the tunnel, it is {}",
+                size,
+                self.settings.options.max_datagram_size.unwrap_or_default(),
+                if self.settings.drops_oversized() {
+                    "dropped"
+                } else {
+                    "fragmented"
+                }
+            );
+        }
+    }
+}
+
+/// Half of a udp flow, a read or write being a single datagram
+pub struct Datagrams<S> {
+    inner: S,
+    flow: Arc<Flow>,
+    /// Reader only, wakes it up to close the flow once idle
+    idle: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Datagrams<S> {
+    /// Ready once the flow has been idle for longer than the timeout, otherwise check again when it might be
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            let now = Instant::now();
+            let timeout = self.flow.settings.timeout_sec.load(Ordering::Relaxed);
+            let next_check = now + TIMEOUT_CHECK_INTERVAL;
+            let deadline = match timeout {
+                0 => next_check,
+                timeout => {
+                    let deadline = *self.flow.last_activity.lock() + Duration::from_secs(timeout);
+                    if deadline <= now {
+                        debug!("Closing udp flow idle for more than {} seconds", timeout);
+                        return Poll::Ready(());
+                    }
+                    deadline.min(next_check)
+                }
+            };
+            let idle = self
+                .idle
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+            idle.as_mut().reset(deadline);
+            if idle.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Datagrams<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let before = buf.filled().len();
+            match Pin::new(&mut this.inner).poll_read(cx, buf) {
+                Poll::Ready(Ok(())) => {
+                    let size = buf.filled().len() - before;
+                    if size > 0 {
+                        *this.flow.last_activity.lock() = Instant::now();
+                        if this.flow.settings.is_oversized(size) {
+                            this.flow.count_oversized(size);
+                            if this.flow.settings.drops_oversized() {
+                                buf.set_filled(before);
+                                continue;
+                            }
+                        }
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                // Nothing read ends the flow, as the idle timeout of wstunnel does
+                Poll::Pending => return this.poll_idle(cx).map(Ok),
+            }
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Datagrams<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let oversized = this.flow.settings.is_oversized(buf.len());
+        if oversized && this.flow.settings.drops_oversized() {
+            this.flow.count_oversized(buf.len());
+            return Poll::Ready(Ok(buf.len()));
+        }
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        if oversized {
+            this.flow.count_oversized(buf.len());
+        }
+        *this.flow.last_activity.lock() = Instant::now();
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,105 @@
+use log::{debug, info, warn};
+use parking_lot::{const_mutex, Mutex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Host;
+use wstunnel::protocols::dns::DnsResolver;
+
+/// A name taking longer than that to resolve is looked up in the cache instead
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+const CACHE_FILE: &str = "dns-cache.json";
+/// Time a resolved address stays usable, the resolver of wstunnel not telling the TTL of the records
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Serialize the read-modify-write cycles of the cache file within the process
+static CACHE_LOCK: Mutex<()> = const_mutex(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    addrs: Vec<IpAddr>,
+    /// Unix timestamp in seconds after which the entry must not be used
+    expires_at: u64,
+}
+
+/// Address to use instead of a name, when the name cannot be resolved quickly but was resolved recently enough.
+/// Names are resolved by the resolver of the profile, as wstunnel would resolve them.
+/// Successful lookups refresh the cache on disk, so the addresses survive a restart of the app.
+/// `None` means the name should be resolved as usual.
+pub async fn pinned_addr(host: &Host, resolver: &DnsResolver) -> Option<Host> {
+    let Host::Domain(name) = host else {
+        return None;
+    };
+
+    match tokio::time::timeout(LOOKUP_TIMEOUT, resolver.lookup_host(name, 0)).await {
+        Ok(Ok(addrs)) if !addrs.is_empty() => {
+            let entry = CacheEntry {
+                addrs: addrs.iter().map(|addr| addr.ip()).collect(),
+                expires_at: now_sec() + ENTRY_LIFETIME.as_secs(),
+            };
+            if let Err(err) = store(name, entry) {
+                warn!("Cannot save dns cache: {:?}", err);
+            }
+            None
+        }
+        res => {
+            debug!("Cannot resolve {}: {:?}", name, res.map(|r| r.err()));
+            let addr = load()
+                .remove(name.as_str())
+                .filter(|entry| entry.expires_at > now_sec())
+                .and_then(|entry| entry.addrs.first().copied())?;
+            info!("Using cached address {} for {}", addr, name);
+            Some(match addr {
+                IpAddr::V4(ip) => Host::Ipv4(ip),
+                IpAddr::V6(ip) => Host::Ipv6(ip),
+            })
+        }
+    }
+}
+
+/// Directory of the cache, shared by every instance of the app
+pub fn cache_dir() -> Option<PathBuf> {
+    Some(dirs::cache_dir()?.join("wstunnel-desktop"))
+}
+
+fn cache_path() -> Option<PathBuf> {
+    Some(cache_dir()?.join(CACHE_FILE))
+}
+
+fn load() -> HashMap<String, CacheEntry> {
+    let _lock = CACHE_LOCK.lock();
+    cache_path()
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+fn store(name: &str, entry: CacheEntry) -> anyhow::Result<()> {
+    let path = cache_path().ok_or_else(|| anyhow::anyhow!("No cache directory"))?;
+    let _lock = CACHE_LOCK.lock();
+    let mut entries: HashMap<String, CacheEntry> = std::fs::read(&path)
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default();
+    let now = now_sec();
+    entries.retain(|_, entry| entry.expires_at > now);
+    entries.insert(name.to_string(), entry);
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Write then rename, so another instance never reads a partial file
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&entries)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+fn now_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,86 @@
+use crate::client::profile::Profile;
+use crate::client::stats::ProfileStats;
+use anyhow::anyhow;
+use hickory_resolver::TokioAsyncResolver;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use wstunnel::tunnel::LocalProtocol;
+
+/// Name whose TXT record is the address of the resolver asking for it, as seen by its authoritative servers
+const WHOAMI_NAME: &str = "o-o.myaddr.l.google.com.";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsLeakReport {
+    pub profile_id: String,
+    pub force_remote_dns: bool,
+    /// Local socks5 tunnels of the profile, the ones applications may resolve names for by themselves
+    pub socks_tunnels: Vec<String>,
+    /// Public address of the resolver answering the names resolved locally, i.e: the one of the ISP
+    pub local_resolver: Option<String>,
+    pub local_resolver_error: Option<String>,
+    /// Requests refused for a destination resolved by the application since the profile connected.
+    /// None when unknown, for profiles running in their relay process
+    pub locally_resolved_requests: Option<u64>,
+    /// Applications using the socks tunnels may resolve names with the local resolver instead of the server,
+    /// or did so, the refused request having been preceded by a dns request outside the tunnel
+    pub leaking: bool,
+}
+
+/// Resolve a test name with the resolver of the OS to tell which resolver answers outside the tunnel,
+/// and whether the socks tunnels of the profile let the names resolved that way through
+pub async fn check(profile: &Profile, stats: Option<&ProfileStats>) -> DnsLeakReport {
+    let socks_tunnels: Vec<String> = profile
+        .enabled_tunnels()
+        .filter(|config| !config.reverse)
+        .filter_map(|config| profile.tunnel(config).ok())
+        .filter(|tunnel| matches!(tunnel.local_protocol, LocalProtocol::Socks5 { .. }))
+        .map(|tunnel| tunnel.id)
+        .collect();
+    let locally_resolved_requests = stats.map(|stats| {
+        socks_tunnels
+            .iter()
+            .map(|id| {
+                stats
+                    .tunnel_metrics(id)
+                    .local_dns_refused
+                    .load(Ordering::Relaxed)
+            })
+            .sum()
+    });
+
+    let (local_resolver, local_resolver_error) = match local_resolver().await {
+        Ok(resolver) => (Some(resolver.to_string()), None),
+        Err(err) => (None, Some(format!("{:#}", err))),
+    };
+    // Without the option, a name resolved by the application only reaches the tunnel as an address
+    let leaking = !socks_tunnels.is_empty()
+        && (!profile.force_remote_dns || locally_resolved_requests.unwrap_or(0) > 0);
+
+    DnsLeakReport {
+        profile_id: profile.name.clone(),
+        force_remote_dns: profile.force_remote_dns,
+        socks_tunnels,
+        local_resolver,
+        local_resolver_error,
+        locally_resolved_requests,
+        leaking,
+    }
+}
+
+/// Address of the resolver the OS resolver ends up asking, as told by the authoritative servers of the test name
+async fn local_resolver() -> anyhow::Result<IpAddr> {
+    let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
+    let lookup = tokio::time::timeout(LOOKUP_TIMEOUT, resolver.txt_lookup(WHOAMI_NAME))
+        .await
+        .map_err(|_| anyhow!("Test resolution of {} timed out", WHOAMI_NAME))??;
+    // Next to the resolver address, the answer may hold the client subnet the resolver forwarded
+    lookup
+        .iter()
+        .flat_map(|txt| txt.txt_data().iter())
+        .find_map(|data| String::from_utf8_lossy(data).parse().ok())
+        .ok_or_else(|| anyhow!("No resolver address in the answer for {}", WHOAMI_NAME))
+}
@@ -0,0 +1,54 @@
+use crate::client::client_api::LocalToRemote;
+use crate::parsers::parse_tunnel_spec;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, SocketAddr};
+
+pub const UDP_TUNNEL_ID: &str = "dns-stub-udp";
+pub const TCP_TUNNEL_ID: &str = "dns-stub-tcp";
+const DEFAULT_PORT: u16 = 5353;
+/// Udp flows of a query end with its answer, they do not need the default timeout of udp tunnels
+const UDP_TIMEOUT_SEC: u64 = 10;
+
+/// Local dns listener forwarding every query through the tunnel to a resolver reached from the server.
+/// Pointing the resolver of the OS at it gets names resolved remotely even for applications ignoring the proxies.
+/// It runs as an udp and a tcp tunnel of the profile, listening on the same address.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsStub {
+    #[serde(default = "default_listen")]
+    pub listen: SocketAddr,
+    /// Resolver the queries are sent to from the server, as `host:port`, i.e: `1.1.1.1:53`
+    pub upstream: String,
+}
+
+fn default_listen() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT))
+}
+
+impl DnsStub {
+    /// The udp then the tcp tunnel answering the queries
+    pub fn tunnels(&self) -> anyhow::Result<Vec<LocalToRemote>> {
+        [
+            (
+                UDP_TUNNEL_ID,
+                format!(
+                    "udp://{}:{}?timeout_sec={}",
+                    self.listen, self.upstream, UDP_TIMEOUT_SEC
+                ),
+            ),
+            (
+                TCP_TUNNEL_ID,
+                format!("tcp://{}:{}", self.listen, self.upstream),
+            ),
+        ]
+        .into_iter()
+        .map(|(id, spec)| {
+            let mut tunnel = parse_tunnel_spec(&spec, false)
+                .with_context(|| format!("Invalid dns stub upstream {}", self.upstream))?;
+            tunnel.id = id.to_string();
+            Ok(tunnel)
+        })
+        .collect()
+    }
+}
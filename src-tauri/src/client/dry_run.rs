@@ -0,0 +1,251 @@
+use crate::client::chain::{self, Upstream};
+use crate::client::client_api::Client;
+use crate::client::net_admin;
+use crate::client::placeholders;
+use crate::client::platform::{self, Capability};
+use crate::client::profile::Profile;
+use crate::client::static_hosts;
+use crate::client::transport;
+use serde::Serialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use url::Host;
+use wstunnel::protocols::tls;
+use wstunnel::tunnel::LocalProtocol;
+
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// The profile would fail to connect
+    Error,
+    /// The profile would connect, but something may not work as expected
+    Warning,
+}
+
+/// Problem found by a dry run of the connection of a profile
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationFinding {
+    pub severity: Severity,
+    /// What was checked, i.e: `tls_certificate` or `tunnel:web`
+    pub check: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Findings(Vec<ValidationFinding>);
+
+impl Findings {
+    fn error(&mut self, check: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, check.into(), message.into());
+    }
+
+    fn warning(&mut self, check: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, check.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, check: String, message: String) {
+        self.0.push(ValidationFinding {
+            severity,
+            check,
+            message,
+        });
+    }
+}
+
+/// Run the checks the connection of the profile does, without binding a listener nor connecting to the server.
+/// `connected` tells the profile is running, its tunnels holding their ports. No finding means the profile is
+/// expected to connect
+pub async fn validate(
+    profile: &Profile,
+    upstreams: &[Upstream],
+    connected: bool,
+) -> Vec<ValidationFinding> {
+    let mut findings = Findings::default();
+    let client = match load(profile, upstreams, &mut findings) {
+        Some(client) => client,
+        None => return findings.0,
+    };
+
+    if let Err(err) = transport::for_url(&client.remote_addr) {
+        findings.error("server_addr", format!("{:#}", err));
+    }
+    check_tls_files(&client, &mut findings);
+    if let Some(path) = &client.http_headers_file {
+        if let Err(err) = std::fs::read_to_string(path) {
+            findings.error(
+                "http_headers_file",
+                format!("Cannot read {}: {}", path.display(), err),
+            );
+        }
+    }
+    if client.socket_so_mark.is_some() {
+        if let Err(err) = net_admin::require(Capability::SocketMark) {
+            findings.error("socket_so_mark", format!("{:#}", err));
+        }
+    }
+    for tunnel in client.local_to_remote.iter().chain(&client.remote_to_local) {
+        if let Err(err) = platform::check_tunnel(tunnel) {
+            findings.error(format!("tunnel:{}", tunnel.id), format!("{:#}", err));
+        }
+    }
+    check_ports(&client, connected, &mut findings).await;
+    check_server_name(&client, &mut findings).await;
+    if profile.oauth.is_some() {
+        findings.warning(
+            "oauth",
+            "The authorization of the profile is only checked when it connects",
+        );
+    }
+    findings.0
+}
+
+/// The client connect() would start, as far as it can be built without the network
+fn load(profile: &Profile, upstreams: &[Upstream], findings: &mut Findings) -> Option<Client> {
+    let expanded = match placeholders::expand(profile) {
+        Ok(expanded) => expanded,
+        Err(err) => {
+            findings.error("placeholders", format!("{:#}", err));
+            return None;
+        }
+    };
+    let resolved = match chain::resolve(&expanded, upstreams) {
+        Ok(resolved) => resolved,
+        Err(err) => {
+            findings.error("chain", format!("{:#}", err));
+            return None;
+        }
+    };
+    match resolved.to_client() {
+        Ok(client) => Some(client),
+        Err(err) => {
+            findings.error("profile", format!("{:#}", err));
+            None
+        }
+    }
+}
+
+fn check_tls_files(client: &Client, findings: &mut Findings) {
+    if let Some(path) = &client.tls_certificate {
+        if let Err(err) = tls::load_certificates_from_pem(path) {
+            findings.error("tls_certificate", format!("{:#}", err));
+        }
+    }
+    if let Some(path) = &client.tls_private_key {
+        if let Err(err) = tls::load_private_key_from_file(path) {
+            findings.error("tls_private_key", format!("{:#}", err));
+        }
+    }
+    // Loading the key may ask the user for the PIN of the token
+    if client.tls_private_key_source.is_some() {
+        findings.warning(
+            "tls_private_key_source",
+            "The key in the keystore is only loaded when the profile connects",
+        );
+    }
+}
+
+/// A tcp port is taken when something accepts a connection on it, which needs no listener of our own
+async fn check_ports(client: &Client, connected: bool, findings: &mut Findings) {
+    for tunnel in &client.local_to_remote {
+        let tcp = matches!(
+            tunnel.local_protocol,
+            LocalProtocol::Tcp { .. }
+                | LocalProtocol::Socks5 { .. }
+                | LocalProtocol::HttpProxy { .. }
+        );
+        if !tcp || tunnel.named_pipe.is_some() || tunnel.tun.is_some() {
+            continue;
+        }
+        let local = match tunnel.access.bind_addr(tunnel.local) {
+            Ok(local) => local,
+            Err(err) => {
+                findings.error(format!("tunnel:{}", tunnel.id), format!("{:#}", err));
+                continue;
+            }
+        };
+        // Port 0 is chosen by the OS
+        if local.port() == 0 {
+            continue;
+        }
+        match bind_without_listening(local) {
+            Ok(()) => continue,
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => {}
+            Err(err) => {
+                findings.error(
+                    format!("tunnel:{}", tunnel.id),
+                    format!("Cannot bind {}: {}", local, err),
+                );
+                continue;
+            }
+        }
+        if connected {
+            findings.warning(
+                format!("tunnel:{}", tunnel.id),
+                format!(
+                    "Port {} is in use, by the profile itself while it is connected",
+                    local.port()
+                ),
+            );
+        } else {
+            findings.error(
+                format!("tunnel:{}", tunnel.id),
+                format!("Port {} is already in use on {}", local.port(), local.ip()),
+            );
+        }
+    }
+}
+
+/// Bind the address of a tunnel the way connecting does, without listening so no connection is ever accepted.
+/// The socket is closed right away
+fn bind_without_listening(local: SocketAddr) -> io::Result<()> {
+    let socket = Socket::new(
+        Domain::for_address(local),
+        Type::STREAM,
+        Some(Protocol::TCP),
+    )?;
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&local.into())
+}
+
+async fn check_server_name(client: &Client, findings: &mut Findings) {
+    let Some(host) = client.remote_addr.host().map(|host| host.to_owned()) else {
+        findings.error("server_addr", "The server address has no host");
+        return;
+    };
+    let Host::Domain(name) = &host else {
+        return;
+    };
+    if static_hosts::lookup(&client.static_hosts, &host).is_some() {
+        return;
+    }
+    let port = client.remote_addr.port_or_known_default().unwrap_or(443);
+    let resolved =
+        tokio::time::timeout(DNS_TIMEOUT, tokio::net::lookup_host((name.as_str(), port))).await;
+    let failure = match resolved {
+        Ok(Ok(mut addrs)) if addrs.next().is_some() => return,
+        Ok(Ok(_)) => "it has no address".to_string(),
+        Ok(Err(err)) => err.to_string(),
+        Err(_) => "timed out".to_string(),
+    };
+    // A proxy resolves the name itself
+    if client.http_proxy.is_some() || client.socks5_proxy.is_some() {
+        findings.warning(
+            "server_addr",
+            format!(
+                "Cannot resolve {} locally, leaving it to the proxy: {}",
+                name, failure
+            ),
+        );
+    } else {
+        findings.error(
+            "server_addr",
+            format!("Cannot resolve {}: {}", name, failure),
+        );
+    }
+}
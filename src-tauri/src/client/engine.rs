@@ -0,0 +1,513 @@
+use crate::client::events::ClientEvent;
+use crate::client::failure_cause;
+use crate::client::retry_policy::RetryPolicy;
+use crate::client::stats::ProfileStats;
+use crate::client::tasks::TaskGroup;
+use crate::client::tls_resumption::HandshakeCounts;
+use crate::client::upgrade_failures::{self, UpgradeFailureAction, UpgradeFailureRule};
+use crate::client::upgrade_timeout;
+use anyhow::anyhow;
+use futures_util::future::BoxFuture;
+use futures_util::{pin_mut, Stream, StreamExt};
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::fmt;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tauri::Url;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use wstunnel::protocols::dns::DnsResolver;
+use wstunnel::tunnel::client::{WsClient, WsClientConfig};
+use wstunnel::tunnel::RemoteAddr;
+
+/// A tunnel running for that long before failing is considered healthy again
+const TUNNEL_HEALTHY_AFTER: Duration = Duration::from_secs(60);
+/// Nothing listens on the discard port of loopback, queries sent there fail right away
+const UNREACHABLE_RESOLVER: &str = "dns://127.0.0.1:9";
+
+/// Run a tunnel with the given client until it fails.
+/// Must be callable several times, as tunnels are restarted every time the client is replaced.
+pub type TunnelRunner =
+    Box<dyn Fn(WsClient) -> BoxFuture<'static, anyhow::Result<()>> + Send + Sync>;
+
+/// Keep the accepted connections of a local listener flowing to whichever client is currently running the tunnel.
+/// The listener itself stays bound for the whole life of the profile.
+pub struct SharedListener<T> {
+    rx: Arc<tokio::sync::Mutex<mpsc::Receiver<T>>>,
+}
+
+impl<T: Send + 'static> SharedListener<T> {
+    pub fn new<L>(listener: L, tasks: &TaskGroup) -> Self
+    where
+        L: Stream<Item = T> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(1);
+        tasks.spawn(async move {
+            pin_mut!(listener);
+            while let Some(cnx) = listener.next().await {
+                if tx.send(cnx).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            rx: Arc::new(tokio::sync::Mutex::new(rx)),
+        }
+    }
+
+    pub fn stream(&self) -> impl Stream<Item = T> + Send + 'static {
+        futures_util::stream::unfold(self.rx.clone(), |rx| async move {
+            let cnx = rx.lock().await.recv().await?;
+            Some((cnx, rx))
+        })
+    }
+}
+
+/// Runner of a local to remote tunnel, whose listener outlives the client running it.
+/// A lazy tunnel does nothing until its listener accepts a connection, every time it is (re)started.
+/// The upgrade request of each connection is bounded by `upgrade_timeout` when set.
+pub fn listener_runner<L, R, W>(
+    listener: L,
+    lazy: bool,
+    upgrade_timeout: Option<Duration>,
+    tasks: &TaskGroup,
+) -> TunnelRunner
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>> + Send + 'static,
+    R: AsyncRead + Send + 'static,
+    W: AsyncWrite + Send + 'static,
+{
+    let shared = SharedListener::new(listener, tasks);
+    Box::new(move |client: WsClient| {
+        let stream = shared.stream();
+        Box::pin(async move {
+            if !lazy {
+                return upgrade_timeout::run_tunnel(client, stream, upgrade_timeout).await;
+            }
+
+            let mut stream = Box::pin(stream);
+            let Some(first) = stream.next().await else {
+                return Ok(());
+            };
+            debug!("First local connection, starting lazy tunnel");
+            let stream = futures_util::stream::iter(Some(first)).chain(stream);
+            upgrade_timeout::run_tunnel(client, stream, upgrade_timeout).await
+        })
+    })
+}
+
+/// A tunnel whose local listener is bound, ready to be run by the engine
+pub struct PreparedTunnel {
+    pub id: String,
+    pub reverse: bool,
+    pub runner: TunnelRunner,
+    /// Keep the local listener bound, until the tunnel is removed
+    pub tasks: TaskGroup,
+}
+
+struct EngineTunnel {
+    id: String,
+    reverse: bool,
+    runner: Arc<TunnelRunner>,
+    listener_tasks: TaskGroup,
+    runner_tasks: TaskGroup,
+}
+
+/// Owns the websocket client of a connected profile and the tunnels running on top of it
+pub struct ClientEngine {
+    this: Weak<ClientEngine>,
+    client: Mutex<WsClient>,
+    connection_min_idle: u32,
+    connection_retry_max_backoff: Duration,
+    retry_policy: Arc<RetryPolicy>,
+    upgrade_failure_rules: Arc<Vec<UpgradeFailureRule>>,
+    /// Held while the client is replaced after an upgrade failure, so tunnels failing together replace it once
+    rotating: tokio::sync::Mutex<()>,
+    tunnels: Mutex<Vec<EngineTunnel>>,
+    stats: Arc<ProfileStats>,
+    pool: Mutex<PoolInfo>,
+}
+
+/// What is known of the pool of websocket connections beyond what the pool itself tracks
+#[derive(Debug)]
+struct PoolInfo {
+    created_at: Instant,
+    last_handshake: Option<Duration>,
+}
+
+impl PoolInfo {
+    fn new() -> Self {
+        Self {
+            created_at: Instant::now(),
+            last_handshake: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolStatus {
+    pub min_idle: u32,
+    pub idle: u32,
+    pub in_use: u32,
+    /// Upper bound, pooled connections are opened at the earliest when the pool is created
+    pub oldest_connection_age_sec: Option<u64>,
+    /// Time to open the last connection measured by `warm_pool`
+    pub last_handshake_ms: Option<f64>,
+    /// TLS handshakes of the connections to the server since the profile connected, full or resuming a session
+    pub handshakes: HandshakeCounts,
+}
+
+impl From<PreparedTunnel> for EngineTunnel {
+    fn from(tunnel: PreparedTunnel) -> Self {
+        Self {
+            id: tunnel.id,
+            reverse: tunnel.reverse,
+            runner: Arc::new(tunnel.runner),
+            listener_tasks: tunnel.tasks,
+            runner_tasks: TaskGroup::default(),
+        }
+    }
+}
+
+impl EngineTunnel {
+    fn abort(&self) {
+        self.runner_tasks.abort_all();
+        self.listener_tasks.abort_all();
+    }
+
+    async fn stop(self) {
+        self.runner_tasks.stop().await;
+        self.listener_tasks.stop().await;
+    }
+}
+
+impl fmt::Debug for ClientEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientEngine")
+            .field("tunnels", &self.tunnels.lock().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientEngine {
+    pub fn new(
+        client: WsClient,
+        connection_min_idle: u32,
+        connection_retry_max_backoff: Duration,
+        retry_policy: RetryPolicy,
+        upgrade_failure_rules: Vec<UpgradeFailureRule>,
+        tunnels: Vec<PreparedTunnel>,
+        stats: Arc<ProfileStats>,
+    ) -> Arc<Self> {
+        Arc::new_cyclic(|this| Self {
+            this: this.clone(),
+            client: Mutex::new(client),
+            connection_min_idle,
+            connection_retry_max_backoff,
+            retry_policy: Arc::new(retry_policy),
+            upgrade_failure_rules: Arc::new(upgrade_failure_rules),
+            rotating: tokio::sync::Mutex::new(()),
+            tunnels: Mutex::new(tunnels.into_iter().map(EngineTunnel::from).collect()),
+            stats,
+            pool: Mutex::new(PoolInfo::new()),
+        })
+    }
+
+    /// Start every tunnel with the current client, restarting them with a backoff when they fail
+    pub fn start(&self) {
+        let client = self.client.lock().clone();
+        for tunnel in self.tunnels.lock().iter() {
+            self.spawn_runner(tunnel, client.clone());
+        }
+    }
+
+    fn spawn_runner(&self, tunnel: &EngineTunnel, client: WsClient) {
+        let runner = tunnel.runner.clone();
+        let tunnel_id = tunnel.id.clone();
+        let reverse = tunnel.reverse;
+        let stats = self.stats.clone();
+        let max_backoff = self.connection_retry_max_backoff;
+        let policy = self.retry_policy.clone();
+        let rules = self.upgrade_failure_rules.clone();
+        let engine = self.this.clone();
+        tunnel.runner_tasks.spawn(async move {
+            let mut failures = 0;
+            loop {
+                let started = Instant::now();
+                let result = tokio::select! {
+                    res = runner(client.clone()) => res,
+                    _ = stats.faults.dropped.notified() => {
+                        Err(anyhow!("Connection dropped by an injected fault"))
+                    }
+                };
+                let Err(err) = result else {
+                    return;
+                };
+                let err = upgrade_failures::typed(err);
+                stats.record_tunnel_error(&tunnel_id);
+                if reverse {
+                    stats.reverse_tunnels.failed(&tunnel_id, &err);
+                }
+                error!("{:?}", err);
+                let cause = failure_cause::classify(&err);
+                stats.publish(ClientEvent::TunnelFailed {
+                    tunnel_id: tunnel_id.clone(),
+                    cause: cause.clone(),
+                    error: format!("{:#}", err),
+                });
+
+                let action = upgrade_failures::matching_rule(&rules, &err).map(|rule| {
+                    warn!(
+                        "Upgrade of tunnel {} rejected with status {}, applying {:?}",
+                        tunnel_id, rule.status, rule.action
+                    );
+                    &rule.action
+                });
+                match action {
+                    Some(UpgradeFailureAction::Stop) => {
+                        stats.publish(ClientEvent::ReconnectExhausted {
+                            tunnel_id,
+                            cause,
+                            error: format!("{:#}", err),
+                        });
+                        return;
+                    }
+                    Some(UpgradeFailureAction::RotatePathPrefix { prefixes }) => {
+                        let Some(engine) = engine.upgrade() else {
+                            return;
+                        };
+                        // Replacing the client restarts every tunnel, this one included, so this task is done.
+                        // It runs apart as replacing the client aborts the tasks of the tunnels.
+                        let prefixes = prefixes.clone();
+                        tokio::spawn(async move {
+                            if let Err(err) = engine.rotate_path_prefix(&client, &prefixes).await {
+                                error!("Cannot rotate upgrade path prefix: {:?}", err);
+                            }
+                        });
+                        return;
+                    }
+                    Some(UpgradeFailureAction::RefreshCredentials) => {
+                        stats.credentials_stale.notify_one();
+                    }
+                    Some(UpgradeFailureAction::Retry) | None => {}
+                }
+
+                if started.elapsed() > TUNNEL_HEALTHY_AFTER {
+                    failures = 0;
+                }
+                failures = failures.saturating_add(1);
+                if policy.exhausted(failures) {
+                    stats.publish(ClientEvent::ReconnectExhausted {
+                        tunnel_id,
+                        cause,
+                        error: format!("{:#}", err),
+                    });
+                    return;
+                }
+                let backoff = policy.backoff(failures, max_backoff);
+                info!("Restarting tunnel in {:?}", backoff);
+                tokio::time::sleep(backoff).await;
+            }
+        });
+    }
+
+    /// Stop the tunnels, their local listeners stay bound
+    pub fn stop(&self) {
+        for tunnel in self.tunnels.lock().iter() {
+            tunnel.runner_tasks.abort_all();
+        }
+    }
+
+    /// Stop the tunnels and release their local listeners
+    pub fn shutdown(&self) {
+        for tunnel in self.tunnels.lock().drain(..) {
+            tunnel.abort();
+        }
+    }
+
+    /// Same as `shutdown`, returning once the local listeners are closed so their ports can be bound again
+    pub async fn stop(&self) {
+        let tunnels: Vec<_> = self.tunnels.lock().drain(..).collect();
+        futures_util::future::join_all(tunnels.into_iter().map(EngineTunnel::stop)).await;
+    }
+
+    /// Run a new tunnel with the current client, alongside the running ones
+    pub fn add_tunnel(&self, tunnel: PreparedTunnel) {
+        let tunnel = EngineTunnel::from(tunnel);
+        self.spawn_runner(&tunnel, self.client.lock().clone());
+        self.tunnels.lock().push(tunnel);
+    }
+
+    /// Stop a tunnel and release its local listener, returning whether it was running
+    pub fn remove_tunnel(&self, id: &str, reverse: bool) -> bool {
+        let mut tunnels = self.tunnels.lock();
+        let Some(i) = tunnels
+            .iter()
+            .position(|t| t.id == id && t.reverse == reverse)
+        else {
+            return false;
+        };
+        tunnels.remove(i).abort();
+        if reverse {
+            self.stats.reverse_tunnels.remove(id);
+        }
+        true
+    }
+
+    pub fn stats(&self) -> &Arc<ProfileStats> {
+        &self.stats
+    }
+
+    /// Replace the pool of websocket/TLS connections by a fresh one, keeping the local listeners bound.
+    /// Connections already relayed keep using their current websocket until they close.
+    pub async fn refresh_connections(&self) -> anyhow::Result<()> {
+        info!("Refreshing connections to {}", self.stats.link.remote_addr);
+        self.replace_client(self.connection_min_idle).await
+    }
+
+    /// Close every idle connection of the pool, which is not refilled until `warm_pool` is called or the connections are refreshed
+    pub async fn drain_pool(&self) -> anyhow::Result<()> {
+        info!("Draining connections to {}", self.stats.link.remote_addr);
+        self.replace_client(0).await
+    }
+
+    /// Open connections ahead of time so that the next requests do not pay the handshake
+    pub async fn warm_pool(&self, count: u32) -> anyhow::Result<()> {
+        let client = self.client.lock().clone();
+
+        // A dedicated connection is always a new one, unlike the pooled ones, so it measures a full handshake
+        let start = Instant::now();
+        let cnx = client
+            .cnx_pool
+            .dedicated_connection()
+            .await
+            .map_err(|err| anyhow!("Cannot connect to the server: {:?}", err))?;
+        self.pool.lock().last_handshake = Some(start.elapsed());
+        drop(cnx);
+
+        // Connections checked out at the same time are all opened, and go back idle to the pool when released
+        let connections =
+            futures_util::future::try_join_all((0..count).map(|_| client.cnx_pool.get()))
+                .await
+                .map_err(|err| anyhow!("Cannot connect to the server: {:?}", err))?;
+        info!(
+            "Warmed {} connections to {}",
+            connections.len(),
+            self.stats.link.remote_addr
+        );
+        Ok(())
+    }
+
+    pub fn pool_status(&self) -> PoolStatus {
+        let state = self.client.lock().cnx_pool.state();
+        let pool = self.pool.lock();
+        PoolStatus {
+            min_idle: self.connection_min_idle,
+            idle: state.idle_connections,
+            in_use: state.connections - state.idle_connections,
+            oldest_connection_age_sec: (state.connections > 0)
+                .then(|| pool.created_at.elapsed().as_secs()),
+            last_handshake_ms: pool.last_handshake.map(|d| d.as_secs_f64() * 1000.0),
+            handshakes: self
+                .stats
+                .handshakes
+                .get()
+                .map(|counters| counters.snapshot())
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Resolve the server name with a resolver that cannot answer until the duration elapsed,
+    /// so that new connections fail as they would without network
+    pub async fn fail_dns(self: &Arc<Self>, duration: Duration) -> anyhow::Result<()> {
+        self.stats.faults.fail_dns(duration);
+        let mut config = (*self.client.lock().config).clone();
+        let resolver = config.dns_resolver.clone();
+        config.dns_resolver = DnsResolver::new_from_urls(
+            &[Url::parse(UNREACHABLE_RESOLVER)?],
+            None,
+            config.socket_so_mark,
+            true,
+        )?;
+        self.replace_client_config(config, self.connection_min_idle)
+            .await?;
+
+        let engine = Arc::downgrade(self);
+        tokio::spawn(async move {
+            tokio::time::sleep(duration).await;
+            let Some(engine) = engine.upgrade() else {
+                return;
+            };
+            info!(
+                "Restoring dns resolution of {}",
+                engine.stats.link.remote_addr
+            );
+            let mut config = (*engine.client.lock().config).clone();
+            config.dns_resolver = resolver;
+            if let Err(err) = engine
+                .replace_client_config(config, engine.connection_min_idle)
+                .await
+            {
+                error!("Cannot restore dns resolution: {:?}", err);
+            }
+        });
+        Ok(())
+    }
+
+    /// Reconnect with the prefix following the one `failed` used, unless the client has been replaced since
+    async fn rotate_path_prefix(
+        &self,
+        failed: &WsClient,
+        prefixes: &[String],
+    ) -> anyhow::Result<()> {
+        let _rotating = self.rotating.lock().await;
+        let mut config = {
+            let client = self.client.lock();
+            if !Arc::ptr_eq(&client.config, &failed.config) {
+                return Ok(());
+            }
+            (*client.config).clone()
+        };
+        let Some(prefix) =
+            upgrade_failures::next_prefix(prefixes, &config.http_upgrade_path_prefix)
+        else {
+            return Ok(());
+        };
+        info!(
+            "Switching upgrade path prefix of {} from {} to {}",
+            self.stats.link.remote_addr, config.http_upgrade_path_prefix, prefix
+        );
+        config.http_upgrade_path_prefix = prefix.clone();
+        self.replace_client_config(config, self.connection_min_idle)
+            .await
+    }
+
+    async fn replace_client(&self, connection_min_idle: u32) -> anyhow::Result<()> {
+        let config = (*self.client.lock().config).clone();
+        self.replace_client_config(config, connection_min_idle)
+            .await
+    }
+
+    async fn replace_client_config(
+        &self,
+        config: WsClientConfig,
+        connection_min_idle: u32,
+    ) -> anyhow::Result<()> {
+        let client = WsClient::new(
+            config,
+            connection_min_idle,
+            self.connection_retry_max_backoff,
+        )
+        .await?;
+
+        self.stop();
+        *self.client.lock() = client;
+        *self.pool.lock() = PoolInfo::new();
+        self.start();
+        Ok(())
+    }
+}
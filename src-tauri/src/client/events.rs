@@ -0,0 +1,106 @@
+use crate::client::connections::ActiveConnection;
+use crate::client::failure_cause::FailureCause;
+use crate::client::port_mapping::PortMapping;
+use crate::client::quality::Degradation;
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+const EVENTS_CAPACITY: usize = 16;
+
+/// Changes in the state of a connected profile worth telling the user about
+#[derive(Debug, Clone)]
+pub enum ClientEvent {
+    /// The server stopped answering the link probes
+    ServerUnreachable,
+    /// The server answers the link probes again after having been unreachable
+    ServerReachable,
+    /// The link to the server has been probed once more
+    LinkMeasured,
+    /// The latest probes show the link got worse, or worse in another way
+    LinkDegraded {
+        degradation: Degradation,
+    },
+    /// The latest probes show a healthy link again
+    LinkRecovered,
+    /// A tunnel failed, it is restarted unless followed by `ReconnectExhausted`
+    TunnelFailed {
+        tunnel_id: String,
+        cause: FailureCause,
+        error: String,
+    },
+    /// A tunnel kept failing and is not restarted anymore
+    ReconnectExhausted {
+        tunnel_id: String,
+        cause: FailureCause,
+        error: String,
+    },
+    /// The input of a stdio tunnel has been closed, the tunnel stopped for good
+    StdioClosed {
+        tunnel_id: String,
+    },
+    /// The client certificate expires in that many days
+    CertificateExpiring {
+        days_left: i64,
+    },
+    CertificateExpired,
+    /// The renewal command of the profile gave a new client certificate, picked up without dropping the tunnels
+    CertificateRenewed {
+        days_left: i64,
+    },
+    CertificateRenewalFailed {
+        error: String,
+    },
+    /// The server accepted the first connection on the remote port of a reverse tunnel, since it was requested
+    ReverseTunnelLive {
+        tunnel_id: String,
+        public_addr: String,
+    },
+    /// The server accepted a connection on the remote port of a reverse tunnel, which reached its local destination
+    ReverseConnectionAccepted {
+        tunnel_id: String,
+        connection: ActiveConnection,
+    },
+    /// A connection of a reverse tunnel closed, with what it transferred
+    ReverseConnectionClosed {
+        connection: ActiveConnection,
+    },
+    /// The router forwards a port of its public address to the listener of a tunnel, or now another one
+    PortMapped {
+        tunnel_id: String,
+        mapping: PortMapping,
+    },
+    /// The router of a tunnel asking for a port mapping refused it or does not support it
+    PortMappingFailed {
+        tunnel_id: String,
+        error: String,
+    },
+    /// A socks client asked for an address it resolved itself, the application resolves names locally.
+    /// Only the first one of a tunnel is reported
+    LocalDnsResolution {
+        tunnel_id: String,
+        destination: String,
+    },
+}
+
+/// Step reached while a profile connects, so a profile with many tunnels shows how far it got
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum ConnectProgress {
+    /// The transport to the server has been chosen, after probing the fallbacks if enabled
+    TransportSelected { remote_addr: String },
+    /// The pool of websocket connections to the server is ready
+    PoolReady,
+    /// The listener of a tunnel is bound, or a reverse tunnel is ready to be requested from the server
+    #[serde(rename_all = "camelCase")]
+    TunnelReady {
+        tunnel_id: String,
+        ready: usize,
+        total: usize,
+    },
+    /// Every tunnel is running
+    Started,
+}
+
+pub fn channel() -> broadcast::Sender<ClientEvent> {
+    broadcast::channel(EVENTS_CAPACITY).0
+}
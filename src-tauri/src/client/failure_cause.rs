@@ -0,0 +1,114 @@
+use crate::client::upgrade_failures;
+use serde::Serialize;
+use std::io::ErrorKind;
+use tokio_rustls::rustls;
+
+/// Why a tunnel failed, read from the error chain wstunnel returned, so the UI can tell what to fix
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FailureCause {
+    /// The name of the server, or of the proxy, cannot be resolved
+    DnsFailure,
+    /// Nothing listens on the port of the server
+    ConnectionRefused,
+    Timeout,
+    /// The certificate of the server is not trusted, or does not match its name
+    TlsVerifyFailed {
+        reason: String,
+    },
+    /// The server answered the upgrade request with an http error status
+    UpgradeRejected {
+        status: u16,
+    },
+    /// The http proxy wants credentials, or other ones
+    ProxyAuthRequired,
+    Other,
+}
+
+/// Cause of the failure of a tunnel. Typed errors of the chain are looked at first, then the messages,
+/// as wstunnel turns many errors into plain messages.
+pub fn classify(err: &anyhow::Error) -> FailureCause {
+    for cause in err.chain() {
+        if let Some(tls) = cause.downcast_ref::<rustls::Error>() {
+            if let Some(cause) = tls_cause(tls) {
+                return cause;
+            }
+        }
+        if let Some(io) = cause.downcast_ref::<std::io::Error>() {
+            if let Some(tls) = io
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<rustls::Error>())
+            {
+                if let Some(cause) = tls_cause(tls) {
+                    return cause;
+                }
+            }
+            match io.kind() {
+                ErrorKind::ConnectionRefused => return FailureCause::ConnectionRefused,
+                ErrorKind::TimedOut => return FailureCause::Timeout,
+                _ => {}
+            }
+        }
+        if cause.is::<tokio::time::error::Elapsed>() {
+            return FailureCause::Timeout;
+        }
+    }
+
+    let message = format!("{:#}", err).to_ascii_lowercase();
+    if http_statuses(&message).any(|status| status == 407)
+        || message.contains("proxy authentication required")
+    {
+        return FailureCause::ProxyAuthRequired;
+    }
+    if let Some(status) = upgrade_failures::upgrade_status(err) {
+        return FailureCause::UpgradeRejected { status };
+    }
+    if [
+        "failed to lookup address",
+        "no record found",
+        "name or service not known",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+    {
+        return FailureCause::DnsFailure;
+    }
+    if message.contains("connection refused") {
+        return FailureCause::ConnectionRefused;
+    }
+    if message.contains("timed out") || message.contains("timeout") {
+        return FailureCause::Timeout;
+    }
+    if message.contains("invalid peer certificate") || message.contains("unknownissuer") {
+        return FailureCause::TlsVerifyFailed {
+            reason: "invalid peer certificate".to_string(),
+        };
+    }
+    FailureCause::Other
+}
+
+/// Statuses of the http status lines quoted in a message, i.e: `HTTP/1.1 407 Proxy Authentication Required`.
+/// Numbers elsewhere, such as a port, are not statuses
+fn http_statuses(message: &str) -> impl Iterator<Item = u16> + '_ {
+    message.match_indices("http/1.").filter_map(|(start, _)| {
+        let mut parts = message[start..].split_ascii_whitespace();
+        let version = parts.next()?;
+        let status = parts.next()?;
+        if !matches!(version, "http/1.0" | "http/1.1")
+            || status.len() != 3
+            || !status.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        status.parse().ok()
+    })
+}
+
+fn tls_cause(err: &rustls::Error) -> Option<FailureCause> {
+    match err {
+        rustls::Error::InvalidCertificate(reason) => Some(FailureCause::TlsVerifyFailed {
+            reason: format!("{:?}", reason),
+        }),
+        _ => None,
+    }
+}
@@ -0,0 +1,243 @@
+use crate::client::server_select::ProbeSettings;
+use crate::client::static_hosts;
+use crate::client::transport;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tauri::Url;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+const PROBE_ATTEMPTS: usize = 3;
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+const H2_ALPN: &[u8] = b"h2";
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_GOAWAY: u8 = 0x7;
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+/// Default size of a frame payload, which the probe never raises in its settings
+const MAX_FRAME_SIZE: usize = 16_384;
+/// Time the transport selected for a server is reused by the next connections without probing again
+const SELECTION_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Transport selected for each server url, so reconnecting after a network blip does not probe again
+fn selections() -> &'static Mutex<HashMap<Url, (Instant, Url)>> {
+    static SELECTIONS: OnceLock<Mutex<HashMap<Url, (Instant, Url)>>> = OnceLock::new();
+    SELECTIONS.get_or_init(Mutex::default)
+}
+
+/// Forget the transport selected for a server url, once connecting with it failed
+pub fn forget(remote_addr: &Url) {
+    selections().lock().remove(remote_addr);
+}
+
+/// Why an HTTP/2 probe against the server failed
+#[derive(Debug)]
+enum ProbeFailure {
+    /// Something in the path did not negotiate h2, i.e: a reverse proxy/CDN downgrading the request to http1
+    NoH2,
+    /// h2 was negotiated but the request did not get an answer, i.e: the stream reset or the connection closed
+    /// by a middlebox that does not speak it
+    StreamFailed(String),
+    /// The handshake or the request never completed, typical of middleboxes buffering the request
+    Timeout,
+    /// Any other error, not related to http2 itself. Falling back would not help
+    Other(anyhow::Error),
+}
+
+/// When the profile opted in, check that http2 is usable to reach the server and otherwise
+/// switch the transport to websocket. Returns the server url that must be used.
+pub async fn select_transport(remote_addr: &Url, settings: ProbeSettings<'_>) -> Url {
+    if remote_addr.scheme() != "https" {
+        return remote_addr.clone();
+    }
+    if let Some((selected_at, selected)) = selections().lock().get(remote_addr) {
+        if selected_at.elapsed() < SELECTION_TTL {
+            debug!(
+                "Reusing transport {} selected for {}",
+                selected, remote_addr
+            );
+            return selected.clone();
+        }
+    }
+
+    for attempt in 1..=PROBE_ATTEMPTS {
+        let failure =
+            match tokio::time::timeout(PROBE_TIMEOUT, probe_h2(remote_addr, settings)).await {
+                Ok(Ok(())) => {
+                    info!("Http2 transport is usable to reach {}", remote_addr);
+                    selections()
+                        .lock()
+                        .insert(remote_addr.clone(), (Instant::now(), remote_addr.clone()));
+                    return remote_addr.clone();
+                }
+                Ok(Err(failure)) => failure,
+                Err(_) => ProbeFailure::Timeout,
+            };
+
+        match failure {
+            ProbeFailure::NoH2 | ProbeFailure::StreamFailed(_) | ProbeFailure::Timeout => {
+                warn!(
+                    "Http2 probe {}/{} to {} failed: {:?}",
+                    attempt, PROBE_ATTEMPTS, remote_addr, failure
+                );
+            }
+            ProbeFailure::Other(err) => {
+                warn!("Cannot probe http2 transport, keeping it: {:?}", err);
+                return remote_addr.clone();
+            }
+        }
+    }
+
+    let mut fallback = remote_addr.clone();
+    if fallback.set_scheme("wss").is_err() {
+        return remote_addr.clone();
+    }
+    warn!(
+        "Http2 transport does not work to reach {}, falling back to {}",
+        remote_addr, fallback
+    );
+    selections()
+        .lock()
+        .insert(remote_addr.clone(), (Instant::now(), fallback.clone()));
+    fallback
+}
+
+/// Connect as the tunnels do, static hosts, SNI override, pinned certificates and client identity included,
+/// then send the request opening the tunnels stream and wait for the server to answer it
+async fn probe_h2(remote_addr: &Url, settings: ProbeSettings<'_>) -> Result<(), ProbeFailure> {
+    let host = remote_addr
+        .host()
+        .ok_or_else(|| ProbeFailure::Other(anyhow::anyhow!("server url without host")))?
+        .to_owned();
+    let port = remote_addr.port_or_known_default().unwrap_or(443);
+    let connect_host = static_hosts::lookup(settings.static_hosts, &host)
+        .unwrap_or_else(|| host.clone())
+        .to_string();
+    let server_name = match &settings.tls.sni_override {
+        Some(sni) => ServerName::DnsName(sni.clone()),
+        None => ServerName::try_from(host.to_string().trim_matches(['[', ']']).to_string())
+            .map_err(|err| ProbeFailure::Other(err.into()))?,
+    };
+    let authority = match remote_addr.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let path = format!("/{}/events", settings.upgrade_path_prefix);
+
+    let connector = transport::http2_tls_connector(settings.tls).map_err(ProbeFailure::Other)?;
+    let tcp = TcpStream::connect((connect_host.trim_matches(['[', ']']), port))
+        .await
+        .map_err(|err| ProbeFailure::Other(err.into()))?;
+    let stream = connector
+        .connect(server_name, tcp)
+        .await
+        .map_err(|err| ProbeFailure::Other(err.into()))?;
+    if stream.get_ref().1.alpn_protocol() != Some(H2_ALPN) {
+        return Err(ProbeFailure::NoH2);
+    }
+    exchange(stream, &authority, &path).await
+}
+
+/// Send the preface and a request on the first stream, returning once the server answered it.
+/// The status does not matter: the server refusing the request answers through the same path as accepting it
+async fn exchange<S>(mut stream: S, authority: &str, path: &str) -> Result<(), ProbeFailure>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = H2_PREFACE.to_vec();
+    request.extend(frame(FRAME_SETTINGS, 0, 0, &[]));
+    request.extend(frame(
+        FRAME_HEADERS,
+        FLAG_END_STREAM | FLAG_END_HEADERS,
+        1,
+        &request_headers(authority, path),
+    ));
+    let failed = |err: std::io::Error| ProbeFailure::StreamFailed(err.to_string());
+    stream.write_all(&request).await.map_err(failed)?;
+
+    let mut payload = vec![0; MAX_FRAME_SIZE];
+    loop {
+        let mut header = [0; 9];
+        stream.read_exact(&mut header).await.map_err(failed)?;
+        let length = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+        let (kind, flags) = (header[3], header[4]);
+        let stream_id =
+            u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff;
+        if length > MAX_FRAME_SIZE {
+            return Err(ProbeFailure::StreamFailed(format!(
+                "frame of {} bytes over the size allowed",
+                length
+            )));
+        }
+        stream
+            .read_exact(&mut payload[..length])
+            .await
+            .map_err(failed)?;
+
+        match kind {
+            FRAME_HEADERS if stream_id == 1 => return Ok(()),
+            FRAME_RST_STREAM if stream_id == 1 => {
+                return Err(ProbeFailure::StreamFailed("stream reset".to_string()))
+            }
+            FRAME_GOAWAY => {
+                return Err(ProbeFailure::StreamFailed("connection closed".to_string()))
+            }
+            FRAME_SETTINGS if flags & FLAG_ACK == 0 => {
+                stream
+                    .write_all(&frame(FRAME_SETTINGS, FLAG_ACK, 0, &[]))
+                    .await
+                    .map_err(failed)?;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+    frame.extend([kind, flags]);
+    frame.extend(stream_id.to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+/// Headers of the request, as HPACK without indexing nor huffman coding: the static table for the method and
+/// the scheme, literals named from it for the authority and the path
+fn request_headers(authority: &str, path: &str) -> Vec<u8> {
+    const METHOD_POST: u8 = 0x83;
+    const SCHEME_HTTPS: u8 = 0x87;
+    const NAME_AUTHORITY: u8 = 1;
+    const NAME_PATH: u8 = 4;
+
+    let mut block = vec![METHOD_POST, SCHEME_HTTPS];
+    for (name, value) in [(NAME_AUTHORITY, authority), (NAME_PATH, path)] {
+        block.push(name);
+        hpack_integer(&mut block, value.len(), 7);
+        block.extend(value.as_bytes());
+    }
+    block
+}
+
+/// Integer of HPACK, in the low bits of a first byte and continued on the next ones when it does not fit
+fn hpack_integer(block: &mut Vec<u8>, mut value: usize, prefix_bits: u32) {
+    let max = (1 << prefix_bits) - 1;
+    if value < max {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
@@ -0,0 +1,153 @@
+use crate::client::stats::ProfileStats;
+use futures_util::{Stream, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use tokio::time::Sleep;
+use wstunnel::tunnel::RemoteAddr;
+
+/// Given to the app to allow injecting faults in release builds
+pub const DIAGNOSTICS_ARG: &str = "--diagnostics";
+
+/// Failure injected on demand in a connected profile, to check how the setup reacts to it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Fault {
+    /// Make every running tunnel fail as if the websocket connection dropped, so they go through their restart backoff
+    DropConnections,
+    /// Hold every chunk of data relayed by the tunnels for the given time
+    DelayFrames { delay_ms: u64, duration_sec: u64 },
+    /// Make the resolution of the server name fail, for new connections and link probes
+    FailDns { duration_sec: u64 },
+}
+
+pub fn diagnostics_enabled() -> bool {
+    cfg!(debug_assertions) || std::env::args().any(|arg| arg == DIAGNOSTICS_ARG)
+}
+
+/// Faults currently injected in a profile
+#[derive(Debug, Default)]
+pub struct FaultState {
+    /// Delay added to relayed data, and until when
+    delay: Mutex<Option<(Duration, Instant)>>,
+    dns_failure_until: Mutex<Option<Instant>>,
+    /// Notified to make the running tunnels fail
+    pub dropped: Notify,
+}
+
+impl FaultState {
+    pub fn delay_frames(&self, delay: Duration, duration: Duration) {
+        *self.delay.lock() = Some((delay, Instant::now() + duration));
+    }
+
+    pub fn fail_dns(&self, duration: Duration) {
+        *self.dns_failure_until.lock() = Some(Instant::now() + duration);
+    }
+
+    pub fn frame_delay(&self) -> Option<Duration> {
+        self.delay
+            .lock()
+            .filter(|(_, until)| Instant::now() < *until)
+            .map(|(delay, _)| delay)
+    }
+
+    pub fn dns_failing(&self) -> bool {
+        self.dns_failure_until
+            .lock()
+            .is_some_and(|until| Instant::now() < until)
+    }
+}
+
+/// Stream of a tunnel connection, holding its data while a frame delay is injected
+pub struct Faulty<S> {
+    inner: S,
+    stats: Arc<ProfileStats>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> Faulty<S> {
+    fn new(inner: S, stats: Arc<ProfileStats>) -> Self {
+        Self {
+            inner,
+            stats,
+            sleep: None,
+        }
+    }
+
+    /// Ready once the injected delay, if any, elapsed
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.sleep.is_none() {
+            let Some(delay) = self.stats.faults.frame_delay() else {
+                return Poll::Ready(());
+            };
+            self.sleep = Some(Box::pin(tokio::time::sleep(delay)));
+        }
+        if let Some(sleep) = self.sleep.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+        }
+        self.sleep = None;
+        Poll::Ready(())
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Faulty<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_delay(cx));
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Faulty<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_delay(cx));
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Let the faults injected in the profile affect the connections accepted by a tunnel listener
+pub fn fault_listener<L, R, W>(
+    listener: L,
+    stats: Arc<ProfileStats>,
+) -> impl Stream<Item = anyhow::Result<((Faulty<R>, Faulty<W>), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    listener.map(move |item| {
+        item.map(|((reader, writer), remote)| {
+            (
+                (
+                    Faulty::new(reader, stats.clone()),
+                    Faulty::new(writer, stats.clone()),
+                ),
+                remote,
+            )
+        })
+    })
+}
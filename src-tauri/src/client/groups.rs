@@ -0,0 +1,74 @@
+use crate::client::profile::Profile;
+use crate::client::stats::ProfileStats;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+
+/// A named group of tunnels of a profile, with the traffic of its tunnels added up
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupStats {
+    pub group: String,
+    pub tunnels: usize,
+    /// Tunnels of the group started with the profile
+    pub enabled: usize,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub connections: u64,
+    pub active_connections: u64,
+}
+
+/// The profile with every tunnel of the group enabled or disabled, the other tunnels as they were
+pub fn with_group_enabled(
+    profile: &Profile,
+    group: &str,
+    enabled: bool,
+) -> anyhow::Result<Profile> {
+    let mut profile = profile.clone();
+    let mut found = false;
+    for tunnel in profile
+        .tunnels
+        .iter_mut()
+        .filter(|tunnel| tunnel.group.as_deref() == Some(group))
+    {
+        tunnel.enabled = enabled;
+        found = true;
+    }
+    if !found {
+        return Err(anyhow!(
+            "Profile {} has no tunnel group {}",
+            profile.name,
+            group
+        ));
+    }
+    Ok(profile)
+}
+
+/// Traffic of each group of a profile, since it connected. Tunnels without a group are left out
+pub fn group_stats(profile: &Profile, stats: &ProfileStats) -> Vec<GroupStats> {
+    let mut groups: BTreeMap<&str, GroupStats> = BTreeMap::new();
+    for tunnel in &profile.tunnels {
+        let Some(group) = tunnel.group.as_deref() else {
+            continue;
+        };
+        let entry = groups.entry(group).or_insert_with(|| GroupStats {
+            group: group.to_string(),
+            ..GroupStats::default()
+        });
+        entry.tunnels += 1;
+        if !tunnel.enabled {
+            continue;
+        }
+        entry.enabled += 1;
+        let Ok(parsed) = tunnel.to_tunnel() else {
+            continue;
+        };
+        let traffic = &stats.tunnel_metrics(&parsed.id).traffic;
+        entry.bytes_up += traffic.bytes_up.load(Ordering::Relaxed);
+        entry.bytes_down += traffic.bytes_down.load(Ordering::Relaxed);
+        entry.connections += traffic.connections.load(Ordering::Relaxed);
+        entry.active_connections += traffic.active_connections.load(Ordering::Relaxed);
+    }
+    groups.into_values().collect()
+}
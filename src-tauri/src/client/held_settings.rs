@@ -0,0 +1,253 @@
+use crate::client::client_key::ClientKeySource;
+use crate::client::placeholders;
+use crate::client::profile::Profile;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+const REDACTED: &str = "<redacted>";
+
+/// Setting of a profile that runs a command, loads code, reads a local file or changes the network of this machine,
+/// dangerous when the profile comes from someone else (a link, a QR code, a bundle, an administrator)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeldKind {
+    BeforeConnect,
+    AfterDisconnect,
+    /// Token command or file of the upgrade credentials
+    CredentialsProvider,
+    CertificateRenewal,
+    /// Module of the PKCS#11 token, loaded into the app
+    Pkcs11Module,
+    /// File whose headers are sent to the server
+    HeadersFile,
+    /// Client certificate and private key files, read and presented to the server
+    ClientCertificate,
+    /// htpasswd files of the users of the proxy tunnels
+    HtpasswdFiles,
+    /// Firewall rules of the transparent proxy, installed with administrator rights
+    TproxyRules,
+    /// Tun tunnels, which create a network interface and route traffic of this machine into it. Held tunnels are
+    /// kept in the profile, disabled
+    TunTunnels,
+    /// `${ENV_VAR}` and `${secret:NAME}` placeholders, which would send the variables and secrets of this machine
+    /// to the server. Until approved they are kept as literal text
+    Placeholders,
+}
+
+/// Setting taken out of a received profile, put back only once the user approved it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HeldSetting {
+    pub kind: HeldKind,
+    /// The setting as the profile had it
+    pub value: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ClientCertificate {
+    certificate: Option<PathBuf>,
+    private_key: Option<PathBuf>,
+}
+
+/// Setting of a tunnel of the profile, by position
+#[derive(Debug, Serialize, Deserialize)]
+struct TunnelSetting<T> {
+    tunnel: usize,
+    value: T,
+}
+
+/// Take the settings running commands, loading code, reading local files or changing the network of this machine
+/// out of a received profile.
+/// The profile can neither connect nor start on its own: `autoconnect` is cleared.
+pub fn hold(profile: &mut Profile) -> Vec<HeldSetting> {
+    profile.autoconnect = false;
+    let mut held = vec![];
+    let mut take = |kind: HeldKind, value: Option<serde_json::Value>| {
+        if let Some(value) = value {
+            held.push(HeldSetting { kind, value });
+        }
+    };
+    take(
+        HeldKind::BeforeConnect,
+        to_value(profile.before_connect.take()),
+    );
+    take(
+        HeldKind::AfterDisconnect,
+        to_value(profile.after_disconnect.take()),
+    );
+    take(
+        HeldKind::CredentialsProvider,
+        to_value(profile.http_upgrade_credentials_provider.take()),
+    );
+    take(
+        HeldKind::CertificateRenewal,
+        to_value(profile.tls_certificate_renewal.take()),
+    );
+    if matches!(
+        profile.tls_private_key_source,
+        Some(ClientKeySource::Pkcs11 { .. })
+    ) {
+        take(
+            HeldKind::Pkcs11Module,
+            to_value(profile.tls_private_key_source.take()),
+        );
+    }
+    take(
+        HeldKind::HeadersFile,
+        to_value(profile.http_headers_file.take()),
+    );
+    if profile.tls_certificate.is_some() || profile.tls_private_key.is_some() {
+        take(
+            HeldKind::ClientCertificate,
+            to_value(Some(ClientCertificate {
+                certificate: profile.tls_certificate.take(),
+                private_key: profile.tls_private_key.take(),
+            })),
+        );
+    }
+    let htpasswd_files: Vec<_> = profile
+        .tunnels
+        .iter_mut()
+        .enumerate()
+        .filter_map(|(tunnel, config)| {
+            let file = config.listener_auth.as_mut()?.htpasswd_file.take()?;
+            Some(TunnelSetting {
+                tunnel,
+                value: file,
+            })
+        })
+        .collect();
+    take(
+        HeldKind::HtpasswdFiles,
+        to_value(Some(htpasswd_files).filter(|files| !files.is_empty())),
+    );
+    take(HeldKind::TproxyRules, to_value(profile.tproxy_rules.take()));
+    // Shown with their spec, for the user to tell what is enabled again
+    let tun_tunnels: Vec<_> = profile
+        .tunnels
+        .iter_mut()
+        .enumerate()
+        .filter(|(_, config)| config.enabled && config.spec.trim_start().starts_with("tun://"))
+        .map(|(tunnel, config)| {
+            config.enabled = false;
+            TunnelSetting {
+                tunnel,
+                value: config.spec.clone(),
+            }
+        })
+        .collect();
+    take(
+        HeldKind::TunTunnels,
+        to_value(Some(tun_tunnels).filter(|tunnels| !tunnels.is_empty())),
+    );
+    // Once the settings above are out of the profile, they are approved as written, placeholders included
+    take(
+        HeldKind::Placeholders,
+        to_value(placeholders::escape(profile)),
+    );
+    held
+}
+
+/// Put the approved settings back into the profile they were held from
+pub fn restore(profile: &mut Profile, settings: &[HeldSetting]) -> anyhow::Result<()> {
+    // Placeholders were held from the profile without the other settings, they are put back in that same profile
+    let placeholders_first = settings
+        .iter()
+        .filter(|setting| setting.kind == HeldKind::Placeholders)
+        .chain(
+            settings
+                .iter()
+                .filter(|setting| setting.kind != HeldKind::Placeholders),
+        );
+    for setting in placeholders_first {
+        let value = setting.value.clone();
+        let invalid = || format!("Invalid held setting {:?}", setting.kind);
+        match setting.kind {
+            HeldKind::BeforeConnect => {
+                profile.before_connect = serde_json::from_value(value).with_context(invalid)?
+            }
+            HeldKind::AfterDisconnect => {
+                profile.after_disconnect = serde_json::from_value(value).with_context(invalid)?
+            }
+            HeldKind::CredentialsProvider => {
+                profile.http_upgrade_credentials_provider =
+                    serde_json::from_value(value).with_context(invalid)?
+            }
+            HeldKind::CertificateRenewal => {
+                profile.tls_certificate_renewal =
+                    serde_json::from_value(value).with_context(invalid)?
+            }
+            HeldKind::Pkcs11Module => {
+                profile.tls_private_key_source =
+                    serde_json::from_value(value).with_context(invalid)?
+            }
+            HeldKind::HeadersFile => {
+                profile.http_headers_file = serde_json::from_value(value).with_context(invalid)?
+            }
+            HeldKind::ClientCertificate => {
+                let files: ClientCertificate =
+                    serde_json::from_value(value).with_context(invalid)?;
+                profile.tls_certificate = files.certificate;
+                profile.tls_private_key = files.private_key;
+            }
+            HeldKind::HtpasswdFiles => {
+                let files: Vec<TunnelSetting<PathBuf>> =
+                    serde_json::from_value(value).with_context(invalid)?;
+                for file in files {
+                    let auth = profile
+                        .tunnels
+                        .get_mut(file.tunnel)
+                        .and_then(|config| config.listener_auth.as_mut())
+                        .ok_or_else(|| {
+                            anyhow!("The tunnels changed since the settings were held")
+                        })?;
+                    auth.htpasswd_file = Some(file.value);
+                }
+            }
+            HeldKind::TproxyRules => {
+                profile.tproxy_rules = serde_json::from_value(value).with_context(invalid)?
+            }
+            HeldKind::TunTunnels => {
+                let tunnels: Vec<TunnelSetting<String>> =
+                    serde_json::from_value(value).with_context(invalid)?;
+                for tunnel in tunnels {
+                    let config = profile.tunnels.get_mut(tunnel.tunnel).ok_or_else(|| {
+                        anyhow!("The tunnels changed since the settings were held")
+                    })?;
+                    config.enabled = true;
+                }
+            }
+            HeldKind::Placeholders => {
+                let written: Vec<String> = serde_json::from_value(value).with_context(invalid)?;
+                placeholders::restore(profile, &written)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Held settings as shown to the user, without the fields without placeholders
+pub fn redacted(settings: &[HeldSetting]) -> Vec<HeldSetting> {
+    settings
+        .iter()
+        .map(|setting| {
+            let mut setting = setting.clone();
+            if setting.kind == HeldKind::Placeholders {
+                if let Some(fields) = setting.value.as_array_mut() {
+                    for field in fields.iter_mut() {
+                        if !field.as_str().is_some_and(|value| value.contains("${")) {
+                            *field = serde_json::Value::from(REDACTED);
+                        }
+                    }
+                }
+            }
+            setting
+        })
+        .collect()
+}
+
+fn to_value<T: Serialize>(setting: Option<T>) -> Option<serde_json::Value> {
+    setting.and_then(|setting| serde_json::to_value(setting).ok())
+}
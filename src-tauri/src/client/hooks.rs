@@ -0,0 +1,207 @@
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+
+/// Name of the profile the hook runs for, in the environment of its command
+const PROFILE_ENV: &str = "WSTUNNEL_PROFILE";
+/// Left to the output of a finished hook to reach the log. A process the hook started in the background may keep
+/// its output open for as long as it runs
+const OUTPUT_GRACE: Duration = Duration::from_secs(1);
+
+/// Command run around the connection of a profile, i.e: fetching a fresh token to the headers file before
+/// connecting, or unmounting a network share after disconnecting
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hook {
+    /// Program first
+    pub command: Vec<String>,
+    /// The command is killed once running for that long, and considered failed
+    #[serde(default = "default_timeout_sec")]
+    pub timeout_sec: u64,
+    #[serde(default)]
+    pub on_failure: HookFailure,
+}
+
+/// What a failing hook does to the connection. A hook run after disconnecting can only warn
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookFailure {
+    /// The profile does not connect
+    #[default]
+    Abort,
+    /// The failure is logged and the profile connects anyway
+    Warn,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum HookStage {
+    BeforeConnect,
+    AfterDisconnect,
+}
+
+impl fmt::Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookStage::BeforeConnect => write!(f, "before connect"),
+            HookStage::AfterDisconnect => write!(f, "after disconnect"),
+        }
+    }
+}
+
+fn default_timeout_sec() -> u64 {
+    30
+}
+
+impl Hook {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self
+            .command
+            .first()
+            .map(String::as_str)
+            .unwrap_or_default()
+            .is_empty()
+        {
+            return Err(anyhow!("Empty hook command"));
+        }
+        if self.timeout_sec == 0 {
+            return Err(anyhow!("The hook timeout must be at least a second"));
+        }
+        Ok(())
+    }
+
+    /// Run the command to completion, its output going to the log line by line.
+    /// Errors are the ones of the failure policy: a failing hook allowed to warn only logs it
+    pub async fn run(&self, profile_id: &str, stage: HookStage) -> anyhow::Result<()> {
+        let result = self.execute(profile_id, stage).await;
+        match (result, self.on_failure, stage) {
+            (Ok(()), _, _) => Ok(()),
+            (Err(err), HookFailure::Abort, HookStage::BeforeConnect) => Err(err),
+            (Err(err), _, _) => {
+                warn!("{:#}", err);
+                Ok(())
+            }
+        }
+    }
+
+    async fn execute(&self, profile_id: &str, stage: HookStage) -> anyhow::Result<()> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| anyhow!("Empty hook command"))?;
+        info!(
+            "Running {} hook of profile {}: {}",
+            stage, profile_id, program
+        );
+        let mut command = Command::new(program);
+        command
+            .args(args)
+            .env(PROFILE_ENV, profile_id)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        // The hook leads a group of its own, so the processes it started are killed along with it
+        #[cfg(unix)]
+        command.process_group(0);
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Cannot execute {} hook {}", stage, program))?;
+        let mut output = tokio::spawn(log_output(
+            child.stdout.take(),
+            child.stderr.take(),
+            profile_id.to_string(),
+            stage,
+        ));
+        let status =
+            match tokio::time::timeout(Duration::from_secs(self.timeout_sec), child.wait()).await {
+                Ok(status) => status?,
+                Err(_) => {
+                    kill_group(&mut child).await;
+                    output.abort();
+                    return Err(anyhow!(
+                        "The {} hook of profile {} did not finish within {} seconds",
+                        stage,
+                        profile_id,
+                        self.timeout_sec
+                    ));
+                }
+            };
+        if tokio::time::timeout(OUTPUT_GRACE, &mut output)
+            .await
+            .is_err()
+        {
+            output.abort();
+        }
+        if !status.success() {
+            return Err(anyhow!(
+                "The {} hook of profile {} failed with {}",
+                stage,
+                profile_id,
+                status
+            ));
+        }
+        Ok(())
+    }
+}
+
+async fn log_output(
+    stdout: Option<impl AsyncRead + Unpin>,
+    stderr: Option<impl AsyncRead + Unpin>,
+    profile_id: String,
+    stage: HookStage,
+) {
+    tokio::join!(
+        log_lines(stdout, &profile_id, stage, false),
+        log_lines(stderr, &profile_id, stage, true),
+    );
+}
+
+async fn log_lines(
+    output: Option<impl AsyncRead + Unpin>,
+    profile_id: &str,
+    stage: HookStage,
+    stderr: bool,
+) {
+    let Some(output) = output else {
+        return;
+    };
+    let mut lines = BufReader::new(output).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if stderr {
+            warn!("[{} hook of {}] {}", stage, profile_id, line);
+        } else {
+            info!("[{} hook of {}] {}", stage, profile_id, line);
+        }
+    }
+}
+
+/// Kill a hook which timed out along with the processes it started
+#[cfg(unix)]
+async fn kill_group(child: &mut Child) {
+    if let Some(pid) = child.id() {
+        // The group has the id of the hook, its leader
+        unsafe {
+            libc::killpg(pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+    let _ = child.kill().await;
+}
+
+/// Kill a hook which timed out along with the processes it started
+#[cfg(windows)]
+async fn kill_group(child: &mut Child) {
+    if let Some(pid) = child.id() {
+        let _ = Command::new("taskkill")
+            .args(["/T", "/F", "/PID", &pid.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+    }
+    let _ = child.kill().await;
+}
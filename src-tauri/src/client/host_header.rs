@@ -0,0 +1,154 @@
+use crate::client::credentials;
+use crate::client::stats::ProfileStats;
+use crate::parsers;
+use anyhow::anyhow;
+use futures_util::{Stream, StreamExt};
+use log::{debug, error};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tauri::http::HeaderValue;
+
+const PLACEHOLDERS: [&str; 3] = ["{sni}", "{server}", "{host}"];
+
+static NEXT_HOST_FILE: AtomicU64 = AtomicU64::new(0);
+
+/// Host header computed from a template, where `{sni}` is the SNI sent to the server (its name unless overridden),
+/// `{server}` the name of the server and `{host}` one of the rotated hostnames
+#[derive(Debug, Clone)]
+pub struct HostTemplate {
+    pub template: String,
+    pub sni: String,
+    pub server: String,
+    pub hostnames: Vec<String>,
+}
+
+impl HostTemplate {
+    pub fn validate(template: &str, hostnames: &[String]) -> anyhow::Result<()> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let placeholder = rest[start..]
+                .find('}')
+                .map(|end| &rest[start..start + end + 1])
+                .ok_or_else(|| anyhow!("Unclosed placeholder in host template {}", template))?;
+            if !PLACEHOLDERS.contains(&placeholder) {
+                return Err(anyhow!(
+                    "Unknown placeholder {} in host template, expected one of {}",
+                    placeholder,
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+            rest = &rest[start + placeholder.len()..];
+        }
+        if template.contains("{host}") && hostnames.is_empty() {
+            return Err(anyhow!(
+                "Host template uses {{host}} but no hostname to rotate through is set"
+            ));
+        }
+        for hostname in hostnames {
+            HeaderValue::from_str(hostname)
+                .map_err(|_| anyhow!("Invalid hostname {}", hostname))?;
+        }
+        Ok(())
+    }
+
+    /// Host header for the n-th connection
+    pub fn render(&self, n: usize) -> anyhow::Result<HeaderValue> {
+        let mut host = self
+            .template
+            .replace("{sni}", &self.sni)
+            .replace("{server}", &self.server);
+        if !self.hostnames.is_empty() {
+            host = host.replace("{host}", &self.hostnames[n % self.hostnames.len()]);
+        }
+        HeaderValue::from_str(&host).map_err(|_| anyhow!("Invalid host header {}", host))
+    }
+
+    /// Whether the header differs from a connection to another
+    pub fn rotates(&self) -> bool {
+        self.hostnames.len() > 1 && self.template.contains("{host}")
+    }
+}
+
+/// Headers file given to wstunnel in place of the configured one, holding the Host header of the next connection.
+/// wstunnel reads the file for every upgrade request, and a Host found there replaces the default one.
+/// It is rewritten for every connection accepted by the tunnels, so consecutive connections use the next hostname.
+#[derive(Debug)]
+pub struct HostRotation {
+    template: HostTemplate,
+    next: AtomicUsize,
+    path: PathBuf,
+    /// Headers file whose other headers are kept
+    source: Option<PathBuf>,
+}
+
+impl HostRotation {
+    pub fn new(template: HostTemplate, source: Option<PathBuf>) -> anyhow::Result<Self> {
+        let path = credentials::private_dir()?.join(format!(
+            "upgrade-host-{}-{}",
+            std::process::id(),
+            NEXT_HOST_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let rotation = Self {
+            template,
+            next: AtomicUsize::new(0),
+            path,
+            source,
+        };
+        rotation.advance()?;
+        Ok(rotation)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write the Host header of the next connection
+    pub fn advance(&self) -> anyhow::Result<()> {
+        let host = self
+            .template
+            .render(self.next.fetch_add(1, Ordering::Relaxed))?;
+        let mut content = String::new();
+        if let Some(source) = &self.source {
+            for (name, value) in parsers::read_headers_file(source)? {
+                if name != "host" {
+                    content.push_str(&format!("{}: {}\n", name, value.to_str()?));
+                }
+            }
+        }
+        content.push_str(&format!("Host: {}\n", host.to_str()?));
+        debug!("Next upgrade request with host {:?}", host);
+
+        // Write then rename, so wstunnel never reads a partial file. Connections accepted at the same time each
+        // write a file of their own
+        let tmp = self.path.with_extension(format!(
+            "{}.tmp",
+            NEXT_HOST_FILE.fetch_add(1, Ordering::Relaxed)
+        ));
+        credentials::write_private(&tmp, &content)?;
+        std::fs::rename(&tmp, &self.path).inspect_err(|_| {
+            let _ = std::fs::remove_file(&tmp);
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for HostRotation {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Move on to the next hostname for every connection accepted by a tunnel listener, when the profile rotates its Host header
+pub fn rotate_host_listener<L>(listener: L, stats: Arc<ProfileStats>) -> impl Stream<Item = L::Item>
+where
+    L: Stream,
+{
+    listener.inspect(move |_| {
+        if let Some(rotation) = stats.host_rotation.get() {
+            if let Err(err) = rotation.advance() {
+                error!("Cannot rotate host header: {:?}", err);
+            }
+        }
+    })
+}
@@ -0,0 +1,180 @@
+use crate::client::accept::AcceptBackoff;
+use crate::client::access::{self, GatedListener};
+use crate::client::connections::{ClientReader, ConnectionClient};
+use crate::client::listener_auth::{self, AuthorizedUser, CredentialValidator};
+use crate::client::proxy_auth::read_head;
+use crate::client::tasks::TaskGroup;
+use anyhow::anyhow;
+use base64::Engine;
+use futures_util::{stream, Stream};
+use log::{debug, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Semaphore};
+use url::Host;
+use wstunnel::tunnel::{LocalProtocol, RemoteAddr};
+
+/// Time given to a client to send its CONNECT request, when the tunnel has no timeout
+const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PENDING_CONNECTIONS: usize = 256;
+/// Connections yet to send their CONNECT request and credentials. Past that, no more are accepted until one of
+/// them is done, so clients that never send anything cannot pile up
+const PENDING_HANDSHAKES: usize = 64;
+
+type HttpProxyItem = ((ClientReader<OwnedReadHalf>, OwnedWriteHalf), RemoteAddr);
+
+/// Local http proxy answering CONNECT requests, used instead of the one of wstunnel which only knows a single login
+/// and binds its listener by itself. Like it, other methods are refused. Without `auth`, anyone may connect
+pub fn http_proxy_listener(
+    listener: GatedListener,
+    timeout: Option<Duration>,
+    auth: Option<Arc<dyn CredentialValidator>>,
+    proxy_protocol: bool,
+    tasks: &TaskGroup,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<HttpProxyItem>>> {
+    let listen = listener.local_addr()?;
+    let (tx, rx) = mpsc::channel(PENDING_CONNECTIONS);
+    let timeout = timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT);
+
+    let handshakes = Arc::new(Semaphore::new(PENDING_HANDSHAKES));
+
+    tasks.spawn(async move {
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            let Ok(handshake) = handshakes.clone().acquire_owned().await else {
+                return;
+            };
+            let (stream, peer) = match listener.accept().await {
+                Ok(cnx) => {
+                    backoff.succeeded();
+                    cnx
+                }
+                Err(err) => {
+                    warn!(
+                        "Cannot accept http proxy connection on {}: {:?}",
+                        listen, err
+                    );
+                    backoff.failed().await;
+                    continue;
+                }
+            };
+            let (auth, tx) = (auth.clone(), tx.clone());
+            tokio::spawn(async move {
+                let item = tokio::time::timeout(timeout, serve(stream, &auth, proxy_protocol))
+                    .await
+                    .map_err(|_| anyhow!("CONNECT request timed out"));
+                drop(handshake);
+                match item {
+                    Ok(Ok(item)) => {
+                        let _ = tx.send(item).await;
+                    }
+                    Ok(Err(err)) | Err(err) => {
+                        debug!("Http proxy request from {} failed: {:?}", peer, err)
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(stream::unfold(rx, |mut rx| async move {
+        let item = rx.recv().await?;
+        Some((Ok(item), rx))
+    }))
+}
+
+async fn serve(
+    mut stream: TcpStream,
+    auth: &Option<Arc<dyn CredentialValidator>>,
+    proxy_protocol: bool,
+) -> anyhow::Result<HttpProxyItem> {
+    let head = read_head(&mut stream).await?;
+    let mut lines = head.lines();
+    let target = match lines
+        .next()
+        .and_then(|line| line.strip_prefix("CONNECT "))
+        .and_then(|rest| rest.split(' ').next())
+    {
+        Some(target) => target.to_string(),
+        None => {
+            respond(&mut stream, "405 Method Not Allowed").await?;
+            return Err(anyhow!("Only CONNECT requests are supported"));
+        }
+    };
+    let user = match auth {
+        Some(auth) => Some(authenticate(&mut stream, auth, lines).await?),
+        None => None,
+    };
+
+    let destination = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((Host::parse(host).ok()?, port.parse::<u16>().ok()?)));
+    let Some((host, port)) = destination else {
+        respond(&mut stream, "400 Bad Request").await?;
+        return Err(anyhow!("Invalid CONNECT target {}", target));
+    };
+    if let Some(user) = user.as_ref().filter(|user| !user.allows(&host)) {
+        respond(&mut stream, "403 Forbidden").await?;
+        return Err(anyhow!("{} is not allowed to reach {}", user.login, host));
+    }
+    stream
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+
+    // Every connection comes from the gate, which knows the actual client
+    let client = ConnectionClient {
+        peer: stream.peer_addr().ok().and_then(access::gated_peer),
+        user: user.map(|user| user.login),
+    };
+    let (reader, writer) = stream.into_split();
+    let remote = RemoteAddr {
+        protocol: LocalProtocol::Tcp { proxy_protocol },
+        host,
+        port,
+    };
+    Ok(((ClientReader::new(reader, client), writer), remote))
+}
+
+/// User of the Proxy-Authorization header of the request, answered with a 407 when there is none
+async fn authenticate<'a>(
+    stream: &mut TcpStream,
+    auth: &Arc<dyn CredentialValidator>,
+    headers: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<AuthorizedUser> {
+    let credentials = headers
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Basic "))
+        .and_then(|token| {
+            base64::engine::general_purpose::STANDARD
+                .decode(token.trim())
+                .ok()
+        })
+        .and_then(|decoded| {
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (login, password) = decoded.split_once(':')?;
+            Some((login.to_string(), password.to_string()))
+        });
+    let user = match credentials {
+        Some((login, password)) => listener_auth::authenticate(auth, login, password).await,
+        None => None,
+    };
+    let Some(user) = user else {
+        stream
+            .write_all(
+                b"HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"wstunnel\"\r\nContent-Length: 0\r\n\r\n",
+            )
+            .await?;
+        return Err(anyhow!("Missing or invalid credentials"));
+    };
+    Ok(user)
+}
+
+async fn respond(stream: &mut TcpStream, status: &str) -> anyhow::Result<()> {
+    stream
+        .write_all(format!("HTTP/1.1 {}\r\nConnection: close\r\n\r\n", status).as_bytes())
+        .await?;
+    Ok(())
+}
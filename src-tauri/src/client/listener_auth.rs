@@ -0,0 +1,398 @@
+use crate::client::split_tunnel::{parse_rules, Rule};
+use anyhow::{anyhow, Context};
+use argon2::password_hash::{PasswordHash, PasswordVerifier};
+use argon2::Argon2;
+use base64::Engine;
+use log::{debug, warn};
+use md5::{Digest, Md5};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::SystemTime;
+use url::Host;
+
+/// Alphabet of the crypt(3) hashes, apr1 among them
+const CRYPT_ALPHABET: &[u8; 64] =
+    b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Users allowed on a local socks5 or http proxy tunnel, instead of the single login of its spec
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenerAuth {
+    #[serde(default)]
+    pub users: Vec<ListenerUser>,
+    /// htpasswd file of more users, read again whenever it changes. bcrypt (`htpasswd -B`), apr1 (the default of
+    /// htpasswd), `{SHA}` and argon2 hashes are accepted
+    pub htpasswd_file: Option<PathBuf>,
+    /// Destinations each user may reach, by login, in the syntax of the split tunneling rules.
+    /// The users without an entry reach every destination
+    #[serde(default)]
+    pub destinations: HashMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenerUser {
+    pub login: String,
+    /// Hash of the password, in one of the formats of the htpasswd file
+    pub password_hash: String,
+}
+
+impl ListenerAuth {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.users.is_empty() && self.htpasswd_file.is_none() {
+            return Err(anyhow!("No user is allowed, add one or an htpasswd file"));
+        }
+        for user in &self.users {
+            if user.login.is_empty() || user.login.contains(':') {
+                return Err(anyhow!("Invalid login {:?}", user.login));
+            }
+            Hash::parse(&user.password_hash)
+                .with_context(|| format!("Invalid password hash of {}", user.login))?;
+        }
+        for (login, rules) in &self.destinations {
+            parse_rules(rules).with_context(|| format!("Invalid destinations of {}", login))?;
+        }
+        Ok(())
+    }
+}
+
+/// A client of a proxy listener once authenticated
+#[derive(Debug, Clone)]
+pub struct AuthorizedUser {
+    pub login: String,
+    /// Destinations the user may reach, every one when not set
+    destinations: Option<Arc<Vec<Rule>>>,
+}
+
+impl AuthorizedUser {
+    pub fn allows(&self, host: &Host) -> bool {
+        match &self.destinations {
+            Some(rules) => rules.iter().any(|rule| rule.matches(host)),
+            None => true,
+        }
+    }
+}
+
+/// Checks the credentials given to a proxy listener. Checking may be slow, hashes being costly on purpose, so
+/// the listeners call it through `authenticate`
+pub trait CredentialValidator: Debug + Send + Sync {
+    /// The user the credentials are the ones of, None when they are wrong
+    fn validate(&self, login: &str, password: &str) -> Option<AuthorizedUser>;
+}
+
+/// Check the credentials off the runtime threads
+pub async fn authenticate(
+    validator: &Arc<dyn CredentialValidator>,
+    login: String,
+    password: String,
+) -> Option<AuthorizedUser> {
+    let validator = validator.clone();
+    tokio::task::spawn_blocking(move || validator.validate(&login, &password))
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Validator of a listener: its users when it has some, otherwise the login of its spec, if any
+pub fn validator(
+    auth: Option<&ListenerAuth>,
+    credentials: Option<&(String, String)>,
+) -> anyhow::Result<Option<Arc<dyn CredentialValidator>>> {
+    Ok(match (auth, credentials) {
+        (Some(auth), _) => Some(Arc::new(UserDatabase::new(auth)?)),
+        (None, Some((login, password))) => Some(Arc::new(StaticCredentials {
+            login: login.clone(),
+            password: password.clone(),
+        })),
+        (None, None) => None,
+    })
+}
+
+/// The single login and password of a tunnel spec
+struct StaticCredentials {
+    login: String,
+    password: String,
+}
+
+impl Debug for StaticCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StaticCredentials")
+            .field("login", &self.login)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CredentialValidator for StaticCredentials {
+    fn validate(&self, login: &str, password: &str) -> Option<AuthorizedUser> {
+        let matches =
+            (login == self.login) & constant_time_eq(password.as_bytes(), self.password.as_bytes());
+        matches.then(|| AuthorizedUser {
+            login: login.to_string(),
+            destinations: None,
+        })
+    }
+}
+
+/// Users of the profile and of the htpasswd file
+#[derive(Debug)]
+struct UserDatabase {
+    users: HashMap<String, String>,
+    htpasswd: Option<HtpasswdFile>,
+    destinations: HashMap<String, Arc<Vec<Rule>>>,
+    /// Digests of the credentials already checked against their hash, so the connections after the first one of
+    /// a user do not pay for hashing again
+    verified: Mutex<HashSet<[u8; 32]>>,
+}
+
+impl UserDatabase {
+    fn new(auth: &ListenerAuth) -> anyhow::Result<Self> {
+        auth.validate()?;
+        let destinations = auth
+            .destinations
+            .iter()
+            .map(|(login, rules)| Ok((login.clone(), Arc::new(parse_rules(rules)?))))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            users: auth
+                .users
+                .iter()
+                .map(|user| (user.login.clone(), user.password_hash.clone()))
+                .collect(),
+            htpasswd: auth.htpasswd_file.clone().map(HtpasswdFile::new),
+            destinations,
+            verified: Mutex::default(),
+        })
+    }
+
+    fn hash_of(&self, login: &str) -> Option<String> {
+        if let Some(hash) = self.users.get(login) {
+            return Some(hash.clone());
+        }
+        self.htpasswd.as_ref()?.hash_of(login)
+    }
+}
+
+impl CredentialValidator for UserDatabase {
+    fn validate(&self, login: &str, password: &str) -> Option<AuthorizedUser> {
+        let Some(hash) = self.hash_of(login) else {
+            // As long to refuse as a wrong password, so the logins cannot be told from the time taken
+            if let Some(dummy) = dummy_hash() {
+                let _ = bcrypt::verify(password, dummy);
+            }
+            return None;
+        };
+        // Bound to the hash, a password changed in the htpasswd file is checked again
+        let digest: [u8; 32] = Sha256::new()
+            .chain_update(login)
+            .chain_update([0])
+            .chain_update(password)
+            .chain_update([0])
+            .chain_update(&hash)
+            .finalize()
+            .into();
+        if !self.verified.lock().contains(&digest) {
+            match Hash::parse(&hash).and_then(|hash| hash.verify(password)) {
+                Ok(true) => {
+                    self.verified.lock().insert(digest);
+                }
+                Ok(false) => return None,
+                Err(err) => {
+                    warn!("Cannot check the password of {}: {:?}", login, err);
+                    return None;
+                }
+            }
+        }
+        Some(AuthorizedUser {
+            login: login.to_string(),
+            destinations: self.destinations.get(login).cloned(),
+        })
+    }
+}
+
+/// Hash checked against for the unknown logins, made once with the cost of the hashes made by the app
+fn dummy_hash() -> Option<&'static str> {
+    static DUMMY: OnceLock<Option<String>> = OnceLock::new();
+    DUMMY
+        .get_or_init(|| bcrypt::hash("wstunnel", bcrypt::DEFAULT_COST).ok())
+        .as_deref()
+}
+
+/// htpasswd file, `login:hash` per line
+#[derive(Debug)]
+struct HtpasswdFile {
+    path: PathBuf,
+    /// Users as of the last modification time of the file
+    loaded: Mutex<Option<(SystemTime, HashMap<String, String>)>>,
+}
+
+impl HtpasswdFile {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            loaded: Mutex::default(),
+        }
+    }
+
+    fn hash_of(&self, login: &str) -> Option<String> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .map_err(|err| warn!("Cannot read {}: {:?}", self.path.display(), err))
+            .ok()?;
+        let mut loaded = self.loaded.lock();
+        if !loaded.as_ref().is_some_and(|(at, _)| *at == modified) {
+            match std::fs::read_to_string(&self.path) {
+                Ok(content) => {
+                    let users = parse_htpasswd(&content);
+                    debug!("{} users read from {}", users.len(), self.path.display());
+                    *loaded = Some((modified, users));
+                }
+                // The users loaded before are kept, the file may be in the middle of being written
+                Err(err) => warn!("Cannot read {}: {:?}", self.path.display(), err),
+            }
+        }
+        loaded.as_ref()?.1.get(login).cloned()
+    }
+}
+
+fn parse_htpasswd(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .map(|(login, hash)| (login.to_string(), hash.to_string()))
+        .collect()
+}
+
+/// Password hash formats of htpasswd files. Plain text and crypt(3) DES are refused
+enum Hash<'a> {
+    Bcrypt(&'a str),
+    Apr1 { salt: &'a str, hash: &'a str },
+    Sha1(&'a str),
+    Argon2(PasswordHash<'a>),
+}
+
+impl<'a> Hash<'a> {
+    fn parse(hash: &'a str) -> anyhow::Result<Self> {
+        if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$") {
+            Ok(Hash::Bcrypt(hash))
+        } else if let Some(rest) = hash.strip_prefix("$apr1$") {
+            let (salt, hash) = rest
+                .split_once('$')
+                .ok_or_else(|| anyhow!("Invalid apr1 hash"))?;
+            Ok(Hash::Apr1 { salt, hash })
+        } else if let Some(hash) = hash.strip_prefix("{SHA}") {
+            Ok(Hash::Sha1(hash))
+        } else if hash.starts_with("$argon2") {
+            Ok(Hash::Argon2(
+                PasswordHash::new(hash).map_err(|err| anyhow!("Invalid argon2 hash: {}", err))?,
+            ))
+        } else {
+            Err(anyhow!(
+                "Unsupported password hash, use bcrypt (htpasswd -B), apr1, {{SHA}} or argon2"
+            ))
+        }
+    }
+
+    fn verify(&self, password: &str) -> anyhow::Result<bool> {
+        match self {
+            Hash::Bcrypt(hash) => Ok(bcrypt::verify(password, hash)?),
+            Hash::Apr1 { salt, hash } => Ok(constant_time_eq(
+                apr1(password.as_bytes(), salt.as_bytes()).as_bytes(),
+                hash.as_bytes(),
+            )),
+            Hash::Sha1(hash) => {
+                let digest = ring::digest::digest(
+                    &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+                    password.as_bytes(),
+                );
+                let expected = base64::engine::general_purpose::STANDARD.encode(digest);
+                Ok(constant_time_eq(expected.as_bytes(), hash.as_bytes()))
+            }
+            Hash::Argon2(hash) => Ok(Argon2::default()
+                .verify_password(password.as_bytes(), hash)
+                .is_ok()),
+        }
+    }
+}
+
+/// Hash a password for the users of a listener, as `htpasswd -B` does
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    if password.is_empty() {
+        return Err(anyhow!("The password cannot be empty"));
+    }
+    Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?)
+}
+
+/// MD5 based hash of Apache, the encoded digest without its `$apr1$salt$` prefix
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+    let mut context = Md5::new()
+        .chain_update(password)
+        .chain_update(b"$apr1$")
+        .chain_update(salt);
+    for chunk in password.chunks(16) {
+        context.update(&alternate[..chunk.len()]);
+    }
+    let mut length = password.len();
+    while length > 0 {
+        if length & 1 == 1 {
+            context.update([0]);
+        } else {
+            context.update(&password[..1]);
+        }
+        length >>= 1;
+    }
+    let mut digest = context.finalize();
+    for round in 0..1000 {
+        let mut context = Md5::new();
+        if round & 1 == 1 {
+            context.update(password);
+        } else {
+            context.update(digest);
+        }
+        if round % 3 != 0 {
+            context.update(salt);
+        }
+        if round % 7 != 0 {
+            context.update(password);
+        }
+        if round & 1 == 1 {
+            context.update(digest);
+        } else {
+            context.update(password);
+        }
+        digest = context.finalize();
+    }
+
+    let mut encoded = String::with_capacity(22);
+    let mut push = |value: u32, chars: usize| {
+        let mut value = value;
+        for _ in 0..chars {
+            encoded.push(CRYPT_ALPHABET[(value & 0x3f) as usize] as char);
+            value >>= 6;
+        }
+    };
+    for [a, b, c] in [[0, 6, 12], [1, 7, 13], [2, 8, 14], [3, 9, 15], [4, 10, 5]] {
+        push(
+            (digest[a] as u32) << 16 | (digest[b] as u32) << 8 | digest[c] as u32,
+            4,
+        );
+    }
+    push(digest[11] as u32, 2);
+    encoded
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
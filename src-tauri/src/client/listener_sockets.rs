@@ -0,0 +1,94 @@
+use parking_lot::{const_mutex, Mutex};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Public sockets of the local listeners, shared by the whole process.
+/// The sockets in use can be handed to a newer version of the app during an upgrade,
+/// which finds them back here as inherited sockets when binding the same addresses.
+pub struct ListenerSockets {
+    next_id: AtomicU64,
+    owned: Mutex<Vec<(u64, SocketAddr, TcpListener)>>,
+    inherited: Mutex<Vec<(SocketAddr, TcpListener)>>,
+}
+
+static LISTENER_SOCKETS: ListenerSockets = ListenerSockets {
+    next_id: AtomicU64::new(0),
+    owned: const_mutex(Vec::new()),
+    inherited: const_mutex(Vec::new()),
+};
+
+pub fn listener_sockets() -> &'static ListenerSockets {
+    &LISTENER_SOCKETS
+}
+
+/// Keep a socket registered as long as its listener is running
+pub struct Registration {
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        LISTENER_SOCKETS
+            .owned
+            .lock()
+            .retain(|(id, _, _)| *id != self.id);
+    }
+}
+
+impl ListenerSockets {
+    pub fn register(
+        &self,
+        addr: SocketAddr,
+        listener: &tokio::net::TcpListener,
+    ) -> anyhow::Result<Registration> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.owned.lock().push((id, addr, duplicate(listener)?));
+        Ok(Registration { id })
+    }
+
+    /// Copies of the sockets in use, with the address they are bound to
+    pub fn owned(&self) -> anyhow::Result<Vec<(SocketAddr, TcpListener)>> {
+        self.owned
+            .lock()
+            .iter()
+            .map(|(_, addr, listener)| Ok((*addr, listener.try_clone()?)))
+            .collect()
+    }
+
+    /// Whether a running listener is bound to this port
+    pub fn is_bound(&self, port: u16) -> bool {
+        self.owned
+            .lock()
+            .iter()
+            .any(|(_, addr, _)| addr.port() == port)
+    }
+
+    pub fn inherit(&self, sockets: Vec<(SocketAddr, TcpListener)>) {
+        self.inherited.lock().extend(sockets);
+    }
+
+    pub fn take_inherited(&self, addr: SocketAddr) -> Option<TcpListener> {
+        let mut inherited = self.inherited.lock();
+        let i = inherited.iter().position(|(a, _)| *a == addr)?;
+        Some(inherited.remove(i).1)
+    }
+
+    /// Close the inherited sockets no listener has been bound with
+    pub fn clear_inherited(&self) {
+        self.inherited.lock().clear();
+    }
+}
+
+#[cfg(unix)]
+fn duplicate(listener: &tokio::net::TcpListener) -> anyhow::Result<TcpListener> {
+    use std::os::fd::AsFd;
+    Ok(TcpListener::from(listener.as_fd().try_clone_to_owned()?))
+}
+
+#[cfg(windows)]
+fn duplicate(listener: &tokio::net::TcpListener) -> anyhow::Result<TcpListener> {
+    use std::os::windows::io::AsSocket;
+    Ok(TcpListener::from(
+        listener.as_socket().try_clone_to_owned()?,
+    ))
+}
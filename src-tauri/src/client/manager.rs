@@ -0,0 +1,74 @@
+use crate::client::client_api::ConnectedClient;
+use crate::client::profile::Profile;
+use crate::client::user_presence;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A connected profile along with the configuration it has been started with
+#[derive(Debug, Clone)]
+pub struct ManagedClient {
+    pub profile: Profile,
+    /// The profile with its placeholders expanded, as its tunnels run
+    pub expanded: Profile,
+    pub config_hash: String,
+    pub client: ConnectedClient,
+}
+
+/// Keep track of the connected profiles, indexed by profile id.
+/// Shared with the tauri commands as managed state.
+#[derive(Default)]
+pub struct ClientManager {
+    connected: RwLock<HashMap<String, ManagedClient>>,
+}
+
+impl ClientManager {
+    /// Register a connected profile, returning the previous connection of the same profile if any
+    pub fn insert(
+        &self,
+        profile: Profile,
+        expanded: Profile,
+        client: ConnectedClient,
+    ) -> Option<ManagedClient> {
+        let managed = ManagedClient {
+            config_hash: profile.config_hash(),
+            profile,
+            expanded,
+            client,
+        };
+        self.connected
+            .write()
+            .insert(managed.profile.name.clone(), managed)
+    }
+
+    /// Ask the user to authenticate before connecting a profile requiring it, by the profile or by what the app
+    /// recorded in `data_dir`. A profile already connected, i.e: connected again after an edit, is not asked for again
+    pub async fn confirm_connect(&self, data_dir: &Path, profile: &Profile) -> anyhow::Result<()> {
+        if self.get(&profile.name).is_some() {
+            return Ok(());
+        }
+        user_presence::confirm(data_dir, profile).await
+    }
+
+    /// Change a connected profile in place, `None` when it is not connected. Unlike a `get` followed by an `insert`,
+    /// the changes made to the profile in between are kept
+    pub fn update<T>(
+        &self,
+        profile_id: &str,
+        change: impl FnOnce(&mut ManagedClient) -> T,
+    ) -> Option<T> {
+        self.connected.write().get_mut(profile_id).map(change)
+    }
+
+    pub fn remove(&self, profile_id: &str) -> Option<ManagedClient> {
+        self.connected.write().remove(profile_id)
+    }
+
+    pub fn get(&self, profile_id: &str) -> Option<ManagedClient> {
+        self.connected.read().get(profile_id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<ManagedClient> {
+        self.connected.read().values().cloned().collect()
+    }
+}
@@ -1 +1,72 @@
+pub mod accept;
+pub mod access;
+pub mod access_log;
+pub mod app_rules;
+pub mod bridge;
+pub mod buffers;
+pub mod bundle;
+pub mod capture;
+pub mod cert_monitor;
+pub mod chain;
+pub mod cli_format;
 pub mod client_api;
+pub mod client_key;
+pub mod concurrency;
+pub mod connections;
+pub mod credentials;
+pub mod datagrams;
+pub mod dns_cache;
+pub mod dns_leak;
+pub mod dns_stub;
+pub mod dry_run;
+pub mod engine;
+pub mod events;
+pub mod failure_cause;
+pub mod fallback;
+pub mod faults;
+pub mod groups;
+pub mod held_settings;
+pub mod hooks;
+pub mod host_header;
+pub mod http_proxy;
+pub mod listener_auth;
+pub mod listener_sockets;
+pub mod manager;
+pub mod multipath;
+pub mod net_admin;
+pub mod placeholders;
+pub mod platform;
+pub mod port_mapping;
+pub mod presets;
+pub mod profile;
+pub mod proxy_auth;
+pub mod proxy_detect;
+pub mod proxy_protocol;
+pub mod quality;
+pub mod rate_limit;
+pub mod relay;
+pub mod reload;
+pub mod repair;
+pub mod retry_policy;
+pub mod reverse_status;
+pub mod route;
+pub mod schedule;
+pub mod selftest;
+pub mod server_select;
+pub mod server_trust;
+pub mod setup;
+pub mod socks5;
+pub mod split_tunnel;
+pub mod static_hosts;
+pub mod stats;
+pub mod tasks;
+pub mod temp_tunnels;
+pub mod tls_fingerprint;
+pub mod tls_resumption;
+pub mod trace;
+pub mod transport;
+pub mod tun;
+pub mod upgrade_failures;
+pub mod upgrade_timeout;
+pub mod upstream_socket;
+pub mod user_presence;
@@ -0,0 +1,353 @@
+use crate::client::bridge::{Bridge, Target};
+use crate::client::buffers::BufferTuning;
+use crate::client::relay;
+use crate::client::stats::ProfileStats;
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use url::Url;
+
+/// Time between two checks that the server is reachable through each interface
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Time given to an interface to reach the server before the next one is tried
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Experimental: reach the server over several network interfaces, i.e: Wi-Fi and LTE, so the tunnels keep
+/// working while one of them is flaky. This is not bandwidth aggregation: each connection to the server goes out
+/// of a single interface for its whole life, the server having no way to put back together a stream split over
+/// several of them. New connections avoid the interfaces no longer reaching the server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Multipath {
+    /// Names of the network interfaces, in order of preference
+    pub interfaces: Vec<String>,
+    #[serde(default)]
+    pub mode: MultipathMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MultipathMode {
+    /// New connections go through the interfaces reaching the server in turn
+    #[default]
+    #[serde(alias = "stripe")]
+    RoundRobin,
+    /// New connections go through the first interface reaching the server
+    Failover,
+}
+
+impl Multipath {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.interfaces.len() < 2 {
+            return Err(anyhow!("Multipath needs at least two network interfaces"));
+        }
+        if self.interfaces.iter().any(|name| name.trim().is_empty()) {
+            return Err(anyhow!("Network interface names cannot be empty"));
+        }
+        let mut seen = HashSet::new();
+        if let Some(name) = self.interfaces.iter().find(|name| !seen.insert(*name)) {
+            return Err(anyhow!("Network interface {} is listed twice", name));
+        }
+        Ok(())
+    }
+}
+
+/// Interface the connections to the server go through, as seen by the bridge
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PathStatus {
+    pub interface: String,
+    /// Whether the last connection or probe through the interface reached the server
+    pub healthy: bool,
+    /// Connections to the server made through the interface since the profile connected
+    pub connections: u64,
+    pub active_connections: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug)]
+struct Path {
+    interface: String,
+    healthy: AtomicBool,
+    connections: AtomicU64,
+    active_connections: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl Path {
+    fn succeeded(&self) {
+        if !self.healthy.swap(true, Ordering::Relaxed) {
+            info!("Server reachable again through {}", self.interface);
+        }
+        *self.last_error.lock() = None;
+    }
+
+    fn failed(&self, err: &anyhow::Error) {
+        if self.healthy.swap(false, Ordering::Relaxed) {
+            warn!(
+                "Server unreachable through {}, using the other interfaces: {:?}",
+                self.interface, err
+            );
+        }
+        *self.last_error.lock() = Some(format!("{:#}", err));
+    }
+}
+
+/// Interfaces of a multipath profile, kept in its stats while connected
+#[derive(Debug)]
+pub struct Paths {
+    mode: MultipathMode,
+    paths: Vec<Path>,
+    next: AtomicUsize,
+    /// Server probed through every interface
+    server: Target,
+}
+
+impl Paths {
+    fn new(multipath: Multipath, server: Target) -> Self {
+        Self {
+            mode: multipath.mode,
+            paths: multipath
+                .interfaces
+                .into_iter()
+                .map(|interface| Path {
+                    interface,
+                    healthy: AtomicBool::new(true),
+                    connections: AtomicU64::new(0),
+                    active_connections: AtomicU64::new(0),
+                    last_error: Mutex::new(None),
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+            server,
+        }
+    }
+
+    pub fn status(&self) -> Vec<PathStatus> {
+        self.paths
+            .iter()
+            .map(|path| PathStatus {
+                interface: path.interface.clone(),
+                healthy: path.healthy.load(Ordering::Relaxed),
+                connections: path.connections.load(Ordering::Relaxed),
+                active_connections: path.active_connections.load(Ordering::Relaxed),
+                last_error: path.last_error.lock().clone(),
+            })
+            .collect()
+    }
+
+    /// Interfaces to try for a new connection: the healthy ones first, in turn unless failing over, then the others
+    /// as a last resort
+    fn order(&self) -> Vec<&Path> {
+        let (mut healthy, unhealthy): (Vec<&Path>, Vec<&Path>) = self
+            .paths
+            .iter()
+            .partition(|path| path.healthy.load(Ordering::Relaxed));
+        if self.mode == MultipathMode::RoundRobin && !healthy.is_empty() {
+            let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+            healthy.rotate_left(start);
+        }
+        healthy.extend(unhealthy);
+        healthy
+    }
+}
+
+/// Serve a loopback http proxy connecting to the targets through the interfaces of the profile, for wstunnel to
+/// use as its http proxy. The first target is the server, the bridge stops once the profile is disconnected.
+pub async fn spawn_bridge(
+    multipath: Multipath,
+    tuning: BufferTuning,
+    targets: Vec<Target>,
+    stats: &Arc<ProfileStats>,
+) -> anyhow::Result<(Url, Arc<Paths>)> {
+    let server = targets
+        .first()
+        .cloned()
+        .ok_or_else(|| anyhow!("No server to reach through the network interfaces"))?;
+    let bridge = Bridge::bind(targets).await?;
+    info!(
+        "Connecting to the server through {} ({:?})",
+        multipath.interfaces.join(", "),
+        multipath.mode
+    );
+    let paths = Arc::new(Paths::new(multipath, server));
+
+    stats
+        .bridges
+        .spawn(probe(paths.clone(), tuning, Arc::downgrade(stats)));
+    let url = bridge.url()?;
+    let bridged = paths.clone();
+    bridge.serve(stats, move |stream, target| {
+        let paths = bridged.clone();
+        async move { connect_through(stream, &target, &paths, tuning).await }
+    });
+
+    Ok((url, paths))
+}
+
+/// Answer the CONNECT request of wstunnel once the target is reached through one of the interfaces, then relay
+/// the connection
+async fn connect_through(
+    mut inbound: TcpStream,
+    target: &Target,
+    paths: &Paths,
+    tuning: BufferTuning,
+) -> anyhow::Result<()> {
+    let target = target.to_string();
+    let mut last_err = anyhow!("No network interface to reach {}", target);
+    let mut connected = None;
+    for path in paths.order() {
+        match connect(&path.interface, &target, &tuning).await {
+            Ok(stream) => {
+                path.succeeded();
+                connected = Some((path, stream));
+                break;
+            }
+            Err(err) => {
+                path.failed(&err);
+                last_err = err;
+            }
+        }
+    }
+    let Some((path, mut outbound)) = connected else {
+        inbound
+            .write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")
+            .await?;
+        return Err(last_err.context(format!("Cannot reach {}", target)));
+    };
+    inbound
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+    debug!(
+        "Connection to {} established through {}",
+        target, path.interface
+    );
+    path.connections.fetch_add(1, Ordering::Relaxed);
+    path.active_connections.fetch_add(1, Ordering::Relaxed);
+    let relayed = relay::relay(&mut inbound, &mut outbound, &tuning).await;
+    path.active_connections.fetch_sub(1, Ordering::Relaxed);
+    relayed?;
+    Ok(())
+}
+
+/// Check the server can be reached through every interface, so new connections avoid the ones which cannot
+/// before wstunnel waits on them
+async fn probe(paths: Arc<Paths>, tuning: BufferTuning, stats: Weak<ProfileStats>) {
+    let mut interval = tokio::time::interval(PROBE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match stats.upgrade() {
+            Some(stats) if stats.suspended.load(Ordering::Relaxed) => continue,
+            Some(_) => {}
+            None => return,
+        }
+        let server = paths.server.to_string();
+        for path in &paths.paths {
+            let probed =
+                tokio::time::timeout(PROBE_TIMEOUT, connect(&path.interface, &server, &tuning))
+                    .await
+                    .map_err(|_| anyhow!("Timed out"))
+                    .and_then(|connected| connected);
+            match probed {
+                Ok(_) => path.succeeded(),
+                Err(err) => path.failed(&err),
+            }
+        }
+    }
+}
+
+/// Try every address of the target through the interface until one accepts the connection
+async fn connect(
+    interface: &str,
+    target: &str,
+    tuning: &BufferTuning,
+) -> anyhow::Result<TcpStream> {
+    let mut last_err = anyhow!("{} resolves to no address", target);
+    for addr in tokio::net::lookup_host(target).await? {
+        let connected = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            tuning.connect_with(addr, |socket| platform::bind(socket, interface, addr)),
+        )
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("Timed out connecting to {}", addr)));
+        match connected {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err.context(format!("Through {}", interface)))
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use anyhow::Context;
+    use socket2::SockRef;
+    use std::net::SocketAddr;
+    use tokio::net::TcpSocket;
+
+    /// Route the connection out of the interface whatever the routing table prefers (SO_BINDTODEVICE)
+    pub fn bind(socket: &TcpSocket, interface: &str, _addr: SocketAddr) -> anyhow::Result<()> {
+        SockRef::from(socket)
+            .bind_device(Some(interface.as_bytes()))
+            .with_context(|| format!("Cannot bind to network interface {}", interface))
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use anyhow::{anyhow, Context};
+    use socket2::SockRef;
+    use std::ffi::CString;
+    use std::net::SocketAddr;
+    use std::num::NonZeroU32;
+    use tokio::net::TcpSocket;
+
+    /// Route the connection out of the interface whatever the routing table prefers (IP_BOUND_IF)
+    pub fn bind(socket: &TcpSocket, interface: &str, addr: SocketAddr) -> anyhow::Result<()> {
+        let name = CString::new(interface)?;
+        // SAFETY: the name is a valid nul terminated string
+        let index = NonZeroU32::new(unsafe { libc::if_nametoindex(name.as_ptr()) })
+            .ok_or_else(|| anyhow!("Network interface {} not found", interface))?;
+        let socket = SockRef::from(socket);
+        if addr.is_ipv4() {
+            socket.bind_device_by_index_v4(Some(index))
+        } else {
+            socket.bind_device_by_index_v6(Some(index))
+        }
+        .with_context(|| format!("Cannot bind to network interface {}", interface))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod platform {
+    use anyhow::{anyhow, Context};
+    use std::net::SocketAddr;
+    use tokio::net::TcpSocket;
+
+    /// Use an address of the interface as the source of the connection, which Windows routes out of it
+    pub fn bind(socket: &TcpSocket, interface: &str, addr: SocketAddr) -> anyhow::Result<()> {
+        let ip = if_addrs::get_if_addrs()
+            .with_context(|| "Cannot list network interfaces")?
+            .into_iter()
+            .filter(|iface| iface.name == interface)
+            .map(|iface| iface.ip())
+            .find(|ip| ip.is_ipv4() == addr.is_ipv4())
+            .ok_or_else(|| {
+                anyhow!(
+                    "No {} address found for network interface {}",
+                    if addr.is_ipv4() { "IPv4" } else { "IPv6" },
+                    interface
+                )
+            })?;
+        socket.bind(SocketAddr::new(ip, 0))?;
+        Ok(())
+    }
+}
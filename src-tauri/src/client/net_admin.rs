@@ -0,0 +1,163 @@
+use crate::client::platform::Capability;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::path::PathBuf;
+
+/// Capabilities set on the binary: marking sockets needs CAP_NET_ADMIN, transparent sockets CAP_NET_RAW as well
+const FILE_CAPABILITIES: &str = "cap_net_admin,cap_net_raw+ep";
+
+/// Whether the process may use the features needing CAP_NET_ADMIN, and how to get it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetAdminStatus {
+    /// In the effective set of the running process, or the process runs as root
+    pub granted: bool,
+    /// Binary the capability is set on
+    pub binary: Option<PathBuf>,
+    /// `grant` can set the capability, polkit asking for the password of an administrator
+    pub can_grant: bool,
+    /// Why `grant` cannot be used, i.e: the binary is inside a read-only AppImage
+    pub reason: Option<String>,
+}
+
+/// Features needing CAP_NET_ADMIN at runtime on Linux, on top of the platform supporting them
+fn needs_net_admin(capability: Capability) -> bool {
+    matches!(
+        capability,
+        Capability::SocketMark | Capability::TransparentProxy
+    )
+}
+
+/// Fail with what to do when the process lacks the capability a feature needs, instead of the EPERM of the
+/// socket once connecting
+pub fn require(capability: Capability) -> anyhow::Result<()> {
+    capability.require()?;
+    if !needs_net_admin(capability) || has_net_admin() {
+        return Ok(());
+    }
+    Err(anyhow!(
+        "{} requires the CAP_NET_ADMIN capability, which the app does not have. \
+         Grant it from the settings, or run `sudo setcap {} {}`, then restart the app",
+        capability.description(),
+        FILE_CAPABILITIES,
+        std::env::current_exe()
+            .map(|exe| exe.display().to_string())
+            .unwrap_or_else(|_| "<path of the app>".to_string())
+    ))
+}
+
+pub fn status() -> NetAdminStatus {
+    let binary = std::env::current_exe().ok();
+    let reason = platform::grant_blocker();
+    NetAdminStatus {
+        granted: has_net_admin(),
+        binary,
+        can_grant: reason.is_none(),
+        reason,
+    }
+}
+
+fn has_net_admin() -> bool {
+    platform::has_net_admin()
+}
+
+/// Set the capabilities on the binary of the app with polkit elevation. They only apply to the processes started
+/// from it afterwards, the app must be restarted
+pub async fn grant() -> anyhow::Result<()> {
+    if let Some(reason) = platform::grant_blocker() {
+        return Err(anyhow!(reason));
+    }
+    platform::grant().await
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::FILE_CAPABILITIES;
+    use anyhow::{anyhow, Context};
+    use log::info;
+    use std::path::Path;
+    use tokio::process::Command;
+
+    const CAP_NET_ADMIN: u32 = 12;
+    const PKEXEC: &str = "/usr/bin/pkexec";
+    const SETCAP: [&str; 3] = ["/usr/sbin/setcap", "/sbin/setcap", "/usr/bin/setcap"];
+
+    /// Read from the effective set of the process, which root has in full unless it dropped it
+    pub fn has_net_admin() -> bool {
+        let Ok(status) = std::fs::read_to_string("/proc/self/status") else {
+            return false;
+        };
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix("CapEff:"))
+            .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+            .is_some_and(|caps| caps & (1 << CAP_NET_ADMIN) != 0)
+    }
+
+    fn setcap() -> Option<&'static str> {
+        SETCAP.into_iter().find(|path| Path::new(path).exists())
+    }
+
+    pub fn grant_blocker() -> Option<String> {
+        if std::env::var_os("APPIMAGE").is_some() {
+            return Some(
+                "The app runs from an AppImage, whose files cannot be changed: install a package of the app instead"
+                    .to_string(),
+            );
+        }
+        if !Path::new(PKEXEC).exists() {
+            return Some("polkit is not installed, pkexec was not found".to_string());
+        }
+        if setcap().is_none() {
+            return Some(
+                "setcap was not found, install the package providing it (libcap2-bin or libcap)"
+                    .to_string(),
+            );
+        }
+        None
+    }
+
+    pub async fn grant() -> anyhow::Result<()> {
+        let binary =
+            std::env::current_exe().with_context(|| "Cannot find the binary of the app")?;
+        let setcap = setcap().ok_or_else(|| anyhow!("setcap was not found"))?;
+        let output = Command::new(PKEXEC)
+            .arg(setcap)
+            .arg(FILE_CAPABILITIES)
+            .arg(&binary)
+            .output()
+            .await
+            .with_context(|| format!("Cannot execute {}", PKEXEC))?;
+        match output.status.code() {
+            Some(0) => {
+                info!("Set {} on {}", FILE_CAPABILITIES, binary.display());
+                Ok(())
+            }
+            // Exit codes of pkexec when the authentication dialog was dismissed or failed
+            Some(126) | Some(127) => Err(anyhow!("The administrator authentication was cancelled")),
+            _ => Err(anyhow!(
+                "Cannot set the capabilities on {}: {}",
+                binary.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    pub fn has_net_admin() -> bool {
+        false
+    }
+
+    pub fn grant_blocker() -> Option<String> {
+        Some(format!(
+            "Capabilities only exist on Linux, not on {}",
+            std::env::consts::OS
+        ))
+    }
+
+    pub async fn grant() -> anyhow::Result<()> {
+        Err(anyhow::anyhow!("Capabilities only exist on Linux"))
+    }
+}
@@ -0,0 +1,329 @@
+use crate::client::profile::Profile;
+use anyhow::{anyhow, Context};
+use std::path::PathBuf;
+
+/// Keychain service the secrets of the placeholders are saved under, apart from the tokens of the app
+const SECRETS_SERVICE: &str = "wstunnel-desktop-secrets";
+const SECRET_PREFIX: &str = "secret:";
+
+/// Copy of the profile with the `${ENV_VAR}` and `${secret:NAME}` placeholders of its header values, credentials,
+/// paths and tunnel specs replaced by the variable of the process environment or the secret of the keychain,
+/// so an exported profile works on any machine. `$${` is written for a literal `${`.
+/// Tunnels without an id keep their spec as written as id, so they are told apart as before.
+pub fn expand(profile: &Profile) -> anyhow::Result<Profile> {
+    let mut expanded = profile.clone();
+    visit(&mut expanded, |field, value| {
+        *value = expand_str(value)
+            .with_context(|| format!("Cannot expand {} of profile {}", field, profile.name))?;
+        Ok(())
+    })?;
+    for (tunnel, written) in expanded.tunnels.iter_mut().zip(&profile.tunnels) {
+        if tunnel.spec != written.spec && tunnel.id.is_none() {
+            tunnel.id = Some(written.spec.clone());
+        }
+    }
+    Ok(expanded)
+}
+
+/// Turn the placeholders of a profile received from someone else into literal text, so it cannot send the
+/// variables and secrets of this machine to its server. Returns the fields as written when they had any, for
+/// `restore` to put back once the user approved them
+pub fn escape(profile: &mut Profile) -> Option<Vec<String>> {
+    let mut written = vec![];
+    let mut found = false;
+    let _ = visit(profile, |_, value| {
+        written.push(value.clone());
+        let escaped = escape_str(value);
+        found |= escaped != *value;
+        *value = escaped;
+        Ok(())
+    });
+    found.then_some(written)
+}
+
+/// Put back the fields of a profile as written before `escape`
+pub fn restore(profile: &mut Profile, written: &[String]) -> anyhow::Result<()> {
+    let mut written = written.iter();
+    visit(profile, |field, value| {
+        *value = written
+            .next()
+            .ok_or_else(|| anyhow!("The {} changed since the placeholders were held", field))?
+            .clone();
+        Ok(())
+    })?;
+    if written.next().is_some() {
+        return Err(anyhow!(
+            "The profile changed since the placeholders were held"
+        ));
+    }
+    Ok(())
+}
+
+/// Call `visit` on every field placeholders are expanded in, always in the same order
+fn visit(
+    profile: &mut Profile,
+    mut visit: impl FnMut(&str, &mut String) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    for header in &mut profile.http_headers {
+        visit("http header", &mut header.value)?;
+    }
+    for (field, value) in [
+        ("upgrade credentials", &mut profile.http_upgrade_credentials),
+        ("http proxy", &mut profile.http_proxy),
+        ("http proxy login", &mut profile.http_proxy_login),
+        ("http proxy password", &mut profile.http_proxy_password),
+        ("tls sni override", &mut profile.tls_sni_override),
+    ] {
+        if let Some(value) = value {
+            visit(field, value)?;
+        }
+    }
+    for (field, path) in [
+        ("tls certificate", &mut profile.tls_certificate),
+        ("tls private key", &mut profile.tls_private_key),
+        ("http headers file", &mut profile.http_headers_file),
+    ] {
+        // A path which is not unicode has no placeholder to expand
+        let Some(mut value) = path.as_ref().and_then(|p| p.to_str()).map(str::to_string) else {
+            continue;
+        };
+        visit(field, &mut value)?;
+        *path = Some(PathBuf::from(value));
+    }
+    for tunnel in &mut profile.tunnels {
+        visit("tunnel", &mut tunnel.spec)?;
+    }
+    Ok(())
+}
+
+/// Save a secret in the keychain, for profiles to refer to it as `${secret:NAME}`
+pub fn set_secret(name: &str, value: &str) -> anyhow::Result<()> {
+    if name.trim().is_empty() {
+        return Err(anyhow!("The secret name cannot be empty"));
+    }
+    keychain_entry(name)?.set_password(value)?;
+    Ok(())
+}
+
+pub fn delete_secret(name: &str) -> anyhow::Result<()> {
+    match keychain_entry(name)?.delete_credential() {
+        Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn keychain_entry(name: &str) -> anyhow::Result<keyring::Entry> {
+    keyring::Entry::new(SECRETS_SERVICE, name).with_context(|| "Cannot access the keychain")
+}
+
+/// Secret saved in the keychain by `set_secret`
+pub fn secret(name: &str) -> anyhow::Result<String> {
+    match keychain_entry(name)?.get_password() {
+        Ok(value) => Ok(value),
+        Err(keyring::Error::NoEntry) => Err(anyhow!("Secret {} is not in the keychain", name)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn lookup(name: &str) -> anyhow::Result<String> {
+    match name.strip_prefix(SECRET_PREFIX) {
+        Some(secret) => self::secret(secret),
+        None => {
+            std::env::var(name).map_err(|_| anyhow!("Environment variable {} is not set", name))
+        }
+    }
+}
+
+fn expand_str(value: &str) -> anyhow::Result<String> {
+    let mut expanded = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..];
+        let end = placeholder
+            .find('}')
+            .ok_or_else(|| anyhow!("A placeholder is not closed"))?;
+        let name = placeholder[..end].trim();
+        if name.is_empty() || name == SECRET_PREFIX {
+            return Err(anyhow!("A placeholder has no name"));
+        }
+        if name.contains("${") {
+            return Err(anyhow!("Placeholders cannot be nested"));
+        }
+        expanded.push_str(&lookup(name)?);
+        rest = &placeholder[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// `${` written as `$${`, except when it already is
+fn escape_str(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        escaped.push_str(&rest[..start]);
+        if !rest[..start].ends_with('$') {
+            escaped.push('$');
+        }
+        escaped.push_str("${");
+        rest = &rest[start + 2..];
+    }
+    escaped.push_str(rest);
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN_VAR: &str = "WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN";
+    const TOKEN: &str = "s3cr3t-t0ken";
+
+    fn profile() -> Profile {
+        std::env::set_var(TOKEN_VAR, TOKEN);
+        serde_json::from_value(serde_json::json!({
+            "name": "placeholders",
+            "serverAddr": "wss://example.com",
+            "tunnels": [{ "spec": "tcp://1212:${WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN}.internal:443" }],
+            "httpHeaders": [{ "name": "Authorization", "value": "Bearer ${WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN}" }],
+            "httpProxyPassword": "${ WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN }",
+            "tlsCertificate": "/certs/${WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN}.pem",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn expands_variables() {
+        std::env::set_var(TOKEN_VAR, TOKEN);
+        for (value, expected) in [
+            ("plain", "plain"),
+            ("${WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN}", TOKEN),
+            (
+                "a ${ WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN } b",
+                "a s3cr3t-t0ken b",
+            ),
+            (
+                "$WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN",
+                "$WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN",
+            ),
+            ("{}$}", "{}$}"),
+        ] {
+            assert_eq!(expand_str(value).unwrap(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn keeps_escaped_placeholders_literal() {
+        for (value, expected) in [
+            ("$${HOME}", "${HOME}"),
+            ("a$${b}c", "a${b}c"),
+            ("$${unterminated", "${unterminated"),
+            (
+                "$${HOME}${WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN}",
+                "${HOME}s3cr3t-t0ken",
+            ),
+        ] {
+            std::env::set_var(TOKEN_VAR, TOKEN);
+            assert_eq!(expand_str(value).unwrap(), expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_placeholders() {
+        for value in [
+            "${WSTUNNEL_DESKTOP_TEST_UNSET_VARIABLE}",
+            "${unterminated",
+            "prefix ${WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN",
+            "${}",
+            "${  }",
+            "${secret:}",
+            "${WSTUNNEL_DESKTOP_${WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN}}",
+        ] {
+            assert!(expand_str(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn escapes_every_placeholder_once() {
+        for (value, expected) in [
+            ("plain", "plain"),
+            ("${HOME}", "$${HOME}"),
+            ("$${HOME}", "$${HOME}"),
+            ("${A}${B}", "$${A}$${B}"),
+            ("${unterminated", "$${unterminated"),
+        ] {
+            let escaped = escape_str(value);
+            assert_eq!(escaped, expected, "{}", value);
+            assert_eq!(escape_str(&escaped), escaped, "escaping again {}", value);
+        }
+    }
+
+    #[test]
+    fn expanded_values_stay_out_of_the_saved_profile() {
+        let profile = profile();
+        let expanded = expand(&profile).unwrap();
+        assert_eq!(expanded.http_headers[0].value, "Bearer s3cr3t-t0ken");
+        assert_eq!(expanded.http_proxy_password.as_deref(), Some(TOKEN));
+        assert_eq!(
+            expanded.tls_certificate,
+            Some(PathBuf::from("/certs/s3cr3t-t0ken.pem"))
+        );
+        assert_eq!(
+            expanded.tunnels[0].spec,
+            "tcp://1212:s3cr3t-t0ken.internal:443"
+        );
+        assert_eq!(
+            expanded.tunnels[0].id.as_deref(),
+            Some(profile.tunnels[0].spec.as_str()),
+            "the tunnel keeps its spec as written as id"
+        );
+
+        let saved = serde_json::to_string(&profile).unwrap();
+        assert!(!saved.contains(TOKEN), "{}", saved);
+    }
+
+    #[test]
+    fn escaped_profiles_expand_to_their_placeholders() {
+        let mut profile = profile();
+        let written = escape(&mut profile).expect("the profile has placeholders");
+        let expanded = expand(&profile).unwrap();
+        let sent = serde_json::to_string(&expanded).unwrap();
+        assert!(!sent.contains(TOKEN), "{}", sent);
+        assert_eq!(
+            expanded.http_headers[0].value,
+            "Bearer ${WSTUNNEL_DESKTOP_TEST_PLACEHOLDER_TOKEN}"
+        );
+
+        restore(&mut profile, &written).unwrap();
+        assert_eq!(
+            serde_json::to_value(&profile).unwrap(),
+            serde_json::to_value(self::profile()).unwrap()
+        );
+    }
+
+    #[test]
+    fn profiles_without_placeholders_are_not_held() {
+        let mut profile: Profile = serde_json::from_value(serde_json::json!({
+            "name": "plain",
+            "serverAddr": "wss://example.com",
+            "tunnels": [{ "spec": "tcp://1212:internal:443" }],
+        }))
+        .unwrap();
+        assert_eq!(escape(&mut profile), None);
+    }
+
+    #[test]
+    fn restore_refuses_a_changed_profile() {
+        let mut profile = profile();
+        let written = escape(&mut profile).unwrap();
+        profile.tunnels.pop();
+        assert!(restore(&mut profile, &written).is_err());
+    }
+}
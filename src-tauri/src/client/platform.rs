@@ -0,0 +1,314 @@
+use crate::client::client_api::LocalToRemote;
+use anyhow::anyhow;
+use serde::Serialize;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::NamedPipeServer;
+use url::Host;
+use wstunnel::tunnel::listeners::TunnelListener;
+use wstunnel::tunnel::LocalProtocol;
+
+/// Features whose availability depends on the platform the app has been compiled for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    UnixSocket,
+    NamedPipe,
+    TransparentProxy,
+    SocketMark,
+    /// Full device VPN through a TUN interface
+    TunDevice,
+    /// Telling the application behind a connection to a local proxy tunnel
+    AppRouting,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 6] = [
+        Capability::UnixSocket,
+        Capability::NamedPipe,
+        Capability::TransparentProxy,
+        Capability::SocketMark,
+        Capability::TunDevice,
+        Capability::AppRouting,
+    ];
+
+    pub fn is_available(self) -> bool {
+        match self {
+            Capability::UnixSocket => cfg!(unix),
+            Capability::NamedPipe => cfg!(windows),
+            Capability::TransparentProxy | Capability::SocketMark => cfg!(target_os = "linux"),
+            Capability::TunDevice => {
+                cfg!(any(target_os = "linux", target_os = "macos", windows))
+            }
+            Capability::AppRouting => cfg!(any(target_os = "macos", windows)),
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Capability::UnixSocket => "Unix socket",
+            Capability::NamedPipe => "Named pipe",
+            Capability::TransparentProxy => "Transparent proxy",
+            Capability::SocketMark => "Socket mark (SO_MARK)",
+            Capability::TunDevice => "TUN interface",
+            Capability::AppRouting => "Per application routing",
+        }
+    }
+
+    pub fn unavailable(self) -> anyhow::Error {
+        anyhow!(
+            "{} is not available on {}",
+            self.description(),
+            std::env::consts::OS
+        )
+    }
+
+    pub fn require(self) -> anyhow::Result<()> {
+        if self.is_available() {
+            Ok(())
+        } else {
+            Err(self.unavailable())
+        }
+    }
+}
+
+pub fn compiled_capabilities() -> Vec<Capability> {
+    Capability::ALL
+        .into_iter()
+        .filter(|c| c.is_available())
+        .collect()
+}
+
+/// Check that the tunnel can run on this platform, to reject it when loading the profile and not when connecting
+pub fn check_tunnel(tunnel: &LocalToRemote) -> anyhow::Result<()> {
+    if tunnel.tun.is_some() {
+        return Capability::TunDevice.require();
+    }
+    if tunnel.named_pipe.is_some() {
+        return Capability::NamedPipe.require();
+    }
+    let required = match &tunnel.local_protocol {
+        LocalProtocol::Unix { .. } | LocalProtocol::ReverseUnix { .. } => {
+            Some(Capability::UnixSocket)
+        }
+        LocalProtocol::TProxyTcp | LocalProtocol::TProxyUdp { .. } => {
+            Some(Capability::TransparentProxy)
+        }
+        _ => None,
+    };
+
+    match required {
+        Some(capability) => capability.require(),
+        None => Ok(()),
+    }
+}
+
+/// Tunnel listeners that only exist on some platforms.
+/// On platforms lacking a capability, the corresponding constructor fails instead of panicking.
+pub trait PlatformListeners {
+    type Unix: TunnelListener;
+    type NamedPipe: TunnelListener;
+    type TproxyTcp: TunnelListener;
+    type TproxyUdp: TunnelListener;
+
+    fn unix(
+        path: &Path,
+        remote: (Host, u16),
+        proxy_protocol: bool,
+    ) -> impl Future<Output = anyhow::Result<Self::Unix>> + Send;
+
+    /// Listener accepting the clients of a Windows named pipe, i.e: `\\.\pipe\docker_engine`
+    fn named_pipe(
+        name: &str,
+        remote: (Host, u16),
+    ) -> impl Future<Output = anyhow::Result<Self::NamedPipe>> + Send;
+
+    fn tproxy_tcp(
+        local: SocketAddr,
+    ) -> impl Future<Output = anyhow::Result<Self::TproxyTcp>> + Send;
+
+    fn tproxy_udp(
+        local: SocketAddr,
+        timeout: Option<Duration>,
+    ) -> impl Future<Output = anyhow::Result<Self::TproxyUdp>> + Send;
+}
+
+/// Listeners of the platform the app is compiled for
+pub struct NativePlatform;
+
+/// Listener standing for an unavailable one, it never yields any connection
+#[allow(dead_code)]
+type Unsupported = futures_util::stream::Empty<
+    anyhow::Result<(
+        (tokio::io::Empty, tokio::io::Sink),
+        wstunnel::tunnel::RemoteAddr,
+    )>,
+>;
+
+#[cfg(unix)]
+async fn unix_listener(
+    path: &Path,
+    remote: (Host, u16),
+    proxy_protocol: bool,
+) -> anyhow::Result<wstunnel::tunnel::listeners::UnixTunnelListener> {
+    wstunnel::tunnel::listeners::UnixTunnelListener::new(path, remote, proxy_protocol).await
+}
+
+#[cfg(windows)]
+type NamedPipeListener = std::pin::Pin<
+    Box<
+        dyn futures_util::Stream<
+                Item = anyhow::Result<(
+                    (
+                        tokio::io::ReadHalf<NamedPipeServer>,
+                        tokio::io::WriteHalf<NamedPipeServer>,
+                    ),
+                    wstunnel::tunnel::RemoteAddr,
+                )>,
+            > + Send,
+    >,
+>;
+
+/// Serve a named pipe, a new instance of the pipe being created each time a client connects so the next
+/// client finds it. Remote clients are rejected, only processes of this machine can use the pipe.
+#[cfg(windows)]
+fn named_pipe_listener(name: &str, remote: (Host, u16)) -> anyhow::Result<NamedPipeListener> {
+    use anyhow::Context;
+    use tokio::net::windows::named_pipe::ServerOptions;
+
+    let name = name.to_string();
+    let first = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(&name)
+        .with_context(|| format!("Cannot create named pipe {}", name))?;
+    let (host, port) = remote;
+    let listener = futures_util::stream::unfold(Some(first), move |server| {
+        let name = name.clone();
+        let remote = wstunnel::tunnel::RemoteAddr {
+            protocol: LocalProtocol::Tcp {
+                proxy_protocol: false,
+            },
+            host: host.clone(),
+            port,
+        };
+        async move {
+            let server = server?;
+            if let Err(err) = server.connect().await {
+                let next = ServerOptions::new().create(&name).ok();
+                return Some((
+                    Err(anyhow!(
+                        "Cannot accept client of named pipe {}: {}",
+                        name,
+                        err
+                    )),
+                    next,
+                ));
+            }
+            let next = match ServerOptions::new().create(&name) {
+                Ok(next) => Some(next),
+                Err(err) => {
+                    log::error!("Cannot create named pipe {}, stopping: {:?}", name, err);
+                    None
+                }
+            };
+            Some((Ok((tokio::io::split(server), remote)), next))
+        }
+    });
+    Ok(Box::pin(listener))
+}
+
+#[cfg(target_os = "linux")]
+impl PlatformListeners for NativePlatform {
+    type Unix = wstunnel::tunnel::listeners::UnixTunnelListener;
+    type NamedPipe = Unsupported;
+    type TproxyTcp = wstunnel::tunnel::listeners::TproxyTcpTunnelListener;
+    type TproxyUdp = wstunnel::tunnel::listeners::TProxyUdpTunnelListener;
+
+    async fn unix(
+        path: &Path,
+        remote: (Host, u16),
+        proxy_protocol: bool,
+    ) -> anyhow::Result<Self::Unix> {
+        unix_listener(path, remote, proxy_protocol).await
+    }
+
+    async fn named_pipe(_name: &str, _remote: (Host, u16)) -> anyhow::Result<Self::NamedPipe> {
+        Err(Capability::NamedPipe.unavailable())
+    }
+
+    async fn tproxy_tcp(local: SocketAddr) -> anyhow::Result<Self::TproxyTcp> {
+        wstunnel::tunnel::listeners::TproxyTcpTunnelListener::new(local, false).await
+    }
+
+    async fn tproxy_udp(
+        local: SocketAddr,
+        timeout: Option<Duration>,
+    ) -> anyhow::Result<Self::TproxyUdp> {
+        wstunnel::tunnel::listeners::new_tproxy_udp(local, timeout).await
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+impl PlatformListeners for NativePlatform {
+    type Unix = wstunnel::tunnel::listeners::UnixTunnelListener;
+    type NamedPipe = Unsupported;
+    type TproxyTcp = Unsupported;
+    type TproxyUdp = Unsupported;
+
+    async fn unix(
+        path: &Path,
+        remote: (Host, u16),
+        proxy_protocol: bool,
+    ) -> anyhow::Result<Self::Unix> {
+        unix_listener(path, remote, proxy_protocol).await
+    }
+
+    async fn named_pipe(_name: &str, _remote: (Host, u16)) -> anyhow::Result<Self::NamedPipe> {
+        Err(Capability::NamedPipe.unavailable())
+    }
+
+    async fn tproxy_tcp(_local: SocketAddr) -> anyhow::Result<Self::TproxyTcp> {
+        Err(Capability::TransparentProxy.unavailable())
+    }
+
+    async fn tproxy_udp(
+        _local: SocketAddr,
+        _timeout: Option<Duration>,
+    ) -> anyhow::Result<Self::TproxyUdp> {
+        Err(Capability::TransparentProxy.unavailable())
+    }
+}
+
+#[cfg(not(unix))]
+impl PlatformListeners for NativePlatform {
+    type Unix = Unsupported;
+    type NamedPipe = NamedPipeListener;
+    type TproxyTcp = Unsupported;
+    type TproxyUdp = Unsupported;
+
+    async fn unix(
+        _path: &Path,
+        _remote: (Host, u16),
+        _proxy_protocol: bool,
+    ) -> anyhow::Result<Self::Unix> {
+        Err(Capability::UnixSocket.unavailable())
+    }
+
+    async fn named_pipe(name: &str, remote: (Host, u16)) -> anyhow::Result<Self::NamedPipe> {
+        named_pipe_listener(name, remote)
+    }
+
+    async fn tproxy_tcp(_local: SocketAddr) -> anyhow::Result<Self::TproxyTcp> {
+        Err(Capability::TransparentProxy.unavailable())
+    }
+
+    async fn tproxy_udp(
+        _local: SocketAddr,
+        _timeout: Option<Duration>,
+    ) -> anyhow::Result<Self::TproxyUdp> {
+        Err(Capability::TransparentProxy.unavailable())
+    }
+}
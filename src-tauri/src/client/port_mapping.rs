@@ -0,0 +1,361 @@
+use crate::client::events::ClientEvent;
+use crate::client::stats::ProfileStats;
+use crate::client::tasks::TaskGroup;
+use anyhow::{anyhow, Context};
+use igd_next::aio::tokio::Tokio;
+use igd_next::aio::Gateway;
+use igd_next::{PortMappingProtocol, SearchOptions};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Weak;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const NAT_PMP_PORT: u16 = 5351;
+/// NAT-PMP asks to wait 250ms for the first answer, doubling the wait at each attempt
+const NAT_PMP_FIRST_WAIT: Duration = Duration::from_millis(250);
+const NAT_PMP_ATTEMPTS: usize = 4;
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// Lifetime asked for the mappings, renewed halfway through
+const MAPPING_LIFETIME_SEC: u32 = 3600;
+/// Wait before asking again the router that refused or did not answer
+const RETRY_DELAY: Duration = Duration::from_secs(300);
+const DESCRIPTION: &str = "wstunnel-desktop";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingProtocol {
+    Tcp,
+    Udp,
+}
+
+impl From<MappingProtocol> for PortMappingProtocol {
+    fn from(protocol: MappingProtocol) -> Self {
+        match protocol {
+            MappingProtocol::Tcp => PortMappingProtocol::TCP,
+            MappingProtocol::Udp => PortMappingProtocol::UDP,
+        }
+    }
+}
+
+/// How the router has been asked to forward the port
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingMethod {
+    NatPmp,
+    Upnp,
+}
+
+/// A port of the router forwarded to a local listener
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortMapping {
+    pub method: MappingMethod,
+    pub protocol: MappingProtocol,
+    /// Public address of the router, reachable from the internet
+    pub external_addr: SocketAddr,
+    /// Address of the listener on the local network, the connections to the external address are forwarded to
+    pub local_addr: SocketAddr,
+    pub lifetime_sec: u32,
+}
+
+/// Router holding a mapping, to remove it when the tunnel stops
+enum Router {
+    NatPmp {
+        gateway: Ipv4Addr,
+        local_ip: Ipv4Addr,
+    },
+    Upnp(Gateway<Tokio>),
+}
+
+struct Mapped {
+    mapping: PortMapping,
+    router: Option<Router>,
+}
+
+impl Drop for Mapped {
+    fn drop(&mut self) {
+        let (Some(router), Ok(runtime)) =
+            (self.router.take(), tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        let mapping = self.mapping.clone();
+        runtime.spawn(async move {
+            match unmap(router, &mapping).await {
+                Ok(()) => info!("Port mapping {} removed", mapping.external_addr),
+                Err(err) => warn!(
+                    "Cannot remove port mapping {}: {:#}",
+                    mapping.external_addr, err
+                ),
+            }
+        });
+    }
+}
+
+/// Keep a port of the router forwarded to a local listener while the tunnel runs, with NAT-PMP or else UPnP.
+/// The external address is published when obtained or changed, a failure when the router cannot be asked for it.
+pub fn keep_mapped(
+    tunnel_id: String,
+    protocol: MappingProtocol,
+    bound: SocketAddr,
+    stats: Weak<ProfileStats>,
+    tasks: &TaskGroup,
+) {
+    tasks.spawn(async move {
+        let mut mapped: Option<Mapped> = None;
+        let mut failing = false;
+        loop {
+            let previous = mapped.as_ref().map(|mapped| &mapped.mapping);
+            let wait = match map(protocol, bound, previous).await {
+                Ok((mapping, router)) => {
+                    let changed = !previous
+                        .is_some_and(|previous| previous.external_addr == mapping.external_addr);
+                    if changed || failing {
+                        info!(
+                            "Tunnel {} reachable on {} ({:?})",
+                            tunnel_id, mapping.external_addr, mapping.method
+                        );
+                        if let Some(stats) = stats.upgrade() {
+                            stats.publish(ClientEvent::PortMapped {
+                                tunnel_id: tunnel_id.clone(),
+                                mapping: mapping.clone(),
+                            });
+                        }
+                    }
+                    failing = false;
+                    let wait = Duration::from_secs(mapping.lifetime_sec.max(60) as u64 / 2);
+                    // The previous mapping is renewed under the same port, nothing to remove
+                    if let Some(mut previous) = mapped.take() {
+                        if previous.mapping.external_addr == mapping.external_addr {
+                            previous.router = None;
+                        }
+                    }
+                    mapped = Some(Mapped {
+                        mapping,
+                        router: Some(router),
+                    });
+                    wait
+                }
+                Err(err) => {
+                    if !failing {
+                        warn!("Cannot map the port of tunnel {}: {:#}", tunnel_id, err);
+                        if let Some(stats) = stats.upgrade() {
+                            stats.publish(ClientEvent::PortMappingFailed {
+                                tunnel_id: tunnel_id.clone(),
+                                error: format!("{:#}", err),
+                            });
+                        }
+                    }
+                    failing = true;
+                    RETRY_DELAY
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    });
+}
+
+/// Ask the router for the mapping, or to renew the previous one under the same external port
+async fn map(
+    protocol: MappingProtocol,
+    bound: SocketAddr,
+    previous: Option<&PortMapping>,
+) -> anyhow::Result<(PortMapping, Router)> {
+    let bound_ip = match bound.ip() {
+        IpAddr::V4(ip) => ip,
+        // Dual stack listener, reachable on every ipv4 address too
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv4Addr::UNSPECIFIED,
+        IpAddr::V6(_) => return Err(anyhow!("Only ipv4 listeners can be mapped")),
+    };
+    if bound_ip.is_loopback() {
+        return Err(anyhow!(
+            "The listener is bound to the loopback interface, the router cannot reach it"
+        ));
+    }
+    let external_port = previous.map_or(bound.port(), |mapping| mapping.external_addr.port());
+
+    let nat_pmp = match default_gateway() {
+        Ok(gateway) => nat_pmp_map(gateway, protocol, bound_ip, bound.port(), external_port).await,
+        Err(err) => Err(err),
+    };
+    match nat_pmp {
+        Ok(mapped) => return Ok(mapped),
+        Err(err) => debug!("NAT-PMP unavailable, trying UPnP: {:#}", err),
+    }
+    upnp_map(protocol, bound_ip, bound.port(), external_port).await
+}
+
+fn default_gateway() -> anyhow::Result<Ipv4Addr> {
+    let gateway = netdev::get_default_gateway().map_err(|err| anyhow!(err))?;
+    gateway
+        .ipv4
+        .first()
+        .copied()
+        .ok_or_else(|| anyhow!("No ipv4 default gateway"))
+}
+
+/// Address of the listener as seen from the router, the one of the interface facing it when bound to all of them
+fn local_ip(bound_ip: Ipv4Addr, gateway: IpAddr) -> anyhow::Result<Ipv4Addr> {
+    if !bound_ip.is_unspecified() {
+        return Ok(bound_ip);
+    }
+    // Connecting a udp socket sends nothing, it only picks the route
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((gateway, NAT_PMP_PORT))?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(_) => Err(anyhow!("No ipv4 route to the router {}", gateway)),
+    }
+}
+
+async fn nat_pmp_map(
+    gateway: Ipv4Addr,
+    protocol: MappingProtocol,
+    bound_ip: Ipv4Addr,
+    port: u16,
+    external_port: u16,
+) -> anyhow::Result<(PortMapping, Router)> {
+    let local_ip = local_ip(bound_ip, IpAddr::V4(gateway))?;
+    let answer = nat_pmp_request(gateway, local_ip, &[0, 0], 12).await?;
+    let external_ip = Ipv4Addr::new(answer[8], answer[9], answer[10], answer[11]);
+
+    let answer = nat_pmp_request(
+        gateway,
+        local_ip,
+        &nat_pmp_mapping_request(protocol, port, external_port, MAPPING_LIFETIME_SEC),
+        16,
+    )
+    .await?;
+    let mapped_port = u16::from_be_bytes([answer[10], answer[11]]);
+    let lifetime_sec = u32::from_be_bytes([answer[12], answer[13], answer[14], answer[15]]);
+    let mapping = PortMapping {
+        method: MappingMethod::NatPmp,
+        protocol,
+        external_addr: SocketAddr::V4(SocketAddrV4::new(external_ip, mapped_port)),
+        local_addr: SocketAddr::V4(SocketAddrV4::new(local_ip, port)),
+        lifetime_sec,
+    };
+    Ok((mapping, Router::NatPmp { gateway, local_ip }))
+}
+
+fn nat_pmp_mapping_request(
+    protocol: MappingProtocol,
+    port: u16,
+    external_port: u16,
+    lifetime_sec: u32,
+) -> [u8; 12] {
+    let opcode = match protocol {
+        MappingProtocol::Udp => 1,
+        MappingProtocol::Tcp => 2,
+    };
+    let mut request = [0u8; 12];
+    request[1] = opcode;
+    request[4..6].copy_from_slice(&port.to_be_bytes());
+    request[6..8].copy_from_slice(&external_port.to_be_bytes());
+    request[8..12].copy_from_slice(&lifetime_sec.to_be_bytes());
+    request
+}
+
+/// Send a NAT-PMP request from the given local address until the router answers it successfully
+async fn nat_pmp_request(
+    gateway: Ipv4Addr,
+    local_ip: Ipv4Addr,
+    request: &[u8],
+    answer_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let socket = UdpSocket::bind((local_ip, 0)).await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+    let mut wait = NAT_PMP_FIRST_WAIT;
+    let mut answer = [0u8; 16];
+    for _ in 0..NAT_PMP_ATTEMPTS {
+        socket.send(request).await?;
+        let Ok(read) = tokio::time::timeout(wait, socket.recv(&mut answer)).await else {
+            wait *= 2;
+            continue;
+        };
+        let read = read?;
+        if read < answer_len || answer[1] != request[1] + 128 {
+            continue;
+        }
+        return match u16::from_be_bytes([answer[2], answer[3]]) {
+            0 => Ok(answer[..read].to_vec()),
+            code => Err(anyhow!(
+                "The router refused the NAT-PMP request with result code {}",
+                code
+            )),
+        };
+    }
+    Err(anyhow!("No NAT-PMP answer from the router {}", gateway))
+}
+
+async fn upnp_map(
+    protocol: MappingProtocol,
+    bound_ip: Ipv4Addr,
+    port: u16,
+    external_port: u16,
+) -> anyhow::Result<(PortMapping, Router)> {
+    let gateway = igd_next::aio::tokio::search_gateway(SearchOptions {
+        timeout: Some(UPNP_SEARCH_TIMEOUT),
+        ..Default::default()
+    })
+    .await
+    .context("No UPnP router found")?;
+    let local_addr = SocketAddr::new(IpAddr::V4(local_ip(bound_ip, gateway.addr.ip())?), port);
+    let external_port = match gateway
+        .add_port(
+            protocol.into(),
+            external_port,
+            local_addr,
+            MAPPING_LIFETIME_SEC,
+            DESCRIPTION,
+        )
+        .await
+    {
+        Ok(()) => external_port,
+        Err(err) => {
+            debug!(
+                "Port {} unavailable on the router, asking for any: {:?}",
+                external_port, err
+            );
+            gateway
+                .add_any_port(
+                    protocol.into(),
+                    local_addr,
+                    MAPPING_LIFETIME_SEC,
+                    DESCRIPTION,
+                )
+                .await
+                .context("The router refused the UPnP mapping")?
+        }
+    };
+    let external_ip = gateway
+        .get_external_ip()
+        .await
+        .context("The router did not tell its external address")?;
+    let mapping = PortMapping {
+        method: MappingMethod::Upnp,
+        protocol,
+        external_addr: SocketAddr::new(external_ip, external_port),
+        local_addr,
+        lifetime_sec: MAPPING_LIFETIME_SEC,
+    };
+    Ok((mapping, Router::Upnp(gateway)))
+}
+
+async fn unmap(router: Router, mapping: &PortMapping) -> anyhow::Result<()> {
+    match router {
+        Router::NatPmp { gateway, local_ip } => {
+            let request =
+                nat_pmp_mapping_request(mapping.protocol, mapping.local_addr.port(), 0, 0);
+            nat_pmp_request(gateway, local_ip, &request, 16).await?;
+        }
+        Router::Upnp(gateway) => {
+            gateway
+                .remove_port(mapping.protocol.into(), mapping.external_addr.port())
+                .await?;
+        }
+    }
+    Ok(())
+}
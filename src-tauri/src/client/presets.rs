@@ -0,0 +1,174 @@
+use crate::client::buffers::{BufferTuning, TcpKeepalive};
+use crate::client::concurrency::ConnectionOverflow;
+use crate::client::profile::TunnelConfig;
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use std::net::Ipv6Addr;
+
+/// Services a tunnel can be created for without knowing the wstunnel spec grammar
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelPreset {
+    Rdp,
+    Vnc,
+    Ssh,
+    Postgres,
+    Mysql,
+    Smb,
+    KubernetesApi,
+}
+
+/// A preset as listed to the user
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PresetInfo {
+    pub preset: TunnelPreset,
+    pub name: &'static str,
+    pub remote_port: u16,
+    /// Port the tunnel listens on by default, away from the one of a server of the same kind on this machine
+    pub local_port: u16,
+    /// How to point the client application to the tunnel
+    pub usage: &'static str,
+}
+
+impl TunnelPreset {
+    pub const ALL: [TunnelPreset; 7] = [
+        TunnelPreset::Rdp,
+        TunnelPreset::Vnc,
+        TunnelPreset::Ssh,
+        TunnelPreset::Postgres,
+        TunnelPreset::Mysql,
+        TunnelPreset::Smb,
+        TunnelPreset::KubernetesApi,
+    ];
+
+    pub fn info(self) -> PresetInfo {
+        let (name, remote_port, local_port, usage) = match self {
+            TunnelPreset::Rdp => (
+                "Remote Desktop",
+                3389,
+                13389,
+                "Connect the Remote Desktop client to 127.0.0.1:13389",
+            ),
+            TunnelPreset::Vnc => (
+                "VNC",
+                5900,
+                15900,
+                "Connect the VNC viewer to 127.0.0.1::15900, the port given with two colons",
+            ),
+            TunnelPreset::Ssh => ("SSH", 22, 2222, "Run ssh -p 2222 user@127.0.0.1"),
+            TunnelPreset::Postgres => (
+                "PostgreSQL",
+                5432,
+                15432,
+                "Use host 127.0.0.1 and port 15432, TLS of the database is negotiated inside the tunnel",
+            ),
+            TunnelPreset::Mysql => (
+                "MySQL",
+                3306,
+                13306,
+                "Use host 127.0.0.1 and port 13306, not localhost which makes the client use its unix socket",
+            ),
+            TunnelPreset::Smb => (
+                "Windows file sharing (SMB)",
+                445,
+                4445,
+                "Mount //127.0.0.1/share with port 4445. Windows Explorer only reaches port 445, which Windows keeps for itself",
+            ),
+            TunnelPreset::KubernetesApi => (
+                "Kubernetes API",
+                6443,
+                16443,
+                "Set the server of the kubeconfig to https://127.0.0.1:16443 with tls-server-name set to the name of the API server",
+            ),
+        };
+        PresetInfo {
+            preset: self,
+            name,
+            remote_port,
+            local_port,
+            usage,
+        }
+    }
+
+    fn id(self) -> &'static str {
+        match self {
+            TunnelPreset::Rdp => "rdp",
+            TunnelPreset::Vnc => "vnc",
+            TunnelPreset::Ssh => "ssh",
+            TunnelPreset::Postgres => "postgres",
+            TunnelPreset::Mysql => "mysql",
+            TunnelPreset::Smb => "smb",
+            TunnelPreset::KubernetesApi => "kubernetes-api",
+        }
+    }
+
+    /// Keepalive of the local connections. Database and file sharing connections sit idle in pools and mounts
+    /// for long, which firewalls drop silently, the interactive sessions send their own keepalives
+    fn buffers(self) -> Option<BufferTuning> {
+        let keepalive = match self {
+            TunnelPreset::Postgres | TunnelPreset::Mysql | TunnelPreset::Smb => TcpKeepalive {
+                idle_sec: 60,
+                interval_sec: Some(15),
+                count: Some(4),
+            },
+            TunnelPreset::Rdp
+            | TunnelPreset::Vnc
+            | TunnelPreset::Ssh
+            | TunnelPreset::KubernetesApi => return None,
+        };
+        Some(BufferTuning {
+            keepalive: Some(keepalive),
+            ..BufferTuning::default()
+        })
+    }
+}
+
+/// Tunnel of the preset to a host reachable by the server, listening on loopback only.
+/// None of these services understands the proxy protocol, so no header is sent
+pub fn create(
+    preset: TunnelPreset,
+    remote_host: &str,
+    remote_port: Option<u16>,
+    local_port: Option<u16>,
+) -> anyhow::Result<TunnelConfig> {
+    let remote_host = remote_host.trim().trim_matches(['[', ']']);
+    if remote_host.is_empty() || remote_host.contains(['/', ' ', '?']) {
+        return Err(anyhow!("Invalid remote host {}", remote_host));
+    }
+    let info = preset.info();
+    let remote_port = remote_port.unwrap_or(info.remote_port);
+    let local_port = local_port.unwrap_or(info.local_port);
+    let host = if remote_host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]", remote_host)
+    } else {
+        remote_host.to_string()
+    };
+    let config = TunnelConfig {
+        spec: format!("tcp://127.0.0.1:{}:{}:{}", local_port, host, remote_port),
+        id: Some(format!("{}-{}", preset.id(), remote_host)),
+        reverse: false,
+        group: None,
+        rate_limit_up: None,
+        rate_limit_down: None,
+        bind_interface: None,
+        allowed_sources: vec![],
+        dual_stack: false,
+        buffers: preset.buffers(),
+        set_system_proxy: false,
+        pac_domains: vec![],
+        lazy: false,
+        proxy_header: None,
+        port_mapping: false,
+        datagrams: None,
+        access_log: false,
+        listener_auth: None,
+        max_concurrent_connections: None,
+        connection_overflow: ConnectionOverflow::default(),
+        enabled: true,
+    };
+    config
+        .to_tunnel()
+        .with_context(|| format!("Invalid {} tunnel to {}", info.name, remote_host))?;
+    Ok(config)
+}
@@ -0,0 +1,931 @@
+use crate::auth::OAuthConfig;
+use crate::client::access::AccessPolicy;
+use crate::client::app_rules::AppRouting;
+use crate::client::buffers::BufferTuning;
+use crate::client::cert_monitor::{self, CertificateRenewal};
+use crate::client::chain::ProfileChain;
+use crate::client::client_api::{Client, LocalToRemote, DEFAULT_CLIENT_UPGRADE_PATH_PREFIX};
+use crate::client::client_key::ClientKeySource;
+use crate::client::concurrency::ConnectionOverflow;
+use crate::client::credentials::CredentialsProvider;
+use crate::client::datagrams::DatagramOptions;
+use crate::client::dns_stub::DnsStub;
+use crate::client::hooks::Hook;
+use crate::client::host_header::HostTemplate;
+use crate::client::listener_auth::ListenerAuth;
+use crate::client::multipath::Multipath;
+use crate::client::platform::{self, Capability};
+use crate::client::proxy_auth::HttpProxyAuth;
+use crate::client::proxy_detect::ProxyDetection;
+use crate::client::proxy_protocol::ProxyHeader;
+use crate::client::retry_policy::RetryPolicy;
+use crate::client::schedule::ActiveWindow;
+use crate::client::split_tunnel::SplitTunnel;
+use crate::client::static_hosts::{self, StaticHost};
+use crate::client::tls_fingerprint::TlsFingerprint;
+use crate::client::transport::{self, TlsVersion};
+use crate::client::upgrade_failures::{self, UpgradeFailureRule};
+use crate::parsers::parse_tunnel_spec;
+use crate::system_proxy::ProxyKind;
+use crate::tproxy_rules::TproxyRules;
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use tauri::http::header::HOST;
+use tauri::http::{HeaderName, HeaderValue};
+use tauri::Url;
+use tokio_rustls::rustls::pki_types::DnsName;
+use wstunnel::tunnel::LocalProtocol;
+
+const DEFAULT_RETRY_MAX_BACKOFF_SEC: u64 = 300;
+const DEFAULT_CONNECT_TIMEOUT_SEC: u64 = 10;
+const REDACTED: &str = "<redacted>";
+const SENSITIVE_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "x-api-key",
+];
+
+/// Client configuration as saved by the frontend in the store
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub name: String,
+    pub server_addr: Url,
+    /// Other servers of the profile. Each time it connects, the one answering the upgrade the fastest is used,
+    /// `server_addr` included
+    #[serde(default)]
+    pub server_candidates: Vec<Url>,
+    #[serde(default)]
+    pub tunnels: Vec<TunnelConfig>,
+    pub socket_so_mark: Option<u32>,
+    #[serde(default)]
+    pub connection_min_idle: u32,
+    #[serde(default = "default_retry_max_backoff_sec")]
+    pub connection_retry_max_backoff_sec: u64,
+    /// How failing tunnels are restarted, and when to give up on them
+    #[serde(default)]
+    pub retry_policy: RetryPolicy,
+    /// Time allowed to connect to the server, longer for high latency links (i.e: satellite), shorter to fail fast on a LAN
+    #[serde(default = "default_connect_timeout_sec")]
+    pub connect_timeout_sec: u64,
+    /// Time allowed to the server to answer the upgrade request, unbounded when not set
+    pub upgrade_timeout_sec: Option<u64>,
+    pub tls_sni_override: Option<String>,
+    #[serde(default)]
+    pub tls_sni_disable: bool,
+    #[serde(default)]
+    pub tls_verify_certificate: bool,
+    /// SHA-256 fingerprints of the only server certificates accepted when certificates are not verified, the ones
+    /// the user trusted are added when connecting
+    #[serde(default)]
+    pub tls_pinned_certificates: Vec<String>,
+    pub tls_certificate: Option<PathBuf>,
+    pub tls_private_key: Option<PathBuf>,
+    /// Token or OS keystore holding the key of the client certificate, instead of `tls_private_key`
+    pub tls_private_key_source: Option<ClientKeySource>,
+    /// Warn that many days before the client certificate expires
+    #[serde(default = "default_certificate_warning_days")]
+    pub tls_certificate_warning_days: u64,
+    /// Renew the client certificate before it expires, while connected
+    pub tls_certificate_renewal: Option<CertificateRenewal>,
+    /// Restrict the handshake to these versions of TLS, i.e: 1.3 only for middleboxes letting nothing else through
+    pub tls_min_version: Option<TlsVersion>,
+    pub tls_max_version: Option<TlsVersion>,
+    /// Protocols offered in the TLS handshake instead of the ones of the transport, i.e: `h2` and `http/1.1`
+    pub tls_alpn: Option<Vec<String>>,
+    /// Cipher suites and key exchange groups offered in the ClientHello, in the order of a browser. The rest of the
+    /// handshake stays the one of rustls
+    #[serde(default)]
+    pub tls_fingerprint: TlsFingerprint,
+    pub http_proxy: Option<String>,
+    pub http_proxy_login: Option<String>,
+    pub http_proxy_password: Option<String>,
+    #[serde(default)]
+    pub http_proxy_auth: HttpProxyAuth,
+    /// Look the proxy up in the OS settings or a PAC file on every connection, instead of setting `http_proxy`
+    #[serde(default)]
+    pub http_proxy_detection: ProxyDetection,
+    /// Reach the server through this socks5 proxy instead of an http one, i.e: `socks5://127.0.0.1:9050` for Tor
+    pub socks5_proxy: Option<Url>,
+    /// Reach the server through a local proxy tunnel of another profile, which is connected first
+    pub chain: Option<ProfileChain>,
+    pub http_upgrade_path_prefix: Option<String>,
+    /// `login:password` sent as basic auth during the upgrade request
+    pub http_upgrade_credentials: Option<String>,
+    /// Short-lived token sent instead of `http_upgrade_credentials`, fetched again before it expires
+    pub http_upgrade_credentials_provider: Option<CredentialsProvider>,
+    /// What to do when the server rejects the upgrade request with a given status, instead of retrying with a backoff
+    #[serde(default)]
+    pub upgrade_failure_rules: Vec<UpgradeFailureRule>,
+    #[serde(default)]
+    pub http_headers: Vec<HttpHeader>,
+    pub http_headers_file: Option<PathBuf>,
+    /// Host header of the upgrade request computed from `{sni}`, `{server}` and `{host}`, i.e: `{host}.cdn.example.com`.
+    /// `{host}` is the next of `http_host_rotation` for every connection, defaults to `{host}` when only a rotation is set
+    pub http_host_template: Option<String>,
+    /// Hostnames rotated through by the connections of the profile, i.e: for a CDN routing tenants by Host
+    #[serde(default)]
+    pub http_host_rotation: Vec<String>,
+    pub websocket_ping_frequency_sec: Option<u64>,
+    #[serde(default)]
+    pub websocket_mask_frame: bool,
+    /// Buffers of the local sockets of the tcp based tunnels, i.e: larger ones for a high bandwidth-delay product link
+    pub buffers: Option<BufferTuning>,
+    /// Keepalive and buffers of the connection to the server, i.e: to get through a stateful firewall dropping
+    /// idle connections. Applied through a loopback bridge, not when the server is reached through a proxy
+    pub upstream_socket: Option<BufferTuning>,
+    /// Experimental: reach the server over several network interfaces at once, i.e: Wi-Fi and LTE
+    #[serde(default)]
+    pub multipath: Option<Multipath>,
+    #[serde(default)]
+    pub dns_resolver: Vec<Url>,
+    #[serde(default)]
+    pub dns_resolver_prefer_ipv4: bool,
+    /// Remember the addresses of the server and of the reverse tunnel targets across restarts, for as long as
+    /// their TTL allows, so a slow or blocked DNS does not hold the connection
+    #[serde(default)]
+    pub persist_dns: bool,
+    /// Local socks5 tunnels refuse the destinations the application resolved by itself, so every name is resolved
+    /// by the server and no dns request leaks outside the tunnel. Private and loopback addresses are still allowed
+    #[serde(default)]
+    pub force_remote_dns: bool,
+    /// Local dns listener resolving every name through the tunnel, for the OS to use as its resolver
+    pub dns_stub: Option<DnsStub>,
+    /// Names resolved to a fixed address instead of asking the resolvers, for the server and the tunnel targets
+    #[serde(default)]
+    pub static_hosts: Vec<StaticHost>,
+    /// Destinations of the local proxy tunnels going through the tunnel, the others are connected directly
+    pub split_tunnel: Option<SplitTunnel>,
+    /// Applications whose connections to the local proxy tunnels go through the tunnel, directly or nowhere
+    pub app_routing: Option<AppRouting>,
+    #[serde(default)]
+    pub transport_fallback: bool,
+    /// Connect the profile when the app is started at login
+    #[serde(default)]
+    pub autoconnect: bool,
+    /// Connect the profile while in one of these windows and disconnect it outside, unscheduled when empty
+    #[serde(default)]
+    pub schedule: Vec<ActiveWindow>,
+    /// Show native notifications when the connection drops or the client certificate expires
+    #[serde(default)]
+    pub notifications: bool,
+    /// Identity provider to get a bearer token from, sent in the upgrade request
+    pub oauth: Option<OAuthConfig>,
+    /// Run the tunnels in a separate process instead of in the app, which gives up root when the app runs as root,
+    /// and is denied writing files and running commands it does not need on Linux and macOS
+    #[serde(default)]
+    pub isolated: bool,
+    /// Data the profile may send and receive per calendar month, in megabytes.
+    /// Unless `budget_disconnect` is unset, the profile is disconnected once it used it up, and cannot connect
+    /// again until the next month.
+    pub monthly_budget_mb: Option<u64>,
+    /// Percentages of the monthly budget past which the user is warned, once per month each
+    #[serde(default = "default_budget_warning_percents")]
+    pub budget_warning_percents: Vec<u8>,
+    /// Disconnect the profile once it used up its budget, instead of only warning
+    #[serde(default = "default_budget_disconnect")]
+    pub budget_disconnect: bool,
+    /// Disconnect the profile once its tunnels carried no traffic for that long
+    pub idle_disconnect_after_sec: Option<u64>,
+    /// Only close the connections to the server once idle, keeping the listeners bound so the next local
+    /// connection reconnects, instead of disconnecting the profile
+    #[serde(default)]
+    pub idle_reconnect_on_demand: bool,
+    /// Bind the listeners right away but only connect to the server on the first local connection, closing the
+    /// connections again once the tunnels are idle for `idle_disconnect_after_sec`, 5 minutes when not set
+    #[serde(default)]
+    pub on_demand: bool,
+    /// Command run before connecting, i.e: to fetch a fresh token to the headers file
+    pub before_connect: Option<Hook>,
+    /// Command run once disconnected, i.e: to unmount a network share reached through the tunnels
+    pub after_disconnect: Option<Hook>,
+    /// Block the traffic of the machine not going to the server while the profile is connected, so nothing leaks
+    /// onto the network when the tunnel drops. Installing the firewall rules requires administrator privileges
+    #[serde(default)]
+    pub kill_switch: bool,
+    /// Firewall and routing rules sending the traffic to a transparent proxy tunnel of the profile, installed
+    /// once connected and removed once disconnected
+    #[serde(default)]
+    pub tproxy_rules: Option<TproxyRules>,
+    /// Only connect once the user authenticated with the OS (Windows Hello, Touch ID, polkit), so an unlocked
+    /// machine cannot bring up the tunnels of a sensitive profile unnoticed. Recorded by the app once confirmed,
+    /// clearing it here is not enough to turn it off
+    #[serde(default)]
+    pub require_confirmation: bool,
+    /// Source the profile is fetched from, it is then edited by its administrator rather than by the user
+    #[serde(default)]
+    pub managed_by: Option<Url>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HttpHeader {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelConfig {
+    /// Tunnel written with the wstunnel cli grammar, i.e: tcp://1212:google.com:443
+    pub spec: String,
+    /// Identify the tunnel in the profile, the spec is used when not set
+    pub id: Option<String>,
+    /// Remote to local tunnel (-R of the cli) instead of local to remote (-L)
+    #[serde(default)]
+    pub reverse: bool,
+    /// Group of tunnels started and stopped together, i.e: dev, prod or db
+    pub group: Option<String>,
+    pub rate_limit_up: Option<u64>,
+    pub rate_limit_down: Option<u64>,
+    pub bind_interface: Option<String>,
+    #[serde(default)]
+    pub allowed_sources: Vec<IpNet>,
+    /// Tcp based tunnels listening on every address only, accept both ipv4 and ipv6 clients with one listener
+    #[serde(default)]
+    pub dual_stack: bool,
+    /// Buffers of this tunnel, instead of the ones of the profile
+    pub buffers: Option<BufferTuning>,
+    /// Point the OS proxy settings to this tunnel while the profile is connected
+    #[serde(default)]
+    pub set_system_proxy: bool,
+    /// Domains routed through this tunnel by the PAC file of the profile, for socks5 and http proxy tunnels
+    #[serde(default)]
+    pub pac_domains: Vec<String>,
+    /// Only start the tunnel when the first local connection arrives
+    #[serde(default)]
+    pub lazy: bool,
+    /// Tcp and reverse tcp tunnels only, proxy protocol header written by the app ahead of each connection.
+    /// Replaces the `proxy_protocol` option of the spec, whose header is written by the server and carries
+    /// the address of the client machine rather than the one of the application that connected
+    pub proxy_header: Option<ProxyHeader>,
+    /// Ask the router, with NAT-PMP or UPnP, to forward a port of its public address to the local listener,
+    /// for it to be reachable from the internet. Socks5 and http proxy tunnels need users, a login or allowed sources
+    #[serde(default)]
+    pub port_mapping: bool,
+    /// Udp tunnels only, maximum datagram size, what becomes of larger ones, and idle timeout of the flows
+    pub datagrams: Option<DatagramOptions>,
+    /// Local tunnels only, log the source, destination, traffic and duration of each connection, i.e: to audit a
+    /// socks5 proxy shared on the LAN
+    #[serde(default)]
+    pub access_log: bool,
+    /// Socks5 and http proxy tunnels only, users allowed on the listener and the destinations each may reach,
+    /// instead of the single login of the spec
+    pub listener_auth: Option<ListenerAuth>,
+    /// Local tunnels only, connections open at once, so a misbehaving application cannot trip the limits of the
+    /// server. Unlimited if not set
+    pub max_concurrent_connections: Option<u32>,
+    /// What becomes of the connections over the limit
+    #[serde(default)]
+    pub connection_overflow: ConnectionOverflow,
+    /// A disabled tunnel is kept in the profile but not started, so it can be toggled without losing its settings
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+fn default_retry_max_backoff_sec() -> u64 {
+    DEFAULT_RETRY_MAX_BACKOFF_SEC
+}
+
+fn default_connect_timeout_sec() -> u64 {
+    DEFAULT_CONNECT_TIMEOUT_SEC
+}
+
+fn default_budget_warning_percents() -> Vec<u8> {
+    vec![80, 90]
+}
+
+fn default_budget_disconnect() -> bool {
+    true
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_certificate_warning_days() -> u64 {
+    cert_monitor::DEFAULT_WARNING_DAYS
+}
+
+impl TunnelConfig {
+    pub fn to_tunnel(&self) -> anyhow::Result<LocalToRemote> {
+        let mut tunnel = parse_tunnel_spec(&self.spec, self.reverse)?;
+        if let Some(id) = &self.id {
+            tunnel.id = id.clone();
+        }
+        tunnel.rate_limit_up = self.rate_limit_up;
+        tunnel.rate_limit_down = self.rate_limit_down;
+        tunnel.lazy = self.lazy;
+        tunnel.access = AccessPolicy {
+            bind_interface: self.bind_interface.clone(),
+            allowed_sources: self.allowed_sources.clone(),
+            dual_stack: self.dual_stack,
+        };
+        if self.dual_stack {
+            let tcp_based = matches!(
+                tunnel.local_protocol,
+                LocalProtocol::Tcp { .. }
+                    | LocalProtocol::Socks5 { .. }
+                    | LocalProtocol::HttpProxy { .. }
+            );
+            if !tcp_based || tunnel.named_pipe.is_some() {
+                return Err(anyhow!(
+                    "Tunnel {} cannot be dual stack, only tcp, socks5 and http proxy tunnels can",
+                    tunnel.id
+                ));
+            }
+            if !tunnel.local.ip().is_unspecified() || self.bind_interface.is_some() {
+                return Err(anyhow!(
+                    "Tunnel {} cannot be dual stack, it must listen on every address, i.e: 0.0.0.0 or [::]",
+                    tunnel.id
+                ));
+            }
+        }
+        tunnel.buffers = self.buffers.unwrap_or_default();
+        if let Some(header) = self.proxy_header {
+            match tunnel.local_protocol {
+                LocalProtocol::Tcp {
+                    proxy_protocol: true,
+                } => {
+                    return Err(anyhow!(
+                        "Tunnel {} cannot have both a proxy header and the proxy_protocol option",
+                        tunnel.id
+                    ));
+                }
+                LocalProtocol::Tcp { .. } if header.trusted_load_balancer => {
+                    return Err(anyhow!(
+                        "Tunnel {} cannot trust a load balancer, only reverse tcp tunnels can",
+                        tunnel.id
+                    ));
+                }
+                LocalProtocol::Tcp { .. } | LocalProtocol::ReverseTcp => {
+                    tunnel.proxy_header = Some(header)
+                }
+                _ => {
+                    return Err(anyhow!(
+                        "Tunnel {} cannot have a proxy header, only tcp tunnels can",
+                        tunnel.id
+                    ));
+                }
+            }
+        }
+        if self.port_mapping {
+            let listens = matches!(
+                tunnel.local_protocol,
+                LocalProtocol::Tcp { .. }
+                    | LocalProtocol::Udp { .. }
+                    | LocalProtocol::Socks5 { .. }
+                    | LocalProtocol::HttpProxy { .. }
+            );
+            if !listens || tunnel.named_pipe.is_some() || tunnel.tun.is_some() {
+                return Err(anyhow!(
+                    "Tunnel {} cannot map a port, only tunnels listening on a tcp or udp port can",
+                    tunnel.id
+                ));
+            }
+            // An open proxy on the public address of the router relays anyone from the internet through the server
+            let login = match &tunnel.local_protocol {
+                LocalProtocol::Socks5 { credentials, .. }
+                | LocalProtocol::HttpProxy { credentials, .. } => Some(credentials.is_some()),
+                _ => None,
+            };
+            let restricted = !self.allowed_sources.is_empty()
+                && self.allowed_sources.iter().all(|net| net.prefix_len() > 0);
+            if login == Some(false) && self.listener_auth.is_none() && !restricted {
+                return Err(anyhow!(
+                    "Tunnel {} cannot map a port without users, a login or allowed sources, it would be an open proxy",
+                    tunnel.id
+                ));
+            }
+            tunnel.port_mapping = true;
+        }
+        if let Some(datagrams) = self.datagrams {
+            let udp = matches!(
+                tunnel.local_protocol,
+                LocalProtocol::Udp { .. } | LocalProtocol::TProxyUdp { .. }
+            );
+            if !udp || tunnel.tun.is_some() {
+                return Err(anyhow!(
+                    "Tunnel {} cannot have datagram options, only local udp tunnels can",
+                    tunnel.id
+                ));
+            }
+            datagrams.validate()?;
+            tunnel.datagrams = datagrams;
+        }
+        if self.access_log {
+            if self.reverse {
+                return Err(anyhow!(
+                    "Tunnel {} cannot log its accesses, only local tunnels can",
+                    tunnel.id
+                ));
+            }
+            tunnel.access_log = true;
+        }
+        if let Some(auth) = &self.listener_auth {
+            let credentials = match &tunnel.local_protocol {
+                LocalProtocol::Socks5 { credentials, .. }
+                | LocalProtocol::HttpProxy { credentials, .. } => credentials,
+                _ => {
+                    return Err(anyhow!(
+                        "Tunnel {} cannot have users, only local socks5 and http proxy tunnels can",
+                        tunnel.id
+                    ));
+                }
+            };
+            if credentials.is_some() {
+                return Err(anyhow!(
+                    "Tunnel {} cannot have both users and the login of its spec",
+                    tunnel.id
+                ));
+            }
+            auth.validate()
+                .with_context(|| format!("Invalid users of tunnel {}", tunnel.id))?;
+            tunnel.listener_auth = Some(auth.clone());
+        }
+        if let Some(max) = self.max_concurrent_connections {
+            if self.reverse {
+                return Err(anyhow!(
+                    "Tunnel {} cannot limit its connections, only local tunnels can",
+                    tunnel.id
+                ));
+            }
+            if max == 0 {
+                return Err(anyhow!(
+                    "Tunnel {} must allow at least one connection",
+                    tunnel.id
+                ));
+            }
+            self.connection_overflow.validate()?;
+            tunnel.max_concurrent_connections = Some(max);
+            tunnel.connection_overflow = self.connection_overflow;
+        }
+        Ok(tunnel)
+    }
+}
+
+impl Profile {
+    /// Tunnels started when the profile connects
+    pub fn enabled_tunnels(&self) -> impl Iterator<Item = &TunnelConfig> {
+        self.tunnels.iter().filter(|tunnel| tunnel.enabled)
+    }
+
+    /// Tunnel as run by this profile, with the settings of the profile applying to every tunnel
+    pub fn tunnel(&self, config: &TunnelConfig) -> anyhow::Result<LocalToRemote> {
+        let mut tunnel = config.to_tunnel()?;
+        tunnel.force_remote_dns = self.force_remote_dns;
+        tunnel.buffers = config.buffers.or(self.buffers).unwrap_or_default();
+        if ProxyKind::of(&tunnel.local_protocol).is_some() {
+            tunnel.split = self
+                .split_tunnel
+                .as_ref()
+                .map(SplitTunnel::rules)
+                .transpose()?;
+        }
+        if let Some(host) = static_hosts::lookup(&self.static_hosts(), &tunnel.remote.0) {
+            tunnel.remote.0 = host;
+        }
+        Ok(tunnel)
+    }
+
+    pub(crate) fn static_hosts(&self) -> Vec<(String, IpAddr)> {
+        self.static_hosts
+            .iter()
+            .map(|host| (host.name.clone(), host.ip))
+            .collect()
+    }
+
+    pub fn to_client(&self) -> anyhow::Result<Client> {
+        let (mut local_to_remote, mut remote_to_local) = (vec![], vec![]);
+        for tunnel in &self.tunnels {
+            // Disabled tunnels must stay valid, they can be enabled on the fly
+            if !tunnel.enabled {
+                tunnel.to_tunnel()?;
+                continue;
+            }
+            let parsed = self.tunnel(tunnel)?;
+            platform::check_tunnel(&parsed)
+                .with_context(|| format!("Unsupported tunnel {}", tunnel.spec))?;
+            let is_proxy = !tunnel.reverse && ProxyKind::of(&parsed.local_protocol).is_some();
+            if tunnel.set_system_proxy && !is_proxy {
+                return Err(anyhow!(
+                    "Tunnel {} cannot be the system proxy, only local socks5 and http proxy tunnels can",
+                    tunnel.spec
+                ));
+            }
+            if !tunnel.pac_domains.is_empty() && !is_proxy {
+                return Err(anyhow!(
+                    "Tunnel {} cannot route PAC domains, only local socks5 and http proxy tunnels can",
+                    tunnel.spec
+                ));
+            }
+            if tunnel.reverse {
+                remote_to_local.push(parsed);
+            } else {
+                local_to_remote.push(parsed);
+            }
+        }
+        if local_to_remote.iter().filter(|t| t.tun.is_some()).count() > 1 {
+            return Err(anyhow!(
+                "A profile has at most one tun tunnel, it already captures the traffic of the whole machine"
+            ));
+        }
+        if let Some(stub) = &self.dns_stub {
+            for tunnel in stub.tunnels()? {
+                if local_to_remote.iter().any(|t| t.id == tunnel.id) {
+                    return Err(anyhow!(
+                        "Tunnel id {} is reserved for the dns stub resolver",
+                        tunnel.id
+                    ));
+                }
+                local_to_remote.push(tunnel);
+            }
+        }
+
+        if self.socket_so_mark.is_some() {
+            Capability::SocketMark.require()?;
+        }
+        if self.isolated && self.socket_so_mark.is_some() {
+            return Err(anyhow!(
+                "A socket mark needs privileges an isolated profile runs without"
+            ));
+        }
+        if let Some(rules) = &self.tproxy_rules {
+            rules.validate()?;
+            if self.isolated {
+                return Err(anyhow!(
+                    "An isolated profile cannot install transparent proxy rules"
+                ));
+            }
+            // Otherwise the connection to the server is redirected into the tunnel itself
+            if rules.bypass_mark.is_some() && rules.bypass_mark != self.socket_so_mark {
+                return Err(anyhow!(
+                    "The mark of the app sockets of the transparent proxy rules must be the socket mark of the profile"
+                ));
+            }
+        }
+        if self.isolated
+            && self
+                .enabled_tunnels()
+                .any(|t| t.set_system_proxy || !t.pac_domains.is_empty())
+        {
+            return Err(anyhow!(
+                "An isolated profile cannot set the system proxy nor be part of a PAC file"
+            ));
+        }
+
+        let tls_sni_override = self
+            .tls_sni_override
+            .as_ref()
+            .map(|sni| {
+                DnsName::try_from(sni.clone()).with_context(|| format!("Invalid sni {}", sni))
+            })
+            .transpose()?;
+
+        if self.http_upgrade_credentials.is_some()
+            && self.http_upgrade_credentials_provider.is_some()
+        {
+            return Err(anyhow!(
+                "Http upgrade credentials and a credentials provider cannot be used together"
+            ));
+        }
+        let http_upgrade_credentials = self
+            .http_upgrade_credentials
+            .as_ref()
+            .map(|creds| {
+                let encoded = base64::engine::general_purpose::STANDARD.encode(creds);
+                HeaderValue::from_str(&format!("Basic {}", encoded))
+            })
+            .transpose()
+            .with_context(|| "Invalid http upgrade credentials")?;
+        if self.http_proxy_auth == HttpProxyAuth::Ntlm
+            && (self.http_proxy_login.is_none() || self.http_proxy_password.is_none())
+            && !self
+                .http_proxy
+                .as_deref()
+                .is_some_and(|proxy| proxy.contains('@'))
+        {
+            return Err(anyhow!(
+                "NTLM proxy authentication requires a login and a password"
+            ));
+        }
+        if self.http_proxy.is_some() && self.http_proxy_detection != ProxyDetection::Manual {
+            return Err(anyhow!("An http proxy cannot be set when it is detected"));
+        }
+        if let Some(proxy) = &self.socks5_proxy {
+            if !["socks5", "socks5h"].contains(&proxy.scheme()) || proxy.host().is_none() {
+                return Err(anyhow!("Invalid socks5 proxy {}", proxy));
+            }
+            if self.http_proxy.is_some() || self.http_proxy_detection != ProxyDetection::Manual {
+                return Err(anyhow!(
+                    "A socks5 proxy cannot be used together with an http proxy"
+                ));
+            }
+        }
+        if let Some(chain) = &self.chain {
+            if chain.profile_id == self.name {
+                return Err(anyhow!("A profile cannot go through itself"));
+            }
+            if self.http_proxy.is_some()
+                || self.socks5_proxy.is_some()
+                || self.http_proxy_detection != ProxyDetection::Manual
+            {
+                return Err(anyhow!(
+                    "A profile going through another one cannot use a proxy of its own"
+                ));
+            }
+        }
+        self.retry_policy.validate()?;
+        upgrade_failures::validate(
+            &self.upgrade_failure_rules,
+            self.http_upgrade_credentials_provider.is_some(),
+        )?;
+
+        let http_headers = self
+            .http_headers
+            .iter()
+            .map(|header| {
+                Ok((
+                    HeaderName::from_str(&header.name)
+                        .with_context(|| format!("Invalid http header name {}", header.name))?,
+                    HeaderValue::from_str(&header.value).with_context(|| {
+                        format!("Invalid value for http header {}", header.name)
+                    })?,
+                ))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let http_host_template =
+            match (&self.http_host_template, self.http_host_rotation.is_empty()) {
+                (None, true) => None,
+                (template, _) => {
+                    let template = template.clone().unwrap_or_else(|| "{host}".to_string());
+                    HostTemplate::validate(&template, &self.http_host_rotation)?;
+                    if http_headers.iter().any(|(name, _)| *name == HOST) {
+                        return Err(anyhow!(
+                            "A Host header cannot be set together with a host template"
+                        ));
+                    }
+                    Some(template)
+                }
+            };
+
+        if let Some(source) = &self.tls_private_key_source {
+            if self.tls_private_key.is_some() {
+                return Err(anyhow!(
+                    "A private key file and a private key source cannot be used together"
+                ));
+            }
+            source.validate()?;
+        }
+        if let Some(renewal) = &self.tls_certificate_renewal {
+            if self.tls_certificate.is_none() {
+                return Err(anyhow!(
+                    "A certificate renewal needs a client certificate to renew"
+                ));
+            }
+            renewal.validate()?;
+        }
+        if let (Some(min), Some(max)) = (self.tls_min_version, self.tls_max_version) {
+            if min > max {
+                return Err(anyhow!("Minimum TLS version is above the maximum one"));
+            }
+        }
+        let tls_customized = self.tls_min_version.is_some()
+            || self.tls_max_version.is_some()
+            || self.tls_alpn.is_some()
+            || self.tls_fingerprint != TlsFingerprint::Rustls;
+        if tls_customized && self.tls_certificate_renewal.is_some() {
+            return Err(anyhow!(
+                "A renewed certificate is reloaded by wstunnel without the TLS versions, ALPN and fingerprint of the profile"
+            ));
+        }
+        if let Some(protocol) = self
+            .tls_alpn
+            .iter()
+            .flatten()
+            .find(|p| p.is_empty() || p.len() > 255)
+        {
+            return Err(anyhow!("Invalid ALPN protocol {:?}", protocol));
+        }
+        if self
+            .budget_warning_percents
+            .iter()
+            .any(|percent| *percent == 0 || *percent > 100)
+        {
+            return Err(anyhow!(
+                "Budget warning thresholds must be between 1 and 100 percent"
+            ));
+        }
+        for host in &self.static_hosts {
+            host.validate()?;
+        }
+        if self
+            .tunnels
+            .iter()
+            .any(|tunnel| tunnel.group.as_deref().is_some_and(|g| g.trim().is_empty()))
+        {
+            return Err(anyhow!("Tunnel group names cannot be empty"));
+        }
+        for buffers in self
+            .buffers
+            .iter()
+            .chain(self.upstream_socket.iter())
+            .chain(self.tunnels.iter().filter_map(|t| t.buffers.as_ref()))
+        {
+            buffers.validate()?;
+        }
+        if let Some(multipath) = &self.multipath {
+            multipath.validate()?;
+            if self.http_proxy.is_some() || self.socks5_proxy.is_some() {
+                return Err(anyhow!(
+                    "Multipath reaches the server directly, it cannot be used with a proxy"
+                ));
+            }
+        }
+        if let Some(split) = &self.split_tunnel {
+            split.rules()?;
+        }
+        if let Some(routing) = &self.app_routing {
+            routing.validate()?;
+        }
+        for window in &self.schedule {
+            window.validate()?;
+        }
+        for hook in self.before_connect.iter().chain(&self.after_disconnect) {
+            hook.validate()?;
+        }
+        if self.idle_disconnect_after_sec == Some(0) {
+            return Err(anyhow!("Idle disconnect delay must be at least a second"));
+        }
+        if self.isolated && self.idle_reconnect_on_demand {
+            return Err(anyhow!(
+                "An isolated profile is disconnected once idle, it cannot reconnect on demand"
+            ));
+        }
+        if self.on_demand && self.isolated {
+            return Err(anyhow!(
+                "An isolated profile cannot connect on demand, its connections are held by its own process"
+            ));
+        }
+        if self.on_demand && self.enabled_tunnels().any(|tunnel| tunnel.reverse) {
+            return Err(anyhow!(
+                "A profile with reverse tunnels cannot connect on demand, they keep a connection to the server open"
+            ));
+        }
+        if self.connect_timeout_sec == 0 || self.upgrade_timeout_sec == Some(0) {
+            return Err(anyhow!(
+                "Connect and upgrade timeouts must be at least a second"
+            ));
+        }
+        if self.server_addr.host().is_none() {
+            return Err(anyhow!("Server address {} has no host", self.server_addr));
+        }
+        transport::for_url(&self.server_addr)?;
+        for server in &self.server_candidates {
+            if server.host().is_none() {
+                return Err(anyhow!("Server address {} has no host", server));
+            }
+            transport::for_url(server)?;
+        }
+        if self.kill_switch && !self.server_candidates.is_empty() {
+            return Err(anyhow!(
+                "The kill switch only lets the traffic to the server address through, it cannot be used with other servers"
+            ));
+        }
+
+        Ok(Client {
+            local_to_remote,
+            remote_to_local,
+            socket_so_mark: self.socket_so_mark,
+            connection_min_idle: self.connection_min_idle,
+            on_demand: self.on_demand,
+            connection_retry_max_backoff_sec: Duration::from_secs(
+                self.connection_retry_max_backoff_sec,
+            ),
+            retry_policy: self.retry_policy.clone(),
+            connect_timeout_sec: Duration::from_secs(self.connect_timeout_sec),
+            upgrade_timeout_sec: self.upgrade_timeout_sec.map(Duration::from_secs),
+            tls_sni_override,
+            tls_sni_disable: self.tls_sni_disable,
+            tls_verify_certificate: self.tls_verify_certificate,
+            tls_pinned_certificates: self.tls_pinned_certificates.clone(),
+            http_proxy: self.http_proxy.clone(),
+            http_proxy_login: self.http_proxy_login.clone(),
+            http_proxy_password: self.http_proxy_password.clone(),
+            http_proxy_auth: self.http_proxy_auth,
+            http_proxy_detection: self.http_proxy_detection.clone(),
+            socks5_proxy: self.socks5_proxy.clone(),
+            upstream_socket: self.upstream_socket,
+            multipath: self.multipath.clone(),
+            http_upgrade_path_prefix: self
+                .http_upgrade_path_prefix
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CLIENT_UPGRADE_PATH_PREFIX.to_string()),
+            http_upgrade_credentials,
+            http_upgrade_credentials_provider: self.http_upgrade_credentials_provider.clone(),
+            upgrade_failure_rules: self.upgrade_failure_rules.clone(),
+            websocket_ping_frequency_sec: self
+                .websocket_ping_frequency_sec
+                .map(Duration::from_secs),
+            websocket_mask_frame: self.websocket_mask_frame,
+            http_headers,
+            http_headers_file: self.http_headers_file.clone(),
+            http_host_template,
+            http_host_rotation: self.http_host_rotation.clone(),
+            remote_addr: self.server_addr.clone(),
+            transport_fallback: self.transport_fallback,
+            tls_certificate: self.tls_certificate.clone(),
+            tls_private_key: self.tls_private_key.clone(),
+            tls_private_key_source: self.tls_private_key_source.clone(),
+            tls_certificate_warning_days: self.tls_certificate_warning_days,
+            tls_certificate_renewal: self.tls_certificate_renewal.clone(),
+            tls_min_version: self.tls_min_version,
+            tls_max_version: self.tls_max_version,
+            tls_alpn: self.tls_alpn.clone(),
+            tls_fingerprint: self.tls_fingerprint,
+            dns_resolver: self.dns_resolver.clone(),
+            dns_resolver_prefer_ipv4: self.dns_resolver_prefer_ipv4,
+            dns_cache: self.persist_dns,
+            static_hosts: self.static_hosts(),
+            app_routing: self.app_routing.clone(),
+            server_candidates: self.server_candidates.clone(),
+        })
+    }
+
+    /// Copy of the profile with every secret (passwords, credentials, sensitive headers) replaced by a placeholder.
+    /// Whether a secret is set is kept, only its value is hidden.
+    pub fn redacted(&self) -> Profile {
+        let mut profile = self.clone();
+        let redact = |value: &mut Option<String>| {
+            if value.is_some() {
+                *value = Some(REDACTED.to_string());
+            }
+        };
+
+        redact(&mut profile.http_proxy_password);
+        redact(&mut profile.http_upgrade_credentials);
+        profile.http_proxy = profile.http_proxy.as_deref().map(redact_proxy_url);
+        if let Some(proxy) = profile.socks5_proxy.as_mut() {
+            if proxy.password().is_some() {
+                let _ = proxy.set_password(Some(REDACTED));
+            }
+        }
+        if profile.server_addr.password().is_some() {
+            let _ = profile.server_addr.set_password(Some(REDACTED));
+        }
+        for header in profile.http_headers.iter_mut() {
+            if SENSITIVE_HEADERS.contains(&header.name.to_ascii_lowercase().as_str()) {
+                header.value = REDACTED.to_string();
+            }
+        }
+        for tunnel in profile.tunnels.iter_mut() {
+            tunnel.spec = redact_tunnel_spec(&tunnel.spec);
+        }
+
+        profile
+    }
+
+    /// Stable fingerprint of the effective configuration, secrets excluded.
+    /// Two machines running the same profile get the same hash, without having to share the profile itself.
+    pub fn config_hash(&self) -> String {
+        let canonical = serde_json::to_vec(&self.redacted()).unwrap_or_default();
+        let digest = Sha256::digest(&canonical);
+        digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+pub fn redact_proxy_url(proxy: &str) -> String {
+    let (scheme, rest) = proxy.split_once("://").unwrap_or(("", proxy));
+    let Some((userinfo, host)) = rest.rsplit_once('@') else {
+        return proxy.to_string();
+    };
+    let login = userinfo.split(':').next().unwrap_or_default();
+    let prefix = if scheme.is_empty() {
+        String::new()
+    } else {
+        format!("{}://", scheme)
+    };
+    format!("{}{}:{}@{}", prefix, login, REDACTED, host)
+}
+
+fn redact_tunnel_spec(spec: &str) -> String {
+    let Some((base, query)) = spec.split_once('?') else {
+        return spec.to_string();
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some(("password", _)) => format!("password={}", REDACTED),
+            _ => param.to_string(),
+        })
+        .collect();
+    format!("{}?{}", base, query.join("&"))
+}
@@ -0,0 +1,376 @@
+use crate::client::bridge::{Bridge, Target};
+use crate::client::buffers::BufferTuning;
+use crate::client::stats::ProfileStats;
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use md4::{Digest, Md4};
+use md5::Md5;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::Url;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const NTLM_SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+const NTLM_NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NTLM_REQUEST_TARGET: u32 = 0x0000_0004;
+const NTLM_NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NTLM_NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NTLM_NEGOTIATE_EXTENDED_SESSION_SECURITY: u32 = 0x0008_0000;
+const NTLM_NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NTLM_NEGOTIATE_128: u32 = 0x2000_0000;
+const NTLM_NEGOTIATE_56: u32 = 0x8000_0000;
+const NTLM_AV_EOL: u16 = 0;
+const NTLM_AV_TIMESTAMP: u16 = 7;
+/// 100ns intervals between 1601-01-01, the epoch of windows timestamps, and the unix epoch
+const WINDOWS_EPOCH_OFFSET: u64 = 116_444_736_000_000_000;
+
+/// How to authenticate against the http proxy the server is reached through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpProxyAuth {
+    /// Login and password sent as is, handled by wstunnel itself
+    #[default]
+    Basic,
+    /// NTLMv2 challenge/response with the login (`DOMAIN\user` or `user@domain`) and password
+    Ntlm,
+    /// Kerberos ticket of the current user, the login and password being unused
+    Negotiate,
+}
+
+/// Start a loopback proxy performing the NTLM or Negotiate handshake with `proxy` on behalf of wstunnel,
+/// which only knows basic authentication. Returns the url wstunnel must use as its proxy.
+/// The bridge only connects to the targets and stops once the profile is disconnected.
+pub async fn spawn_bridge(
+    proxy: Url,
+    auth: HttpProxyAuth,
+    tuning: Option<BufferTuning>,
+    targets: Vec<Target>,
+    stats: &Arc<ProfileStats>,
+) -> anyhow::Result<Url> {
+    let bridge = Bridge::bind(targets).await?;
+    let upstream = Arc::new(Upstream {
+        tuning,
+        ..Upstream::new(&proxy, auth)?
+    });
+    info!(
+        "Authenticating against http proxy {}:{} with {:?}",
+        upstream.host, upstream.port, auth
+    );
+    let url = bridge.url()?;
+    bridge.serve(stats, move |stream, target| {
+        let upstream = upstream.clone();
+        async move { upstream.bridge(stream, &target.to_string()).await }
+    });
+    Ok(url)
+}
+
+struct Upstream {
+    host: String,
+    port: u16,
+    auth: HttpProxyAuth,
+    login: String,
+    password: String,
+    /// Socket options of the connection to the proxy, the first hop to the server
+    tuning: Option<BufferTuning>,
+}
+
+impl Upstream {
+    fn new(proxy: &Url, auth: HttpProxyAuth) -> anyhow::Result<Self> {
+        let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().to_string();
+        Ok(Self {
+            host: proxy
+                .host_str()
+                .ok_or_else(|| anyhow!("Http proxy without host"))?
+                .trim_matches(['[', ']'])
+                .to_string(),
+            port: proxy.port_or_known_default().unwrap_or(80),
+            auth,
+            login: decode(proxy.username()),
+            password: decode(proxy.password().unwrap_or_default()),
+            tuning: None,
+        })
+    }
+
+    /// Answer the CONNECT request of wstunnel once the upstream proxy accepted ours, then relay the tunnel
+    async fn bridge(&self, mut inbound: TcpStream, target: &str) -> anyhow::Result<()> {
+        let mut outbound = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Cannot reach http proxy {}:{}", self.host, self.port))?;
+        if let Some(tuning) = &self.tuning {
+            tuning.apply_to_stream(&outbound)?;
+        }
+        let status = match self.auth {
+            HttpProxyAuth::Ntlm => self.connect_ntlm(&mut outbound, target).await?,
+            HttpProxyAuth::Negotiate => self.connect_negotiate(&mut outbound, target).await?,
+            HttpProxyAuth::Basic => return Err(anyhow!("Basic auth does not need a bridge")),
+        };
+
+        if status != 200 {
+            inbound
+                .write_all(
+                    format!(
+                        "HTTP/1.1 {} Proxy Refused\r\nConnection: close\r\n\r\n",
+                        status
+                    )
+                    .as_bytes(),
+                )
+                .await?;
+            return Err(anyhow!(
+                "Http proxy refused CONNECT to {} with status {}",
+                target,
+                status
+            ));
+        }
+        inbound
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        debug!("Tunnel to {} established through http proxy", target);
+        tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await?;
+        Ok(())
+    }
+
+    /// NTLM authenticates the connection itself, so the whole exchange happens on the same connection
+    async fn connect_ntlm(&self, stream: &mut TcpStream, target: &str) -> anyhow::Result<u16> {
+        send_connect(stream, target, &format!("NTLM {}", b64(&ntlm_negotiate()))).await?;
+        let response = read_response(stream).await?;
+        if response.status != 407 {
+            return Ok(response.status);
+        }
+        let challenge = response
+            .challenge("NTLM")
+            .ok_or_else(|| anyhow!("Http proxy did not send an NTLM challenge"))?;
+        let challenge = base64::engine::general_purpose::STANDARD.decode(challenge)?;
+        let authenticate = ntlm_authenticate(&challenge, &self.login, &self.password)?;
+
+        send_connect(stream, target, &format!("NTLM {}", b64(&authenticate))).await?;
+        Ok(read_response(stream).await?.status)
+    }
+
+    async fn connect_negotiate(&self, stream: &mut TcpStream, target: &str) -> anyhow::Result<u16> {
+        let principal = format!("HTTP/{}", self.host);
+        // Getting a ticket may have to reach the KDC, which blocks
+        let token = tokio::task::spawn_blocking(move || {
+            let (_pending, token) = cross_krb5::ClientCtx::new(
+                cross_krb5::InitiateFlags::empty(),
+                None,
+                &principal,
+                None,
+            )?;
+            anyhow::Ok(token.to_vec())
+        })
+        .await??;
+
+        send_connect(stream, target, &format!("Negotiate {}", b64(&token))).await?;
+        Ok(read_response(stream).await?.status)
+    }
+}
+
+struct ProxyResponse {
+    status: u16,
+    head: String,
+}
+
+impl ProxyResponse {
+    /// Token of a `Proxy-Authenticate` header for the given scheme
+    fn challenge(&self, scheme: &str) -> Option<&str> {
+        self.head.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            if !name.trim().eq_ignore_ascii_case("proxy-authenticate") {
+                return None;
+            }
+            let (found, token) = value.trim().split_once(' ')?;
+            found.eq_ignore_ascii_case(scheme).then(|| token.trim())
+        })
+    }
+}
+
+async fn send_connect(
+    stream: &mut TcpStream,
+    target: &str,
+    authorization: &str,
+) -> anyhow::Result<()> {
+    let request = format!(
+        "CONNECT {} HTTP/1.1\r\nHost: {}\r\nProxy-Connection: keep-alive\r\nProxy-Authorization: {}\r\n\r\n",
+        target, target, authorization
+    );
+    stream.write_all(request.as_bytes()).await?;
+    Ok(())
+}
+
+/// Response head of the proxy, its body being skipped so the connection can be reused
+async fn read_response(stream: &mut TcpStream) -> anyhow::Result<ProxyResponse> {
+    let head = read_head(stream).await?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow!("Invalid http proxy response"))?;
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    if status != 200 && content_length > 0 {
+        let mut body = vec![0; content_length.min(MAX_HEAD_SIZE)];
+        stream.read_exact(&mut body).await?;
+    }
+    Ok(ProxyResponse { status, head })
+}
+
+/// Read up to the end of an http head, one byte at a time so nothing past it is consumed
+pub async fn read_head(stream: &mut TcpStream) -> anyhow::Result<String> {
+    let mut head = Vec::with_capacity(512);
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD_SIZE {
+            return Err(anyhow!("Http head too large"));
+        }
+        head.push(stream.read_u8().await?);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+fn b64(data: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+fn ntlm_flags() -> u32 {
+    NTLM_NEGOTIATE_UNICODE
+        | NTLM_REQUEST_TARGET
+        | NTLM_NEGOTIATE_NTLM
+        | NTLM_NEGOTIATE_ALWAYS_SIGN
+        | NTLM_NEGOTIATE_EXTENDED_SESSION_SECURITY
+        | NTLM_NEGOTIATE_TARGET_INFO
+        | NTLM_NEGOTIATE_128
+        | NTLM_NEGOTIATE_56
+}
+
+/// Type 1 message, without domain nor workstation
+fn ntlm_negotiate() -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(NTLM_SIGNATURE);
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&ntlm_flags().to_le_bytes());
+    // Empty domain and workstation fields
+    message.extend_from_slice(&[0; 16]);
+    message
+}
+
+/// Type 3 message answering the type 2 challenge of the proxy with a NTLMv2 response
+fn ntlm_authenticate(challenge: &[u8], login: &str, password: &str) -> anyhow::Result<Vec<u8>> {
+    if challenge.len() < 32
+        || &challenge[..8] != NTLM_SIGNATURE
+        || read_u32(challenge, 8) != Some(2)
+    {
+        return Err(anyhow!("Invalid NTLM challenge"));
+    }
+    let flags = read_u32(challenge, 20).unwrap_or_default() & ntlm_flags();
+    let server_challenge = &challenge[24..32];
+    let target_info = security_buffer(challenge, 40).unwrap_or_default();
+
+    let (domain, user) = match (login.split_once('\\'), login.split_once('@')) {
+        (Some((domain, user)), _) => (domain, user),
+        (None, Some((user, domain))) => (domain, user),
+        (None, None) => ("", login),
+    };
+
+    let nt_hash = Md4::digest(utf16le(password));
+    let ntv2_hash = hmac_md5(&nt_hash, &[&utf16le(&(user.to_uppercase() + domain))]);
+
+    let mut client_challenge = [0u8; 8];
+    getrandom::getrandom(&mut client_challenge)?;
+    // The timestamp of the server is used when it sent one, in which case the LM response must be empty
+    let server_timestamp = av_timestamp(target_info);
+    let timestamp = server_timestamp.unwrap_or_else(|| {
+        let since_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (since_unix.as_nanos() / 100) as u64 + WINDOWS_EPOCH_OFFSET
+    });
+
+    let mut blob = vec![0x01, 0x01, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&timestamp.to_le_bytes());
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let nt_proof = hmac_md5(&ntv2_hash, &[server_challenge, &blob]);
+    let nt_response = [nt_proof.as_slice(), &blob].concat();
+    let lm_response = if server_timestamp.is_some() {
+        vec![0; 24]
+    } else {
+        let lm_proof = hmac_md5(&ntv2_hash, &[server_challenge, &client_challenge]);
+        [lm_proof.as_slice(), &client_challenge].concat()
+    };
+
+    let fields = [
+        lm_response,
+        nt_response,
+        utf16le(domain),
+        utf16le(user),
+        // No workstation
+        vec![],
+        // No session key
+        vec![],
+    ];
+    let header_len = 64;
+    let mut header = Vec::with_capacity(header_len);
+    header.extend_from_slice(NTLM_SIGNATURE);
+    header.extend_from_slice(&3u32.to_le_bytes());
+    let mut payload = vec![];
+    for field in &fields {
+        let len = field.len() as u16;
+        header.extend_from_slice(&len.to_le_bytes());
+        header.extend_from_slice(&len.to_le_bytes());
+        header.extend_from_slice(&((header_len + payload.len()) as u32).to_le_bytes());
+        payload.extend_from_slice(field);
+    }
+    header.extend_from_slice(&flags.to_le_bytes());
+    Ok([header, payload].concat())
+}
+
+/// Server timestamp of the target info, as windows FILETIME
+fn av_timestamp(mut target_info: &[u8]) -> Option<u64> {
+    while target_info.len() >= 4 {
+        let id = u16::from_le_bytes([target_info[0], target_info[1]]);
+        let len = u16::from_le_bytes([target_info[2], target_info[3]]) as usize;
+        let value = target_info.get(4..4 + len)?;
+        match id {
+            NTLM_AV_EOL => return None,
+            NTLM_AV_TIMESTAMP => return Some(u64::from_le_bytes(value.try_into().ok()?)),
+            _ => target_info = &target_info[4 + len..],
+        }
+    }
+    None
+}
+
+fn security_buffer(message: &[u8], offset: usize) -> Option<&[u8]> {
+    let len = u16::from_le_bytes(message.get(offset..offset + 2)?.try_into().ok()?) as usize;
+    let start = read_u32(message, offset + 4)? as usize;
+    message.get(start..start + len)
+}
+
+fn read_u32(message: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        message.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = <Hmac<Md5> as Mac>::new_from_slice(key).expect("hmac accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn utf16le(value: &str) -> Vec<u8> {
+    value.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
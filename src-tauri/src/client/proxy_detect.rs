@@ -0,0 +1,246 @@
+use crate::client::listener_sockets::listener_sockets;
+use crate::system_proxy::{self, OsProxy};
+use anyhow::{anyhow, Context};
+use boa_engine::Source;
+use ipnet::IpNet;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use url::Url;
+
+const PAC_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time given to a PAC file to answer, the proxy being detected without it past that
+const PAC_EVAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// Iterations of any loop of a PAC file, so a script never answering stops on its own rather than keep a thread
+const PAC_LOOP_LIMIT: u64 = 1_000_000;
+const PAC_RECURSION_LIMIT: usize = 512;
+
+/// Helper functions PAC files expect, as browsers define them. The names a script may resolve are resolved beforehand:
+/// only the server is, so `dnsResolve` of any other name fails as if it was unknown. `dateRange` never matches.
+const PAC_PRELUDE: &str = r#"
+function dnsResolve(host) { return host === __host ? __resolved : null; }
+function myIpAddress() { return __myIp; }
+function isPlainHostName(host) { return host.indexOf('.') < 0; }
+function dnsDomainIs(host, domain) {
+  return host.length >= domain.length && host.substring(host.length - domain.length) === domain;
+}
+function localHostOrDomainIs(host, hostdom) { return host === hostdom || hostdom.lastIndexOf(host + '.', 0) === 0; }
+function isResolvable(host) { return dnsResolve(host) !== null; }
+function dnsDomainLevels(host) { return host.split('.').length - 1; }
+function __addr(ip) { var b = ip.split('.'); return ((b[0] << 24) | (b[1] << 16) | (b[2] << 8) | b[3]) >>> 0; }
+function isInNet(host, pattern, mask) {
+  var ip = /^\d+\.\d+\.\d+\.\d+$/.test(host) ? host : dnsResolve(host);
+  if (ip === null || ip.indexOf('.') < 0) return false;
+  return ((__addr(ip) & __addr(mask)) >>> 0) === ((__addr(pattern) & __addr(mask)) >>> 0);
+}
+function shExpMatch(str, shexp) {
+  var re = shexp.replace(/[.+^${}()|[\]\\]/g, '\\$&').replace(/\*/g, '.*').replace(/\?/g, '.');
+  return new RegExp('^' + re + '$').test(str);
+}
+var __days = ['SUN', 'MON', 'TUE', 'WED', 'THU', 'FRI', 'SAT'];
+function weekdayRange(wd1, wd2, gmt) {
+  if (wd2 === 'GMT') { gmt = wd2; wd2 = undefined; }
+  var now = new Date();
+  var day = gmt === 'GMT' ? now.getUTCDay() : now.getDay();
+  var start = __days.indexOf(wd1), end = wd2 === undefined ? start : __days.indexOf(wd2);
+  return start <= end ? day >= start && day <= end : day >= start || day <= end;
+}
+function timeRange() {
+  var args = Array.prototype.slice.call(arguments);
+  var gmt = args[args.length - 1] === 'GMT';
+  if (gmt) args.pop();
+  var now = new Date();
+  var hour = gmt ? now.getUTCHours() : now.getHours();
+  if (args.length === 1) return hour === args[0];
+  var minute = hour * 60 + (gmt ? now.getUTCMinutes() : now.getMinutes());
+  var start, end;
+  if (args.length === 2) { start = args[0] * 60; end = args[1] * 60; }
+  else if (args.length === 4) { start = args[0] * 60 + args[1]; end = args[2] * 60 + args[3]; }
+  else if (args.length === 6) { start = args[0] * 60 + args[1]; end = args[3] * 60 + args[4]; }
+  else return false;
+  return start <= end ? minute >= start && minute < end : minute >= start || minute < end;
+}
+function dateRange() { return false; }
+"#;
+
+/// Where to find the proxy to reach the server through, instead of typing it in `http_proxy`.
+/// It is looked up on every connection, as corporate networks often change proxies with the location.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ProxyDetection {
+    /// Only use the proxy set in the profile
+    #[default]
+    Manual,
+    /// Use the proxy of the OS settings, evaluating their PAC file if they have one
+    System,
+    /// Evaluate this PAC file for the server
+    Pac { url: Url },
+}
+
+/// Http proxy (`host:port`) to reach the server through, None to reach it directly
+pub async fn detect(detection: &ProxyDetection, server: &Url) -> anyhow::Result<Option<String>> {
+    let host = server
+        .host_str()
+        .ok_or_else(|| anyhow!("Server address {} has no host", server))?;
+    let proxy = match detection {
+        ProxyDetection::Manual => return Ok(None),
+        ProxyDetection::System => match system_proxy::outbound_proxy()? {
+            OsProxy::Direct => None,
+            OsProxy::Manual { proxy, bypass } => Some(proxy).filter(|_| !bypassed(host, &bypass)),
+            OsProxy::Auto(pac) => find_proxy(&pac, server).await?,
+        },
+        ProxyDetection::Pac { url } => find_proxy(url, server).await?,
+    };
+
+    // The OS settings point to a tunnel of the app when a profile set it as the system proxy
+    let proxy = proxy.filter(|proxy| {
+        let authority = proxy.rsplit("://").next().unwrap_or(proxy);
+        let own = authority
+            .trim_end_matches('/')
+            .rsplit_once(':')
+            .filter(|(host, _)| is_loopback(host))
+            .and_then(|(_, port)| port.parse().ok())
+            .is_some_and(|port| listener_sockets().is_bound(port));
+        if own {
+            warn!(
+                "Ignoring detected proxy {}, it is a tunnel of the app",
+                proxy
+            );
+        }
+        !own
+    });
+    match &proxy {
+        Some(proxy) => info!("Reaching {} through detected proxy {}", host, proxy),
+        None => info!("Reaching {} directly, no proxy detected", host),
+    }
+    Ok(proxy)
+}
+
+/// Evaluate the PAC file for the server, returning the first http proxy of its answer
+async fn find_proxy(pac: &Url, server: &Url) -> anyhow::Result<Option<String>> {
+    let script = fetch_pac(pac)
+        .await
+        .with_context(|| format!("Cannot fetch PAC file {}", pac))?;
+    let host = server.host_str().unwrap_or_default().to_string();
+    let resolved = tokio::net::lookup_host((host.as_str(), 0))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.find(|addr| addr.is_ipv4()))
+        .map(|addr| addr.ip().to_string());
+    let my_ip = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|iface| !iface.is_loopback() && iface.ip().is_ipv4())
+        .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |iface| iface.ip());
+
+    let call = format!(
+        "{}\nvar __host = {};\nvar __resolved = {};\nvar __myIp = {};\n{}\nFindProxyForURL({}, {});",
+        PAC_PRELUDE,
+        serde_json::to_string(&host)?,
+        serde_json::to_string(&resolved)?,
+        serde_json::to_string(&my_ip.to_string())?,
+        script,
+        serde_json::to_string(server.as_str())?,
+        serde_json::to_string(&host)?,
+    );
+    // The engine is not Send, and a script may take a while
+    let evaluation = tokio::task::spawn_blocking(move || -> anyhow::Result<String> {
+        let mut context = boa_engine::Context::default();
+        let limits = context.runtime_limits_mut();
+        limits.set_loop_iteration_limit(PAC_LOOP_LIMIT);
+        limits.set_recursion_limit(PAC_RECURSION_LIMIT);
+        let value = context
+            .eval(Source::from_bytes(call.as_bytes()))
+            .map_err(|err| anyhow!("PAC file failed: {}", err))?;
+        let answer = value
+            .to_string(&mut context)
+            .map_err(|err| anyhow!("Invalid PAC answer: {}", err))?;
+        Ok(answer.to_std_string_escaped())
+    });
+    let answer = tokio::time::timeout(PAC_EVAL_TIMEOUT, evaluation)
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "PAC file {} did not answer within {:?}",
+                pac,
+                PAC_EVAL_TIMEOUT
+            )
+        })???;
+    debug!("PAC file {} answered {:?} for {}", pac, answer, host);
+    Ok(first_http_proxy(&answer))
+}
+
+async fn fetch_pac(pac: &Url) -> anyhow::Result<String> {
+    if pac.scheme() == "file" {
+        let path = pac
+            .to_file_path()
+            .map_err(|_| anyhow!("Invalid PAC file path {}", pac))?;
+        return Ok(tokio::fs::read_to_string(path).await?);
+    }
+    // The PAC file is always fetched directly, the proxy is what it tells
+    let client = reqwest::Client::builder()
+        .no_proxy()
+        .timeout(PAC_FETCH_TIMEOUT)
+        .build()?;
+    Ok(client
+        .get(pac.clone())
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?)
+}
+
+/// First usable entry of a PAC answer, i.e: `PROXY proxy.corp:8080; SOCKS socks.corp:1080; DIRECT`.
+/// wstunnel only goes through http proxies, so other kinds are skipped.
+fn first_http_proxy(answer: &str) -> Option<String> {
+    for entry in answer.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+        let mut parts = entry.split_whitespace();
+        match (
+            parts.next().map(|kind| kind.to_ascii_uppercase()),
+            parts.next(),
+        ) {
+            (Some(kind), _) if kind == "DIRECT" => return None,
+            (Some(kind), Some(proxy)) if kind == "PROXY" || kind == "HTTP" => {
+                return Some(proxy.to_string())
+            }
+            _ => debug!(
+                "Skipping PAC entry {}, only http proxies are supported",
+                entry
+            ),
+        }
+    }
+    None
+}
+
+/// Whether the OS settings ask to reach the host directly, with patterns as `*.corp`, `.corp`, `10.0.0.0/8` or `<local>`
+fn bypassed(host: &str, bypass: &[String]) -> bool {
+    let host = host.to_ascii_lowercase();
+    bypass.iter().any(|pattern| {
+        let pattern = pattern.trim().to_ascii_lowercase();
+        if pattern == "<local>" {
+            return !host.contains('.');
+        }
+        if let Ok(net) = pattern.parse::<IpNet>() {
+            return host.parse::<IpAddr>().is_ok_and(|ip| net.contains(&ip));
+        }
+        match pattern.strip_prefix('*') {
+            Some(suffix) => host.ends_with(suffix) || suffix.strip_prefix('.') == Some(&host),
+            None => {
+                host == pattern
+                    || pattern
+                        .strip_prefix('.')
+                        .is_some_and(|domain| host == domain || host.ends_with(&pattern))
+            }
+        }
+    })
+}
+
+fn is_loopback(host: &str) -> bool {
+    host == "localhost"
+        || host
+            .trim_matches(|c| c == '[' || c == ']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
@@ -0,0 +1,553 @@
+use anyhow::anyhow;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tauri::Url;
+use tokio::io::AsyncWrite;
+use url::Host;
+use wstunnel::tunnel::connectors::TunnelConnector;
+use wstunnel::tunnel::RemoteAddr;
+
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header, crlf included
+const V1_MAX_LEN: usize = 107;
+/// Headers with more TLVs than that are not worth waiting for, the connection is passed through as is
+const MAX_HEADER_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolVersion {
+    /// Text header, for services only understanding this one
+    V1,
+    #[default]
+    V2,
+}
+
+/// Source address written in the header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ProxySource {
+    /// Address of the client that actually made the connection
+    #[default]
+    Preserve,
+    /// Fixed address, i.e: for a service allowing a single client address
+    Spoof { addr: SocketAddr },
+}
+
+/// Proxy protocol header written ahead of each connection of a tunnel, by the app rather than by the server.
+/// For a local tcp tunnel, the header carries the client connected to the local listener and reaches the remote
+/// service through the tunnel. For a reverse tcp tunnel, the tunnel does not carry the client the server accepted:
+/// the header is an unknown one unless the source is spoofed, or taken from the load balancer when trusted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ProxyHeader {
+    pub version: ProxyProtocolVersion,
+    pub source: ProxySource,
+    /// Reverse tcp tunnels only, the remote port is only reachable through a load balancer writing a proxy
+    /// protocol header itself, which is parsed and written again. Otherwise anyone reaching the remote port
+    /// could send a header of their own and pick the address the local service sees
+    pub trusted_load_balancer: bool,
+}
+
+impl ProxyHeader {
+    /// Header of a connection from `source` accepted on `destination`
+    pub fn encode(&self, source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+        let source = match self.source {
+            ProxySource::Preserve => source,
+            ProxySource::Spoof { addr } => addr,
+        };
+        encode(self.version, Some((source, destination)))
+    }
+}
+
+/// Header for the given addresses, or for an unknown connection
+fn encode(version: ProxyProtocolVersion, addrs: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let addrs = addrs.map(|(source, destination)| same_family(source, destination));
+    match version {
+        ProxyProtocolVersion::V1 => match addrs {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            match addrs {
+                Some((source, destination)) => {
+                    header.push(0x21);
+                    let mut addresses = vec![];
+                    match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                            header.push(0x11);
+                            addresses.extend_from_slice(&src.octets());
+                            addresses.extend_from_slice(&dst.octets());
+                        }
+                        (src, dst) => {
+                            header.push(0x21);
+                            addresses.extend_from_slice(&to_v6(src).octets());
+                            addresses.extend_from_slice(&to_v6(dst).octets());
+                        }
+                    }
+                    addresses.extend_from_slice(&source.port().to_be_bytes());
+                    addresses.extend_from_slice(&destination.port().to_be_bytes());
+                    header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+                    header.extend_from_slice(&addresses);
+                }
+                None => {
+                    header.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+                }
+            }
+            header
+        }
+    }
+}
+
+fn to_v6(ip: IpAddr) -> std::net::Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+/// Both addresses in ipv6 when their families differ, a header holding a single family
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    let v6 = |addr: SocketAddr| SocketAddr::new(IpAddr::V6(to_v6(addr.ip())), addr.port());
+    (v6(source), v6(destination))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Parsed {
+    /// More bytes are needed to tell
+    Incomplete,
+    /// The connection does not start with a proxy protocol header
+    NotProxy,
+    /// Header of the given length, with the source and destination unless the connection is a local or unknown one
+    Header {
+        addrs: Option<(SocketAddr, SocketAddr)>,
+        len: usize,
+    },
+}
+
+/// Proxy protocol header, v1 or v2, at the start of `buf`
+fn parse(buf: &[u8]) -> anyhow::Result<Parsed> {
+    let prefix = buf.len().min(V2_SIGNATURE.len());
+    if buf[..prefix] == V2_SIGNATURE[..prefix] {
+        return parse_v2(buf);
+    }
+    let prefix = buf.len().min(6);
+    if buf[..prefix] == b"PROXY "[..prefix] {
+        return parse_v1(buf);
+    }
+    Ok(Parsed::NotProxy)
+}
+
+fn parse_v1(buf: &[u8]) -> anyhow::Result<Parsed> {
+    let Some(end) = buf.windows(2).position(|w| w == b"\r\n") else {
+        if buf.len() >= V1_MAX_LEN {
+            return Err(anyhow!("Proxy protocol v1 header too long"));
+        }
+        return Ok(Parsed::Incomplete);
+    };
+    let line = std::str::from_utf8(&buf[..end])?;
+    let parts: Vec<&str> = line.split(' ').collect();
+    let addrs = match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", "TCP4" | "TCP6", src, dst, sport, dport] => Some((
+            SocketAddr::new(src.parse()?, sport.parse()?),
+            SocketAddr::new(dst.parse()?, dport.parse()?),
+        )),
+        _ => return Err(anyhow!("Invalid proxy protocol v1 header {}", line)),
+    };
+    Ok(Parsed::Header {
+        addrs,
+        len: end + 2,
+    })
+}
+
+fn parse_v2(buf: &[u8]) -> anyhow::Result<Parsed> {
+    if buf.len() < 16 {
+        return Ok(Parsed::Incomplete);
+    }
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+    if buf[12] >> 4 != 2 {
+        return Err(anyhow!(
+            "Unsupported proxy protocol version {}",
+            buf[12] >> 4
+        ));
+    }
+    let body = &buf[16..len];
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    let addrs = match (buf[12] & 0x0f, buf[13]) {
+        (0x01, 0x11) if body.len() >= 12 => {
+            let src: [u8; 4] = body[0..4].try_into()?;
+            let dst: [u8; 4] = body[4..8].try_into()?;
+            Some((
+                SocketAddr::new(IpAddr::from(src), port(8)),
+                SocketAddr::new(IpAddr::from(dst), port(10)),
+            ))
+        }
+        (0x01, 0x21) if body.len() >= 36 => {
+            let src: [u8; 16] = body[0..16].try_into()?;
+            let dst: [u8; 16] = body[16..32].try_into()?;
+            Some((
+                SocketAddr::new(IpAddr::from(src), port(32)),
+                SocketAddr::new(IpAddr::from(dst), port(34)),
+            ))
+        }
+        // Local connections, unix sockets and udp carry no tcp client
+        _ => None,
+    };
+    Ok(Parsed::Header { addrs, len })
+}
+
+enum WriteState {
+    /// Collecting the first bytes until the header, if any, is complete
+    Detecting(Vec<u8>),
+    /// Writing the header written again and what followed it
+    Flushing(Vec<u8>, usize),
+    Passthrough,
+}
+
+/// Writer toward the local service of a reverse tunnel, always starting with a proxy protocol header of the app.
+/// The header the server side sent is only parsed, and written again in the configured version and with the
+/// configured source, behind a trusted load balancer. Otherwise what the connection starts with is plain data.
+pub struct RewriteHeader<W> {
+    inner: W,
+    header: ProxyHeader,
+    /// Local service the connection goes to
+    destination: Option<SocketAddr>,
+    state: WriteState,
+}
+
+impl<W> RewriteHeader<W> {
+    pub fn new(inner: W, header: ProxyHeader, destination: Option<SocketAddr>) -> Self {
+        let state = if header.trusted_load_balancer {
+            WriteState::Detecting(vec![])
+        } else {
+            WriteState::Flushing(own_header(header, destination), 0)
+        };
+        Self {
+            inner,
+            header,
+            destination,
+            state,
+        }
+    }
+
+    /// What to write in place of the bytes collected so far, None when more are needed
+    fn rewrite(&self, collected: &[u8]) -> Option<Vec<u8>> {
+        let (mut rewritten, len) = match parse(collected) {
+            Ok(Parsed::Incomplete) if collected.len() < MAX_HEADER_LEN => return None,
+            Ok(Parsed::Header { addrs, len }) => {
+                let addrs = addrs.map(|(source, destination)| match self.header.source {
+                    ProxySource::Preserve => (source, destination),
+                    ProxySource::Spoof { addr } => (addr, destination),
+                });
+                if let Some((source, _)) = addrs {
+                    debug!("Reverse tunnel connection from {}", source);
+                }
+                (encode(self.header.version, addrs), len)
+            }
+            Ok(_) => {
+                debug!("Reverse tunnel connection without proxy protocol header");
+                (own_header(self.header, self.destination), 0)
+            }
+            Err(err) => {
+                debug!("Invalid proxy protocol header: {:?}", err);
+                (own_header(self.header, self.destination), 0)
+            }
+        };
+        rewritten.extend_from_slice(&collected[len..]);
+        Some(rewritten)
+    }
+}
+
+/// Header of the app for a reverse tunnel connection, whose client is unknown unless spoofed
+fn own_header(header: ProxyHeader, destination: Option<SocketAddr>) -> Vec<u8> {
+    match (header.source, destination) {
+        (ProxySource::Spoof { addr }, Some(destination)) => header.encode(addr, destination),
+        _ => encode(header.version, None),
+    }
+}
+
+impl<W: AsyncWrite + Unpin> RewriteHeader<W> {
+    /// Write what is pending ahead of the connection data
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let WriteState::Flushing(pending, written) = &mut self.state {
+            if *written >= pending.len() {
+                self.state = WriteState::Passthrough;
+                break;
+            }
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &pending[*written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            *written += n;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for RewriteHeader<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if let WriteState::Detecting(collected) = &mut this.state {
+            collected.extend_from_slice(buf);
+            let collected = std::mem::take(collected);
+            this.state = match this.rewrite(&collected) {
+                Some(rewritten) => WriteState::Flushing(rewritten, 0),
+                None => WriteState::Detecting(collected),
+            };
+            // Accepted, it is written along with the header
+            return Poll::Ready(Ok(buf.len()));
+        }
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let WriteState::Detecting(collected) = &mut this.state {
+            let mut pending = own_header(this.header, this.destination);
+            pending.append(collected);
+            this.state = WriteState::Flushing(pending, 0);
+        }
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Connector of a reverse tunnel writing a proxy protocol header ahead of each connection to the local service
+pub struct RewriteHeaderConnector<C> {
+    inner: C,
+    header: ProxyHeader,
+}
+
+impl<C> RewriteHeaderConnector<C> {
+    pub fn new(inner: C, header: ProxyHeader) -> Self {
+        Self { inner, header }
+    }
+}
+
+impl<C: TunnelConnector> TunnelConnector for RewriteHeaderConnector<C>
+where
+    C::Writer: Unpin,
+{
+    type Reader = C::Reader;
+    type Writer = RewriteHeader<C::Writer>;
+
+    async fn connect(
+        &self,
+        remote: &Option<RemoteAddr>,
+    ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        let (reader, writer) = self.inner.connect(remote).await?;
+        Ok((
+            reader,
+            RewriteHeader::new(writer, self.header, destination(remote)),
+        ))
+    }
+
+    async fn connect_with_http_proxy(
+        &self,
+        proxy: &Url,
+        remote: &Option<RemoteAddr>,
+    ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        let (reader, writer) = self.inner.connect_with_http_proxy(proxy, remote).await?;
+        Ok((
+            reader,
+            RewriteHeader::new(writer, self.header, destination(remote)),
+        ))
+    }
+}
+
+/// Address of the local service, when given as an ip
+fn destination(remote: &Option<RemoteAddr>) -> Option<SocketAddr> {
+    let remote = remote.as_ref()?;
+    let ip = match &remote.host {
+        Host::Ipv4(ip) => IpAddr::V4(*ip),
+        Host::Ipv6(ip) => IpAddr::V6(*ip),
+        Host::Domain(_) => return None,
+    };
+    Some(SocketAddr::new(ip, remote.port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[test]
+    fn parses_the_headers_it_encodes() {
+        let v4 = (
+            "192.0.2.1:4000".parse().unwrap(),
+            "198.51.100.2:80".parse().unwrap(),
+        );
+        let v6 = (
+            "[2001:db8::1]:4000".parse().unwrap(),
+            "[2001:db8::2]:443".parse().unwrap(),
+        );
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for addrs in [Some(v4), Some(v6), None] {
+                let mut header = encode(version, addrs);
+                let len = header.len();
+                header.extend_from_slice(b"GET / HTTP/1.1\r\n");
+                assert_eq!(
+                    parse(&header).unwrap(),
+                    Parsed::Header { addrs, len },
+                    "{:?} {:?}",
+                    version,
+                    addrs
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn waits_for_truncated_headers() {
+        let v1 = encode(ProxyProtocolVersion::V1, None);
+        let v2 = encode(
+            ProxyProtocolVersion::V2,
+            Some((
+                "192.0.2.1:4000".parse().unwrap(),
+                "198.51.100.2:80".parse().unwrap(),
+            )),
+        );
+        for header in [&v1, &v2] {
+            for end in 0..header.len() {
+                assert_eq!(
+                    parse(&header[..end]).unwrap(),
+                    Parsed::Incomplete,
+                    "{:?}",
+                    &header[..end]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn passes_through_without_signature() {
+        for data in [
+            &b"GET / HTTP/1.1\r\n"[..],
+            b"PROXZ TCP4 1.2.3.4 5.6.7.8 1 2\r\n",
+            b"\r\n\r\n\0\r\nQUIZ\n\x21\x11\x00\x0c",
+            b"SSH-2.0-OpenSSH\r\n",
+        ] {
+            assert_eq!(parse(data).unwrap(), Parsed::NotProxy, "{:?}", data);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_v1_headers() {
+        for header in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.2 4000\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 not-an-ip 4000 80\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 4000 65536\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 4000 80\r\n",
+            b"PROXY \xff\r\n",
+        ] {
+            assert!(parse(header).is_err(), "{:?}", header);
+        }
+
+        let mut too_long = b"PROXY TCP6 ".to_vec();
+        too_long.resize(V1_MAX_LEN, b'1');
+        assert!(parse(&too_long).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_v2_versions() {
+        let mut header = v2(0x21, 0x11, &[0; 12]);
+        header[12] = 0x11;
+        assert!(parse(&header).is_err());
+    }
+
+    #[test]
+    fn waits_for_oversized_v2_lengths_up_to_the_limit() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[0x21, 0x11, 0xff, 0xff]);
+        header.resize(MAX_HEADER_LEN, 0);
+        assert_eq!(parse(&header).unwrap(), Parsed::Incomplete);
+
+        // The rewriter gives up waiting and writes the header of the app ahead of the data
+        let proxy = ProxyHeader {
+            trusted_load_balancer: true,
+            ..ProxyHeader::default()
+        };
+        let rewriter = RewriteHeader::new(tokio::io::sink(), proxy, None);
+        let rewritten = rewriter.rewrite(&header).unwrap();
+        let own = encode(ProxyProtocolVersion::V2, None);
+        assert_eq!(&rewritten[..own.len()], own.as_slice());
+        assert_eq!(&rewritten[own.len()..], header.as_slice());
+    }
+
+    #[test]
+    fn v2_without_tcp_client_has_no_addresses() {
+        for (name, header) in [
+            ("local", v2(0x20, 0x11, &[0; 12])),
+            ("local without addresses", v2(0x20, 0x00, &[])),
+            ("udp4", v2(0x21, 0x12, &[0; 12])),
+            ("unix", v2(0x21, 0x31, &[0; 216])),
+            ("unspecified family", v2(0x21, 0x00, &[])),
+            ("tcp4 too short", v2(0x21, 0x11, &[0; 8])),
+            ("tcp6 too short", v2(0x21, 0x21, &[0; 12])),
+        ] {
+            let len = header.len();
+            assert_eq!(
+                parse(&header).unwrap(),
+                Parsed::Header { addrs: None, len },
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn v2_skips_tlvs() {
+        let mut body = vec![192, 0, 2, 1, 198, 51, 100, 2, 0x0f, 0xa0, 0, 80];
+        // PP2_TYPE_AUTHORITY
+        body.extend_from_slice(&[0x02, 0x00, 0x03]);
+        body.extend_from_slice(b"app");
+        let header = v2(0x21, 0x11, &body);
+        assert_eq!(
+            parse(&header).unwrap(),
+            Parsed::Header {
+                addrs: Some((
+                    "192.0.2.1:4000".parse().unwrap(),
+                    "198.51.100.2:80".parse().unwrap()
+                )),
+                len: header.len(),
+            }
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use crate::client::stats::{self, ProfileStats, RttWindow};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+const HIGH_RTT_MS: f64 = 150.0;
+const HIGH_JITTER_MS: f64 = 30.0;
+const HIGH_LOSS: f64 = 0.05;
+const SLOW_DNS: Duration = Duration::from_millis(300);
+/// Latest probes the link is judged on, so a degradation is noticed within a few of them
+const RECENT_PROBES: usize = 6;
+/// Share of the latest probes lost past which the link is degraded, a single one being lost is not enough
+const DEGRADED_LOSS: f64 = 0.3;
+
+/// Actionable change the user can make to the profile to improve the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Recommendation {
+    /// Http2 is very sensitive to buffering middleboxes, websocket is more forgiving
+    SwitchToWebsocket,
+    /// Resolving the server name is slow, another dns resolver should be configured
+    ChangeDnsResolver,
+    /// Masking frames is pure overhead when using TLS
+    DisableFrameMasking,
+    /// Keep some connections opened in advance to avoid paying the handshake on each new tunnel
+    IncreaseConnectionPool,
+    /// The link to the server itself is bad, a closer server or another network would help
+    UseCloserServer,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QualityReport {
+    /// From 0 (unusable) to 100 (perfect)
+    pub score: u8,
+    pub rtt_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    pub loss: f64,
+    pub tunnel_errors: u64,
+    pub throughput_bytes_per_sec: u64,
+    pub recommendations: Vec<Recommendation>,
+}
+
+pub fn evaluate(stats: &ProfileStats) -> QualityReport {
+    let (rtt_ms, jitter_ms, loss, dns_lookup) = {
+        let rtt = stats.rtt.lock();
+        (rtt.mean_ms(), rtt.jitter_ms(), rtt.loss(), rtt.dns_lookup)
+    };
+    let traffic = stats.traffic_snapshot();
+    let tunnel_errors = stats.tunnel_errors.load(Ordering::Relaxed);
+    let throughput_bytes_per_sec =
+        (traffic.bytes_up + traffic.bytes_down) / traffic.uptime_sec.max(1);
+
+    let mut score = 100.0;
+    if let Some(rtt) = rtt_ms {
+        score -= (rtt / 10.0).min(30.0);
+    }
+    if let Some(jitter) = jitter_ms {
+        score -= (jitter / 2.0).min(20.0);
+    }
+    score -= (loss * 200.0).min(30.0);
+    let errors_per_hour = tunnel_errors as f64 * 3600.0 / traffic.uptime_sec.max(60) as f64;
+    score -= (errors_per_hour * 2.0).min(20.0);
+
+    let link = &stats.link;
+    let mut recommendations = vec![];
+    if link.remote_addr.scheme() == "https"
+        && (jitter_ms.unwrap_or(0.0) > HIGH_JITTER_MS || loss > HIGH_LOSS || tunnel_errors > 0)
+    {
+        recommendations.push(Recommendation::SwitchToWebsocket);
+    }
+    if dns_lookup.is_some_and(|d| d > SLOW_DNS) {
+        recommendations.push(Recommendation::ChangeDnsResolver);
+    }
+    if link.websocket_mask_frame && matches!(link.remote_addr.scheme(), "wss" | "https") {
+        recommendations.push(Recommendation::DisableFrameMasking);
+    }
+    if link.connection_min_idle == 0 && traffic.connections > 10 {
+        recommendations.push(Recommendation::IncreaseConnectionPool);
+    }
+    if rtt_ms.unwrap_or(0.0) > HIGH_RTT_MS || loss > HIGH_LOSS {
+        recommendations.push(Recommendation::UseCloserServer);
+    }
+
+    QualityReport {
+        score: score.clamp(0.0, 100.0).round() as u8,
+        rtt_ms,
+        jitter_ms,
+        loss,
+        tunnel_errors,
+        throughput_bytes_per_sec,
+        recommendations,
+    }
+}
+
+/// Why the latest probes of the link look bad
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Degradation {
+    PacketLoss,
+    HighLatency,
+    HighJitter,
+}
+
+/// Measurements of the link to the server, for the UI to draw them
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkQuality {
+    /// Round trip times in milliseconds from the oldest probe to the newest, none for a lost one
+    pub samples_ms: Vec<Option<f64>>,
+    pub probe_interval_sec: u64,
+    pub rtt_ms: Option<f64>,
+    pub jitter_ms: Option<f64>,
+    pub loss: f64,
+    /// Set while the latest probes show a degraded link
+    pub degradation: Option<Degradation>,
+}
+
+pub fn link_quality(stats: &ProfileStats) -> LinkQuality {
+    let rtt = stats.rtt.lock();
+    LinkQuality {
+        samples_ms: rtt.samples_ms(),
+        probe_interval_sec: stats::probe_interval(&stats.link).as_secs(),
+        rtt_ms: rtt.mean_ms(),
+        jitter_ms: rtt.jitter_ms(),
+        loss: rtt.loss(),
+        degradation: degradation(&rtt),
+    }
+}
+
+/// State of the link according to the latest probes, none until there are enough of them
+pub fn degradation(rtt: &RttWindow) -> Option<Degradation> {
+    if rtt.probes() < RECENT_PROBES {
+        return None;
+    }
+    let recent = rtt.recent(RECENT_PROBES);
+    if recent.loss() > DEGRADED_LOSS {
+        Some(Degradation::PacketLoss)
+    } else if recent.mean_ms().is_some_and(|rtt| rtt > HIGH_RTT_MS) {
+        Some(Degradation::HighLatency)
+    } else if recent
+        .jitter_ms()
+        .is_some_and(|jitter| jitter > HIGH_JITTER_MS)
+    {
+        Some(Degradation::HighJitter)
+    } else {
+        None
+    }
+}
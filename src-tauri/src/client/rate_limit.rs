@@ -0,0 +1,275 @@
+use futures_util::{Stream, StreamExt};
+use parking_lot::Mutex;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+use wstunnel::tunnel::RemoteAddr;
+
+/// Classic token bucket, refilled continuously at `rate` bytes per second.
+/// The bucket can hold up to one second worth of tokens, which allows short bursts
+/// while keeping the average throughput at the configured rate.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    /// Fractional, so the time between two refills is never lost at low rates
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Largest amount waited for before letting a transfer through, bigger ones go as the tokens come
+const MAX_CHUNK: u64 = 16 * 1024;
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+        self.last_refill = now;
+    }
+
+    /// Returns the number of bytes that can be transferred right now (at most `wanted`),
+    /// or the time to wait before some tokens are available again.
+    fn available(&mut self, wanted: usize) -> Result<usize, Duration> {
+        self.available_at(Instant::now(), wanted)
+    }
+
+    /// Waits for a chunk worth transferring, 10ms of the rate up to `MAX_CHUNK`, instead of waking up for every
+    /// byte and splitting the transfer into tiny reads and writes
+    fn available_at(&mut self, now: Instant, wanted: usize) -> Result<usize, Duration> {
+        self.refill(now);
+        let chunk = (self.rate / 100).clamp(1, MAX_CHUNK).min(wanted as u64) as f64;
+        if self.tokens < chunk {
+            let wait = Duration::from_secs_f64((chunk - self.tokens) / self.rate as f64);
+            return Err(wait.max(Duration::from_millis(1)));
+        }
+        Ok((self.tokens as usize).min(wanted))
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.tokens = (self.tokens - amount as f64).max(0.0);
+    }
+}
+
+/// Bucket shared by every connection of the same tunnel, so the limit applies to the tunnel as a whole
+pub type SharedBucket = Arc<Mutex<TokenBucket>>;
+
+pub fn shared_bucket(rate: Option<u64>) -> Option<SharedBucket> {
+    rate.map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate))))
+}
+
+/// Wrap a reader or a writer of a tunnel, throttling it with the given bucket.
+/// Without bucket, the stream is passed through untouched.
+pub struct RateLimited<S> {
+    inner: S,
+    bucket: Option<SharedBucket>,
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> RateLimited<S> {
+    pub fn new(inner: S, bucket: Option<SharedBucket>) -> Self {
+        Self {
+            inner,
+            bucket,
+            sleep: None,
+        }
+    }
+
+    fn poll_budget(&mut self, cx: &mut Context<'_>, wanted: usize) -> Poll<usize> {
+        let Some(bucket) = &self.bucket else {
+            return Poll::Ready(wanted);
+        };
+
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+
+            match bucket.lock().available(wanted) {
+                Ok(budget) => return Poll::Ready(budget),
+                Err(wait) => self.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+    }
+
+    fn consume(&self, amount: usize) {
+        if let Some(bucket) = &self.bucket {
+            bucket.lock().consume(amount);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RateLimited<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() == 0 {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+
+        let budget = ready!(this.poll_budget(cx, buf.remaining()));
+        let mut limited = buf.take(budget);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+
+        // SAFETY: the bytes have been initialized by the inner reader through `limited`
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+        this.consume(read);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RateLimited<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        let budget = ready!(this.poll_budget(cx, buf.len()));
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..budget]))?;
+        this.consume(written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Throttle every connection accepted by a tunnel listener.
+/// `up` limits the traffic read from the local side (sent to the server), `down` the traffic written back to it.
+pub fn rate_limit_listener<L, R, W>(
+    listener: L,
+    up: Option<u64>,
+    down: Option<u64>,
+) -> impl Stream<Item = anyhow::Result<((RateLimited<R>, RateLimited<W>), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let up = shared_bucket(up);
+    let down = shared_bucket(down);
+    listener.map(move |item| {
+        item.map(|((reader, writer), remote)| {
+            (
+                (
+                    RateLimited::new(reader, up.clone()),
+                    RateLimited::new(writer, down.clone()),
+                ),
+                remote,
+            )
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty(rate: u64) -> (TokenBucket, Instant) {
+        let mut bucket = TokenBucket::new(rate);
+        bucket.consume(rate as usize);
+        let start = bucket.last_refill;
+        (bucket, start)
+    }
+
+    fn waited_ms(available: Result<usize, Duration>) -> f64 {
+        let wait = available.expect_err("should wait");
+        (wait.as_secs_f64() * 1000.0).round()
+    }
+
+    #[test]
+    fn bursts_up_to_one_second_of_rate() {
+        let mut bucket = TokenBucket::new(1000);
+        let start = bucket.last_refill;
+        assert_eq!(bucket.available_at(start, 5000), Ok(1000));
+
+        // A long idle time does not fill the bucket over its capacity
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.available_at(later, 5000), Ok(1000));
+        assert_eq!(
+            bucket.available_at(later, 300),
+            Ok(300),
+            "capped to what is wanted"
+        );
+    }
+
+    #[test]
+    fn refills_with_the_elapsed_time() {
+        for (elapsed_ms, expected) in [(125, 125), (250, 250), (500, 500), (2000, 1000)] {
+            let (mut bucket, start) = empty(1000);
+            assert_eq!(
+                bucket.available_at(start + Duration::from_millis(elapsed_ms), 5000),
+                Ok(expected),
+                "after {}ms",
+                elapsed_ms
+            );
+        }
+    }
+
+    #[test]
+    fn keeps_fractional_tokens_between_refills() {
+        let (mut bucket, start) = empty(4);
+        // Every refill brings half a token, which used to be truncated away
+        assert!(bucket
+            .available_at(start + Duration::from_millis(125), 1)
+            .is_err());
+        assert_eq!(
+            bucket.available_at(start + Duration::from_millis(250), 1),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn waits_for_a_useful_chunk() {
+        let (mut bucket, start) = empty(100_000);
+
+        // 10ms of the rate, not the time of a single byte
+        assert_eq!(waited_ms(bucket.available_at(start, 5000)), 10.0);
+        assert_eq!(
+            waited_ms(bucket.available_at(start + Duration::from_millis(4), 5000)),
+            6.0
+        );
+        assert_eq!(
+            bucket.available_at(start + Duration::from_millis(16), 5000),
+            Ok(1600)
+        );
+
+        // Small transfers do not wait for more than they need
+        bucket.consume(1600);
+        assert_eq!(
+            bucket.available_at(start + Duration::from_millis(17), 50),
+            Ok(50)
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::client::buffers::BufferTuning;
+use parking_lot::{const_mutex, Mutex};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Copy buffer taken from the pool when none is set, larger than the one of tokio to make fewer syscalls
+const POOLED_BUFFER: usize = 64 * 1024;
+/// Memory kept in buffers for the next connections once theirs closed, the others are freed. Copy buffers are set
+/// per profile up to 64MiB, so the pool is bounded by their size rather than their count
+const MAX_POOLED_BYTES: usize = 4 * 1024 * 1024;
+
+/// Buffers given back by the closed connections, and their total size
+static POOL: Mutex<(Vec<Box<[u8]>>, usize)> = const_mutex((Vec::new(), 0));
+
+/// Copy between the local connection of a tcp tunnel and the tunnel listener until both directions are closed,
+/// returning the bytes sent each way. The copy buffers are reused from one connection to the next.
+pub async fn relay(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+    buffers: &BufferTuning,
+) -> io::Result<(u64, u64)> {
+    let _ = buffers.apply_to_stream(a);
+    let _ = buffers.apply_to_stream(b);
+    let size = buffers.copy_buffer.unwrap_or(POOLED_BUFFER);
+    let (mut a_reader, mut a_writer) = a.split();
+    let (mut b_reader, mut b_writer) = b.split();
+    tokio::try_join!(
+        copy_pooled(&mut a_reader, &mut b_writer, size),
+        copy_pooled(&mut b_reader, &mut a_writer, size)
+    )
+}
+
+/// Buffer of the pool, given back to it when dropped
+struct PooledBuffer(Box<[u8]>);
+
+impl PooledBuffer {
+    fn take(size: usize) -> Self {
+        let mut pool = POOL.lock();
+        let (buffers, pooled) = &mut *pool;
+        let buffer = match buffers.iter().position(|buffer| buffer.len() == size) {
+            Some(i) => {
+                *pooled -= size;
+                buffers.swap_remove(i)
+            }
+            None => vec![0; size].into_boxed_slice(),
+        };
+        PooledBuffer(buffer)
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let mut pool = POOL.lock();
+        let (buffers, pooled) = &mut *pool;
+        if *pooled + self.0.len() <= MAX_POOLED_BYTES {
+            *pooled += self.0.len();
+            buffers.push(std::mem::take(&mut self.0));
+        }
+    }
+}
+
+async fn copy_pooled<R, W>(reader: &mut R, writer: &mut W, size: usize) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = PooledBuffer::take(size);
+    let buf = &mut buffer.0;
+    let mut copied = 0;
+    loop {
+        let n = reader.read(buf).await?;
+        if n == 0 {
+            writer.shutdown().await?;
+            return Ok(copied);
+        }
+        writer.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+}
@@ -0,0 +1,109 @@
+use crate::client::client_api::{ConnectedClient, WsClientApi};
+use crate::client::profile::{Profile, TunnelConfig};
+use anyhow::anyhow;
+use log::{info, warn};
+use serde_json::Value;
+
+/// Profile settings that do not affect the connection to the server
+const DESKTOP_ONLY_FIELDS: [&str; 9] = [
+    "tunnels",
+    "autoconnect",
+    "notifications",
+    "monthlyBudgetMb",
+    "budgetWarningPercents",
+    "budgetDisconnect",
+    "idleDisconnectAfterSec",
+    "idleReconnectOnDemand",
+    "schedule",
+];
+
+/// Whether the edit of a connected profile only touches its tunnels,
+/// in which case it can be applied without opening a new connection to the server
+pub fn only_tunnels_changed(old: &Profile, new: &Profile) -> bool {
+    let connection = |profile: &Profile| {
+        let mut value = serde_json::to_value(profile).unwrap_or_default();
+        if let Value::Object(fields) = &mut value {
+            for field in DESKTOP_ONLY_FIELDS {
+                fields.remove(field);
+            }
+        }
+        value
+    };
+    connection(old) == connection(new)
+}
+
+/// Apply the edited tunnels of a connected profile: removed and disabled tunnels are stopped, added and enabled ones
+/// started and changed ones restarted. The websocket client and the unchanged tunnels, listeners included, are left untouched.
+/// The returned client reflects the tunnels actually running, even when some of them could not be started.
+pub async fn reconcile(
+    connected: &ConnectedClient,
+    old: &Profile,
+    new: &Profile,
+) -> (ConnectedClient, anyhow::Result<()>) {
+    let mut client = connected.clone();
+
+    // Reject an invalid edit before stopping anything
+    if let Err(err) = new.to_client() {
+        return (client, Err(err));
+    }
+
+    let (old_tunnels, new_tunnels): (Vec<_>, Vec<_>) = (
+        old.enabled_tunnels().cloned().collect(),
+        new.enabled_tunnels().cloned().collect(),
+    );
+    let (removed, added) = diff(&old_tunnels, &new_tunnels);
+    for config in &removed {
+        let Ok(tunnel) = config.to_tunnel() else {
+            continue;
+        };
+        info!("Stopping tunnel {} of profile {}", tunnel.id, new.name);
+        client.engine.remove_tunnel(&tunnel.id, config.reverse);
+        if !config.reverse {
+            client.listeners.retain(|l| l.tunnel_id != tunnel.id);
+        }
+    }
+
+    let mut errors = vec![];
+    for config in added {
+        let result = async {
+            let tunnel = new.tunnel(config)?;
+            info!("Starting tunnel {} of profile {}", tunnel.id, new.name);
+            WsClientApi::prepare_tunnel(tunnel, config.reverse, client.stats.clone()).await
+        }
+        .await;
+        match result {
+            Ok((prepared, listener)) => {
+                client.engine.add_tunnel(prepared);
+                client.listeners.extend(listener);
+            }
+            Err(err) => {
+                warn!("Cannot start tunnel {}: {:?}", config.spec, err);
+                errors.push(format!("{}: {:#}", config.spec, err));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return (
+            client,
+            Err(anyhow!("Some tunnels cannot start: {}", errors.join(", "))),
+        );
+    }
+    (client, Ok(()))
+}
+
+/// Tunnels to stop and tunnels to start, a changed tunnel being in both
+fn diff<'a>(
+    old: &'a [TunnelConfig],
+    new: &'a [TunnelConfig],
+) -> (Vec<&'a TunnelConfig>, Vec<&'a TunnelConfig>) {
+    let unchanged = |config: &TunnelConfig, others: &[TunnelConfig]| {
+        let value = serde_json::to_value(config).ok();
+        others
+            .iter()
+            .any(|other| serde_json::to_value(other).ok() == value)
+    };
+    let removed = old.iter().filter(|c| !unchanged(c, new)).collect();
+    let added = new.iter().filter(|c| !unchanged(c, old)).collect();
+    (removed, added)
+}
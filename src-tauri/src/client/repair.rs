@@ -0,0 +1,176 @@
+use crate::client::platform;
+use crate::client::profile::{Profile, TunnelConfig};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+use std::str::FromStr;
+use tauri::http::{HeaderName, HeaderValue};
+use tauri::Url;
+
+const DNS_RESOLVER_SCHEMES: [&str; 4] = ["dns", "dns+https", "dns+tls", "system"];
+const FILE_FIELDS: [&str; 3] = ["tlsCertificate", "tlsPrivateKey", "httpHeadersFile"];
+
+/// Fix the frontend can apply to a stored profile with `repair_profile`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RepairAction {
+    /// Ask the user for another file, whose path is given as value
+    PickFile,
+    /// Drop the field, or the element when it is part of a list
+    RemoveField,
+    /// Drop the field so that its default value applies
+    ResetToDefault,
+}
+
+/// A reason preventing a stored profile to load, with the ways to fix it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileIssue {
+    /// Json pointer to the faulty field of the stored profile, i.e: /httpHeaders/2
+    pub field: String,
+    pub problem: String,
+    pub actions: Vec<RepairAction>,
+}
+
+impl ProfileIssue {
+    fn new(field: String, problem: String, actions: &[RepairAction]) -> Self {
+        Self {
+            field,
+            problem,
+            actions: actions.to_vec(),
+        }
+    }
+}
+
+/// Find what prevents a stored profile to load. No issue means the profile is usable.
+pub fn diagnose(stored: &Value) -> Vec<ProfileIssue> {
+    let mut issues = vec![];
+
+    for field in FILE_FIELDS {
+        if let Some(path) = stored.get(field).and_then(Value::as_str) {
+            if !Path::new(path).exists() {
+                issues.push(ProfileIssue::new(
+                    format!("/{}", field),
+                    format!("File {} does not exist", path),
+                    &[RepairAction::PickFile, RepairAction::RemoveField],
+                ));
+            }
+        }
+    }
+
+    for (i, resolver) in elements(stored, "dnsResolver") {
+        let valid = resolver
+            .as_str()
+            .and_then(|r| Url::parse(r).ok())
+            .is_some_and(|url| DNS_RESOLVER_SCHEMES.contains(&url.scheme()));
+        if !valid {
+            issues.push(ProfileIssue::new(
+                format!("/dnsResolver/{}", i),
+                format!("Invalid dns resolver {}", resolver),
+                &[RepairAction::RemoveField, RepairAction::ResetToDefault],
+            ));
+        }
+    }
+
+    for (i, header) in elements(stored, "httpHeaders") {
+        let name = header
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        let value = header
+            .get("value")
+            .and_then(Value::as_str)
+            .unwrap_or_default();
+        if HeaderName::from_str(name).is_err() || HeaderValue::from_str(value).is_err() {
+            issues.push(ProfileIssue::new(
+                format!("/httpHeaders/{}", i),
+                format!("Invalid http header {}", name),
+                &[RepairAction::RemoveField],
+            ));
+        }
+    }
+
+    for (i, tunnel) in elements(stored, "tunnels") {
+        let check = serde_json::from_value::<TunnelConfig>(tunnel.clone())
+            .map_err(anyhow::Error::from)
+            .and_then(|config| platform::check_tunnel(&config.to_tunnel()?));
+        if let Err(err) = check {
+            issues.push(ProfileIssue::new(
+                format!("/tunnels/{}", i),
+                format!("Invalid tunnel: {}", err),
+                &[RepairAction::RemoveField],
+            ));
+        }
+    }
+
+    if !issues.is_empty() {
+        return issues;
+    }
+
+    // Anything not recognized above can only be reported
+    let loaded = serde_json::from_value::<Profile>(stored.clone())
+        .map_err(anyhow::Error::from)
+        .and_then(|profile| profile.to_client());
+    if let Err(err) = loaded {
+        issues.push(ProfileIssue::new(String::new(), format!("{:#}", err), &[]));
+    }
+    issues
+}
+
+/// Apply a repair action to the field of a stored profile, returning the repaired profile
+pub fn repair(
+    mut stored: Value,
+    field: &str,
+    action: RepairAction,
+    value: Option<Value>,
+) -> anyhow::Result<Value> {
+    match action {
+        RepairAction::PickFile => {
+            let value = value.ok_or_else(|| anyhow!("No file picked for {}", field))?;
+            *stored
+                .pointer_mut(field)
+                .ok_or_else(|| anyhow!("Unknown field {}", field))? = value;
+        }
+        RepairAction::RemoveField => remove(&mut stored, field)?,
+        RepairAction::ResetToDefault => {
+            // Defaults apply to whole fields, not to a single element of a list
+            let field = field
+                .rsplit_once('/')
+                .filter(|(parent, index)| !parent.is_empty() && index.parse::<usize>().is_ok())
+                .map_or(field, |(parent, _)| parent);
+            remove(&mut stored, field)?;
+        }
+    }
+    Ok(stored)
+}
+
+fn remove(stored: &mut Value, field: &str) -> anyhow::Result<()> {
+    let (parent, key) = field
+        .rsplit_once('/')
+        .ok_or_else(|| anyhow!("Invalid field {}", field))?;
+    let removed = match stored.pointer_mut(parent) {
+        Some(Value::Object(map)) => map.remove(key).is_some(),
+        Some(Value::Array(list)) => match key.parse::<usize>() {
+            Ok(i) if i < list.len() => {
+                list.remove(i);
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    };
+    if !removed {
+        return Err(anyhow!("Unknown field {}", field));
+    }
+    Ok(())
+}
+
+fn elements<'a>(stored: &'a Value, field: &str) -> impl Iterator<Item = (usize, &'a Value)> {
+    stored
+        .get(field)
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .enumerate()
+}
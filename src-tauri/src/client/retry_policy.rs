@@ -0,0 +1,72 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How a failing tunnel is restarted. The backoff never exceeds the max backoff of the profile
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RetryPolicy {
+    /// Wait before the first restart
+    pub initial_backoff_ms: u64,
+    /// Factor applied to the wait after each consecutive failure
+    pub multiplier: f64,
+    /// Fraction of the wait randomly added or removed, so many clients do not hammer a server in step
+    pub jitter: f64,
+    /// Consecutive failures after which the tunnel is not restarted anymore, restarted forever when not set
+    pub max_attempts: Option<u32>,
+    /// Show a notification when giving up, even if the notifications of the profile are disabled
+    pub notify_on_give_up: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff_ms: 2000,
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: Some(5),
+            notify_on_give_up: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.initial_backoff_ms == 0 {
+            return Err(anyhow!("The initial retry backoff must be greater than 0"));
+        }
+        if !self.multiplier.is_finite() || self.multiplier < 1.0 {
+            return Err(anyhow!("The retry backoff multiplier must be at least 1"));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(anyhow!("The retry jitter must be between 0 and 1"));
+        }
+        if self.max_attempts == Some(0) {
+            return Err(anyhow!("The maximum retry attempts must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Whether the tunnel is given up after that many consecutive failures
+    pub fn exhausted(&self, failures: u32) -> bool {
+        self.max_attempts.is_some_and(|max| failures > max)
+    }
+
+    /// Wait before restarting a tunnel that failed that many times in a row, starting at 1
+    pub fn backoff(&self, failures: u32, max_backoff: Duration) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_backoff_ms as f64 * self.multiplier.powi(exponent);
+        let backoff = backoff.min(max_backoff.as_millis() as f64);
+        let jittered = backoff * (1.0 + self.jitter * (2.0 * random_unit() - 1.0));
+        Duration::from_millis(jittered.max(0.0) as u64).min(max_backoff)
+    }
+}
+
+/// Random number in [0, 1], 0.5 when there is no randomness available
+fn random_unit() -> f64 {
+    let mut bytes = [0u8; 4];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => u32::from_le_bytes(bytes) as f64 / u32::MAX as f64,
+        Err(_) => 0.5,
+    }
+}
@@ -0,0 +1,190 @@
+use crate::client::connections::{ConnectionClient, Tracked};
+use crate::client::events::ClientEvent;
+use crate::client::stats::ProfileStats;
+use log::info;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::Url;
+use wstunnel::tunnel::connectors::TunnelConnector;
+use wstunnel::tunnel::RemoteAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReverseTunnelState {
+    /// Requested from the server, which does not tell whether it bound the port until a connection comes in
+    Requested,
+    /// The server accepted a connection on the remote port, so it is bound
+    Live,
+    /// The last request failed, it is requested again unless the tunnel stopped for good
+    Failed,
+}
+
+/// What is known of a reverse tunnel on the server side, i.e: for the UI to show that remote port 2222 is live
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReverseTunnelStatus {
+    pub tunnel_id: String,
+    pub remote_port: u16,
+    /// Address to reach the remote port at, the server host when the tunnel listens on every interface of the server
+    pub public_addr: String,
+    pub state: ReverseTunnelState,
+    /// Connections accepted by the server on the remote port
+    pub accepted_connections: u64,
+    pub last_accepted_at_ms: Option<u128>,
+    pub last_error: Option<String>,
+}
+
+/// Statuses of the reverse tunnels of a profile, indexed by tunnel id
+#[derive(Debug, Default)]
+pub struct ReverseTunnels {
+    statuses: Mutex<HashMap<String, ReverseTunnelStatus>>,
+}
+
+impl ReverseTunnels {
+    /// Track a reverse tunnel about to be requested, replacing what was known of a former tunnel with the same id
+    pub fn register(&self, tunnel_id: &str, bind: SocketAddr, server: &Url) {
+        let public_addr = match server.host_str() {
+            Some(host) if bind.ip().is_unspecified() => format!("{}:{}", host, bind.port()),
+            _ => bind.to_string(),
+        };
+        self.statuses.lock().insert(
+            tunnel_id.to_string(),
+            ReverseTunnelStatus {
+                tunnel_id: tunnel_id.to_string(),
+                remote_port: bind.port(),
+                public_addr,
+                state: ReverseTunnelState::Requested,
+                accepted_connections: 0,
+                last_accepted_at_ms: None,
+                last_error: None,
+            },
+        );
+    }
+
+    pub fn remove(&self, tunnel_id: &str) {
+        self.statuses.lock().remove(tunnel_id);
+    }
+
+    /// Record a connection accepted by the server, returning the status when the tunnel just became live
+    fn accepted(&self, tunnel_id: &str) -> Option<ReverseTunnelStatus> {
+        let mut statuses = self.statuses.lock();
+        let status = statuses.get_mut(tunnel_id)?;
+        let was_live = status.state == ReverseTunnelState::Live;
+        status.state = ReverseTunnelState::Live;
+        status.accepted_connections += 1;
+        status.last_accepted_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .ok();
+        status.last_error = None;
+        (!was_live).then(|| status.clone())
+    }
+
+    /// Record the error the request of a reverse tunnel failed with, no-op for other tunnels
+    pub fn failed(&self, tunnel_id: &str, error: &anyhow::Error) {
+        if let Some(status) = self.statuses.lock().get_mut(tunnel_id) {
+            status.state = ReverseTunnelState::Failed;
+            status.last_error = Some(format!("{:#}", error));
+        }
+    }
+
+    pub fn get(&self, tunnel_id: &str) -> Option<ReverseTunnelStatus> {
+        self.statuses.lock().get(tunnel_id).cloned()
+    }
+
+    /// Statuses of every reverse tunnel of the profile, sorted by tunnel id
+    pub fn list(&self) -> Vec<ReverseTunnelStatus> {
+        let mut statuses: Vec<_> = self.statuses.lock().values().cloned().collect();
+        statuses.sort_by(|a, b| a.tunnel_id.cmp(&b.tunnel_id));
+        statuses
+    }
+}
+
+/// Connector of a reverse tunnel reporting its connections. wstunnel only asks it to connect once the server
+/// accepted a connection on the remote port, which is the only sign that the port is actually bound.
+/// Each connection is listed with its destination and traffic until it is closed.
+pub struct Reported<C> {
+    inner: C,
+    tunnel_id: String,
+    stats: Arc<ProfileStats>,
+}
+
+impl<C> Reported<C> {
+    pub fn new(inner: C, tunnel_id: String, stats: Arc<ProfileStats>) -> Self {
+        Self {
+            inner,
+            tunnel_id,
+            stats,
+        }
+    }
+
+    fn accepted(&self) {
+        if let Some(status) = self.stats.reverse_tunnels.accepted(&self.tunnel_id) {
+            info!(
+                "Reverse tunnel {} is live on {}",
+                status.tunnel_id, status.public_addr
+            );
+            self.stats.publish(ClientEvent::ReverseTunnelLive {
+                tunnel_id: status.tunnel_id,
+                public_addr: status.public_addr,
+            });
+        }
+    }
+
+    /// Track the connection made to the local destination, until it is closed
+    fn track<R, W>(
+        &self,
+        remote: &Option<RemoteAddr>,
+        streams: (R, W),
+    ) -> (Tracked<R>, Tracked<W>) {
+        let destination = match remote {
+            Some(remote) => format!("{}:{}", remote.host, remote.port),
+            None => "unknown".to_string(),
+        };
+        let stats = Arc::downgrade(&self.stats);
+        let (connection, streams) = self.stats.connections.open(
+            &self.tunnel_id,
+            ConnectionClient::default(),
+            destination,
+            streams,
+            move |connection| {
+                if let Some(stats) = stats.upgrade() {
+                    stats.publish(ClientEvent::ReverseConnectionClosed { connection });
+                }
+            },
+        );
+        self.stats.publish(ClientEvent::ReverseConnectionAccepted {
+            tunnel_id: self.tunnel_id.clone(),
+            connection,
+        });
+        streams
+    }
+}
+
+impl<C: TunnelConnector> TunnelConnector for Reported<C> {
+    type Reader = Tracked<C::Reader>;
+    type Writer = Tracked<C::Writer>;
+
+    async fn connect(
+        &self,
+        remote: &Option<RemoteAddr>,
+    ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        self.accepted();
+        let streams = self.inner.connect(remote).await?;
+        Ok(self.track(remote, streams))
+    }
+
+    async fn connect_with_http_proxy(
+        &self,
+        proxy: &Url,
+        remote: &Option<RemoteAddr>,
+    ) -> anyhow::Result<(Self::Reader, Self::Writer)> {
+        self.accepted();
+        let streams = self.inner.connect_with_http_proxy(proxy, remote).await?;
+        Ok(self.track(remote, streams))
+    }
+}
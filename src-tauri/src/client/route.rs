@@ -0,0 +1,233 @@
+use crate::client::profile::{redact_proxy_url, Profile};
+use crate::client::proxy_auth::HttpProxyAuth;
+use crate::client::proxy_detect::{self, ProxyDetection};
+use crate::system_proxy::ProxyKind;
+use anyhow::anyhow;
+use serde::Serialize;
+use url::{Host, Url};
+use wstunnel::tunnel::LocalProtocol;
+
+/// How the traffic of a profile to a destination would flow, for the user to understand a routing configuration
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteExplanation {
+    pub host: String,
+    pub port: Option<u16>,
+    /// Tunnel carrying the traffic, None when it does not go through the profile
+    pub tunnel: Option<RouteTunnel>,
+    /// Applications using the PAC file of the profile reach the destination directly, outside of its tunnels
+    pub bypassed_by_pac: bool,
+    /// The split tunneling rules of the profile leave the destination out, its proxy tunnels connect it directly
+    pub bypassed_by_split_tunnel: bool,
+    /// Who resolves the name of the destination
+    pub resolver: RouteResolver,
+    /// How the server is reached, which the traffic of every tunnel goes through
+    pub server: RouteServer,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteTunnel {
+    pub tunnel_id: String,
+    pub reason: RouteReason,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteReason {
+    /// The tunnel forwards its local port to this very destination
+    Forward,
+    /// One of the PAC domains of the tunnel matches the destination
+    PacDomain,
+    /// The tunnel is the system proxy, applications following the OS settings go through it
+    SystemProxy,
+    /// The tunnel is a local proxy, only applications configured to use it go through it
+    LocalProxy,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RouteResolver {
+    /// Names are sent as is through the tunnel, the wstunnel server resolves them
+    Server,
+    /// The application resolves the name itself with the resolver of the OS, as the traffic does not go through the profile
+    System,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RouteServer {
+    pub server_addr: String,
+    /// Resolvers of the server address, the ones of the OS when empty
+    pub resolvers: Vec<Url>,
+    pub prefer_ipv4: bool,
+    /// A recently resolved address of the server is used when resolving it takes too long
+    pub persist_dns: bool,
+    pub proxy: RouteProxy,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RouteProxy {
+    Direct,
+    Http {
+        proxy: String,
+        auth: HttpProxyAuth,
+    },
+    Socks5 {
+        proxy: String,
+    },
+    /// Through a local proxy tunnel of another profile, the route of the server is then the one of that profile
+    Chained {
+        profile_id: String,
+        tunnel_id: Option<String>,
+    },
+    /// Proxy detected now from the OS settings or a PAC file, it may differ on the next connection
+    Detected {
+        detection: ProxyDetection,
+        proxy: Option<String>,
+        error: Option<String>,
+    },
+}
+
+/// Explain the route of a destination, given as `host`, `host:port` or an url
+pub async fn explain(profile: &Profile, target: &str) -> anyhow::Result<RouteExplanation> {
+    let (host, port) = parse_target(target)?;
+    let host_name = host.to_string();
+
+    let mut pac_routed = false;
+    let mut forward = None;
+    let mut pac_domain = None;
+    let mut system_proxy = None;
+    let mut local_proxy = None;
+    for config in profile.enabled_tunnels().filter(|t| !t.reverse) {
+        let tunnel = config.to_tunnel()?;
+        let is_proxy = ProxyKind::of(&tunnel.local_protocol).is_some();
+        pac_routed |= is_proxy && !config.pac_domains.is_empty();
+        let forwards = matches!(
+            tunnel.local_protocol,
+            LocalProtocol::Tcp { .. } | LocalProtocol::Udp { .. } | LocalProtocol::Stdio { .. }
+        ) && tunnel.remote.0 == host
+            && !port.is_some_and(|port| port != tunnel.remote.1);
+        if forwards && forward.is_none() {
+            forward = Some(tunnel.id.clone());
+        }
+        if is_proxy && pac_domain.is_none() && pac_matches(&config.pac_domains, &host_name) {
+            pac_domain = Some(tunnel.id.clone());
+        }
+        if is_proxy && config.set_system_proxy && system_proxy.is_none() {
+            system_proxy = Some(tunnel.id.clone());
+        }
+        if is_proxy && local_proxy.is_none() {
+            local_proxy = Some(tunnel.id.clone());
+        }
+    }
+
+    // The PAC file answers DIRECT for every domain its tunnels do not route
+    let bypassed_by_pac = pac_routed && pac_domain.is_none();
+    let bypassed_by_split_tunnel = match &profile.split_tunnel {
+        Some(split) => !split.rules()?.tunnels(&host),
+        None => false,
+    };
+    let tunnel = [
+        (forward, RouteReason::Forward),
+        (pac_domain, RouteReason::PacDomain),
+        (system_proxy, RouteReason::SystemProxy),
+        (local_proxy, RouteReason::LocalProxy),
+    ]
+    .into_iter()
+    .filter(|(_, reason)| !bypassed_by_split_tunnel || matches!(reason, RouteReason::Forward))
+    .find_map(|(id, reason)| id.map(|tunnel_id| RouteTunnel { tunnel_id, reason }));
+    let resolver = if tunnel.is_some() {
+        RouteResolver::Server
+    } else {
+        RouteResolver::System
+    };
+
+    Ok(RouteExplanation {
+        host: host_name,
+        port,
+        tunnel,
+        bypassed_by_pac,
+        bypassed_by_split_tunnel,
+        resolver,
+        server: RouteServer {
+            server_addr: profile.server_addr.to_string(),
+            resolvers: profile.dns_resolver.clone(),
+            prefer_ipv4: profile.dns_resolver_prefer_ipv4,
+            persist_dns: profile.persist_dns,
+            proxy: server_proxy(profile).await,
+        },
+    })
+}
+
+async fn server_proxy(profile: &Profile) -> RouteProxy {
+    if let Some(chain) = &profile.chain {
+        return RouteProxy::Chained {
+            profile_id: chain.profile_id.clone(),
+            tunnel_id: chain.tunnel_id.clone(),
+        };
+    }
+    if let Some(proxy) = &profile.http_proxy {
+        return RouteProxy::Http {
+            proxy: redact_proxy_url(proxy),
+            auth: profile.http_proxy_auth,
+        };
+    }
+    if let Some(proxy) = &profile.socks5_proxy {
+        let mut proxy = proxy.clone();
+        let _ = proxy.set_password(None);
+        return RouteProxy::Socks5 {
+            proxy: proxy.to_string(),
+        };
+    }
+    if profile.http_proxy_detection == ProxyDetection::Manual {
+        return RouteProxy::Direct;
+    }
+    let (proxy, error) =
+        match proxy_detect::detect(&profile.http_proxy_detection, &profile.server_addr).await {
+            Ok(proxy) => (proxy, None),
+            Err(err) => (None, Some(format!("{:#}", err))),
+        };
+    RouteProxy::Detected {
+        detection: profile.http_proxy_detection.clone(),
+        proxy,
+        error,
+    }
+}
+
+/// A PAC domain matches itself and all its subdomains, as in the generated PAC file
+fn pac_matches(domains: &[String], host: &str) -> bool {
+    domains.iter().any(|domain| {
+        let domain = domain.trim_start_matches("*.").trim_start_matches('.');
+        host == domain || host.ends_with(&format!(".{}", domain))
+    })
+}
+
+fn parse_target(target: &str) -> anyhow::Result<(Host, Option<u16>)> {
+    let target = target.trim();
+    let invalid = |err: String| anyhow!("Invalid destination {}: {}", target, err);
+    if target.contains("://") {
+        let url = Url::parse(target).map_err(|err| invalid(err.to_string()))?;
+        let host = url
+            .host()
+            .ok_or_else(|| invalid("no host".to_string()))?
+            .to_owned();
+        return Ok((host, url.port_or_known_default()));
+    }
+
+    let (host, port) = match target.rsplit_once(':') {
+        // A bare ipv6 address has several colons, and no port
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => (
+            host.to_string(),
+            Some(
+                port.parse()
+                    .map_err(|_| invalid(format!("invalid port {}", port)))?,
+            ),
+        ),
+        Some(_) if !target.starts_with('[') => (format!("[{}]", target), None),
+        _ => (target.to_string(), None),
+    };
+    let host = Host::parse(&host).map_err(|err| invalid(err.to_string()))?;
+    Ok((host, port))
+}
@@ -0,0 +1,95 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// Days to look ahead for the next change of a schedule, a window is at most a week away
+const LOOKAHEAD_DAYS: i64 = 8;
+
+/// Time of the week a scheduled profile is connected, in local time, i.e: weekdays from 09:00 to 18:00
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ActiveWindow {
+    /// Days the window starts on, every day when empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    /// `HH:MM`
+    pub start: String,
+    /// `HH:MM`, before the start for a window going past midnight, which then ends on the next day
+    pub end: String,
+}
+
+impl ActiveWindow {
+    fn times(&self) -> anyhow::Result<(NaiveTime, NaiveTime)> {
+        let parse = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| anyhow!("Invalid time {}, expected HH:MM", time))
+        };
+        Ok((parse(&self.start)?, parse(&self.end)?))
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let (start, end) = self.times()?;
+        if start == end {
+            return Err(anyhow!(
+                "Active window from {} to {} is empty",
+                self.start,
+                self.end
+            ));
+        }
+        Ok(())
+    }
+
+    fn starts_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Start and end of the window when it starts on the day of `date`, if it does
+    fn occurrence(&self, date: DateTime<Local>) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        let (start, end) = self.times().ok()?;
+        if !self.starts_on(date.weekday()) {
+            return None;
+        }
+        let day = date.date_naive();
+        let end_day = if end < start { day.succ_opt()? } else { day };
+        // Skipped by a DST change, the window then starts or ends at the first valid time after it
+        let local = |date: chrono::NaiveDateTime| {
+            Local.from_local_datetime(&date).earliest().or_else(|| {
+                Local
+                    .from_local_datetime(&(date + Duration::hours(1)))
+                    .earliest()
+            })
+        };
+        Some((local(day.and_time(start))?, local(end_day.and_time(end))?))
+    }
+}
+
+/// Whether a profile with these windows must be connected at the given time
+pub fn is_active(windows: &[ActiveWindow], now: DateTime<Local>) -> bool {
+    // A window going past midnight started the day before
+    [now - Duration::days(1), now].iter().any(|day| {
+        windows.iter().any(|window| {
+            window
+                .occurrence(*day)
+                .is_some_and(|(start, end)| start <= now && now < end)
+        })
+    })
+}
+
+/// Next time the profile must be connected or disconnected, None when the schedule never changes
+pub fn next_change(windows: &[ActiveWindow], now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let active = is_active(windows, now);
+    let mut boundaries: Vec<DateTime<Local>> = (-1..LOOKAHEAD_DAYS)
+        .flat_map(|offset| {
+            let day = now + Duration::days(offset);
+            windows
+                .iter()
+                .filter_map(move |window| window.occurrence(day))
+                .flat_map(|(start, end)| [start, end])
+        })
+        .filter(|boundary| *boundary > now)
+        .collect();
+    boundaries.sort();
+    boundaries
+        .into_iter()
+        .find(|boundary| is_active(windows, *boundary) != active)
+}
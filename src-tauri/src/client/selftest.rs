@@ -0,0 +1,325 @@
+use crate::client::client_api::{ConnectedClient, WsClientApi};
+use crate::client::profile::Profile;
+use anyhow::{anyhow, Context};
+use log::warn;
+use serde::Serialize;
+use std::future::Future;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+use wstunnel::protocols::dns::DnsResolver;
+use wstunnel::restrictions::types::RestrictionsRules;
+use wstunnel::tunnel::server::{WsServer, WsServerConfig};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const PAYLOAD_SIZE: usize = 64 * 1024;
+/// Udp datagrams must fit in a single websocket frame of the tunnel
+const DATAGRAM_SIZE: usize = 1200;
+
+/// Part of the app exercised by a check of the self test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelfTestCapability {
+    /// The client connects to a wstunnel server and upgrades the connection
+    Server,
+    Tcp,
+    Udp,
+    Socks5,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfTestCheck {
+    pub capability: SelfTestCapability,
+    pub passed: bool,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelfTestReport {
+    pub checks: Vec<SelfTestCheck>,
+    /// Every check passed, a problem reaching a remote server then lies on the way to it or on the server
+    pub passed: bool,
+}
+
+/// Run a wstunnel server on the loopback interface, connect the client to it and echo traffic through
+/// each kind of tunnel, none of it leaving the machine.
+pub async fn run() -> SelfTestReport {
+    let mut checks = vec![];
+    let (server, client) = match timed(SelfTestCapability::Server, connect()).await {
+        (check, Some(connected)) => {
+            checks.push(check);
+            connected
+        }
+        (check, None) => {
+            checks.push(check);
+            for capability in [
+                SelfTestCapability::Tcp,
+                SelfTestCapability::Udp,
+                SelfTestCapability::Socks5,
+            ] {
+                checks.push(SelfTestCheck {
+                    capability,
+                    passed: false,
+                    duration_ms: 0,
+                    error: Some(
+                        "Not run, the client cannot connect to the test server".to_string(),
+                    ),
+                });
+            }
+            return SelfTestReport {
+                checks,
+                passed: false,
+            };
+        }
+    };
+
+    checks.push(
+        timed(SelfTestCapability::Tcp, check_tcp(&client.listener("tcp")))
+            .await
+            .0,
+    );
+    checks.push(
+        timed(SelfTestCapability::Udp, check_udp(&client.listener("udp")))
+            .await
+            .0,
+    );
+    checks.push(
+        timed(
+            SelfTestCapability::Socks5,
+            check_socks5(&client.listener("socks5"), client.tcp_echo),
+        )
+        .await
+        .0,
+    );
+
+    client.connected.shutdown();
+    client.tcp_echo_task.abort();
+    client.udp_echo_task.abort();
+    server.abort();
+    SelfTestReport {
+        passed: checks.iter().all(|check| check.passed),
+        checks,
+    }
+}
+
+/// Client connected to the test server, with the echo servers its tunnels lead to
+struct TestClient {
+    connected: ConnectedClient,
+    tcp_echo: SocketAddr,
+    tcp_echo_task: JoinHandle<()>,
+    udp_echo_task: JoinHandle<()>,
+}
+
+impl TestClient {
+    fn listener(&self, tunnel_id: &str) -> anyhow::Result<SocketAddr> {
+        self.connected
+            .listeners
+            .iter()
+            .find(|listener| listener.tunnel_id == tunnel_id)
+            .map(|listener| listener.bound)
+            .ok_or_else(|| anyhow!("Tunnel {} has no listener", tunnel_id))
+    }
+}
+
+async fn timed<T>(
+    capability: SelfTestCapability,
+    check: impl Future<Output = anyhow::Result<T>>,
+) -> (SelfTestCheck, Option<T>) {
+    let started = Instant::now();
+    let result = match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(anyhow!("Timed out")),
+    };
+    let duration_ms = started.elapsed().as_millis() as u64;
+    match result {
+        Ok(value) => (
+            SelfTestCheck {
+                capability,
+                passed: true,
+                duration_ms,
+                error: None,
+            },
+            Some(value),
+        ),
+        Err(err) => (
+            SelfTestCheck {
+                capability,
+                passed: false,
+                duration_ms,
+                error: Some(format!("{:#}", err)),
+            },
+            None,
+        ),
+    }
+}
+
+/// Start the echo servers and the test server, and connect a client with a tunnel of each kind to them
+async fn connect() -> anyhow::Result<(JoinHandle<()>, TestClient)> {
+    let (tcp_echo, tcp_echo_task) = tcp_echo_server().await?;
+    let (udp_echo, udp_echo_task) = udp_echo_server().await?;
+    let (server_addr, server) = start_server().await?;
+
+    let profile: Profile = serde_json::from_value(serde_json::json!({
+        "name": "selftest",
+        "serverAddr": format!("ws://{}", server_addr),
+        "tunnels": [
+            { "id": "tcp", "spec": format!("tcp://127.0.0.1:0:127.0.0.1:{}", tcp_echo.port()) },
+            { "id": "udp", "spec": format!("udp://127.0.0.1:0:127.0.0.1:{}", udp_echo.port()) },
+            { "id": "socks5", "spec": "socks5://127.0.0.1:0" },
+        ],
+    }))?;
+    let connected = match WsClientApi::connect(Box::new(profile.to_client()?), |_| {}).await {
+        Ok(connected) => connected,
+        Err(err) => {
+            server.abort();
+            tcp_echo_task.abort();
+            udp_echo_task.abort();
+            return Err(err);
+        }
+    };
+    let client = TestClient {
+        connected,
+        tcp_echo,
+        tcp_echo_task,
+        udp_echo_task,
+    };
+    Ok((server, client))
+}
+
+/// In-process wstunnel server, without tls nor restrictions, on a free port of the loopback interface
+async fn start_server() -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+    // wstunnel binds the server by itself, so a free port is looked for first
+    let bind = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await?
+        .local_addr()?;
+    let config = WsServerConfig {
+        socket_so_mark: None,
+        bind,
+        websocket_ping_frequency: None,
+        timeout_connect: CHECK_TIMEOUT,
+        websocket_mask_frame: false,
+        tls: None,
+        dns_resolver: DnsResolver::new_from_urls(&[], None, None, true)
+            .with_context(|| "Cannot create dns resolver")?,
+        restriction_config: None,
+        http_proxy: None,
+    };
+    let restrictions = RestrictionsRules::from_path_prefix(&[], &[])?;
+    let server = WsServer::new(config);
+    let task = tokio::spawn(async move {
+        if let Err(err) = server.serve(restrictions).await {
+            warn!("Self test server stopped: {:?}", err);
+        }
+    });
+    // Give the server the time to bind before connecting to it
+    for _ in 0..50 {
+        if TcpStream::connect(bind).await.is_ok() {
+            return Ok((bind, task));
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    task.abort();
+    Err(anyhow!("The test server did not start on {}", bind))
+}
+
+async fn tcp_echo_server() -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let task = tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    Ok((addr, task))
+}
+
+async fn udp_echo_server() -> anyhow::Result<(SocketAddr, JoinHandle<()>)> {
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = socket.local_addr()?;
+    let task = tokio::spawn(async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        while let Ok((read, peer)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..read], peer).await;
+        }
+    });
+    Ok((addr, task))
+}
+
+fn payload(size: usize) -> anyhow::Result<Vec<u8>> {
+    let mut payload = vec![0u8; size];
+    getrandom::getrandom(&mut payload)?;
+    Ok(payload)
+}
+
+/// Send a payload through the stream and check the very same one comes back
+async fn echo(stream: &mut TcpStream) -> anyhow::Result<()> {
+    let sent = payload(PAYLOAD_SIZE)?;
+    let (mut reader, mut writer) = stream.split();
+    let mut received = vec![0u8; sent.len()];
+    let (written, read) = tokio::join!(writer.write_all(&sent), reader.read_exact(&mut received));
+    written.context("Cannot send through the tunnel")?;
+    read.context("The payload did not come back through the tunnel")?;
+    if received != sent {
+        return Err(anyhow!("The payload came back altered"));
+    }
+    Ok(())
+}
+
+async fn check_tcp(listener: &anyhow::Result<SocketAddr>) -> anyhow::Result<()> {
+    let listener = listener.as_ref().map_err(|err| anyhow!("{:#}", err))?;
+    let mut stream = TcpStream::connect(listener).await?;
+    echo(&mut stream).await
+}
+
+async fn check_udp(listener: &anyhow::Result<SocketAddr>) -> anyhow::Result<()> {
+    let listener = listener.as_ref().map_err(|err| anyhow!("{:#}", err))?;
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    socket.connect(listener).await?;
+    let sent = payload(DATAGRAM_SIZE)?;
+    socket.send(&sent).await?;
+    let mut received = vec![0u8; DATAGRAM_SIZE * 2];
+    let read = socket.recv(&mut received).await?;
+    if received[..read] != sent[..] {
+        return Err(anyhow!("The datagram came back altered"));
+    }
+    Ok(())
+}
+
+async fn check_socks5(
+    listener: &anyhow::Result<SocketAddr>,
+    destination: SocketAddr,
+) -> anyhow::Result<()> {
+    let listener = listener.as_ref().map_err(|err| anyhow!("{:#}", err))?;
+    let mut stream = TcpStream::connect(listener).await?;
+    // No authentication
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut answer = [0u8; 2];
+    stream.read_exact(&mut answer).await?;
+    if answer != [5, 0] {
+        return Err(anyhow!("The socks5 listener refused the handshake"));
+    }
+    let SocketAddr::V4(destination) = destination else {
+        return Err(anyhow!("The echo server is not on ipv4"));
+    };
+    let mut request = vec![5, 1, 0, 1];
+    request.extend_from_slice(&destination.ip().octets());
+    request.extend_from_slice(&destination.port().to_be_bytes());
+    stream.write_all(&request).await?;
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(anyhow!(
+            "The socks5 listener refused the connection with code {}",
+            reply[1]
+        ));
+    }
+    echo(&mut stream).await
+}
@@ -0,0 +1,135 @@
+use crate::client::static_hosts;
+use crate::client::transport::{self, TlsSettings};
+use anyhow::anyhow;
+use futures_util::future::join_all;
+use log::{info, warn};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tauri::Url;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Any key does, the server answers before getting to check the upgrade
+const WEBSOCKET_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+
+/// Time a server took to answer an upgrade request
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerProbe {
+    pub server: Url,
+    /// From connecting to the answer of the server, none when it could not be reached
+    pub latency_ms: Option<f64>,
+    pub error: Option<String>,
+}
+
+/// How the servers are reached, as the profile would
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeSettings<'a> {
+    pub tls: &'a TlsSettings,
+    pub upgrade_path_prefix: &'a str,
+    pub static_hosts: &'a [(String, IpAddr)],
+}
+
+/// Measure the whole handshake with every server in parallel: tcp, TLS and the upgrade request.
+/// The upgrade carries no tunnel, the server refusing it answers as fast as it would accept it
+pub async fn probe_all(servers: &[Url], settings: ProbeSettings<'_>) -> Vec<ServerProbe> {
+    join_all(servers.iter().map(|server| async move {
+        let (latency_ms, error) =
+            match tokio::time::timeout(PROBE_TIMEOUT, probe(server, settings)).await {
+                Ok(Ok(latency)) => (Some(latency.as_secs_f64() * 1000.0), None),
+                Ok(Err(err)) => (None, Some(format!("{:#}", err))),
+                Err(_) => (None, Some("Timed out".to_string())),
+            };
+        ServerProbe {
+            server: server.clone(),
+            latency_ms,
+            error,
+        }
+    }))
+    .await
+}
+
+/// Server answering the upgrade request the fastest, the first one when none answered
+pub async fn fastest(servers: &[Url], settings: ProbeSettings<'_>) -> anyhow::Result<Url> {
+    let first = servers
+        .first()
+        .ok_or_else(|| anyhow!("No server to select from"))?;
+    let probes = probe_all(servers, settings).await;
+    for probe in &probes {
+        if let Some(error) = &probe.error {
+            warn!("Cannot probe server {}: {}", probe.server, error);
+        }
+    }
+    let fastest = probes
+        .into_iter()
+        .filter_map(|probe| Some((probe.latency_ms?, probe.server)))
+        .min_by(|a, b| a.0.total_cmp(&b.0));
+    match fastest {
+        Some((latency_ms, server)) => {
+            info!(
+                "Selected server {}, answering in {:.0}ms",
+                server, latency_ms
+            );
+            Ok(server)
+        }
+        None => {
+            warn!("No server answered, keeping {}", first);
+            Ok(first.clone())
+        }
+    }
+}
+
+async fn probe(server: &Url, settings: ProbeSettings<'_>) -> anyhow::Result<Duration> {
+    let host = server
+        .host()
+        .ok_or_else(|| anyhow!("Server address {} has no host", server))?
+        .to_owned();
+    let port = server
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("Server address {} has no port", server))?;
+    let connect_host = static_hosts::lookup(settings.static_hosts, &host)
+        .unwrap_or_else(|| host.clone())
+        .to_string();
+    let host_header = match server.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    let request = format!(
+        "GET /{}/events HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n\r\n",
+        settings.upgrade_path_prefix, host_header, WEBSOCKET_KEY
+    );
+
+    let start = Instant::now();
+    let tcp = TcpStream::connect((connect_host.trim_matches(['[', ']']), port)).await?;
+    match server.scheme() {
+        "wss" | "https" => {
+            let server_name = match &settings.tls.sni_override {
+                Some(sni) => ServerName::DnsName(sni.clone()),
+                None => {
+                    ServerName::try_from(host.to_string().trim_matches(['[', ']']).to_string())?
+                }
+            };
+            let connector = transport::websocket_tls_connector(settings.tls)?;
+            upgrade(connector.connect(server_name, tcp).await?, &request).await?;
+        }
+        _ => upgrade(tcp, &request).await?,
+    }
+    Ok(start.elapsed())
+}
+
+/// Send the upgrade request, returning once the status line of the answer arrived
+async fn upgrade<S>(mut stream: S, request: &str) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.write_all(request.as_bytes()).await?;
+    let mut status = [0; 12];
+    stream.read_exact(&mut status).await?;
+    if !status.starts_with(b"HTTP/") {
+        return Err(anyhow!("The server did not answer with http"));
+    }
+    Ok(())
+}
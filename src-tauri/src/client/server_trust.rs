@@ -0,0 +1,347 @@
+use crate::client::profile::Profile;
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use log::{info, warn};
+use parking_lot::{const_mutex, Mutex};
+use ring::digest;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+use tauri::Url;
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
+use wstunnel::protocols::tls;
+use x509_parser::extensions::GeneralName;
+use x509_parser::time::ASN1Time;
+
+const TRUST_FILE: &str = "trusted-servers.json";
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serialize the read-modify-write cycles of the trust file within the process
+static TRUST_LOCK: Mutex<()> = const_mutex(());
+
+/// Certificate presented by a server the user must confirm, because the profile does not verify it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerCertificate {
+    /// `host:port` the decision is recorded for
+    pub server: String,
+    pub subject: String,
+    pub issuer: String,
+    pub not_before_sec: i64,
+    pub not_after_sec: i64,
+    /// SHA-256 of the DER certificate, as colon separated hex bytes
+    pub fingerprint: String,
+    /// Fingerprint trusted before for this server, when the certificate changed since
+    pub previous_fingerprint: Option<String>,
+}
+
+/// Certificate of a server the user must confirm before connecting, when the profile does not verify certificates.
+/// Every server the profile may select is checked, the first one whose certificate is not trusted yet is returned.
+/// `None` when the certificates are verified, the transport has no TLS, or the same certificates were trusted before.
+/// This only asks the user: the tunnels accept nothing but the `pinned` certificates on each of their handshakes.
+pub async fn untrusted_certificate(
+    profile: &Profile,
+    data_dir: &Path,
+) -> anyhow::Result<Option<ServerCertificate>> {
+    if profile.tls_verify_certificate {
+        return Ok(None);
+    }
+    let servers = std::iter::once(&profile.server_addr).chain(&profile.server_candidates);
+    for server in servers.filter(|server| matches!(server.scheme(), "wss" | "https")) {
+        // An on demand profile does not reach the server before it is used, the tunnels enforce the certificate
+        // trusted before
+        if profile.on_demand && load(data_dir).contains_key(&server_key(server)?) {
+            continue;
+        }
+        let certificate = match fetch_certificate(profile, server).await {
+            Ok(certificate) => certificate,
+            // Reached directly rather than through the proxy or the chain of the profile, the server may only be
+            // reachable through them. A certificate trusted before is still enforced by the tunnels
+            Err(err) if load(data_dir).contains_key(&server_key(server)?) => {
+                warn!(
+                    "Cannot fetch the certificate of {}, connecting with the one trusted before: {:#}",
+                    server, err
+                );
+                continue;
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Cannot fetch the certificate of {}", server))
+            }
+        };
+        let trusted = load(data_dir).remove(&certificate.server);
+        if trusted.as_ref() != Some(&certificate.fingerprint) {
+            return Ok(Some(ServerCertificate {
+                previous_fingerprint: trusted,
+                ..certificate
+            }));
+        }
+    }
+    Ok(None)
+}
+
+/// Fingerprints of the certificates the user trusted for the servers of the profile, the only ones its tunnels
+/// accept when the profile does not verify certificates
+pub fn pinned(profile: &Profile, data_dir: &Path) -> Vec<String> {
+    let mut trusted = load(data_dir);
+    std::iter::once(&profile.server_addr)
+        .chain(&profile.server_candidates)
+        .filter_map(|server| trusted.remove(&server_key(server).ok()?))
+        .collect()
+}
+
+/// Remember the user confirmed the certificate with that fingerprint for the server
+pub fn trust(data_dir: &Path, server: &str, fingerprint: &str) -> anyhow::Result<()> {
+    let _lock = TRUST_LOCK.lock();
+    let mut trusted = load_unlocked(data_dir);
+    trusted.insert(server.to_string(), fingerprint.to_string());
+    save(data_dir, &trusted)?;
+    info!("Certificate {} trusted for {}", fingerprint, server);
+    Ok(())
+}
+
+/// Ask again for the certificate of the server on the next connection
+pub fn forget(data_dir: &Path, server: &str) -> anyhow::Result<()> {
+    let _lock = TRUST_LOCK.lock();
+    let mut trusted = load_unlocked(data_dir);
+    if trusted.remove(server).is_some() {
+        save(data_dir, &trusted)?;
+    }
+    Ok(())
+}
+
+/// Certificate of the chain presented by a server, parsed for the user to review before trusting or pinning it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateDetails {
+    pub subject: String,
+    pub issuer: String,
+    /// Colon separated hex bytes
+    pub serial: String,
+    /// DNS names, IP addresses, emails and URIs the certificate is valid for
+    pub subject_alt_names: Vec<String>,
+    pub not_before_sec: i64,
+    pub not_after_sec: i64,
+    /// Past the end of its validity period
+    pub expired: bool,
+    /// Before the start of its validity period, or the clock of this machine is behind
+    pub not_yet_valid: bool,
+    pub self_signed: bool,
+    /// SHA-256 of the DER certificate, as trusted by `trust_server_certificate`
+    pub sha256_fingerprint: String,
+    pub sha1_fingerprint: String,
+    /// Base64 SHA-256 of the public key, which stays the same across renewals keeping the key
+    pub public_key_sha256: String,
+}
+
+/// Certificate chain presented by a server, the leaf first
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CertificateChain {
+    /// `host:port` as recorded when trusting the certificate
+    pub server: String,
+    pub certificates: Vec<CertificateDetails>,
+    /// Whether the chain is trusted by the roots of the OS
+    pub verified: bool,
+    pub verification_error: Option<String>,
+}
+
+/// Certificate chain the server presents, captured without verifying it, then checked against the roots of the OS
+pub async fn inspect(url: &Url) -> anyhow::Result<CertificateChain> {
+    if !matches!(url.scheme(), "wss" | "https") {
+        return Err(anyhow!("{} does not use TLS", url));
+    }
+    let (host, port) = host_port(url)?;
+    let server_name = ServerName::try_from(host.clone())?;
+
+    let connector = tls::tls_connector(false, vec![], true, None, None)?;
+    let stream = handshake(&host, port, server_name.clone(), connector).await?;
+    let certificates = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .filter(|certs| !certs.is_empty())
+        .ok_or_else(|| anyhow!("server presented no certificate"))?
+        .iter()
+        .map(|der| certificate_details(der.as_ref()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let connector = tls::tls_connector(true, vec![], true, None, None)?;
+    let verification_error = handshake(&host, port, server_name, connector)
+        .await
+        .err()
+        .map(|err| format!("{:#}", err));
+    Ok(CertificateChain {
+        server: format!("{}:{}", host, port),
+        certificates,
+        verified: verification_error.is_none(),
+        verification_error,
+    })
+}
+
+fn certificate_details(der: &[u8]) -> anyhow::Result<CertificateDetails> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(der)
+        .map_err(|err| anyhow!("cannot parse a certificate of the server: {}", err))?;
+    let subject_alt_names = certificate
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_string()),
+                    GeneralName::RFC822Name(email) => Some(email.to_string()),
+                    GeneralName::URI(uri) => Some(uri.to_string()),
+                    GeneralName::IPAddress(ip) => match ip.len() {
+                        4 => Some(IpAddr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string()),
+                        16 => Some(IpAddr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string()),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let validity = certificate.validity();
+    let now = ASN1Time::now();
+    Ok(CertificateDetails {
+        subject: certificate.subject().to_string(),
+        issuer: certificate.issuer().to_string(),
+        serial: certificate.raw_serial_as_string(),
+        subject_alt_names,
+        not_before_sec: validity.not_before.timestamp(),
+        not_after_sec: validity.not_after.timestamp(),
+        expired: validity.not_after < now,
+        not_yet_valid: now < validity.not_before,
+        self_signed: certificate.subject() == certificate.issuer(),
+        sha256_fingerprint: fingerprint(der),
+        sha1_fingerprint: hex_fingerprint(
+            digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, der).as_ref(),
+        ),
+        public_key_sha256: base64::engine::general_purpose::STANDARD
+            .encode(Sha256::digest(certificate.public_key().raw)),
+    })
+}
+
+/// Complete a TLS handshake without sending anything, to read the certificate the server presents
+async fn fetch_certificate(profile: &Profile, url: &Url) -> anyhow::Result<ServerCertificate> {
+    let (host, port) = host_port(url)?;
+    let sni = profile
+        .tls_sni_override
+        .clone()
+        .unwrap_or_else(|| host.clone());
+    let server_name = ServerName::try_from(sni)?;
+
+    // Servers requiring mTLS end the handshake before showing their certificate to unknown clients
+    let (tls_certificate, tls_key) = if let (Some(cert), Some(key)) = (
+        profile.tls_certificate.as_ref(),
+        profile.tls_private_key.as_ref(),
+    ) {
+        (
+            Some(tls::load_certificates_from_pem(cert)?),
+            Some(tls::load_private_key_from_file(key)?),
+        )
+    } else {
+        (None, None)
+    };
+    let connector = tls::tls_connector(
+        false,
+        vec![],
+        !profile.tls_sni_disable,
+        tls_certificate,
+        tls_key,
+    )?;
+    let stream = handshake(&host, port, server_name, connector).await?;
+
+    let der = stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .ok_or_else(|| anyhow!("server presented no certificate"))?
+        .clone();
+    let leaf = tls::find_leaf_certificate(std::slice::from_ref(&der))
+        .ok_or_else(|| anyhow!("cannot parse the server certificate"))?;
+
+    Ok(ServerCertificate {
+        server: server_key(url)?,
+        subject: leaf.subject().to_string(),
+        issuer: leaf.issuer().to_string(),
+        not_before_sec: leaf.validity().not_before.timestamp(),
+        not_after_sec: leaf.validity().not_after.timestamp(),
+        fingerprint: fingerprint(der.as_ref()),
+        previous_fingerprint: None,
+    })
+}
+
+/// `host:port` the certificate of a server is trusted for
+fn server_key(url: &Url) -> anyhow::Result<String> {
+    let (host, port) = host_port(url)?;
+    Ok(format!("{}:{}", host, port))
+}
+
+fn host_port(url: &Url) -> anyhow::Result<(String, u16)> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow!("server url without host"))?
+        .trim_matches(['[', ']'])
+        .to_string();
+    Ok((host, url.port_or_known_default().unwrap_or(443)))
+}
+
+async fn handshake(
+    host: &str,
+    port: u16,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+) -> anyhow::Result<TlsStream<TcpStream>> {
+    let handshake = async {
+        let tcp = TcpStream::connect((host, port)).await?;
+        anyhow::Ok(connector.connect(server_name, tcp).await?)
+    };
+    tokio::time::timeout(FETCH_TIMEOUT, handshake)
+        .await
+        .map_err(|_| anyhow!("TLS handshake timed out"))?
+}
+
+/// SHA-256 of the DER certificate, as trusted by the user
+pub(crate) fn fingerprint(der: &[u8]) -> String {
+    hex_fingerprint(&Sha256::digest(der))
+}
+
+fn hex_fingerprint(digest: &[u8]) -> String {
+    digest
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn load(data_dir: &Path) -> HashMap<String, String> {
+    let _lock = TRUST_LOCK.lock();
+    load_unlocked(data_dir)
+}
+
+fn load_unlocked(data_dir: &Path) -> HashMap<String, String> {
+    std::fs::read(data_dir.join(TRUST_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save(data_dir: &Path, trusted: &HashMap<String, String>) -> anyhow::Result<()> {
+    std::fs::create_dir_all(data_dir)?;
+    let path = data_dir.join(TRUST_FILE);
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(trusted)?)?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
@@ -0,0 +1,446 @@
+use crate::client::client_api::DEFAULT_CLIENT_UPGRADE_PATH_PREFIX;
+use crate::client::transport;
+use anyhow::{anyhow, Context};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+use tauri::Url;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use wstunnel::protocols::tls;
+
+const STEP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Any key does, the server answers before getting to check the upgrade
+const WEBSOCKET_KEY: &str = "dGhlIHNhbXBsZSBub25jZQ==";
+/// Headers of the answer past that many lines are not read
+const MAX_HEADER_LINES: usize = 64;
+
+/// Check of the guided setup of a new profile, run one after the other by the wizard
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SetupStepKind {
+    Url,
+    Tcp,
+    Tls,
+    Upgrade,
+}
+
+/// Outcome of a check, worded for the user
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetupStep {
+    pub step: SetupStepKind,
+    pub passed: bool,
+    pub duration_ms: Option<f64>,
+    /// What the check found, i.e: the address connected to or the http status of the upgrade
+    pub detail: Option<String>,
+    pub error: Option<String>,
+    /// What the user may change for the check to pass
+    pub hint: Option<String>,
+}
+
+impl SetupStep {
+    fn passed(step: SetupStepKind, start: Instant, detail: String) -> Self {
+        Self {
+            step,
+            passed: true,
+            duration_ms: Some(start.elapsed().as_secs_f64() * 1000.0),
+            detail: Some(detail),
+            error: None,
+            hint: None,
+        }
+    }
+
+    fn failed(step: SetupStepKind, error: &anyhow::Error, hint: &str) -> Self {
+        Self {
+            step,
+            passed: false,
+            duration_ms: None,
+            detail: None,
+            error: Some(format!("{:#}", error)),
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Server url as typed by the user, normalized
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UrlCheck {
+    pub step: SetupStep,
+    /// Scheme, host and port only, for the server address of the profile
+    pub url: Option<Url>,
+    /// Path typed after the host, likely the upgrade path prefix of the server
+    pub path_prefix: Option<String>,
+}
+
+/// TCP and TLS reachability of the server
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReachabilityCheck {
+    pub steps: Vec<SetupStep>,
+    pub reachable: bool,
+    /// Whether the certificate of the server is trusted by the OS, none without TLS or when the handshake failed
+    pub certificate_trusted: Option<bool>,
+}
+
+/// Answer of the server to upgrade requests on each candidate path prefix
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpgradeCheck {
+    /// One per prefix tried, in order
+    pub steps: Vec<SetupStep>,
+    /// First prefix reaching a wstunnel server, or a server asking for credentials
+    pub path_prefix: Option<String>,
+    pub requires_credentials: bool,
+    /// Scheme of the `WWW-Authenticate` challenge, i.e: `Basic`
+    pub auth_scheme: Option<String>,
+}
+
+/// Settings the wizard fills the new profile with
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SuggestedConfig {
+    pub server_addr: Url,
+    pub http_upgrade_path_prefix: String,
+    pub tls_verify_certificate: bool,
+    /// The user must enter the upgrade credentials before connecting
+    pub requires_credentials: bool,
+}
+
+/// Every check of the setup, up to the first failing one
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SetupReport {
+    pub steps: Vec<SetupStep>,
+    /// None when a check failed
+    pub suggestion: Option<SuggestedConfig>,
+}
+
+/// Validate the format of a server url. A url without scheme is taken as `wss://`, what servers run behind
+/// a reverse proxy expect
+pub fn check_url(input: &str) -> UrlCheck {
+    let start = Instant::now();
+    match normalize_url(input) {
+        Ok((url, path_prefix)) => UrlCheck {
+            step: SetupStep::passed(SetupStepKind::Url, start, url.to_string()),
+            url: Some(url),
+            path_prefix,
+        },
+        Err(err) => UrlCheck {
+            step: SetupStep::failed(
+                SetupStepKind::Url,
+                &err,
+                "Enter the address of the server as wss://host or wss://host:port",
+            ),
+            url: None,
+            path_prefix: None,
+        },
+    }
+}
+
+fn normalize_url(input: &str) -> anyhow::Result<(Url, Option<String>)> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err(anyhow!("The server url is empty"));
+    }
+    let input = if input.contains("://") {
+        input.to_string()
+    } else {
+        format!("wss://{}", input)
+    };
+    let mut url = Url::parse(&input).with_context(|| format!("Invalid url {}", input))?;
+    transport::for_url(&url)?;
+    if url.host_str().unwrap_or_default().is_empty() {
+        return Err(anyhow!("The server url has no host"));
+    }
+    if url.port_or_known_default().is_none() {
+        return Err(anyhow!("The server url has no port"));
+    }
+    let path_prefix = url
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .next()
+        .filter(|prefix| !prefix.is_empty())
+        .map(str::to_string);
+    url.set_path("");
+    url.set_query(None);
+    url.set_fragment(None);
+    Ok((url, path_prefix))
+}
+
+/// Connect to the server, then complete a TLS handshake when the scheme asks for it.
+/// A certificate the OS does not trust is reported, the handshake being done again without verifying it
+pub async fn check_reachability(url: &Url) -> ReachabilityCheck {
+    let mut check = ReachabilityCheck {
+        steps: vec![],
+        reachable: false,
+        certificate_trusted: None,
+    };
+    let start = Instant::now();
+    let tcp = match timed(connect(url)).await {
+        Ok(tcp) => tcp,
+        Err(err) => {
+            check.steps.push(SetupStep::failed(
+                SetupStepKind::Tcp,
+                &err,
+                "Check the host and port, and that no firewall blocks the connection",
+            ));
+            return check;
+        }
+    };
+    let peer = tcp
+        .peer_addr()
+        .map_or_else(|_| url.to_string(), |addr| addr.to_string());
+    check.steps.push(SetupStep::passed(
+        SetupStepKind::Tcp,
+        start,
+        format!("Connected to {}", peer),
+    ));
+    if !uses_tls(url) {
+        check.reachable = true;
+        return check;
+    }
+
+    let start = Instant::now();
+    let trusted = timed(async { handshake(url, tcp, true).await.map(drop) }).await;
+    let step = match trusted {
+        Ok(()) => {
+            check.certificate_trusted = Some(true);
+            SetupStep::passed(
+                SetupStepKind::Tls,
+                start,
+                "The certificate of the server is trusted".to_string(),
+            )
+        }
+        Err(verified_err) => {
+            let start = Instant::now();
+            let untrusted =
+                timed(async { handshake(url, connect(url).await?, false).await.map(drop) }).await;
+            match untrusted {
+                Ok(()) => {
+                    check.certificate_trusted = Some(false);
+                    SetupStep {
+                        hint: Some(
+                            "The server presents a certificate the OS does not trust, i.e: a self-signed one. \
+                             Disable its verification only if you trust the server"
+                                .to_string(),
+                        ),
+                        error: Some(format!("{:#}", verified_err)),
+                        ..SetupStep::passed(
+                            SetupStepKind::Tls,
+                            start,
+                            "TLS works with an untrusted certificate".to_string(),
+                        )
+                    }
+                }
+                Err(err) => SetupStep::failed(
+                    SetupStepKind::Tls,
+                    &err,
+                    "The server does not speak TLS on that port, try ws:// or another port",
+                ),
+            }
+        }
+    };
+    check.reachable = step.passed;
+    check.steps.push(step);
+    check
+}
+
+/// Send an upgrade request on each candidate prefix until one reaches the server: the one typed in the url,
+/// then the default of wstunnel. The request carries no tunnel, a wstunnel server refuses it with a 400 while a
+/// reverse proxy not routing the path answers with a 404. A server restricting its prefix also answers 400 to a
+/// wrong one, which cannot be told apart from a right one
+pub async fn check_upgrade(
+    url: &Url,
+    verify_certificate: bool,
+    path_prefix: Option<&str>,
+) -> UpgradeCheck {
+    let mut prefixes: Vec<&str> = path_prefix.into_iter().collect();
+    if !prefixes.contains(&DEFAULT_CLIENT_UPGRADE_PATH_PREFIX) {
+        prefixes.push(DEFAULT_CLIENT_UPGRADE_PATH_PREFIX);
+    }
+    let mut check = UpgradeCheck {
+        steps: vec![],
+        path_prefix: None,
+        requires_credentials: false,
+        auth_scheme: None,
+    };
+    for prefix in prefixes {
+        let start = Instant::now();
+        let answer = match timed(upgrade(url, verify_certificate, prefix)).await {
+            Ok(answer) => answer,
+            Err(err) => {
+                check.steps.push(SetupStep::failed(
+                    SetupStepKind::Upgrade,
+                    &err,
+                    "The server did not answer the upgrade request",
+                ));
+                continue;
+            }
+        };
+        let (passed, hint) = match answer.status {
+            101 | 400 => (true, None),
+            401 | 407 => {
+                check.requires_credentials = true;
+                check.auth_scheme = answer.auth_scheme.clone();
+                (
+                    true,
+                    Some("The server asks for credentials, enter them as the upgrade credentials"),
+                )
+            }
+            403 => (
+                false,
+                Some("The server refused the path prefix or this client"),
+            ),
+            404 => (false, Some("Nothing answers on that path prefix")),
+            _ => (
+                false,
+                Some("The server does not look like a wstunnel server"),
+            ),
+        };
+        let mut step = SetupStep::passed(
+            SetupStepKind::Upgrade,
+            start,
+            format!("/{} answered {}", prefix, answer.status_line),
+        );
+        step.passed = passed;
+        step.hint = hint.map(str::to_string);
+        check.steps.push(step);
+        if passed {
+            check.path_prefix = Some(prefix.to_string());
+            break;
+        }
+    }
+    check
+}
+
+/// Run every check, stopping at the first failing one, and suggest the settings of the new profile
+pub async fn suggest(input: &str) -> SetupReport {
+    let url_check = check_url(input);
+    let mut report = SetupReport {
+        steps: vec![url_check.step],
+        suggestion: None,
+    };
+    let Some(url) = url_check.url else {
+        return report;
+    };
+    let reachability = check_reachability(&url).await;
+    report.steps.extend(reachability.steps);
+    if !reachability.reachable {
+        return report;
+    }
+    let verify_certificate = reachability.certificate_trusted.unwrap_or(true);
+    let upgrade = check_upgrade(&url, verify_certificate, url_check.path_prefix.as_deref()).await;
+    report.steps.extend(upgrade.steps);
+    let Some(path_prefix) = upgrade.path_prefix else {
+        return report;
+    };
+    report.suggestion = Some(SuggestedConfig {
+        server_addr: url,
+        http_upgrade_path_prefix: path_prefix,
+        tls_verify_certificate: verify_certificate,
+        requires_credentials: upgrade.requires_credentials,
+    });
+    report
+}
+
+/// Status of the answer to an upgrade request
+struct UpgradeAnswer {
+    status: u16,
+    status_line: String,
+    auth_scheme: Option<String>,
+}
+
+async fn upgrade(
+    url: &Url,
+    verify_certificate: bool,
+    prefix: &str,
+) -> anyhow::Result<UpgradeAnswer> {
+    let host_header = match url.port() {
+        Some(port) => format!("{}:{}", host(url)?, port),
+        None => host(url)?.to_string(),
+    };
+    let request = format!(
+        "GET /{}/events HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: {}\r\n\r\n",
+        prefix, host_header, WEBSOCKET_KEY
+    );
+    let tcp = connect(url).await?;
+    if uses_tls(url) {
+        read_answer(handshake(url, tcp, verify_certificate).await?, &request).await
+    } else {
+        read_answer(tcp, &request).await
+    }
+}
+
+async fn read_answer<S>(stream: S, request: &str) -> anyhow::Result<UpgradeAnswer>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(stream);
+    stream.get_mut().write_all(request.as_bytes()).await?;
+    let mut status_line = String::new();
+    stream.read_line(&mut status_line).await?;
+    let status_line = status_line.trim_end().to_string();
+    let status = status_line
+        .strip_prefix("HTTP/")
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| anyhow!("The server did not answer with http"))?;
+
+    let mut auth_scheme = None;
+    for _ in 0..MAX_HEADER_LINES {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.eq_ignore_ascii_case("www-authenticate")
+            || name.eq_ignore_ascii_case("proxy-authenticate")
+        {
+            auth_scheme = value.split_whitespace().next().map(str::to_string);
+        }
+    }
+    Ok(UpgradeAnswer {
+        status,
+        status_line,
+        auth_scheme,
+    })
+}
+
+fn uses_tls(url: &Url) -> bool {
+    matches!(url.scheme(), "wss" | "https")
+}
+
+fn host(url: &Url) -> anyhow::Result<&str> {
+    url.host_str()
+        .ok_or_else(|| anyhow!("Server address {} has no host", url))
+}
+
+async fn connect(url: &Url) -> anyhow::Result<TcpStream> {
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("Server address {} has no port", url))?;
+    Ok(TcpStream::connect((host(url)?.trim_matches(['[', ']']), port)).await?)
+}
+
+async fn handshake(
+    url: &Url,
+    tcp: TcpStream,
+    verify_certificate: bool,
+) -> anyhow::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let server_name = ServerName::try_from(host(url)?.trim_matches(['[', ']']).to_string())?;
+    let connector = tls::tls_connector(verify_certificate, vec![], true, None, None)?;
+    Ok(connector.connect(server_name, tcp).await?)
+}
+
+async fn timed<T>(
+    check: impl std::future::Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    tokio::time::timeout(STEP_TIMEOUT, check)
+        .await
+        .map_err(|_| anyhow!("Timed out after {} seconds", STEP_TIMEOUT.as_secs()))?
+}
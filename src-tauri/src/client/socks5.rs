@@ -0,0 +1,740 @@
+use crate::client::accept::AcceptBackoff;
+use crate::client::access::{self, GatedListener};
+use crate::client::bridge::{Bridge, Target};
+use crate::client::buffers::BufferTuning;
+use crate::client::connections::{ClientReader, ConnectionClient};
+use crate::client::events::ClientEvent;
+use crate::client::listener_auth::{self, AuthorizedUser, CredentialValidator};
+use crate::client::stats::ProfileStats;
+use crate::client::tasks::TaskGroup;
+use anyhow::{anyhow, Context};
+use futures_util::{stream, Stream};
+use log::{debug, info, warn};
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::select;
+use tokio::sync::mpsc;
+use url::{Host, Url};
+use wstunnel::tunnel::{LocalProtocol, RemoteAddr};
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS_VERSION: u8 = 0x05;
+const NO_AUTH: u8 = 0x00;
+const USER_PASS_AUTH: u8 = 0x02;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const REPLY_SUCCEEDED: u8 = 0x00;
+const AUTH_SUCCEEDED: u8 = 0x00;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+/// Longest user id or domain name accepted in a socks4 request, both being null terminated
+const SOCKS4_MAX_FIELD: usize = 255;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Destinations of an association without traffic for that long are closed, when the tunnel has no timeout
+const DEFAULT_UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Datagrams waiting to be sent through the tunnel of their destination, newer ones are dropped past that
+const DATAGRAM_QUEUE: usize = 256;
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+
+pub type Socks5Reader = Pin<Box<dyn AsyncRead + Send>>;
+pub type Socks5Writer = Pin<Box<dyn AsyncWrite + Send>>;
+type Socks5Item = ((ClientReader<Socks5Reader>, Socks5Writer), RemoteAddr);
+
+/// Local socks5 proxy handling both CONNECT and UDP ASSOCIATE.
+/// Each CONNECT request becomes a tcp tunnel. Each association gets its own udp relay socket,
+/// bound next to the public address of the listener, and every destination it sends datagrams to
+/// becomes an udp tunnel.
+/// Legacy socks4 and socks4a CONNECT requests are accepted on the same port, unless the listener requires credentials.
+pub async fn socks5_listener(
+    listener: GatedListener,
+    public: SocketAddr,
+    timeout: Option<Duration>,
+    auth: Option<Arc<dyn CredentialValidator>>,
+    remote_dns: Option<RemoteDnsOnly>,
+    tasks: &TaskGroup,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<Socks5Item>>> {
+    let listen = listener.local_addr()?;
+    let (tx, rx) = mpsc::channel(DATAGRAM_QUEUE);
+    let proxy = Arc::new(Socks5Proxy {
+        public_ip: public.ip(),
+        timeout,
+        auth,
+        remote_dns,
+    });
+
+    tasks.spawn(async move {
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(cnx) => {
+                    backoff.succeeded();
+                    cnx
+                }
+                Err(err) => {
+                    warn!("Cannot accept socks5 connection on {}: {:?}", listen, err);
+                    backoff.failed().await;
+                    continue;
+                }
+            };
+            let (proxy, tx) = (proxy.clone(), tx.clone());
+            tokio::spawn(async move {
+                if let Err(err) = proxy.serve(stream, tx).await {
+                    debug!("Socks5 request from {} failed: {:?}", peer, err);
+                }
+            });
+        }
+    });
+
+    Ok(stream::unfold(rx, |mut rx| async move {
+        let item = rx.recv().await?;
+        Some((Ok(item), rx))
+    }))
+}
+
+/// Refuse the CONNECT requests for an address the application resolved by itself, so that names are only ever
+/// resolved by the server. Private and loopback addresses are let through: no public name resolves to them and
+/// they are how the hosts behind the server are reached. The first refused request is reported, as it tells the
+/// application, i.e: a browser, is configured to resolve names locally.
+pub struct RemoteDnsOnly {
+    tunnel_id: String,
+    stats: Arc<ProfileStats>,
+    reported: AtomicBool,
+}
+
+impl RemoteDnsOnly {
+    pub fn new(tunnel_id: String, stats: Arc<ProfileStats>) -> Self {
+        Self {
+            tunnel_id,
+            stats,
+            reported: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the destination is a public address, so it has been resolved before reaching the listener
+    fn resolved_locally(host: &Host) -> bool {
+        match host {
+            Host::Domain(_) => false,
+            Host::Ipv4(ip) => is_public_v4(ip),
+            Host::Ipv6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => is_public_v4(&ip),
+                None => {
+                    let [first, ..] = ip.segments();
+                    !(ip.is_loopback()
+                        || ip.is_unspecified()
+                        // Unique local fc00::/7 and link local fe80::/10
+                        || first & 0xfe00 == 0xfc00
+                        || first & 0xffc0 == 0xfe80)
+                }
+            },
+        }
+    }
+
+    fn refused(&self, host: &Host, port: u16) {
+        self.stats
+            .tunnel_metrics(&self.tunnel_id)
+            .local_dns_refused
+            .fetch_add(1, Ordering::Relaxed);
+        let destination = format!("{}:{}", host, port);
+        debug!(
+            "Refusing {} on tunnel {}, it has been resolved locally",
+            destination, self.tunnel_id
+        );
+        if !self.reported.swap(true, Ordering::Relaxed) {
+            warn!(
+                "An application resolved {} locally before using socks tunnel {}, its dns requests leak",
+                destination, self.tunnel_id
+            );
+            self.stats.publish(ClientEvent::LocalDnsResolution {
+                tunnel_id: self.tunnel_id.clone(),
+                destination,
+            });
+        }
+    }
+}
+
+fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified())
+}
+
+struct Request {
+    version: u8,
+    command: u8,
+    host: Host,
+    port: u16,
+    /// Set when the listener requires credentials
+    user: Option<AuthorizedUser>,
+}
+
+struct Socks5Proxy {
+    public_ip: IpAddr,
+    timeout: Option<Duration>,
+    auth: Option<Arc<dyn CredentialValidator>>,
+    remote_dns: Option<RemoteDnsOnly>,
+}
+
+impl Socks5Proxy {
+    async fn serve(
+        &self,
+        mut stream: TcpStream,
+        tx: mpsc::Sender<Socks5Item>,
+    ) -> anyhow::Result<()> {
+        let request = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            match read_array(&mut stream).await? {
+                [SOCKS4_VERSION] => self.handshake_v4(&mut stream).await,
+                [SOCKS_VERSION] => self.handshake(&mut stream).await,
+                [version] => Err(anyhow!("unsupported socks version {}", version)),
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("handshake timed out"))??;
+        let Request {
+            version,
+            command,
+            host,
+            port,
+            user,
+        } = request;
+        // Every connection comes from the gate, which knows the actual client
+        let client = ConnectionClient {
+            peer: stream.peer_addr().ok().and_then(access::gated_peer),
+            user: user.as_ref().map(|user| user.login.clone()),
+        };
+
+        match command {
+            CMD_CONNECT => {
+                if let Some(remote_dns) = &self.remote_dns {
+                    if RemoteDnsOnly::resolved_locally(&host) {
+                        remote_dns.refused(&host, port);
+                        if version == SOCKS4_VERSION {
+                            stream
+                                .write_all(&[0, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0])
+                                .await?;
+                        } else {
+                            reply(&mut stream, REPLY_NOT_ALLOWED, unspecified()).await?;
+                        }
+                        return Err(anyhow!("{} has been resolved locally", host));
+                    }
+                }
+                // Users only exist with socks5, socks4 being refused when credentials are required
+                if let Some(user) = user.as_ref().filter(|user| !user.allows(&host)) {
+                    reply(&mut stream, REPLY_NOT_ALLOWED, unspecified()).await?;
+                    return Err(anyhow!("{} is not allowed to reach {}", user.login, host));
+                }
+                if version == SOCKS4_VERSION {
+                    stream
+                        .write_all(&[0, SOCKS4_GRANTED, 0, 0, 0, 0, 0, 0])
+                        .await?;
+                } else {
+                    reply(&mut stream, REPLY_SUCCEEDED, unspecified()).await?;
+                }
+                let (reader, writer) = stream.into_split();
+                let remote = RemoteAddr {
+                    protocol: LocalProtocol::Tcp {
+                        proxy_protocol: false,
+                    },
+                    host,
+                    port,
+                };
+                let reader = ClientReader::new(Box::pin(reader) as Socks5Reader, client);
+                let _ = tx.send(((reader, Box::pin(writer)), remote)).await;
+            }
+            CMD_UDP_ASSOCIATE => {
+                let Some(peer) = client.peer else {
+                    reply(&mut stream, REPLY_NOT_ALLOWED, unspecified()).await?;
+                    return Err(anyhow!("udp association from an unknown client"));
+                };
+                let socket = UdpSocket::bind((self.public_ip, 0)).await?;
+                reply(&mut stream, REPLY_SUCCEEDED, socket.local_addr()?).await?;
+                debug!("Socks5 udp association relayed on {}", socket.local_addr()?);
+                self.associate(stream, Arc::new(socket), peer.ip(), user, client, tx)
+                    .await;
+            }
+            _ => {
+                reply(&mut stream, REPLY_COMMAND_NOT_SUPPORTED, unspecified()).await?;
+                return Err(anyhow!("unsupported socks5 command {}", command));
+            }
+        }
+        Ok(())
+    }
+
+    /// Socks5 negotiation, the version byte being already read
+    async fn handshake(&self, stream: &mut TcpStream) -> anyhow::Result<Request> {
+        let [nmethods] = read_array(stream).await?;
+        let mut methods = vec![0; nmethods as usize];
+        stream.read_exact(&mut methods).await?;
+
+        let method = if self.auth.is_some() {
+            USER_PASS_AUTH
+        } else {
+            NO_AUTH
+        };
+        if !methods.contains(&method) {
+            stream
+                .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHOD])
+                .await?;
+            return Err(anyhow!("no acceptable authentication method"));
+        }
+        stream.write_all(&[SOCKS_VERSION, method]).await?;
+
+        let mut user = None;
+        if let Some(auth) = &self.auth {
+            // Username/password sub-negotiation of RFC 1929
+            let [_, len] = read_array(stream).await?;
+            let mut login = vec![0; len as usize];
+            stream.read_exact(&mut login).await?;
+            let [len] = read_array(stream).await?;
+            let mut password = vec![0; len as usize];
+            stream.read_exact(&mut password).await?;
+            let login = String::from_utf8_lossy(&login).into_owned();
+            let password = String::from_utf8_lossy(&password).into_owned();
+            match listener_auth::authenticate(auth, login, password).await {
+                Some(authorized) => user = Some(authorized),
+                None => {
+                    stream.write_all(&[0x01, 0x01]).await?;
+                    return Err(anyhow!("invalid credentials"));
+                }
+            }
+            stream.write_all(&[0x01, 0x00]).await?;
+        }
+
+        let [version, command, _, atyp] = read_array(stream).await?;
+        if version != SOCKS_VERSION {
+            return Err(anyhow!("unsupported socks version {}", version));
+        }
+        let host = match atyp {
+            ATYP_IPV4 => Host::Ipv4(Ipv4Addr::from(read_array::<4>(stream).await?)),
+            ATYP_IPV6 => Host::Ipv6(Ipv6Addr::from(read_array::<16>(stream).await?)),
+            ATYP_DOMAIN => {
+                let [len] = read_array(stream).await?;
+                let mut name = vec![0; len as usize];
+                stream.read_exact(&mut name).await?;
+                Host::Domain(String::from_utf8(name)?)
+            }
+            _ => return Err(anyhow!("unsupported address type {}", atyp)),
+        };
+        let port = u16::from_be_bytes(read_array(stream).await?);
+        Ok(Request {
+            version: SOCKS_VERSION,
+            command,
+            host,
+            port,
+            user,
+        })
+    }
+
+    /// Socks4 and socks4a CONNECT request, the version byte being already read.
+    /// Socks4 has no password, so it is refused by listeners requiring credentials.
+    async fn handshake_v4(&self, stream: &mut TcpStream) -> anyhow::Result<Request> {
+        let [command, port_hi, port_lo, a, b, c, d] = read_array(stream).await?;
+        let _user_id = read_null_terminated(stream).await?;
+        let reject = [0, SOCKS4_REJECTED, 0, 0, 0, 0, 0, 0];
+        if self.auth.is_some() {
+            stream.write_all(&reject).await?;
+            return Err(anyhow!(
+                "socks4 cannot authenticate, credentials are required"
+            ));
+        }
+        if command != CMD_CONNECT {
+            stream.write_all(&reject).await?;
+            return Err(anyhow!("unsupported socks4 command {}", command));
+        }
+
+        // Socks4a sends the name to resolve after the user id, with an invalid ip 0.0.0.x as a marker
+        let host = if [a, b, c] == [0, 0, 0] && d != 0 {
+            let name = read_null_terminated(stream).await?;
+            Host::Domain(String::from_utf8(name)?)
+        } else {
+            Host::Ipv4(Ipv4Addr::new(a, b, c, d))
+        };
+        Ok(Request {
+            version: SOCKS4_VERSION,
+            command,
+            host,
+            port: u16::from_be_bytes([port_hi, port_lo]),
+            user: None,
+        })
+    }
+
+    /// Relay the datagrams of an association until its control connection is closed.
+    /// Only the datagrams from `client_ip`, the client of the control connection known by the access gate, are
+    /// relayed, the association being bound to the port of the first of them.
+    /// Datagrams to the destinations the user may not reach are dropped.
+    async fn associate(
+        &self,
+        mut control: TcpStream,
+        socket: Arc<UdpSocket>,
+        client_ip: IpAddr,
+        user: Option<AuthorizedUser>,
+        connection: ConnectionClient,
+        tx: mpsc::Sender<Socks5Item>,
+    ) {
+        let idle_timeout = self.timeout.unwrap_or(DEFAULT_UDP_IDLE_TIMEOUT);
+        let mut client: Option<SocketAddr> = None;
+        let mut destinations: HashMap<(Host, u16), (mpsc::Sender<Vec<u8>>, Instant)> =
+            HashMap::new();
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        let mut control_buf = [0u8; 64];
+        let mut sweep = tokio::time::interval(idle_timeout);
+
+        loop {
+            select! {
+                read = control.read(&mut control_buf) => {
+                    if !matches!(read, Ok(n) if n > 0) {
+                        break;
+                    }
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let Ok((len, peer)) = received else {
+                        break;
+                    };
+                    if peer.ip().to_canonical() != client_ip.to_canonical()
+                        || client.is_some_and(|client| client != peer)
+                    {
+                        continue;
+                    }
+                    client = Some(peer);
+                    let Some((host, port, payload)) = parse_datagram(&buf[..len]) else {
+                        continue;
+                    };
+                    if user.as_ref().is_some_and(|user| !user.allows(&host)) {
+                        continue;
+                    }
+
+                    let key = (host, port);
+                    let open = destinations.get(&key).is_some_and(|(sender, _)| !sender.is_closed());
+                    if !open {
+                        let (sender, receiver) = mpsc::channel(DATAGRAM_QUEUE);
+                        let reader = DatagramReader { receiver };
+                        let writer = DatagramWriter {
+                            socket: socket.clone(),
+                            client: peer,
+                            header: datagram_header(&key.0, key.1),
+                        };
+                        let remote = RemoteAddr {
+                            protocol: LocalProtocol::Udp { timeout: self.timeout },
+                            host: key.0.clone(),
+                            port: key.1,
+                        };
+                        let reader = ClientReader::new(Box::pin(reader) as Socks5Reader, connection.clone());
+                        if tx.send(((reader, Box::pin(writer)), remote)).await.is_err() {
+                            break;
+                        }
+                        destinations.insert(key.clone(), (sender, Instant::now()));
+                    }
+                    if let Some((sender, last_seen)) = destinations.get_mut(&key) {
+                        *last_seen = Instant::now();
+                        let _ = sender.try_send(payload.to_vec());
+                    }
+                }
+                _ = sweep.tick() => {
+                    // Dropping the sender ends the tunnel of the destination
+                    destinations.retain(|_, (_, last_seen)| last_seen.elapsed() < idle_timeout);
+                }
+                _ = tx.closed() => break,
+            }
+        }
+    }
+}
+
+/// Datagrams sent by the client to one destination, each read returning a single datagram
+struct DatagramReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+}
+
+impl AsyncRead for DatagramReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if let Some(datagram) = ready!(self.receiver.poll_recv(cx)) {
+            // Like an udp socket, what does not fit in the buffer is lost
+            let len = datagram.len().min(buf.remaining());
+            buf.put_slice(&datagram[..len]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Send each write back to the client as a datagram coming from the destination
+struct DatagramWriter {
+    socket: Arc<UdpSocket>,
+    client: SocketAddr,
+    header: Vec<u8>,
+}
+
+impl AsyncWrite for DatagramWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut datagram = Vec::with_capacity(self.header.len() + buf.len());
+        datagram.extend_from_slice(&self.header);
+        datagram.extend_from_slice(buf);
+        ready!(self.socket.poll_send_to(cx, &datagram, self.client))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Destination and payload of a datagram sent by the client, fragmented datagrams being dropped
+fn parse_datagram(datagram: &[u8]) -> Option<(Host, u16, &[u8])> {
+    let (&[_, _, frag, atyp], rest) = datagram.split_first_chunk::<4>()?;
+    if frag != 0 {
+        return None;
+    }
+    let (host, rest) = match atyp {
+        ATYP_IPV4 => {
+            let (ip, rest) = rest.split_first_chunk::<4>()?;
+            (Host::Ipv4(Ipv4Addr::from(*ip)), rest)
+        }
+        ATYP_IPV6 => {
+            let (ip, rest) = rest.split_first_chunk::<16>()?;
+            (Host::Ipv6(Ipv6Addr::from(*ip)), rest)
+        }
+        ATYP_DOMAIN => {
+            let (&len, rest) = rest.split_first()?;
+            let name = rest.get(..len as usize)?;
+            (
+                Host::Domain(String::from_utf8(name.to_vec()).ok()?),
+                &rest[len as usize..],
+            )
+        }
+        _ => return None,
+    };
+    let (port, payload) = rest.split_first_chunk::<2>()?;
+    Some((host, u16::from_be_bytes(*port), payload))
+}
+
+fn datagram_header(host: &Host, port: u16) -> Vec<u8> {
+    let mut header = vec![0, 0, 0];
+    encode_address(&mut header, host, port);
+    header
+}
+
+fn encode_address(out: &mut Vec<u8>, host: &Host, port: u16) {
+    match host {
+        Host::Ipv4(ip) => {
+            out.push(ATYP_IPV4);
+            out.extend_from_slice(&ip.octets());
+        }
+        Host::Ipv6(ip) => {
+            out.push(ATYP_IPV6);
+            out.extend_from_slice(&ip.octets());
+        }
+        Host::Domain(name) => {
+            out.push(ATYP_DOMAIN);
+            out.push(name.len() as u8);
+            out.extend_from_slice(name.as_bytes());
+        }
+    }
+    out.extend_from_slice(&port.to_be_bytes());
+}
+
+async fn reply(stream: &mut TcpStream, status: u8, bound: SocketAddr) -> io::Result<()> {
+    let mut response = vec![SOCKS_VERSION, status, 0];
+    let host = match bound.ip() {
+        IpAddr::V4(ip) => Host::Ipv4(ip),
+        IpAddr::V6(ip) => Host::Ipv6(ip),
+    };
+    encode_address(&mut response, &host, bound.port());
+    stream.write_all(&response).await
+}
+
+fn unspecified() -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+}
+
+async fn read_null_terminated(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut field = vec![];
+    loop {
+        let [byte] = read_array(stream).await?;
+        if byte == 0 {
+            return Ok(field);
+        }
+        if field.len() == SOCKS4_MAX_FIELD {
+            return Err(anyhow!("socks4 field too long"));
+        }
+        field.push(byte);
+    }
+}
+
+async fn read_array<const N: usize>(stream: &mut TcpStream) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Start a loopback http proxy reaching the targets of its CONNECT requests through the socks5 `proxy`,
+/// i.e: Tor or a jump box, as wstunnel only goes through http proxies. Returns the url wstunnel must use as its proxy.
+/// The bridge only connects to the targets and stops once the profile is disconnected.
+pub async fn spawn_upstream_bridge(
+    proxy: Url,
+    tuning: Option<BufferTuning>,
+    targets: Vec<Target>,
+    stats: &Arc<ProfileStats>,
+) -> anyhow::Result<Url> {
+    let upstream = Arc::new(Socks5Upstream {
+        tuning,
+        ..Socks5Upstream::new(&proxy)?
+    });
+    let bridge = Bridge::bind(targets).await?;
+    info!(
+        "Reaching the server through socks5 proxy {}:{}",
+        upstream.host, upstream.port
+    );
+    let url = bridge.url()?;
+    bridge.serve(stats, move |stream, target| {
+        let upstream = upstream.clone();
+        async move { upstream.bridge(stream, &target).await }
+    });
+    Ok(url)
+}
+
+struct Socks5Upstream {
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+    /// Socket options of the connection to the proxy, the first hop to the server
+    tuning: Option<BufferTuning>,
+}
+
+impl Socks5Upstream {
+    fn new(proxy: &Url) -> anyhow::Result<Self> {
+        let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().to_string();
+        let credentials = match (proxy.username(), proxy.password()) {
+            ("", None) => None,
+            (login, password) => Some((decode(login), decode(password.unwrap_or_default()))),
+        };
+        // RFC 1929 gives each of them a single length byte
+        if let Some((login, password)) = &credentials {
+            if login.is_empty() || login.len() > 255 || password.len() > 255 {
+                return Err(anyhow!(
+                    "The login and password of a socks5 proxy must be 1 to 255 bytes long"
+                ));
+            }
+        }
+        Ok(Self {
+            host: proxy
+                .host_str()
+                .ok_or_else(|| anyhow!("Socks5 proxy without host"))?
+                .trim_matches(['[', ']'])
+                .to_string(),
+            port: proxy.port().unwrap_or(1080),
+            credentials,
+            tuning: None,
+        })
+    }
+
+    /// Answer the CONNECT request of wstunnel once the socks5 proxy reached the target, then relay the tunnel
+    async fn bridge(&self, mut inbound: TcpStream, target: &Target) -> anyhow::Result<()> {
+        let host = Host::parse(&target.host)
+            .ok()
+            .filter(|host| !matches!(host, Host::Domain(name) if name.len() > 255))
+            .ok_or_else(|| anyhow!("Invalid CONNECT target {}", target))?;
+
+        let mut outbound =
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, self.connect(&host, target.port))
+                .await
+                .unwrap_or_else(|_| Err(anyhow!("socks5 handshake timed out")))
+            {
+                Ok(outbound) => outbound,
+                Err(err) => {
+                    inbound
+                        .write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")
+                        .await?;
+                    return Err(
+                        err.context(format!("Cannot reach {} through socks5 proxy", target))
+                    );
+                }
+            };
+        inbound
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+        debug!("Tunnel to {} established through socks5 proxy", target);
+        tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await?;
+        Ok(())
+    }
+
+    /// CONNECT of RFC 1928, names being resolved by the proxy so they do not leak, as Tor requires
+    async fn connect(&self, host: &Host, port: u16) -> anyhow::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .with_context(|| format!("Cannot reach socks5 proxy {}:{}", self.host, self.port))?;
+        if let Some(tuning) = &self.tuning {
+            tuning.apply_to_stream(&stream)?;
+        }
+
+        let method = if self.credentials.is_some() {
+            USER_PASS_AUTH
+        } else {
+            NO_AUTH
+        };
+        stream.write_all(&[SOCKS_VERSION, 1, method]).await?;
+        match read_array(&mut stream).await? {
+            [SOCKS_VERSION, chosen] if chosen == method => {}
+            [_, NO_ACCEPTABLE_METHOD] => {
+                return Err(anyhow!("socks5 proxy refused the authentication method"))
+            }
+            [version, _] => return Err(anyhow!("unsupported socks version {}", version)),
+        }
+
+        if let Some((login, password)) = &self.credentials {
+            // Username/password sub-negotiation of RFC 1929
+            let mut auth = vec![0x01, login.len() as u8];
+            auth.extend_from_slice(login.as_bytes());
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(password.as_bytes());
+            stream.write_all(&auth).await?;
+            let [_, status] = read_array(&mut stream).await?;
+            if status != AUTH_SUCCEEDED {
+                return Err(anyhow!("socks5 proxy rejected the credentials"));
+            }
+        }
+
+        let mut request = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+        encode_address(&mut request, host, port);
+        stream.write_all(&request).await?;
+        let [_, status, _, atyp] = read_array(&mut stream).await?;
+        if status != REPLY_SUCCEEDED {
+            return Err(anyhow!(
+                "socks5 proxy failed to connect with status {}",
+                status
+            ));
+        }
+        // Bound address of the proxy, unused
+        let len = match atyp {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => read_array::<1>(&mut stream).await?[0] as usize,
+            _ => return Err(anyhow!("unsupported address type {}", atyp)),
+        };
+        let mut bound = vec![0; len + 2];
+        stream.read_exact(&mut bound).await?;
+        Ok(stream)
+    }
+}
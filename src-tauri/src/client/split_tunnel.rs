@@ -0,0 +1,165 @@
+use anyhow::anyhow;
+use futures_util::{stream, Stream, StreamExt};
+use ipnet::IpNet;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use url::Host;
+use wstunnel::tunnel::{LocalProtocol, RemoteAddr};
+
+/// Destinations of the local socks5 and http proxy tunnels going through the tunnel, the others being connected
+/// directly from this machine. A rule is either a network, i.e: `10.0.0.0/8`, matching the destinations given as
+/// an address, or a domain, i.e: `corp.example.com`, matching itself and its subdomains when given as a name.
+/// Names are not resolved to be matched against the networks, that would leak them to the resolver of the OS.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SplitTunnel {
+    /// Destinations going through the tunnel, every destination when empty
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Destinations connected directly, even when included
+    #[serde(default)]
+    pub exclude: Vec<String>,
+}
+
+/// Destination matched by a rule, a network or a domain with its subdomains
+#[derive(Debug)]
+pub enum Rule {
+    Network(IpNet),
+    Domain(String),
+}
+
+impl Rule {
+    pub fn parse(rule: &str) -> anyhow::Result<Self> {
+        let rule = rule.trim();
+        if let Ok(network) = rule.parse::<IpNet>() {
+            return Ok(Rule::Network(network));
+        }
+        if let Ok(ip) = rule.parse::<IpAddr>() {
+            return Ok(Rule::Network(IpNet::from(ip)));
+        }
+        let domain = rule
+            .trim_start_matches("*.")
+            .trim_start_matches('.')
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        if domain.is_empty() || domain.contains(|c: char| c.is_whitespace() || c == '/') {
+            return Err(anyhow!("Invalid destination rule {:?}", rule));
+        }
+        Ok(Rule::Domain(domain))
+    }
+
+    pub fn matches(&self, host: &Host) -> bool {
+        match (self, host) {
+            (Rule::Network(network), Host::Ipv4(ip)) => network.contains(&IpAddr::V4(*ip)),
+            (Rule::Network(network), Host::Ipv6(ip)) => network.contains(&IpAddr::V6(*ip)),
+            (Rule::Domain(domain), Host::Domain(name)) => {
+                let name = name.trim_end_matches('.').to_ascii_lowercase();
+                name == *domain || name.ends_with(&format!(".{}", domain))
+            }
+            _ => false,
+        }
+    }
+}
+
+pub fn parse_rules(rules: &[String]) -> anyhow::Result<Vec<Rule>> {
+    rules.iter().map(|rule| Rule::parse(rule)).collect()
+}
+
+/// Rules of a profile, parsed once for the tunnels to evaluate them on every request
+#[derive(Debug, Clone)]
+pub struct SplitRules {
+    include: Arc<Vec<Rule>>,
+    exclude: Arc<Vec<Rule>>,
+}
+
+impl SplitTunnel {
+    pub fn rules(&self) -> anyhow::Result<SplitRules> {
+        Ok(SplitRules {
+            include: Arc::new(parse_rules(&self.include)?),
+            exclude: Arc::new(parse_rules(&self.exclude)?),
+        })
+    }
+}
+
+impl SplitRules {
+    /// Whether the traffic to `host` goes through the tunnel
+    pub fn tunnels(&self, host: &Host) -> bool {
+        !self.exclude.iter().any(|rule| rule.matches(host))
+            && (self.include.is_empty() || self.include.iter().any(|rule| rule.matches(host)))
+    }
+}
+
+/// Connect the tcp requests of a proxy tunnel to the destinations the rules leave out directly, only passing the
+/// others on to the tunnel. Udp flows always go through the tunnel. The direct connections are closed with the listener.
+pub fn split_listener<L, R, W>(
+    listener: L,
+    rules: SplitRules,
+) -> impl Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    stream::unfold(
+        (Box::pin(listener), JoinSet::new()),
+        move |(mut listener, mut direct)| {
+            let rules = rules.clone();
+            async move {
+                loop {
+                    while direct.try_join_next().is_some() {}
+                    let item = listener.next().await?;
+                    let ((reader, writer), remote) = match item {
+                        Ok(((reader, writer), remote))
+                            if matches!(remote.protocol, LocalProtocol::Tcp { .. })
+                                && !rules.tunnels(&remote.host) =>
+                        {
+                            ((reader, writer), remote)
+                        }
+                        item => return Some((item, (listener, direct))),
+                    };
+                    debug!("Connecting directly to {}:{}", remote.host, remote.port);
+                    direct.spawn(connect_directly(reader, writer, remote));
+                }
+            }
+        },
+    )
+}
+
+/// Relay a connection accepted by a proxy tunnel to its destination, connected from this machine
+pub async fn connect_directly<R, W>(mut reader: R, mut writer: W, remote: RemoteAddr)
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let host = match &remote.host {
+        Host::Domain(name) => name.clone(),
+        Host::Ipv4(ip) => ip.to_string(),
+        Host::Ipv6(ip) => ip.to_string(),
+    };
+    let stream = match TcpStream::connect((host, remote.port)).await {
+        Ok(stream) => stream,
+        Err(err) => {
+            warn!(
+                "Cannot connect directly to {}:{}: {}",
+                remote.host, remote.port, err
+            );
+            let _ = writer.shutdown().await;
+            return;
+        }
+    };
+    let (mut remote_reader, mut remote_writer) = stream.into_split();
+    let upload = async {
+        let _ = tokio::io::copy(&mut reader, &mut remote_writer).await;
+        let _ = remote_writer.shutdown().await;
+    };
+    let download = async {
+        let _ = tokio::io::copy(&mut remote_reader, &mut writer).await;
+        let _ = writer.shutdown().await;
+    };
+    tokio::join!(upload, download);
+}
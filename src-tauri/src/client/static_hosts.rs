@@ -0,0 +1,40 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use url::Host;
+
+/// Address a name resolves to within a profile, like a line of /etc/hosts, i.e: gitlab.corp -> 10.0.0.5
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticHost {
+    pub name: String,
+    pub ip: IpAddr,
+}
+
+impl StaticHost {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if normalize(&self.name).is_empty() || self.name.contains(char::is_whitespace) {
+            return Err(anyhow!("Invalid static host name {:?}", self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Names are case insensitive and may be written fully qualified, with a trailing dot
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Address given to `host` by the overrides, None when it is not overridden and must be resolved as usual
+pub fn lookup(hosts: &[(String, IpAddr)], host: &Host) -> Option<Host> {
+    let Host::Domain(name) = host else {
+        return None;
+    };
+    let name = normalize(name);
+    hosts
+        .iter()
+        .find(|(host, _)| normalize(host) == name)
+        .map(|(_, ip)| match ip {
+            IpAddr::V4(ip) => Host::Ipv4(*ip),
+            IpAddr::V6(ip) => Host::Ipv6(*ip),
+        })
+}
@@ -0,0 +1,578 @@
+use crate::client::access_log::AccessLog;
+use crate::client::app_rules::AppRouting;
+use crate::client::buffers::BufferTuning;
+use crate::client::capture::CaptureRegistry;
+use crate::client::connections::ConnectionRegistry;
+use crate::client::datagrams::DatagramRegistry;
+use crate::client::events::{self, ClientEvent};
+use crate::client::faults::FaultState;
+use crate::client::host_header::HostRotation;
+use crate::client::multipath::Paths;
+use crate::client::quality;
+use crate::client::reverse_status::ReverseTunnels;
+use crate::client::static_hosts;
+use crate::client::tasks::TaskGroup;
+use crate::client::tls_resumption::HandshakeCounters;
+use crate::client::trace::TraceRegistry;
+use futures_util::{Stream, StreamExt};
+use log::debug;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+use tauri::Url;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, Notify};
+use url::Host;
+use wstunnel::protocols::dns::DnsResolver;
+use wstunnel::tunnel::RemoteAddr;
+
+const RTT_WINDOW_SIZE: usize = 60;
+/// Probing pace of the profiles sending no websocket pings
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Consecutive failed probes after which the server is considered unreachable
+const UNREACHABLE_AFTER: usize = 3;
+
+/// Counters of the traffic going through the tunnels of a profile
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub connections: AtomicU64,
+    pub active_connections: AtomicU64,
+}
+
+/// Upper bounds, in seconds, of the buckets of the handshake latency histograms
+pub const HANDSHAKE_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Distribution of latencies over the fixed `HANDSHAKE_BUCKETS`
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; HANDSHAKE_BUCKETS.len()],
+    sum_us: AtomicU64,
+    count: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        if let Some(i) = HANDSHAKE_BUCKETS.iter().position(|le| secs <= *le) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_us
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Cumulative count of each bucket, along with the sum in seconds and the count of all observations
+    pub fn cumulative(&self) -> (Vec<(f64, u64)>, f64, u64) {
+        let mut total = 0;
+        let buckets = HANDSHAKE_BUCKETS
+            .iter()
+            .zip(&self.buckets)
+            .map(|(le, count)| {
+                total += count.load(Ordering::Relaxed);
+                (*le, total)
+            })
+            .collect();
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+        (buckets, sum, self.count.load(Ordering::Relaxed))
+    }
+}
+
+/// Counters of a single tunnel of a profile
+#[derive(Debug, Default)]
+pub struct TunnelMetrics {
+    pub traffic: TrafficStats,
+    /// Number of times the tunnel failed and had to be re-established
+    pub reconnects: AtomicU64,
+    /// Socks requests refused for a destination the application resolved itself, when the profile forces remote dns
+    pub local_dns_refused: AtomicU64,
+    /// Time between accepting a connection and receiving its first byte from the server, the upgrade included
+    pub handshake: LatencyHistogram,
+    /// Udp datagrams larger than the maximum size of the tunnel
+    pub oversized_datagrams: AtomicU64,
+}
+
+/// Description of the link to the server, used to interpret the measurements
+#[derive(Debug, Clone)]
+pub struct LinkInfo {
+    pub remote_addr: Url,
+    /// Where the connections to the server go out to: the server, at the address it is pinned to, or the
+    /// proxy it is reached through
+    pub first_hops: Vec<Url>,
+    pub websocket_mask_frame: bool,
+    pub connection_min_idle: u32,
+    /// Connections whose upgrade request took longer are dropped
+    pub upgrade_timeout: Option<Duration>,
+    /// Websocket pings keep the connections alive at that pace, the link is probed at the same one
+    pub ping_frequency: Option<Duration>,
+}
+
+/// Sliding window of round trip time measurements to the server
+#[derive(Debug, Default)]
+pub struct RttWindow {
+    samples: VecDeque<Option<Duration>>,
+    pub dns_lookup: Option<Duration>,
+}
+
+impl RttWindow {
+    pub fn push(&mut self, sample: Option<Duration>) {
+        if self.samples.len() == RTT_WINDOW_SIZE {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Window of the last `count` samples only
+    pub fn recent(&self, count: usize) -> RttWindow {
+        RttWindow {
+            samples: self
+                .samples
+                .iter()
+                .rev()
+                .take(count)
+                .rev()
+                .copied()
+                .collect(),
+            dns_lookup: self.dns_lookup,
+        }
+    }
+
+    /// Number of probes in the window
+    pub fn probes(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Round trip times in milliseconds from the oldest sample to the newest, none for a failed probe
+    pub fn samples_ms(&self) -> Vec<Option<f64>> {
+        self.samples
+            .iter()
+            .map(|sample| sample.map(|d| d.as_secs_f64() * 1000.0))
+            .collect()
+    }
+
+    fn successes(&self) -> impl Iterator<Item = f64> + '_ {
+        self.samples
+            .iter()
+            .flatten()
+            .map(|d| d.as_secs_f64() * 1000.0)
+    }
+
+    pub fn mean_ms(&self) -> Option<f64> {
+        let (count, sum) = self.successes().fold((0, 0.0), |(c, s), v| (c + 1, s + v));
+        (count > 0).then(|| sum / count as f64)
+    }
+
+    /// Mean of the absolute difference between consecutive samples (RFC 3550 style jitter)
+    pub fn jitter_ms(&self) -> Option<f64> {
+        let values: Vec<f64> = self.successes().collect();
+        if values.len() < 2 {
+            return None;
+        }
+        let sum: f64 = values.windows(2).map(|w| (w[1] - w[0]).abs()).sum();
+        Some(sum / (values.len() - 1) as f64)
+    }
+
+    /// Ratio of probes that failed or timed out
+    pub fn loss(&self) -> f64 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+        let failed = self.samples.iter().filter(|s| s.is_none()).count();
+        failed as f64 / self.samples.len() as f64
+    }
+}
+
+/// Everything measured for a connected profile
+#[derive(Debug)]
+pub struct ProfileStats {
+    pub started_at: Instant,
+    pub link: LinkInfo,
+    pub traffic: TrafficStats,
+    /// Number of times a tunnel failed and had to be re-established
+    pub tunnel_errors: AtomicU64,
+    pub rtt: Mutex<RttWindow>,
+    pub traces: TraceRegistry,
+    /// Packet captures of the tunnels, for debugging
+    pub captures: CaptureRegistry,
+    /// Datagram settings of the udp tunnels, their idle timeout can be changed on the fly
+    pub datagrams: DatagramRegistry,
+    pub events: broadcast::Sender<ClientEvent>,
+    pub faults: FaultState,
+    /// Notified when the server rejected the upgrade token, so it is fetched again right away. Shared with the
+    /// refresher of the token, which must not keep the stats of a disconnected profile alive
+    pub credentials_stale: Arc<Notify>,
+    /// Headers file holding the Host header of the next connection, when the profile rotates it
+    pub host_rotation: OnceLock<HostRotation>,
+    /// Interfaces the server is reached through, when the profile uses several of them
+    pub multipath: OnceLock<Arc<Paths>>,
+    /// Loopback proxy wstunnel reaches the server through, and its connections
+    pub bridges: TaskGroup,
+    /// TLS handshakes of the connections to the server
+    pub handshakes: OnceLock<Arc<HandshakeCounters>>,
+    /// How the server, or the proxy it is reached through, is reached. Known before the tunnels are brought up
+    pub first_hop: OnceLock<Arc<HopRoute>>,
+    /// What the server side of the reverse tunnels is known to be
+    pub reverse_tunnels: ReverseTunnels,
+    /// Live connections of the tunnels
+    pub connections: Arc<ConnectionRegistry>,
+    /// Connections closed on the tunnels logging their accesses
+    pub access_log: Arc<AccessLog>,
+    /// Routing of the local proxy tunnels by application, changed on the fly when the user edits the rules
+    pub app_routing: Mutex<Option<AppRouting>>,
+    /// Set while the connections to the server are closed for being idle, the server is not probed meanwhile
+    pub suspended: AtomicBool,
+    /// Per tunnel counters, indexed by tunnel id
+    tunnels: Mutex<HashMap<String, Arc<TunnelMetrics>>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrafficSnapshot {
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub connections: u64,
+    pub active_connections: u64,
+    pub uptime_sec: u64,
+}
+
+impl ProfileStats {
+    pub fn new(link: LinkInfo) -> Arc<Self> {
+        Arc::new(Self {
+            started_at: Instant::now(),
+            link,
+            traffic: TrafficStats::default(),
+            tunnel_errors: AtomicU64::new(0),
+            rtt: Mutex::new(RttWindow::default()),
+            traces: TraceRegistry::default(),
+            captures: CaptureRegistry::default(),
+            datagrams: DatagramRegistry::default(),
+            events: events::channel(),
+            faults: FaultState::default(),
+            credentials_stale: Arc::new(Notify::new()),
+            host_rotation: OnceLock::new(),
+            multipath: OnceLock::new(),
+            bridges: TaskGroup::default(),
+            handshakes: OnceLock::new(),
+            first_hop: OnceLock::new(),
+            reverse_tunnels: ReverseTunnels::default(),
+            connections: Arc::default(),
+            access_log: Arc::default(),
+            app_routing: Mutex::default(),
+            suspended: AtomicBool::new(false),
+            tunnels: Mutex::default(),
+        })
+    }
+
+    pub fn traffic_snapshot(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            bytes_up: self.traffic.bytes_up.load(Ordering::Relaxed),
+            bytes_down: self.traffic.bytes_down.load(Ordering::Relaxed),
+            connections: self.traffic.connections.load(Ordering::Relaxed),
+            active_connections: self.traffic.active_connections.load(Ordering::Relaxed),
+            uptime_sec: self.started_at.elapsed().as_secs(),
+        }
+    }
+
+    pub fn record_tunnel_error(&self, tunnel_id: &str) {
+        self.tunnel_errors.fetch_add(1, Ordering::Relaxed);
+        self.tunnel_metrics(tunnel_id)
+            .reconnects
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tunnel_metrics(&self, tunnel_id: &str) -> Arc<TunnelMetrics> {
+        self.tunnels
+            .lock()
+            .entry(tunnel_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Counters of every tunnel that has run in the profile, sorted by tunnel id
+    pub fn all_tunnel_metrics(&self) -> Vec<(String, Arc<TunnelMetrics>)> {
+        let mut tunnels: Vec<_> = self
+            .tunnels
+            .lock()
+            .iter()
+            .map(|(id, metrics)| (id.clone(), metrics.clone()))
+            .collect();
+        tunnels.sort_by(|a, b| a.0.cmp(&b.0));
+        tunnels
+    }
+
+    /// Nobody listening to the events is not an error
+    pub fn publish(&self, event: ClientEvent) {
+        let _ = self.events.send(event);
+    }
+}
+
+/// How the connections of the profile reach their first hop: the resolver, the static hosts and the socket
+/// options of the profile. The link prober and the tun tunnels go the same way, so they deal with the addresses
+/// and the link the tunnels use
+pub struct HopRoute {
+    pub resolver: DnsResolver,
+    pub static_hosts: Vec<(String, IpAddr)>,
+    pub so_mark: Option<u32>,
+    pub tuning: BufferTuning,
+}
+
+impl std::fmt::Debug for HopRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HopRoute")
+            .field("static_hosts", &self.static_hosts)
+            .field("so_mark", &self.so_mark)
+            .field("tuning", &self.tuning)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HopRoute {
+    /// Addresses of a host, as overridden by the static hosts or resolved by the resolver of the profile
+    pub async fn lookup(&self, host: &Host, port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+        match static_hosts::lookup(&self.static_hosts, host).unwrap_or_else(|| host.clone()) {
+            Host::Ipv4(ip) => Ok(vec![SocketAddr::new(ip.into(), port)]),
+            Host::Ipv6(ip) => Ok(vec![SocketAddr::new(ip.into(), port)]),
+            Host::Domain(name) => Ok(self.resolver.lookup_host(&name, port).await?),
+        }
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> anyhow::Result<TcpStream> {
+        self.tuning
+            .connect_with(addr, |socket| {
+                #[cfg(target_os = "linux")]
+                if let Some(mark) = self.so_mark {
+                    socket2::SockRef::from(socket).set_mark(mark)?;
+                }
+                #[cfg(not(target_os = "linux"))]
+                let _ = (socket, self.so_mark);
+                Ok(())
+            })
+            .await
+    }
+}
+
+/// Periodically measure the tcp handshake time to the first hop, and the time it takes to resolve its name.
+/// The first hop is the server, or the proxy it is reached through, reached the way the tunnels reach it.
+/// wstunnel answers the websocket pongs by itself without telling how long they took, so the server is probed
+/// at the pace of the pings instead. Each measurement is published, and so are the changes of the link quality.
+/// Stops when the stats are not referenced anymore by anyone else, i.e: the profile has been disconnected.
+pub fn spawn_link_prober(stats: Arc<ProfileStats>) {
+    let period = probe_interval(&stats.link);
+    let stats = Arc::downgrade(&stats);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        let mut consecutive_failures = 0;
+        let mut degradation = None;
+        loop {
+            interval.tick().await;
+            let Some(stats) = stats.upgrade() else {
+                return;
+            };
+            if stats.suspended.load(Ordering::Relaxed) {
+                continue;
+            }
+
+            let (Some(first_hop), Some(route)) =
+                (stats.link.first_hops.first(), stats.first_hop.get())
+            else {
+                return;
+            };
+            let Some(host) = first_hop.host().map(|host| host.to_owned()) else {
+                return;
+            };
+            let port = first_hop.port_or_known_default().unwrap_or(443);
+
+            let lookup_start = Instant::now();
+            let addrs = match tokio::time::timeout(PROBE_TIMEOUT, route.lookup(&host, port)).await {
+                Ok(Ok(addrs)) => addrs,
+                res => {
+                    debug!(
+                        "Link probe cannot resolve {}: {:?}",
+                        host,
+                        res.map(|r| r.err())
+                    );
+                    vec![]
+                }
+            };
+            stats.rtt.lock().dns_lookup = Some(lookup_start.elapsed());
+            let addr = addrs.first().filter(|_| !stats.faults.dns_failing());
+
+            let sample = match addr {
+                Some(&addr) => {
+                    let start = Instant::now();
+                    match tokio::time::timeout(PROBE_TIMEOUT, route.connect(addr)).await {
+                        Ok(Ok(_)) => Some(start.elapsed()),
+                        res => {
+                            debug!("Link probe to {} failed: {:?}", addr, res.map(|r| r.err()));
+                            None
+                        }
+                    }
+                }
+                None => None,
+            };
+            stats.rtt.lock().push(sample);
+            stats.publish(ClientEvent::LinkMeasured);
+            let degraded = quality::degradation(&stats.rtt.lock());
+            if degraded != degradation {
+                stats.publish(match degraded {
+                    Some(degradation) => ClientEvent::LinkDegraded { degradation },
+                    None => ClientEvent::LinkRecovered,
+                });
+                degradation = degraded;
+            }
+
+            if sample.is_some() {
+                if consecutive_failures >= UNREACHABLE_AFTER {
+                    stats.publish(ClientEvent::ServerReachable);
+                }
+                consecutive_failures = 0;
+            } else {
+                consecutive_failures += 1;
+                if consecutive_failures == UNREACHABLE_AFTER {
+                    stats.publish(ClientEvent::ServerUnreachable);
+                }
+            }
+        }
+    });
+}
+
+pub fn probe_interval(link: &LinkInfo) -> Duration {
+    link.ping_frequency.unwrap_or(PROBE_INTERVAL)
+}
+
+/// Count the bytes going through a stream of a tunnel
+pub struct Metered<S> {
+    inner: S,
+    counter: Arc<ProfileStats>,
+    tunnel: Arc<TunnelMetrics>,
+    upload: bool,
+    /// When the connection was accepted, until the first byte from the server is written to the local client
+    accepted_at: Option<Instant>,
+}
+
+impl<S> Metered<S> {
+    fn add(&mut self, amount: usize) {
+        let (counter, tunnel_counter) = if self.upload {
+            (
+                &self.counter.traffic.bytes_up,
+                &self.tunnel.traffic.bytes_up,
+            )
+        } else {
+            (
+                &self.counter.traffic.bytes_down,
+                &self.tunnel.traffic.bytes_down,
+            )
+        };
+        counter.fetch_add(amount as u64, Ordering::Relaxed);
+        tunnel_counter.fetch_add(amount as u64, Ordering::Relaxed);
+
+        if amount > 0 {
+            if let Some(accepted_at) = self.accepted_at.take() {
+                self.tunnel.handshake.observe(accepted_at.elapsed());
+            }
+        }
+    }
+}
+
+impl<S> Drop for Metered<S> {
+    fn drop(&mut self) {
+        // The reader is the one owning the lifetime of the connection accounting
+        if self.upload {
+            self.counter
+                .traffic
+                .active_connections
+                .fetch_sub(1, Ordering::Relaxed);
+            self.tunnel
+                .traffic
+                .active_connections
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.add(buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.add(written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Account the traffic of every connection accepted by a tunnel listener in the profile and tunnel stats
+pub fn meter_listener<L, R, W>(
+    listener: L,
+    tunnel_id: &str,
+    stats: Arc<ProfileStats>,
+) -> impl Stream<Item = anyhow::Result<((Metered<R>, Metered<W>), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let tunnel = stats.tunnel_metrics(tunnel_id);
+    listener.map(move |item| {
+        item.map(|((reader, writer), remote)| {
+            for traffic in [&stats.traffic, &tunnel.traffic] {
+                traffic.connections.fetch_add(1, Ordering::Relaxed);
+                traffic.active_connections.fetch_add(1, Ordering::Relaxed);
+            }
+            (
+                (
+                    Metered {
+                        inner: reader,
+                        counter: stats.clone(),
+                        tunnel: tunnel.clone(),
+                        upload: true,
+                        accepted_at: None,
+                    },
+                    Metered {
+                        inner: writer,
+                        counter: stats.clone(),
+                        tunnel: tunnel.clone(),
+                        upload: false,
+                        accepted_at: Some(Instant::now()),
+                    },
+                ),
+                remote,
+            )
+        })
+    })
+}
@@ -0,0 +1,44 @@
+use parking_lot::Mutex;
+use std::future::Future;
+use tokio::task::JoinHandle;
+
+/// Tasks spawned on behalf of a connected profile, so they can all be stopped on disconnect
+#[derive(Debug, Default)]
+pub struct TaskGroup {
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl TaskGroup {
+    pub fn spawn<F>(&self, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let handle = tokio::spawn(future);
+        let mut handles = self.handles.lock();
+        handles.retain(|h| !h.is_finished());
+        handles.push(handle);
+    }
+
+    pub fn abort_all(&self) {
+        for handle in self.handles.lock().drain(..) {
+            handle.abort();
+        }
+    }
+
+    /// Abort the tasks and wait for them to be dropped, along with the sockets they hold
+    pub async fn stop(&self) {
+        let handles: Vec<_> = self.handles.lock().drain(..).collect();
+        for handle in &handles {
+            handle.abort();
+        }
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+}
+
+impl Drop for TaskGroup {
+    fn drop(&mut self) {
+        self.abort_all();
+    }
+}
@@ -0,0 +1,90 @@
+use crate::client::client_api::{BoundListener, ConnectedClient, WsClientApi};
+use crate::client::concurrency::ConnectionOverflow;
+use crate::client::engine::PreparedTunnel;
+use crate::client::platform;
+use crate::client::profile::{Profile, TunnelConfig};
+use anyhow::Context;
+use log::info;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_TEMP_TUNNEL: AtomicU64 = AtomicU64::new(0);
+
+/// Tunnel attached to a connected profile for the current session only, for one-off forwards.
+/// It is never saved in the profile, so it goes away with the connection it is attached to.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TempTunnel {
+    pub id: String,
+    pub spec: String,
+    pub reverse: bool,
+    pub listener: Option<BoundListener>,
+}
+
+/// Bind a temporary tunnel of a connected profile, to `attach` to the connection it was prepared for
+pub async fn prepare(
+    profile: &Profile,
+    connected: &ConnectedClient,
+    spec: &str,
+    reverse: bool,
+) -> anyhow::Result<(PreparedTunnel, TempTunnel)> {
+    let config = TunnelConfig {
+        spec: spec.to_string(),
+        id: Some(format!(
+            "temp-{}",
+            NEXT_TEMP_TUNNEL.fetch_add(1, Ordering::Relaxed)
+        )),
+        reverse,
+        group: None,
+        rate_limit_up: None,
+        rate_limit_down: None,
+        bind_interface: None,
+        allowed_sources: vec![],
+        dual_stack: false,
+        buffers: None,
+        set_system_proxy: false,
+        pac_domains: vec![],
+        lazy: false,
+        proxy_header: None,
+        port_mapping: false,
+        datagrams: None,
+        access_log: false,
+        listener_auth: None,
+        max_concurrent_connections: None,
+        connection_overflow: ConnectionOverflow::default(),
+        enabled: true,
+    };
+    let tunnel = profile.tunnel(&config)?;
+    platform::check_tunnel(&tunnel).with_context(|| format!("Unsupported tunnel {}", spec))?;
+
+    let id = tunnel.id.clone();
+    let (prepared, listener) =
+        WsClientApi::prepare_tunnel(tunnel, reverse, connected.stats.clone()).await?;
+    let temp = TempTunnel {
+        id,
+        spec: spec.to_string(),
+        reverse,
+        listener,
+    };
+    Ok((prepared, temp))
+}
+
+/// Start a prepared temporary tunnel alongside the tunnels of the profile, without touching them
+pub fn attach(connected: &mut ConnectedClient, prepared: PreparedTunnel, temp: TempTunnel) {
+    info!("Starting temporary tunnel {} ({})", temp.id, temp.spec);
+    connected.engine.add_tunnel(prepared);
+    connected.listeners.extend(temp.listener.clone());
+    connected.temp_tunnels.push(temp);
+}
+
+/// Stop a temporary tunnel before the profile is disconnected, `false` when the profile has no such tunnel
+pub fn remove(connected: &mut ConnectedClient, tunnel_id: &str) -> bool {
+    let Some(temp) = connected.temp_tunnels.iter().find(|t| t.id == tunnel_id) else {
+        return false;
+    };
+    info!("Stopping temporary tunnel {} ({})", temp.id, temp.spec);
+    connected.engine.remove_tunnel(&temp.id, temp.reverse);
+    connected.temp_tunnels.retain(|t| t.id != tunnel_id);
+    connected.listeners.retain(|l| l.tunnel_id != tunnel_id);
+    true
+}
@@ -0,0 +1,119 @@
+use serde::{Deserialize, Serialize};
+use tokio_rustls::rustls::crypto::ring::{cipher_suite, kx_group};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider, SupportedKxGroup};
+use tokio_rustls::rustls::SupportedCipherSuite;
+
+/// Cipher suites and key exchange groups offered in the ClientHello sent to the server.
+/// Only their choice and order change: the extensions, their order and the lack of GREASE stay the ones of rustls,
+/// so the fingerprint of the handshake (JA3, JA4) remains the one of rustls and does not pass for a browser
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TlsFingerprint {
+    /// As wstunnel sends it
+    #[default]
+    Rustls,
+    /// The suites and groups of Chrome, in its order
+    Chrome,
+    /// The suites and groups of Firefox, in its order
+    Firefox,
+}
+
+/// Suites and groups offered in the ClientHello of the handshakes with the server, the only part of it rustls
+/// lets choose. Adding one only requires implementing this trait and registering it in `SHAPES`.
+pub trait HelloShape: Send + Sync {
+    fn fingerprint(&self) -> TlsFingerprint;
+
+    /// Cipher suites, in the order they are offered
+    fn cipher_suites(&self) -> Vec<SupportedCipherSuite>;
+
+    /// Key exchange groups, the first one being sent a key share
+    fn kx_groups(&self) -> Vec<&'static dyn SupportedKxGroup>;
+
+    /// Provider doing the handshakes with the suites and groups of the shape
+    fn provider(&self) -> CryptoProvider {
+        CryptoProvider {
+            cipher_suites: self.cipher_suites(),
+            kx_groups: self.kx_groups(),
+            ..ring::default_provider()
+        }
+    }
+}
+
+pub struct RustlsShape;
+
+impl HelloShape for RustlsShape {
+    fn fingerprint(&self) -> TlsFingerprint {
+        TlsFingerprint::Rustls
+    }
+
+    fn cipher_suites(&self) -> Vec<SupportedCipherSuite> {
+        ring::default_provider().cipher_suites
+    }
+
+    fn kx_groups(&self) -> Vec<&'static dyn SupportedKxGroup> {
+        ring::default_provider().kx_groups
+    }
+}
+
+pub struct ChromeShape;
+
+impl HelloShape for ChromeShape {
+    fn fingerprint(&self) -> TlsFingerprint {
+        TlsFingerprint::Chrome
+    }
+
+    fn cipher_suites(&self) -> Vec<SupportedCipherSuite> {
+        vec![
+            cipher_suite::TLS13_AES_128_GCM_SHA256,
+            cipher_suite::TLS13_AES_256_GCM_SHA384,
+            cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+            cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            cipher_suite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        ]
+    }
+
+    fn kx_groups(&self) -> Vec<&'static dyn SupportedKxGroup> {
+        vec![kx_group::X25519, kx_group::SECP256R1, kx_group::SECP384R1]
+    }
+}
+
+pub struct FirefoxShape;
+
+impl HelloShape for FirefoxShape {
+    fn fingerprint(&self) -> TlsFingerprint {
+        TlsFingerprint::Firefox
+    }
+
+    fn cipher_suites(&self) -> Vec<SupportedCipherSuite> {
+        vec![
+            cipher_suite::TLS13_AES_128_GCM_SHA256,
+            cipher_suite::TLS13_CHACHA20_POLY1305_SHA256,
+            cipher_suite::TLS13_AES_256_GCM_SHA384,
+            cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            cipher_suite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            cipher_suite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+            cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+            cipher_suite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+        ]
+    }
+
+    fn kx_groups(&self) -> Vec<&'static dyn SupportedKxGroup> {
+        vec![kx_group::X25519, kx_group::SECP256R1, kx_group::SECP384R1]
+    }
+}
+
+/// Every shape the app has been compiled with
+static SHAPES: [&dyn HelloShape; 3] = [&RustlsShape, &ChromeShape, &FirefoxShape];
+
+pub fn shape(fingerprint: TlsFingerprint) -> &'static dyn HelloShape {
+    SHAPES
+        .iter()
+        .copied()
+        .find(|shape| shape.fingerprint() == fingerprint)
+        .unwrap_or(&RustlsShape)
+}
@@ -0,0 +1,177 @@
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::{ClientSessionMemoryCache, Resumption};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, KeyLog, SignatureScheme};
+
+/// Sessions remembered per server, each ticket being good for a single resumption
+const SESSIONS_PER_SERVER: usize = 64;
+
+/// TLS handshakes made with a shared config, by every profile using it
+#[derive(Debug, Default)]
+struct SharedCounters {
+    /// Handshakes in which the server sent its certificate
+    full: AtomicU64,
+    /// Handshakes which derived the keys of the connection, full or resumed
+    completed: AtomicU64,
+}
+
+impl SharedCounters {
+    fn counts(&self) -> HandshakeCounts {
+        let full = self.full.load(Ordering::Relaxed);
+        HandshakeCounts {
+            full,
+            resumed: self.completed.load(Ordering::Relaxed).saturating_sub(full),
+        }
+    }
+}
+
+/// TLS handshakes made by the connections of a profile to its server, since it connected. The handshakes of the
+/// other profiles sharing the same config in the meantime are counted as well
+#[derive(Debug)]
+pub struct HandshakeCounters {
+    shared: Arc<SharedCounters>,
+    baseline: HandshakeCounts,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HandshakeCounts {
+    pub full: u64,
+    /// Handshakes resuming the session of a previous connection, without the server sending its certificate
+    pub resumed: u64,
+}
+
+impl HandshakeCounters {
+    pub fn snapshot(&self) -> HandshakeCounts {
+        let now = self.shared.counts();
+        HandshakeCounts {
+            full: now.full.saturating_sub(self.baseline.full),
+            resumed: now.resumed.saturating_sub(self.baseline.resumed),
+        }
+    }
+}
+
+/// Configs of the connections to the servers, by session key and protocols offered, kept for as long as the app
+/// runs so a profile connecting again after a network blip skips the full handshake
+fn shared_configs() -> &'static Mutex<HashMap<(String, Vec<Vec<u8>>), Arc<ClientConfig>>> {
+    static CONFIGS: OnceLock<Mutex<HashMap<(String, Vec<Vec<u8>>), Arc<ClientConfig>>>> =
+        OnceLock::new();
+    CONFIGS.get_or_init(Mutex::default)
+}
+
+/// Handshakes by session key, whatever the transport
+fn shared_counters(key: &str) -> Arc<SharedCounters> {
+    static COUNTERS: OnceLock<Mutex<HashMap<String, Arc<SharedCounters>>>> = OnceLock::new();
+    COUNTERS
+        .get_or_init(Mutex::default)
+        .lock()
+        .entry(key.to_string())
+        .or_default()
+        .clone()
+}
+
+/// Config of the connections to a server, built by `build` the first time `key` is seen with these protocols then
+/// reused along with its sessions. `key` must tell apart everything the config depends on, the way the server is
+/// verified and the client certificate included, so a session is never resumed by a profile that would not have
+/// accepted the server. `build` gives the config and the verifier of the server, which the handshakes are counted from
+pub fn shared_config(
+    key: &str,
+    protocols: &[Vec<u8>],
+    build: impl FnOnce() -> anyhow::Result<(ClientConfig, Arc<dyn ServerCertVerifier>)>,
+) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut configs = shared_configs().lock();
+    let entry = (key.to_string(), protocols.to_vec());
+    if let Some(config) = configs.get(&entry) {
+        return Ok(config.clone());
+    }
+    let (mut config, verifier) = build()?;
+    let counters = shared_counters(key);
+    config.resumption =
+        Resumption::store(Arc::new(ClientSessionMemoryCache::new(SESSIONS_PER_SERVER)));
+    config
+        .dangerous()
+        .set_certificate_verifier(Arc::new(CountingVerifier {
+            inner: verifier,
+            counters: counters.clone(),
+        }));
+    config.key_log = Arc::new(CompletedHandshakes(counters));
+    let config = Arc::new(config);
+    configs.insert(entry, config.clone());
+    Ok(config)
+}
+
+/// Handshakes of the configs shared under `key` from now on
+pub fn counters(key: &str) -> HandshakeCounters {
+    let shared = shared_counters(key);
+    let baseline = shared.counts();
+    HandshakeCounters { shared, baseline }
+}
+
+/// Verifier of a shared config, counting the full handshakes: a resumed one never gets a certificate to verify
+#[derive(Debug)]
+struct CountingVerifier {
+    inner: Arc<dyn ServerCertVerifier>,
+    counters: Arc<SharedCounters>,
+}
+
+impl ServerCertVerifier for CountingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        self.counters.full.fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Counts the handshakes once the keys of the connection are derived, which rustls tells through the key log.
+/// The secrets themselves are dropped
+#[derive(Debug)]
+struct CompletedHandshakes(Arc<SharedCounters>);
+
+impl KeyLog for CompletedHandshakes {
+    fn log(&self, label: &str, _client_random: &[u8], _secret: &[u8]) {
+        // Logged once per handshake, TLS 1.3 and TLS 1.2 respectively
+        if label == "CLIENT_TRAFFIC_SECRET_0" || label == "CLIENT_RANDOM" {
+            self.0.completed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn will_log(&self, label: &str) -> bool {
+        label == "CLIENT_TRAFFIC_SECRET_0" || label == "CLIENT_RANDOM"
+    }
+}
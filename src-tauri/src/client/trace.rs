@@ -0,0 +1,336 @@
+use crate::client::stats::ProfileStats;
+use futures_util::{Stream, StreamExt};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use wstunnel::tunnel::RemoteAddr;
+
+const MAX_TRACES: usize = 16;
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// The local listener accepted the connection
+    Accepted {
+        destination: String,
+    },
+    /// Resolution of the server name, as done when a new websocket connection must be opened
+    DnsResolved {
+        host: String,
+        addresses: Vec<String>,
+        duration_ms: f64,
+    },
+    DnsFailed {
+        host: String,
+        error: String,
+    },
+    /// First byte sent by the local client
+    FirstByteUp,
+    /// First byte received from the remote, meaning the upgrade succeeded
+    FirstByteDown,
+    /// The local client is done sending, the connection stays open for the response
+    LocalHalfClosed,
+    /// Bytes relayed since the previous sample
+    Throughput {
+        bytes_up: u64,
+        bytes_down: u64,
+    },
+    Closed {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TraceEntry {
+    pub elapsed_ms: f64,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+/// Timeline of a single relayed connection
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionTrace {
+    pub trace_id: String,
+    pub tunnel_id: String,
+    /// Unix timestamp in milliseconds of the moment the connection was accepted, none while waiting for it
+    pub started_at_ms: Option<u128>,
+    pub completed: bool,
+    pub events: Vec<TraceEntry>,
+}
+
+/// Record the events of one connection in its trace
+struct TraceRecorder {
+    trace: Mutex<ConnectionTrace>,
+    start: Instant,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    closed: AtomicBool,
+}
+
+impl TraceRecorder {
+    fn push(&self, event: TraceEvent) {
+        let mut trace = self.trace.lock();
+        if trace.completed {
+            return;
+        }
+        if matches!(event, TraceEvent::Closed { .. }) {
+            trace.completed = true;
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        trace.events.push(TraceEntry {
+            elapsed_ms: self.start.elapsed().as_secs_f64() * 1000.0,
+            event,
+        });
+    }
+}
+
+/// Traces requested on the tunnels of a profile.
+/// A trace is armed on a tunnel and captures the next connection accepted by its listener.
+#[derive(Default)]
+pub struct TraceRegistry {
+    next_id: AtomicU64,
+    /// Trace id waiting for a connection, by tunnel id
+    armed: Mutex<HashMap<String, String>>,
+    traces: Mutex<VecDeque<Arc<TraceRecorder>>>,
+}
+
+impl std::fmt::Debug for TraceRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TraceRegistry")
+            .field("traces", &self.traces.lock().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TraceRegistry {
+    /// Trace the next connection of the tunnel, returning the id of the trace
+    pub fn arm(&self, tunnel_id: &str) -> String {
+        let trace_id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        self.armed
+            .lock()
+            .insert(tunnel_id.to_string(), trace_id.clone());
+        trace_id
+    }
+
+    pub fn get(&self, trace_id: &str) -> Option<ConnectionTrace> {
+        if let Some(tunnel_id) = self
+            .armed
+            .lock()
+            .iter()
+            .find_map(|(tunnel, id)| (id == trace_id).then(|| tunnel.clone()))
+        {
+            return Some(ConnectionTrace {
+                trace_id: trace_id.to_string(),
+                tunnel_id,
+                started_at_ms: None,
+                completed: false,
+                events: vec![],
+            });
+        }
+
+        self.traces
+            .lock()
+            .iter()
+            .map(|recorder| recorder.trace.lock().clone())
+            .find(|trace| trace.trace_id == trace_id)
+    }
+
+    fn start(&self, tunnel_id: &str) -> Option<Arc<TraceRecorder>> {
+        let trace_id = self.armed.lock().remove(tunnel_id)?;
+        let started_at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .ok();
+        let recorder = Arc::new(TraceRecorder {
+            trace: Mutex::new(ConnectionTrace {
+                trace_id,
+                tunnel_id: tunnel_id.to_string(),
+                started_at_ms,
+                completed: false,
+                events: vec![],
+            }),
+            start: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+        });
+
+        let mut traces = self.traces.lock();
+        if traces.len() == MAX_TRACES {
+            traces.pop_front();
+        }
+        traces.push_back(recorder.clone());
+        Some(recorder)
+    }
+}
+
+/// Stream of a traced connection, only recording anything when the connection is traced
+pub struct Traced<S> {
+    inner: S,
+    recorder: Option<Arc<TraceRecorder>>,
+    upload: bool,
+    first_byte: bool,
+}
+
+impl<S> Traced<S> {
+    fn new(inner: S, recorder: Option<Arc<TraceRecorder>>, upload: bool) -> Self {
+        Self {
+            inner,
+            recorder,
+            upload,
+            first_byte: true,
+        }
+    }
+
+    fn record(&mut self, result: Result<usize, &io::Error>) {
+        let Some(recorder) = &self.recorder else {
+            return;
+        };
+        let (direction, counter, first_byte) = if self.upload {
+            ("reading from", &recorder.bytes_up, TraceEvent::FirstByteUp)
+        } else {
+            (
+                "writing to",
+                &recorder.bytes_down,
+                TraceEvent::FirstByteDown,
+            )
+        };
+        match result {
+            Ok(0) if self.upload => recorder.push(TraceEvent::LocalHalfClosed),
+            Ok(0) => {}
+            Ok(amount) => {
+                if self.first_byte {
+                    self.first_byte = false;
+                    recorder.push(first_byte);
+                }
+                counter.fetch_add(amount as u64, Ordering::Relaxed);
+            }
+            Err(err) => recorder.push(TraceEvent::Closed {
+                reason: format!("Error {} the local client: {}", direction, err),
+            }),
+        }
+    }
+}
+
+impl<S> Drop for Traced<S> {
+    fn drop(&mut self) {
+        if let Some(recorder) = &self.recorder {
+            recorder.push(TraceEvent::Closed {
+                reason: "Closed by the tunnel".to_string(),
+            });
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Traced<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = ready!(Pin::new(&mut this.inner).poll_read(cx, buf));
+        this.record(result.as_ref().map(|_| buf.filled().len() - before));
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Traced<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let result = ready!(Pin::new(&mut this.inner).poll_write(cx, buf));
+        this.record(result.as_ref().copied());
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Trace the connections accepted by a tunnel listener when a trace has been armed on the tunnel
+pub fn trace_listener<L, R, W>(
+    listener: L,
+    tunnel_id: String,
+    stats: Arc<ProfileStats>,
+) -> impl Stream<Item = anyhow::Result<((Traced<R>, Traced<W>), RemoteAddr)>>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>>,
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    listener.map(move |item| {
+        item.map(|((reader, writer), remote)| {
+            let recorder = stats.traces.start(&tunnel_id);
+            if let Some(recorder) = &recorder {
+                recorder.push(TraceEvent::Accepted {
+                    destination: format!("{}:{}", remote.host, remote.port),
+                });
+                spawn_trace_probes(recorder.clone(), &stats);
+            }
+            (
+                (
+                    Traced::new(reader, recorder.clone(), true),
+                    Traced::new(writer, recorder, false),
+                ),
+                remote,
+            )
+        })
+    })
+}
+
+/// Resolve the server name and sample the throughput of the connection until it closes
+fn spawn_trace_probes(recorder: Arc<TraceRecorder>, stats: &ProfileStats) {
+    let remote_addr = stats.link.remote_addr.clone();
+    tokio::spawn(async move {
+        if let Some(host) = remote_addr.host_str() {
+            let host = host.trim_matches(['[', ']']).to_string();
+            let port = remote_addr.port_or_known_default().unwrap_or(443);
+            let start = Instant::now();
+            let event = match tokio::net::lookup_host((host.as_str(), port)).await {
+                Ok(addrs) => TraceEvent::DnsResolved {
+                    host,
+                    addresses: addrs.map(|a| a.ip().to_string()).collect(),
+                    duration_ms: start.elapsed().as_secs_f64() * 1000.0,
+                },
+                Err(err) => TraceEvent::DnsFailed {
+                    host,
+                    error: err.to_string(),
+                },
+            };
+            recorder.push(event);
+        }
+
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if recorder.closed.load(Ordering::Relaxed) {
+                return;
+            }
+            recorder.push(TraceEvent::Throughput {
+                bytes_up: recorder.bytes_up.swap(0, Ordering::Relaxed),
+                bytes_down: recorder.bytes_down.swap(0, Ordering::Relaxed),
+            });
+        }
+    });
+}
@@ -0,0 +1,436 @@
+use crate::client::client_key::ClientIdentity;
+use crate::client::fallback;
+use crate::client::server_select::ProbeSettings;
+use crate::client::server_trust;
+use crate::client::tls_fingerprint::{self, TlsFingerprint};
+use crate::client::tls_resumption;
+use anyhow::{anyhow, Context};
+use futures_util::future::BoxFuture;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tauri::Url;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{
+    verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+};
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, DnsName, PrivateKeyDer, ServerName, UnixTime,
+};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{
+    version, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+    SupportedProtocolVersion,
+};
+use tokio_rustls::TlsConnector;
+use wstunnel::protocols::tls;
+use wstunnel::tunnel::client::TlsClientConfig;
+use wstunnel::tunnel::transport::{TransportAddr, TransportScheme};
+
+/// Protocols able to carry the tunnels to the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportKind {
+    Websocket,
+    Http2,
+}
+
+/// Version of TLS the connection to the server may be restricted to, written `1.2` or `1.3`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// Tls settings of a profile, shared by every transport
+pub struct TlsSettings {
+    pub verify_certificate: bool,
+    /// SHA-256 fingerprints of the only certificates accepted when they are not verified, any when empty
+    pub pinned_certificates: Vec<String>,
+    pub sni_disable: bool,
+    pub sni_override: Option<DnsName<'static>>,
+    pub certificate: Option<Vec<CertificateDer<'static>>>,
+    pub key: Option<PrivateKeyDer<'static>>,
+    /// Client certificate whose key stays in a token or an OS keystore, used instead of `key`
+    pub identity: Option<Arc<CertifiedKey>>,
+    pub certificate_path: Option<PathBuf>,
+    pub key_path: Option<PathBuf>,
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    /// Protocols offered in the handshake instead of the ones of the transport, i.e: `h2` and `http/1.1`
+    pub alpn: Option<Vec<String>>,
+    pub fingerprint: TlsFingerprint,
+    /// Key of the config shared by the profiles connecting the same way to the same server, keeping its sessions
+    /// across reconnects. Each connector has a config of its own when not set
+    pub session_key: Option<String>,
+}
+
+impl TlsSettings {
+    /// Versions allowed by the profile, None when it lets rustls pick among all the ones it supports
+    fn versions(&self) -> Option<Vec<&'static SupportedProtocolVersion>> {
+        if self.min_version.is_none() && self.max_version.is_none() {
+            return None;
+        }
+        let allowed = self.min_version.unwrap_or(TlsVersion::Tls12)
+            ..=self.max_version.unwrap_or(TlsVersion::Tls13);
+        let versions = [
+            (TlsVersion::Tls12, &version::TLS12),
+            (TlsVersion::Tls13, &version::TLS13),
+        ];
+        Some(
+            versions
+                .into_iter()
+                .filter(|(version, _)| allowed.contains(version))
+                .map(|(_, version)| version)
+                .collect(),
+        )
+    }
+
+    /// rustls fixes the versions and the crypto provider once a config is built, which is built again to change them
+    fn rebuilds_config(&self) -> bool {
+        self.versions().is_some() || self.fingerprint != TlsFingerprint::Rustls
+    }
+
+    /// wstunnel builds the connector again when the certificate file changes, without what the profile customized
+    fn customized(&self) -> bool {
+        self.identity.is_some()
+            || self.alpn.is_some()
+            || self.rebuilds_config()
+            || self.pins_certificates()
+    }
+
+    fn pins_certificates(&self) -> bool {
+        !self.verify_certificate && !self.pinned_certificates.is_empty()
+    }
+}
+
+/// A way to reach the server, selected from the scheme of the server url.
+/// Adding a transport only requires implementing this trait and registering it in `TRANSPORTS`.
+pub trait Transport: Send + Sync {
+    fn kind(&self) -> TransportKind;
+
+    /// Schemes of the server url handled by this transport
+    fn schemes(&self) -> &'static [&'static str];
+
+    /// Called before connecting when the profile opted in for transport fallback.
+    /// Returns the server url to use, whose scheme may belong to another transport.
+    fn fallback<'a>(
+        &'a self,
+        remote_addr: &'a Url,
+        _settings: ProbeSettings<'a>,
+    ) -> BoxFuture<'a, Url> {
+        Box::pin(async move { remote_addr.clone() })
+    }
+
+    /// Address of the server as expected by the wstunnel client
+    fn transport_addr(
+        &self,
+        remote_addr: &Url,
+        tls: &TlsSettings,
+    ) -> anyhow::Result<TransportAddr> {
+        wstunnel_transport_addr(remote_addr, tls)
+    }
+}
+
+pub struct WebsocketTransport;
+
+impl Transport for WebsocketTransport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Websocket
+    }
+
+    fn schemes(&self) -> &'static [&'static str] {
+        &["ws", "wss"]
+    }
+}
+
+pub struct Http2Transport;
+
+impl Transport for Http2Transport {
+    fn kind(&self) -> TransportKind {
+        TransportKind::Http2
+    }
+
+    fn schemes(&self) -> &'static [&'static str] {
+        &["http", "https"]
+    }
+
+    fn fallback<'a>(
+        &'a self,
+        remote_addr: &'a Url,
+        settings: ProbeSettings<'a>,
+    ) -> BoxFuture<'a, Url> {
+        Box::pin(fallback::select_transport(remote_addr, settings))
+    }
+}
+
+/// Every transport the app has been compiled with
+static TRANSPORTS: [&dyn Transport; 2] = [&WebsocketTransport, &Http2Transport];
+
+pub fn available_transports() -> Vec<TransportKind> {
+    TRANSPORTS.iter().map(|t| t.kind()).collect()
+}
+
+/// Schemes users may expect for HTTP/3, which wstunnel has neither a client nor a server for.
+/// A QUIC transport needs one in wstunnel first, so these are rejected with the reason instead of as unknown
+const QUIC_SCHEMES: [&str; 3] = ["wss+quic", "h3", "quic"];
+
+/// Transport handling the scheme of the server url
+pub fn for_url(remote_addr: &Url) -> anyhow::Result<&'static dyn Transport> {
+    if QUIC_SCHEMES.contains(&remote_addr.scheme()) {
+        return Err(anyhow!(
+            "{} is a QUIC url, which wstunnel cannot reach: use wss:// or https:// instead",
+            remote_addr
+        ));
+    }
+    TRANSPORTS
+        .iter()
+        .copied()
+        .find(|t| t.schemes().contains(&remote_addr.scheme()))
+        .ok_or_else(|| {
+            anyhow!(
+                "No transport for scheme {} of {}",
+                remote_addr.scheme(),
+                remote_addr
+            )
+        })
+}
+
+/// Transports natively supported by wstunnel only differ by their scheme
+fn wstunnel_transport_addr(remote_addr: &Url, tls: &TlsSettings) -> anyhow::Result<TransportAddr> {
+    let scheme = TransportScheme::from_str(remote_addr.scheme())
+        .map_err(|_| anyhow!("Invalid scheme in server url {}", remote_addr))?;
+    let tls = match scheme {
+        TransportScheme::Ws | TransportScheme::Http => None,
+        TransportScheme::Wss | TransportScheme::Https => Some(TlsClientConfig {
+            tls_connector: Arc::new(RwLock::new(tls_connector(tls, &scheme)?)),
+            tls_sni_override: tls.sni_override.clone(),
+            tls_verify_certificate: tls.verify_certificate,
+            tls_sni_disabled: tls.sni_disable,
+            // wstunnel reloads the key from its file when it changes, with a connector of its own which knows
+            // neither a key in a keystore nor the versions and protocols the profile restricts the handshake to
+            tls_certificate_path: tls.certificate_path.clone().filter(|_| !tls.customized()),
+            tls_key_path: tls.key_path.clone(),
+        }),
+    };
+
+    let host = remote_addr
+        .host()
+        .ok_or_else(|| anyhow!("Server address {} has no host", remote_addr))?
+        .to_owned();
+    let port = remote_addr
+        .port_or_known_default()
+        .ok_or_else(|| anyhow!("Server address {} has no port", remote_addr))?;
+    TransportAddr::new(scheme, host, port, tls)
+        .ok_or_else(|| anyhow!("Invalid server address {}", remote_addr))
+}
+
+/// Connector doing the handshake of a websocket connection to the server, for probes measuring it
+pub fn websocket_tls_connector(tls: &TlsSettings) -> anyhow::Result<TlsConnector> {
+    tls_connector(tls, &TransportScheme::Wss)
+}
+
+/// Connector doing the handshake of an http2 connection to the server, for the probe of the transport fallback
+pub fn http2_tls_connector(tls: &TlsSettings) -> anyhow::Result<TlsConnector> {
+    tls_connector(tls, &TransportScheme::Https)
+}
+
+/// Connector verifying the server as wstunnel does, with the client certificate of the profile
+fn tls_connector(tls: &TlsSettings, scheme: &TransportScheme) -> anyhow::Result<TlsConnector> {
+    let connector = tls::tls_connector(
+        tls.verify_certificate,
+        scheme.alpn_protocols(),
+        !tls.sni_disable,
+        tls.certificate.clone().filter(|_| tls.identity.is_none()),
+        tls.key.as_ref().map(|key| key.clone_key()),
+    )
+    .with_context(|| "Cannot create tls connector")?;
+    if !tls.customized() && tls.session_key.is_none() {
+        return Ok(connector);
+    }
+    let build = || -> anyhow::Result<(ClientConfig, Arc<dyn ServerCertVerifier>)> {
+        let mut config = if tls.rebuilds_config() {
+            rebuilt_config(connector.config(), tls)?
+        } else {
+            ClientConfig::clone(connector.config())
+        };
+        if let Some(alpn) = &tls.alpn {
+            config.alpn_protocols = alpn.iter().map(|p| p.as_bytes().to_vec()).collect();
+        }
+        if let Some(identity) = &tls.identity {
+            // The key cannot be given to wstunnel, the handshake asks the keystore to sign instead
+            config.client_auth_cert_resolver = Arc::new(ClientIdentity(identity.clone()));
+        }
+        let verifier = server_verifier(tls, config.crypto_provider())?;
+        Ok((config, verifier))
+    };
+    let config = match &tls.session_key {
+        // The protocols offered differ between transports, which do not share their sessions
+        Some(key) => tls_resumption::shared_config(key, &scheme.alpn_protocols(), build)?,
+        None => {
+            let (mut config, verifier) = build()?;
+            config.dangerous().set_certificate_verifier(verifier);
+            Arc::new(config)
+        }
+    };
+    Ok(TlsConnector::from(config))
+}
+
+/// Verifier of the server certificate as the profile asks: against the roots of the OS, against the pinned
+/// fingerprints, or not at all
+fn server_verifier(
+    tls: &TlsSettings,
+    provider: &Arc<CryptoProvider>,
+) -> anyhow::Result<Arc<dyn ServerCertVerifier>> {
+    if tls.pins_certificates() {
+        // Checked on the handshake of every connection of the tunnels, not once before connecting
+        return Ok(Arc::new(PinnedCertificate {
+            fingerprints: tls.pinned_certificates.clone(),
+            signatures: AcceptAnyCertificate(provider.clone()),
+        }));
+    }
+    if !tls.verify_certificate {
+        return Ok(Arc::new(AcceptAnyCertificate(provider.clone())));
+    }
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .with_context(|| "Cannot verify the server with the roots of the OS")?;
+    Ok(verifier)
+}
+
+/// Config of wstunnel built again for the allowed versions only, with the ClientHello shaped as the profile asks.
+/// The server certificate is verified as wstunnel does, against the roots of the OS unless verification is disabled
+fn rebuilt_config(base: &ClientConfig, tls: &TlsSettings) -> anyhow::Result<ClientConfig> {
+    let provider = Arc::new(tls_fingerprint::shape(tls.fingerprint).provider());
+    let versions = tls
+        .versions()
+        .unwrap_or_else(|| vec![&version::TLS13, &version::TLS12]);
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_protocol_versions(&versions)
+        .with_context(|| "Invalid TLS versions")?;
+    let mut config = if tls.verify_certificate {
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+        builder.with_root_certificates(roots).with_no_client_auth()
+    } else {
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+            .with_no_client_auth()
+    };
+    config.alpn_protocols = base.alpn_protocols.clone();
+    config.enable_sni = base.enable_sni;
+    config.client_auth_cert_resolver = base.client_auth_cert_resolver.clone();
+    Ok(config)
+}
+
+/// Verifier of a profile not verifying the server certificate, the handshake signatures are still checked
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Verifier of a profile not verifying the server certificate, accepting only the certificates the user trusted
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprints: Vec<String>,
+    signatures: AcceptAnyCertificate,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        let fingerprint = server_trust::fingerprint(end_entity.as_ref());
+        if self
+            .fingerprints
+            .iter()
+            .any(|pinned| pinned.eq_ignore_ascii_case(&fingerprint))
+        {
+            return Ok(ServerCertVerified::assertion());
+        }
+        Err(tokio_rustls::rustls::Error::General(format!(
+            "certificate {} of the server is not the one trusted",
+            fingerprint
+        )))
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.signatures.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.signatures.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.signatures.supported_verify_schemes()
+    }
+}
@@ -0,0 +1,675 @@
+use crate::client::stats::HopRoute;
+use crate::client::tasks::TaskGroup;
+use crate::system_proxy::{cmd, run, CommandLine};
+use anyhow::{anyhow, Context};
+use futures_util::{stream, Stream};
+use ipnet::Ipv4Net;
+use ipstack::{IpStack, IpStackConfig, IpStackStream};
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tun2::AbstractDevice;
+use url::{Host, Url};
+use wstunnel::tunnel::{LocalProtocol, RemoteAddr};
+
+const MTU: u16 = 1500;
+/// Flows accepted on the device and waiting for their tunnel, newer ones are held back past that
+const FLOW_QUEUE: usize = 256;
+/// Half of the ipv4 then of the ipv6 space each, together more specific than the default routes which are left
+/// untouched. Ipv6 is captured too, or it would leave the machine outside of the tunnel
+const CAPTURED_NETWORKS: [&str; 4] = ["0.0.0.0/1", "128.0.0.0/1", "::/1", "8000::/1"];
+/// Pace at which the routes pinning the server are checked against the gateway of the machine
+const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Pace at which a server reached by name is resolved again
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// TUN interface of a tun tunnel, i.e: `tun://10.66.0.1/24`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunDevice {
+    /// Address of the interface and network it is on
+    pub network: Ipv4Net,
+    /// Udp flows without traffic for that long are closed, never when not set
+    pub udp_timeout: Option<Duration>,
+}
+
+pub type TunReader = Pin<Box<dyn AsyncRead + Send>>;
+pub type TunWriter = Pin<Box<dyn AsyncWrite + Send>>;
+type TunItem = ((TunReader, TunWriter), RemoteAddr);
+
+/// Full device VPN: create a TUN interface, route the ipv4 and ipv6 traffic of the machine to it and turn
+/// each tcp connection and udp flow read from it into a tunnel to its destination. The server, or the proxy it
+/// is reached through, stays reached through the former gateway, so the tunnels do not go through themselves.
+/// Its addresses are the ones the tunnels connect to, found along `route`.
+/// The routes are removed and the interface closed once the tunnel stops. Creating the interface and changing
+/// the routes requires administrator privileges.
+pub async fn tun_listener(
+    device: TunDevice,
+    first_hops: &[Url],
+    route: Arc<HopRoute>,
+    tasks: &TaskGroup,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<TunItem>>> {
+    let TunDevice {
+        network,
+        udp_timeout: timeout,
+    } = device;
+    let mut config = tun2::Configuration::default();
+    config
+        .address(network.addr())
+        .netmask(network.netmask())
+        .mtu(MTU)
+        .up();
+    let device = tun2::create_as_async(&config)
+        .with_context(|| "Cannot create the TUN interface, it requires administrator privileges")?;
+    let name = device.tun_name()?;
+    info!("TUN interface {} created on {}", name, network);
+
+    let mut names = vec![];
+    let mut server_ips: Vec<IpAddr> = vec![];
+    for hop in first_hops {
+        match hop.host() {
+            Some(Host::Ipv4(ip)) => server_ips.push(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => server_ips.push(IpAddr::V6(ip)),
+            Some(Host::Domain(name)) => {
+                names.push((name.to_string(), hop.port_or_known_default().unwrap_or(443)))
+            }
+            None => return Err(anyhow!("Server url {} has no host", hop)),
+        }
+    }
+    server_ips.extend(resolve(&route, &names).await?);
+    let routes = tokio::task::spawn_blocking(move || Routes::install(&name, &server_ips)).await??;
+    let routes = Arc::new(Mutex::new(routes));
+    tasks.spawn(watch_routes(Arc::downgrade(&routes), route, names));
+
+    let mut stack_config = IpStackConfig::default();
+    stack_config.mtu(MTU);
+    if let Some(timeout) = timeout {
+        stack_config.udp_timeout(timeout);
+    }
+    let mut ip_stack = IpStack::new(stack_config, device);
+    let (tx, rx) = mpsc::channel(FLOW_QUEUE);
+    tasks.spawn(async move {
+        // Owned by the task, so the routes are removed when the tunnel stops
+        let _routes = routes;
+        loop {
+            let flow = match ip_stack.accept().await {
+                Ok(flow) => flow,
+                Err(err) => {
+                    warn!("TUN interface stopped: {:?}", err);
+                    return;
+                }
+            };
+            let (streams, destination, protocol) = match flow {
+                IpStackStream::Tcp(tcp) => {
+                    let destination = tcp.peer_addr();
+                    let protocol = LocalProtocol::Tcp {
+                        proxy_protocol: false,
+                    };
+                    (split(tcp), destination, protocol)
+                }
+                IpStackStream::Udp(udp) => {
+                    let destination = udp.peer_addr();
+                    (split(udp), destination, LocalProtocol::Udp { timeout })
+                }
+                IpStackStream::UnknownTransport(_) | IpStackStream::UnknownNetwork(_) => continue,
+            };
+            debug!("TUN flow to {}", destination);
+            let remote = RemoteAddr {
+                protocol,
+                host: match destination {
+                    SocketAddr::V4(addr) => Host::Ipv4(*addr.ip()),
+                    SocketAddr::V6(addr) => Host::Ipv6(*addr.ip()),
+                },
+                port: destination.port(),
+            };
+            if tx.send((streams, remote)).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    Ok(stream::unfold(rx, |mut rx| async move {
+        let item = rx.recv().await?;
+        Some((Ok(item), rx))
+    }))
+}
+
+fn split<S>(stream: S) -> (TunReader, TunWriter)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    (Box::pin(reader), Box::pin(writer))
+}
+
+/// Addresses of the servers reached by name
+async fn resolve(route: &HopRoute, names: &[(String, u16)]) -> anyhow::Result<Vec<IpAddr>> {
+    let mut ips = vec![];
+    for (name, port) in names {
+        ips.extend(
+            route
+                .lookup(&Host::Domain(name.clone()), *port)
+                .await
+                .with_context(|| format!("Cannot resolve server {}", name))?
+                .into_iter()
+                .map(|addr| addr.ip()),
+        );
+    }
+    Ok(ips)
+}
+
+/// Keep the server off the TUN interface for as long as its routes are installed. When the network changes
+/// under the tunnel, i.e: DHCP handing out another gateway or the interface of the pinned route going down,
+/// the server would be reached through the tunnel itself, so it is pinned again to the current gateway.
+/// A server reached by name is resolved again from time to time, the addresses it moves to being pinned as well.
+async fn watch_routes(
+    routes: Weak<Mutex<Routes>>,
+    route: Arc<HopRoute>,
+    names: Vec<(String, u16)>,
+) {
+    let mut resolved_at = Instant::now();
+    let mut server_ips = vec![];
+    loop {
+        tokio::time::sleep(ROUTE_CHECK_INTERVAL).await;
+        if !names.is_empty() && resolved_at.elapsed() >= RESOLVE_INTERVAL {
+            resolved_at = Instant::now();
+            match resolve(&route, &names).await {
+                Ok(ips) => server_ips = ips,
+                Err(err) => warn!("Cannot resolve the server again: {:?}", err),
+            }
+        }
+        let Some(routes) = routes.upgrade() else {
+            return;
+        };
+        let server_ips = server_ips.clone();
+        match tokio::task::spawn_blocking(move || routes.lock().repin(&server_ips)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => warn!("Cannot check the route to the server: {:?}", err),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Route sending the traffic to an address of the server through the gateway of the machine
+struct PinnedRoute {
+    ip: IpAddr,
+    gateway: Gateway,
+}
+
+/// Routes sending the traffic to the TUN interface, removed in reverse order when dropped
+struct Routes {
+    /// The TUN interface, as the gateways of the platform tell it: its name, or its index on Windows
+    device: String,
+    pinned: Vec<PinnedRoute>,
+    /// Default gateways of the machine when the server was last pinned
+    default_gateways: DefaultGateways,
+    undo: Vec<CommandLine>,
+}
+
+impl Routes {
+    fn install(name: &str, server_ips: &[IpAddr]) -> anyhow::Result<Self> {
+        let mut ips: Vec<IpAddr> = server_ips.to_vec();
+        ips.sort();
+        ips.dedup();
+        let table = platform::route_table(&ips)?;
+        let mut routes = Routes {
+            device: platform::interface_id(name)?,
+            pinned: vec![],
+            default_gateways: table.default_gateways,
+            undo: vec![],
+        };
+        for (ip, gateway) in ips.into_iter().zip(table.gateways) {
+            match gateway {
+                Some(gateway) => routes.pin(ip, gateway)?,
+                // Unreachable from here anyway, wstunnel tries the other addresses
+                None if ip.is_ipv6() => warn!("No route to server {}, it is not pinned", ip),
+                None => return Err(anyhow!("No route to {}", ip)),
+            }
+        }
+        for network in CAPTURED_NETWORKS {
+            let (add, delete) = platform::device_route(network, name);
+            routes.add(add, delete)?;
+        }
+        Ok(routes)
+    }
+
+    fn add(&mut self, add: CommandLine, delete: CommandLine) -> anyhow::Result<()> {
+        run(&add)?;
+        self.undo.push(delete);
+        Ok(())
+    }
+
+    fn pin(&mut self, ip: IpAddr, gateway: Gateway) -> anyhow::Result<()> {
+        let (add, delete) = platform::host_route(ip, &gateway);
+        self.add(add, delete)?;
+        self.pinned.push(PinnedRoute { ip, gateway });
+        Ok(())
+    }
+
+    /// Pin the server again to the default gateway of its family when it changed, or when its route was removed
+    /// along with the interface it went through, leaving the server to the TUN interface. A server pinned to
+    /// another gateway than the default one, i.e: on the local network, keeps its route while it is there.
+    /// The addresses the server was resolved to since it was last pinned are pinned as well
+    fn repin(&mut self, server_ips: &[IpAddr]) -> anyhow::Result<()> {
+        let mut ips: Vec<IpAddr> = self.pinned.iter().map(|pinned| pinned.ip).collect();
+        let pinned_count = ips.len();
+        for ip in server_ips {
+            if !ips.contains(ip) {
+                ips.push(*ip);
+            }
+        }
+        // Read at once, the routes being checked every few seconds
+        let table = platform::route_table(&ips)?;
+        // Offline for a family, its addresses are pinned again once the machine has a network
+        let defaults = DefaultGateways {
+            v4: table
+                .default_gateways
+                .v4
+                .filter(|gateway| !self.through_device(gateway)),
+            v6: table
+                .default_gateways
+                .v6
+                .filter(|gateway| !self.through_device(gateway)),
+        };
+        let device = self.device.as_str();
+        for (pinned, current) in self.pinned.iter_mut().zip(&table.gateways) {
+            let Some(default_gateway) = defaults.of(pinned.ip) else {
+                continue;
+            };
+            let previous_default = self.default_gateways.of(pinned.ip);
+            let moved = previous_default != Some(default_gateway);
+            let looped = match current {
+                Some(current) => current.device.as_deref() == Some(device),
+                None => true,
+            };
+            let followed_default = previous_default == Some(&pinned.gateway);
+            if !looped && !(moved && followed_default) {
+                continue;
+            }
+            let (add, delete) = platform::host_route(pinned.ip, default_gateway);
+            // The route may be gone with its interface, the delete command stays the one to undo it
+            let _ = run(&delete);
+            run(&add)?;
+            info!(
+                "Route to server {} pinned again, from {:?} to {:?}",
+                pinned.ip, pinned.gateway, default_gateway
+            );
+            pinned.gateway = default_gateway.clone();
+        }
+        let resolved = ips[pinned_count..]
+            .iter()
+            .zip(&table.gateways[pinned_count..]);
+        for (ip, current) in resolved {
+            let gateway = match (current, defaults.of(*ip)) {
+                (Some(current), _) if !self.through_device(current) => current.clone(),
+                (_, Some(default_gateway)) => default_gateway.clone(),
+                (_, None) => continue,
+            };
+            info!("Server moved to {}, pinned to {:?}", ip, gateway);
+            self.pin(*ip, gateway)?;
+        }
+        if defaults.v4.is_some() {
+            self.default_gateways.v4 = defaults.v4;
+        }
+        if defaults.v6.is_some() {
+            self.default_gateways.v6 = defaults.v6;
+        }
+        Ok(())
+    }
+
+    fn through_device(&self, gateway: &Gateway) -> bool {
+        gateway.device.as_deref() == Some(self.device.as_str())
+    }
+}
+
+impl Drop for Routes {
+    fn drop(&mut self) {
+        for delete in self.undo.drain(..).rev() {
+            if let Err(err) = run(&delete) {
+                warn!("Cannot remove route: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Next hop currently used to reach an address, through which it stays reachable once the traffic is captured
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Gateway {
+    via: Option<String>,
+    /// Interface of the route, its name or its index on Windows
+    device: Option<String>,
+}
+
+/// Default gateway of each family, None when the machine has no default route for it
+#[derive(Debug, Clone, Default)]
+struct DefaultGateways {
+    v4: Option<Gateway>,
+    v6: Option<Gateway>,
+}
+
+impl DefaultGateways {
+    fn of(&self, ip: IpAddr) -> Option<&Gateway> {
+        match ip {
+            IpAddr::V4(_) => self.v4.as_ref(),
+            IpAddr::V6(_) => self.v6.as_ref(),
+        }
+    }
+}
+
+/// Routes of the machine, read at once
+struct RouteTable {
+    default_gateways: DefaultGateways,
+    /// Route to each of the addresses asked, in the same order
+    gateways: Vec<Option<Gateway>>,
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::{cmd, run, CommandLine, DefaultGateways, Gateway, RouteTable};
+    use anyhow::anyhow;
+    use std::net::IpAddr;
+
+    pub fn interface_id(name: &str) -> anyhow::Result<String> {
+        Ok(name.to_string())
+    }
+
+    pub fn route_table(ips: &[IpAddr]) -> anyhow::Result<RouteTable> {
+        Ok(RouteTable {
+            default_gateways: DefaultGateways {
+                v4: default_gateway("-4").ok(),
+                v6: default_gateway("-6").ok(),
+            },
+            gateways: ips.iter().map(|ip| gateway(*ip).ok()).collect(),
+        })
+    }
+
+    fn gateway(ip: IpAddr) -> anyhow::Result<Gateway> {
+        // i.e: 1.2.3.4 via 192.168.1.1 dev wlan0 src 192.168.1.20 uid 1000, the family being the one of the address
+        let output = run(&cmd(&["ip", "route", "get", &ip.to_string()]))?;
+        parse_route(&output, &ip.to_string())
+    }
+
+    fn default_gateway(family: &str) -> anyhow::Result<Gateway> {
+        // i.e: default via 192.168.1.1 dev wlan0 proto dhcp metric 600, the preferred one first
+        let output = run(&cmd(&["ip", family, "route", "show", "default"]))?;
+        parse_route(output.lines().next().unwrap_or_default(), "default")
+    }
+
+    fn parse_route(output: &str, target: &str) -> anyhow::Result<Gateway> {
+        let words: Vec<&str> = output.split_whitespace().collect();
+        let after = |key: &str| {
+            words
+                .iter()
+                .position(|word| *word == key)
+                .and_then(|i| words.get(i + 1))
+                .map(|word| word.to_string())
+        };
+        let gateway = Gateway {
+            via: after("via"),
+            device: after("dev"),
+        };
+        if gateway.via.is_none() && gateway.device.is_none() {
+            return Err(anyhow!("No route to {}", target));
+        }
+        Ok(gateway)
+    }
+
+    pub fn host_route(ip: IpAddr, gateway: &Gateway) -> (CommandLine, CommandLine) {
+        let host = match ip {
+            IpAddr::V4(ip) => format!("{}/32", ip),
+            IpAddr::V6(ip) => format!("{}/128", ip),
+        };
+        let mut add = cmd(&["ip", "route", "add", &host]);
+        if let Some(via) = &gateway.via {
+            add.extend(cmd(&["via", via]));
+        }
+        if let Some(device) = &gateway.device {
+            add.extend(cmd(&["dev", device]));
+        }
+        (add, cmd(&["ip", "route", "del", &host]))
+    }
+
+    pub fn device_route(network: &str, device: &str) -> (CommandLine, CommandLine) {
+        (
+            cmd(&["ip", "route", "add", network, "dev", device]),
+            cmd(&["ip", "route", "del", network, "dev", device]),
+        )
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::{cmd, run, CommandLine, DefaultGateways, Gateway, RouteTable};
+    use anyhow::anyhow;
+    use std::net::IpAddr;
+
+    pub fn interface_id(name: &str) -> anyhow::Result<String> {
+        Ok(name.to_string())
+    }
+
+    pub fn route_table(ips: &[IpAddr]) -> anyhow::Result<RouteTable> {
+        Ok(RouteTable {
+            default_gateways: DefaultGateways {
+                v4: route_get("-inet", "default").ok(),
+                v6: route_get("-inet6", "default").ok(),
+            },
+            gateways: ips
+                .iter()
+                .map(|ip| route_get(family(*ip), &ip.to_string()).ok())
+                .collect(),
+        })
+    }
+
+    fn family(ip: IpAddr) -> &'static str {
+        if ip.is_ipv4() {
+            "-inet"
+        } else {
+            "-inet6"
+        }
+    }
+
+    fn route_get(family: &str, target: &str) -> anyhow::Result<Gateway> {
+        let output = run(&cmd(&["route", "-n", "get", family, target]))?;
+        let field = |key: &str| {
+            output.lines().find_map(|line| {
+                let (name, value) = line.trim().split_once(':')?;
+                (name == key).then(|| value.trim().to_string())
+            })
+        };
+        let gateway = Gateway {
+            via: field("gateway"),
+            device: field("interface"),
+        };
+        if gateway.via.is_none() && gateway.device.is_none() {
+            return Err(anyhow!("No route to {}", target));
+        }
+        Ok(gateway)
+    }
+
+    pub fn host_route(ip: IpAddr, gateway: &Gateway) -> (CommandLine, CommandLine) {
+        let family = family(ip);
+        let ip = ip.to_string();
+        let add = match (&gateway.via, &gateway.device) {
+            (Some(via), _) => cmd(&["route", "-n", "add", family, "-host", &ip, via]),
+            (None, Some(device)) => cmd(&[
+                "route",
+                "-n",
+                "add",
+                family,
+                "-host",
+                &ip,
+                "-interface",
+                device,
+            ]),
+            (None, None) => cmd(&["route", "-n", "add", family, "-host", &ip]),
+        };
+        (add, cmd(&["route", "-n", "delete", family, "-host", &ip]))
+    }
+
+    pub fn device_route(network: &str, device: &str) -> (CommandLine, CommandLine) {
+        let family = if network.contains(':') {
+            "-inet6"
+        } else {
+            "-inet"
+        };
+        (
+            cmd(&[
+                "route",
+                "-n",
+                "add",
+                family,
+                "-net",
+                network,
+                "-interface",
+                device,
+            ]),
+            cmd(&[
+                "route",
+                "-n",
+                "delete",
+                family,
+                "-net",
+                network,
+                "-interface",
+                device,
+            ]),
+        )
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::{cmd, run, CommandLine, DefaultGateways, Gateway, RouteTable};
+    use anyhow::anyhow;
+    use std::net::IpAddr;
+
+    /// Index of the interface, which the routes refer to whatever the language of the system
+    pub fn interface_id(name: &str) -> anyhow::Result<String> {
+        netdev::get_interfaces()
+            .into_iter()
+            .find(|interface| {
+                interface.name == name || interface.friendly_name.as_deref() == Some(name)
+            })
+            .map(|interface| interface.index.to_string())
+            .ok_or_else(|| anyhow!("No interface {}", name))
+    }
+
+    /// Read the default route and the routes to the addresses with a single powershell, which is slow to start.
+    /// Find-NetRoute gives the source address then the route used to reach the destination
+    pub fn route_table(ips: &[IpAddr]) -> anyhow::Result<RouteTable> {
+        let targets = ips
+            .iter()
+            .map(|ip| format!("'{}'", ip))
+            .collect::<Vec<_>>()
+            .join(",");
+        let script = format!(
+            "$ErrorActionPreference = 'SilentlyContinue'; \
+             $r = Get-NetRoute -DestinationPrefix 0.0.0.0/0 | Sort-Object RouteMetric | Select-Object -First 1; \
+             \"default $($r.NextHop) $($r.InterfaceIndex)\"; \
+             $r = Get-NetRoute -DestinationPrefix ::/0 | Sort-Object RouteMetric | Select-Object -First 1; \
+             \"default6 $($r.NextHop) $($r.InterfaceIndex)\"; \
+             foreach ($ip in @({})) {{ \
+               $r = Find-NetRoute -RemoteIPAddress $ip | Select-Object -Last 1; \
+               \"$ip $($r.NextHop) $($r.InterfaceIndex)\" \
+             }}",
+            targets
+        );
+        let output = run(&cmd(&["powershell", "-NoProfile", "-Command", &script]))?;
+        // i.e: `default 192.168.1.1 12`, `default6 fe80::1 12` then `1.2.3.4 192.168.1.1 12`, without next hop nor
+        // index when no route
+        let routes: Vec<(&str, Option<Gateway>)> = output
+            .lines()
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                let target = words.next()?;
+                let gateway = match (words.next(), words.next()) {
+                    (Some(via), Some(index)) => Some(Gateway {
+                        via: Some(via.to_string()),
+                        device: Some(index.to_string()),
+                    }),
+                    _ => None,
+                };
+                Some((target, gateway))
+            })
+            .collect();
+        let gateway_of = |target: &str| {
+            routes
+                .iter()
+                .find(|(line_target, _)| *line_target == target)
+                .and_then(|(_, gateway)| gateway.clone())
+        };
+        Ok(RouteTable {
+            default_gateways: DefaultGateways {
+                v4: gateway_of("default"),
+                v6: gateway_of("default6"),
+            },
+            gateways: ips.iter().map(|ip| gateway_of(&ip.to_string())).collect(),
+        })
+    }
+
+    pub fn host_route(ip: IpAddr, gateway: &Gateway) -> (CommandLine, CommandLine) {
+        if ip.is_ipv6() {
+            return netsh_route(
+                &format!("{}/128", ip),
+                gateway.device.as_deref(),
+                gateway.via.as_deref(),
+            );
+        }
+        let ip = ip.to_string();
+        let via = gateway.via.as_deref().unwrap_or("0.0.0.0");
+        let mut add = cmd(&["route", "add", &ip, "mask", "255.255.255.255", via]);
+        if let Some(index) = &gateway.device {
+            add.extend(cmd(&["if", index]));
+        }
+        (add, cmd(&["route", "delete", &ip]))
+    }
+
+    pub fn device_route(network: &str, device: &str) -> (CommandLine, CommandLine) {
+        netsh_route(network, Some(device), None)
+    }
+
+    /// Route of the family of `prefix` through an interface, by its index, and a next hop when it has one
+    fn netsh_route(
+        prefix: &str,
+        device: Option<&str>,
+        via: Option<&str>,
+    ) -> (CommandLine, CommandLine) {
+        let family = if prefix.contains(':') { "ipv6" } else { "ipv4" };
+        let mut target = vec![prefix.to_string()];
+        target.extend(device.map(|device| format!("interface={}", device)));
+        let mut add = cmd(&["netsh", "interface", family, "add", "route"]);
+        add.extend(target.iter().cloned());
+        add.extend(via.map(|via| format!("nexthop={}", via)));
+        add.push("store=active".to_string());
+        let mut delete = cmd(&["netsh", "interface", family, "delete", "route"]);
+        delete.extend(target);
+        (add, delete)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use super::{CommandLine, Gateway, RouteTable};
+    use crate::client::platform::Capability;
+    use std::net::IpAddr;
+
+    pub fn interface_id(_name: &str) -> anyhow::Result<String> {
+        Err(Capability::TunDevice.unavailable())
+    }
+
+    pub fn route_table(_ips: &[IpAddr]) -> anyhow::Result<RouteTable> {
+        Err(Capability::TunDevice.unavailable())
+    }
+
+    pub fn host_route(_ip: IpAddr, _gateway: &Gateway) -> (CommandLine, CommandLine) {
+        (vec![], vec![])
+    }
+
+    pub fn device_route(_network: &str, _device: &str) -> (CommandLine, CommandLine) {
+        (vec![], vec![])
+    }
+}
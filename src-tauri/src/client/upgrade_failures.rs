@@ -0,0 +1,124 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What to do when the server rejects the upgrade request of a tunnel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum UpgradeFailureAction {
+    /// Stop the tunnel, as retrying cannot succeed (i.e: a revoked account)
+    Stop,
+    /// Restart the tunnel with the usual backoff
+    Retry,
+    /// Reconnect with the next prefix of the list, wrapping around, i.e: for a server moving its upgrade path
+    RotatePathPrefix { prefixes: Vec<String> },
+    /// Fetch a new token from the credentials provider right away, then restart the tunnel
+    RefreshCredentials,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpgradeFailureRule {
+    /// Http status of the rejected upgrade, i.e: 401, 403, 404 or 502
+    pub status: u16,
+    pub action: UpgradeFailureAction,
+}
+
+pub fn validate(
+    rules: &[UpgradeFailureRule],
+    has_credentials_provider: bool,
+) -> anyhow::Result<()> {
+    for rule in rules {
+        if !(400..600).contains(&rule.status) {
+            return Err(anyhow!(
+                "Invalid upgrade failure status {}, expected an http error status",
+                rule.status
+            ));
+        }
+        if rules.iter().filter(|r| r.status == rule.status).count() > 1 {
+            return Err(anyhow!(
+                "Several rules for upgrade failure status {}",
+                rule.status
+            ));
+        }
+        match &rule.action {
+            UpgradeFailureAction::RotatePathPrefix { prefixes } if prefixes.is_empty() => {
+                return Err(anyhow!(
+                    "No path prefix to rotate through on upgrade failure status {}",
+                    rule.status
+                ));
+            }
+            UpgradeFailureAction::RefreshCredentials if !has_credentials_provider => {
+                return Err(anyhow!(
+                    "Refreshing credentials on upgrade failure status {} requires a credentials provider",
+                    rule.status
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Rule matching the failure of a tunnel, when it failed because the server rejected the upgrade request
+pub fn matching_rule<'a>(
+    rules: &'a [UpgradeFailureRule],
+    err: &anyhow::Error,
+) -> Option<&'a UpgradeFailureRule> {
+    let status = upgrade_status(err)?;
+    rules.iter().find(|rule| rule.status == status)
+}
+
+/// Prefix following the current one in the list, the first one when the current one is not part of it
+pub fn next_prefix<'a>(prefixes: &'a [String], current: &str) -> Option<&'a String> {
+    let next = prefixes
+        .iter()
+        .position(|prefix| prefix == current)
+        .map_or(0, |i| (i + 1) % prefixes.len());
+    prefixes.get(next)
+}
+
+/// The server rejected the upgrade request of a tunnel with an http error status
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpgradeRejected {
+    pub status: u16,
+}
+
+impl fmt::Display for UpgradeRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upgrade request rejected with status {}", self.status)
+    }
+}
+
+impl std::error::Error for UpgradeRejected {}
+
+/// Error of a failed tunnel with the rejection of its upgrade request typed, for the failure to be told apart
+/// from the error rather than from its message. wstunnel only reports the status in its message, as the first
+/// 3 digit number following a mention of the upgrade or the status: it is read there once, when the tunnel fails
+pub fn typed(err: anyhow::Error) -> anyhow::Error {
+    if upgrade_status(&err).is_some() {
+        return err;
+    }
+    let message = format!("{:#}", err).to_ascii_lowercase();
+    let status = ["upgrade", "status"]
+        .iter()
+        .filter_map(|keyword| message.find(keyword))
+        .min()
+        .and_then(|start| {
+            message[start..]
+                .split(|c: char| !c.is_ascii_digit())
+                .filter(|digits| digits.len() == 3)
+                .filter_map(|digits| digits.parse::<u16>().ok())
+                .find(|status| (400..600).contains(status))
+        });
+    match status {
+        Some(status) => err.context(UpgradeRejected { status }),
+        None => err,
+    }
+}
+
+/// Http status of a rejected upgrade request, once the error is `typed`
+pub fn upgrade_status(err: &anyhow::Error) -> Option<u16> {
+    err.downcast_ref::<UpgradeRejected>()
+        .map(|rejected| rejected.status)
+}
@@ -0,0 +1,134 @@
+use crate::client::accept::AcceptBackoff;
+use anyhow::anyhow;
+use futures_util::{pin_mut, Stream, StreamExt};
+use log::warn;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+use uuid::Uuid;
+use wstunnel::tunnel::client::WsClient;
+use wstunnel::tunnel::RemoteAddr;
+
+/// Stream of a tunnel connection telling when wstunnel first uses it, which it only does once the upgrade request
+/// to the server is done
+struct Upgraded<S> {
+    inner: Pin<Box<S>>,
+    upgraded: Option<Arc<Notify>>,
+}
+
+impl<S> Upgraded<S> {
+    fn new(inner: S, upgraded: Arc<Notify>) -> Self {
+        Self {
+            inner: Box::pin(inner),
+            upgraded: Some(upgraded),
+        }
+    }
+
+    fn used(&mut self) {
+        if let Some(upgraded) = self.upgraded.take() {
+            upgraded.notify_one();
+        }
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Upgraded<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.used();
+        this.inner.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for Upgraded<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.used();
+        this.inner.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+/// Run a local to remote tunnel the way wstunnel does, giving up the upgrade request of a connection when the
+/// server did not complete it within `timeout`. wstunnel does not bound the exchange itself: the local connection
+/// would wait on the server for as long as it keeps the connection open
+pub async fn run_tunnel<L, R, W>(
+    client: WsClient,
+    listener: L,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()>
+where
+    L: Stream<Item = anyhow::Result<((R, W), RemoteAddr)>> + Send + 'static,
+    R: AsyncRead + Send + 'static,
+    W: AsyncWrite + Send + 'static,
+{
+    let Some(timeout) = timeout else {
+        return client.run_tunnel(listener).await;
+    };
+    pin_mut!(listener);
+    let mut backoff = AcceptBackoff::default();
+    while let Some(cnx) = listener.next().await {
+        let ((reader, writer), remote) = match cnx {
+            Ok(cnx) => {
+                backoff.succeeded();
+                cnx
+            }
+            Err(err) => {
+                warn!("Error accepting connection: {:?}", err);
+                backoff.failed().await;
+                continue;
+            }
+        };
+        let upgraded = Arc::new(Notify::new());
+        let duplex = (
+            Upgraded::new(reader, upgraded.clone()),
+            Upgraded::new(writer, upgraded.clone()),
+        );
+        let client = client.clone();
+        tokio::spawn(async move {
+            let connection = client.connect_to_server(Uuid::now_v7(), &remote, duplex);
+            pin_mut!(connection);
+            let upgrade = async {
+                tokio::select! {
+                    res = &mut connection => Some(res),
+                    _ = upgraded.notified() => None,
+                }
+            };
+            // Dropping the connection future cancels the upgrade request and closes the local connection
+            let upgrade = tokio::time::timeout(timeout, upgrade).await;
+            let res = match upgrade {
+                Ok(Some(res)) => res,
+                Ok(None) => connection.await,
+                Err(_) => Err(anyhow!(
+                    "Upgrade request to the server took longer than {:?}",
+                    timeout
+                )),
+            };
+            if let Err(err) = res {
+                warn!(
+                    "Tunnel connection to {}:{} failed: {:?}",
+                    remote.host, remote.port, err
+                );
+            }
+        });
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+use crate::client::bridge::{Bridge, Target};
+use crate::client::buffers::BufferTuning;
+use crate::client::relay;
+use crate::client::stats::ProfileStats;
+use anyhow::anyhow;
+use log::{debug, info};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use url::Url;
+use wstunnel::protocols::dns::DnsResolver;
+
+/// Serve a loopback http proxy connecting to the server with the socket options of the profile, for wstunnel to
+/// use as its http proxy. wstunnel opens the connection to the server by itself and has no setting for them.
+/// Names are resolved by the resolver of the profile, the static hosts being already applied to the targets.
+/// The bridge only connects to the targets and stops once the profile is disconnected.
+pub async fn spawn_tuned_bridge(
+    tuning: BufferTuning,
+    resolver: DnsResolver,
+    targets: Vec<Target>,
+    stats: &Arc<ProfileStats>,
+) -> anyhow::Result<Url> {
+    let bridge = Bridge::bind(targets).await?;
+    info!("Connecting to the server with socket options {:?}", tuning);
+    let url = bridge.url()?;
+    let resolver = Arc::new(resolver);
+    bridge.serve(stats, move |stream, target| {
+        let resolver = resolver.clone();
+        async move { connect_tuned(stream, &target, &resolver, tuning).await }
+    });
+    Ok(url)
+}
+
+/// Answer the CONNECT request of wstunnel once the server is reached, then relay the connection
+async fn connect_tuned(
+    mut inbound: TcpStream,
+    target: &Target,
+    resolver: &DnsResolver,
+    tuning: BufferTuning,
+) -> anyhow::Result<()> {
+    let mut outbound = match connect(target, resolver, &tuning).await {
+        Ok(outbound) => outbound,
+        Err(err) => {
+            inbound
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n")
+                .await?;
+            return Err(err.context(format!("Cannot reach {}", target)));
+        }
+    };
+    inbound
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await?;
+    debug!("Connection to {} established with tuned socket", target);
+    relay::relay(&mut inbound, &mut outbound, &tuning).await?;
+    Ok(())
+}
+
+/// Try every address of the target until one accepts the connection
+async fn connect(
+    target: &Target,
+    resolver: &DnsResolver,
+    tuning: &BufferTuning,
+) -> anyhow::Result<TcpStream> {
+    let addrs = match target.host.parse::<IpAddr>() {
+        Ok(ip) => vec![SocketAddr::new(ip, target.port)],
+        Err(_) => resolver.lookup_host(&target.host, target.port).await?,
+    };
+    let mut last_err = anyhow!("{} resolves to no address", target);
+    for addr in addrs {
+        match tuning.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
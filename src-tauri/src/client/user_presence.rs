@@ -0,0 +1,251 @@
+use crate::client::profile::Profile;
+use crate::profile_store;
+use anyhow::Context;
+use log::{info, warn};
+use parking_lot::{const_mutex, Mutex};
+use std::collections::BTreeSet;
+use std::path::Path;
+
+/// Profiles requiring a confirmation, as recorded by the app. The frontend writes the profile store on its own,
+/// so the setting of a saved profile alone could be cleared without the user authenticating
+const CONFIRMATION_FILE: &str = "confirmations.json";
+
+static CONFIRMATION_LOCK: Mutex<()> = const_mutex(());
+
+/// Ask the user to authenticate with the OS before a profile requiring it connects: Windows Hello, Touch ID or the
+/// password of the Mac, polkit on Linux. Fails when the prompt is dismissed or cannot be shown
+pub async fn confirm(data_dir: &Path, profile: &Profile) -> anyhow::Result<()> {
+    if !required(data_dir, profile) {
+        return Ok(());
+    }
+    let reason = format!("connect the wstunnel profile {}", profile.name);
+    platform::verify(reason).await?;
+    info!(
+        "Connection of profile {} confirmed by the user",
+        profile.name
+    );
+    // Kept required even if the profile store is edited afterwards
+    if let Err(err) = record(data_dir, &profile.name, true) {
+        warn!(
+            "Cannot record the confirmation of {}: {:?}",
+            profile.name, err
+        );
+    }
+    Ok(())
+}
+
+/// Whether the profile requires a confirmation, by what the app recorded, the profile store or the profile given
+fn required(data_dir: &Path, profile: &Profile) -> bool {
+    if profile.require_confirmation || load(data_dir).contains(&profile.name) {
+        return true;
+    }
+    match profile_store::load_profiles(data_dir) {
+        Ok(saved) => saved
+            .iter()
+            .any(|saved| saved.name == profile.name && saved.require_confirmation),
+        Err(err) => {
+            warn!("Cannot read saved profiles: {:?}", err);
+            false
+        }
+    }
+}
+
+/// Record whether a profile requires a confirmation. Turning it off is only done once the user authenticated
+pub async fn set_required(data_dir: &Path, profile_id: &str, required: bool) -> anyhow::Result<()> {
+    let recorded = load(data_dir).contains(profile_id);
+    if recorded && !required {
+        let reason = format!("stop asking to confirm the wstunnel profile {}", profile_id);
+        platform::verify(reason).await?;
+    }
+    record(data_dir, profile_id, required)
+}
+
+fn record(data_dir: &Path, profile_id: &str, required: bool) -> anyhow::Result<()> {
+    let _lock = CONFIRMATION_LOCK.lock();
+    let mut profiles = load_unlocked(data_dir);
+    if required {
+        profiles.insert(profile_id.to_string());
+    } else {
+        profiles.remove(profile_id);
+    }
+    save(data_dir, &profiles)
+}
+
+fn load(data_dir: &Path) -> BTreeSet<String> {
+    let _lock = CONFIRMATION_LOCK.lock();
+    load_unlocked(data_dir)
+}
+
+fn load_unlocked(data_dir: &Path) -> BTreeSet<String> {
+    std::fs::read(data_dir.join(CONFIRMATION_FILE))
+        .ok()
+        .and_then(|content| serde_json::from_slice(&content).ok())
+        .unwrap_or_default()
+}
+
+fn save(data_dir: &Path, profiles: &BTreeSet<String>) -> anyhow::Result<()> {
+    std::fs::create_dir_all(data_dir)?;
+    let path = data_dir.join(CONFIRMATION_FILE);
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(profiles)?)
+        .with_context(|| format!("Cannot write {}", tmp.display()))?;
+    std::fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+mod platform {
+    use anyhow::anyhow;
+    use windows::core::HSTRING;
+    use windows::Security::Credentials::UI::{
+        UserConsentVerificationResult, UserConsentVerifier, UserConsentVerifierAvailability,
+    };
+
+    pub async fn verify(reason: String) -> anyhow::Result<()> {
+        // The WinRT operations are waited on, off the runtime threads
+        tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            let availability = UserConsentVerifier::CheckAvailabilityAsync()?.get()?;
+            if availability != UserConsentVerifierAvailability::Available {
+                return Err(anyhow!(
+                    "Windows Hello is not set up, add a PIN or biometrics in the sign-in options of Windows"
+                ));
+            }
+            match UserConsentVerifier::RequestVerificationAsync(&HSTRING::from(reason))?.get()? {
+                UserConsentVerificationResult::Verified => Ok(()),
+                UserConsentVerificationResult::Canceled => {
+                    Err(anyhow!("The authentication was cancelled"))
+                }
+                result => Err(anyhow!(
+                    "Windows Hello did not verify the user: {:?}",
+                    result
+                )),
+            }
+        })
+        .await?
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use anyhow::anyhow;
+    use block2::RcBlock;
+    use objc2::runtime::Bool;
+    use objc2_foundation::{NSError, NSString};
+    use objc2_local_authentication::{LAContext, LAPolicy};
+    use std::sync::mpsc;
+
+    pub async fn verify(reason: String) -> anyhow::Result<()> {
+        // The context cancels the prompt once released, it is kept until the reply on a thread of its own
+        tokio::task::spawn_blocking(move || {
+            let (tx, rx) = mpsc::channel();
+            let reply = RcBlock::new(move |success: Bool, error: *mut NSError| {
+                let result = if success.as_bool() {
+                    Ok(())
+                } else {
+                    // SAFETY: LocalAuthentication passes a valid error, or null, along with a failure
+                    Err(unsafe { error.as_ref() }
+                        .map(|error| error.localizedDescription().to_string())
+                        .unwrap_or_else(|| "unknown error".to_string()))
+                };
+                let _ = tx.send(result);
+            });
+            // SAFETY: the context outlives the evaluation, and the reply block has the signature it expects
+            let context = unsafe { LAContext::new() };
+            unsafe {
+                context.evaluatePolicy_localizedReason_reply(
+                    LAPolicy::DeviceOwnerAuthentication,
+                    &NSString::from_str(&reason),
+                    &reply,
+                );
+            }
+            let result = rx.recv();
+            drop(context);
+            match result {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(message)) => Err(anyhow!("The user was not authenticated: {}", message)),
+                Err(_) => Err(anyhow!("The authentication prompt was closed")),
+            }
+        })
+        .await?
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use anyhow::{anyhow, Context};
+    use std::path::Path;
+    use tokio::process::Command;
+
+    /// Action of the polkit policy installed with the app packages, asking for the password of the user
+    const POLKIT_ACTION: &str = "com.wstunnel.desktop.connect";
+    const POLKIT_ACTIONS_DIR: &str = "/usr/share/polkit-1/actions";
+    const PKCHECK: &str = "/usr/bin/pkcheck";
+    const PKEXEC: &str = "/usr/bin/pkexec";
+
+    pub async fn verify(_reason: String) -> anyhow::Result<()> {
+        // polkit shows the message of its action, not one given by the app
+        let policy = Path::new(POLKIT_ACTIONS_DIR).join(format!("{}.policy", POLKIT_ACTION));
+        if policy.exists() {
+            // The start time and uid along with the pid, a pid alone being racy for polkit
+            let output = Command::new(PKCHECK)
+                .args(["--action-id", POLKIT_ACTION, "--process"])
+                .arg(process_subject()?)
+                .arg("--allow-user-interaction")
+                .output()
+                .await
+                .with_context(|| format!("Cannot execute {}", PKCHECK))?;
+            return match output.status.code() {
+                Some(0) => Ok(()),
+                Some(3) => Err(anyhow!("The authentication was cancelled")),
+                _ => Err(anyhow!(
+                    "polkit did not authenticate the user: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )),
+            };
+        }
+        // Not installed from a package, i.e: an AppImage, the action of pkexec asks for an administrator instead
+        let output = Command::new(PKEXEC)
+            .arg("/bin/true")
+            .output()
+            .await
+            .with_context(|| format!("Cannot execute {}, is polkit installed?", PKEXEC))?;
+        match output.status.code() {
+            Some(0) => Ok(()),
+            Some(126) | Some(127) => Err(anyhow!("The authentication was cancelled")),
+            _ => Err(anyhow!(
+                "polkit did not authenticate the user: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+
+    /// `pid,start-time,uid` of the app for pkcheck, the start time being field 22 of `/proc/self/stat`
+    fn process_subject() -> anyhow::Result<String> {
+        let stat =
+            std::fs::read_to_string("/proc/self/stat").context("Cannot read /proc/self/stat")?;
+        // The command name, in parentheses, can hold spaces
+        let fields = stat
+            .rsplit_once(')')
+            .map(|(_, fields)| fields)
+            .ok_or_else(|| anyhow!("Invalid /proc/self/stat"))?;
+        let start_time = fields
+            .split_whitespace()
+            .nth(19)
+            .ok_or_else(|| anyhow!("Invalid /proc/self/stat"))?;
+        // SAFETY: getuid cannot fail
+        let uid = unsafe { libc::getuid() };
+        Ok(format!("{},{},{}", std::process::id(), start_time, uid))
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos", target_os = "linux")))]
+mod platform {
+    use anyhow::anyhow;
+
+    pub async fn verify(_reason: String) -> anyhow::Result<()> {
+        Err(anyhow!(
+            "Confirming a connection is not available on {}",
+            std::env::consts::OS
+        ))
+    }
+}
@@ -0,0 +1,118 @@
+use crate::client::cli_format;
+use crate::client::profile::Profile;
+use crate::deep_link::{DeepLinkImports, ImportSource, PendingImport};
+use anyhow::anyhow;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Manager, Url};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Longer text is not a command someone copied from docs or a chat
+const MAX_TEXT_LEN: usize = 16 * 1024;
+/// Name given to a profile whose command does not carry one, replaced by the host of its server
+const PLACEHOLDER_NAME: &str = "clipboard";
+
+/// Opt-in watcher offering to import the `wstunnel client` commands and `ws(s)://` urls the user copies.
+/// The clipboard is only read while it is enabled, the frontend keeps the choice and enables it again at startup.
+#[derive(Default)]
+pub struct ClipboardWatch {
+    enabled: Arc<AtomicBool>,
+    running: Mutex<bool>,
+}
+
+impl ClipboardWatch {
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, app: &AppHandle, enabled: bool) {
+        let mut running = self.running.lock();
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if enabled && !*running {
+            *running = true;
+            spawn(app.clone(), self.enabled.clone());
+        }
+    }
+}
+
+/// Poll the clipboard on a thread of its own, clipboard access being blocking and tied to a thread on some OSes.
+/// The thread exits once the watch is disabled. The text present when the watch is enabled is not offered, only
+/// what is copied afterward.
+fn spawn(app: AppHandle, enabled: Arc<AtomicBool>) {
+    std::thread::spawn(move || {
+        let mut clipboard = match arboard::Clipboard::new() {
+            Ok(clipboard) => clipboard,
+            Err(err) => {
+                warn!("Cannot watch the clipboard: {:?}", err);
+                *app.state::<ClipboardWatch>().running.lock() = false;
+                return;
+            }
+        };
+        let mut last = clipboard.get_text().ok();
+        info!("Watching the clipboard for wstunnel commands");
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            if !enabled.load(Ordering::Relaxed) {
+                let watch = app.state::<ClipboardWatch>();
+                let mut running = watch.running.lock();
+                // Enabled again meanwhile, it found this thread running
+                if enabled.load(Ordering::Relaxed) {
+                    continue;
+                }
+                *running = false;
+                info!("Stopped watching the clipboard");
+                return;
+            }
+            let Ok(text) = clipboard.get_text() else {
+                continue;
+            };
+            if last.as_ref() == Some(&text) {
+                continue;
+            }
+            last = Some(text.clone());
+            match detect(&text) {
+                Ok(Some(profile)) => {
+                    let import = PendingImport::new(profile);
+                    if let Err(err) =
+                        app.state::<DeepLinkImports>()
+                            .offer(&app, import, ImportSource::Clipboard)
+                    {
+                        warn!("Cannot offer to import the copied profile: {:?}", err);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => debug!("Ignoring copied wstunnel command: {:?}", err),
+            }
+        }
+    });
+}
+
+/// Profile of a copied `wstunnel client` command or server url, None for any other text
+fn detect(text: &str) -> anyhow::Result<Option<Profile>> {
+    let text = text.trim();
+    if text.is_empty() || text.len() > MAX_TEXT_LEN {
+        return Ok(None);
+    }
+    let script = if text.contains("wstunnel") && text.contains("client") {
+        text.to_string()
+    } else {
+        match Url::parse(text) {
+            Ok(url) if matches!(url.scheme(), "ws" | "wss") => format!("wstunnel client {}", url),
+            _ => return Ok(None),
+        }
+    };
+    let mut profile = cli_format::import_profile(&script, PLACEHOLDER_NAME)?;
+    if profile.name == PLACEHOLDER_NAME {
+        profile.name = profile
+            .server_addr
+            .host_str()
+            .ok_or_else(|| anyhow!("Server address has no host"))?
+            .to_string();
+    }
+    // Offer only what could connect, as a deep link would
+    profile.to_client()?;
+    Ok(Some(profile))
+}
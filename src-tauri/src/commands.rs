@@ -0,0 +1,2251 @@
+use crate::auth;
+use crate::autostart;
+use crate::bulk::{self, BulkReport};
+use crate::client::access_log::{AccessLogEntry, AccessLogWriter};
+use crate::client::app_rules::AppRouting;
+use crate::client::buffers::{self, BufferBenchmark, BufferTuning};
+use crate::client::bundle;
+use crate::client::capture::CaptureSummary;
+use crate::client::chain::{self, Upstream};
+use crate::client::cli_format;
+use crate::client::client_api::{BoundListener, WsClientApi};
+use crate::client::connections::ActiveConnection;
+use crate::client::datagrams::DatagramStatus;
+use crate::client::dns_leak::{self, DnsLeakReport};
+use crate::client::dry_run::{self, ValidationFinding};
+use crate::client::engine::PoolStatus;
+use crate::client::events::{ClientEvent, ConnectProgress};
+use crate::client::failure_cause::FailureCause;
+use crate::client::faults::{self, Fault};
+use crate::client::groups::{self, GroupStats};
+use crate::client::held_settings::HeldKind;
+use crate::client::hooks::{Hook, HookStage};
+use crate::client::listener_auth;
+use crate::client::manager::{ClientManager, ManagedClient};
+use crate::client::multipath::PathStatus;
+use crate::client::net_admin::{self, NetAdminStatus};
+use crate::client::placeholders;
+use crate::client::platform::{self, Capability};
+use crate::client::port_mapping::PortMapping;
+use crate::client::presets::{self, PresetInfo, TunnelPreset};
+use crate::client::profile::{Profile, TunnelConfig};
+use crate::client::quality::{self, LinkQuality, QualityReport};
+use crate::client::reload;
+use crate::client::repair::{self, ProfileIssue, RepairAction};
+use crate::client::reverse_status::ReverseTunnelStatus;
+use crate::client::route::{self, RouteExplanation};
+use crate::client::selftest::{self, SelfTestReport};
+use crate::client::server_select::ServerProbe;
+use crate::client::server_trust::{self, CertificateChain, ServerCertificate};
+use crate::client::setup::{self, ReachabilityCheck, SetupReport, UpgradeCheck, UrlCheck};
+use crate::client::stats::TrafficSnapshot;
+use crate::client::temp_tunnels::{self, TempTunnel};
+use crate::client::trace::ConnectionTrace;
+use crate::client::transport::{self, TransportKind};
+use crate::client::user_presence;
+use crate::clipboard_watch::ClipboardWatch;
+use crate::config_migration::{self, MigrationReport};
+use crate::control_api::{ControlApi, ControlEndpoint};
+use crate::daemon::{self, DaemonCall, DaemonProfile, DaemonStatus};
+use crate::deep_link::{DeepLinkImports, ImportRequest, ImportSource, PendingImport};
+use crate::diagnostics;
+use crate::handoff;
+use crate::history::{self, ConnectionHistory, HistoryEntry, HistoryFilter, HistoryKind};
+use crate::kill_switch::{Endpoints, KillSwitch};
+use crate::managed_profiles::{self, ManagedSource, ManagedSources};
+use crate::messages::{self, Locale, MessageCatalog, Messages};
+use crate::metrics::MetricsServer;
+use crate::notifications;
+use crate::pac::PacServer;
+use crate::parsers::{self, TunnelSpecCheck};
+use crate::profile_store;
+use crate::qr::{self, ProfileQrCode};
+use crate::relay::{RelayProcesses, RelayStatus};
+use crate::scheduler::{self, ScheduleStatus};
+use crate::session::{self, RecoveryMode, Session};
+use crate::ssh_config::{self, SshProxyMode};
+use crate::stats_panel::{self, StatsSubscribers};
+use crate::stats_store::{self, BudgetUsage, Granularity, StatsStore, TimeRange, UsagePoint};
+use crate::status_file;
+use crate::system_proxy::{ProxyKind, SystemProxy};
+use crate::tproxy_rules::{self, TproxyRules};
+use log::warn;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State, Url, WebviewWindow};
+use tauri_plugin_autostart::ManagerExt;
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionInfo {
+    pub profile_id: String,
+    pub remote_addr: String,
+    pub listeners: Vec<BoundListener>,
+    pub temp_tunnels: Vec<TempTunnel>,
+    /// Stable hash of the profile configuration, secrets excluded
+    pub config_hash: String,
+}
+
+/// Sent to the frontend at each step of the connection of a profile
+pub const CONNECT_PROGRESS_EVENT: &str = "connect-progress";
+
+/// Sent to the frontend when the user must confirm the certificate of a server the profile does not verify
+pub const SERVER_TRUST_EVENT: &str = "server-trust-required";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServerTrustEvent {
+    pub profile_id: String,
+    pub certificate: ServerCertificate,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectProgressEvent {
+    pub profile_id: String,
+    #[serde(flatten)]
+    pub step: ConnectProgress,
+}
+
+/// Sent to the frontend when a reverse tunnel goes live or the server accepts a connection on its remote port
+pub const REVERSE_TUNNEL_EVENT: &str = "reverse-tunnel";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReverseTunnelEvent {
+    pub profile_id: String,
+    #[serde(flatten)]
+    pub status: ReverseTunnelStatus,
+}
+
+/// Sent to the frontend when a connection of a reverse tunnel opens or closes
+pub const REVERSE_CONNECTION_EVENT: &str = "reverse-connection";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReverseConnectionEvent {
+    pub profile_id: String,
+    pub closed: bool,
+    #[serde(flatten)]
+    pub connection: ActiveConnection,
+}
+
+/// Sent to the frontend when the router forwards a port to the listener of a tunnel, or refused to
+pub const PORT_MAPPING_EVENT: &str = "port-mapping";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PortMappingEvent {
+    pub profile_id: String,
+    pub tunnel_id: String,
+    /// Not set when the mapping failed
+    pub mapping: Option<PortMapping>,
+    pub error: Option<String>,
+}
+
+/// Sent to the frontend when an application resolved a name by itself before using a socks tunnel
+/// of a profile forcing remote dns, so the user can fix its proxy settings
+pub const DNS_LEAK_EVENT: &str = "dns-leak";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsLeakEvent {
+    pub profile_id: String,
+    pub tunnel_id: String,
+    pub destination: String,
+}
+
+/// Sent to the frontend each time a connection of a tunnel logging its accesses is closed
+pub const ACCESS_LOG_EVENT: &str = "access-log";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AccessLogEvent {
+    pub profile_id: String,
+    #[serde(flatten)]
+    pub entry: AccessLogEntry,
+}
+
+/// Sent to the frontend each time a tunnel of a profile fails, with what caused it
+pub const TUNNEL_FAILURE_EVENT: &str = "tunnel-failure";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TunnelFailureEvent {
+    pub profile_id: String,
+    pub tunnel_id: String,
+    pub cause: FailureCause,
+    /// Explanation of the cause to show the user
+    pub message: String,
+    /// Full error chain, for the details
+    pub error: String,
+    /// Whether the tunnel stopped for good rather than being restarted
+    pub stopped: bool,
+}
+
+/// Sent to the frontend each time the link to the server of a profile has been probed
+pub const LINK_QUALITY_EVENT: &str = "link-quality";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkQualityEvent {
+    pub profile_id: String,
+    #[serde(flatten)]
+    pub quality: LinkQuality,
+}
+
+/// A live connection of a connected profile
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileConnection {
+    pub profile_id: String,
+    #[serde(flatten)]
+    pub connection: ActiveConnection,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileStatus {
+    #[serde(flatten)]
+    pub connection: ConnectionInfo,
+    pub traffic: TrafficSnapshot,
+    pub reverse_tunnels: Vec<ReverseTunnelStatus>,
+}
+
+impl From<&ManagedClient> for ConnectionInfo {
+    fn from(managed: &ManagedClient) -> Self {
+        Self {
+            profile_id: managed.profile.name.clone(),
+            remote_addr: managed.client.remote_addr.to_string(),
+            listeners: managed.client.listeners.clone(),
+            temp_tunnels: managed.client.temp_tunnels.clone(),
+            config_hash: managed.config_hash.clone(),
+        }
+    }
+}
+
+impl From<&RelayStatus> for ConnectionInfo {
+    fn from(status: &RelayStatus) -> Self {
+        Self {
+            profile_id: status.profile.name.clone(),
+            remote_addr: status.remote_addr.clone(),
+            listeners: status.listeners.clone(),
+            temp_tunnels: vec![],
+            config_hash: status.profile.config_hash(),
+        }
+    }
+}
+
+/// Connected profiles, in the app or in their relay process, other profiles may be chained to
+fn upstreams(manager: &ClientManager, relays: &RelayProcesses) -> Vec<Upstream> {
+    manager
+        .list()
+        .into_iter()
+        .map(|managed| Upstream {
+            profile: managed.expanded,
+            listeners: managed.client.listeners,
+        })
+        .chain(relays.list().into_iter().map(|status| Upstream {
+            profile: status.expanded,
+            listeners: status.listeners,
+        }))
+        .collect()
+}
+
+/// Point the system proxy to the first tunnel of the profile asking for it
+fn apply_system_proxy(managed: &ManagedClient, system_proxy: &SystemProxy) -> anyhow::Result<()> {
+    for config in managed
+        .expanded
+        .enabled_tunnels()
+        .filter(|t| t.set_system_proxy)
+    {
+        let tunnel = config.to_tunnel()?;
+        let Some(kind) = ProxyKind::of(&tunnel.local_protocol) else {
+            continue;
+        };
+        if let Some(listener) = managed
+            .client
+            .listeners
+            .iter()
+            .find(|l| l.tunnel_id == tunnel.id)
+        {
+            return system_proxy.apply(&managed.profile.name, kind, listener.bound);
+        }
+    }
+    Ok(())
+}
+
+/// Write each profile as a script running the upstream wstunnel cli, one file per profile, its secrets redacted
+#[tauri::command]
+pub fn export_profiles(profiles: Vec<Profile>, directory: PathBuf) -> Result<Vec<PathBuf>, String> {
+    cli_format::export_profiles(&profiles, &directory).map_err(|err| format!("{:?}", err))
+}
+
+/// Read the profiles of the scripts running the upstream wstunnel cli found in the directory
+#[tauri::command]
+pub fn import_profiles(directory: PathBuf) -> Result<Vec<Profile>, String> {
+    cli_format::import_profiles(&directory).map_err(|err| format!("{:?}", err))
+}
+
+/// Migration of the saved profiles done by the last upgrade of the app, if any
+#[tauri::command]
+pub fn get_config_migration_report(app: AppHandle) -> Result<Option<MigrationReport>, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    config_migration::last_report(&data_dir).map_err(|err| format!("{:?}", err))
+}
+
+/// Write saved profiles into a file encrypted with the passphrase, to share them with their secrets
+#[tauri::command]
+pub fn export_profile_bundle(
+    profile_ids: Vec<String>,
+    passphrase: String,
+    path: PathBuf,
+    embed_certificates: bool,
+    app: AppHandle,
+) -> Result<(), String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    let saved = profile_store::load_profiles(&data_dir).map_err(|err| format!("{:?}", err))?;
+    let profiles = profile_ids
+        .iter()
+        .map(|profile_id| {
+            saved
+                .iter()
+                .find(|profile| &profile.name == profile_id)
+                .cloned()
+                .ok_or_else(|| format!("No saved profile named {}", profile_id))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    bundle::export(profiles, &passphrase, embed_certificates, &path)
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Read the profiles of an encrypted bundle, which the user confirms one by one as any profile received through a link
+#[tauri::command]
+pub fn import_profile_bundle(
+    path: PathBuf,
+    passphrase: String,
+    app: AppHandle,
+    imports: State<'_, DeepLinkImports>,
+) -> Result<Vec<ImportRequest>, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    let profiles =
+        bundle::import(&path, &passphrase, &data_dir).map_err(|err| format!("{:?}", err))?;
+    profiles
+        .into_iter()
+        .map(|profile| imports.offer(&app, PendingImport::new(profile), ImportSource::Bundle))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// QR code provisioning the profile on another machine, with the same link as a deep link
+#[tauri::command]
+pub fn get_profile_qr_code(profile: Profile) -> Result<ProfileQrCode, String> {
+    qr::render(&profile).map_err(|err| format!("{:?}", err))
+}
+
+/// Read the profile of a QR code image, which the user confirms as any profile received through a link
+#[tauri::command]
+pub fn import_profile_qr_code(
+    image: Vec<u8>,
+    app: AppHandle,
+    imports: State<'_, DeepLinkImports>,
+) -> Result<ImportRequest, String> {
+    let url = qr::decode(&image).map_err(|err| format!("{:?}", err))?;
+    imports
+        .handle_url(&app, &url, ImportSource::QrCode)
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn get_clipboard_watch(watch: State<'_, ClipboardWatch>) -> bool {
+    watch.enabled()
+}
+
+/// Offer to import the wstunnel commands and server urls the user copies, as long as it is enabled
+#[tauri::command]
+pub fn set_clipboard_watch(enabled: bool, app: AppHandle, watch: State<'_, ClipboardWatch>) {
+    watch.set_enabled(&app, enabled);
+}
+
+/// Language of the notifications and of the messages sent to the frontend
+#[tauri::command]
+pub fn set_locale(locale: Locale, messages: State<'_, Messages>) -> Result<(), String> {
+    messages
+        .set_locale(locale)
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Templates of the messages in the current locale, keyed after the causes and events the frontend receives
+#[tauri::command]
+pub fn get_message_catalog(messages: State<'_, Messages>) -> MessageCatalog {
+    messages::catalog(messages.locale())
+}
+
+/// Check a stored profile, returning what prevents it to load and how to fix it
+#[tauri::command]
+pub fn check_profile(profile: serde_json::Value) -> Vec<ProfileIssue> {
+    repair::diagnose(&profile)
+}
+
+/// Save a copy of a saved profile under another name
+#[tauri::command]
+pub fn duplicate_profile(
+    profile_id: String,
+    new_name: String,
+    app: AppHandle,
+) -> Result<Profile, String> {
+    profile_store::duplicate(&app, &profile_id, &new_name).map_err(|err| format!("{:?}", err))
+}
+
+/// Record whether connecting a profile requires the user to authenticate. Turning it off asks for the
+/// authentication first, the profile store alone being writable without it
+#[tauri::command]
+pub async fn set_require_confirmation(
+    profile_id: String,
+    required: bool,
+    app: AppHandle,
+) -> Result<(), String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    user_presence::set_required(&data_dir, &profile_id, required)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Run the checks of the connection of a saved profile without connecting it, for the editor to show what
+/// would fail
+#[tauri::command]
+pub async fn validate_profile(
+    profile_id: String,
+    app: AppHandle,
+) -> Result<Vec<ValidationFinding>, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    let profile = profile_store::load_profiles(&data_dir)
+        .map_err(|err| format!("{:?}", err))?
+        .into_iter()
+        .find(|profile| profile.name == profile_id)
+        .ok_or_else(|| format!("No saved profile named {}", profile_id))?;
+    let manager = app.state::<ClientManager>();
+    let relays = app.state::<RelayProcesses>();
+    let connected = manager.get(&profile_id).is_some() || relays.contains(&profile_id);
+    let upstreams = upstreams(&manager, &relays);
+    Ok(dry_run::validate(&profile, &upstreams, connected).await)
+}
+
+/// Apply one of the repair actions suggested by `check_profile`
+#[tauri::command]
+pub fn repair_profile(
+    profile: serde_json::Value,
+    field: String,
+    action: RepairAction,
+    value: Option<serde_json::Value>,
+) -> Result<serde_json::Value, String> {
+    repair::repair(profile, &field, action, value).map_err(|err| format!("{:?}", err))
+}
+
+/// Connect a profile, recording the outcome in the connection history
+#[tauri::command]
+pub async fn connect(
+    profile: Profile,
+    app: AppHandle,
+    manager: State<'_, ClientManager>,
+    system_proxy: State<'_, SystemProxy>,
+    pac_server: State<'_, PacServer>,
+) -> Result<ConnectionInfo, String> {
+    let profile_id = profile.name.clone();
+    let server = profile.server_addr.to_string();
+    let result = connect_profile(profile, app.clone(), manager, system_proxy, pac_server).await;
+    let entry = match &result {
+        Ok(connection) => {
+            HistoryEntry::new(&profile_id, &connection.remote_addr, HistoryKind::Connected)
+        }
+        Err(error) => HistoryEntry::failed(&profile_id, &server, error),
+    };
+    app.state::<ConnectionHistory>().record(entry);
+    result
+}
+
+async fn connect_profile(
+    profile: Profile,
+    app: AppHandle,
+    manager: State<'_, ClientManager>,
+    system_proxy: State<'_, SystemProxy>,
+    pac_server: State<'_, PacServer>,
+) -> Result<ConnectionInfo, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    manager
+        .confirm_connect(&data_dir, &profile)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    if let Some(hook) = &profile.before_connect {
+        hook.run(&profile.name, HookStage::BeforeConnect)
+            .await
+            .map_err(|err| format!("{:?}", err))?;
+    }
+    // The profile is kept as written, only the one connected has its placeholders expanded
+    let expanded = placeholders::expand(&profile).map_err(|err| format!("{:?}", err))?;
+    let authorized = auth::authorize(&expanded, |prompt| {
+        if let Err(err) = app.emit(auth::DEVICE_CODE_EVENT, prompt) {
+            warn!("Cannot ask for authorization: {:?}", err);
+        }
+    })
+    .await
+    .map_err(|err| format!("{:?}", err))?;
+    let relays = app.state::<RelayProcesses>();
+    let mut authorized = chain::resolve(&authorized, &upstreams(&manager, &relays))
+        .map_err(|err| format!("{:?}", err))?;
+    let kill_switch_endpoints = if profile.kill_switch {
+        let endpoints = app
+            .state::<KillSwitch>()
+            .resolve(&mut authorized)
+            .await
+            .map_err(|err| format!("{:?}", err))?;
+        Some(endpoints)
+    } else {
+        None
+    };
+    authorized
+        .tls_pinned_certificates
+        .extend(server_trust::pinned(&profile, &data_dir));
+    let client = authorized.to_client().map_err(|err| format!("{:?}", err))?;
+    let kill_switch_endpoints = kill_switch_endpoints.map(|endpoints| {
+        // The traffic of the machine captured by a TUN interface goes out through it
+        endpoints.with_sources(
+            client
+                .local_to_remote
+                .iter()
+                .filter_map(|tunnel| tunnel.tun)
+                .map(|device| IpAddr::V4(device.network.addr())),
+        )
+    });
+    if profile.budget_disconnect {
+        app.state::<StatsStore>()
+            .check_budget(&profile.name, profile.monthly_budget_mb)
+            .map_err(|err| format!("{:#}", err))?;
+    }
+    let untrusted = server_trust::untrusted_certificate(&profile, &data_dir)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    if let Some(certificate) = untrusted {
+        let server = certificate.server.clone();
+        let event = ServerTrustEvent {
+            profile_id: profile.name.clone(),
+            certificate,
+        };
+        if let Err(err) = app.emit(SERVER_TRUST_EVENT, event) {
+            warn!("Cannot ask to trust server certificate: {:?}", err);
+        }
+        return Err(format!(
+            "The certificate of {} must be confirmed before connecting",
+            server
+        ));
+    }
+
+    // The previous connection of the profile holds the local ports the new one binds, they are released first
+    if let Some(previous) = manager.remove(&profile.name) {
+        previous.client.stop().await;
+        pac_server.unpublish(&profile.name);
+        if let Err(err) = system_proxy.release(&profile.name) {
+            warn!("Cannot restore system proxy: {:?}", err);
+        }
+    }
+    if profile.isolated {
+        let binary =
+            tauri::process::current_binary(&app.env()).map_err(|err| format!("{:?}", err))?;
+        let profile_id = profile.name.clone();
+        let status = relays
+            .start(&binary, profile, expanded, authorized)
+            .await
+            .map_err(|err| format!("{:?}", err))?;
+        if let Err(err) = engage_kill_switch(&app, &profile_id, kill_switch_endpoints) {
+            relays.stop(&profile_id);
+            return Err(err);
+        }
+        return Ok(ConnectionInfo::from(&status));
+    }
+    relays.stop(&profile.name);
+
+    let profile_id = profile.name.clone();
+    let connected = WsClientApi::connect(Box::new(client), |step| {
+        let progress = ConnectProgressEvent {
+            profile_id: profile_id.clone(),
+            step,
+        };
+        if let Err(err) = app.emit(CONNECT_PROGRESS_EVENT, progress) {
+            warn!("Cannot report connection progress: {:?}", err);
+        }
+    })
+    .await
+    .map_err(|err| format!("{:?}", err))?;
+
+    if let Some(previous) = manager.insert(profile.clone(), expanded, connected) {
+        previous.client.shutdown();
+        if let Err(err) = system_proxy.release(&profile.name) {
+            warn!("Cannot restore system proxy: {:?}", err);
+        }
+    }
+    let managed = manager
+        .get(&profile.name)
+        .ok_or_else(|| format!("Profile {} has been disconnected", profile.name))?;
+    // The tunnels are usable without it, so failing to set the system proxy does not fail the connection
+    if let Err(err) = apply_system_proxy(&managed, &system_proxy) {
+        warn!("Cannot set system proxy: {:?}", err);
+    }
+    pac_server
+        .publish(&managed)
+        .map_err(|err| format!("{:?}", err))?;
+    notifications::watch(&app, &managed);
+    watch_reverse_tunnels(&app, &managed);
+    watch_reverse_connections(&app, &managed);
+    watch_port_mappings(&app, &managed);
+    watch_dns_leaks(&app, &managed);
+    watch_access_log(&app, &managed);
+    watch_link_quality(&app, &managed);
+    watch_tunnel_failures(&app, &managed);
+    history::watch(&app, &managed);
+    let engaged = match engage_kill_switch(&app, &profile.name, kill_switch_endpoints) {
+        Ok(()) => install_tproxy_rules(&app, &data_dir, &managed).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = engaged {
+        // Recorded as a failed connection
+        let _ = disconnect_one(
+            &profile.name,
+            &manager,
+            &relays,
+            &system_proxy,
+            &pac_server,
+            &app.state::<KillSwitch>(),
+            None,
+        );
+        return Err(err);
+    }
+    Ok(ConnectionInfo::from(&managed))
+}
+
+/// Install the transparent proxy rules of a connected profile, removed once its client is gone unless the profile
+/// connected again with the same rules or other rules replaced them
+async fn install_tproxy_rules(
+    app: &AppHandle,
+    data_dir: &Path,
+    managed: &ManagedClient,
+) -> Result<(), String> {
+    let Some(rules) = managed.profile.tproxy_rules.clone() else {
+        return Ok(());
+    };
+    tproxy_rules::install(data_dir, &rules)
+        .await
+        .map_err(|err| format!("Cannot install the transparent proxy rules: {:?}", err))?;
+    let profile_id = managed.profile.name.clone();
+    let mut events = managed.client.stats.events.subscribe();
+    let data_dir = data_dir.to_path_buf();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        while !matches!(events.recv().await, Err(RecvError::Closed)) {}
+        let connected = app
+            .state::<ClientManager>()
+            .get(&profile_id)
+            .and_then(|managed| managed.profile.tproxy_rules);
+        // Replaced in the meantime by the rules of another profile unless still installed
+        let installed = tproxy_rules::installed(&data_dir).await.ok().flatten();
+        if connected.as_ref() == Some(&rules) || installed.as_ref() != Some(&rules) {
+            return;
+        }
+        if let Err(err) = tproxy_rules::remove(&data_dir).await {
+            warn!("Cannot remove the transparent proxy rules: {:?}", err);
+        }
+    });
+    Ok(())
+}
+
+/// Install the kill switch rules of a connected profile, or remove them when it no longer has one.
+/// A profile which asked for a kill switch is not left running without it
+fn engage_kill_switch(
+    app: &AppHandle,
+    profile_id: &str,
+    endpoints: Option<Endpoints>,
+) -> Result<(), String> {
+    let kill_switch = app.state::<KillSwitch>();
+    match endpoints {
+        Some(endpoints) => kill_switch
+            .engage(profile_id, endpoints)
+            .map_err(|err| format!("Cannot engage the kill switch: {:?}", err)),
+        None => kill_switch
+            .release(profile_id)
+            .map_err(|err| format!("Cannot release the kill switch: {:?}", err)),
+    }
+}
+
+/// Forward the connections accepted by the reverse tunnels of a profile to the frontend, until it is disconnected
+fn watch_reverse_tunnels(app: &AppHandle, managed: &ManagedClient) {
+    let profile_id = managed.profile.name.clone();
+    let stats = managed.client.stats.clone();
+    let mut events = stats.events.subscribe();
+    let stats = Arc::downgrade(&stats);
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let tunnel_id = match events.recv().await {
+                Ok(ClientEvent::ReverseTunnelLive { tunnel_id, .. })
+                | Ok(ClientEvent::ReverseConnectionAccepted { tunnel_id, .. }) => tunnel_id,
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            let Some(status) = stats
+                .upgrade()
+                .and_then(|stats| stats.reverse_tunnels.get(&tunnel_id))
+            else {
+                continue;
+            };
+            let event = ReverseTunnelEvent {
+                profile_id: profile_id.clone(),
+                status,
+            };
+            if let Err(err) = app.emit(REVERSE_TUNNEL_EVENT, event) {
+                warn!("Cannot report reverse tunnel status: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Forward the connections opened and closed by the reverse tunnels of a profile to the frontend, until it is disconnected
+fn watch_reverse_connections(app: &AppHandle, managed: &ManagedClient) {
+    let profile_id = managed.profile.name.clone();
+    let mut events = managed.client.stats.events.subscribe();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let (connection, closed) = match events.recv().await {
+                Ok(ClientEvent::ReverseConnectionAccepted { connection, .. }) => {
+                    (connection, false)
+                }
+                Ok(ClientEvent::ReverseConnectionClosed { connection }) => (connection, true),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            let event = ReverseConnectionEvent {
+                profile_id: profile_id.clone(),
+                closed,
+                connection,
+            };
+            if let Err(err) = app.emit(REVERSE_CONNECTION_EVENT, event) {
+                warn!("Cannot report reverse connection: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Forward the port mappings of the tunnels to the frontend, until the profile is disconnected
+fn watch_port_mappings(app: &AppHandle, managed: &ManagedClient) {
+    let profile_id = managed.profile.name.clone();
+    let mut events = managed.client.stats.events.subscribe();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let (tunnel_id, mapping, error) = match events.recv().await {
+                Ok(ClientEvent::PortMapped { tunnel_id, mapping }) => {
+                    (tunnel_id, Some(mapping), None)
+                }
+                Ok(ClientEvent::PortMappingFailed { tunnel_id, error }) => {
+                    (tunnel_id, None, Some(error))
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            let event = PortMappingEvent {
+                profile_id: profile_id.clone(),
+                tunnel_id,
+                mapping,
+                error,
+            };
+            if let Err(err) = app.emit(PORT_MAPPING_EVENT, event) {
+                warn!("Cannot report port mapping: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Forward the destinations refused for having been resolved locally to the frontend, until the profile is disconnected
+fn watch_dns_leaks(app: &AppHandle, managed: &ManagedClient) {
+    if !managed.profile.force_remote_dns {
+        return;
+    }
+    let profile_id = managed.profile.name.clone();
+    let mut events = managed.client.stats.events.subscribe();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let (tunnel_id, destination) = match events.recv().await {
+                Ok(ClientEvent::LocalDnsResolution {
+                    tunnel_id,
+                    destination,
+                }) => (tunnel_id, destination),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            let event = DnsLeakEvent {
+                profile_id: profile_id.clone(),
+                tunnel_id,
+                destination,
+            };
+            if let Err(err) = app.emit(DNS_LEAK_EVENT, event) {
+                warn!("Cannot report dns leak: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Write the connections closed on the tunnels logging their accesses to the access log, and forward them to the
+/// frontend, until the profile is disconnected. Watched even when no tunnel logs yet, a reload can enable it
+fn watch_access_log(app: &AppHandle, managed: &ManagedClient) {
+    let profile_id = managed.profile.name.clone();
+    let mut entries = managed.client.stats.access_log.subscribe();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let entry = match entries.recv().await {
+                Ok(entry) => entry,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(
+                        "{} connections of profile {} missing from the access log",
+                        skipped, profile_id
+                    );
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            let event = AccessLogEvent {
+                profile_id: profile_id.clone(),
+                entry,
+            };
+            if let Err(err) = app.state::<AccessLogWriter>().append(&event) {
+                warn!("Cannot write access log: {:?}", err);
+            }
+            if let Err(err) = app.emit(ACCESS_LOG_EVENT, event) {
+                warn!("Cannot report access: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Forward the measurements of the link to the server to the frontend, until the profile is disconnected
+fn watch_link_quality(app: &AppHandle, managed: &ManagedClient) {
+    let profile_id = managed.profile.name.clone();
+    let stats = managed.client.stats.clone();
+    let mut events = stats.events.subscribe();
+    let stats = Arc::downgrade(&stats);
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(ClientEvent::LinkMeasured) => {}
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            }
+            let Some(stats) = stats.upgrade() else {
+                return;
+            };
+            let event = LinkQualityEvent {
+                profile_id: profile_id.clone(),
+                quality: quality::link_quality(&stats),
+            };
+            if let Err(err) = app.emit(LINK_QUALITY_EVENT, event) {
+                warn!("Cannot report link quality: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Forward the failures of the tunnels of a profile to the frontend, until it is disconnected
+fn watch_tunnel_failures(app: &AppHandle, managed: &ManagedClient) {
+    let profile_id = managed.profile.name.clone();
+    let mut events = managed.client.stats.events.subscribe();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let (tunnel_id, cause, error, stopped) = match events.recv().await {
+                Ok(ClientEvent::TunnelFailed {
+                    tunnel_id,
+                    cause,
+                    error,
+                }) => (tunnel_id, cause, error, false),
+                Ok(ClientEvent::ReconnectExhausted {
+                    tunnel_id,
+                    cause,
+                    error,
+                }) => (tunnel_id, cause, error, true),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            let event = TunnelFailureEvent {
+                profile_id: profile_id.clone(),
+                tunnel_id,
+                message: messages::failure_cause(app.state::<Messages>().locale(), &cause),
+                cause,
+                error,
+                stopped,
+            };
+            if let Err(err) = app.emit(TUNNEL_FAILURE_EVENT, event) {
+                warn!("Cannot report tunnel failure: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Apply the edit of a connected profile. When only its tunnels changed, they are reconciled
+/// without dropping the connection to the server nor the listeners of the unchanged tunnels.
+/// Otherwise the profile is connected again.
+#[tauri::command]
+pub async fn update_profile(
+    profile: Profile,
+    app: AppHandle,
+    manager: State<'_, ClientManager>,
+    system_proxy: State<'_, SystemProxy>,
+    pac_server: State<'_, PacServer>,
+) -> Result<ConnectionInfo, String> {
+    // An isolated profile runs in its own process, which is started again with the edited profile
+    if profile.isolated || app.state::<RelayProcesses>().contains(&profile.name) {
+        return connect(profile, app, manager, system_proxy, pac_server).await;
+    }
+    let previous = manager
+        .get(&profile.name)
+        .ok_or_else(|| format!("Profile {} is not connected", profile.name))?;
+    if !reload::only_tunnels_changed(&previous.profile, &profile) {
+        return connect(profile, app, manager, system_proxy, pac_server).await;
+    }
+
+    let expanded = placeholders::expand(&profile).map_err(|err| format!("{:?}", err))?;
+    let (client, result) = reload::reconcile(&previous.client, &previous.expanded, &expanded).await;
+    manager.insert(profile.clone(), expanded, client);
+    let managed = manager
+        .get(&profile.name)
+        .ok_or_else(|| format!("Profile {} has been disconnected", profile.name))?;
+
+    let proxied = profile.enabled_tunnels().any(|t| t.set_system_proxy);
+    let system_proxy_result = if proxied {
+        apply_system_proxy(&managed, &system_proxy)
+    } else {
+        system_proxy.release(&profile.name)
+    };
+    if let Err(err) = system_proxy_result {
+        warn!("Cannot update system proxy: {:?}", err);
+    }
+    pac_server
+        .publish(&managed)
+        .map_err(|err| format!("{:?}", err))?;
+
+    result.map_err(|err| format!("{:?}", err))?;
+    Ok(ConnectionInfo::from(&managed))
+}
+
+/// Save a secret in the keychain, for the profiles to refer to it as `${secret:NAME}`
+#[tauri::command]
+pub fn set_secret(name: String, value: String) -> Result<(), String> {
+    placeholders::set_secret(&name, &value).map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn delete_secret(name: String) -> Result<(), String> {
+    placeholders::delete_secret(&name).map_err(|err| format!("{:?}", err))
+}
+
+/// Enable every tunnel of a group of the profile, connecting the profile when it is not
+#[tauri::command]
+pub async fn start_group(
+    profile: Profile,
+    group: String,
+    app: AppHandle,
+    manager: State<'_, ClientManager>,
+    system_proxy: State<'_, SystemProxy>,
+    pac_server: State<'_, PacServer>,
+) -> Result<ConnectionInfo, String> {
+    let profile =
+        groups::with_group_enabled(&profile, &group, true).map_err(|err| format!("{:?}", err))?;
+    if manager.get(&profile.name).is_some() {
+        update_profile(profile, app, manager, system_proxy, pac_server).await
+    } else {
+        connect(profile, app, manager, system_proxy, pac_server).await
+    }
+}
+
+/// Disable every tunnel of a group of a connected profile, the other tunnels keep running
+#[tauri::command]
+pub async fn stop_group(
+    profile: Profile,
+    group: String,
+    app: AppHandle,
+    manager: State<'_, ClientManager>,
+    system_proxy: State<'_, SystemProxy>,
+    pac_server: State<'_, PacServer>,
+) -> Result<ConnectionInfo, String> {
+    let profile =
+        groups::with_group_enabled(&profile, &group, false).map_err(|err| format!("{:?}", err))?;
+    update_profile(profile, app, manager, system_proxy, pac_server).await
+}
+
+/// Traffic of the tunnel groups of a connected profile
+#[tauri::command]
+pub fn get_group_stats(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<Vec<GroupStats>, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    Ok(groups::group_stats(
+        &managed.expanded,
+        &managed.client.stats,
+    ))
+}
+
+/// Connect every profile or none of them, the ones connected before the failure are put back as they were
+#[tauri::command]
+pub async fn connect_many(profiles: Vec<Profile>, app: AppHandle) -> Result<BulkReport, String> {
+    bulk::connect_many(&app, profiles)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Disconnect every connected profile, or connect again the ones disconnected before the failure
+#[tauri::command]
+pub async fn disconnect_all(app: AppHandle) -> Result<BulkReport, String> {
+    bulk::disconnect_all(&app)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Save a bundle of profiles and apply it to the connected ones, restoring the saved profiles and the connections on failure.
+/// A bundle may come from anywhere: it cannot bring hooks the saved profiles do not already have, those are added
+/// from the profile editor or approved as the settings of a received profile
+#[tauri::command]
+pub async fn apply_profiles_bundle(
+    profiles: Vec<Profile>,
+    app: AppHandle,
+) -> Result<BulkReport, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    // Without saved profiles, any hook is new
+    let saved = profile_store::load_profiles(&data_dir).unwrap_or_default();
+    for profile in &profiles {
+        let previous = saved.iter().find(|saved| saved.name == profile.name);
+        let brings_hook = |hook: &Option<Hook>, saved_hook: fn(&Profile) -> &Option<Hook>| {
+            hook.is_some() && previous.map(saved_hook) != Some(hook)
+        };
+        if brings_hook(&profile.before_connect, |saved| &saved.before_connect)
+            || brings_hook(&profile.after_disconnect, |saved| &saved.after_disconnect)
+        {
+            return Err(format!(
+                "Profile {} of the bundle brings a hook, which must be added from the profile editor",
+                profile.name
+            ));
+        }
+    }
+    bulk::apply_profiles_bundle(&app, profiles)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn list_tunnel_presets() -> Vec<PresetInfo> {
+    TunnelPreset::ALL
+        .into_iter()
+        .map(TunnelPreset::info)
+        .collect()
+}
+
+/// Tunnel to a service of a host behind the server, with the port and options of the preset, to add to a profile
+#[tauri::command]
+pub fn create_tunnel_from_preset(
+    preset: TunnelPreset,
+    remote_host: String,
+    remote_port: Option<u16>,
+    local_port: Option<u16>,
+) -> Result<TunnelConfig, String> {
+    presets::create(preset, &remote_host, remote_port, local_port)
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Hash of a password for the users of a socks5 or http proxy tunnel, as written by `htpasswd -B`
+#[tauri::command]
+pub async fn hash_listener_password(password: String) -> Result<String, String> {
+    tauri::async_runtime::spawn_blocking(move || listener_auth::hash_password(&password))
+        .await
+        .map_err(|err| format!("{:?}", err))?
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Attach a tunnel to a connected profile without saving it, it is gone once the profile is disconnected
+#[tauri::command]
+pub async fn create_temp_tunnel(
+    profile_id: String,
+    spec: String,
+    reverse: bool,
+    manager: State<'_, ClientManager>,
+) -> Result<TempTunnel, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    let (prepared, temp) =
+        temp_tunnels::prepare(&managed.expanded, &managed.client, &spec, reverse)
+            .await
+            .map_err(|err| format!("{:?}", err))?;
+    manager
+        .update(&profile_id, |current| {
+            // Connected again while the tunnel was bound, it belonged to the previous connection
+            if !Arc::ptr_eq(&current.client.stats, &managed.client.stats) {
+                return Err(format!("Profile {} connected again", profile_id));
+            }
+            temp_tunnels::attach(&mut current.client, prepared, temp.clone());
+            Ok(())
+        })
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))??;
+    Ok(temp)
+}
+
+#[tauri::command]
+pub fn remove_temp_tunnel(
+    profile_id: String,
+    tunnel_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<(), String> {
+    let removed = manager
+        .update(&profile_id, |managed| {
+            temp_tunnels::remove(&mut managed.client, &tunnel_id)
+        })
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    if !removed {
+        return Err(format!(
+            "No temporary tunnel {} in profile {}",
+            tunnel_id, profile_id
+        ));
+    }
+    Ok(())
+}
+
+/// Hand over a profile received through a deep link, once the user accepted it, for the frontend to save.
+/// Only the held settings the user approved one by one are put back. With `connect`, confirmed by the user when the
+/// link asks for it, the profile is connected too, failures being reported like the ones of autoconnect.
+#[tauri::command]
+pub fn confirm_profile_import(
+    import_id: String,
+    approved: Vec<HeldKind>,
+    connect: bool,
+    app: AppHandle,
+    imports: State<'_, DeepLinkImports>,
+) -> Result<Profile, String> {
+    let import = imports
+        .take(&import_id)
+        .ok_or_else(|| format!("Unknown profile import {}", import_id))?;
+    let profile = import
+        .approve(&approved)
+        .map_err(|err| format!("{:?}", err))?;
+    if connect {
+        let profile = profile.clone();
+        tauri::async_runtime::spawn(async move {
+            autostart::connect_profiles(&app, vec![profile]).await;
+        });
+    }
+    Ok(profile)
+}
+
+/// Forget the OAuth2 refresh token of a profile, its next connection asks for authorization again
+#[tauri::command]
+pub fn oauth_sign_out(profile_id: String) -> Result<(), String> {
+    auth::sign_out(&profile_id).map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn reject_profile_import(import_id: String, imports: State<'_, DeepLinkImports>) {
+    imports.take(&import_id);
+}
+
+#[tauri::command]
+pub fn disconnect(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+    relays: State<'_, RelayProcesses>,
+    system_proxy: State<'_, SystemProxy>,
+    pac_server: State<'_, PacServer>,
+    kill_switch: State<'_, KillSwitch>,
+    history: State<'_, ConnectionHistory>,
+) -> Result<(), String> {
+    // Profiles going through this one would be left without a way to their server
+    let connected: Vec<Profile> = upstreams(&manager, &relays)
+        .into_iter()
+        .map(|upstream| upstream.profile)
+        .collect();
+    for dependent in chain::dependents(&profile_id, &connected) {
+        warn!(
+            "Disconnecting profile {} which goes through profile {}",
+            dependent, profile_id
+        );
+        disconnect_one(
+            &dependent,
+            &manager,
+            &relays,
+            &system_proxy,
+            &pac_server,
+            &kill_switch,
+            Some(&history),
+        )?;
+    }
+    disconnect_one(
+        &profile_id,
+        &manager,
+        &relays,
+        &system_proxy,
+        &pac_server,
+        &kill_switch,
+        Some(&history),
+    )
+}
+
+fn disconnect_one(
+    profile_id: &str,
+    manager: &ClientManager,
+    relays: &RelayProcesses,
+    system_proxy: &SystemProxy,
+    pac_server: &PacServer,
+    kill_switch: &KillSwitch,
+    history: Option<&ConnectionHistory>,
+) -> Result<(), String> {
+    // Released before the tunnels stop, so a profile whose rules cannot be removed keeps its way out through them
+    kill_switch
+        .release(profile_id)
+        .map_err(|err| format!("Cannot release the kill switch: {:?}", err))?;
+    let isolated = relays
+        .list()
+        .into_iter()
+        .find(|status| status.profile.name == profile_id);
+    if relays.stop(profile_id) {
+        if let Some(status) = isolated {
+            if let Some(history) = history {
+                history.record(HistoryEntry::disconnected(
+                    profile_id,
+                    &status.remote_addr,
+                    &status.traffic,
+                ));
+            }
+            run_after_disconnect(&status.profile);
+        }
+        return Ok(());
+    }
+    let managed = manager
+        .remove(profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    managed.client.shutdown();
+    run_after_disconnect(&managed.profile);
+    if let Some(history) = history {
+        history.record(HistoryEntry::disconnected(
+            profile_id,
+            managed.client.remote_addr.as_str(),
+            &managed.client.stats.traffic_snapshot(),
+        ));
+    }
+    pac_server.unpublish(profile_id);
+    system_proxy
+        .release(profile_id)
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// The disconnection does not wait for the hook, whose failure is only logged
+fn run_after_disconnect(profile: &Profile) {
+    if let Some(hook) = profile.after_disconnect.clone() {
+        let profile_id = profile.name.clone();
+        tauri::async_runtime::spawn(async move {
+            let _ = hook.run(&profile_id, HookStage::AfterDisconnect).await;
+        });
+    }
+}
+
+#[tauri::command]
+pub async fn refresh_connections(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<(), String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    managed
+        .client
+        .engine
+        .refresh_connections()
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Replace the application rules of a connected profile, applying to the next connections to its proxy tunnels
+#[tauri::command]
+pub fn set_app_rules(
+    profile_id: String,
+    routing: AppRouting,
+    manager: State<'_, ClientManager>,
+) -> Result<(), String> {
+    routing.validate().map_err(|err| format!("{:?}", err))?;
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    let mut current = managed.client.stats.app_routing.lock();
+    if current.is_none() {
+        return Err(format!(
+            "Profile {} has been connected without application rules, connect it again to route its applications",
+            profile_id
+        ));
+    }
+    *current = Some(routing);
+    Ok(())
+}
+
+/// Throughput of the local relay of the tcp tunnels with each of the buffer settings, the defaults and larger
+/// buffers when none are given
+#[tauri::command]
+pub async fn benchmark_buffers(
+    candidates: Option<Vec<BufferTuning>>,
+) -> Result<Vec<BufferBenchmark>, String> {
+    buffers::benchmark(candidates.unwrap_or_default())
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn get_pool_status(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<PoolStatus, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    Ok(managed.client.engine.pool_status())
+}
+
+#[tauri::command]
+pub async fn warm_pool(
+    profile_id: String,
+    count: u32,
+    manager: State<'_, ClientManager>,
+) -> Result<PoolStatus, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    managed
+        .client
+        .engine
+        .warm_pool(count)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    Ok(managed.client.engine.pool_status())
+}
+
+#[tauri::command]
+pub async fn drain_pool(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<PoolStatus, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    managed
+        .client
+        .engine
+        .drain_pool()
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    Ok(managed.client.engine.pool_status())
+}
+
+/// Inject a failure in a connected profile, to check that reconnections and notifications behave as expected.
+/// Only available in debug builds or when the app is started with `--diagnostics`.
+#[tauri::command]
+pub async fn inject_fault(
+    profile_id: String,
+    fault: Fault,
+    manager: State<'_, ClientManager>,
+) -> Result<(), String> {
+    if !faults::diagnostics_enabled() {
+        return Err(format!(
+            "Fault injection is disabled, start the app with {}",
+            faults::DIAGNOSTICS_ARG
+        ));
+    }
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    warn!("Injecting fault {:?} in profile {}", fault, profile_id);
+
+    let stats = &managed.client.stats;
+    match fault {
+        Fault::DropConnections => stats.faults.dropped.notify_waiters(),
+        Fault::DelayFrames {
+            delay_ms,
+            duration_sec,
+        } => stats.faults.delay_frames(
+            Duration::from_millis(delay_ms),
+            Duration::from_secs(duration_sec),
+        ),
+        Fault::FailDns { duration_sec } => managed
+            .client
+            .engine
+            .fail_dns(Duration::from_secs(duration_sec))
+            .await
+            .map_err(|err| format!("{:?}", err))?,
+    }
+    Ok(())
+}
+
+/// Time each server takes to answer an upgrade request, reached with the settings of the profile.
+/// The servers of the profile are probed when none are given
+#[tauri::command]
+pub async fn probe_servers(
+    profile: Profile,
+    servers: Option<Vec<Url>>,
+) -> Result<Vec<ServerProbe>, String> {
+    let servers = servers.unwrap_or_else(|| {
+        std::iter::once(profile.server_addr.clone())
+            .chain(profile.server_candidates.iter().cloned())
+            .collect()
+    });
+    let client = placeholders::expand(&profile)
+        .and_then(|expanded| expanded.to_client())
+        .map_err(|err| format!("{:?}", err))?;
+    WsClientApi::probe_servers(Box::new(client), &servers)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// First step of the setup wizard: the format of the server url
+#[tauri::command]
+pub fn setup_check_url(url: String) -> UrlCheck {
+    setup::check_url(&url)
+}
+
+#[tauri::command]
+pub async fn setup_check_reachability(url: Url) -> ReachabilityCheck {
+    setup::check_reachability(&url).await
+}
+
+#[tauri::command]
+pub async fn setup_check_upgrade(
+    url: Url,
+    verify_certificate: bool,
+    path_prefix: Option<String>,
+) -> UpgradeCheck {
+    setup::check_upgrade(&url, verify_certificate, path_prefix.as_deref()).await
+}
+
+/// Every check of the setup wizard at once, with the configuration suggested for the new profile
+#[tauri::command]
+pub async fn setup_suggest_config(url: String) -> SetupReport {
+    setup::suggest(&url).await
+}
+
+#[tauri::command]
+pub fn get_connection_quality(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<QualityReport, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    Ok(quality::evaluate(&managed.client.stats))
+}
+
+/// Latest measurements of the link to the server of a connected profile, for a sparkline of its latency
+#[tauri::command]
+pub fn get_link_quality(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<LinkQuality, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    Ok(quality::link_quality(&managed.client.stats))
+}
+
+/// Interfaces a connected multipath profile reaches the server through, empty for the other profiles
+#[tauri::command]
+pub fn get_multipath_status(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<Vec<PathStatus>, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    Ok(managed
+        .client
+        .stats
+        .multipath
+        .get()
+        .map(|paths| paths.status())
+        .unwrap_or_default())
+}
+
+/// Capture the timeline of the next connection accepted by a local tunnel, returning the id of the trace
+#[tauri::command]
+pub fn trace_next_connection(
+    profile_id: String,
+    tunnel_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<String, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    let is_local_tunnel = managed
+        .expanded
+        .enabled_tunnels()
+        .filter(|t| !t.reverse)
+        .filter_map(|t| t.to_tunnel().ok())
+        .any(|t| t.id == tunnel_id);
+    if !is_local_tunnel {
+        return Err(format!(
+            "Profile {} has no local tunnel {}",
+            profile_id, tunnel_id
+        ));
+    }
+    Ok(managed.client.stats.traces.arm(&tunnel_id))
+}
+
+/// Write what a local tunnel relays to a pcapng file, until `stop_capture`. Connections already open are captured
+/// from then on
+#[tauri::command]
+pub async fn start_capture(
+    profile_id: String,
+    tunnel_id: String,
+    path: PathBuf,
+    manager: State<'_, ClientManager>,
+) -> Result<(), String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    let is_local_tunnel = managed
+        .expanded
+        .enabled_tunnels()
+        .filter(|t| !t.reverse)
+        .filter_map(|t| t.to_tunnel().ok())
+        .any(|t| t.id == tunnel_id);
+    if !is_local_tunnel {
+        return Err(format!(
+            "Profile {} has no local tunnel {}",
+            profile_id, tunnel_id
+        ));
+    }
+    managed
+        .client
+        .stats
+        .captures
+        .start(&tunnel_id, &path)
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub async fn stop_capture(
+    profile_id: String,
+    tunnel_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<CaptureSummary, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    managed
+        .client
+        .stats
+        .captures
+        .stop(&tunnel_id)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Datagram settings of a udp tunnel of a connected profile, with the count of oversized datagrams
+#[tauri::command]
+pub fn get_datagram_status(
+    profile_id: String,
+    tunnel_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<DatagramStatus, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    let stats = &managed.client.stats;
+    stats
+        .datagrams
+        .status(&tunnel_id, &stats.tunnel_metrics(&tunnel_id))
+        .ok_or_else(|| format!("Tunnel {} is not a udp tunnel", tunnel_id))
+}
+
+/// Idle timeout of the flows of a udp tunnel, applied to the open ones too. It lasts until the tunnel restarts
+#[tauri::command]
+pub fn set_udp_timeout(
+    profile_id: String,
+    tunnel_id: String,
+    timeout_sec: u64,
+    manager: State<'_, ClientManager>,
+) -> Result<(), String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    managed
+        .client
+        .stats
+        .datagrams
+        .set_timeout(&tunnel_id, timeout_sec)
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Live connections of a reverse tunnel of a connected profile
+#[tauri::command]
+pub fn get_active_connections(
+    profile_id: String,
+    tunnel_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<Vec<ActiveConnection>, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    Ok(managed.client.stats.connections.list(Some(&tunnel_id)))
+}
+
+/// Live connections of every tunnel of the connected profiles
+#[tauri::command]
+pub fn list_connections(manager: State<'_, ClientManager>) -> Vec<ProfileConnection> {
+    manager
+        .list()
+        .into_iter()
+        .flat_map(|managed| {
+            let profile_id = managed.profile.name.clone();
+            managed
+                .client
+                .stats
+                .connections
+                .list(None)
+                .into_iter()
+                .map(move |connection| ProfileConnection {
+                    profile_id: profile_id.clone(),
+                    connection,
+                })
+        })
+        .collect()
+}
+
+/// Drop a single connection, its tunnel keeps running
+#[tauri::command]
+pub fn kill_connection(
+    connection_id: u64,
+    manager: State<'_, ClientManager>,
+) -> Result<(), String> {
+    if manager
+        .list()
+        .iter()
+        .any(|managed| managed.client.stats.connections.kill(connection_id))
+    {
+        Ok(())
+    } else {
+        Err(format!("No live connection {}", connection_id))
+    }
+}
+
+#[tauri::command]
+pub fn get_connection_trace(
+    profile_id: String,
+    trace_id: String,
+    manager: State<'_, ClientManager>,
+) -> Result<ConnectionTrace, String> {
+    let managed = manager
+        .get(&profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    managed
+        .client
+        .stats
+        .traces
+        .get(&trace_id)
+        .ok_or_else(|| format!("Unknown trace {}", trace_id))
+}
+
+#[tauri::command]
+pub async fn get_pac_url(
+    profile_id: String,
+    pac_server: State<'_, PacServer>,
+) -> Result<String, String> {
+    pac_server
+        .url(&profile_id)
+        .await
+        .map(|url| url.to_string())
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Publish the tunnel metrics on `http://127.0.0.1:<port>/metrics`, returning the url to scrape
+#[tauri::command]
+pub async fn start_metrics_endpoint(
+    port: u16,
+    app: AppHandle,
+    metrics: State<'_, MetricsServer>,
+) -> Result<String, String> {
+    let local_addr = metrics
+        .start(app.clone(), port)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    Ok(format!("http://{}/metrics", local_addr))
+}
+
+#[tauri::command]
+pub fn stop_metrics_endpoint(metrics: State<'_, MetricsServer>) {
+    metrics.stop();
+}
+
+/// Let scripts and other apps drive the tunnels through `http://127.0.0.1:<port>`, returning the url and the token
+#[tauri::command]
+pub async fn start_control_api(
+    port: u16,
+    app: AppHandle,
+    control_api: State<'_, ControlApi>,
+) -> Result<ControlEndpoint, String> {
+    control_api
+        .start(app.clone(), port)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn stop_control_api(control_api: State<'_, ControlApi>) {
+    control_api.stop();
+}
+
+/// Traffic of every profile over a period, summed per hour, day, week or month, for the usage charts
+#[tauri::command]
+pub async fn query_stats(
+    range: TimeRange,
+    granularity: Granularity,
+    app: AppHandle,
+) -> Result<Vec<UsagePoint>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        app.state::<StatsStore>().query(range, granularity)
+    })
+    .await
+    .map_err(|err| format!("{:?}", err))?
+    .map_err(|err| format!("{:?}", err))
+}
+
+/// How much of its monthly budget a profile used this month, None when it has no budget
+#[tauri::command]
+pub async fn get_budget_usage(
+    profile: Profile,
+    app: AppHandle,
+) -> Result<Option<BudgetUsage>, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        stats_store::budget_usage(&app.state::<StatsStore>(), &profile)
+    })
+    .await
+    .map_err(|err| format!("{:?}", err))?
+    .map_err(|err| format!("{:?}", err))
+}
+
+/// Tell which resolver answers the names resolved outside the tunnel, and whether the socks tunnels
+/// of a connected profile let applications use it
+#[tauri::command]
+pub async fn check_dns_leak(
+    profile_id: String,
+    manager: State<'_, ClientManager>,
+    relays: State<'_, RelayProcesses>,
+) -> Result<DnsLeakReport, String> {
+    if let Some(managed) = manager.get(&profile_id) {
+        return Ok(dns_leak::check(&managed.expanded, Some(managed.client.stats.as_ref())).await);
+    }
+    let relay = relays
+        .list()
+        .into_iter()
+        .find(|relay| relay.profile.name == profile_id)
+        .ok_or_else(|| format!("Profile {} is not connected", profile_id))?;
+    Ok(dns_leak::check(&relay.expanded, None).await)
+}
+
+/// Run the client against a wstunnel server started on the loopback interface, telling whether the tunnels work
+/// on this machine when a remote server cannot be used
+#[tauri::command]
+pub async fn run_selftest() -> Result<SelfTestReport, String> {
+    Ok(selftest::run().await)
+}
+
+/// Write a zip of the recent logs and the sanitized configuration of the connected profiles, to attach to a bug report
+#[tauri::command]
+pub fn export_diagnostics(path: PathBuf, app: AppHandle) -> Result<(), String> {
+    diagnostics::export(&app, &path).map_err(|err| format!("{:?}", err))
+}
+
+/// Change the log level of a module and its submodules while the app runs, i.e: `wstunnel::tunnel` at `debug`.
+/// Without a level, the module is logged at the default level again
+#[tauri::command]
+pub fn set_log_level(target: String, level: Option<String>) -> Result<(), String> {
+    diagnostics::set_log_level(&target, level.as_deref()).map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn get_log_levels() -> BTreeMap<String, String> {
+    diagnostics::log_levels()
+}
+
+#[tauri::command]
+pub fn get_status(
+    manager: State<'_, ClientManager>,
+    relays: State<'_, RelayProcesses>,
+) -> Vec<ProfileStatus> {
+    profile_statuses(&manager, &relays)
+}
+
+/// Status of every connected profile, isolated ones included, as shown by any window
+pub fn profile_statuses(manager: &ClientManager, relays: &RelayProcesses) -> Vec<ProfileStatus> {
+    let isolated = relays.list().into_iter().map(|status| ProfileStatus {
+        connection: ConnectionInfo::from(&status),
+        traffic: status.traffic,
+        reverse_tunnels: status.reverse_tunnels,
+    });
+    manager
+        .list()
+        .iter()
+        .map(|managed| ProfileStatus {
+            connection: ConnectionInfo::from(managed),
+            traffic: managed.client.stats.traffic_snapshot(),
+            reverse_tunnels: managed.client.stats.reverse_tunnels.list(),
+        })
+        .chain(isolated)
+        .collect()
+}
+
+/// Explain how the traffic of a profile to a destination would flow: which tunnel carries it, whether the PAC file
+/// bypasses it, who resolves its name and how the server is reached. Connected profiles are explained as connected,
+/// others as saved.
+#[tauri::command]
+pub async fn explain_route(
+    profile_id: String,
+    target: String,
+    app: AppHandle,
+    manager: State<'_, ClientManager>,
+    relays: State<'_, RelayProcesses>,
+) -> Result<RouteExplanation, String> {
+    let connected = manager.get(&profile_id).map(|m| m.expanded).or_else(|| {
+        relays
+            .list()
+            .into_iter()
+            .find(|status| status.profile.name == profile_id)
+            .map(|status| status.expanded)
+    });
+    let profile = match connected {
+        Some(profile) => profile,
+        None => {
+            let data_dir = app
+                .path()
+                .app_data_dir()
+                .map_err(|err| format!("{:?}", err))?;
+            let saved = profile_store::load_profiles(&data_dir)
+                .map_err(|err| format!("{:?}", err))?
+                .into_iter()
+                .find(|p| p.name == profile_id)
+                .ok_or_else(|| format!("No profile named {}", profile_id))?;
+            placeholders::expand(&saved).map_err(|err| format!("{:?}", err))?
+        }
+    };
+    route::explain(&profile, &target)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// ssh config stanza sending the hosts matching the pattern through a saved profile
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SshConfigStanza {
+    pub stanza: String,
+    /// Config file the stanza was written to, when asked to
+    pub path: Option<PathBuf>,
+}
+
+#[tauri::command]
+pub fn generate_ssh_config(
+    profile_id: String,
+    host_pattern: String,
+    mode: SshProxyMode,
+    write: bool,
+    app: AppHandle,
+) -> Result<SshConfigStanza, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    // The bridge loads the saved profile when ssh starts it
+    if !profile_store::load_profiles(&data_dir)
+        .map_err(|err| format!("{:?}", err))?
+        .iter()
+        .any(|p| p.name == profile_id)
+    {
+        return Err(format!("No saved profile named {}", profile_id));
+    }
+    let stanza = ssh_config::stanza(&profile_id, &host_pattern, &mode)
+        .map_err(|err| format!("{:?}", err))?;
+    let path = if write {
+        Some(ssh_config::install(&host_pattern, &stanza).map_err(|err| format!("{:?}", err))?)
+    } else {
+        None
+    };
+    Ok(SshConfigStanza { stanza, path })
+}
+
+/// Remove the stanza written to the ssh config for the pattern
+#[tauri::command]
+pub fn remove_ssh_config(host_pattern: String) -> Result<(), String> {
+    ssh_config::uninstall(&host_pattern).map_err(|err| format!("{:?}", err))
+}
+
+/// Where external widgets read the status of the connected profiles from
+#[tauri::command]
+pub fn get_status_file_path(app: AppHandle) -> Result<PathBuf, String> {
+    status_file::status_path(&app).map_err(|err| format!("{:?}", err))
+}
+
+/// Accept the certificate with that fingerprint for the server, for profiles not verifying certificates
+#[tauri::command]
+pub fn trust_server_certificate(
+    server: String,
+    fingerprint: String,
+    app: AppHandle,
+) -> Result<(), String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    server_trust::trust(&data_dir, &server, &fingerprint).map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn forget_server_certificate(server: String, app: AppHandle) -> Result<(), String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    server_trust::forget(&data_dir, &server).map_err(|err| format!("{:?}", err))
+}
+
+/// Certificate chain of a server, captured even when it does not verify, to review before trusting it
+#[tauri::command]
+pub async fn inspect_server_certificate(url: Url) -> Result<CertificateChain, String> {
+    server_trust::inspect(&url)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Parse a tunnel as the user types it, reporting where it is wrong and what was expected there
+#[tauri::command]
+pub fn parse_tunnel_spec(input: String, reverse: Option<bool>) -> TunnelSpecCheck {
+    parsers::check_tunnel_spec(&input, reverse.unwrap_or(false))
+}
+
+#[tauri::command]
+pub fn open_stats_window(app: AppHandle) -> Result<(), String> {
+    stats_panel::open_window(&app).map_err(|err| format!("{:?}", err))
+}
+
+/// Receive the status of the connected profiles every second in the calling window, as `stats-updated` events
+#[tauri::command]
+pub fn subscribe_stats(
+    window: WebviewWindow,
+    app: AppHandle,
+    subscribers: State<'_, StatsSubscribers>,
+) {
+    subscribers.subscribe(&app, window.label());
+}
+
+#[tauri::command]
+pub fn unsubscribe_stats(window: WebviewWindow, subscribers: State<'_, StatsSubscribers>) {
+    subscribers.unsubscribe(window.label());
+}
+
+#[tauri::command]
+pub fn get_capabilities() -> Vec<Capability> {
+    platform::compiled_capabilities()
+}
+
+/// Whether socket marks and transparent proxying can be used, the process needing CAP_NET_ADMIN on Linux
+#[tauri::command]
+pub fn get_net_admin_status() -> NetAdminStatus {
+    net_admin::status()
+}
+
+/// Set CAP_NET_ADMIN on the binary of the app, polkit asking for an administrator. Applies once restarted
+#[tauri::command]
+pub async fn grant_net_admin() -> Result<(), String> {
+    net_admin::grant().await.map_err(|err| format!("{:?}", err))
+}
+
+/// Transparent proxy rules installed from the app, none when the traffic is not redirected
+#[tauri::command]
+pub async fn get_tproxy_rules(app: AppHandle) -> Result<Option<TproxyRules>, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    tproxy_rules::installed(&data_dir)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Remove the rules left behind, i.e: by a crash of the app. The rules of a profile are otherwise removed once
+/// it disconnects
+#[tauri::command]
+pub async fn remove_tproxy_rules(app: AppHandle) -> Result<(), String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    tproxy_rules::remove(&data_dir)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn get_transports() -> Vec<TransportKind> {
+    transport::available_transports()
+}
+
+#[tauri::command]
+pub fn get_autostart(app: AppHandle) -> Result<bool, String> {
+    app.autolaunch()
+        .is_enabled()
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Start the app at login, connecting the profiles marked for autoconnect
+#[tauri::command]
+pub fn set_autostart(enabled: bool, app: AppHandle) -> Result<(), String> {
+    let autolaunch = app.autolaunch();
+    let result = if enabled {
+        autolaunch.enable()
+    } else {
+        autolaunch.disable()
+    };
+    result.map_err(|err| format!("{:?}", err))
+}
+
+/// Whether each scheduled profile is in an active window, and when it is next connected or disconnected
+#[tauri::command]
+pub fn get_schedules(app: AppHandle) -> Result<Vec<ScheduleStatus>, String> {
+    scheduler::statuses(&app).map_err(|err| format!("{:?}", err))
+}
+
+/// Profiles that were connected when the app crashed or the machine rebooted, not reconnected nor dismissed yet
+#[tauri::command]
+pub fn get_interrupted_session(session: State<'_, Session>) -> Vec<String> {
+    session.interrupted()
+}
+
+/// Reconnect the profiles of the interrupted session, failures are reported like the ones of autoconnect
+#[tauri::command]
+pub async fn restore_interrupted_session(app: AppHandle) -> Result<(), String> {
+    session::reconnect(&app)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn dismiss_interrupted_session(session: State<'_, Session>) {
+    session.take_interrupted();
+}
+
+#[tauri::command]
+pub fn get_session_recovery(session: State<'_, Session>) -> RecoveryMode {
+    session.recovery()
+}
+
+/// Choose whether the profiles of an interrupted session are reconnected on next launch, offered or forgotten
+#[tauri::command]
+pub fn set_session_recovery(
+    recovery: RecoveryMode,
+    session: State<'_, Session>,
+) -> Result<(), String> {
+    session
+        .set_recovery(recovery)
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Called once the updater installed the new version, instead of restarting the app.
+/// The new version takes the running tunnels over before this one exits.
+#[tauri::command]
+pub async fn upgrade_handoff(app: AppHandle) -> Result<(), String> {
+    handoff::hand_over(&app)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Whether the background daemon is installed and running, with the profiles it runs so the app can attach to them
+#[tauri::command]
+pub async fn get_daemon_status(app: AppHandle) -> Result<DaemonStatus, String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    Ok(daemon::status(&data_dir, &app.config().identifier).await)
+}
+
+/// Run the tunnels in a background process started with the session, which keeps them up once the app is closed
+#[tauri::command]
+pub fn install_daemon(app: AppHandle) -> Result<(), String> {
+    let binary = tauri::process::current_binary(&app.env()).map_err(|err| format!("{:?}", err))?;
+    daemon::install(&binary, &app.config().identifier).map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn uninstall_daemon(app: AppHandle) -> Result<(), String> {
+    daemon::uninstall(&app.config().identifier).map_err(|err| format!("{:?}", err))
+}
+
+/// Connect a profile in the background daemon. The app authorizes it and confirms the server certificate first,
+/// the daemon only gets the authorized profile.
+#[tauri::command]
+pub async fn daemon_connect(profile: Profile, app: AppHandle) -> Result<DaemonProfile, String> {
+    let expanded = placeholders::expand(&profile).map_err(|err| format!("{:?}", err))?;
+    let authorized = auth::authorize(&expanded, |prompt| {
+        if let Err(err) = app.emit(auth::DEVICE_CODE_EVENT, prompt) {
+            warn!("Cannot ask for authorization: {:?}", err);
+        }
+    })
+    .await
+    .map_err(|err| format!("{:?}", err))?;
+    // The daemon runs apart from the profiles connected in the app, a profile going through one of them cannot connect
+    let mut authorized = chain::resolve(&authorized, &[]).map_err(|err| format!("{:?}", err))?;
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    authorized
+        .tls_pinned_certificates
+        .extend(server_trust::pinned(&profile, &data_dir));
+    let untrusted = server_trust::untrusted_certificate(&profile, &data_dir)
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    if let Some(certificate) = untrusted {
+        let server = certificate.server.clone();
+        let event = ServerTrustEvent {
+            profile_id: profile.name.clone(),
+            certificate,
+        };
+        if let Err(err) = app.emit(SERVER_TRUST_EVENT, event) {
+            warn!("Cannot ask to trust server certificate: {:?}", err);
+        }
+        return Err(format!(
+            "The certificate of {} must be confirmed before connecting",
+            server
+        ));
+    }
+    daemon::call(
+        &data_dir,
+        DaemonCall::Connect {
+            profile: authorized,
+        },
+    )
+    .await
+    .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub async fn daemon_disconnect(profile_id: String, app: AppHandle) -> Result<(), String> {
+    let data_dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|err| format!("{:?}", err))?;
+    daemon::call(&data_dir, DaemonCall::Disconnect { profile_id })
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+#[tauri::command]
+pub fn get_managed_sources(sources: State<'_, ManagedSources>) -> Vec<ManagedSource> {
+    sources.list()
+}
+
+/// Fetch profiles from an administrator, signed with the given ed25519 key, and keep them up to date
+#[tauri::command]
+pub async fn add_managed_source(
+    url: Url,
+    public_key: String,
+    poll_interval_sec: Option<u64>,
+    app: AppHandle,
+) -> Result<(), String> {
+    app.state::<ManagedSources>()
+        .add(url.clone(), public_key, poll_interval_sec)
+        .map_err(|err| format!("{:?}", err))?;
+    managed_profiles::sync(&app, &url)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Stop fetching the profiles of a source, the profiles it had are disconnected and removed
+#[tauri::command]
+pub fn remove_managed_source(url: Url, app: AppHandle) -> Result<(), String> {
+    managed_profiles::remove_source(&app, &url).map_err(|err| format!("{:?}", err))
+}
+
+/// Fetch the profiles of a source now rather than at its next poll
+#[tauri::command]
+pub async fn refresh_managed_source(url: Url, app: AppHandle) -> Result<(), String> {
+    managed_profiles::sync(&app, &url)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Approve the settings of a managed profile that run commands, load code or read local files, as the source sends
+/// them now. They are held back again whenever the source changes them
+#[tauri::command]
+pub async fn approve_managed_settings(
+    url: Url,
+    profile_id: String,
+    app: AppHandle,
+) -> Result<(), String> {
+    managed_profiles::approve_settings(&app, &url, &profile_id)
+        .await
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Connections, disconnections and link losses of the profiles, the most recent first
+#[tauri::command]
+pub async fn get_connection_history(
+    filter: HistoryFilter,
+    app: AppHandle,
+) -> Result<Vec<HistoryEntry>, String> {
+    tauri::async_runtime::spawn_blocking(move || app.state::<ConnectionHistory>().query(&filter))
+        .await
+        .map_err(|err| format!("{:?}", err))?
+        .map_err(|err| format!("{:?}", err))
+}
+
+/// Write the connection history matching the filter to a CSV file, returning how many entries it holds
+#[tauri::command]
+pub async fn export_connection_history(
+    filter: HistoryFilter,
+    path: PathBuf,
+    app: AppHandle,
+) -> Result<usize, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        app.state::<ConnectionHistory>().export_csv(&filter, &path)
+    })
+    .await
+    .map_err(|err| format!("{:?}", err))?
+    .map_err(|err| format!("{:?}", err))
+}
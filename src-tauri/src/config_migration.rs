@@ -0,0 +1,248 @@
+use crate::client::placeholders;
+use crate::profile_store::{PROFILE_STORE, PROFILE_STORE_KEY};
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the saved profiles written by this version of the app, stored next to them
+pub const SCHEMA_VERSION: u64 = 2;
+const SCHEMA_VERSION_KEY: &str = "schema_version";
+/// Directory of the app data the profile store is copied into before being migrated
+const BACKUP_DIR: &str = "config-backups";
+/// Last migration done, kept for the frontend to show what changed
+const MIGRATION_REPORT_FILE: &str = "config-migration.json";
+
+/// Upgrade of the saved profiles from the previous schema version to `version`
+struct Migration {
+    version: u64,
+    description: &'static str,
+    /// Change the saved profiles in place, returning what was changed
+    apply: fn(&mut [Value]) -> Vec<String>,
+}
+
+/// Every migration, in order. A new one is appended with the next version, and SCHEMA_VERSION bumped to it
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description:
+            "Pin the id of the tunnels to their spec, so editing a spec keeps the history of its tunnel",
+        apply: pin_tunnel_ids,
+    },
+    Migration {
+        version: 2,
+        description: "Move the PINs of the PKCS#11 tokens out of the profiles, into the keychain",
+        apply: move_pkcs11_pins,
+    },
+];
+/// Name of the keychain secret the PIN of the token of a profile is moved to
+const PKCS11_PIN_SECRET: &str = "pkcs11-pin-";
+
+/// Migration applied to the saved profiles
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationStep {
+    pub version: u64,
+    pub description: String,
+    pub changes: Vec<String>,
+}
+
+/// Migration of the saved profiles done when the app started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationReport {
+    pub from_version: u64,
+    pub to_version: u64,
+    /// Unix timestamp in milliseconds
+    pub migrated_at_ms: u128,
+    /// Copy of the profile store as it was before the migration
+    pub backup: Option<PathBuf>,
+    pub steps: Vec<MigrationStep>,
+}
+
+/// Schema version of a profile store, the profiles saved before versioning being at 0
+fn version_of(store: &Value) -> u64 {
+    store
+        .get(SCHEMA_VERSION_KEY)
+        .and_then(Value::as_u64)
+        .unwrap_or(0)
+}
+
+/// Apply to the content of a profile store the migrations it is missing. A store written by a newer version of
+/// the app is left as is, its profiles being read as far as this version understands them
+pub fn migrate_store(store: &mut Value) -> anyhow::Result<Vec<MigrationStep>> {
+    let from = version_of(store);
+    if from >= SCHEMA_VERSION {
+        return Ok(vec![]);
+    }
+    let object = store
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("The profile store is not a JSON object"))?;
+    let mut profiles = match object.remove(PROFILE_STORE_KEY) {
+        Some(Value::Array(profiles)) => profiles,
+        None | Some(Value::Null) => vec![],
+        Some(_) => return Err(anyhow!("The saved profiles are not a list")),
+    };
+    let steps = MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > from)
+        .map(|migration| MigrationStep {
+            version: migration.version,
+            description: migration.description.to_string(),
+            changes: (migration.apply)(&mut profiles),
+        })
+        .collect();
+    object.insert(PROFILE_STORE_KEY.to_string(), Value::Array(profiles));
+    object.insert(SCHEMA_VERSION_KEY.to_string(), SCHEMA_VERSION.into());
+    Ok(steps)
+}
+
+/// Migrate the profile store of the data directory on startup, before anything reads it. The store is copied
+/// to the backup directory first and replaced at once, so an interrupted migration leaves it untouched.
+/// Returns the report of the migration, if one was needed
+pub fn migrate(data_dir: &Path) -> anyhow::Result<Option<MigrationReport>> {
+    let path = data_dir.join(PROFILE_STORE);
+    let content = match std::fs::read(&path) {
+        Ok(content) => content,
+        // Nothing saved yet, the frontend writes the current version
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err).with_context(|| format!("Cannot read {}", path.display())),
+    };
+    let mut store: Value = serde_json::from_slice(&content)
+        .with_context(|| format!("Invalid profile store {}", path.display()))?;
+    let from = version_of(&store);
+    if from > SCHEMA_VERSION {
+        warn!(
+            "Profiles saved by a newer version of the app (schema {}), settings it added are ignored",
+            from
+        );
+        return Ok(None);
+    }
+    if from == SCHEMA_VERSION {
+        return Ok(None);
+    }
+
+    let migrated_at_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    let backup_dir = data_dir.join(BACKUP_DIR);
+    std::fs::create_dir_all(&backup_dir)?;
+    let backup = backup_dir.join(format!("{}.v{}.{}", PROFILE_STORE, from, migrated_at_ms));
+    std::fs::write(&backup, &content)
+        .with_context(|| format!("Cannot back the profiles up to {}", backup.display()))?;
+
+    let steps = migrate_store(&mut store)?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&store)?)
+        .with_context(|| "Cannot save migrated profiles")?;
+    std::fs::rename(&tmp, &path)?;
+
+    let report = MigrationReport {
+        from_version: from,
+        to_version: SCHEMA_VERSION,
+        migrated_at_ms,
+        backup: Some(backup),
+        steps,
+    };
+    std::fs::write(
+        data_dir.join(MIGRATION_REPORT_FILE),
+        serde_json::to_vec_pretty(&report)?,
+    )?;
+    info!(
+        "Saved profiles migrated from schema {} to {}",
+        from, SCHEMA_VERSION
+    );
+    Ok(Some(report))
+}
+
+/// Report of the last migration of the saved profiles, if any happened
+pub fn last_report(data_dir: &Path) -> anyhow::Result<Option<MigrationReport>> {
+    let path = data_dir.join(MIGRATION_REPORT_FILE);
+    match std::fs::read(&path) {
+        Ok(content) => Ok(Some(serde_json::from_slice(&content).with_context(
+            || format!("Invalid migration report {}", path.display()),
+        )?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("Cannot read {}", path.display())),
+    }
+}
+
+/// Tunnels without id are identified by their spec, which changes along with it
+fn pin_tunnel_ids(profiles: &mut [Value]) -> Vec<String> {
+    let mut changes = Vec::new();
+    for profile in profiles.iter_mut() {
+        let name = profile
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let Some(tunnels) = profile.get_mut("tunnels").and_then(Value::as_array_mut) else {
+            continue;
+        };
+        for tunnel in tunnels.iter_mut().filter_map(Value::as_object_mut) {
+            if tunnel.get("id").is_some_and(|id| !id.is_null()) {
+                continue;
+            }
+            let Some(spec) = tunnel
+                .get("spec")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+            else {
+                continue;
+            };
+            changes.push(format!(
+                "Tunnel {} of profile {} given its spec as id",
+                spec, name
+            ));
+            tunnel.insert("id".to_string(), Value::String(spec));
+        }
+    }
+    changes
+}
+
+/// PKCS#11 sources had their PIN in the profile, they now name the keychain secret holding it. A PIN which cannot be
+/// saved in the keychain is dropped, the user is asked to save it again
+fn move_pkcs11_pins(profiles: &mut [Value]) -> Vec<String> {
+    let mut changes = Vec::new();
+    for profile in profiles.iter_mut() {
+        let name = profile
+            .get("name")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        let Some(source) = profile
+            .get_mut("tlsPrivateKeySource")
+            .and_then(Value::as_object_mut)
+            .filter(|source| source.get("kind").and_then(Value::as_str) == Some("pkcs11"))
+        else {
+            continue;
+        };
+        let Some(Value::String(pin)) = source.remove("pin") else {
+            continue;
+        };
+        let secret = format!("{}{}", PKCS11_PIN_SECRET, name);
+        match placeholders::set_secret(&secret, &pin) {
+            Ok(()) => {
+                source.insert("pinSecret".to_string(), Value::String(secret.clone()));
+                changes.push(format!(
+                    "PIN of the token of profile {} moved to keychain secret {}",
+                    name, secret
+                ));
+            }
+            Err(err) => {
+                warn!(
+                    "Cannot save the PIN of profile {} in the keychain: {:?}",
+                    name, err
+                );
+                changes.push(format!(
+                    "PIN of the token of profile {} removed, it could not be saved in the keychain",
+                    name
+                ));
+            }
+        }
+    }
+    changes
+}
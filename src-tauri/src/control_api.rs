@@ -0,0 +1,279 @@
+use crate::client::accept::AcceptBackoff;
+use crate::client::credentials;
+use crate::client::manager::ClientManager;
+use crate::commands;
+use crate::history::ConnectionHistory;
+use crate::kill_switch::KillSwitch;
+use crate::pac::PacServer;
+use crate::profile_store;
+use crate::relay::RelayProcesses;
+use crate::system_proxy::SystemProxy;
+use anyhow::{anyhow, Context};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const TOKEN_FILE: &str = "control-api.token";
+const TOKEN_BYTES: usize = 32;
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+/// Time given to a client to send its request once connected
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the control api listens and the token its clients must send
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ControlEndpoint {
+    pub url: String,
+    pub token: String,
+    /// File holding the token, readable by the user only, for scripts to pick it up
+    pub token_path: PathBuf,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SavedProfile {
+    profile_id: String,
+    connected: bool,
+}
+
+/// Optional loopback api to drive the tunnels from scripts and other apps, each request carrying the token as
+/// `Authorization: Bearer <token>`.
+///
+/// - `GET /profiles`: saved profiles and whether they are connected
+/// - `POST /profiles/<id>/connect` and `POST /profiles/<id>/disconnect`
+/// - `GET /stats`: connections and traffic of the connected profiles
+#[derive(Default)]
+pub struct ControlApi {
+    running: Mutex<Option<(SocketAddr, ControlEndpoint, JoinHandle<()>)>>,
+}
+
+impl ControlApi {
+    /// Serve the api on the given port of the loopback interface, moving it if it runs on another port.
+    /// The token is kept from one start to the next so the scripts using it keep working.
+    pub async fn start(&self, app: AppHandle, port: u16) -> anyhow::Result<ControlEndpoint> {
+        if let Some((local_addr, endpoint, _)) = &*self.running.lock() {
+            if port == local_addr.port() {
+                return Ok(endpoint.clone());
+            }
+        }
+        self.stop();
+
+        let token_path = app.path().app_data_dir()?.join(TOKEN_FILE);
+        let token = load_or_create_token(&token_path)?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .await
+            .with_context(|| format!("Cannot bind control api on port {}", port))?;
+        let local_addr = listener.local_addr()?;
+        info!("Serving control api on http://{}", local_addr);
+
+        let expected = token.clone();
+        let task = tokio::spawn(async move {
+            let mut backoff = AcceptBackoff::default();
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => {
+                        backoff.succeeded();
+                        accepted
+                    }
+                    Err(err) => {
+                        warn!("Cannot accept control api connection: {:?}", err);
+                        backoff.failed().await;
+                        continue;
+                    }
+                };
+                let app = app.clone();
+                let expected = expected.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve(stream, &app, &expected).await {
+                        debug!("Cannot serve control api to {}: {:?}", peer, err);
+                    }
+                });
+            }
+        });
+        let endpoint = ControlEndpoint {
+            url: format!("http://{}", local_addr),
+            token,
+            token_path,
+        };
+        *self.running.lock() = Some((local_addr, endpoint.clone(), task));
+        Ok(endpoint)
+    }
+
+    pub fn stop(&self) {
+        if let Some((local_addr, _, task)) = self.running.lock().take() {
+            info!("Control api on {} stopped", local_addr);
+            task.abort();
+        }
+    }
+}
+
+fn load_or_create_token(path: &Path) -> anyhow::Result<String> {
+    if let Ok(token) = std::fs::read_to_string(path) {
+        if !token.trim().is_empty() {
+            // Written readable by others by former versions
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+            }
+            return Ok(token.trim().to_string());
+        }
+    }
+    let mut bytes = [0u8; TOKEN_BYTES];
+    getrandom::getrandom(&mut bytes)?;
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Created readable by the user only, then moved in place, so the token is never readable by others
+    let tmp = path.with_extension("tmp");
+    let _ = std::fs::remove_file(&tmp);
+    credentials::write_private(&tmp, &token)?;
+    std::fs::rename(&tmp, path)
+        .with_context(|| format!("Cannot write control api token to {}", path.display()))?;
+    Ok(token)
+}
+
+/// Compare without returning early, so the time taken tells nothing about the token
+fn same_token(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Answer a single request, the connection is closed afterward
+async fn serve(mut stream: TcpStream, app: &AppHandle, token: &str) -> anyhow::Result<()> {
+    let Ok(request) = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await else {
+        return Ok(());
+    };
+    let Some(request) = request? else {
+        return Ok(());
+    };
+
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or_default().split(' ');
+    let (method, path) = (
+        request_line.next().unwrap_or_default(),
+        request_line.next().unwrap_or_default(),
+    );
+    let authorized = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, value)| value.trim().strip_prefix("Bearer "))
+        .is_some_and(|given| same_token(given.trim(), token));
+
+    let (status, body) = if authorized {
+        match route(app, method, path).await {
+            Ok(Some(body)) => ("200 OK", body),
+            Ok(None) => ("404 Not Found", error_body("Not found")),
+            Err(err) => ("400 Bad Request", error_body(&err.to_string())),
+        }
+    } else {
+        ("401 Unauthorized", error_body("Missing or invalid token"))
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Head of the request, None when the client closed the connection or sent too much
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    Ok(Some(request))
+}
+
+fn error_body(error: &str) -> String {
+    serde_json::json!({ "error": error }).to_string()
+}
+
+/// Json body of the answer to a request, None when there is no such endpoint
+async fn route(app: &AppHandle, method: &str, path: &str) -> anyhow::Result<Option<String>> {
+    let segments: Vec<String> = path
+        .trim_matches('/')
+        .split('/')
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+    let body = match (method, segments.as_slice()) {
+        ("GET", ["profiles"]) => {
+            let connected = commands::profile_statuses(
+                &app.state::<ClientManager>(),
+                &app.state::<RelayProcesses>(),
+            );
+            let profiles: Vec<SavedProfile> =
+                profile_store::load_profiles(&app.path().app_data_dir()?)?
+                    .into_iter()
+                    .map(|profile| SavedProfile {
+                        connected: connected
+                            .iter()
+                            .any(|status| status.connection.profile_id == profile.name),
+                        profile_id: profile.name,
+                    })
+                    .collect();
+            serde_json::to_string(&profiles)?
+        }
+        ("POST", ["profiles", profile_id, "connect"]) => {
+            let profile = profile_store::load_profiles(&app.path().app_data_dir()?)?
+                .into_iter()
+                .find(|profile| profile.name == *profile_id)
+                .ok_or_else(|| anyhow!("No saved profile named {}", profile_id))?;
+            let connection = commands::connect(
+                profile,
+                app.clone(),
+                app.state::<ClientManager>(),
+                app.state::<SystemProxy>(),
+                app.state::<PacServer>(),
+            )
+            .await
+            .map_err(|err| anyhow!(err))?;
+            serde_json::to_string(&connection)?
+        }
+        ("POST", ["profiles", profile_id, "disconnect"]) => {
+            commands::disconnect(
+                profile_id.to_string(),
+                app.state::<ClientManager>(),
+                app.state::<RelayProcesses>(),
+                app.state::<SystemProxy>(),
+                app.state::<PacServer>(),
+                app.state::<KillSwitch>(),
+                app.state::<ConnectionHistory>(),
+            )
+            .map_err(|err| anyhow!(err))?;
+            "{}".to_string()
+        }
+        ("GET", ["stats"]) => serde_json::to_string(&commands::profile_statuses(
+            &app.state::<ClientManager>(),
+            &app.state::<RelayProcesses>(),
+        ))?,
+        _ => return Ok(None),
+    };
+    Ok(Some(body))
+}
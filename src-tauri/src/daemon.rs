@@ -0,0 +1,724 @@
+use crate::client::accept::AcceptBackoff;
+use crate::client::client_api::{BoundListener, ConnectedClient, WsClientApi};
+use crate::client::hooks::HookStage;
+use crate::client::manager::ClientManager;
+use crate::client::profile::Profile;
+use crate::client::stats::TrafficSnapshot;
+use anyhow::{anyhow, Context};
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::sync::Notify;
+
+/// Given to the app to run the tunnel engine in the background, controlled by the app over a local socket
+pub const DAEMON_ARG: &str = "--daemon";
+const JSONRPC_VERSION: &str = "2.0";
+/// Error codes of JSON-RPC 2.0
+const PARSE_ERROR: i64 = -32700;
+const SERVER_ERROR: i64 = -32000;
+
+/// Methods of the daemon, sent by the app as JSON-RPC 2.0 requests, one json document per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "snake_case")]
+pub enum DaemonCall {
+    /// Profile to run, already authorized so the daemon never needs the keychain nor the user
+    Connect {
+        profile: Profile,
+    },
+    Disconnect {
+        #[serde(rename = "profileId")]
+        profile_id: String,
+    },
+    List,
+    Shutdown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    id: u64,
+    #[serde(flatten)]
+    call: DaemonCall,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcResponse {
+    jsonrpc: String,
+    id: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+/// A profile running in the daemon
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DaemonProfile {
+    pub profile_id: String,
+    pub remote_addr: String,
+    pub listeners: Vec<BoundListener>,
+    pub traffic: TrafficSnapshot,
+}
+
+/// Whether the daemon is installed to start with the session, and the profiles it runs when it is reachable
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DaemonStatus {
+    pub installed: bool,
+    pub running: bool,
+    pub profiles: Vec<DaemonProfile>,
+}
+
+struct Daemon {
+    manager: ClientManager,
+    /// Profiles being connected, a profile being connected once at a time
+    connecting: Mutex<HashSet<String>>,
+    stopped: Notify,
+    data_dir: PathBuf,
+}
+
+impl Daemon {
+    async fn handle(&self, call: DaemonCall) -> anyhow::Result<Value> {
+        match call {
+            DaemonCall::Connect { profile } => {
+                // Its rules are kept by the app, which removes them when it exits or after a crash
+                if profile.kill_switch {
+                    return Err(anyhow!(
+                        "Profile {} has a kill switch, which is only available from the app",
+                        profile.name
+                    ));
+                }
+                let profile_id = profile.name.clone();
+                if !self.connecting.lock().insert(profile_id.clone()) {
+                    return Err(anyhow!("Profile {} is already connecting", profile_id));
+                }
+                // The previous connection of the profile holds the local ports the new one binds
+                if let Some(previous) = self.manager.remove(&profile_id) {
+                    previous.client.stop().await;
+                }
+                let connected = self.connect(&profile).await;
+                // Taken out by a disconnect received while connecting
+                let cancelled = !self.connecting.lock().remove(&profile_id);
+                let connected = connected?;
+                if cancelled {
+                    connected.shutdown();
+                    return Err(anyhow!(
+                        "Profile {} has been disconnected while connecting",
+                        profile_id
+                    ));
+                }
+                info!(
+                    "Profile {} connected to {}",
+                    profile.name, connected.remote_addr
+                );
+                // The app sends the profile with its placeholders already expanded
+                if let Some(previous) = self.manager.insert(profile.clone(), profile, connected) {
+                    previous.client.shutdown();
+                }
+                let running = self
+                    .profile(&profile_id)
+                    .ok_or_else(|| anyhow!("Profile {} has been disconnected", profile_id))?;
+                Ok(serde_json::to_value(running)?)
+            }
+            DaemonCall::Disconnect { profile_id } => {
+                let was_connecting = self.connecting.lock().remove(&profile_id);
+                match self.manager.remove(&profile_id) {
+                    Some(managed) => {
+                        managed.client.shutdown();
+                        run_after_disconnect(&managed.profile);
+                    }
+                    None if was_connecting => {}
+                    None => return Err(anyhow!("Profile {} is not running", profile_id)),
+                }
+                info!("Profile {} disconnected", profile_id);
+                Ok(Value::Null)
+            }
+            DaemonCall::List => Ok(serde_json::to_value(self.profiles())?),
+            DaemonCall::Shutdown => {
+                self.stopped.notify_one();
+                Ok(Value::Null)
+            }
+        }
+    }
+
+    async fn connect(&self, profile: &Profile) -> anyhow::Result<ConnectedClient> {
+        self.manager
+            .confirm_connect(&self.data_dir, profile)
+            .await?;
+        if let Some(hook) = &profile.before_connect {
+            hook.run(&profile.name, HookStage::BeforeConnect).await?;
+        }
+        let client = profile.to_client()?;
+        WsClientApi::connect(Box::new(client), |step| debug!("{:?}", step)).await
+    }
+
+    fn profile(&self, profile_id: &str) -> Option<DaemonProfile> {
+        self.profiles()
+            .into_iter()
+            .find(|profile| profile.profile_id == profile_id)
+    }
+
+    fn profiles(&self) -> Vec<DaemonProfile> {
+        self.manager
+            .list()
+            .iter()
+            .map(|managed| DaemonProfile {
+                profile_id: managed.profile.name.clone(),
+                remote_addr: managed.client.remote_addr.to_string(),
+                listeners: managed.client.listeners.clone(),
+                traffic: managed.client.stats.traffic_snapshot(),
+            })
+            .collect()
+    }
+}
+
+/// The disconnection does not wait for the hook, whose failure is only logged
+fn run_after_disconnect(profile: &Profile) {
+    if let Some(hook) = profile.after_disconnect.clone() {
+        let profile_id = profile.name.clone();
+        tokio::spawn(async move {
+            let _ = hook.run(&profile_id, HookStage::AfterDisconnect).await;
+        });
+    }
+}
+
+/// Entry point of the daemon: serve the app on the local socket until asked to stop or terminated.
+/// The daemon outlives the windows of the app, which attaches to it again when started.
+pub fn run(app_identifier: &str) -> anyhow::Result<()> {
+    let data_dir = data_dir(app_identifier)?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let mut listener = platform::Listener::bind(&data_dir).await?;
+        info!("Daemon listening on {}", platform::name(&data_dir));
+        let daemon = Arc::new(Daemon {
+            manager: ClientManager::default(),
+            connecting: Mutex::default(),
+            stopped: Notify::new(),
+            data_dir: data_dir.clone(),
+        });
+        let mut backoff = AcceptBackoff::default();
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(stream) => {
+                        backoff.succeeded();
+                        tokio::spawn(serve(daemon.clone(), stream));
+                    }
+                    Err(err) => {
+                        warn!("Cannot accept app connection: {:?}", err);
+                        backoff.failed().await;
+                    }
+                },
+                _ = daemon.stopped.notified() => break,
+                res = wait_for_shutdown() => {
+                    res?;
+                    break;
+                }
+            }
+        }
+        let running = daemon.manager.list();
+        for managed in &running {
+            managed.client.shutdown();
+        }
+        // The daemon exits right after, the hooks are waited for
+        for managed in &running {
+            if let Some(hook) = &managed.profile.after_disconnect {
+                let _ = hook
+                    .run(&managed.profile.name, HookStage::AfterDisconnect)
+                    .await;
+            }
+        }
+        info!("Daemon stopped {} profiles", running.len());
+        Ok(())
+    })
+}
+
+/// Answer the requests of one app connection, in order, until it is closed
+async fn serve<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(daemon: Arc<Daemon>, stream: S) {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(err) => {
+                debug!("App connection closed: {:?}", err);
+                return;
+            }
+        };
+        let response = match serde_json::from_str::<RpcRequest>(&line) {
+            Ok(request) => match daemon.handle(request.call).await {
+                Ok(result) => RpcResponse {
+                    jsonrpc: JSONRPC_VERSION.to_string(),
+                    id: Some(request.id),
+                    result: Some(result),
+                    error: None,
+                },
+                Err(err) => RpcResponse {
+                    jsonrpc: JSONRPC_VERSION.to_string(),
+                    id: Some(request.id),
+                    result: None,
+                    error: Some(RpcError {
+                        code: SERVER_ERROR,
+                        message: format!("{:?}", err),
+                    }),
+                },
+            },
+            Err(err) => RpcResponse {
+                jsonrpc: JSONRPC_VERSION.to_string(),
+                id: None,
+                result: None,
+                error: Some(RpcError {
+                    code: PARSE_ERROR,
+                    message: err.to_string(),
+                }),
+            },
+        };
+        if let Err(err) = send(&mut writer, &response).await {
+            error!("Cannot answer the app: {:?}", err);
+            return;
+        }
+    }
+}
+
+/// Call a method of the daemon running for the user, failing when none is running
+pub async fn call<T: DeserializeOwned>(data_dir: &Path, call: DaemonCall) -> anyhow::Result<T> {
+    let stream = platform::connect(data_dir)
+        .await
+        .context("The daemon is not running")?;
+    let (reader, mut writer) = tokio::io::split(stream);
+    let request = RpcRequest {
+        jsonrpc: JSONRPC_VERSION.to_string(),
+        id: 1,
+        call,
+    };
+    send(&mut writer, &request).await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("The daemon closed the connection"))?;
+    let response: RpcResponse =
+        serde_json::from_str(&line).with_context(|| "Invalid daemon response")?;
+    if let Some(error) = response.error {
+        return Err(anyhow!(error.message));
+    }
+    Ok(serde_json::from_value(
+        response.result.unwrap_or(Value::Null),
+    )?)
+}
+
+/// State of the daemon, without failing when it is not running
+pub async fn status(data_dir: &Path, app_identifier: &str) -> DaemonStatus {
+    let profiles = call::<Vec<DaemonProfile>>(data_dir, DaemonCall::List).await;
+    DaemonStatus {
+        installed: platform::installed(app_identifier),
+        running: profiles.is_ok(),
+        profiles: profiles.unwrap_or_default(),
+    }
+}
+
+/// Register the daemon to start with the session of the user and start it now
+pub fn install(binary: &Path, app_identifier: &str) -> anyhow::Result<()> {
+    platform::install(binary, app_identifier)
+}
+
+/// Stop the daemon and remove it from the session of the user
+pub fn uninstall(app_identifier: &str) -> anyhow::Result<()> {
+    platform::uninstall(app_identifier)
+}
+
+fn data_dir(app_identifier: &str) -> anyhow::Result<PathBuf> {
+    Ok(dirs::data_dir()
+        .ok_or_else(|| anyhow!("Cannot find the data directory of the user"))?
+        .join(app_identifier))
+}
+
+async fn send<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // SIGTERM is what systemd and launchd send to stop the daemon
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown() -> anyhow::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+#[cfg(unix)]
+mod platform {
+    use anyhow::{anyhow, Context};
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+    use std::path::{Path, PathBuf};
+    use tokio::net::{UnixListener, UnixStream};
+
+    /// Directory of the socket, which only the user can enter
+    const SOCKET_DIR: &str = "daemon";
+    const SOCKET_FILE: &str = "daemon.sock";
+
+    pub fn name(data_dir: &Path) -> String {
+        socket_path(data_dir).to_string_lossy().into_owned()
+    }
+
+    fn socket_path(data_dir: &Path) -> PathBuf {
+        data_dir.join(SOCKET_DIR).join(SOCKET_FILE)
+    }
+
+    /// The socket is created with the umask of the process, reachable by anyone until its permissions are set:
+    /// it is bound in a directory nobody else can enter
+    fn private_socket_dir(data_dir: &Path) -> anyhow::Result<()> {
+        std::fs::create_dir_all(data_dir)?;
+        let dir = data_dir.join(SOCKET_DIR);
+        match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(err) => {
+                return Err(err).with_context(|| format!("Cannot create {}", dir.display()));
+            }
+        }
+        let metadata = std::fs::symlink_metadata(&dir)?;
+        // SAFETY: getuid cannot fail
+        let uid = unsafe { libc::getuid() };
+        if !metadata.is_dir() || metadata.uid() != uid {
+            return Err(anyhow!("{} does not belong to this user", dir.display()));
+        }
+        if metadata.permissions().mode() & 0o077 != 0 {
+            std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))?;
+        }
+        Ok(())
+    }
+
+    pub struct Listener {
+        path: PathBuf,
+        listener: UnixListener,
+    }
+
+    impl Listener {
+        pub async fn bind(data_dir: &Path) -> anyhow::Result<Self> {
+            private_socket_dir(data_dir)?;
+            let path = socket_path(data_dir);
+            if UnixStream::connect(&path).await.is_ok() {
+                return Err(anyhow!("A daemon is already running"));
+            }
+            // Left over by a daemon that did not exit cleanly
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path)?;
+            // Only the user may drive the tunnels, even once the directory is moved around
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            Ok(Self { path, listener })
+        }
+
+        pub async fn accept(&mut self) -> anyhow::Result<UnixStream> {
+            let (stream, _) = self.listener.accept().await?;
+            Ok(stream)
+        }
+    }
+
+    impl Drop for Listener {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    pub async fn connect(data_dir: &Path) -> anyhow::Result<UnixStream> {
+        Ok(UnixStream::connect(socket_path(data_dir)).await?)
+    }
+
+    pub fn installed(app_identifier: &str) -> bool {
+        unit_path(app_identifier).is_some_and(|path| path.exists())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn unit_path(app_identifier: &str) -> Option<PathBuf> {
+        Some(
+            dirs::config_dir()?
+                .join("systemd/user")
+                .join(format!("{}.daemon.service", app_identifier)),
+        )
+    }
+
+    /// Systemd user unit, started with the session of the user and again whenever it dies
+    #[cfg(target_os = "linux")]
+    pub fn install(binary: &Path, app_identifier: &str) -> anyhow::Result<()> {
+        use crate::system_proxy::{cmd, run};
+
+        let path = unit_path(app_identifier)
+            .ok_or_else(|| anyhow!("Cannot find the configuration directory of the user"))?;
+        let unit = format!(
+            "[Unit]\nDescription=wstunnel desktop tunnels\n\n[Service]\nExecStart=\"{}\" {}\nRestart=on-failure\n\n[Install]\nWantedBy=default.target\n",
+            binary.display(),
+            super::DAEMON_ARG
+        );
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, unit)?;
+        let unit_name = format!("{}.daemon.service", app_identifier);
+        run(&cmd(&["systemctl", "--user", "daemon-reload"]))?;
+        run(&cmd(&[
+            "systemctl",
+            "--user",
+            "enable",
+            "--now",
+            &unit_name,
+        ]))?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn uninstall(app_identifier: &str) -> anyhow::Result<()> {
+        use crate::system_proxy::{cmd, run};
+
+        let unit_name = format!("{}.daemon.service", app_identifier);
+        run(&cmd(&[
+            "systemctl",
+            "--user",
+            "disable",
+            "--now",
+            &unit_name,
+        ]))?;
+        if let Some(path) = unit_path(app_identifier) {
+            let _ = std::fs::remove_file(path);
+        }
+        run(&cmd(&["systemctl", "--user", "daemon-reload"]))?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    fn unit_path(app_identifier: &str) -> Option<PathBuf> {
+        Some(
+            dirs::home_dir()?
+                .join("Library/LaunchAgents")
+                .join(format!("{}.daemon.plist", app_identifier)),
+        )
+    }
+
+    /// Launchd agent, loaded with the session of the user and kept alive
+    #[cfg(target_os = "macos")]
+    pub fn install(binary: &Path, app_identifier: &str) -> anyhow::Result<()> {
+        use crate::system_proxy::{cmd, run};
+
+        let path = unit_path(app_identifier)
+            .ok_or_else(|| anyhow!("Cannot find the home directory of the user"))?;
+        let plist = format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}.daemon</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+        <string>{}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+</dict>
+</plist>
+"#,
+            app_identifier,
+            binary.display(),
+            super::DAEMON_ARG
+        );
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&path, plist)?;
+        let path = path.to_string_lossy();
+        run(&cmd(&["launchctl", "load", "-w", &path]))?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "macos")]
+    pub fn uninstall(app_identifier: &str) -> anyhow::Result<()> {
+        use crate::system_proxy::{cmd, run};
+
+        let path = unit_path(app_identifier)
+            .ok_or_else(|| anyhow!("Cannot find the home directory of the user"))?;
+        run(&cmd(&[
+            "launchctl",
+            "unload",
+            "-w",
+            &path.to_string_lossy(),
+        ]))?;
+        let _ = std::fs::remove_file(path);
+        Ok(())
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    fn unit_path(_app_identifier: &str) -> Option<PathBuf> {
+        None
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn install(_binary: &Path, _app_identifier: &str) -> anyhow::Result<()> {
+        Err(anyhow!("The daemon cannot be installed on this platform"))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    pub fn uninstall(_app_identifier: &str) -> anyhow::Result<()> {
+        Err(anyhow!("The daemon cannot be installed on this platform"))
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use crate::system_proxy::{cmd, run};
+    use anyhow::{anyhow, Context};
+    use std::path::Path;
+    use tokio::net::windows::named_pipe::{
+        ClientOptions, NamedPipeClient, NamedPipeServer, ServerOptions,
+    };
+
+    /// Only the owner of the pipe, the user running the daemon, may open it. The default security of a pipe lets
+    /// everyone read it
+    const OWNER_ONLY: &str = "D:P(A;;GA;;;OW)";
+
+    /// One pipe per user
+    pub fn name(data_dir: &Path) -> String {
+        let user = std::env::var("USERNAME").unwrap_or_default();
+        let app = data_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        format!(r"\\.\pipe\{}.daemon.{}", app, user)
+    }
+
+    pub struct Listener {
+        name: String,
+        next: NamedPipeServer,
+    }
+
+    impl Listener {
+        pub async fn bind(data_dir: &Path) -> anyhow::Result<Self> {
+            let name = name(data_dir);
+            // Fails when another process, a daemon or not, created the pipe first
+            let next = create(ServerOptions::new().first_pipe_instance(true), &name)
+                .map_err(|err| anyhow!("A daemon is already running: {}", err))?;
+            Ok(Self { name, next })
+        }
+
+        /// Wait for the app on the current instance of the pipe, creating the next one before handing it out
+        pub async fn accept(&mut self) -> anyhow::Result<NamedPipeServer> {
+            self.next.connect().await?;
+            let next = create(&mut ServerOptions::new(), &self.name)?;
+            Ok(std::mem::replace(&mut self.next, next))
+        }
+    }
+
+    /// Instance of the pipe only the user can open, from this machine
+    fn create(options: &mut ServerOptions, name: &str) -> anyhow::Result<NamedPipeServer> {
+        use std::ffi::c_void;
+        use windows_sys::Win32::Foundation::LocalFree;
+        use windows_sys::Win32::Security::Authorization::{
+            ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1,
+        };
+        use windows_sys::Win32::Security::{PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES};
+
+        let sddl: Vec<u16> = OWNER_ONLY.encode_utf16().chain(Some(0)).collect();
+        let mut descriptor: PSECURITY_DESCRIPTOR = std::ptr::null_mut();
+        // SAFETY: the string is null terminated, the descriptor is allocated by the call and freed below
+        let converted = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                std::ptr::null_mut(),
+            )
+        };
+        if converted == 0 {
+            return Err(std::io::Error::last_os_error())
+                .context("Cannot build the security of the pipe");
+        }
+        let mut attributes = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: descriptor,
+            bInheritHandle: 0,
+        };
+        // SAFETY: the attributes and their descriptor outlive the call, which copies them into the pipe
+        let created = unsafe {
+            options
+                .reject_remote_clients(true)
+                .create_with_security_attributes_raw(name, &mut attributes as *mut _ as *mut c_void)
+        };
+        // SAFETY: allocated by ConvertStringSecurityDescriptorToSecurityDescriptorW, not used anymore
+        unsafe { LocalFree(descriptor as _) };
+        Ok(created?)
+    }
+
+    pub async fn connect(data_dir: &Path) -> anyhow::Result<NamedPipeClient> {
+        Ok(ClientOptions::new().open(name(data_dir))?)
+    }
+
+    fn task_name(app_identifier: &str) -> String {
+        format!("{}.daemon", app_identifier)
+    }
+
+    /// Windows services run in a session of their own, away from the profiles and the keychain of the user.
+    /// A scheduled task started at logon runs the daemon as the user instead.
+    pub fn install(binary: &Path, app_identifier: &str) -> anyhow::Result<()> {
+        let task = task_name(app_identifier);
+        let command = format!("\"{}\" {}", binary.display(), super::DAEMON_ARG);
+        run(&cmd(&[
+            "schtasks", "/Create", "/F", "/TN", &task, "/TR", &command, "/SC", "ONLOGON", "/RL",
+            "LIMITED",
+        ]))?;
+        run(&cmd(&["schtasks", "/Run", "/TN", &task]))?;
+        Ok(())
+    }
+
+    pub fn uninstall(app_identifier: &str) -> anyhow::Result<()> {
+        let task = task_name(app_identifier);
+        let _ = run(&cmd(&["schtasks", "/End", "/TN", &task]));
+        run(&cmd(&["schtasks", "/Delete", "/F", "/TN", &task]))?;
+        Ok(())
+    }
+
+    pub fn installed(app_identifier: &str) -> bool {
+        run(&cmd(&[
+            "schtasks",
+            "/Query",
+            "/TN",
+            &task_name(app_identifier),
+        ]))
+        .is_ok()
+    }
+}
@@ -0,0 +1,179 @@
+use crate::client::held_settings::{self, HeldKind, HeldSetting};
+use crate::client::profile::Profile;
+use anyhow::{anyhow, Context};
+use base64::Engine;
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tauri::{AppHandle, Emitter, Url};
+
+pub const SCHEME: &str = "wstunnel";
+/// Sent to the frontend so the user confirms the import, nothing is saved or connected before that
+pub const IMPORT_REQUESTED_EVENT: &str = "profile-import-requested";
+
+/// A profile received through a `wstunnel://connect?config=<base64 json>&autoconnect=true` link.
+/// With `autoconnect`, the user is offered to connect the profile once imported, it is never connected without them.
+#[derive(Debug, Clone)]
+pub struct PendingImport {
+    pub profile: Profile,
+    /// Settings taken out of the profile, put back only once the user approved each one
+    pub held: Vec<HeldSetting>,
+    /// The link asks for the profile to be connected once imported
+    pub autoconnect: bool,
+}
+
+impl PendingImport {
+    pub fn new(mut profile: Profile) -> Self {
+        let held = held_settings::hold(&mut profile);
+        Self {
+            profile,
+            held,
+            autoconnect: false,
+        }
+    }
+
+    /// The profile, with the held settings the user approved put back
+    pub fn approve(mut self, approved: &[HeldKind]) -> anyhow::Result<Profile> {
+        let settings: Vec<HeldSetting> = self
+            .held
+            .into_iter()
+            .filter(|setting| approved.contains(&setting.kind))
+            .collect();
+        held_settings::restore(&mut self.profile, &settings)?;
+        Ok(self.profile)
+    }
+}
+
+/// Where a profile waiting for confirmation comes from
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportSource {
+    Link,
+    QrCode,
+    /// A `wstunnel client` command or a server url copied by the user
+    Clipboard,
+    /// An encrypted profile bundle
+    Bundle,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImportRequest {
+    pub import_id: String,
+    /// Secrets are hidden, the link may come from anyone
+    pub profile: Profile,
+    /// Settings running commands, loading code, reading local files or changing the network, left out unless
+    /// approved one by one
+    pub held: Vec<HeldSetting>,
+    pub source: ImportSource,
+    /// The link asks for the profile to be connected once imported, for the user to confirm along with the import
+    pub autoconnect: bool,
+}
+
+/// Imports waiting for the user confirmation
+#[derive(Default)]
+pub struct DeepLinkImports {
+    next_id: AtomicU64,
+    pending: Mutex<HashMap<String, PendingImport>>,
+}
+
+impl DeepLinkImports {
+    pub fn handle_urls(&self, app: &AppHandle, urls: &[Url]) {
+        for url in urls {
+            if let Err(err) = self.handle_url(app, url, ImportSource::Link) {
+                warn!("Ignoring deep link {}: {:?}", url.scheme(), err);
+            }
+        }
+    }
+
+    /// Ask the user to confirm the import of the profile of a link, returning what the frontend is sent
+    pub fn handle_url(
+        &self,
+        app: &AppHandle,
+        url: &Url,
+        source: ImportSource,
+    ) -> anyhow::Result<ImportRequest> {
+        self.offer(app, parse_url(url)?, source)
+    }
+
+    /// Ask the user to confirm the import of a profile, nothing is saved or connected before that
+    pub fn offer(
+        &self,
+        app: &AppHandle,
+        import: PendingImport,
+        source: ImportSource,
+    ) -> anyhow::Result<ImportRequest> {
+        let import_id = self.next_id.fetch_add(1, Ordering::Relaxed).to_string();
+        info!(
+            "Profile {} received through {:?}, waiting for confirmation",
+            import.profile.name, source
+        );
+
+        let request = ImportRequest {
+            import_id: import_id.clone(),
+            profile: import.profile.redacted(),
+            held: held_settings::redacted(&import.held),
+            source,
+            autoconnect: import.autoconnect,
+        };
+        self.pending.lock().insert(import_id, import);
+        app.emit(IMPORT_REQUESTED_EVENT, request.clone())?;
+        Ok(request)
+    }
+
+    pub fn take(&self, import_id: &str) -> Option<PendingImport> {
+        self.pending.lock().remove(import_id)
+    }
+}
+
+/// Link importing the profile, read back by the app receiving it.
+/// Unset settings are left out, they are read back as unset, which keeps the link short enough for a QR code.
+pub fn profile_url(profile: &Profile) -> anyhow::Result<Url> {
+    let mut config = serde_json::to_value(profile)?;
+    if let Some(fields) = config.as_object_mut() {
+        fields.retain(|_, value| !value.is_null());
+    }
+    let config =
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(serde_json::to_vec(&config)?);
+    let mut url = Url::parse(&format!("{}://connect", SCHEME))?;
+    url.query_pairs_mut().append_pair("config", &config);
+    Ok(url)
+}
+
+fn parse_url(url: &Url) -> anyhow::Result<PendingImport> {
+    if url.scheme() != SCHEME {
+        return Err(anyhow!("Unexpected scheme {}", url.scheme()));
+    }
+    // wstunnel://connect?... is parsed with `connect` as host
+    if url.host_str() != Some("connect") {
+        return Err(anyhow!(
+            "Unknown action {}",
+            url.host_str().unwrap_or_default()
+        ));
+    }
+
+    let config = url
+        .query_pairs()
+        .find(|(key, _)| key == "config")
+        .map(|(_, value)| value.into_owned())
+        .ok_or_else(|| anyhow!("Missing config parameter"))?;
+    let autoconnect = url
+        .query_pairs()
+        .any(|(key, value)| key == "autoconnect" && matches!(value.as_ref(), "1" | "true"));
+    let config = config.trim_end_matches('=');
+    let json = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(config)
+        .or_else(|_| base64::engine::general_purpose::STANDARD_NO_PAD.decode(config))
+        .with_context(|| "Config is not valid base64")?;
+    let profile: Profile =
+        serde_json::from_slice(&json).with_context(|| "Config is not a valid profile")?;
+    // Reject the link right away rather than after the user confirmed it
+    profile.to_client()?;
+
+    Ok(PendingImport {
+        autoconnect,
+        ..PendingImport::new(profile)
+    })
+}
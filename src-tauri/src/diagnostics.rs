@@ -0,0 +1,205 @@
+use crate::client::manager::ClientManager;
+use crate::client::platform::{self, Capability};
+use crate::client::profile::Profile;
+use crate::relay::RelayProcesses;
+use anyhow::{anyhow, Context};
+use log::{info, warn, LevelFilter, Metadata};
+use parking_lot::RwLock;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tauri::plugin::TauriPlugin;
+use tauri::{AppHandle, Manager, Runtime};
+use tauri_plugin_log::{RotationStrategy, Target, TargetKind};
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// Directory of the app data directory the logs are written to
+const LOG_DIR: &str = "logs";
+const LOG_FILE_NAME: &str = "wstunnel-desktop";
+/// The log file is rotated once that big, and the rotated ones deleted after a while
+const MAX_LOG_FILE_SIZE: u128 = 5 * 1024 * 1024;
+const MAX_LOG_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+const MAX_LOG_FILES: usize = 10;
+/// Logs put in a diagnostic bundle, the most recent ones
+const BUNDLED_LOG_FILES: usize = 3;
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+
+/// Level of the modules whose level has been changed at runtime, by target prefix, i.e: `wstunnel::tunnel`
+static LOG_LEVELS: RwLock<BTreeMap<String, LevelFilter>> = RwLock::new(BTreeMap::new());
+
+/// What a bug report needs to know about the machine, without anything identifying the user
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Environment {
+    app_version: &'static str,
+    os: &'static str,
+    os_family: &'static str,
+    arch: &'static str,
+    capabilities: Vec<Capability>,
+    connected_profiles: Vec<String>,
+}
+
+pub fn log_dir(app: &AppHandle) -> tauri::Result<PathBuf> {
+    Ok(app.path().app_data_dir()?.join(LOG_DIR))
+}
+
+/// Log to a file of the app data directory rotated by size, and to the console of development builds
+pub fn log_plugin<R: Runtime>(dir: PathBuf) -> TauriPlugin<R> {
+    let mut builder = tauri_plugin_log::Builder::default()
+        .clear_targets()
+        .target(Target::new(TargetKind::Folder {
+            path: dir,
+            file_name: Some(LOG_FILE_NAME.to_string()),
+        }))
+        .max_file_size(MAX_LOG_FILE_SIZE)
+        .rotation_strategy(RotationStrategy::KeepAll)
+        // Everything reaches the filter, for the levels to be changed without reloading the logger
+        .level(LevelFilter::Trace)
+        .filter(enabled);
+    if cfg!(debug_assertions) {
+        builder = builder.target(Target::new(TargetKind::Stdout));
+    }
+    builder.build()
+}
+
+/// Whether a record is logged, at the level of the most specific target set for its module
+fn enabled(metadata: &Metadata) -> bool {
+    let target = metadata.target();
+    let levels = LOG_LEVELS.read();
+    let level = levels
+        .iter()
+        .filter(|(prefix, _)| {
+            target == prefix.as_str()
+                || target
+                    .strip_prefix(prefix.as_str())
+                    .is_some_and(|rest| rest.starts_with("::"))
+        })
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(DEFAULT_LOG_LEVEL, |(_, level)| *level);
+    metadata.level() <= level
+}
+
+/// Log the module and its submodules at the given level, or at the default level again when none is given
+pub fn set_log_level(target: &str, level: Option<&str>) -> anyhow::Result<()> {
+    let target = target.trim();
+    if target.is_empty() {
+        return Err(anyhow!("The log target cannot be empty"));
+    }
+    match level {
+        Some(level) => {
+            let level = LevelFilter::from_str(level).map_err(|_| {
+                anyhow!(
+                    "Unknown log level {}, expecting off, error, warn, info, debug or trace",
+                    level
+                )
+            })?;
+            info!("Logging {} at level {}", target, level);
+            LOG_LEVELS.write().insert(target.to_string(), level);
+        }
+        None => {
+            LOG_LEVELS.write().remove(target);
+            info!("Logging {} at the default level", target);
+        }
+    }
+    Ok(())
+}
+
+/// Levels changed at runtime, by target
+pub fn log_levels() -> BTreeMap<String, String> {
+    LOG_LEVELS
+        .read()
+        .iter()
+        .map(|(target, level)| (target.clone(), level.to_string().to_ascii_lowercase()))
+        .collect()
+}
+
+/// Log files of the directory, the most recent first
+fn log_files(dir: &Path) -> Vec<(PathBuf, SystemTime)> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut files: Vec<_> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .filter_map(|path| {
+            let modified = path.metadata().and_then(|m| m.modified()).ok()?;
+            Some((path, modified))
+        })
+        .collect();
+    files.sort_by(|a, b| b.1.cmp(&a.1));
+    files
+}
+
+/// Delete the rotated log files too old or too many, the file being written to is always the most recent
+pub fn prune_logs(dir: &Path) {
+    let now = SystemTime::now();
+    for (index, (path, modified)) in log_files(dir).into_iter().enumerate() {
+        let too_old = now
+            .duration_since(modified)
+            .is_ok_and(|age| age > MAX_LOG_AGE);
+        if index > 0 && (too_old || index >= MAX_LOG_FILES) {
+            if let Err(err) = std::fs::remove_file(&path) {
+                warn!("Cannot delete log file {}: {:?}", path.display(), err);
+            }
+        }
+    }
+}
+
+/// Zip the recent logs, the configuration of the connected profiles with their secrets redacted,
+/// and what the machine is, for a bug report
+pub fn export(app: &AppHandle, destination: &Path) -> anyhow::Result<()> {
+    let manager = app.state::<ClientManager>();
+    let relays = app.state::<RelayProcesses>();
+    let profiles: Vec<Profile> = manager
+        .list()
+        .into_iter()
+        .map(|managed| managed.profile.redacted())
+        .chain(
+            relays
+                .list()
+                .into_iter()
+                .map(|relay| relay.profile.redacted()),
+        )
+        .collect();
+    let environment = Environment {
+        app_version: env!("CARGO_PKG_VERSION"),
+        os: std::env::consts::OS,
+        os_family: std::env::consts::FAMILY,
+        arch: std::env::consts::ARCH,
+        capabilities: platform::compiled_capabilities(),
+        connected_profiles: profiles
+            .iter()
+            .map(|profile| profile.name.clone())
+            .collect(),
+    };
+
+    let file = File::create(destination)
+        .with_context(|| format!("Cannot create {}", destination.display()))?;
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("environment.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&environment)?)?;
+    zip.start_file("profiles.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&profiles)?)?;
+    for (path, _) in log_files(&log_dir(app)?)
+        .into_iter()
+        .take(BUNDLED_LOG_FILES)
+    {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        zip.start_file(format!("{}/{}", LOG_DIR, name.to_string_lossy()), options)?;
+        zip.write_all(
+            &std::fs::read(&path).with_context(|| format!("Cannot read {}", path.display()))?,
+        )?;
+    }
+    zip.finish()?;
+    info!("Diagnostics exported to {}", destination.display());
+    Ok(())
+}
@@ -0,0 +1,402 @@
+use crate::client::listener_sockets::listener_sockets;
+use crate::client::manager::ClientManager;
+use crate::client::profile::Profile;
+use crate::commands;
+use crate::kill_switch::KillSwitch;
+use crate::pac::PacServer;
+use crate::relay::RelayProcesses;
+use crate::session::Session;
+use crate::system_proxy::SystemProxy;
+use anyhow::{anyhow, Context};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Manager};
+
+/// Given to the new version of the app started by an upgrade, with the endpoint to take the running tunnels over from
+pub const HANDOFF_ARG: &str = "--handoff";
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(30);
+/// Sent by the new version once the tunnels are running again
+const READY: &[u8] = b"ready";
+
+/// What the new version needs to resume the tunnels of the running one.
+/// Only tcp listeners are handed over, udp ones are bound again.
+#[derive(Debug, Serialize, Deserialize)]
+struct HandoffState {
+    profiles: Vec<Profile>,
+    /// Address of each socket sent along, in the same order
+    listeners: Vec<SocketAddr>,
+}
+
+/// Endpoint to take over from, when the app has been started by an upgrade
+pub fn requested() -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter()
+        .position(|arg| arg == HANDOFF_ARG)
+        .and_then(|i| args.get(i + 1).cloned())
+}
+
+/// Start the installed version of the app and pass it the connected profiles along with their listening sockets,
+/// so local clients keep reaching the tunnels during the upgrade. Exit once the new version is running them.
+pub async fn hand_over(app: &AppHandle) -> anyhow::Result<()> {
+    let manager = app.state::<ClientManager>();
+    let sockets = listener_sockets().owned()?;
+    // Listeners of isolated profiles belong to their relay process, which must exit so the new version binds them again
+    let isolated = app.state::<RelayProcesses>().stop_all().await;
+    let state = HandoffState {
+        profiles: manager
+            .list()
+            .into_iter()
+            .map(|m| m.profile)
+            .chain(isolated.iter().cloned())
+            .collect(),
+        listeners: sockets.iter().map(|(addr, _)| *addr).collect(),
+    };
+    let sockets: Vec<TcpListener> = sockets.into_iter().map(|(_, socket)| socket).collect();
+
+    let endpoint = platform::Endpoint::bind(&app.path().app_data_dir()?)?;
+    let binary = tauri::process::current_binary(&app.env())?;
+    let mut child = Command::new(&binary)
+        .arg(HANDOFF_ARG)
+        .arg(endpoint.name())
+        .spawn()
+        .with_context(|| format!("Cannot start {}", binary.display()))?;
+    info!(
+        "Handing {} profiles over to the new version (pid {})",
+        state.profiles.len(),
+        child.id()
+    );
+
+    let child_pid = child.id();
+    let result = tauri::async_runtime::spawn_blocking(move || -> anyhow::Result<()> {
+        let mut stream = endpoint.accept(HANDOFF_TIMEOUT)?;
+        platform::send_sockets(&mut stream, &sockets, child_pid)?;
+        write_frame(&mut stream, &serde_json::to_vec(&state)?)?;
+        if read_frame(&mut stream)? != READY {
+            return Err(anyhow!("New version failed to take over"));
+        }
+        Ok(())
+    })
+    .await
+    .map_err(anyhow::Error::from)
+    .and_then(|res| res);
+
+    if let Err(err) = result {
+        let _ = child.kill();
+        for profile in isolated {
+            let profile_id = profile.name.clone();
+            if let Err(err) = reconnect(app, profile).await {
+                warn!("Cannot resume profile {}: {}", profile_id, err);
+            }
+        }
+        return Err(err.context("Upgrade handoff failed, the current version keeps running"));
+    }
+
+    info!("New version took over, exiting");
+    for managed in manager.list() {
+        managed.client.shutdown();
+    }
+    app.state::<SystemProxy>().hand_over();
+    app.state::<KillSwitch>().hand_over();
+    app.state::<Session>().hand_over();
+    app.exit(0);
+    Ok(())
+}
+
+async fn reconnect(app: &AppHandle, profile: Profile) -> Result<(), String> {
+    commands::connect(
+        profile,
+        app.clone(),
+        app.state::<ClientManager>(),
+        app.state::<SystemProxy>(),
+        app.state::<PacServer>(),
+    )
+    .await
+    .map(|_| ())
+}
+
+/// Resume the tunnels of the previous version, using the listening sockets it passed
+pub fn take_over(app: AppHandle, endpoint: String) {
+    tauri::async_runtime::spawn(async move {
+        if let Err(err) = resume(&app, endpoint).await {
+            error!("Cannot take over from the previous version: {:?}", err);
+        }
+    });
+}
+
+async fn resume(app: &AppHandle, endpoint: String) -> anyhow::Result<()> {
+    let (mut stream, state, sockets) = tauri::async_runtime::spawn_blocking(
+        move || -> anyhow::Result<(platform::Stream, HandoffState, Vec<TcpListener>)> {
+            let mut stream = platform::connect(&endpoint)?;
+            let sockets = platform::recv_sockets(&mut stream)?;
+            let state: HandoffState = serde_json::from_slice(&read_frame(&mut stream)?)
+                .with_context(|| "Invalid handoff state")?;
+            Ok((stream, state, sockets))
+        },
+    )
+    .await??;
+
+    if state.listeners.len() != sockets.len() {
+        return Err(anyhow!(
+            "Received {} sockets for {} listeners",
+            sockets.len(),
+            state.listeners.len()
+        ));
+    }
+    listener_sockets().inherit(state.listeners.into_iter().zip(sockets).collect());
+
+    for profile in state.profiles {
+        let profile_id = profile.name.clone();
+        if let Err(err) = reconnect(app, profile).await {
+            warn!("Cannot resume profile {}: {}", profile_id, err);
+        }
+    }
+    listener_sockets().clear_inherited();
+
+    tauri::async_runtime::spawn_blocking(move || write_frame(&mut stream, READY)).await??;
+    info!("Took over from the previous version");
+    Ok(())
+}
+
+fn write_frame(stream: &mut impl Write, payload: &[u8]) -> anyhow::Result<()> {
+    stream.write_all(&(payload.len() as u32).to_be_bytes())?;
+    stream.write_all(payload)?;
+    Ok(stream.flush()?)
+}
+
+fn read_frame(stream: &mut impl Read) -> anyhow::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let mut payload = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn accept_before<T>(
+    deadline: Instant,
+    mut accept: impl FnMut() -> std::io::Result<T>,
+) -> anyhow::Result<T> {
+    loop {
+        match accept() {
+            Ok(stream) => return Ok(stream),
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {
+                if Instant::now() > deadline {
+                    return Err(anyhow!("New version did not connect in time"));
+                }
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Sockets are passed as file descriptors over a unix socket of the app data directory
+#[cfg(unix)]
+mod platform {
+    use super::accept_before;
+    use anyhow::anyhow;
+    use sendfd::{RecvWithFd, SendWithFd};
+    use std::net::TcpListener;
+    use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    const SOCKET_FILE: &str = "handoff.sock";
+    /// Most file descriptors a single message can carry
+    const MAX_SOCKETS: usize = 253;
+
+    pub type Stream = UnixStream;
+
+    pub struct Endpoint {
+        path: PathBuf,
+        listener: UnixListener,
+    }
+
+    impl Endpoint {
+        pub fn bind(data_dir: &Path) -> anyhow::Result<Self> {
+            std::fs::create_dir_all(data_dir)?;
+            let path = data_dir.join(SOCKET_FILE);
+            // Left over by a previous handoff that did not complete
+            let _ = std::fs::remove_file(&path);
+            let listener = UnixListener::bind(&path)?;
+            listener.set_nonblocking(true)?;
+            Ok(Self { path, listener })
+        }
+
+        pub fn name(&self) -> String {
+            self.path.to_string_lossy().into_owned()
+        }
+
+        pub fn accept(&self, timeout: Duration) -> anyhow::Result<UnixStream> {
+            let (stream, _) = accept_before(Instant::now() + timeout, || self.listener.accept())?;
+            stream.set_nonblocking(false)?;
+            stream.set_read_timeout(Some(timeout))?;
+            Ok(stream)
+        }
+    }
+
+    impl Drop for Endpoint {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    pub fn connect(name: &str) -> anyhow::Result<UnixStream> {
+        Ok(UnixStream::connect(name)?)
+    }
+
+    pub fn send_sockets(
+        stream: &mut UnixStream,
+        sockets: &[TcpListener],
+        _child_pid: u32,
+    ) -> anyhow::Result<()> {
+        if sockets.len() > MAX_SOCKETS {
+            return Err(anyhow!(
+                "Too many listeners to hand over: {}",
+                sockets.len()
+            ));
+        }
+        let fds: Vec<RawFd> = sockets.iter().map(|s| s.as_raw_fd()).collect();
+        stream.send_with_fd(&(fds.len() as u32).to_be_bytes(), &fds)?;
+        Ok(())
+    }
+
+    pub fn recv_sockets(stream: &mut UnixStream) -> anyhow::Result<Vec<TcpListener>> {
+        let mut count = [0; 4];
+        let mut fds = [0 as RawFd; MAX_SOCKETS];
+        let (_, received) = stream.recv_with_fd(&mut count, &mut fds)?;
+        // Safety: the descriptors have just been received, nothing else owns them
+        let sockets: Vec<TcpListener> = fds[..received]
+            .iter()
+            .map(|fd| unsafe { TcpListener::from_raw_fd(*fd) })
+            .collect();
+        if u32::from_be_bytes(count) as usize != sockets.len() {
+            return Err(anyhow!("Some sockets were not received"));
+        }
+        Ok(sockets)
+    }
+}
+
+/// Sockets are duplicated for the new process with WSADuplicateSocket, the control connection is on loopback
+#[cfg(windows)]
+mod platform {
+    use super::accept_before;
+    use anyhow::anyhow;
+    use std::io::{Read, Write};
+    use std::net::{Ipv4Addr, TcpListener, TcpStream};
+    use std::os::windows::io::{AsRawSocket, FromRawSocket, RawSocket};
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+    use windows_sys::Win32::Networking::WinSock::{
+        WSADuplicateSocketW, WSASocketW, FROM_PROTOCOL_INFO, INVALID_SOCKET, SOCKET,
+        WSAPROTOCOL_INFOW, WSA_FLAG_OVERLAPPED,
+    };
+
+    const PROTOCOL_INFO_SIZE: usize = std::mem::size_of::<WSAPROTOCOL_INFOW>();
+
+    pub type Stream = TcpStream;
+
+    pub struct Endpoint {
+        listener: TcpListener,
+    }
+
+    impl Endpoint {
+        pub fn bind(_data_dir: &Path) -> anyhow::Result<Self> {
+            let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+            listener.set_nonblocking(true)?;
+            Ok(Self { listener })
+        }
+
+        pub fn name(&self) -> String {
+            self.listener
+                .local_addr()
+                .map(|addr| addr.port().to_string())
+                .unwrap_or_default()
+        }
+
+        pub fn accept(&self, timeout: Duration) -> anyhow::Result<TcpStream> {
+            let (stream, _) = accept_before(Instant::now() + timeout, || self.listener.accept())?;
+            stream.set_nonblocking(false)?;
+            stream.set_read_timeout(Some(timeout))?;
+            Ok(stream)
+        }
+    }
+
+    pub fn connect(name: &str) -> anyhow::Result<TcpStream> {
+        let port: u16 = name.parse()?;
+        Ok(TcpStream::connect((Ipv4Addr::LOCALHOST, port))?)
+    }
+
+    pub fn send_sockets(
+        stream: &mut TcpStream,
+        sockets: &[TcpListener],
+        child_pid: u32,
+    ) -> anyhow::Result<()> {
+        // Anyone can connect on loopback, only the process we started gets the sockets
+        let mut pid = [0; 4];
+        stream.read_exact(&mut pid)?;
+        if u32::from_be_bytes(pid) != child_pid {
+            return Err(anyhow!("Unexpected process asking for the sockets"));
+        }
+
+        stream.write_all(&(sockets.len() as u32).to_be_bytes())?;
+        for socket in sockets {
+            // Safety: the structure is plain data filled by the call
+            let mut info: WSAPROTOCOL_INFOW = unsafe { std::mem::zeroed() };
+            let res = unsafe {
+                WSADuplicateSocketW(socket.as_raw_socket() as SOCKET, child_pid, &mut info)
+            };
+            if res != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            // Safety: the structure is plain data, sent as is to the other process
+            let bytes = unsafe {
+                std::slice::from_raw_parts(
+                    &info as *const WSAPROTOCOL_INFOW as *const u8,
+                    PROTOCOL_INFO_SIZE,
+                )
+            };
+            stream.write_all(bytes)?;
+        }
+        Ok(())
+    }
+
+    pub fn recv_sockets(stream: &mut TcpStream) -> anyhow::Result<Vec<TcpListener>> {
+        stream.write_all(&std::process::id().to_be_bytes())?;
+        let mut count = [0; 4];
+        stream.read_exact(&mut count)?;
+
+        let mut sockets = vec![];
+        for _ in 0..u32::from_be_bytes(count) {
+            // Safety: the structure is plain data, filled from the bytes sent by the other process
+            let mut info: WSAPROTOCOL_INFOW = unsafe { std::mem::zeroed() };
+            let bytes = unsafe {
+                std::slice::from_raw_parts_mut(
+                    &mut info as *mut WSAPROTOCOL_INFOW as *mut u8,
+                    PROTOCOL_INFO_SIZE,
+                )
+            };
+            stream.read_exact(bytes)?;
+            let socket = unsafe {
+                WSASocketW(
+                    FROM_PROTOCOL_INFO,
+                    FROM_PROTOCOL_INFO,
+                    FROM_PROTOCOL_INFO,
+                    &info,
+                    0,
+                    WSA_FLAG_OVERLAPPED,
+                )
+            };
+            if socket == INVALID_SOCKET {
+                return Err(std::io::Error::last_os_error().into());
+            }
+            // Safety: the socket has just been created, nothing else owns it
+            sockets.push(unsafe { TcpListener::from_raw_socket(socket as RawSocket) });
+        }
+        Ok(sockets)
+    }
+}
@@ -0,0 +1,182 @@
+use crate::auth;
+use crate::client::chain;
+use crate::client::client_api::WsClientApi;
+use crate::client::concurrency::ConnectionOverflow;
+use crate::client::events::ClientEvent;
+use crate::client::hooks::HookStage;
+use crate::client::placeholders;
+use crate::client::profile::{Profile, TunnelConfig};
+use crate::client::server_trust;
+use crate::client::user_presence;
+use crate::profile_store;
+use anyhow::anyhow;
+use log::{debug, info};
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// Left to the stdio tunnel to write out what it received last, before exiting
+const STDIO_FLUSH_DELAY: Duration = Duration::from_secs(1);
+
+/// Connect a saved profile without any window and keep it running until interrupted,
+/// so the same profiles can be used from a ssh session or a service.
+pub fn run(profile_name: &str, app_identifier: &str) -> anyhow::Result<()> {
+    let data_dir = data_dir(app_identifier)?;
+    let profile = load_profile(&data_dir, profile_name)?;
+    run_profile(profile, &data_dir)
+}
+
+/// Relay stdio to `host:port` through a saved profile, its own tunnels left out, for ssh to use the app as
+/// its ProxyCommand without a wstunnel binary. The process is done once ssh closes the connection
+pub fn stdio_bridge(
+    profile_name: &str,
+    host: &str,
+    port: u16,
+    app_identifier: &str,
+) -> anyhow::Result<()> {
+    let data_dir = data_dir(app_identifier)?;
+    let mut profile = load_profile(&data_dir, profile_name)?;
+    let host = if host.parse::<Ipv6Addr>().is_ok() {
+        format!("[{}]", host)
+    } else {
+        host.to_string()
+    };
+    profile.tunnels = vec![TunnelConfig {
+        spec: format!("stdio://{}:{}", host, port),
+        id: Some("stdio-bridge".to_string()),
+        reverse: false,
+        group: None,
+        rate_limit_up: None,
+        rate_limit_down: None,
+        bind_interface: None,
+        allowed_sources: vec![],
+        dual_stack: false,
+        buffers: None,
+        set_system_proxy: false,
+        pac_domains: vec![],
+        lazy: false,
+        proxy_header: None,
+        port_mapping: false,
+        datagrams: None,
+        access_log: false,
+        listener_auth: None,
+        max_concurrent_connections: None,
+        connection_overflow: ConnectionOverflow::default(),
+        enabled: true,
+    }];
+    profile.dns_stub = None;
+    profile.on_demand = false;
+    run_profile(profile, &data_dir)
+}
+
+fn data_dir(app_identifier: &str) -> anyhow::Result<PathBuf> {
+    Ok(dirs::data_dir()
+        .ok_or_else(|| anyhow!("Cannot find the data directory of the user"))?
+        .join(app_identifier))
+}
+
+fn load_profile(data_dir: &Path, profile_name: &str) -> anyhow::Result<Profile> {
+    let profile = profile_store::load_profiles(data_dir)?
+        .into_iter()
+        .find(|p| p.name == profile_name)
+        .ok_or_else(|| anyhow!("No saved profile named {}", profile_name))?;
+    // Its rules are kept by the app, which removes them when it exits or after a crash
+    if profile.kill_switch {
+        return Err(anyhow!(
+            "Profile {} has a kill switch, which is only available from the app",
+            profile_name
+        ));
+    }
+    Ok(profile)
+}
+
+fn run_profile(profile: Profile, data_dir: &Path) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        user_presence::confirm(&profile).await?;
+        if let Some(hook) = &profile.before_connect {
+            hook.run(&profile.name, HookStage::BeforeConnect).await?;
+        }
+        let expanded = placeholders::expand(&profile)?;
+        let authorized = auth::authorize(&expanded, |prompt| {
+            info!(
+                "Open {} and enter the code {} to authorize profile {}",
+                prompt.verification_uri, prompt.user_code, prompt.profile_id
+            );
+        })
+        .await?;
+        // Nobody is there to confirm the certificate, it must have been trusted from the app before
+        if let Some(certificate) = server_trust::untrusted_certificate(&profile, data_dir).await? {
+            return Err(anyhow!(
+                "Certificate {} of {} is not trusted, connect the profile once from the app to confirm it",
+                certificate.fingerprint,
+                certificate.server
+            ));
+        }
+        // Only this profile runs here, so a profile going through another one cannot connect
+        let mut authorized = chain::resolve(&authorized, &[])?;
+        authorized
+            .tls_pinned_certificates
+            .extend(server_trust::pinned(&profile, data_dir));
+        let client = authorized.to_client()?;
+        let connected = WsClientApi::connect(Box::new(client), |step| debug!("{:?}", step)).await?;
+        for listener in &connected.listeners {
+            info!(
+                "Tunnel {} listening on {}",
+                listener.tunnel_id, listener.bound
+            );
+        }
+        info!(
+            "Profile {} connected to {}, press ctrl+c to stop",
+            profile.name, connected.remote_addr
+        );
+
+        let mut events = connected.stats.events.subscribe();
+        tokio::select! {
+            res = wait_for_shutdown() => res?,
+            tunnel_id = stdio_closed(&mut events) => {
+                // As with the wstunnel cli used as a ssh ProxyCommand, the process is done once stdio is closed
+                info!("Stdio tunnel {} closed", tunnel_id);
+                tokio::time::sleep(STDIO_FLUSH_DELAY).await;
+            }
+        }
+        info!("Disconnecting profile {}", profile.name);
+        connected.shutdown();
+        // The process exits right after, the hook is waited for
+        if let Some(hook) = &profile.after_disconnect {
+            hook.run(&profile.name, HookStage::AfterDisconnect).await?;
+        }
+        Ok(())
+    })
+}
+
+/// Wait until the input of a stdio tunnel of the profile is closed, forever if it has none
+async fn stdio_closed(events: &mut broadcast::Receiver<ClientEvent>) -> String {
+    loop {
+        match events.recv().await {
+            Ok(ClientEvent::StdioClosed { tunnel_id }) => return tunnel_id,
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    // SIGTERM is what systemd sends to stop a service
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res?,
+        _ = terminate.recv() => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown() -> anyhow::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,312 @@
+use crate::client::events::ClientEvent;
+use crate::client::manager::ManagedClient;
+use crate::client::stats::TrafficSnapshot;
+use crate::messages::{self, Messages};
+use anyhow::Context;
+use log::{error, warn};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager};
+use tokio::sync::broadcast::error::RecvError;
+
+const DATABASE_FILE: &str = "connection-history.sqlite";
+/// Entries older than that are deleted when the app starts
+const RETENTION_DAYS: u64 = 400;
+const DEFAULT_LIMIT: u32 = 1000;
+
+/// What happened to a profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryKind {
+    Connected,
+    ConnectFailed,
+    /// Carries how long the profile stayed connected and what it transferred
+    Disconnected,
+    /// The server stopped answering, the tunnels are being re-established
+    LinkLost,
+    LinkRestored,
+    /// A tunnel kept failing and is not restarted anymore
+    TunnelStopped,
+}
+
+impl HistoryKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            HistoryKind::Connected => "connected",
+            HistoryKind::ConnectFailed => "connect_failed",
+            HistoryKind::Disconnected => "disconnected",
+            HistoryKind::LinkLost => "link_lost",
+            HistoryKind::LinkRestored => "link_restored",
+            HistoryKind::TunnelStopped => "tunnel_stopped",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        [
+            HistoryKind::Connected,
+            HistoryKind::ConnectFailed,
+            HistoryKind::Disconnected,
+            HistoryKind::LinkLost,
+            HistoryKind::LinkRestored,
+            HistoryKind::TunnelStopped,
+        ]
+        .into_iter()
+        .find(|known| known.as_str() == kind)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    /// Unix timestamp in seconds
+    pub at_sec: u64,
+    pub profile_id: String,
+    pub server: String,
+    pub kind: HistoryKind,
+    pub duration_sec: Option<u64>,
+    pub bytes_up: Option<u64>,
+    pub bytes_down: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    pub fn new(profile_id: &str, server: &str, kind: HistoryKind) -> Self {
+        Self {
+            at_sec: now_sec(),
+            profile_id: profile_id.to_string(),
+            server: server.to_string(),
+            kind,
+            duration_sec: None,
+            bytes_up: None,
+            bytes_down: None,
+            error: None,
+        }
+    }
+
+    pub fn failed(profile_id: &str, server: &str, error: &str) -> Self {
+        Self {
+            error: Some(error.to_string()),
+            ..Self::new(profile_id, server, HistoryKind::ConnectFailed)
+        }
+    }
+
+    pub fn disconnected(profile_id: &str, server: &str, traffic: &TrafficSnapshot) -> Self {
+        Self {
+            duration_sec: Some(traffic.uptime_sec),
+            bytes_up: Some(traffic.bytes_up),
+            bytes_down: Some(traffic.bytes_down),
+            ..Self::new(profile_id, server, HistoryKind::Disconnected)
+        }
+    }
+}
+
+/// Entries to return, every bound being optional. The most recent ones come first
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryFilter {
+    pub profile_id: Option<String>,
+    pub from_sec: Option<u64>,
+    /// Excluded
+    pub to_sec: Option<u64>,
+    #[serde(default)]
+    pub kinds: Vec<HistoryKind>,
+    pub limit: Option<u32>,
+}
+
+/// Connections, disconnections and link losses of the profiles, kept in a SQLite database of the app data directory
+pub struct ConnectionHistory {
+    db: Mutex<Connection>,
+}
+
+impl ConnectionHistory {
+    pub fn open(data_dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(DATABASE_FILE);
+        let db = Connection::open(&path)
+            .with_context(|| format!("Cannot open connection history {}", path.display()))?;
+        Self::init(db)
+    }
+
+    /// History kept in memory only, when the database cannot be opened
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(db: Connection) -> anyhow::Result<Self> {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS history (
+                ts INTEGER NOT NULL,
+                profile TEXT NOT NULL,
+                server TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                duration_sec INTEGER,
+                bytes_up INTEGER,
+                bytes_down INTEGER,
+                error TEXT
+            );
+            CREATE INDEX IF NOT EXISTS history_ts ON history (ts);
+            CREATE INDEX IF NOT EXISTS history_profile_ts ON history (profile, ts);",
+        )?;
+        let retention = (RETENTION_DAYS * 24 * 3600) as i64;
+        db.execute(
+            "DELETE FROM history WHERE ts < ?1",
+            params![now_sec() as i64 - retention],
+        )?;
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    /// Add an entry, logging rather than failing, the history never prevents a profile to connect or disconnect
+    pub fn record(&self, entry: HistoryEntry) {
+        let db = self.db.lock();
+        let inserted = db.execute(
+            "INSERT INTO history (ts, profile, server, kind, duration_sec, bytes_up, bytes_down, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                entry.at_sec as i64,
+                entry.profile_id,
+                entry.server,
+                entry.kind.as_str(),
+                entry.duration_sec.map(|value| value as i64),
+                entry.bytes_up.map(|value| value as i64),
+                entry.bytes_down.map(|value| value as i64),
+                entry.error
+            ],
+        );
+        if let Err(err) = inserted {
+            error!("Cannot record connection history: {:?}", err);
+        }
+    }
+
+    pub fn query(&self, filter: &HistoryFilter) -> anyhow::Result<Vec<HistoryEntry>> {
+        let db = self.db.lock();
+        let mut query = db.prepare_cached(
+            "SELECT ts, profile, server, kind, duration_sec, bytes_up, bytes_down, error
+             FROM history
+             WHERE (?1 IS NULL OR profile = ?1) AND (?2 IS NULL OR ts >= ?2) AND (?3 IS NULL OR ts < ?3)
+             ORDER BY ts DESC, rowid DESC",
+        )?;
+        let rows = query.query_map(
+            params![
+                filter.profile_id,
+                filter.from_sec.map(|value| value as i64),
+                filter.to_sec.map(|value| value as i64)
+            ],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, Option<i64>>(4)?,
+                    row.get::<_, Option<i64>>(5)?,
+                    row.get::<_, Option<i64>>(6)?,
+                    row.get::<_, Option<String>>(7)?,
+                ))
+            },
+        )?;
+        let limit = filter.limit.unwrap_or(DEFAULT_LIMIT) as usize;
+        let mut entries = vec![];
+        for row in rows {
+            let (ts, profile_id, server, kind, duration_sec, bytes_up, bytes_down, error) = row?;
+            let Some(kind) = HistoryKind::parse(&kind) else {
+                continue;
+            };
+            if !filter.kinds.is_empty() && !filter.kinds.contains(&kind) {
+                continue;
+            }
+            entries.push(HistoryEntry {
+                at_sec: ts as u64,
+                profile_id,
+                server,
+                kind,
+                duration_sec: duration_sec.map(|value| value as u64),
+                bytes_up: bytes_up.map(|value| value as u64),
+                bytes_down: bytes_down.map(|value| value as u64),
+                error,
+            });
+            if entries.len() >= limit {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Write the entries matching the filter as CSV, timestamps in RFC 3339 UTC
+    pub fn export_csv(&self, filter: &HistoryFilter, path: &Path) -> anyhow::Result<usize> {
+        let entries = self.query(filter)?;
+        let mut csv =
+            String::from("time,profile,server,event,duration_sec,bytes_up,bytes_down,error\n");
+        for entry in &entries {
+            let time = chrono::DateTime::from_timestamp(entry.at_sec as i64, 0)
+                .map(|time| time.to_rfc3339())
+                .unwrap_or_default();
+            let optional = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                time,
+                csv_field(&entry.profile_id),
+                csv_field(&entry.server),
+                entry.kind.as_str(),
+                optional(entry.duration_sec),
+                optional(entry.bytes_up),
+                optional(entry.bytes_down),
+                csv_field(entry.error.as_deref().unwrap_or_default())
+            ));
+        }
+        std::fs::write(path, csv).with_context(|| format!("Cannot write {}", path.display()))?;
+        Ok(entries.len())
+    }
+}
+
+/// Quoted when it holds a separator, a quote or a line break, quotes being doubled
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Record the link losses of a connected profile, until it is disconnected
+pub fn watch(app: &AppHandle, managed: &ManagedClient) {
+    let profile_id = managed.profile.name.clone();
+    let server = managed.client.remote_addr.to_string();
+    let mut events = managed.client.stats.events.subscribe();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let entry = match events.recv().await {
+                Ok(ClientEvent::ServerUnreachable) => {
+                    HistoryEntry::new(&profile_id, &server, HistoryKind::LinkLost)
+                }
+                Ok(ClientEvent::ServerReachable) => {
+                    HistoryEntry::new(&profile_id, &server, HistoryKind::LinkRestored)
+                }
+                Ok(ClientEvent::ReconnectExhausted { cause, error, .. }) => HistoryEntry {
+                    error: Some(format!(
+                        "{}: {}",
+                        messages::failure_cause(app.state::<Messages>().locale(), &cause),
+                        error
+                    )),
+                    ..HistoryEntry::new(&profile_id, &server, HistoryKind::TunnelStopped)
+                },
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            match app.try_state::<ConnectionHistory>() {
+                Some(history) => history.record(entry),
+                None => warn!("Connection history is not available"),
+            }
+        }
+    });
+}
+
+fn now_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
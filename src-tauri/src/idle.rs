@@ -0,0 +1,201 @@
+use crate::client::engine::ClientEngine;
+use crate::client::manager::ClientManager;
+use crate::client::profile::Profile;
+use crate::client::reverse_status::ReverseTunnelStatus;
+use crate::client::stats::TrafficSnapshot;
+use crate::commands;
+use crate::history::ConnectionHistory;
+use crate::kill_switch::KillSwitch;
+use crate::pac::PacServer;
+use crate::relay::RelayProcesses;
+use crate::system_proxy::SystemProxy;
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// On demand profiles close their connections to the server after that long without traffic, unless they set a delay
+const ON_DEMAND_IDLE: Duration = Duration::from_secs(5 * 60);
+/// Sent to the frontend when a profile is disconnected or suspended for being idle, or resumes
+pub const IDLE_EVENT: &str = "profile-idle";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleState {
+    /// The idle connections to the server are closed, the listeners stay bound and reconnect on demand
+    Suspended,
+    /// A local connection came in after the profile has been suspended
+    Resumed,
+    Disconnected,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IdleEvent {
+    pub profile_id: String,
+    pub state: IdleState,
+}
+
+/// Last activity seen on a connected profile
+struct Activity {
+    counter: u64,
+    since: Instant,
+    suspended: bool,
+}
+
+/// A connected profile, in the app or in its relay process
+struct Connected {
+    profile: Profile,
+    traffic: TrafficSnapshot,
+    reverse_tunnels: Vec<ReverseTunnelStatus>,
+    /// Only the profiles running in the app can close their connections without being disconnected
+    engine: Option<Arc<ClientEngine>>,
+}
+
+/// Anything changing when a tunnel carries traffic, reverse tunnels included
+fn activity_counter(traffic: &TrafficSnapshot, reverse_tunnels: &[ReverseTunnelStatus]) -> u64 {
+    traffic.connections
+        + traffic.bytes_up
+        + traffic.bytes_down
+        + reverse_tunnels
+            .iter()
+            .map(|tunnel| tunnel.accepted_connections)
+            .sum::<u64>()
+}
+
+/// Disconnect the profiles whose tunnels carried no traffic for longer than they allow, or only close their
+/// connections to the server when they reconnect on demand. On demand profiles are connected to the server
+/// only while used. Runs for as long as the app does.
+pub fn spawn_enforcer(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut seen: HashMap<String, Activity> = HashMap::new();
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            enforce(&app, &mut seen).await;
+        }
+    });
+}
+
+async fn enforce(app: &AppHandle, seen: &mut HashMap<String, Activity>) {
+    let connected: Vec<Connected> = app
+        .state::<ClientManager>()
+        .list()
+        .into_iter()
+        .map(|managed| Connected {
+            traffic: managed.client.stats.traffic_snapshot(),
+            reverse_tunnels: managed.client.stats.reverse_tunnels.list(),
+            engine: Some(managed.client.engine.clone()),
+            profile: managed.profile,
+        })
+        .chain(
+            app.state::<RelayProcesses>()
+                .list()
+                .into_iter()
+                .map(|relay| Connected {
+                    profile: relay.profile,
+                    traffic: relay.traffic,
+                    reverse_tunnels: relay.reverse_tunnels,
+                    engine: None,
+                }),
+        )
+        .collect();
+    // Profiles disconnected since are forgotten
+    seen.retain(|profile_id, _| connected.iter().any(|c| c.profile.name == *profile_id));
+
+    for Connected {
+        profile,
+        traffic,
+        reverse_tunnels,
+        engine,
+    } in connected
+    {
+        let after = match profile.idle_disconnect_after_sec {
+            Some(after) => Duration::from_secs(after),
+            None if profile.on_demand => ON_DEMAND_IDLE,
+            None => {
+                seen.remove(&profile.name);
+                continue;
+            }
+        };
+        let counter = activity_counter(&traffic, &reverse_tunnels);
+        // An on demand profile starts suspended, whatever it carried since it connected resumes it
+        let activity = seen.entry(profile.name.clone()).or_insert(Activity {
+            counter: if profile.on_demand { 0 } else { counter },
+            since: Instant::now(),
+            suspended: profile.on_demand,
+        });
+
+        if activity.counter != counter || traffic.active_connections > 0 {
+            activity.counter = counter;
+            activity.since = Instant::now();
+            if activity.suspended {
+                activity.suspended = false;
+                info!("Profile {} is used again, resuming it", profile.name);
+                if let Some(engine) = &engine {
+                    engine.stats().suspended.store(false, Ordering::Relaxed);
+                    // Brings the pool of idle connections back
+                    if let Err(err) = engine.refresh_connections().await {
+                        warn!("Cannot resume profile {}: {:?}", profile.name, err);
+                    }
+                }
+                report(app, &profile.name, IdleState::Resumed);
+            }
+            continue;
+        }
+        if activity.suspended || activity.since.elapsed() < after {
+            continue;
+        }
+
+        match engine {
+            Some(engine) if profile.idle_reconnect_on_demand || profile.on_demand => {
+                info!(
+                    "Profile {} idle for {:?}, closing its connections to the server",
+                    profile.name, after
+                );
+                if let Err(err) = engine.drain_pool().await {
+                    warn!("Cannot suspend profile {}: {:?}", profile.name, err);
+                    continue;
+                }
+                activity.suspended = true;
+                engine.stats().suspended.store(true, Ordering::Relaxed);
+                report(app, &profile.name, IdleState::Suspended);
+            }
+            _ => {
+                info!(
+                    "Profile {} idle for {:?}, disconnecting it",
+                    profile.name, after
+                );
+                let result = commands::disconnect(
+                    profile.name.clone(),
+                    app.state::<ClientManager>(),
+                    app.state::<RelayProcesses>(),
+                    app.state::<SystemProxy>(),
+                    app.state::<PacServer>(),
+                    app.state::<KillSwitch>(),
+                    app.state::<ConnectionHistory>(),
+                );
+                if let Err(err) = result {
+                    warn!("Cannot disconnect profile {}: {}", profile.name, err);
+                    continue;
+                }
+                seen.remove(&profile.name);
+                report(app, &profile.name, IdleState::Disconnected);
+            }
+        }
+    }
+}
+
+fn report(app: &AppHandle, profile_id: &str, state: IdleState) {
+    let event = IdleEvent {
+        profile_id: profile_id.to_string(),
+        state,
+    };
+    if let Err(err) = app.emit(IDLE_EVENT, event) {
+        warn!("Cannot report idle profile: {:?}", err);
+    }
+}
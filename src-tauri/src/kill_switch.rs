@@ -0,0 +1,590 @@
+use crate::client::profile::Profile;
+use crate::client::static_hosts::{self, StaticHost};
+use crate::parsers;
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use url::{Host, Url};
+
+const STATE_FILE: &str = "kill_switch.json";
+
+/// Addresses a profile with a kill switch is allowed to reach: its server, and the proxy it goes through
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Endpoints {
+    /// Server and proxies they were resolved from, they are resolved again once these change
+    source: String,
+    /// Address the server name is pinned to
+    server: Option<IpAddr>,
+    addresses: Vec<IpAddr>,
+    /// Local addresses whose traffic is let out wherever it goes, those of the TUN interfaces of the profile
+    #[serde(default)]
+    sources: Vec<IpAddr>,
+}
+
+impl Endpoints {
+    pub fn with_sources(mut self, sources: impl IntoIterator<Item = IpAddr>) -> Self {
+        self.sources = sources.into_iter().collect();
+        self
+    }
+}
+
+/// Profiles with a kill switch and what they are allowed to reach, persisted so the rules left by a crash
+/// can be removed on next launch, or taken over by a newer version of the app during an upgrade
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Engaged {
+    allowed: BTreeMap<String, Endpoints>,
+    /// What the platform needs to remove its rules, i.e: the pf reference on macOS
+    #[serde(default)]
+    saved: Option<String>,
+}
+
+/// Firewall rules only letting out the traffic to loopback and to the servers of the connected profiles having
+/// a kill switch, along with the traffic their TUN interfaces capture, so nothing leaks onto the network when
+/// their tunnels drop. The rules stay while such a profile
+/// is connected, even when its connection to the server is lost, and are removed once it is disconnected.
+/// Changing them asks for an administrator, unless the app runs as one.
+pub struct KillSwitch {
+    state_file: PathBuf,
+    engaged: Mutex<Engaged>,
+    /// Set once the rules have been left to a newer version of the app, they must not be removed anymore
+    handed_over: AtomicBool,
+}
+
+impl KillSwitch {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            state_file: data_dir.join(STATE_FILE),
+            engaged: Mutex::new(Engaged::default()),
+            handed_over: AtomicBool::new(false),
+        }
+    }
+
+    fn load(&self) -> anyhow::Result<Option<Engaged>> {
+        if !self.state_file.exists() {
+            return Ok(None);
+        }
+        let engaged = serde_json::from_slice(&std::fs::read(&self.state_file)?)
+            .with_context(|| "Invalid kill switch state")?;
+        Ok(Some(engaged))
+    }
+
+    /// Take over the rules installed by the previous version of the app during an upgrade
+    pub fn adopt(&self) -> anyhow::Result<()> {
+        if let Some(engaged) = self.load()? {
+            *self.engaged.lock() = engaged;
+        }
+        Ok(())
+    }
+
+    /// Leave the rules to the newer version of the app taking over
+    pub fn hand_over(&self) {
+        self.handed_over.store(true, Ordering::Relaxed);
+    }
+
+    /// Remove the rules left by a previous run that did not exit cleanly, which block the network until then
+    pub fn recover(&self) -> anyhow::Result<()> {
+        let Some(mut engaged) = self.load()? else {
+            return Ok(());
+        };
+        warn!("Removing the kill switch rules left over by a previous run");
+        platform::remove(&mut engaged.saved)?;
+        std::fs::remove_file(&self.state_file)?;
+        Ok(())
+    }
+
+    /// Resolve the server of the profile, and the proxy it goes through, to the addresses the rules let through.
+    /// The server name is pinned to its address, so reconnecting does not need a dns request the rules would block.
+    /// A profile whose rules are already installed keeps the addresses it was given, which it can still reach.
+    pub async fn resolve(&self, profile: &mut Profile) -> anyhow::Result<Endpoints> {
+        let source = format!(
+            "{} {:?} {:?}",
+            profile.server_addr, profile.http_proxy, profile.socks5_proxy
+        );
+        let engaged = self.engaged.lock().allowed.get(&profile.name).cloned();
+        let endpoints = match engaged {
+            Some(endpoints) if endpoints.source == source => endpoints,
+            _ => {
+                let server = resolve_host(profile, &profile.server_addr).await?;
+                let mut addresses = server.clone();
+                if let Some(proxy) = &profile.http_proxy {
+                    let proxy = parsers::parse_proxy_url(proxy, None, None)?;
+                    addresses.extend(resolve_host(profile, &proxy).await?);
+                }
+                if let Some(proxy) = &profile.socks5_proxy {
+                    addresses.extend(resolve_host(profile, proxy).await?);
+                }
+                Endpoints {
+                    source,
+                    server: server.first().copied(),
+                    addresses,
+                    sources: vec![],
+                }
+            }
+        };
+        if let (Some(Host::Domain(name)), Some(ip)) = (profile.server_addr.host(), endpoints.server)
+        {
+            profile.static_hosts.push(StaticHost {
+                name: name.to_string(),
+                ip,
+            });
+        }
+        Ok(endpoints)
+    }
+
+    /// Install the rules, or update them with the addresses of the profile
+    pub fn engage(&self, profile_id: &str, endpoints: Endpoints) -> anyhow::Result<()> {
+        let mut engaged = self.engaged.lock();
+        engaged.allowed.insert(profile_id.to_string(), endpoints);
+        info!("Kill switch engaged for profile {}", profile_id);
+        self.apply(&mut engaged)
+    }
+
+    /// Stop letting the profile through, removing the rules once no profile needs them anymore
+    pub fn release(&self, profile_id: &str) -> anyhow::Result<()> {
+        let mut engaged = self.engaged.lock();
+        if engaged.allowed.remove(profile_id).is_none() {
+            return Ok(());
+        }
+        info!("Kill switch released for profile {}", profile_id);
+        self.apply(&mut engaged)
+    }
+
+    /// Remove the rules whichever profiles they are for, when the app exits
+    pub fn release_all(&self) -> anyhow::Result<()> {
+        let mut engaged = self.engaged.lock();
+        if engaged.allowed.is_empty() {
+            return Ok(());
+        }
+        engaged.allowed.clear();
+        self.apply(&mut engaged)
+    }
+
+    fn apply(&self, engaged: &mut Engaged) -> anyhow::Result<()> {
+        if self.handed_over.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        if engaged.allowed.is_empty() {
+            platform::remove(&mut engaged.saved)?;
+            if self.state_file.exists() {
+                std::fs::remove_file(&self.state_file)?;
+            }
+            return Ok(());
+        }
+
+        let mut allowed: Vec<IpAddr> = engaged
+            .allowed
+            .values()
+            .flat_map(|endpoints| endpoints.addresses.iter().copied())
+            .collect();
+        allowed.sort();
+        allowed.dedup();
+        let mut sources: Vec<IpAddr> = engaged
+            .allowed
+            .values()
+            .flat_map(|endpoints| endpoints.sources.iter().copied())
+            .collect();
+        sources.sort();
+        sources.dedup();
+        // Saved first, so the rules are removed on next launch even if installing them fails half way
+        if let Some(dir) = self.state_file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.state_file, serde_json::to_vec(&*engaged)?)
+            .with_context(|| "Cannot save kill switch state")?;
+        platform::install(&allowed, &sources, &mut engaged.saved)?;
+        std::fs::write(&self.state_file, serde_json::to_vec(&*engaged)?)
+            .with_context(|| "Cannot save kill switch state")
+    }
+}
+
+async fn resolve_host(profile: &Profile, url: &Url) -> anyhow::Result<Vec<IpAddr>> {
+    let host = url
+        .host()
+        .map(|host| host.to_owned())
+        .ok_or_else(|| anyhow!("{} has no host", url))?;
+    match static_hosts::lookup(&profile.static_hosts(), &host).unwrap_or(host) {
+        Host::Ipv4(ip) => Ok(vec![IpAddr::V4(ip)]),
+        Host::Ipv6(ip) => Ok(vec![IpAddr::V6(ip)]),
+        Host::Domain(name) => {
+            let port = url.port_or_known_default().unwrap_or(443);
+            let addrs = tokio::net::lookup_host((name.as_str(), port))
+                .await
+                .with_context(|| format!("Cannot resolve {}", name))?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        }
+    }
+}
+
+/// Run a command fed with `input`, returning what it printed on both outputs
+fn run_with_input(cmd: &[&str], input: &str) -> anyhow::Result<String> {
+    let (program, args) = cmd.split_first().ok_or_else(|| anyhow!("Empty command"))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Cannot execute {}", program))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    let printed = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    if !output.status.success() {
+        return Err(anyhow!("{} failed: {}", cmd.join(" "), printed.trim()));
+    }
+    Ok(printed)
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::run_with_input;
+    use std::net::IpAddr;
+
+    const TABLE: &str = "wstunnel_kill_switch";
+    const PKEXEC: &str = "/usr/bin/pkexec";
+
+    /// Replace the nftables table of the kill switch, declaring it first so deleting it never fails
+    pub fn install(
+        allowed: &[IpAddr],
+        sources: &[IpAddr],
+        _saved: &mut Option<String>,
+    ) -> anyhow::Result<()> {
+        let list = |addresses: &[IpAddr], v4: bool| {
+            addresses
+                .iter()
+                .filter(|ip| ip.is_ipv4() == v4)
+                .map(IpAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut rules = vec!["oifname \"lo\" accept".to_string()];
+        for (family, v4) in [("ip", true), ("ip6", false)] {
+            let ips = list(allowed, v4);
+            if !ips.is_empty() {
+                rules.push(format!("{} daddr {{ {} }} accept", family, ips));
+            }
+            let ips = list(sources, v4);
+            if !ips.is_empty() {
+                rules.push(format!("{} saddr {{ {} }} accept", family, ips));
+            }
+        }
+        let script = format!(
+            "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n  chain output {{\n    type filter hook output priority 0; policy drop;\n    {rules}\n  }}\n}}\n",
+            table = TABLE,
+            rules = rules.join("\n    ")
+        );
+        run_elevated(&["nft", "-f", "-"], &script)?;
+        Ok(())
+    }
+
+    pub fn remove(_saved: &mut Option<String>) -> anyhow::Result<()> {
+        let script = format!(
+            "table inet {table}\ndelete table inet {table}\n",
+            table = TABLE
+        );
+        run_elevated(&["nft", "-f", "-"], &script)?;
+        Ok(())
+    }
+
+    /// Run the command as root, polkit asking for an administrator unless the app already runs as root.
+    /// pkexec hands its standard input over to the command
+    fn run_elevated(cmd: &[&str], input: &str) -> anyhow::Result<String> {
+        // SAFETY: geteuid cannot fail
+        if unsafe { libc::geteuid() } == 0 {
+            return run_with_input(cmd, input);
+        }
+        let mut elevated = vec![PKEXEC];
+        elevated.extend(cmd);
+        run_with_input(&elevated, input)
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::run_with_input;
+    use std::net::IpAddr;
+
+    /// Evaluated by the default pf.conf, which loads every anchor under com.apple
+    const ANCHOR: &str = "com.apple/wstunnel.kill-switch";
+
+    /// Load the rules in the anchor, enabling pf with a reference kept in `saved` to release it later
+    pub fn install(
+        allowed: &[IpAddr],
+        sources: &[IpAddr],
+        saved: &mut Option<String>,
+    ) -> anyhow::Result<()> {
+        let list = |addresses: &[IpAddr], v4: bool| {
+            addresses
+                .iter()
+                .filter(|ip| ip.is_ipv4() == v4)
+                .map(IpAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let mut rules = vec!["pass out quick on lo0 all".to_string()];
+        for (family, v4) in [("inet", true), ("inet6", false)] {
+            let ips = list(allowed, v4);
+            if !ips.is_empty() {
+                rules.push(format!(
+                    "pass out quick {} from any to {{ {} }}",
+                    family, ips
+                ));
+            }
+            let ips = list(sources, v4);
+            if !ips.is_empty() {
+                rules.push(format!(
+                    "pass out quick {} from {{ {} }} to any",
+                    family, ips
+                ));
+            }
+        }
+        rules.push("block drop out all".to_string());
+        let mut script = format!(
+            "pfctl -a {} -f - <<'EOF'\n{}\nEOF\n",
+            ANCHOR,
+            rules.join("\n")
+        );
+        if saved.is_none() {
+            // pfctl tells the reference on its error output
+            script.push_str("pfctl -E 2>&1\n");
+        }
+        let output = run_elevated(&script)?;
+
+        if saved.is_none() {
+            // i.e: Token : 12345678
+            *saved = output.lines().find_map(|line| {
+                let (key, value) = line.split_once(':')?;
+                (key.trim() == "Token").then(|| value.trim().to_string())
+            });
+        }
+        Ok(())
+    }
+
+    pub fn remove(saved: &mut Option<String>) -> anyhow::Result<()> {
+        let mut script = format!("pfctl -a {} -F all\n", ANCHOR);
+        if let Some(token) = saved.as_deref() {
+            // The reference comes from the state file, it is only ever digits
+            if !token.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(anyhow::anyhow!("Invalid pf reference {:?}", token));
+            }
+            script.push_str(&format!("pfctl -X {}\n", token));
+        }
+        run_elevated(&script)?;
+        saved.take();
+        Ok(())
+    }
+
+    /// Run the script as root, macOS asking for an administrator unless the app already runs as root.
+    /// Returns its standard output
+    fn run_elevated(script: &str) -> anyhow::Result<String> {
+        let script = format!("set -e\n{}", script);
+        // SAFETY: geteuid cannot fail
+        if unsafe { libc::geteuid() } == 0 {
+            return run_with_input(&["/bin/sh"], &script);
+        }
+        let applescript = format!(
+            "do shell script \"{}\" with administrator privileges without altering line endings",
+            script.replace('\\', "\\\\").replace('"', "\\\"")
+        );
+        run_with_input(&["osascript", "-e", &applescript], "")
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::run_with_input;
+    use anyhow::anyhow;
+    use base64::Engine;
+    use std::net::IpAddr;
+
+    const RULE: &str = "name=wstunnel kill switch";
+    /// Profiles of the firewall, each having its own policy
+    const PROFILES: [&str; 3] = ["Domain", "Private", "Public"];
+    const OUTBOUND_ACTIONS: [&str; 3] = ["Allow", "Block", "NotConfigured"];
+
+    /// Block the outbound traffic in the Windows Filtering Platform through the firewall policy, allowing the
+    /// servers with a rule. The former outbound policy of each firewall profile is kept in `saved` to be put back
+    pub fn install(
+        allowed: &[IpAddr],
+        sources: &[IpAddr],
+        saved: &mut Option<String>,
+    ) -> anyhow::Result<()> {
+        if saved.is_none() {
+            *saved = Some(outbound_policies()?);
+        }
+        let mut script = vec![netsh(
+            &["advfirewall", "firewall", "delete", "rule", RULE],
+            false,
+        )];
+        let mut remote: Vec<String> = vec!["127.0.0.0/8".to_string(), "::1".to_string()];
+        remote.extend(allowed.iter().map(IpAddr::to_string));
+        let remote = format!("remoteip={}", remote.join(","));
+        script.push(netsh(
+            &[
+                "advfirewall",
+                "firewall",
+                "add",
+                "rule",
+                RULE,
+                "dir=out",
+                "action=allow",
+                &remote,
+            ],
+            true,
+        ));
+        if !sources.is_empty() {
+            let local = sources
+                .iter()
+                .map(IpAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+            script.push(netsh(
+                &[
+                    "advfirewall",
+                    "firewall",
+                    "add",
+                    "rule",
+                    RULE,
+                    "dir=out",
+                    "action=allow",
+                    &format!("localip={}", local),
+                ],
+                true,
+            ));
+        }
+        // The inbound policy of the profiles is left as it is
+        script.push(format!(
+            "Set-NetFirewallProfile -Name {} -DefaultOutboundAction Block",
+            PROFILES.join(",")
+        ));
+        run_elevated(&script.join("\n"))
+    }
+
+    pub fn remove(saved: &mut Option<String>) -> anyhow::Result<()> {
+        let saved_policies = saved.clone().unwrap_or_default();
+        let mut script: Vec<String> = PROFILES
+            .iter()
+            .map(|profile| {
+                // Allowed when the policy of the profile was not saved, as by the netsh policy of older versions
+                let action = saved_policies
+                    .split(';')
+                    .filter_map(|policy| policy.split_once('='))
+                    .find(|(name, _)| name == profile)
+                    .map(|(_, action)| action)
+                    .filter(|action| OUTBOUND_ACTIONS.contains(action))
+                    .unwrap_or("Allow");
+                format!(
+                    "Set-NetFirewallProfile -Name {} -DefaultOutboundAction {}",
+                    profile, action
+                )
+            })
+            .collect();
+        script.push(netsh(
+            &["advfirewall", "firewall", "delete", "rule", RULE],
+            false,
+        ));
+        run_elevated(&script.join("\n"))?;
+        saved.take();
+        Ok(())
+    }
+
+    /// Outbound policy of each firewall profile, i.e: `Domain=NotConfigured;Private=Allow;Public=Allow`. The
+    /// cmdlets give the names of the profiles and of the actions in english whatever the language of Windows,
+    /// unlike the output of netsh. Reading them needs no administrator
+    fn outbound_policies() -> anyhow::Result<String> {
+        let output = powershell(
+            "Get-NetFirewallProfile | ForEach-Object { '{0}={1}' -f $_.Name, $_.DefaultOutboundAction }",
+        )?;
+        let policies: Vec<&str> = output
+            .lines()
+            .map(str::trim)
+            .filter(|line| {
+                line.split_once('=').is_some_and(|(name, action)| {
+                    PROFILES.contains(&name) && OUTBOUND_ACTIONS.contains(&action)
+                })
+            })
+            .collect();
+        if policies.len() != PROFILES.len() {
+            return Err(anyhow!(
+                "Cannot read the firewall policies from {:?}",
+                output.trim()
+            ));
+        }
+        Ok(policies.join(";"))
+    }
+
+    /// Line of a powershell script running netsh, stopping the script when it fails and `required`
+    fn netsh(args: &[&str], required: bool) -> String {
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| format!("'{}'", arg.replace('\'', "''")))
+            .collect();
+        let mut line = format!("& netsh {} | Out-Null", args.join(" "));
+        if required {
+            line.push_str("; if ($LASTEXITCODE -ne 0) { exit 1 }");
+        }
+        line
+    }
+
+    /// Run the script in an elevated powershell, Windows asking for an administrator unless the app already is
+    /// one. Only its exit code comes back, the elevated powershell having its own console
+    fn run_elevated(script: &str) -> anyhow::Result<()> {
+        let script = format!("$ErrorActionPreference = 'Stop'\n{}", script);
+        let utf16: Vec<u8> = script.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let encoded = base64::engine::general_purpose::STANDARD.encode(utf16);
+        powershell(&format!(
+            "$p = Start-Process powershell -Verb RunAs -Wait -PassThru -WindowStyle Hidden \
+             -ArgumentList '-NoProfile','-NonInteractive','-EncodedCommand','{}'; exit $p.ExitCode",
+            encoded
+        ))
+        .map_err(|err| err.context("Cannot change the firewall with administrator privileges"))?;
+        Ok(())
+    }
+
+    fn powershell(script: &str) -> anyhow::Result<String> {
+        run_with_input(
+            &[
+                "powershell",
+                "-NoProfile",
+                "-NonInteractive",
+                "-Command",
+                script,
+            ],
+            "",
+        )
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+mod platform {
+    use anyhow::anyhow;
+    use std::net::IpAddr;
+
+    pub fn install(
+        _allowed: &[IpAddr],
+        _sources: &[IpAddr],
+        _saved: &mut Option<String>,
+    ) -> anyhow::Result<()> {
+        Err(anyhow!(
+            "Kill switch is not available on {}",
+            std::env::consts::OS
+        ))
+    }
+
+    pub fn remove(_saved: &mut Option<String>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
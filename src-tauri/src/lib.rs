@@ -1,17 +1,324 @@
+mod auth;
+mod autostart;
+mod bulk;
+mod client;
+mod clipboard_watch;
+mod commands;
+mod config_migration;
+mod control_api;
+mod daemon;
+mod deep_link;
+mod diagnostics;
+mod handoff;
+mod headless;
+mod history;
+mod idle;
+mod kill_switch;
+mod managed_profiles;
+mod messages;
+mod metrics;
+mod notifications;
+mod pac;
+pub mod parsers;
+mod profile_store;
+mod qr;
+mod relay;
+mod sandbox;
+mod scheduler;
+mod session;
+mod shutdown;
+mod ssh_config;
+mod stats_panel;
+mod stats_store;
+mod status_file;
+mod system_proxy;
+mod tproxy_rules;
+
+pub use daemon::DAEMON_ARG;
+pub use relay::RELAY_ARG;
+
+use client::access_log::AccessLogWriter;
+use client::manager::ClientManager;
+use clipboard_watch::ClipboardWatch;
+use control_api::ControlApi;
+use deep_link::DeepLinkImports;
+use history::ConnectionHistory;
+use kill_switch::KillSwitch;
+use managed_profiles::ManagedSources;
+use messages::Messages;
+use metrics::MetricsServer;
+use pac::PacServer;
+use relay::RelayProcesses;
+use session::Session;
+use stats_panel::StatsSubscribers;
+use stats_store::StatsStore;
+use system_proxy::SystemProxy;
+use tauri::{Manager, RunEvent, WindowEvent};
+use tauri_plugin_deep_link::DeepLinkExt;
+
+fn context() -> tauri::Context {
+    tauri::generate_context!()
+}
+
+/// Connect a saved profile and run until interrupted, without any window
+pub fn run_headless(profile_name: &str) -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    headless::run(profile_name, &context().config().identifier)
+}
+
+/// Relay stdio to a host through a profile, for ssh to run the app as its ProxyCommand.
+/// Only warnings are logged, ssh showing them in the terminal
+pub fn run_stdio_bridge(profile_name: &str, host: &str, port: u16) -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    headless::stdio_bridge(profile_name, host, port, &context().config().identifier)
+}
+
+/// Run the tunnel engine in the background, controlled by the app over a local socket
+pub fn run_daemon() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    daemon::run(&context().config().identifier)
+}
+
+/// Run the relay of an isolated profile, started by the app which drives it through the standard input and output
+pub fn run_relay() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    relay::run_child()
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
-    tauri::Builder::default()
+    let handoff = handoff::requested();
+    let mut builder = tauri::Builder::default();
+    // The version started by an upgrade runs alongside the previous one until it took over
+    if handoff.is_none() {
+        // Links opened while the app runs are forwarded to it by the new instance
+        builder = builder.plugin(tauri_plugin_single_instance::init(|app, _args, _cwd| {
+            if let Some(window) = app.get_webview_window("main") {
+                let _ = window.set_focus();
+            }
+        }));
+    }
+    builder
+        .plugin(tauri_plugin_deep_link::init())
         .plugin(tauri_plugin_store::Builder::new().build())
-        .setup(|app| {
-            if cfg!(debug_assertions) {
-                app.handle().plugin(
-                    tauri_plugin_log::Builder::default()
-                        .level(log::LevelFilter::Info)
-                        .build(),
-                )?;
+        .plugin(autostart::plugin())
+        .plugin(tauri_plugin_notification::init())
+        .manage(ClientManager::default())
+        .manage(ClipboardWatch::default())
+        .manage(ControlApi::default())
+        .manage(DeepLinkImports::default())
+        .manage(MetricsServer::default())
+        .manage(PacServer::default())
+        .manage(RelayProcesses::default())
+        .manage(StatsSubscribers::default())
+        .invoke_handler(tauri::generate_handler![
+            commands::check_profile,
+            commands::repair_profile,
+            commands::duplicate_profile,
+            commands::set_require_confirmation,
+            commands::validate_profile,
+            commands::export_profiles,
+            commands::import_profiles,
+            commands::export_profile_bundle,
+            commands::get_config_migration_report,
+            commands::import_profile_bundle,
+            commands::get_profile_qr_code,
+            commands::import_profile_qr_code,
+            commands::get_clipboard_watch,
+            commands::set_clipboard_watch,
+            commands::set_locale,
+            commands::get_message_catalog,
+            commands::connect,
+            commands::disconnect,
+            commands::update_profile,
+            commands::set_secret,
+            commands::delete_secret,
+            commands::start_group,
+            commands::stop_group,
+            commands::get_group_stats,
+            commands::connect_many,
+            commands::disconnect_all,
+            commands::apply_profiles_bundle,
+            commands::list_tunnel_presets,
+            commands::create_tunnel_from_preset,
+            commands::hash_listener_password,
+            commands::create_temp_tunnel,
+            commands::remove_temp_tunnel,
+            commands::confirm_profile_import,
+            commands::reject_profile_import,
+            commands::oauth_sign_out,
+            commands::refresh_connections,
+            commands::set_app_rules,
+            commands::benchmark_buffers,
+            commands::get_pool_status,
+            commands::warm_pool,
+            commands::drain_pool,
+            commands::probe_servers,
+            commands::setup_check_url,
+            commands::setup_check_reachability,
+            commands::setup_check_upgrade,
+            commands::setup_suggest_config,
+            commands::get_connection_quality,
+            commands::get_link_quality,
+            commands::get_multipath_status,
+            commands::inject_fault,
+            commands::trace_next_connection,
+            commands::start_capture,
+            commands::stop_capture,
+            commands::get_datagram_status,
+            commands::set_udp_timeout,
+            commands::get_active_connections,
+            commands::list_connections,
+            commands::kill_connection,
+            commands::get_connection_trace,
+            commands::get_pac_url,
+            commands::start_metrics_endpoint,
+            commands::stop_metrics_endpoint,
+            commands::start_control_api,
+            commands::stop_control_api,
+            commands::get_capabilities,
+            commands::get_net_admin_status,
+            commands::grant_net_admin,
+            commands::get_tproxy_rules,
+            commands::remove_tproxy_rules,
+            commands::get_transports,
+            commands::get_autostart,
+            commands::set_autostart,
+            commands::get_interrupted_session,
+            commands::restore_interrupted_session,
+            commands::dismiss_interrupted_session,
+            commands::get_session_recovery,
+            commands::get_schedules,
+            commands::set_session_recovery,
+            commands::upgrade_handoff,
+            commands::get_daemon_status,
+            commands::install_daemon,
+            commands::uninstall_daemon,
+            commands::daemon_connect,
+            commands::daemon_disconnect,
+            commands::get_status,
+            commands::explain_route,
+            commands::query_stats,
+            commands::get_connection_history,
+            commands::export_connection_history,
+            commands::get_budget_usage,
+            commands::check_dns_leak,
+            commands::run_selftest,
+            commands::export_diagnostics,
+            commands::set_log_level,
+            commands::get_log_levels,
+            commands::generate_ssh_config,
+            commands::remove_ssh_config,
+            commands::get_status_file_path,
+            commands::parse_tunnel_spec,
+            commands::get_managed_sources,
+            commands::add_managed_source,
+            commands::remove_managed_source,
+            commands::refresh_managed_source,
+            commands::approve_managed_settings,
+            commands::trust_server_certificate,
+            commands::forget_server_certificate,
+            commands::inspect_server_certificate,
+            commands::open_stats_window,
+            commands::subscribe_stats,
+            commands::unsubscribe_stats
+        ])
+        .setup(move |app| {
+            let log_dir = diagnostics::log_dir(app.handle())?;
+            diagnostics::prune_logs(&log_dir);
+            app.manage(AccessLogWriter::spawn(log_dir.clone()));
+            app.handle().plugin(diagnostics::log_plugin(log_dir))?;
+            // Before the frontend or anything else reads the saved profiles
+            if let Err(err) = config_migration::migrate(&app.path().app_data_dir()?) {
+                log::error!("Cannot migrate the saved profiles: {:?}", err);
+            }
+            app.manage(Messages::open(&app.path().app_data_dir()?));
+
+            let system_proxy = SystemProxy::new(&app.path().app_data_dir()?);
+            // Settings left by the previous version are kept while it hands its tunnels over
+            let recovered = match handoff {
+                Some(_) => system_proxy.adopt(),
+                None => system_proxy.recover(),
+            };
+            if let Err(err) = recovered {
+                log::error!("Cannot restore system proxy settings: {:?}", err);
+            }
+            app.manage(system_proxy);
+
+            let kill_switch = KillSwitch::new(&app.path().app_data_dir()?);
+            let recovered = match handoff {
+                Some(_) => kill_switch.adopt(),
+                None => kill_switch.recover(),
+            };
+            if let Err(err) = recovered {
+                log::error!("Cannot remove the kill switch rules: {:?}", err);
+            }
+            app.manage(kill_switch);
+
+            let stats_store = StatsStore::open(&app.path().app_data_dir()?).or_else(|err| {
+                log::error!(
+                    "Cannot open traffic history, keeping it in memory: {:?}",
+                    err
+                );
+                StatsStore::in_memory()
+            })?;
+            app.manage(stats_store);
+            let history = ConnectionHistory::open(&app.path().app_data_dir()?).or_else(|err| {
+                log::error!(
+                    "Cannot open connection history, keeping it in memory: {:?}",
+                    err
+                );
+                ConnectionHistory::in_memory()
+            })?;
+            app.manage(history);
+            stats_store::spawn_sampler(app.handle().clone());
+            idle::spawn_enforcer(app.handle().clone());
+            scheduler::spawn(app.handle().clone());
+            status_file::spawn_writer(app.handle().clone());
+            app.manage(Session::open(&app.path().app_data_dir()?));
+            session::spawn_writer(app.handle().clone());
+            app.manage(ManagedSources::open(&app.path().app_data_dir()?));
+            managed_profiles::spawn_poller(app.handle().clone());
+
+            // Only bundled apps get the scheme registered at install time
+            #[cfg(any(windows, target_os = "linux"))]
+            app.deep_link().register_all()?;
+            let handle = app.handle().clone();
+            app.deep_link().on_open_url(move |event| {
+                handle
+                    .state::<DeepLinkImports>()
+                    .handle_urls(&handle, &event.urls());
+            });
+            // The app may have been launched by a link
+            if let Some(urls) = app.deep_link().get_current()? {
+                app.state::<DeepLinkImports>()
+                    .handle_urls(app.handle(), &urls);
+            }
+
+            if let Some(endpoint) = handoff.clone() {
+                // The previous version is running the profiles, they are not interrupted
+                app.state::<Session>().take_interrupted();
+                handoff::take_over(app.handle().clone(), endpoint);
+            } else {
+                if autostart::launched_at_login() {
+                    autostart::connect_marked_profiles(app.handle().clone());
+                }
+                session::recover(app.handle());
             }
             Ok(())
         })
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .on_window_event(|window, event| {
+            if let WindowEvent::Destroyed = event {
+                window
+                    .state::<StatsSubscribers>()
+                    .unsubscribe(window.label());
+            }
+        })
+        .build(context())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let RunEvent::Exit = event {
+                shutdown::on_exit(app);
+            }
+        });
 }
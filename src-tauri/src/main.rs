@@ -1,15 +1,69 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-mod client;
-
-use wstunnel::tunnel::client::WsClient;
-
 #[tauri::command]
 fn greet(name: &str) -> String {
     format!("Hello, {}!", name)
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|arg| arg == "--headless") {
+        let Some(profile) = args
+            .iter()
+            .position(|arg| arg == "--profile")
+            .and_then(|i| args.get(i + 1))
+        else {
+            eprintln!("Usage: {} --headless --profile <name>", args[0]);
+            std::process::exit(2);
+        };
+        if let Err(err) = app_lib::run_headless(profile) {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // ProxyCommand "<app>" --stdio-bridge --profile <name> %h %p
+    if args.iter().any(|arg| arg == "--stdio-bridge") {
+        let profile = args
+            .iter()
+            .position(|arg| arg == "--profile")
+            .and_then(|i| args.get(i + 1));
+        let target = args.len().checked_sub(2).map(|i| (&args[i], &args[i + 1]));
+        let (Some(profile), Some((host, port))) = (profile, target) else {
+            eprintln!(
+                "Usage: {} --stdio-bridge --profile <name> <host> <port>",
+                args[0]
+            );
+            std::process::exit(2);
+        };
+        let Ok(port) = port.parse() else {
+            eprintln!("Invalid port {}", port);
+            std::process::exit(2);
+        };
+        if let Err(err) = app_lib::run_stdio_bridge(profile, host, port) {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == app_lib::RELAY_ARG) {
+        if let Err(err) = app_lib::run_relay() {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == app_lib::DAEMON_ARG) {
+        if let Err(err) = app_lib::run_daemon() {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     app_lib::run();
 }
@@ -0,0 +1,469 @@
+use crate::bulk;
+use crate::client::held_settings::{self, HeldSetting};
+use crate::client::manager::ClientManager;
+use crate::client::profile::Profile;
+use crate::commands;
+use crate::history::ConnectionHistory;
+use crate::kill_switch::KillSwitch;
+use crate::pac::PacServer;
+use crate::profile_store::{self, PROFILE_STORE, PROFILE_STORE_KEY};
+use crate::relay::RelayProcesses;
+use crate::system_proxy::SystemProxy;
+use anyhow::{anyhow, Context};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::StatusCode;
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, Url};
+use tauri_plugin_store::StoreExt;
+
+const SOURCES_FILE: &str = "managed-sources.json";
+/// Header carrying the ed25519 signature of the body, base64 encoded
+const SIGNATURE_HEADER: &str = "x-signature";
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const MIN_POLL_INTERVAL: Duration = Duration::from_secs(60);
+/// Sources are checked that often, each one being fetched once its poll interval elapsed
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// Sent to the frontend once the profiles of a source have been fetched, or failed to
+pub const MANAGED_PROFILES_EVENT: &str = "managed-profiles-updated";
+
+/// Url the profiles of an administrator are fetched from. The document is a json object holding the profiles and
+/// their version, i.e: `{"version": 3, "profiles": [...]}`, signed with the key of the administrator, the signature
+/// being sent along in the `X-Signature` header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ManagedSource {
+    pub url: Url,
+    /// Ed25519 public key the documents must be signed with, base64 encoded
+    pub public_key: String,
+    pub poll_interval_sec: Option<u64>,
+    /// Of the last document applied, sent back so an unchanged document is not downloaded again
+    #[serde(default)]
+    pub etag: Option<String>,
+    #[serde(default)]
+    pub last_checked_at_ms: Option<i64>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Of the last document applied. A document with a lower version is an older one served again, rejected
+    #[serde(default)]
+    pub version: Option<u64>,
+    /// Profiles saved from the last document applied
+    #[serde(default)]
+    pub profiles: Vec<String>,
+    /// Settings running commands, loading code or reading local files that the last document applied has and the
+    /// user did not approve yet, left out of its profiles
+    #[serde(default)]
+    pub pending_settings: Vec<ProfileSettings>,
+    /// Settings the user approved, put back for as long as the source sends them unchanged
+    #[serde(default)]
+    pub approved_settings: Vec<ProfileSettings>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileSettings {
+    pub profile_id: String,
+    pub settings: Vec<HeldSetting>,
+}
+
+/// Signed document of a source
+#[derive(Debug, Deserialize)]
+struct ManagedDocument {
+    /// Raised by the administrator with each change
+    version: u64,
+    profiles: Vec<Profile>,
+}
+
+/// What applying a document changed
+struct Applied {
+    etag: Option<String>,
+    version: u64,
+    profiles: Vec<String>,
+    pending_settings: Vec<ProfileSettings>,
+}
+
+impl ManagedSource {
+    fn poll_interval(&self) -> Duration {
+        self.poll_interval_sec
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_POLL_INTERVAL)
+            .max(MIN_POLL_INTERVAL)
+    }
+
+    fn due(&self, now_ms: i64) -> bool {
+        match self.last_checked_at_ms {
+            Some(checked) => now_ms - checked >= self.poll_interval().as_millis() as i64,
+            None => true,
+        }
+    }
+}
+
+/// Sources of the managed profiles, saved in the data directory
+pub struct ManagedSources {
+    file: PathBuf,
+    sources: Mutex<Vec<ManagedSource>>,
+    /// Syncs run one at a time, so two of them never apply documents of the same source out of order
+    syncing: tokio::sync::Mutex<()>,
+}
+
+impl ManagedSources {
+    pub fn open(data_dir: &Path) -> Self {
+        let file = data_dir.join(SOURCES_FILE);
+        let sources = match std::fs::read(&file) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|err| {
+                warn!("Ignoring invalid managed profile sources: {:?}", err);
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        Self {
+            file,
+            sources: Mutex::new(sources),
+            syncing: tokio::sync::Mutex::const_new(()),
+        }
+    }
+
+    pub fn list(&self) -> Vec<ManagedSource> {
+        self.sources.lock().clone()
+    }
+
+    pub fn add(
+        &self,
+        url: Url,
+        public_key: String,
+        poll_interval_sec: Option<u64>,
+    ) -> anyhow::Result<()> {
+        if url.scheme() != "https" {
+            return Err(anyhow!("Managed profiles must be fetched over https"));
+        }
+        public_key_bytes(&public_key)?;
+        let mut sources = self.sources.lock();
+        sources.retain(|source| source.url != url);
+        sources.push(ManagedSource {
+            url,
+            public_key,
+            poll_interval_sec,
+            etag: None,
+            last_checked_at_ms: None,
+            last_error: None,
+            version: None,
+            profiles: vec![],
+            pending_settings: vec![],
+            approved_settings: vec![],
+        });
+        self.save(&sources)
+    }
+
+    fn remove(&self, url: &Url) -> anyhow::Result<Option<ManagedSource>> {
+        let mut sources = self.sources.lock();
+        let Some(index) = sources.iter().position(|source| &source.url == url) else {
+            return Ok(None);
+        };
+        let removed = sources.remove(index);
+        self.save(&sources)?;
+        Ok(Some(removed))
+    }
+
+    fn get(&self, url: &Url) -> Option<ManagedSource> {
+        self.sources
+            .lock()
+            .iter()
+            .find(|source| &source.url == url)
+            .cloned()
+    }
+
+    fn update(&self, url: &Url, change: impl FnOnce(&mut ManagedSource)) -> anyhow::Result<()> {
+        let mut sources = self.sources.lock();
+        if let Some(source) = sources.iter_mut().find(|source| &source.url == url) {
+            change(source);
+        }
+        self.save(&sources)
+    }
+
+    /// Write then rename, so a crash while writing does not lose the previous content
+    fn save(&self, sources: &[ManagedSource]) -> anyhow::Result<()> {
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.file.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(sources)?)
+            .with_context(|| "Cannot save managed profile sources")?;
+        std::fs::rename(&tmp, &self.file)?;
+        Ok(())
+    }
+}
+
+fn public_key_bytes(public_key: &str) -> anyhow::Result<Vec<u8>> {
+    let key = STANDARD
+        .decode(public_key.trim())
+        .with_context(|| "Public key is not valid base64")?;
+    if key.len() != 32 {
+        return Err(anyhow!("Public key must be a 32 bytes ed25519 key"));
+    }
+    Ok(key)
+}
+
+/// Fetch the profiles of a source and apply them when they changed: new and edited profiles are saved, the
+/// connected ones reconnected, and the ones the source no longer has are disconnected and removed.
+/// A document that is not signed with the key of the source, or older than the last one applied, is rejected as a
+/// whole.
+pub async fn sync(app: &AppHandle, url: &Url) -> anyhow::Result<()> {
+    let sources = app.state::<ManagedSources>();
+    let _guard = sources.syncing.lock().await;
+    let source = sources
+        .get(url)
+        .ok_or_else(|| anyhow!("No managed profile source {}", url))?;
+    let result = fetch_and_apply(app, &source).await;
+    let now = Utc::now().timestamp_millis();
+    sources.update(url, |source| {
+        source.last_checked_at_ms = Some(now);
+        source.last_error = result.as_ref().err().map(|err| format!("{:#}", err));
+        if let Ok(Some(applied)) = &result {
+            source.etag = applied.etag.clone();
+            source.version = Some(applied.version);
+            source.profiles = applied.profiles.clone();
+            source.pending_settings = applied.pending_settings.clone();
+        }
+    })?;
+    if let Some(source) = sources.get(url) {
+        if let Err(err) = app.emit(MANAGED_PROFILES_EVENT, source) {
+            warn!("Cannot report managed profiles: {:?}", err);
+        }
+    }
+    result.map(|_| ())
+}
+
+/// Approve the held settings of a managed profile, and apply the document of its source again with them
+pub async fn approve_settings(app: &AppHandle, url: &Url, profile_id: &str) -> anyhow::Result<()> {
+    let sources = app.state::<ManagedSources>();
+    let pending = sources
+        .get(url)
+        .ok_or_else(|| anyhow!("No managed profile source {}", url))?
+        .pending_settings
+        .into_iter()
+        .find(|pending| pending.profile_id == profile_id)
+        .ok_or_else(|| anyhow!("No settings of profile {} wait for approval", profile_id))?;
+    sources.update(url, |source| {
+        source
+            .approved_settings
+            .retain(|approved| approved.profile_id != profile_id);
+        source.approved_settings.push(pending);
+        // Fetched again in full, the document did not change
+        source.etag = None;
+    })?;
+    sync(app, url).await
+}
+
+/// What the document changed once applied, None when it did not change
+async fn fetch_and_apply(
+    app: &AppHandle,
+    source: &ManagedSource,
+) -> anyhow::Result<Option<Applied>> {
+    let http = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let mut request = http.get(source.url.clone());
+    if let Some(etag) = &source.etag {
+        request = request.header(IF_NONE_MATCH, etag);
+    }
+    let response = request.send().await?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    let response = response.error_for_status()?;
+    let etag = response
+        .headers()
+        .get(ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let signature = response
+        .headers()
+        .get(SIGNATURE_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| anyhow!("The document is not signed"))?;
+    let signature = STANDARD
+        .decode(signature.trim())
+        .with_context(|| "Signature is not valid base64")?;
+    let body = response.bytes().await?;
+    UnparsedPublicKey::new(&ED25519, public_key_bytes(&source.public_key)?)
+        .verify(&body, &signature)
+        .map_err(|_| {
+            anyhow!("The signature of the document does not match the key of the source")
+        })?;
+
+    let ManagedDocument {
+        version,
+        mut profiles,
+    } = serde_json::from_slice(&body)
+        .with_context(|| "The document is not a versioned list of profiles")?;
+    // A signed document stays valid forever, an older one would bring back the profiles it replaced
+    if let Some(last) = source.version.filter(|last| version < *last) {
+        return Err(anyhow!(
+            "The document has version {}, older than version {} already applied",
+            version,
+            last
+        ));
+    }
+    let saved = saved_profiles(app)?;
+    let mut pending_settings = vec![];
+    for profile in profiles.iter_mut() {
+        // A managed profile never replaces one of the user, nor one of another source
+        if let Some(existing) = saved.iter().find(|saved| saved.name == profile.name) {
+            if existing.managed_by.as_ref() != Some(&source.url) {
+                return Err(anyhow!(
+                    "Profile {} already exists and is not managed by this source",
+                    profile.name
+                ));
+            }
+        }
+        profile.managed_by = Some(source.url.clone());
+
+        // Only the settings the user approved as they are now are put back
+        let approved = source
+            .approved_settings
+            .iter()
+            .find(|approved| approved.profile_id == profile.name)
+            .map(|approved| approved.settings.as_slice())
+            .unwrap_or_default();
+        let (kept, pending): (Vec<HeldSetting>, Vec<HeldSetting>) = held_settings::hold(profile)
+            .into_iter()
+            .partition(|setting| approved.contains(setting));
+        held_settings::restore(profile, &kept)?;
+        if !pending.is_empty() {
+            warn!(
+                "Managed profile {} has settings waiting for approval, it is saved without them",
+                profile.name
+            );
+            pending_settings.push(ProfileSettings {
+                profile_id: profile.name.clone(),
+                settings: pending,
+            });
+        }
+    }
+    let names: Vec<String> = profiles
+        .iter()
+        .map(|profile| profile.name.clone())
+        .collect();
+
+    let report = bulk::apply_profiles_bundle(app, profiles).await?;
+    if !report.committed {
+        let failed = report
+            .profiles
+            .iter()
+            .find_map(|entry| match &entry.outcome {
+                bulk::BulkOutcome::Failed { error } => {
+                    Some(format!("{}: {}", entry.profile_id, error))
+                }
+                _ => None,
+            })
+            .unwrap_or_default();
+        return Err(anyhow!("Cannot apply managed profiles, {}", failed));
+    }
+    let gone: Vec<String> = source
+        .profiles
+        .iter()
+        .filter(|name| !names.contains(name))
+        .cloned()
+        .collect();
+    forget(app, &gone)?;
+    info!(
+        "Applied {} managed profiles of version {} from {}, removed {}",
+        names.len(),
+        version,
+        source.url,
+        gone.len()
+    );
+    Ok(Some(Applied {
+        etag,
+        version,
+        profiles: names,
+        pending_settings,
+    }))
+}
+
+fn saved_profiles(app: &AppHandle) -> anyhow::Result<Vec<Profile>> {
+    let data_dir = app.path().app_data_dir()?;
+    // Nothing saved yet
+    if !data_dir.join(PROFILE_STORE).exists() {
+        return Ok(vec![]);
+    }
+    profile_store::load_profiles(&data_dir)
+}
+
+/// Disconnect the profiles and remove them from the saved ones
+fn forget(app: &AppHandle, profile_ids: &[String]) -> anyhow::Result<()> {
+    if profile_ids.is_empty() {
+        return Ok(());
+    }
+    for profile_id in profile_ids {
+        let connected = app.state::<ClientManager>().get(profile_id).is_some()
+            || app.state::<RelayProcesses>().contains(profile_id);
+        if !connected {
+            continue;
+        }
+        if let Err(err) = commands::disconnect(
+            profile_id.clone(),
+            app.state::<ClientManager>(),
+            app.state::<RelayProcesses>(),
+            app.state::<SystemProxy>(),
+            app.state::<PacServer>(),
+            app.state::<KillSwitch>(),
+            app.state::<ConnectionHistory>(),
+        ) {
+            warn!(
+                "Cannot disconnect removed managed profile {}: {}",
+                profile_id, err
+            );
+        }
+    }
+    let store = app.store(PROFILE_STORE)?;
+    if let Some(serde_json::Value::Array(mut profiles)) = store.get(PROFILE_STORE_KEY) {
+        profiles.retain(|entry| {
+            !entry
+                .get("name")
+                .and_then(|name| name.as_str())
+                .is_some_and(|name| profile_ids.iter().any(|id| id == name))
+        });
+        store.set(PROFILE_STORE_KEY, serde_json::Value::Array(profiles));
+        store.save()?;
+    }
+    Ok(())
+}
+
+/// Stop fetching the profiles of a source, removing the ones it had
+pub fn remove_source(app: &AppHandle, url: &Url) -> anyhow::Result<()> {
+    let removed = app
+        .state::<ManagedSources>()
+        .remove(url)?
+        .ok_or_else(|| anyhow!("No managed profile source {}", url))?;
+    forget(app, &removed.profiles)
+}
+
+/// Fetch the sources whose poll interval elapsed, for as long as the app runs
+pub fn spawn_poller(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let now = Utc::now().timestamp_millis();
+            let due: Vec<Url> = app
+                .state::<ManagedSources>()
+                .list()
+                .into_iter()
+                .filter(|source| source.due(now))
+                .map(|source| source.url)
+                .collect();
+            for url in due {
+                if let Err(err) = sync(&app, &url).await {
+                    error!("Cannot update managed profiles from {}: {:?}", url, err);
+                }
+            }
+        }
+    });
+}
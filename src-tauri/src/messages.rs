@@ -0,0 +1,361 @@
+use crate::client::events::ClientEvent;
+use crate::client::failure_cause::FailureCause;
+use crate::client::quality::Degradation;
+use anyhow::Context;
+use log::warn;
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+const LOCALE_FILE: &str = "locale.json";
+
+/// Language of the messages shown to the user, in notifications or sent to the frontend
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Locale {
+    #[default]
+    En,
+    Ru,
+    De,
+}
+
+/// Locale chosen by the user, saved in the data directory so the notifications sent before the frontend loads
+/// are in that locale too
+pub struct Messages {
+    file: PathBuf,
+    locale: RwLock<Locale>,
+}
+
+impl Messages {
+    pub fn open(data_dir: &Path) -> Self {
+        let file = data_dir.join(LOCALE_FILE);
+        let locale = match std::fs::read(&file) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|err| {
+                warn!("Ignoring invalid locale: {:?}", err);
+                Locale::default()
+            }),
+            Err(_) => Locale::default(),
+        };
+        Self {
+            file,
+            locale: RwLock::new(locale),
+        }
+    }
+
+    pub fn locale(&self) -> Locale {
+        *self.locale.read()
+    }
+
+    /// Write then rename, so a crash while writing does not lose the previous choice
+    pub fn set_locale(&self, locale: Locale) -> anyhow::Result<()> {
+        let mut current = self.locale.write();
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.file.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&locale)?)
+            .with_context(|| "Cannot save the locale")?;
+        std::fs::rename(&tmp, &self.file)?;
+        *current = locale;
+        Ok(())
+    }
+}
+
+/// Templates of a locale by message key, for the frontend to render the typed causes and events it receives
+/// with the same text as the notifications. Parameters are written `{name}`, after the fields of the enums
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageCatalog {
+    pub locale: Locale,
+    pub messages: BTreeMap<&'static str, &'static str>,
+}
+
+/// Every message of a locale, those it lacks given in english
+pub fn catalog(locale: Locale) -> MessageCatalog {
+    let mut messages: BTreeMap<_, _> = EN.iter().copied().collect();
+    messages.extend(templates(locale).iter().copied());
+    MessageCatalog { locale, messages }
+}
+
+/// Short explanation of why a tunnel failed, with what to check when that is known
+pub fn failure_cause(locale: Locale, cause: &FailureCause) -> String {
+    match cause {
+        FailureCause::DnsFailure => render(locale, "failure.dns_failure", &[]),
+        FailureCause::ConnectionRefused => render(locale, "failure.connection_refused", &[]),
+        FailureCause::Timeout => render(locale, "failure.timeout", &[]),
+        FailureCause::TlsVerifyFailed { reason } => {
+            render(locale, "failure.tls_verify_failed", &[("reason", reason)])
+        }
+        FailureCause::UpgradeRejected { status: 401 } => {
+            render(locale, "failure.upgrade_rejected.401", &[])
+        }
+        FailureCause::UpgradeRejected { status: 403 } => {
+            render(locale, "failure.upgrade_rejected.403", &[])
+        }
+        FailureCause::UpgradeRejected { status: 404 } => {
+            render(locale, "failure.upgrade_rejected.404", &[])
+        }
+        FailureCause::UpgradeRejected { status } => render(
+            locale,
+            "failure.upgrade_rejected",
+            &[("status", &status.to_string())],
+        ),
+        FailureCause::ProxyAuthRequired => render(locale, "failure.proxy_auth_required", &[]),
+        FailureCause::Other => render(locale, "failure.other", &[]),
+    }
+}
+
+/// Text of the notification about an event of a connected profile, None for the events not worth one
+pub fn event(locale: Locale, event: &ClientEvent) -> Option<String> {
+    let message = match event {
+        ClientEvent::ServerUnreachable => render(locale, "event.server_unreachable", &[]),
+        ClientEvent::ServerReachable => render(locale, "event.server_reachable", &[]),
+        ClientEvent::LinkMeasured => return None,
+        ClientEvent::LinkDegraded { degradation } => {
+            let key = match degradation {
+                Degradation::PacketLoss => "event.link_degraded.packet_loss",
+                Degradation::HighLatency => "event.link_degraded.high_latency",
+                Degradation::HighJitter => "event.link_degraded.high_jitter",
+            };
+            render(locale, key, &[])
+        }
+        ClientEvent::LinkRecovered => render(locale, "event.link_recovered", &[]),
+        // Notified once the tunnel stops for good
+        ClientEvent::TunnelFailed { .. } => return None,
+        ClientEvent::ReconnectExhausted { cause, .. } => render(
+            locale,
+            "event.reconnect_exhausted",
+            &[("cause", &failure_cause(locale, cause))],
+        ),
+        ClientEvent::StdioClosed { tunnel_id } => {
+            render(locale, "event.stdio_closed", &[("tunnel_id", tunnel_id)])
+        }
+        ClientEvent::CertificateExpiring { days_left } => render(
+            locale,
+            "event.certificate_expiring",
+            &[("days_left", &days_left.to_string())],
+        ),
+        ClientEvent::CertificateExpired => render(locale, "event.certificate_expired", &[]),
+        ClientEvent::CertificateRenewed { days_left } => render(
+            locale,
+            "event.certificate_renewed",
+            &[("days_left", &days_left.to_string())],
+        ),
+        ClientEvent::CertificateRenewalFailed { error } => render(
+            locale,
+            "event.certificate_renewal_failed",
+            &[("error", error)],
+        ),
+        ClientEvent::ReverseTunnelLive {
+            tunnel_id,
+            public_addr,
+        } => render(
+            locale,
+            "event.reverse_tunnel_live",
+            &[("tunnel_id", tunnel_id), ("public_addr", public_addr)],
+        ),
+        // Way too many to notify each of them
+        ClientEvent::ReverseConnectionAccepted { .. }
+        | ClientEvent::ReverseConnectionClosed { .. } => return None,
+        ClientEvent::LocalDnsResolution {
+            tunnel_id,
+            destination,
+        } => render(
+            locale,
+            "event.local_dns_resolution",
+            &[("tunnel_id", tunnel_id), ("destination", destination)],
+        ),
+        ClientEvent::PortMapped { tunnel_id, mapping } => render(
+            locale,
+            "event.port_mapped",
+            &[
+                ("tunnel_id", tunnel_id),
+                ("external_addr", &mapping.external_addr.to_string()),
+            ],
+        ),
+        ClientEvent::PortMappingFailed { tunnel_id, error } => render(
+            locale,
+            "event.port_mapping_failed",
+            &[("tunnel_id", tunnel_id), ("error", error)],
+        ),
+    };
+    Some(message)
+}
+
+/// Fill the template of `key` in the locale, falling back to english then to the key itself.
+/// The template is read once: a value holding `{name}`, i.e: an error message, is left as it is
+fn render(locale: Locale, key: &str, params: &[(&str, &str)]) -> String {
+    let template = lookup(locale, key)
+        .or_else(|| lookup(Locale::En, key))
+        .unwrap_or(key);
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        let param = &rest[start..];
+        let value = param[1..].find('}').and_then(|end| {
+            let name = &param[1..end + 1];
+            params
+                .iter()
+                .find(|(param, _)| *param == name)
+                .map(|(_, value)| (*value, end + 2))
+        });
+        match value {
+            Some((value, len)) => {
+                text.push_str(value);
+                rest = &param[len..];
+            }
+            None => {
+                text.push('{');
+                rest = &param[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+fn lookup(locale: Locale, key: &str) -> Option<&'static str> {
+    templates(locale)
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, template)| *template)
+}
+
+fn templates(locale: Locale) -> &'static [(&'static str, &'static str)] {
+    match locale {
+        Locale::En => EN,
+        Locale::Ru => RU,
+        Locale::De => DE,
+    }
+}
+
+const EN: &[(&str, &str)] = &[
+    ("failure.dns_failure", "Cannot resolve the server name, check the server address and the dns settings"),
+    ("failure.connection_refused", "The server refused the connection, check the server port and that wstunnel runs on it"),
+    ("failure.timeout", "The server did not answer in time"),
+    ("failure.tls_verify_failed", "The server certificate is not trusted: {reason}"),
+    ("failure.upgrade_rejected.401", "The server rejected the credentials, check the http upgrade credentials"),
+    ("failure.upgrade_rejected.403", "The server rejected the tunnel, check the restrictions of the server"),
+    ("failure.upgrade_rejected.404", "The server rejected the path prefix, check the http upgrade path prefix"),
+    ("failure.upgrade_rejected", "The server rejected the upgrade with status {status}"),
+    ("failure.proxy_auth_required", "The http proxy requires authentication, check the proxy credentials"),
+    ("failure.other", "The tunnel failed"),
+    ("event.server_unreachable", "Server unreachable, tunnels are down"),
+    ("event.server_reachable", "Server reachable again"),
+    ("event.link_degraded.packet_loss", "Connection degraded: probes to the server get lost"),
+    ("event.link_degraded.high_latency", "Connection degraded: high latency to the server"),
+    ("event.link_degraded.high_jitter", "Connection degraded: unstable latency to the server"),
+    ("event.link_recovered", "Connection back to normal"),
+    ("event.reconnect_exhausted", "A tunnel stopped after too many failures. {cause}"),
+    ("event.stdio_closed", "Stdio tunnel {tunnel_id} closed"),
+    ("event.certificate_expiring", "Client certificate expires in {days_left} days"),
+    ("event.certificate_expired", "Client certificate has expired"),
+    ("event.certificate_renewed", "Client certificate renewed, valid for {days_left} days"),
+    ("event.certificate_renewal_failed", "Cannot renew client certificate: {error}"),
+    ("event.reverse_tunnel_live", "Reverse tunnel {tunnel_id} is live on {public_addr}"),
+    ("event.local_dns_resolution", "Tunnel {tunnel_id} refused {destination}, resolved outside the tunnel. Enable remote dns in the proxy settings of the application"),
+    ("event.port_mapped", "Tunnel {tunnel_id} is reachable from the internet on {external_addr}"),
+    ("event.port_mapping_failed", "Cannot map the port of tunnel {tunnel_id}: {error}"),
+];
+
+const RU: &[(&str, &str)] = &[
+    ("failure.dns_failure", "Не удается разрешить имя сервера, проверьте адрес сервера и настройки DNS"),
+    ("failure.connection_refused", "Сервер отклонил соединение, проверьте порт сервера и что на нем запущен wstunnel"),
+    ("failure.timeout", "Сервер не ответил вовремя"),
+    ("failure.tls_verify_failed", "Сертификат сервера не является доверенным: {reason}"),
+    ("failure.upgrade_rejected.401", "Сервер отклонил учетные данные, проверьте учетные данные http upgrade"),
+    ("failure.upgrade_rejected.403", "Сервер отклонил туннель, проверьте ограничения сервера"),
+    ("failure.upgrade_rejected.404", "Сервер отклонил префикс пути, проверьте префикс пути http upgrade"),
+    ("failure.upgrade_rejected", "Сервер отклонил upgrade со статусом {status}"),
+    ("failure.proxy_auth_required", "Http-прокси требует аутентификации, проверьте учетные данные прокси"),
+    ("failure.other", "Сбой туннеля"),
+    ("event.server_unreachable", "Сервер недоступен, туннели не работают"),
+    ("event.server_reachable", "Сервер снова доступен"),
+    ("event.link_degraded.packet_loss", "Соединение ухудшилось: пробы до сервера теряются"),
+    ("event.link_degraded.high_latency", "Соединение ухудшилось: высокая задержка до сервера"),
+    ("event.link_degraded.high_jitter", "Соединение ухудшилось: нестабильная задержка до сервера"),
+    ("event.link_recovered", "Соединение восстановилось"),
+    ("event.reconnect_exhausted", "Туннель остановлен после слишком большого числа сбоев. {cause}"),
+    ("event.stdio_closed", "Stdio-туннель {tunnel_id} закрыт"),
+    ("event.certificate_expiring", "Срок действия клиентского сертификата истекает через {days_left} дн."),
+    ("event.certificate_expired", "Срок действия клиентского сертификата истек"),
+    ("event.certificate_renewed", "Клиентский сертификат обновлен, действителен {days_left} дн."),
+    ("event.certificate_renewal_failed", "Не удается обновить клиентский сертификат: {error}"),
+    ("event.reverse_tunnel_live", "Обратный туннель {tunnel_id} доступен на {public_addr}"),
+    ("event.local_dns_resolution", "Туннель {tunnel_id} отклонил {destination}, имя разрешено вне туннеля. Включите удаленный DNS в настройках прокси приложения"),
+    ("event.port_mapped", "Туннель {tunnel_id} доступен из интернета на {external_addr}"),
+    ("event.port_mapping_failed", "Не удается пробросить порт туннеля {tunnel_id}: {error}"),
+];
+
+const DE: &[(&str, &str)] = &[
+    ("failure.dns_failure", "Der Servername kann nicht aufgelöst werden, prüfen Sie die Serveradresse und die DNS-Einstellungen"),
+    ("failure.connection_refused", "Der Server hat die Verbindung abgelehnt, prüfen Sie den Serverport und ob wstunnel darauf läuft"),
+    ("failure.timeout", "Der Server hat nicht rechtzeitig geantwortet"),
+    ("failure.tls_verify_failed", "Dem Serverzertifikat wird nicht vertraut: {reason}"),
+    ("failure.upgrade_rejected.401", "Der Server hat die Zugangsdaten abgelehnt, prüfen Sie die Zugangsdaten für das HTTP-Upgrade"),
+    ("failure.upgrade_rejected.403", "Der Server hat den Tunnel abgelehnt, prüfen Sie die Einschränkungen des Servers"),
+    ("failure.upgrade_rejected.404", "Der Server hat das Pfadpräfix abgelehnt, prüfen Sie das Pfadpräfix für das HTTP-Upgrade"),
+    ("failure.upgrade_rejected", "Der Server hat das Upgrade mit Status {status} abgelehnt"),
+    ("failure.proxy_auth_required", "Der HTTP-Proxy verlangt eine Authentifizierung, prüfen Sie die Zugangsdaten des Proxys"),
+    ("failure.other", "Der Tunnel ist fehlgeschlagen"),
+    ("event.server_unreachable", "Server nicht erreichbar, die Tunnel sind unterbrochen"),
+    ("event.server_reachable", "Server wieder erreichbar"),
+    ("event.link_degraded.packet_loss", "Verbindung beeinträchtigt: Proben zum Server gehen verloren"),
+    ("event.link_degraded.high_latency", "Verbindung beeinträchtigt: hohe Latenz zum Server"),
+    ("event.link_degraded.high_jitter", "Verbindung beeinträchtigt: schwankende Latenz zum Server"),
+    ("event.link_recovered", "Verbindung wieder normal"),
+    ("event.reconnect_exhausted", "Ein Tunnel wurde nach zu vielen Fehlern angehalten. {cause}"),
+    ("event.stdio_closed", "Stdio-Tunnel {tunnel_id} geschlossen"),
+    ("event.certificate_expiring", "Das Client-Zertifikat läuft in {days_left} Tag(en) ab"),
+    ("event.certificate_expired", "Das Client-Zertifikat ist abgelaufen"),
+    ("event.certificate_renewed", "Client-Zertifikat erneuert, gültig für {days_left} Tag(e)"),
+    ("event.certificate_renewal_failed", "Das Client-Zertifikat kann nicht erneuert werden: {error}"),
+    ("event.reverse_tunnel_live", "Reverse-Tunnel {tunnel_id} ist auf {public_addr} erreichbar"),
+    ("event.local_dns_resolution", "Tunnel {tunnel_id} hat {destination} abgelehnt, der Name wurde außerhalb des Tunnels aufgelöst. Aktivieren Sie Remote-DNS in den Proxy-Einstellungen der Anwendung"),
+    ("event.port_mapped", "Tunnel {tunnel_id} ist aus dem Internet auf {external_addr} erreichbar"),
+    ("event.port_mapping_failed", "Der Port von Tunnel {tunnel_id} kann nicht weitergeleitet werden: {error}"),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn keys(templates: &[(&'static str, &'static str)]) -> BTreeSet<&'static str> {
+        templates.iter().map(|(key, _)| *key).collect()
+    }
+
+    #[test]
+    fn locales_have_the_keys_of_english() {
+        let english = keys(EN);
+        assert_eq!(english.len(), EN.len(), "english has a key twice");
+        for locale in [Locale::Ru, Locale::De] {
+            let translated = keys(templates(locale));
+            assert_eq!(
+                translated.len(),
+                templates(locale).len(),
+                "{:?} has a key twice",
+                locale
+            );
+            assert_eq!(translated, english, "keys of {:?}", locale);
+        }
+    }
+
+    #[test]
+    fn renders_values_as_they_are() {
+        let text = render(
+            Locale::En,
+            "event.port_mapping_failed",
+            &[("tunnel_id", "{error}"), ("error", "no gateway")],
+        );
+        assert_eq!(text, "Cannot map the port of tunnel {error}: no gateway");
+    }
+
+    #[test]
+    fn keeps_unknown_parameters() {
+        let text = render(Locale::En, "failure.upgrade_rejected", &[]);
+        assert_eq!(text, "The server rejected the upgrade with status {status}");
+    }
+}
@@ -0,0 +1,204 @@
+use crate::client::accept::AcceptBackoff;
+use crate::client::manager::ClientManager;
+use crate::client::stats::TunnelMetrics;
+use anyhow::Context;
+use log::{debug, info, warn};
+use parking_lot::Mutex;
+use std::fmt::Write;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::atomic::Ordering;
+use tauri::{AppHandle, Manager};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+const METRICS_PATH: &str = "/metrics";
+const METRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Optional loopback endpoint publishing the tunnel counters in the OpenMetrics format, for Prometheus to scrape
+#[derive(Default)]
+pub struct MetricsServer {
+    running: Mutex<Option<(SocketAddr, JoinHandle<()>)>>,
+}
+
+impl MetricsServer {
+    /// Serve the metrics on the given port of the loopback interface, moving the endpoint if it runs on another port
+    pub async fn start(&self, app: AppHandle, port: u16) -> anyhow::Result<SocketAddr> {
+        if let Some((local_addr, _)) = &*self.running.lock() {
+            if port == local_addr.port() {
+                return Ok(*local_addr);
+            }
+        }
+        self.stop();
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port))
+            .await
+            .with_context(|| format!("Cannot bind metrics endpoint on port {}", port))?;
+        let local_addr = listener.local_addr()?;
+        info!("Serving metrics on http://{}{}", local_addr, METRICS_PATH);
+
+        let task = tokio::spawn(async move {
+            let mut backoff = AcceptBackoff::default();
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => {
+                        backoff.succeeded();
+                        accepted
+                    }
+                    Err(err) => {
+                        warn!("Cannot accept metrics connection: {:?}", err);
+                        backoff.failed().await;
+                        continue;
+                    }
+                };
+                let app = app.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve(stream, &app).await {
+                        debug!("Cannot serve metrics to {}: {:?}", peer, err);
+                    }
+                });
+            }
+        });
+        *self.running.lock() = Some((local_addr, task));
+        Ok(local_addr)
+    }
+
+    pub fn stop(&self) {
+        if let Some((local_addr, task)) = self.running.lock().take() {
+            info!("Metrics endpoint on {} stopped", local_addr);
+            task.abort();
+        }
+    }
+}
+
+/// Answer a single GET request, the connection is closed afterward
+async fn serve(mut stream: TcpStream, app: &AppHandle) -> anyhow::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next().unwrap_or_default());
+
+    let response = match method {
+        Some("GET") if path == METRICS_PATH => {
+            let metrics = render(&app.state::<ClientManager>());
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                METRICS_CONTENT_TYPE,
+                metrics.len(),
+                metrics
+            )
+        }
+        Some("GET") => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Metric families, each sample labelled with its profile and tunnel
+fn render(manager: &ClientManager) -> String {
+    let mut tunnels = vec![];
+    for managed in manager.list() {
+        for (tunnel_id, metrics) in managed.client.stats.all_tunnel_metrics() {
+            let labels = format!(
+                "profile=\"{}\",tunnel=\"{}\"",
+                escape(&managed.profile.name),
+                escape(&tunnel_id)
+            );
+            tunnels.push((labels, metrics));
+        }
+    }
+    tunnels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let counters: [(&str, &str, fn(&TunnelMetrics) -> u64); 5] = [
+        (
+            "wstunnel_tunnel_sent_bytes",
+            "Bytes sent by the local clients",
+            |m| m.traffic.bytes_up.load(Ordering::Relaxed),
+        ),
+        (
+            "wstunnel_tunnel_received_bytes",
+            "Bytes received from the server",
+            |m| m.traffic.bytes_down.load(Ordering::Relaxed),
+        ),
+        (
+            "wstunnel_tunnel_connections",
+            "Connections accepted by the tunnel",
+            |m| m.traffic.connections.load(Ordering::Relaxed),
+        ),
+        (
+            "wstunnel_tunnel_reconnects",
+            "Times the tunnel failed and has been re-established",
+            |m| m.reconnects.load(Ordering::Relaxed),
+        ),
+        (
+            "wstunnel_tunnel_oversized_datagrams",
+            "Udp datagrams larger than the maximum size of the tunnel",
+            |m| m.oversized_datagrams.load(Ordering::Relaxed),
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, help, value) in counters {
+        let _ = writeln!(out, "# TYPE {} counter\n# HELP {} {}", name, name, help);
+        for (labels, metrics) in &tunnels {
+            let _ = writeln!(out, "{}_total{{{}}} {}", name, labels, value(metrics));
+        }
+    }
+
+    let name = "wstunnel_tunnel_active_connections";
+    let _ = writeln!(
+        out,
+        "# TYPE {} gauge\n# HELP {} Connections currently relayed by the tunnel",
+        name, name
+    );
+    for (labels, metrics) in &tunnels {
+        let active = metrics.traffic.active_connections.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, active);
+    }
+
+    let name = "wstunnel_tunnel_handshake_seconds";
+    let _ = writeln!(
+        out,
+        "# TYPE {} histogram\n# UNIT {} seconds\n# HELP {} Time until the first byte from the server, upgrade included",
+        name, name, name
+    );
+    for (labels, metrics) in &tunnels {
+        let (buckets, sum, count) = metrics.handshake.cumulative();
+        for (le, cumulative) in buckets {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, le, cumulative
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+/// Label values are quoted, with backslashes, quotes and line feeds escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
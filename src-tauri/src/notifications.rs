@@ -0,0 +1,80 @@
+use crate::client::cert_monitor;
+use crate::client::events::ClientEvent;
+use crate::client::manager::ManagedClient;
+use crate::messages::{self, Messages};
+use log::{debug, warn};
+use std::path::Path;
+use tauri::{AppHandle, Manager};
+use tauri_plugin_notification::NotificationExt;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Show native notifications about the state of a connected profile, when enabled in the profile.
+/// Giving up on a tunnel is notified as well when its retry policy asks for it.
+/// Stops by itself once the profile is disconnected.
+pub fn watch(app: &AppHandle, managed: &ManagedClient) {
+    let enabled = managed.profile.notifications;
+    if !enabled && !managed.profile.retry_policy.notify_on_give_up {
+        return;
+    }
+
+    let profile_id = managed.profile.name.clone();
+    if let (Some(cert), true) = (&managed.expanded.tls_certificate, enabled) {
+        check_certificate_expiry(
+            app,
+            &profile_id,
+            cert,
+            managed.profile.tls_certificate_warning_days,
+        );
+    }
+
+    let mut events = managed.client.stats.events.subscribe();
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return,
+            };
+            if !enabled && !matches!(event, ClientEvent::ReconnectExhausted { .. }) {
+                continue;
+            }
+            let locale = app.state::<Messages>().locale();
+            let Some(body) = messages::event(locale, &event) else {
+                continue;
+            };
+            notify(&app, &profile_id, &body);
+        }
+    });
+}
+
+fn check_certificate_expiry(app: &AppHandle, profile_id: &str, cert: &Path, warning_days: u64) {
+    let Ok(days_left) = cert_monitor::days_left(cert) else {
+        return;
+    };
+
+    let event = if days_left < 0 {
+        ClientEvent::CertificateExpired
+    } else if days_left < warning_days as i64 {
+        ClientEvent::CertificateExpiring { days_left }
+    } else {
+        return;
+    };
+    let locale = app.state::<Messages>().locale();
+    if let Some(body) = messages::event(locale, &event) {
+        notify(app, profile_id, &body);
+    }
+}
+
+fn notify(app: &AppHandle, profile_id: &str, body: &str) {
+    debug!("Notifying {}: {}", profile_id, body);
+    if let Err(err) = app
+        .notification()
+        .builder()
+        .title(format!("wstunnel - {}", profile_id))
+        .body(body)
+        .show()
+    {
+        warn!("Cannot show notification: {:?}", err);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::client::accept::AcceptBackoff;
+use crate::client::manager::ManagedClient;
+use crate::system_proxy::{reachable_addr, ProxyKind};
+use anyhow::Context;
+use log::{debug, info, warn};
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::OnceCell;
+use url::Url;
+
+const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Serve on loopback one PAC file per connected profile, routing the configured domains through its local proxies
+#[derive(Default)]
+pub struct PacServer {
+    /// Generated scripts, indexed by their url path
+    scripts: Arc<RwLock<HashMap<String, String>>>,
+    local_addr: OnceCell<SocketAddr>,
+}
+
+impl PacServer {
+    /// Generate the PAC file of a connected profile, or remove it if no tunnel of the profile routes any domain
+    pub fn publish(&self, managed: &ManagedClient) -> anyhow::Result<()> {
+        let mut routes = vec![];
+        for config in managed.expanded.enabled_tunnels() {
+            if config.pac_domains.is_empty() {
+                continue;
+            }
+            let tunnel = config.to_tunnel()?;
+            let Some(kind) = ProxyKind::of(&tunnel.local_protocol) else {
+                continue;
+            };
+            if let Some(listener) = managed
+                .client
+                .listeners
+                .iter()
+                .find(|l| l.tunnel_id == tunnel.id)
+            {
+                routes.push((config.pac_domains.as_slice(), kind, listener.bound));
+            }
+        }
+
+        let path = pac_path(&managed.profile.name);
+        if routes.is_empty() {
+            self.scripts.write().remove(&path);
+        } else {
+            self.scripts.write().insert(path, generate_pac(&routes));
+        }
+        Ok(())
+    }
+
+    pub fn unpublish(&self, profile_id: &str) {
+        self.scripts.write().remove(&pac_path(profile_id));
+    }
+
+    /// Url of the PAC file of a profile, starting the server on first use
+    pub async fn url(&self, profile_id: &str) -> anyhow::Result<Url> {
+        let path = pac_path(profile_id);
+        if !self.scripts.read().contains_key(&path) {
+            return Err(anyhow::anyhow!(
+                "Profile {} has no PAC file, no connected tunnel routes any domain",
+                profile_id
+            ));
+        }
+
+        let local_addr = self.local_addr.get_or_try_init(|| self.start()).await?;
+        Ok(Url::parse(&format!("http://{}{}", local_addr, path))?)
+    }
+
+    async fn start(&self) -> anyhow::Result<SocketAddr> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .with_context(|| "Cannot bind PAC server")?;
+        let local_addr = listener.local_addr()?;
+        info!("Serving PAC files on {}", local_addr);
+
+        let scripts = self.scripts.clone();
+        tokio::spawn(async move {
+            let mut backoff = AcceptBackoff::default();
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => {
+                        backoff.succeeded();
+                        accepted
+                    }
+                    Err(err) => {
+                        warn!("Cannot accept PAC connection: {:?}", err);
+                        backoff.failed().await;
+                        continue;
+                    }
+                };
+                let scripts = scripts.clone();
+                tokio::spawn(async move {
+                    if let Err(err) = serve(stream, &scripts).await {
+                        debug!("Cannot serve PAC file to {}: {:?}", peer, err);
+                    }
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+}
+
+/// Answer a single GET request, the connection is closed afterward
+async fn serve(
+    mut stream: TcpStream,
+    scripts: &RwLock<HashMap<String, String>>,
+) -> anyhow::Result<()> {
+    let mut request = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 || request.len() + read > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let (method, path) = (request_line.next(), request_line.next().unwrap_or_default());
+    let script = scripts.read().get(path).cloned();
+
+    let response = match (method, script) {
+        (Some("GET"), Some(script)) => format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n{}",
+            PAC_CONTENT_TYPE,
+            script.len(),
+            script
+        ),
+        (Some("GET"), None) => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            .to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Url path of the PAC file of a profile, percent encoded
+fn pac_path(profile_id: &str) -> String {
+    let mut url = Url::parse("http://localhost/").expect("valid base url");
+    if let Ok(mut segments) = url.path_segments_mut() {
+        segments.push(&format!("{}.pac", profile_id));
+    }
+    url.path().to_string()
+}
+
+fn generate_pac(routes: &[(&[String], ProxyKind, SocketAddr)]) -> String {
+    let mut pac = String::from("function FindProxyForURL(url, host) {\n");
+    for (domains, kind, addr) in routes {
+        let addr = reachable_addr(*addr);
+        let proxy = match kind {
+            ProxyKind::Socks5 => format!("SOCKS5 {addr}; SOCKS {addr}"),
+            ProxyKind::Http => format!("PROXY {addr}"),
+        };
+        let conditions: Vec<String> = domains
+            .iter()
+            .map(|domain| {
+                // A domain matches itself and all its subdomains
+                let domain = domain.trim_start_matches("*.").trim_start_matches('.');
+                // Quoted as json strings, which are valid javascript literals
+                let exact = serde_json::to_string(domain).unwrap_or_default();
+                let suffix = serde_json::to_string(&format!(".{}", domain)).unwrap_or_default();
+                format!("host == {} || dnsDomainIs(host, {})", exact, suffix)
+            })
+            .collect();
+        pac.push_str(&format!(
+            "  if ({}) return \"{}\";\n",
+            conditions.join(" || "),
+            proxy
+        ));
+    }
+    pac.push_str("  return \"DIRECT\";\n}\n");
+    pac
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+use std::ops::Range;
+
+/// Error in a user provided string, pointing at the faulty part of it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub input: String,
+    /// Byte range of the faulty part of the input
+    pub span: Range<usize>,
+    pub reason: String,
+    /// What would have been valid at the faulty part, when it is one of a few known tokens
+    pub expected: Vec<String>,
+}
+
+impl ParseError {
+    /// `part` is expected to be a slice of `input`, otherwise the whole input is pointed at
+    pub fn new(input: &str, part: &str, reason: impl Into<String>) -> Self {
+        Self {
+            input: input.to_string(),
+            span: span_of(input, part),
+            reason: reason.into(),
+            expected: vec![],
+        }
+    }
+
+    pub fn expecting(mut self, tokens: &[&str]) -> Self {
+        self.expected = tokens.iter().map(|t| t.to_string()).collect();
+        self
+    }
+}
+
+fn span_of(input: &str, part: &str) -> Range<usize> {
+    let start = (part.as_ptr() as usize).checked_sub(input.as_ptr() as usize);
+    match start {
+        Some(start) if start + part.len() <= input.len() => start..start + part.len(),
+        _ => 0..input.len(),
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Spans are always on char boundaries, as they come from slices of the input
+        let before = self.input.get(..self.span.start).unwrap_or_default();
+        let part = self.input.get(self.span.clone()).unwrap_or_default();
+        writeln!(f, "{}", self.reason)?;
+        writeln!(f, "  {}", self.input)?;
+        write!(
+            f,
+            "  {}{}",
+            " ".repeat(before.chars().count()),
+            "^".repeat(part.chars().count().max(1))
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Every error found in an input, as an input can be made of independent parts (options, lines)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseErrors(pub Vec<ParseError>);
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ParseErrors {}
+
+impl From<ParseError> for ParseErrors {
+    fn from(error: ParseError) -> Self {
+        Self(vec![error])
+    }
+}
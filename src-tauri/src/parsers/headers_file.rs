@@ -0,0 +1,115 @@
+use crate::parsers::{ParseError, ParseErrors};
+use anyhow::Context;
+use std::path::Path;
+use std::str::FromStr;
+use tauri::http::{HeaderName, HeaderValue};
+
+/// Parse the content of a headers file, one `Name: value` per line.
+/// Empty lines and lines starting with # are ignored. Every invalid line is reported.
+pub fn parse_headers(content: &str) -> Result<Vec<(HeaderName, HeaderValue)>, ParseErrors> {
+    let (mut headers, mut errors) = (vec![], vec![]);
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        let Some((name, value)) = line.split_once(':') else {
+            errors.push(ParseError::new(
+                line,
+                line,
+                "Missing ':' between header name and value",
+            ));
+            continue;
+        };
+        let name = match HeaderName::from_str(name.trim()) {
+            Ok(name) => name,
+            Err(_) => {
+                errors.push(ParseError::new(line, name, "Invalid header name"));
+                continue;
+            }
+        };
+        match HeaderValue::from_str(value.trim()) {
+            Ok(value) => headers.push((name, value)),
+            Err(_) => errors.push(ParseError::new(line, value, "Invalid header value")),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(headers)
+    } else {
+        Err(ParseErrors(errors))
+    }
+}
+
+pub fn read_headers_file(path: &Path) -> anyhow::Result<Vec<(HeaderName, HeaderValue)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read http headers file {}", path.display()))?;
+    parse_headers(&content).with_context(|| format!("Invalid http headers file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_headers() {
+        let cases = [
+            ("", vec![]),
+            ("# comment\n\n", vec![]),
+            ("X-Api-Key: secret", vec![("x-api-key", "secret")]),
+            (
+                "Host:example.com\r\n  # indented comment\nX-Forwarded-For: 10.0.0.1 ",
+                vec![("host", "example.com"), ("x-forwarded-for", "10.0.0.1")],
+            ),
+            (
+                "Authorization: Bearer a:b",
+                vec![("authorization", "Bearer a:b")],
+            ),
+        ];
+        for (content, expected) in cases {
+            let headers = parse_headers(content)
+                .unwrap_or_else(|err| panic!("{:?} is valid, got {}", content, err));
+            let headers: Vec<(&str, &str)> = headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.to_str().unwrap_or_default()))
+                .collect();
+            assert_eq!(headers, expected, "headers of {:?}", content);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let cases = [
+            ("X-Api-Key secret", vec!["Missing ':'"]),
+            ("X Api Key: secret", vec!["Invalid header name"]),
+            (": secret", vec!["Invalid header name"]),
+            ("X-Api-Key: sec\u{1}ret", vec!["Invalid header value"]),
+            (
+                "X-Api-Key secret\nX-Ok: 1\nX Bad: 2",
+                vec!["Missing ':'", "Invalid header name"],
+            ),
+        ];
+        for (content, reasons) in cases {
+            let Err(ParseErrors(errors)) = parse_headers(content) else {
+                panic!("{:?} is invalid", content);
+            };
+            let found: Vec<&str> = errors.iter().map(|err| err.reason.as_str()).collect();
+            assert_eq!(
+                found.len(),
+                reasons.len(),
+                "errors of {:?}: {:?}",
+                content,
+                found
+            );
+            for (found, reason) in found.iter().zip(reasons) {
+                assert!(
+                    found.contains(reason),
+                    "{:?} fails with {:?}",
+                    content,
+                    reason
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,13 @@
+//! Parsers of the strings typed by the user, which never panic and point at the faulty part of the input
+
+mod error;
+mod headers_file;
+mod proxy_url;
+mod tunnel_spec;
+
+pub use error::{ParseError, ParseErrors};
+pub use headers_file::{parse_headers, read_headers_file};
+pub use proxy_url::parse_proxy_url;
+pub use tunnel_spec::{
+    check_tunnel_spec, parse_tunnel_spec, ParsedTunnelSpec, SpecDiagnostic, TunnelSpecCheck,
+};
@@ -0,0 +1,579 @@
+use crate::client::access::AccessPolicy;
+use crate::client::buffers::BufferTuning;
+use crate::client::client_api::LocalToRemote;
+use crate::client::concurrency::ConnectionOverflow;
+use crate::client::datagrams::DatagramOptions;
+use crate::client::tun::TunDevice;
+use crate::parsers::{ParseError, ParseErrors};
+use ipnet::Ipv4Net;
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use url::Host;
+use wstunnel::tunnel::LocalProtocol;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_BIND_IP: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+const PROTOCOLS: [&str; 10] = [
+    "tcp",
+    "udp",
+    "socks5",
+    "http",
+    "tproxy+tcp",
+    "tproxy+udp",
+    "stdio",
+    "unix",
+    "npipe",
+    "tun",
+];
+const REVERSE_PROTOCOLS: [&str; 5] = ["tcp", "udp", "socks5", "http", "unix"];
+const OPTIONS: [&str; 4] = ["timeout_sec", "proxy_protocol", "login", "password"];
+
+/// Options of a tunnel, given as query parameters
+struct TunnelOptions {
+    timeout: Option<Duration>,
+    proxy_protocol: bool,
+    credentials: Option<(String, String)>,
+}
+
+/// Parse a tunnel written with the wstunnel cli grammar, i.e: 'tcp://1212:google.com:443' or 'socks5://[::1]:1212?login=admin&password=admin'.
+/// When `reverse` is set, the tunnel is a remote to local one (-R of the cli), the local part being bound on the server.
+/// Invalid options are all reported along with the first error in the addresses.
+pub fn parse_tunnel_spec(spec: &str, reverse: bool) -> Result<LocalToRemote, ParseErrors> {
+    let Some((scheme, rest)) = spec.split_once("://") else {
+        return Err(ParseError::new(spec, spec, "Missing protocol, i.e: tcp://")
+            .expecting(&PROTOCOLS)
+            .into());
+    };
+    let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+
+    let mut errors = vec![];
+    let options = parse_options(spec, query, &mut errors);
+    match parse_addresses(spec, scheme, rest, reverse, options) {
+        Ok(tunnel) if errors.is_empty() => Ok(tunnel),
+        Ok(_) => Err(ParseErrors(errors)),
+        Err(err) => {
+            errors.insert(0, err);
+            Err(ParseErrors(errors))
+        }
+    }
+}
+
+/// Outcome of checking a tunnel as the user types it
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TunnelSpecCheck {
+    Valid { tunnel: ParsedTunnelSpec },
+    Invalid { errors: Vec<SpecDiagnostic> },
+}
+
+/// Parts of a valid tunnel, for the UI to show what it understood
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ParsedTunnelSpec {
+    pub protocol: String,
+    pub reverse: bool,
+    pub local: SocketAddr,
+    /// None for dynamic tunnels, whose destination is only known per connection
+    pub remote: Option<String>,
+    pub timeout_sec: Option<u64>,
+    pub proxy_protocol: bool,
+    pub authenticated: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpecDiagnostic {
+    /// Byte offsets of the faulty part of the input, the end excluded
+    pub start: usize,
+    pub end: usize,
+    pub reason: String,
+    pub expected: Vec<String>,
+}
+
+/// Parse a tunnel into its parts, or every error found in it
+pub fn check_tunnel_spec(spec: &str, reverse: bool) -> TunnelSpecCheck {
+    let tunnel = match parse_tunnel_spec(spec, reverse) {
+        Ok(tunnel) => tunnel,
+        Err(ParseErrors(errors)) => {
+            let errors = errors
+                .into_iter()
+                .map(|err| SpecDiagnostic {
+                    start: err.span.start,
+                    end: err.span.end,
+                    reason: err.reason,
+                    expected: err.expected,
+                })
+                .collect();
+            return TunnelSpecCheck::Invalid { errors };
+        }
+    };
+
+    let (scheme, rest) = spec.split_once("://").unwrap_or_default();
+    let query = rest.split_once('?').map(|(_, q)| q).unwrap_or_default();
+    let options = parse_options(spec, query, &mut vec![]);
+    let dynamic = tunnel.remote == dynamic_remote();
+    TunnelSpecCheck::Valid {
+        tunnel: ParsedTunnelSpec {
+            protocol: scheme.to_string(),
+            reverse,
+            local: tunnel.local,
+            remote: (!dynamic).then(|| format!("{}:{}", tunnel.remote.0, tunnel.remote.1)),
+            timeout_sec: options.timeout.map(|t| t.as_secs()),
+            proxy_protocol: options.proxy_protocol,
+            authenticated: options.credentials.is_some(),
+        },
+    }
+}
+
+fn parse_options(spec: &str, query: &str, errors: &mut Vec<ParseError>) -> TunnelOptions {
+    let mut options = TunnelOptions {
+        timeout: Some(DEFAULT_TIMEOUT),
+        proxy_protocol: false,
+        credentials: None,
+    };
+    let (mut login, mut password) = (None, None);
+
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let Some((key, value)) = url::form_urlencoded::parse(param.as_bytes()).next() else {
+            continue;
+        };
+        match key.as_ref() {
+            "timeout_sec" => match value.parse::<u64>() {
+                Ok(timeout) => {
+                    options.timeout = (timeout > 0).then(|| Duration::from_secs(timeout))
+                }
+                Err(_) => errors.push(
+                    ParseError::new(
+                        spec,
+                        param,
+                        "Invalid timeout_sec, expected a number of seconds",
+                    )
+                    .expecting(&["<seconds>"]),
+                ),
+            },
+            "proxy_protocol" => options.proxy_protocol = true,
+            "login" => login = Some((value.into_owned(), param)),
+            "password" => password = Some((value.into_owned(), param)),
+            _ => errors.push(
+                ParseError::new(spec, param, format!("Unknown option {}", key)).expecting(&OPTIONS),
+            ),
+        }
+    }
+
+    match (login, password) {
+        (Some((login, _)), Some((password, _))) => options.credentials = Some((login, password)),
+        (None, None) => {}
+        (Some((_, param)), None) | (None, Some((_, param))) => errors.push(ParseError::new(
+            spec,
+            param,
+            "Login and password must be set together",
+        )),
+    }
+    options
+}
+
+fn parse_addresses(
+    spec: &str,
+    scheme: &str,
+    rest: &str,
+    reverse: bool,
+    options: TunnelOptions,
+) -> Result<LocalToRemote, ParseError> {
+    let TunnelOptions {
+        timeout,
+        proxy_protocol,
+        credentials,
+    } = options;
+
+    let mut tun = None;
+    let mut named_pipe = None;
+    let (local_protocol, local, remote) = match (scheme, reverse) {
+        ("tcp", false) => {
+            let (local, rest) = parse_local_bind(spec, rest)?;
+            (
+                LocalProtocol::Tcp { proxy_protocol },
+                local,
+                parse_remote(spec, rest)?,
+            )
+        }
+        ("tcp", true) => {
+            let (local, rest) = parse_local_bind(spec, rest)?;
+            (LocalProtocol::ReverseTcp, local, parse_remote(spec, rest)?)
+        }
+        ("udp", false) => {
+            let (local, rest) = parse_local_bind(spec, rest)?;
+            (
+                LocalProtocol::Udp { timeout },
+                local,
+                parse_remote(spec, rest)?,
+            )
+        }
+        ("udp", true) => {
+            let (local, rest) = parse_local_bind(spec, rest)?;
+            (
+                LocalProtocol::ReverseUdp { timeout },
+                local,
+                parse_remote(spec, rest)?,
+            )
+        }
+        ("socks5", false) => {
+            let (local, _) = parse_local_bind(spec, rest)?;
+            let protocol = LocalProtocol::Socks5 {
+                timeout,
+                credentials,
+            };
+            (protocol, local, dynamic_remote())
+        }
+        ("socks5", true) => {
+            let (local, _) = parse_local_bind(spec, rest)?;
+            let protocol = LocalProtocol::ReverseSocks5 {
+                timeout,
+                credentials,
+            };
+            (protocol, local, dynamic_remote())
+        }
+        ("http", false) => {
+            let (local, _) = parse_local_bind(spec, rest)?;
+            let protocol = LocalProtocol::HttpProxy {
+                timeout,
+                credentials,
+                proxy_protocol,
+            };
+            (protocol, local, dynamic_remote())
+        }
+        ("http", true) => {
+            let (local, _) = parse_local_bind(spec, rest)?;
+            let protocol = LocalProtocol::ReverseHttpProxy {
+                timeout,
+                credentials,
+            };
+            (protocol, local, dynamic_remote())
+        }
+        ("tproxy+tcp", false) => {
+            let (local, _) = parse_local_bind(spec, rest)?;
+            (LocalProtocol::TProxyTcp, local, dynamic_remote())
+        }
+        ("tproxy+udp", false) => {
+            let (local, _) = parse_local_bind(spec, rest)?;
+            (
+                LocalProtocol::TProxyUdp { timeout },
+                local,
+                dynamic_remote(),
+            )
+        }
+        ("tun", false) => {
+            let network: Ipv4Net = rest.parse().map_err(|_| {
+                ParseError::new(spec, rest, "Invalid tun network, expected <ipv4>/<prefix>")
+                    .expecting(&["<ipv4>/<prefix>"])
+            })?;
+            tun = Some(TunDevice {
+                network,
+                udp_timeout: timeout,
+            });
+            let protocol = LocalProtocol::Tcp {
+                proxy_protocol: false,
+            };
+            (
+                protocol,
+                SocketAddr::new(IpAddr::V4(network.addr()), 0),
+                dynamic_remote(),
+            )
+        }
+        ("stdio", false) => {
+            let local = SocketAddr::new(DEFAULT_BIND_IP, 0);
+            (
+                LocalProtocol::Stdio { proxy_protocol },
+                local,
+                parse_remote(spec, rest)?,
+            )
+        }
+        ("unix", reverse) => {
+            let mut parts = rest.rsplitn(3, ':');
+            let (Some(_), Some(_), Some(path)) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(ParseError::new(
+                    spec,
+                    rest,
+                    "Invalid unix tunnel, expected unix://<path>:<host>:<port>",
+                )
+                .expecting(&["<path>:<host>:<port>"]));
+            };
+            let remote = parse_remote(spec, &rest[path.len() + 1..])?;
+            let path = PathBuf::from(path);
+            let local = SocketAddr::new(DEFAULT_BIND_IP, 0);
+            let protocol = if reverse {
+                LocalProtocol::ReverseUnix { path }
+            } else {
+                LocalProtocol::Unix {
+                    path,
+                    proxy_protocol,
+                }
+            };
+            (protocol, local, remote)
+        }
+        ("npipe", false) => {
+            let mut parts = rest.rsplitn(3, ':');
+            let (Some(_), Some(_), Some(name)) = (parts.next(), parts.next(), parts.next()) else {
+                return Err(ParseError::new(
+                    spec,
+                    rest,
+                    "Invalid named pipe tunnel, expected npipe://<pipe name>:<host>:<port>",
+                )
+                .expecting(&["<pipe name>:<host>:<port>"]));
+            };
+            let remote = parse_remote(spec, &rest[name.len() + 1..])?;
+            named_pipe = Some(pipe_path(name));
+            let local = SocketAddr::new(DEFAULT_BIND_IP, 0);
+            // A pipe client has no address to put in a proxy protocol header
+            let protocol = LocalProtocol::Tcp {
+                proxy_protocol: false,
+            };
+            (protocol, local, remote)
+        }
+        (scheme, true) => {
+            return Err(ParseError::new(
+                spec,
+                scheme,
+                format!("Protocol {} is not supported for reverse tunnels", scheme),
+            )
+            .expecting(&REVERSE_PROTOCOLS))
+        }
+        (scheme, false) => {
+            return Err(ParseError::new(
+                spec,
+                scheme,
+                format!("Unknown tunnel protocol {}", scheme),
+            )
+            .expecting(&PROTOCOLS))
+        }
+    };
+
+    Ok(LocalToRemote {
+        id: spec.to_string(),
+        local_protocol,
+        local,
+        remote,
+        rate_limit_up: None,
+        rate_limit_down: None,
+        access: AccessPolicy::default(),
+        buffers: BufferTuning::default(),
+        lazy: false,
+        force_remote_dns: false,
+        split: None,
+        proxy_header: None,
+        port_mapping: false,
+        datagrams: DatagramOptions::default(),
+        access_log: false,
+        listener_auth: None,
+        max_concurrent_connections: None,
+        connection_overflow: ConnectionOverflow::default(),
+        named_pipe,
+        tun,
+    })
+}
+
+/// Parse the local part of a tunnel: `port`, `ipv4:port` or `[ipv6]:port`, returning what remains after it
+fn parse_local_bind<'a>(spec: &str, input: &'a str) -> Result<(SocketAddr, &'a str), ParseError> {
+    let (ip, rest) = if let Some(v6) = input.strip_prefix('[') {
+        let (ip, rest) = v6.split_once(']').ok_or_else(|| {
+            ParseError::new(spec, input, "Missing ] after ipv6 address").expecting(&["]"])
+        })?;
+        let rest = rest.strip_prefix(':').ok_or_else(|| {
+            ParseError::new(spec, rest, "Missing port after ipv6 address").expecting(&[":<port>"])
+        })?;
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|_| ParseError::new(spec, ip, "Invalid ipv6 address"))?;
+        (ip, rest)
+    } else {
+        let (first, rest) = input.split_once(':').unwrap_or((input, ""));
+        match first.parse::<Ipv4Addr>() {
+            Ok(ip) => (IpAddr::V4(ip), rest),
+            Err(_) => (DEFAULT_BIND_IP, input),
+        }
+    };
+
+    let (port, rest) = rest.split_once(':').unwrap_or((rest, ""));
+    let port: u16 = port
+        .parse()
+        .map_err(|_| ParseError::new(spec, port, "Invalid local port").expecting(&["<port>"]))?;
+    Ok((SocketAddr::new(ip, port), rest))
+}
+
+/// Parse the remote part of a tunnel: `host:port` or `[ipv6]:port`
+fn parse_remote(spec: &str, input: &str) -> Result<(Host, u16), ParseError> {
+    // i.e: tcp://5432:/var/run/postgresql/.s.PGSQL.5432
+    if input.starts_with('/') {
+        return Err(ParseError::new(
+            spec,
+            input,
+            "A unix socket of the server cannot be a remote, the wstunnel server only connects to a host and a port",
+        )
+        .expecting(&["<host>:<port>"]));
+    }
+    let (host, port) = input.rsplit_once(':').ok_or_else(|| {
+        ParseError::new(spec, input, "Invalid remote, expected <host>:<port>")
+            .expecting(&["<host>:<port>"])
+    })?;
+    let host = Host::parse(host)
+        .map_err(|err| ParseError::new(spec, host, format!("Invalid remote host: {}", err)))?;
+    let port: u16 = port
+        .parse()
+        .map_err(|_| ParseError::new(spec, port, "Invalid remote port").expecting(&["<port>"]))?;
+    Ok((host, port))
+}
+
+/// Full path of a named pipe, `docker_engine` standing for `\\.\pipe\docker_engine`
+fn pipe_path(name: &str) -> String {
+    if name.starts_with(r"\\") {
+        name.to_string()
+    } else {
+        format!(r"\\.\pipe\{}", name)
+    }
+}
+
+/// Destination of dynamic tunnels (socks5, http proxy, tproxy) is only known per connection
+fn dynamic_remote() -> (Host, u16) {
+    (Host::Ipv4(Ipv4Addr::UNSPECIFIED), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_tunnels() {
+        let cases = [
+            (
+                "tcp://1212:google.com:443",
+                false,
+                "127.0.0.1:1212",
+                "google.com:443",
+            ),
+            (
+                "tcp://0.0.0.0:1212:10.0.0.1:22",
+                false,
+                "0.0.0.0:1212",
+                "10.0.0.1:22",
+            ),
+            ("tcp://[::1]:1212:[::1]:22", false, "[::1]:1212", "[::1]:22"),
+            (
+                "tcp://8080:localhost:80",
+                true,
+                "127.0.0.1:8080",
+                "localhost:80",
+            ),
+            (
+                "udp://1212:1.1.1.1:53?timeout_sec=10",
+                false,
+                "127.0.0.1:1212",
+                "1.1.1.1:53",
+            ),
+            (
+                "socks5://[::1]:1080?login=admin&password=admin",
+                false,
+                "[::1]:1080",
+                "0.0.0.0:0",
+            ),
+            ("http://8080", false, "127.0.0.1:8080", "0.0.0.0:0"),
+            (
+                "tproxy+tcp://0.0.0.0:1212",
+                false,
+                "0.0.0.0:1212",
+                "0.0.0.0:0",
+            ),
+            ("tun://10.0.0.1/24", false, "10.0.0.1:0", "0.0.0.0:0"),
+            (
+                "unix:///tmp/app.sock:localhost:22",
+                false,
+                "127.0.0.1:0",
+                "localhost:22",
+            ),
+        ];
+        for (spec, reverse, local, remote) in cases {
+            let tunnel = parse_tunnel_spec(spec, reverse)
+                .unwrap_or_else(|err| panic!("{} is valid, got {}", spec, err));
+            assert_eq!(tunnel.local.to_string(), local, "local of {}", spec);
+            assert_eq!(
+                format!("{}:{}", tunnel.remote.0, tunnel.remote.1),
+                remote,
+                "remote of {}",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_tunnels() {
+        let cases = [
+            ("1212:google.com:443", false, "Missing protocol"),
+            (
+                "ftp://1212:google.com:21",
+                false,
+                "Unknown tunnel protocol ftp",
+            ),
+            (
+                "stdio://google.com:443",
+                true,
+                "not supported for reverse tunnels",
+            ),
+            ("tcp://99999:google.com:443", false, "Invalid local port"),
+            (
+                "tcp://[::1:1212:google.com:443",
+                false,
+                "Missing ] after ipv6 address",
+            ),
+            ("tcp://1212:google.com", false, "Invalid remote"),
+            ("tcp://1212:google.com:https", false, "Invalid remote port"),
+            (
+                "tcp://5432:/var/run/postgresql/.s.PGSQL.5432",
+                false,
+                "unix socket",
+            ),
+            (
+                "socks5://1080?login=admin",
+                false,
+                "Login and password must be set together",
+            ),
+            (
+                "udp://1212:1.1.1.1:53?timeout_sec=soon",
+                false,
+                "Invalid timeout_sec",
+            ),
+            ("tun://10.0.0.1", false, "Invalid tun network"),
+            ("unix:///tmp/app.sock", false, "Invalid unix tunnel"),
+        ];
+        for (spec, reverse, reason) in cases {
+            let Err(ParseErrors(errors)) = parse_tunnel_spec(spec, reverse) else {
+                panic!("{} is invalid", spec);
+            };
+            assert!(
+                errors.iter().any(|err| err.reason.contains(reason)),
+                "{} fails with {:?}, got {:?}",
+                spec,
+                reason,
+                errors
+            );
+        }
+    }
+
+    #[test]
+    fn points_at_the_faulty_part() {
+        let Err(ParseErrors(errors)) = parse_tunnel_spec("tcp://1212:google.com:https", false)
+        else {
+            panic!("the remote port is invalid");
+        };
+        assert_eq!(errors[0].span, 22..27);
+    }
+
+    #[test]
+    fn reports_every_invalid_option() {
+        let Err(ParseErrors(errors)) =
+            parse_tunnel_spec("socks5://1080?timeout_sec=soon&color=blue", false)
+        else {
+            panic!("the options are invalid");
+        };
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+    }
+}
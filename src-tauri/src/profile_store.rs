@@ -0,0 +1,90 @@
+use crate::client::profile::Profile;
+use crate::config_migration;
+use anyhow::{anyhow, Context};
+use std::path::Path;
+use tauri::AppHandle;
+use tauri_plugin_store::StoreExt;
+
+/// File and key the frontend saves the profiles into, through the store plugin
+pub const PROFILE_STORE: &str = "ws-client-config.json";
+pub const PROFILE_STORE_KEY: &str = "ws-configs";
+const MAX_NAME_LENGTH: usize = 128;
+
+/// Read the profiles saved by the frontend, from outside of the store plugin
+pub fn load_profiles(data_dir: &Path) -> anyhow::Result<Vec<Profile>> {
+    let store = data_dir.join(PROFILE_STORE);
+    let content = std::fs::read(&store)
+        .with_context(|| format!("Cannot read saved profiles from {}", store.display()))?;
+    let mut saved: serde_json::Value = serde_json::from_slice(&content)
+        .with_context(|| format!("Invalid profile store {}", store.display()))?;
+    // Read before the app migrated the store, i.e: by the stdio bridge
+    config_migration::migrate_store(&mut saved)
+        .with_context(|| format!("Cannot migrate profile store {}", store.display()))?;
+    serde_json::from_value(
+        saved
+            .get_mut(PROFILE_STORE_KEY)
+            .map(serde_json::Value::take)
+            .unwrap_or_else(|| serde_json::Value::Array(vec![])),
+    )
+    .with_context(|| format!("Invalid profiles in {}", store.display()))
+}
+
+/// Names are shown in menus and notifications, and refer to the profiles in the cli and the control api
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty() {
+        return Err(anyhow!("The name of the copy cannot be empty"));
+    }
+    if name.chars().count() > MAX_NAME_LENGTH {
+        return Err(anyhow!(
+            "The name of the copy cannot be longer than {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(anyhow!(
+            "The name of the copy cannot have control characters"
+        ));
+    }
+    Ok(())
+}
+
+/// Save a copy of a saved profile under another name, returning the copy. Settings the frontend keeps in the
+/// profile without the app knowing them are copied as well
+pub fn duplicate(app: &AppHandle, profile_id: &str, new_name: &str) -> anyhow::Result<Profile> {
+    let new_name = new_name.trim();
+    check_name(new_name)?;
+    let store = app.store(PROFILE_STORE)?;
+    let mut profiles = match store.get(PROFILE_STORE_KEY) {
+        Some(serde_json::Value::Array(profiles)) => profiles,
+        Some(_) => return Err(anyhow!("Invalid profiles in {}", PROFILE_STORE)),
+        None => vec![],
+    };
+    let named = |entry: &serde_json::Value, name: &str| {
+        entry.get("name").and_then(|n| n.as_str()) == Some(name)
+    };
+    if profiles.iter().any(|entry| named(entry, new_name)) {
+        return Err(anyhow!("A profile named {} already exists", new_name));
+    }
+    let mut copy = profiles
+        .iter()
+        .find(|entry| named(entry, profile_id))
+        .cloned()
+        .ok_or_else(|| anyhow!("No saved profile named {}", profile_id))?;
+    copy["name"] = serde_json::Value::String(new_name.to_string());
+    let profile: Profile = serde_json::from_value(copy.clone())
+        .with_context(|| format!("Invalid saved profile {}", profile_id))?;
+    if profile
+        .chain
+        .as_ref()
+        .is_some_and(|chain| chain.profile_id == new_name)
+    {
+        return Err(anyhow!(
+            "The copy cannot be named {}, the profile goes through it",
+            new_name
+        ));
+    }
+    profiles.push(copy);
+    store.set(PROFILE_STORE_KEY, serde_json::Value::Array(profiles));
+    store.save()?;
+    Ok(profile)
+}
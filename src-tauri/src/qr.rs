@@ -0,0 +1,53 @@
+use crate::client::profile::Profile;
+use crate::deep_link;
+use anyhow::{anyhow, Context};
+use qrcode::render::svg;
+use qrcode::{EcLevel, QrCode};
+use serde::Serialize;
+use tauri::Url;
+
+/// QR code of a profile, to provision another machine by scanning it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProfileQrCode {
+    /// `wstunnel://connect` link encoded in the code, the same as a deep link
+    pub payload: String,
+    pub svg: String,
+}
+
+/// Render the deep link of the profile as a QR code.
+/// The link holds the secrets of the profile, as the other machine needs them to connect.
+pub fn render(profile: &Profile) -> anyhow::Result<ProfileQrCode> {
+    let payload = deep_link::profile_url(profile)?.to_string();
+    // Low correction keeps the most room for the profile, the code is read from a screen rather than from print
+    let code =
+        QrCode::with_error_correction_level(payload.as_bytes(), EcLevel::L).map_err(|err| {
+            anyhow!(
+                "Profile {} does not fit in a QR code: {}",
+                profile.name,
+                err
+            )
+        })?;
+    let svg = code.render::<svg::Color>().min_dimensions(256, 256).build();
+    Ok(ProfileQrCode { payload, svg })
+}
+
+/// Read the link of the first QR code found in an image, a screenshot or a camera capture
+pub fn decode(image: &[u8]) -> anyhow::Result<Url> {
+    let image = image::load_from_memory(image)
+        .with_context(|| "Cannot read the image")?
+        .to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare_from_greyscale(
+        image.width() as usize,
+        image.height() as usize,
+        |x, y| image.get_pixel(x as u32, y as u32).0[0],
+    );
+    let grids = prepared.detect_grids();
+    let grid = grids
+        .first()
+        .ok_or_else(|| anyhow!("No QR code found in the image"))?;
+    let (_, content) = grid
+        .decode()
+        .map_err(|err| anyhow!("Cannot decode the QR code: {:?}", err))?;
+    Url::parse(content.trim()).with_context(|| "The QR code does not hold a profile link")
+}
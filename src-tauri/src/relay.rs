@@ -0,0 +1,429 @@
+use crate::client::client_api::{BoundListener, WsClientApi};
+use crate::client::profile::Profile;
+use crate::client::reverse_status::ReverseTunnelStatus;
+use crate::client::stats::TrafficSnapshot;
+use crate::sandbox::{self, Allowed};
+use anyhow::{anyhow, Context};
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// Given to the app to run the relay of an isolated profile, driven by the app through its standard input and output
+pub const RELAY_ARG: &str = "--relay";
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+/// Account the relay switches to when started as root
+#[cfg(unix)]
+const UNPRIVILEGED_USER: &str = "nobody";
+
+/// Sent by the app to the relay process, one json document per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RelayRequest {
+    /// Profile to run, already authorized so the relay never needs the keychain
+    Connect {
+        profile: Profile,
+    },
+    Shutdown,
+}
+
+/// Sent by the relay process to the app, one json document per line
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum RelayEvent {
+    Connected {
+        remote_addr: String,
+        listeners: Vec<BoundListener>,
+    },
+    Failed {
+        error: String,
+    },
+    Traffic {
+        traffic: TrafficSnapshot,
+        #[serde(default)]
+        reverse_tunnels: Vec<ReverseTunnelStatus>,
+    },
+}
+
+/// An isolated profile, as last reported by its relay process
+#[derive(Debug, Clone)]
+pub struct RelayStatus {
+    pub profile: Profile,
+    /// The profile with its placeholders expanded, as its tunnels run
+    pub expanded: Profile,
+    pub remote_addr: String,
+    pub listeners: Vec<BoundListener>,
+    pub traffic: TrafficSnapshot,
+    pub reverse_tunnels: Vec<ReverseTunnelStatus>,
+    /// How many times the relay process died and has been started again
+    pub restarts: u32,
+}
+
+struct Relay {
+    status: Arc<Mutex<RelayStatus>>,
+    shutdown: Arc<Notify>,
+    supervisor: JoinHandle<()>,
+}
+
+/// Relay processes of the isolated profiles, indexed by profile id.
+/// The network facing code of those profiles runs in a child process, apart from the memory of the app and only
+/// given the profile it runs. It runs as the user of the app: only an app started as root has its relays switch to
+/// an unprivileged account, so otherwise a bug in the relay path is contained by the process boundary alone.
+#[derive(Default)]
+pub struct RelayProcesses {
+    relays: Mutex<HashMap<String, Relay>>,
+}
+
+impl RelayProcesses {
+    /// Run a profile in a new relay process, replacing the one already running it.
+    /// `authorized` is the profile with its upgrade credentials resolved, it is what the relay receives.
+    pub async fn start(
+        &self,
+        binary: &Path,
+        profile: Profile,
+        expanded: Profile,
+        authorized: Profile,
+    ) -> anyhow::Result<RelayStatus> {
+        let previous = self.relays.lock().remove(&profile.name);
+        if let Some(previous) = previous {
+            // The previous relay holds the local ports of the profile until its process exited
+            previous.shutdown.notify_one();
+            let _ = previous.supervisor.await;
+        }
+        let (child, remote_addr, listeners) = RelayProcess::spawn(binary, &authorized).await?;
+        let status = Arc::new(Mutex::new(RelayStatus {
+            profile: profile.clone(),
+            expanded,
+            remote_addr,
+            listeners,
+            traffic: TrafficSnapshot::default(),
+            reverse_tunnels: vec![],
+            restarts: 0,
+        }));
+        let shutdown = Arc::new(Notify::new());
+        let supervisor = tokio::spawn(supervise(
+            child,
+            binary.to_path_buf(),
+            authorized,
+            status.clone(),
+            shutdown.clone(),
+        ));
+
+        let current = status.lock().clone();
+        let relay = Relay {
+            status,
+            shutdown,
+            supervisor,
+        };
+        self.relays.lock().insert(profile.name.clone(), relay);
+        Ok(current)
+    }
+
+    /// Stop the relay of a profile, returning whether the profile had one
+    pub fn stop(&self, profile_id: &str) -> bool {
+        let Some(relay) = self.relays.lock().remove(profile_id) else {
+            return false;
+        };
+        relay.shutdown.notify_one();
+        true
+    }
+
+    /// Stop every relay and wait until their processes exited, returning the profiles they ran
+    pub async fn stop_all(&self) -> Vec<Profile> {
+        let relays: Vec<Relay> = self.relays.lock().drain().map(|(_, relay)| relay).collect();
+        let mut profiles = vec![];
+        for relay in relays {
+            relay.shutdown.notify_one();
+            let _ = relay.supervisor.await;
+            profiles.push(relay.status.lock().profile.clone());
+        }
+        profiles
+    }
+
+    pub fn contains(&self, profile_id: &str) -> bool {
+        self.relays.lock().contains_key(profile_id)
+    }
+
+    pub fn list(&self) -> Vec<RelayStatus> {
+        self.relays
+            .lock()
+            .values()
+            .map(|relay| relay.status.lock().clone())
+            .collect()
+    }
+}
+
+/// A running relay process, talked to through its standard input and output
+struct RelayProcess {
+    child: Child,
+    requests: ChildStdin,
+    events: Lines<BufReader<ChildStdout>>,
+}
+
+impl RelayProcess {
+    /// Start a relay process and wait until it connected the profile
+    async fn spawn(
+        binary: &Path,
+        profile: &Profile,
+    ) -> anyhow::Result<(Self, String, Vec<BoundListener>)> {
+        let mut child = Command::new(binary)
+            .arg(RELAY_ARG)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Cannot start relay {}", binary.display()))?;
+        let (Some(requests), Some(events)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(anyhow!("Cannot talk to the relay process"));
+        };
+        let mut process = Self {
+            child,
+            requests,
+            events: BufReader::new(events).lines(),
+        };
+        info!(
+            "Relay of profile {} started (pid {:?})",
+            profile.name,
+            process.child.id()
+        );
+
+        send(
+            &mut process.requests,
+            &RelayRequest::Connect {
+                profile: profile.clone(),
+            },
+        )
+        .await?;
+        match receive(&mut process.events).await? {
+            Some(RelayEvent::Connected {
+                remote_addr,
+                listeners,
+            }) => Ok((process, remote_addr, listeners)),
+            Some(RelayEvent::Failed { error }) => Err(anyhow!(error)),
+            Some(event) => Err(anyhow!("Unexpected relay event {:?}", event)),
+            None => Err(anyhow!("Relay process exited before connecting")),
+        }
+    }
+
+    /// Ask the relay to disconnect, killing it when it does not exit in time
+    async fn shutdown(mut self) {
+        let _ = send(&mut self.requests, &RelayRequest::Shutdown).await;
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, self.child.wait())
+            .await
+            .is_err()
+        {
+            warn!("Relay process did not exit, killing it");
+            let _ = self.child.kill().await;
+        }
+    }
+}
+
+/// Keep track of the traffic reported by a relay and start it again whenever it dies, until the profile is disconnected
+async fn supervise(
+    mut process: RelayProcess,
+    binary: PathBuf,
+    profile: Profile,
+    status: Arc<Mutex<RelayStatus>>,
+    shutdown: Arc<Notify>,
+) {
+    loop {
+        let event = tokio::select! {
+            _ = shutdown.notified() => {
+                process.shutdown().await;
+                info!("Relay of profile {} stopped", profile.name);
+                return;
+            }
+            event = receive(&mut process.events) => event,
+        };
+        match event {
+            Ok(Some(RelayEvent::Traffic {
+                traffic,
+                reverse_tunnels,
+            })) => {
+                let mut status = status.lock();
+                status.traffic = traffic;
+                status.reverse_tunnels = reverse_tunnels;
+                continue;
+            }
+            Ok(Some(event)) => {
+                warn!("Unexpected relay event {:?}", event);
+                continue;
+            }
+            Ok(None) => error!("Relay of profile {} exited", profile.name),
+            Err(err) => error!("Relay of profile {} failed: {:?}", profile.name, err),
+        }
+
+        let _ = process.child.kill().await;
+        let mut backoff = MIN_RESTART_BACKOFF;
+        process = loop {
+            tokio::select! {
+                _ = shutdown.notified() => return,
+                _ = tokio::time::sleep(backoff) => {}
+            }
+            match RelayProcess::spawn(&binary, &profile).await {
+                Ok((process, remote_addr, listeners)) => {
+                    let mut status = status.lock();
+                    status.remote_addr = remote_addr;
+                    status.listeners = listeners;
+                    status.restarts += 1;
+                    break process;
+                }
+                Err(err) => {
+                    warn!(
+                        "Cannot restart relay of profile {}: {:?}",
+                        profile.name, err
+                    );
+                    backoff = (backoff * 2).min(MAX_RESTART_BACKOFF);
+                }
+            }
+        };
+    }
+}
+
+async fn send<W: AsyncWrite + Unpin, T: Serialize>(
+    writer: &mut W,
+    message: &T,
+) -> anyhow::Result<()> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn receive<R: AsyncRead + Unpin, T: DeserializeOwned>(
+    lines: &mut Lines<BufReader<R>>,
+) -> anyhow::Result<Option<T>> {
+    let Some(line) = lines.next_line().await? else {
+        return Ok(None);
+    };
+    Ok(Some(
+        serde_json::from_str(&line).with_context(|| "Invalid relay message")?,
+    ))
+}
+
+/// Entry point of the relay process: connect the profile sent by the app, give up the privileges and confine
+/// itself once the listeners are bound, then report the traffic until the app asks to stop or goes away.
+pub fn run_child() -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let mut events = tokio::io::stdout();
+        let mut requests = BufReader::new(tokio::io::stdin()).lines();
+        let Some(RelayRequest::Connect { profile }) = receive(&mut requests).await? else {
+            return Err(anyhow!("Expected a profile to connect"));
+        };
+
+        let connected = async {
+            let connected = WsClientApi::connect(Box::new(profile.to_client()?), |_| {}).await?;
+            // Listeners are bound by now, so privileged ports keep working
+            if let Err(err) = drop_privileges() {
+                connected.shutdown();
+                return Err(err.context("Cannot drop privileges"));
+            }
+            match Allowed::of(&profile).and_then(|allowed| sandbox::confine(&allowed)) {
+                Ok(true) => info!("Relay of profile {} confined", profile.name),
+                Ok(false) => warn!(
+                    "The relay of profile {} cannot be confined on this system, it keeps the rights of its account",
+                    profile.name
+                ),
+                Err(err) => {
+                    connected.shutdown();
+                    return Err(err.context("Cannot confine the relay"));
+                }
+            }
+            Ok(connected)
+        }
+        .await;
+        let connected = match connected {
+            Ok(connected) => connected,
+            Err(err) => {
+                let error = format!("{:?}", err);
+                return send(&mut events, &RelayEvent::Failed { error }).await;
+            }
+        };
+        send(
+            &mut events,
+            &RelayEvent::Connected {
+                remote_addr: connected.remote_addr.to_string(),
+                listeners: connected.listeners.clone(),
+            },
+        )
+        .await?;
+
+        let mut interval = tokio::time::interval(STATUS_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let traffic = connected.stats.traffic_snapshot();
+                    let reverse_tunnels = connected.stats.reverse_tunnels.list();
+                    send(&mut events, &RelayEvent::Traffic { traffic, reverse_tunnels }).await?;
+                }
+                request = receive::<_, RelayRequest>(&mut requests) => match request {
+                    Ok(Some(RelayRequest::Shutdown)) | Ok(None) => break,
+                    Ok(Some(request)) => warn!("Unexpected relay request {:?}", request),
+                    Err(err) => {
+                        error!("Cannot read relay request: {:?}", err);
+                        break;
+                    }
+                },
+            }
+        }
+        info!("Disconnecting profile {}", profile.name);
+        connected.shutdown();
+        Ok(())
+    })
+}
+
+/// Switch to an unprivileged account when started as root, and forbid gaining privileges back through exec.
+/// Started by another user, the relay keeps the rights of that user
+#[cfg(unix)]
+fn drop_privileges() -> anyhow::Result<()> {
+    use std::ffi::CString;
+    use std::io;
+
+    let user = CString::new(UNPRIVILEGED_USER)?;
+    // SAFETY: getpwnam returns null or a pointer to a static entry, read right away before any other call
+    unsafe {
+        if libc::geteuid() == 0 {
+            let passwd = libc::getpwnam(user.as_ptr());
+            if passwd.is_null() {
+                return Err(anyhow!("No {} user to run the relay as", UNPRIVILEGED_USER));
+            }
+            let (uid, gid) = ((*passwd).pw_uid, (*passwd).pw_gid);
+            // Groups first, setuid removes the right to change them
+            if libc::setgroups(0, std::ptr::null()) != 0
+                || libc::setgid(gid) != 0
+                || libc::setuid(uid) != 0
+            {
+                return Err(io::Error::last_os_error().into());
+            }
+            // glibc switches every thread of the runtime, the relay must not be able to switch back
+            if libc::setuid(0) == 0 || libc::geteuid() == 0 || libc::getegid() == 0 {
+                return Err(anyhow!("The relay still has the privileges of root"));
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+    }
+    Ok(())
+}
+
+/// The relay runs with the rights of the user of the app on windows
+#[cfg(not(unix))]
+fn drop_privileges() -> anyhow::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,236 @@
+use crate::client::credentials;
+use crate::client::dns_cache;
+use crate::client::profile::Profile;
+use std::path::PathBuf;
+
+/// What the relay of a profile still does once its listeners are bound, everything else being denied to it
+#[derive(Debug, Default)]
+pub struct Allowed {
+    /// Directories the relay writes into: its private files, i.e: the upgrade requests headers, the dns cache, and
+    /// the ones of the client certificate when it is renewed
+    pub writable: Vec<PathBuf>,
+    /// Commands are run to refresh the upgrade credentials or renew the client certificate
+    pub exec: bool,
+}
+
+impl Allowed {
+    /// Called once the relay runs as the account it keeps, the private directory being the one of that account
+    pub fn of(profile: &Profile) -> anyhow::Result<Self> {
+        let mut writable = vec![credentials::private_dir()?];
+        writable.extend(dns_cache::cache_dir());
+        if profile.tls_certificate_renewal.is_some() {
+            writable.extend(
+                [&profile.tls_certificate, &profile.tls_private_key]
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|path| path.parent().map(PathBuf::from)),
+            );
+        }
+        Ok(Self {
+            writable,
+            exec: profile.http_upgrade_credentials_provider.is_some()
+                || profile.tls_certificate_renewal.is_some(),
+        })
+    }
+}
+
+/// Confine the relay process: it may read the files, but only write into the directories it needs and only run
+/// commands when the profile has some. Returns false when the system cannot confine it, i.e: a Linux kernel older
+/// than 5.13 or windows, where the relay keeps the rights of its account.
+/// Cannot be undone, by the relay nor by the commands it runs.
+pub fn confine(allowed: &Allowed) -> anyhow::Result<bool> {
+    platform::confine(allowed)
+}
+
+/// Landlock ruleset, read only access to every file and full access beneath the writable directories
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::Allowed;
+    use anyhow::Context;
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::path::Path;
+
+    const CREATE_RULESET_VERSION: libc::c_uint = 1;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// Every access of the first version of landlock, from executing to making a symbolic link
+    const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+    /// Linking or renaming a file into another directory, since version 2
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    /// Truncating a file, since version 3
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: libc::c_int,
+    }
+
+    pub fn confine(allowed: &Allowed) -> anyhow::Result<bool> {
+        // SAFETY: asking for the version takes no attribute
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0usize,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Ok(false);
+        }
+        let mut handled = ACCESS_FS_V1;
+        if abi >= 2 {
+            handled |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            handled |= ACCESS_FS_TRUNCATE;
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        // SAFETY: the attribute outlives the call, and the descriptor returned is owned from here
+        let ruleset = unsafe {
+            let fd = libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                std::mem::size_of::<RulesetAttr>(),
+                0u32,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error()).context("Cannot create landlock ruleset");
+            }
+            OwnedFd::from_raw_fd(fd as libc::c_int)
+        };
+
+        let mut read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+        if allowed.exec {
+            read |= ACCESS_FS_EXECUTE;
+        }
+        allow(&ruleset, Path::new("/"), read)?;
+        // The output of the commands is read through pipes, their standard input is /dev/null
+        allow(
+            &ruleset,
+            Path::new("/dev/null"),
+            ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE,
+        )?;
+        for dir in &allowed.writable {
+            allow(&ruleset, dir, handled)?;
+        }
+
+        // SAFETY: plain calls on a descriptor owned above
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            if libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0u32) != 0 {
+                return Err(io::Error::last_os_error()).context("Cannot restrict the relay");
+            }
+        }
+        Ok(true)
+    }
+
+    /// A missing path is skipped, the relay creates nothing above the directories it is given
+    fn allow(ruleset: &OwnedFd, path: &Path, access: u64) -> anyhow::Result<()> {
+        let parent = match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(path)
+        {
+            Ok(parent) => parent,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).with_context(|| format!("Cannot open {}", path.display())),
+        };
+        let rule = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: parent.as_raw_fd(),
+        };
+        // SAFETY: the rule and both descriptors outlive the call
+        let added = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &rule as *const PathBeneathAttr,
+                0u32,
+            )
+        };
+        if added != 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Cannot allow access to {}", path.display()));
+        }
+        Ok(())
+    }
+}
+
+/// Sandbox profile denying the writes outside of the writable directories, and running commands unless allowed
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::Allowed;
+    use anyhow::anyhow;
+    use std::ffi::{c_char, c_int, CStr, CString};
+    use std::fmt::Write;
+
+    extern "C" {
+        fn sandbox_init(profile: *const c_char, flags: u64, errorbuf: *mut *mut c_char) -> c_int;
+        fn sandbox_free_error(errorbuf: *mut c_char);
+    }
+
+    pub fn confine(allowed: &Allowed) -> anyhow::Result<bool> {
+        let mut profile = String::from(
+            "(version 1)\n(allow default)\n(deny file-write*)\n(allow file-write* (literal \"/dev/null\")",
+        );
+        for dir in &allowed.writable {
+            // Rules match the real path, i.e: /private/var for the temporary directory
+            let dir = std::fs::canonicalize(dir).unwrap_or_else(|_| dir.clone());
+            let Some(dir) = dir.to_str() else {
+                continue;
+            };
+            write!(profile, " (subpath {:?})", dir)?;
+        }
+        profile.push_str(")\n");
+        if !allowed.exec {
+            profile.push_str("(deny process-exec)\n");
+        }
+
+        let profile = CString::new(profile)?;
+        let mut error = std::ptr::null_mut();
+        // SAFETY: the profile is a valid string, the error is only read and freed when the call fails
+        unsafe {
+            if sandbox_init(profile.as_ptr(), 0, &mut error) != 0 {
+                let message = if error.is_null() {
+                    String::new()
+                } else {
+                    let message = CStr::from_ptr(error).to_string_lossy().into_owned();
+                    sandbox_free_error(error);
+                    message
+                };
+                return Err(anyhow!("Cannot apply the sandbox profile: {}", message));
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Restricted tokens and app containers would also take away the files the relay writes, and the access to the
+/// network of the machine for the latter: the relay is only isolated by the process boundary
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod platform {
+    use super::Allowed;
+
+    pub fn confine(_allowed: &Allowed) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+}
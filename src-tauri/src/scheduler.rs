@@ -0,0 +1,129 @@
+use crate::autostart;
+use crate::client::manager::ClientManager;
+use crate::client::profile::Profile;
+use crate::client::schedule;
+use crate::commands;
+use crate::history::ConnectionHistory;
+use crate::kill_switch::KillSwitch;
+use crate::pac::PacServer;
+use crate::profile_store;
+use crate::relay::RelayProcesses;
+use crate::system_proxy::SystemProxy;
+use chrono::Local;
+use log::{error, info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// Saved profiles are read again that often, so edited schedules apply and clock changes are caught up
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Sent to the frontend when a scheduled profile enters or leaves its active windows
+pub const SCHEDULE_EVENT: &str = "profile-schedule";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleStatus {
+    pub profile_id: String,
+    /// In one of the active windows of the profile, it is then meant to be connected
+    pub active: bool,
+    /// When the profile is next connected or disconnected, None when its schedule never changes
+    pub next_change_at_ms: Option<i64>,
+}
+
+fn status(profile: &Profile) -> ScheduleStatus {
+    let now = Local::now();
+    ScheduleStatus {
+        profile_id: profile.name.clone(),
+        active: schedule::is_active(&profile.schedule, now),
+        next_change_at_ms: schedule::next_change(&profile.schedule, now)
+            .map(|next| next.timestamp_millis()),
+    }
+}
+
+/// Saved profiles having a schedule
+fn scheduled_profiles(app: &AppHandle) -> anyhow::Result<Vec<Profile>> {
+    let data_dir = app.path().app_data_dir()?;
+    // Nothing saved yet
+    if !data_dir.join(profile_store::PROFILE_STORE).exists() {
+        return Ok(vec![]);
+    }
+    Ok(profile_store::load_profiles(&data_dir)?
+        .into_iter()
+        .filter(|profile| !profile.schedule.is_empty())
+        .collect())
+}
+
+/// Where every scheduled profile stands, for the UI to show when it is next connected or disconnected
+pub fn statuses(app: &AppHandle) -> anyhow::Result<Vec<ScheduleStatus>> {
+    Ok(scheduled_profiles(app)?.iter().map(status).collect())
+}
+
+/// Connect the scheduled profiles when they enter an active window, and disconnect them when they leave it.
+/// Only the boundaries are acted upon, a profile connected or disconnected by hand in between is left as is.
+/// The first check waits for the profiles connected at launch (autoconnect, upgrade handoff) to be up.
+pub fn spawn(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut active: HashMap<String, bool> = HashMap::new();
+        let mut interval =
+            tokio::time::interval_at(tokio::time::Instant::now() + CHECK_INTERVAL, CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let profiles = match scheduled_profiles(&app) {
+                Ok(profiles) => profiles,
+                Err(err) => {
+                    error!("Cannot load scheduled profiles: {:?}", err);
+                    continue;
+                }
+            };
+            active.retain(|profile_id, _| profiles.iter().any(|p| p.name == *profile_id));
+
+            let mut to_connect = vec![];
+            for profile in profiles {
+                let status = status(&profile);
+                let previous = active.insert(profile.name.clone(), status.active);
+                if previous == Some(status.active) {
+                    continue;
+                }
+                if let Err(err) = app.emit(SCHEDULE_EVENT, &status) {
+                    warn!("Cannot report profile schedule: {:?}", err);
+                }
+                if status.active {
+                    if !is_connected(&app, &profile.name) {
+                        info!("Profile {} entered its schedule", profile.name);
+                        to_connect.push(profile);
+                    }
+                } else if previous == Some(true) && is_connected(&app, &profile.name) {
+                    info!(
+                        "Profile {} left its schedule, disconnecting it",
+                        profile.name
+                    );
+                    let result = commands::disconnect(
+                        profile.name.clone(),
+                        app.state::<ClientManager>(),
+                        app.state::<RelayProcesses>(),
+                        app.state::<SystemProxy>(),
+                        app.state::<PacServer>(),
+                        app.state::<KillSwitch>(),
+                        app.state::<ConnectionHistory>(),
+                    );
+                    if let Err(err) = result {
+                        warn!("Cannot disconnect profile {}: {}", profile.name, err);
+                    }
+                }
+            }
+            if !to_connect.is_empty() {
+                autostart::connect_profiles(&app, to_connect).await;
+            }
+        }
+    });
+}
+
+fn is_connected(app: &AppHandle, profile_id: &str) -> bool {
+    app.state::<ClientManager>().get(profile_id).is_some()
+        || app
+            .state::<RelayProcesses>()
+            .list()
+            .iter()
+            .any(|relay| relay.profile.name == profile_id)
+}
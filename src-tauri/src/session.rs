@@ -0,0 +1,228 @@
+use crate::autostart;
+use crate::client::manager::ClientManager;
+use crate::commands;
+use crate::profile_store;
+use crate::relay::RelayProcesses;
+use anyhow::Context;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+/// File of the app data directory remembering which profiles are connected, to restore them after a crash
+pub const SESSION_FILE: &str = "session.json";
+/// Sent to the frontend with the profiles connected when the previous run stopped, for the user to reconnect them
+pub const INTERRUPTED_SESSION_EVENT: &str = "session-interrupted";
+const WRITE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What to do on launch with the profiles that were connected when the app crashed or the machine rebooted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecoveryMode {
+    /// Offer the user to reconnect them
+    #[default]
+    Ask,
+    Reconnect,
+    Never,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SessionState {
+    #[serde(default)]
+    recovery: RecoveryMode,
+    /// Set while the app runs, a run that did not exit cleanly leaves it set
+    running: bool,
+    connected: Vec<String>,
+}
+
+/// Connected profiles of the current run, and the ones of the previous run if it did not exit cleanly
+pub struct Session {
+    file: PathBuf,
+    state: Mutex<SessionState>,
+    /// Waiting for the user to reconnect them or not
+    interrupted: Mutex<Vec<String>>,
+    /// Set once a newer version of the app took the profiles over, the file is then its own
+    handed_over: AtomicBool,
+}
+
+impl Session {
+    /// Read what the previous run left, then mark this one as running
+    pub fn open(data_dir: &Path) -> Self {
+        let file = data_dir.join(SESSION_FILE);
+        let previous: SessionState = match std::fs::read(&file) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|err| {
+                warn!("Ignoring invalid session file: {:?}", err);
+                SessionState::default()
+            }),
+            Err(_) => SessionState::default(),
+        };
+        let interrupted = if previous.running {
+            previous.connected.clone()
+        } else {
+            vec![]
+        };
+        if !interrupted.is_empty() {
+            warn!(
+                "Previous run did not exit cleanly, profiles {} were connected",
+                interrupted.join(", ")
+            );
+        }
+
+        let session = Self {
+            file,
+            state: Mutex::new(SessionState {
+                recovery: previous.recovery,
+                running: true,
+                connected: vec![],
+            }),
+            interrupted: Mutex::new(interrupted),
+            handed_over: AtomicBool::new(false),
+        };
+        if let Err(err) = session.save(&session.state.lock()) {
+            error!("Cannot save session: {:?}", err);
+        }
+        session
+    }
+
+    pub fn recovery(&self) -> RecoveryMode {
+        self.state.lock().recovery
+    }
+
+    pub fn set_recovery(&self, recovery: RecoveryMode) -> anyhow::Result<()> {
+        let mut state = self.state.lock();
+        state.recovery = recovery;
+        self.save(&state)
+    }
+
+    /// Profiles connected when the previous run stopped, that have not been reconnected nor dismissed yet
+    pub fn interrupted(&self) -> Vec<String> {
+        self.interrupted.lock().clone()
+    }
+
+    pub fn take_interrupted(&self) -> Vec<String> {
+        std::mem::take(&mut *self.interrupted.lock())
+    }
+
+    /// Remember the connected profiles, only writing the file when they changed.
+    /// The ones still waiting to be reconnected are kept, so they are offered again if this run stops too.
+    fn record(&self, mut connected: Vec<String>) -> anyhow::Result<()> {
+        if self.handed_over.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        for profile_id in self.interrupted() {
+            if !connected.contains(&profile_id) {
+                connected.push(profile_id);
+            }
+        }
+        connected.sort();
+        let mut state = self.state.lock();
+        if state.connected == connected {
+            return Ok(());
+        }
+        state.connected = connected;
+        self.save(&state)
+    }
+
+    /// Leave the file to the newer version of the app taking over
+    pub fn hand_over(&self) {
+        self.handed_over.store(true, Ordering::Relaxed);
+    }
+
+    /// Mark the run as exited cleanly, nothing is restored on next launch
+    pub fn close(&self) -> anyhow::Result<()> {
+        if self.handed_over.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let mut state = self.state.lock();
+        state.running = false;
+        self.save(&state)
+    }
+
+    /// Write then rename, so a crash while writing does not lose the previous content
+    fn save(&self, state: &SessionState) -> anyhow::Result<()> {
+        if let Some(dir) = self.file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let tmp = self.file.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(state)?)
+            .with_context(|| "Cannot save session")?;
+        std::fs::rename(&tmp, &self.file)?;
+        Ok(())
+    }
+}
+
+/// Keep track of the connected profiles for as long as the app runs
+pub fn spawn_writer(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(WRITE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let connected = commands::profile_statuses(
+                &app.state::<ClientManager>(),
+                &app.state::<RelayProcesses>(),
+            )
+            .into_iter()
+            .map(|status| status.connection.profile_id)
+            .collect();
+            if let Err(err) = app.state::<Session>().record(connected) {
+                warn!("Cannot save session: {:?}", err);
+            }
+        }
+    });
+}
+
+/// Deal with the profiles of an interrupted previous run as the user asked: reconnect them, offer to, or forget them
+pub fn recover(app: &AppHandle) {
+    let session = app.state::<Session>();
+    if session.interrupted().is_empty() {
+        return;
+    }
+    match session.recovery() {
+        RecoveryMode::Never => {
+            session.take_interrupted();
+        }
+        RecoveryMode::Reconnect => {
+            let app = app.clone();
+            tauri::async_runtime::spawn(async move {
+                if let Err(err) = reconnect(&app).await {
+                    error!("Cannot restore interrupted session: {:?}", err);
+                }
+            });
+        }
+        RecoveryMode::Ask => {
+            if let Err(err) = app.emit(INTERRUPTED_SESSION_EVENT, session.interrupted()) {
+                error!("Cannot offer to restore interrupted session: {:?}", err);
+            }
+        }
+    }
+}
+
+/// Reconnect the saved profiles that were connected when the previous run stopped. Profiles connected since are
+/// left as they are, as well as the ones marked for autoconnect when the app has been launched at login.
+pub async fn reconnect(app: &AppHandle) -> anyhow::Result<()> {
+    let interrupted = app.state::<Session>().take_interrupted();
+    let at_login = autostart::launched_at_login();
+    let connected: Vec<String> = commands::profile_statuses(
+        &app.state::<ClientManager>(),
+        &app.state::<RelayProcesses>(),
+    )
+    .into_iter()
+    .map(|status| status.connection.profile_id)
+    .collect();
+    let data_dir = app.path().app_data_dir()?;
+    let profiles = profile_store::load_profiles(&data_dir)?
+        .into_iter()
+        .filter(|p| interrupted.contains(&p.name) && !connected.contains(&p.name))
+        .filter(|p| !(at_login && p.autoconnect))
+        .collect::<Vec<_>>();
+    info!(
+        "Restoring {} profiles of the interrupted session",
+        profiles.len()
+    );
+    autostart::connect_profiles(app, profiles).await;
+    Ok(())
+}
@@ -0,0 +1,59 @@
+use crate::client::manager::ClientManager;
+use crate::history::{ConnectionHistory, HistoryEntry};
+use crate::kill_switch::KillSwitch;
+use crate::relay::RelayProcesses;
+use crate::session::Session;
+use crate::stats_store::StatsStore;
+use crate::status_file;
+use crate::system_proxy::SystemProxy;
+use log::{error, info, warn};
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+/// Time the relay processes get to exit before the app exits without them
+const RELAYS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Leave the machine as it was before the app ran, once it is exiting.
+/// The traffic since the last sample is recorded before the tunnels stop, then the tunnels and the relays of the
+/// isolated profiles are stopped, and only then are the system proxy and the firewall restored, so no traffic
+/// leaks while the tunnels are still up. The connected profiles stay in the session, which is marked as closed.
+pub fn on_exit(app: &AppHandle) {
+    if let Err(err) = app.state::<StatsStore>().record(app) {
+        error!("Cannot record traffic stats: {:?}", err);
+    }
+
+    let managed = app.state::<ClientManager>().list();
+    let history = app.state::<ConnectionHistory>();
+    for managed in &managed {
+        managed.client.shutdown();
+        history.record(HistoryEntry::disconnected(
+            &managed.profile.name,
+            managed.client.remote_addr.as_str(),
+            &managed.client.stats.traffic_snapshot(),
+        ));
+    }
+    let relays = app.state::<RelayProcesses>();
+    let stopped = tauri::async_runtime::block_on(async {
+        tokio::time::timeout(RELAYS_TIMEOUT, relays.stop_all()).await
+    });
+    match stopped {
+        Ok(isolated) => info!(
+            "Stopped {} profiles before exiting",
+            managed.len() + isolated.len()
+        ),
+        Err(_) => warn!("Relay processes did not exit in time"),
+    }
+
+    if let Err(err) = app.state::<SystemProxy>().restore() {
+        error!("Cannot restore system proxy settings: {:?}", err);
+    }
+    if let Err(err) = app.state::<KillSwitch>().release_all() {
+        error!("Cannot remove the kill switch rules: {:?}", err);
+    }
+    if let Err(err) = status_file::clear(app) {
+        error!("Cannot clear status file: {:?}", err);
+    }
+    if let Err(err) = app.state::<Session>().close() {
+        error!("Cannot save session: {:?}", err);
+    }
+}
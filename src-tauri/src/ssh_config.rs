@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Context};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const BLOCK_BEGIN: &str = "# BEGIN wstunnel-desktop";
+const BLOCK_END: &str = "# END wstunnel-desktop";
+
+/// How ssh reaches the hosts through a profile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SshProxyMode {
+    /// ssh starts the app as its ProxyCommand, relaying the connection over stdio to the host and port it asks for,
+    /// the app window being open or not
+    StdioBridge,
+    /// ssh connects to a tunnel of the connected profile listening on that port, which leads to a single host
+    Listener { local_port: u16 },
+}
+
+/// Stanza of the ssh config sending the hosts matching `host_pattern` through the profile
+pub fn stanza(profile: &str, host_pattern: &str, mode: &SshProxyMode) -> anyhow::Result<String> {
+    let host_pattern = host_pattern.trim();
+    if host_pattern.is_empty() || host_pattern.contains(['\n', '"']) {
+        return Err(anyhow!("Invalid host pattern {}", host_pattern));
+    }
+    let mut lines = vec![format!("Host {}", host_pattern)];
+    match mode {
+        SshProxyMode::StdioBridge => {
+            let binary =
+                std::env::current_exe().with_context(|| "Cannot find the binary of the app")?;
+            lines.push(format!(
+                "    ProxyCommand {} --stdio-bridge --profile {} %h %p",
+                proxy_command_arg(&binary.to_string_lossy())?,
+                proxy_command_arg(profile)?
+            ));
+        }
+        SshProxyMode::Listener { local_port } => {
+            // The tunnel leads to one host only, a pattern would send every matching host to it
+            if host_pattern.contains(['*', '?', '!', ' ']) {
+                return Err(anyhow!(
+                    "A tunnel listener leads to a single host, {} matches several",
+                    host_pattern
+                ));
+            }
+            lines.push("    HostName 127.0.0.1".to_string());
+            lines.push(format!("    Port {}", local_port));
+            // The host key is checked as the one of the host, not of 127.0.0.1
+            lines.push(format!("    HostKeyAlias {}", host_pattern));
+        }
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Argument of the ProxyCommand, quoted so that it reaches the app as it is. ssh expands the `%` tokens of the
+/// command, then runs it through `sh -c` on unix, where nothing is expanded within single quotes
+#[cfg(unix)]
+fn proxy_command_arg(arg: &str) -> anyhow::Result<String> {
+    if arg.contains(['\n', '\r']) {
+        return Err(anyhow!("Invalid ProxyCommand argument {:?}", arg));
+    }
+    Ok(format!(
+        "'{}'",
+        arg.replace('%', "%%").replace('\'', "'\\''")
+    ))
+}
+
+/// Argument of the ProxyCommand, quoted so that it reaches the app as it is. ssh expands the `%` tokens of the
+/// command, then starts it without a shell on Windows, where a double quote cannot be escaped reliably
+#[cfg(windows)]
+fn proxy_command_arg(arg: &str) -> anyhow::Result<String> {
+    if arg.contains(['\n', '\r', '"']) {
+        return Err(anyhow!("Invalid ProxyCommand argument {:?}", arg));
+    }
+    Ok(format!("\"{}\"", arg.replace('%', "%%")))
+}
+
+fn config_path() -> anyhow::Result<PathBuf> {
+    Ok(dirs::home_dir()
+        .ok_or_else(|| anyhow!("Cannot find the home directory of the user"))?
+        .join(".ssh")
+        .join("config"))
+}
+
+fn markers(host_pattern: &str) -> (String, String) {
+    (
+        format!("{} {}", BLOCK_BEGIN, host_pattern.trim()),
+        format!("{} {}", BLOCK_END, host_pattern.trim()),
+    )
+}
+
+/// The config without the block of the pattern
+fn without_block(config: &str, host_pattern: &str) -> String {
+    let (begin, end) = markers(host_pattern);
+    let mut kept = Vec::new();
+    let mut inside = false;
+    // The blank line written after the block goes with it
+    let mut after_block = false;
+    for line in config.lines() {
+        let trimmed = line.trim();
+        if trimmed == begin {
+            inside = true;
+        } else if inside {
+            if trimmed == end {
+                inside = false;
+                after_block = true;
+            }
+        } else if !(after_block && trimmed.is_empty()) {
+            kept.push(line);
+            after_block = false;
+        } else {
+            after_block = false;
+        }
+    }
+    kept.into_iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/// Write the stanza to `~/.ssh/config`, replacing the one written before for the same pattern.
+/// ssh uses the first value it finds for an option, so the stanza goes before the first `Host` or `Match`
+/// section, after the global options, taking precedence over a `Host *` section
+pub fn install(host_pattern: &str, stanza: &str) -> anyhow::Result<PathBuf> {
+    let path = config_path()?;
+    let config = match std::fs::read_to_string(&path) {
+        Ok(config) => config,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("Cannot read {}", path.display())),
+    };
+    let config = without_block(&config, host_pattern);
+    let (begin, end) = markers(host_pattern);
+    let block = format!("{}\n{}{}\n\n", begin, stanza, end);
+
+    let lines: Vec<&str> = config.split_inclusive('\n').collect();
+    let first_section = lines.iter().position(|line| {
+        let keyword = line.split_whitespace().next().unwrap_or_default();
+        keyword.eq_ignore_ascii_case("host") || keyword.eq_ignore_ascii_case("match")
+    });
+    let mut updated = String::with_capacity(config.len() + block.len());
+    match first_section {
+        Some(index) => {
+            updated.extend(lines[..index].iter().copied());
+            updated.push_str(&block);
+            updated.extend(lines[index..].iter().copied());
+        }
+        None => {
+            updated.push_str(&config);
+            if !updated.is_empty() && !updated.ends_with("\n\n") {
+                updated.push('\n');
+            }
+            updated.push_str(&block);
+        }
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_config(&path, &updated)?;
+    info!(
+        "ssh config for {} written to {}",
+        host_pattern,
+        path.display()
+    );
+    Ok(path)
+}
+
+/// Remove the stanza written for the pattern, if any
+pub fn uninstall(host_pattern: &str) -> anyhow::Result<()> {
+    let path = config_path()?;
+    let Ok(config) = std::fs::read_to_string(&path) else {
+        return Ok(());
+    };
+    let updated = without_block(&config, host_pattern);
+    if updated != config {
+        write_config(&path, &updated)?;
+    }
+    Ok(())
+}
+
+/// Write then rename, so a crash while writing does not truncate the config. The permissions of the config are
+/// kept, ssh refusing a config others can write to
+fn write_config(path: &Path, content: &str) -> anyhow::Result<()> {
+    let tmp = path.with_extension("wstunnel-desktop.tmp");
+    std::fs::write(&tmp, content).with_context(|| format!("Cannot write {}", tmp.display()))?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        std::fs::set_permissions(&tmp, metadata.permissions())?;
+    }
+    std::fs::rename(&tmp, path).with_context(|| format!("Cannot write {}", path.display()))?;
+    Ok(())
+}
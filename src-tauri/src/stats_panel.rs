@@ -0,0 +1,81 @@
+use crate::client::manager::ClientManager;
+use crate::commands;
+use crate::relay::RelayProcesses;
+use log::warn;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, WebviewUrl, WebviewWindowBuilder};
+
+pub const STATS_WINDOW: &str = "stats";
+/// Sent to every subscribed webview with the status of all connected profiles
+pub const STATS_EVENT: &str = "stats-updated";
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Webviews receiving the stats of the connected profiles.
+/// Every window reads the same client manager, no window runs a client of its own.
+#[derive(Default)]
+pub struct StatsSubscribers {
+    labels: Mutex<HashSet<String>>,
+    publishing: AtomicBool,
+}
+
+impl StatsSubscribers {
+    /// Start sending the stats to a webview, the publisher only runs once someone subscribed
+    pub fn subscribe(&self, app: &AppHandle, label: &str) {
+        self.labels.lock().insert(label.to_string());
+        if !self.publishing.swap(true, Ordering::Relaxed) {
+            spawn_publisher(app.clone());
+        }
+    }
+
+    pub fn unsubscribe(&self, label: &str) {
+        self.labels.lock().remove(label);
+    }
+}
+
+/// Show the detached stats window, focusing it when already open
+pub fn open_window(app: &AppHandle) -> tauri::Result<()> {
+    if let Some(window) = app.get_webview_window(STATS_WINDOW) {
+        return window.set_focus();
+    }
+    WebviewWindowBuilder::new(
+        app,
+        STATS_WINDOW,
+        WebviewUrl::App("index.html#/stats".into()),
+    )
+    .title("wstunnel statistics")
+    .inner_size(480.0, 600.0)
+    .build()?;
+    Ok(())
+}
+
+fn spawn_publisher(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(PUBLISH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let labels: Vec<String> = app
+                .state::<StatsSubscribers>()
+                .labels
+                .lock()
+                .iter()
+                .cloned()
+                .collect();
+            if labels.is_empty() {
+                continue;
+            }
+
+            let statuses = commands::profile_statuses(
+                &app.state::<ClientManager>(),
+                &app.state::<RelayProcesses>(),
+            );
+            for label in labels {
+                if let Err(err) = app.emit_to(label.as_str(), STATS_EVENT, &statuses) {
+                    warn!("Cannot send stats to {}: {:?}", label, err);
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,435 @@
+use crate::client::manager::ClientManager;
+use crate::client::profile::Profile;
+use crate::client::stats::TunnelMetrics;
+use crate::commands;
+use crate::history::ConnectionHistory;
+use crate::kill_switch::KillSwitch;
+use crate::pac::PacServer;
+use crate::relay::RelayProcesses;
+use crate::system_proxy::SystemProxy;
+use anyhow::Context;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, Manager};
+
+const DATABASE_FILE: &str = "traffic-stats.sqlite";
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(60);
+/// Samples older than that are deleted when the app starts
+const RETENTION_DAYS: u64 = 400;
+/// Sent to the frontend when a profile is disconnected for having used up its monthly budget
+pub const BUDGET_EXCEEDED_EVENT: &str = "budget-exceeded";
+/// Sent to the frontend when a profile crosses one of its budget warning thresholds, once per threshold and month
+pub const BUDGET_WARNING_EVENT: &str = "budget-warning";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BudgetUsage {
+    pub profile_id: String,
+    pub used_bytes: u64,
+    pub budget_mb: u64,
+    /// Highest warning threshold crossed this month, 100 once the budget is used up
+    pub percent: u8,
+}
+
+/// Width of the buckets the samples are summed into, in UTC
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Granularity {
+    Hour,
+    Day,
+    Week,
+    Month,
+}
+
+impl Granularity {
+    /// strftime format naming the bucket of a sample
+    fn bucket_format(self) -> &'static str {
+        match self {
+            Granularity::Hour => "%Y-%m-%dT%H:00",
+            Granularity::Day => "%Y-%m-%d",
+            Granularity::Week => "%Y-W%W",
+            Granularity::Month => "%Y-%m",
+        }
+    }
+}
+
+/// Period to query, as unix timestamps in seconds, the end excluded
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeRange {
+    pub from_sec: u64,
+    pub to_sec: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsagePoint {
+    pub bucket: String,
+    pub profile_id: String,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub connections: u64,
+}
+
+/// Traffic history of the tunnels, kept in a SQLite database of the app data directory
+pub struct StatsStore {
+    db: Mutex<Connection>,
+    /// Counters of every tunnel when last recorded, only what they counted since is recorded next
+    last: Mutex<HashMap<(String, String), LastCounters>>,
+}
+
+impl StatsStore {
+    pub fn open(data_dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(DATABASE_FILE);
+        let db = Connection::open(&path)
+            .with_context(|| format!("Cannot open stats database {}", path.display()))?;
+        Self::init(db)
+    }
+
+    /// Store kept in memory only, when the database cannot be opened
+    pub fn in_memory() -> anyhow::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(db: Connection) -> anyhow::Result<Self> {
+        db.execute_batch(
+            "CREATE TABLE IF NOT EXISTS samples (
+                ts INTEGER NOT NULL,
+                profile TEXT NOT NULL,
+                tunnel TEXT NOT NULL,
+                bytes_up INTEGER NOT NULL,
+                bytes_down INTEGER NOT NULL,
+                connections INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS samples_ts ON samples (ts);
+            CREATE INDEX IF NOT EXISTS samples_profile_ts ON samples (profile, ts);
+            CREATE TABLE IF NOT EXISTS budget_warnings (
+                profile TEXT NOT NULL,
+                month TEXT NOT NULL,
+                percent INTEGER NOT NULL,
+                PRIMARY KEY (profile, month)
+            );",
+        )?;
+        let retention = (RETENTION_DAYS * 24 * 3600) as i64;
+        db.execute(
+            "DELETE FROM samples WHERE ts < ?1",
+            params![now_sec() as i64 - retention],
+        )?;
+        Ok(Self {
+            db: Mutex::new(db),
+            last: Mutex::default(),
+        })
+    }
+
+    /// Record the traffic of the connected profiles since the previous call
+    pub fn record(&self, app: &AppHandle) -> anyhow::Result<()> {
+        let samples = sample(app, &mut self.last.lock());
+        self.insert(now_sec(), &samples)
+    }
+
+    fn insert(&self, ts: u64, samples: &[Sample]) -> anyhow::Result<()> {
+        let mut db = self.db.lock();
+        let tx = db.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO samples (ts, profile, tunnel, bytes_up, bytes_down, connections)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for sample in samples {
+                insert.execute(params![
+                    ts as i64,
+                    sample.profile,
+                    sample.tunnel,
+                    sample.bytes_up as i64,
+                    sample.bytes_down as i64,
+                    sample.connections as i64,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Traffic of every profile over a period, summed per bucket
+    pub fn query(
+        &self,
+        range: TimeRange,
+        granularity: Granularity,
+    ) -> anyhow::Result<Vec<UsagePoint>> {
+        let db = self.db.lock();
+        let mut query = db.prepare_cached(
+            "SELECT strftime(?1, ts, 'unixepoch') AS bucket, profile,
+                    SUM(bytes_up), SUM(bytes_down), SUM(connections)
+             FROM samples WHERE ts >= ?2 AND ts < ?3
+             GROUP BY bucket, profile ORDER BY bucket, profile",
+        )?;
+        let points = query
+            .query_map(
+                params![
+                    granularity.bucket_format(),
+                    range.from_sec as i64,
+                    range.to_sec as i64
+                ],
+                |row| {
+                    Ok(UsagePoint {
+                        bucket: row.get(0)?,
+                        profile_id: row.get(1)?,
+                        bytes_up: row.get::<_, i64>(2)? as u64,
+                        bytes_down: row.get::<_, i64>(3)? as u64,
+                        connections: row.get::<_, i64>(4)? as u64,
+                    })
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(points)
+    }
+
+    /// Bytes sent and received by a profile since the start of the current month, in UTC
+    pub fn month_usage(&self, profile_id: &str) -> anyhow::Result<u64> {
+        let db = self.db.lock();
+        let usage: i64 = db.query_row(
+            "SELECT COALESCE(SUM(bytes_up + bytes_down), 0) FROM samples
+             WHERE profile = ?1 AND ts >= CAST(strftime('%s', 'now', 'start of month') AS INTEGER)",
+            params![profile_id],
+            |row| row.get(0),
+        )?;
+        Ok(usage as u64)
+    }
+
+    /// Highest budget warning threshold reported this month, 0 when none has been
+    fn warned_percent(&self, profile_id: &str) -> anyhow::Result<u8> {
+        let db = self.db.lock();
+        let percent: Option<i64> = db
+            .query_row(
+                "SELECT percent FROM budget_warnings WHERE profile = ?1 AND month = strftime('%Y-%m', 'now')",
+                params![profile_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(percent.unwrap_or_default() as u8)
+    }
+
+    fn set_warned_percent(&self, profile_id: &str, percent: u8) -> anyhow::Result<()> {
+        self.db.lock().execute(
+            "INSERT OR REPLACE INTO budget_warnings (profile, month, percent)
+             VALUES (?1, strftime('%Y-%m', 'now'), ?2)",
+            params![profile_id, percent as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Fail when the profile already used up its monthly budget
+    pub fn check_budget(&self, profile_id: &str, budget_mb: Option<u64>) -> anyhow::Result<()> {
+        let Some(budget_mb) = budget_mb else {
+            return Ok(());
+        };
+        let used = self.month_usage(profile_id)?;
+        if used >= budget_mb * 1024 * 1024 {
+            return Err(anyhow::anyhow!(
+                "Profile {} used {} MB of its {} MB monthly budget",
+                profile_id,
+                used / 1024 / 1024,
+                budget_mb
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Traffic of a tunnel since the previous sample
+struct Sample {
+    profile: String,
+    tunnel: String,
+    bytes_up: u64,
+    bytes_down: u64,
+    connections: u64,
+}
+
+/// Counters as read at the previous sample. The counters start over when a profile reconnects,
+/// which is told apart by the counters being another instance, or going down for isolated profiles.
+struct LastCounters {
+    metrics: Option<Weak<TunnelMetrics>>,
+    bytes_up: u64,
+    bytes_down: u64,
+    connections: u64,
+}
+
+impl LastCounters {
+    fn continues(&self, previous: &LastCounters) -> bool {
+        match (&self.metrics, &previous.metrics) {
+            (Some(current), Some(previous)) => current.ptr_eq(previous),
+            (None, None) => {
+                self.bytes_up >= previous.bytes_up
+                    && self.bytes_down >= previous.bytes_down
+                    && self.connections >= previous.connections
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Record the traffic of the connected profiles every minute, and disconnect those exceeding their monthly budget
+pub fn spawn_sampler(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(SAMPLE_INTERVAL);
+        loop {
+            interval.tick().await;
+            let store = app.state::<StatsStore>();
+            if let Err(err) = store.record(&app) {
+                error!("Cannot record traffic stats: {:?}", err);
+                continue;
+            }
+            enforce_budgets(&app, &store);
+        }
+    });
+}
+
+fn sample(app: &AppHandle, last: &mut HashMap<(String, String), LastCounters>) -> Vec<Sample> {
+    let mut current = vec![];
+    for managed in app.state::<ClientManager>().list() {
+        for (tunnel_id, metrics) in managed.client.stats.all_tunnel_metrics() {
+            let counters = LastCounters {
+                metrics: Some(Arc::downgrade(&metrics)),
+                bytes_up: metrics.traffic.bytes_up.load(Ordering::Relaxed),
+                bytes_down: metrics.traffic.bytes_down.load(Ordering::Relaxed),
+                connections: metrics.traffic.connections.load(Ordering::Relaxed),
+            };
+            current.push(((managed.profile.name.clone(), tunnel_id), counters));
+        }
+    }
+    // The tunnels of an isolated profile run in its relay, which only reports the totals of the profile
+    for relay in app.state::<RelayProcesses>().list() {
+        let counters = LastCounters {
+            metrics: None,
+            bytes_up: relay.traffic.bytes_up,
+            bytes_down: relay.traffic.bytes_down,
+            connections: relay.traffic.connections,
+        };
+        current.push(((relay.profile.name, String::new()), counters));
+    }
+
+    let mut samples = vec![];
+    let mut seen = HashMap::new();
+    for (key, counters) in current {
+        let sample = match last.get(&key) {
+            Some(previous) if counters.continues(previous) => Sample {
+                profile: key.0.clone(),
+                tunnel: key.1.clone(),
+                bytes_up: counters.bytes_up - previous.bytes_up,
+                bytes_down: counters.bytes_down - previous.bytes_down,
+                connections: counters.connections - previous.connections,
+            },
+            _ => Sample {
+                profile: key.0.clone(),
+                tunnel: key.1.clone(),
+                bytes_up: counters.bytes_up,
+                bytes_down: counters.bytes_down,
+                connections: counters.connections,
+            },
+        };
+        if sample.bytes_up > 0 || sample.bytes_down > 0 || sample.connections > 0 {
+            samples.push(sample);
+        }
+        seen.insert(key, counters);
+    }
+    // Tunnels gone since are forgotten
+    *last = seen;
+    samples
+}
+
+fn enforce_budgets(app: &AppHandle, store: &StatsStore) {
+    let profiles = app
+        .state::<ClientManager>()
+        .list()
+        .into_iter()
+        .map(|m| m.profile)
+        .chain(
+            app.state::<RelayProcesses>()
+                .list()
+                .into_iter()
+                .map(|r| r.profile),
+        );
+    for profile in profiles {
+        if let Err(err) = warn_budget(app, store, &profile) {
+            warn!("Cannot check budget of profile {}: {:?}", profile.name, err);
+        }
+        if !profile.budget_disconnect {
+            continue;
+        }
+        let Err(err) = store.check_budget(&profile.name, profile.monthly_budget_mb) else {
+            continue;
+        };
+        warn!("{:#}, disconnecting it", err);
+        let result = commands::disconnect(
+            profile.name.clone(),
+            app.state::<ClientManager>(),
+            app.state::<RelayProcesses>(),
+            app.state::<SystemProxy>(),
+            app.state::<PacServer>(),
+            app.state::<KillSwitch>(),
+            app.state::<ConnectionHistory>(),
+        );
+        if let Err(err) = result {
+            warn!("Cannot disconnect profile {}: {}", profile.name, err);
+        }
+        if let Err(err) = app.emit(BUDGET_EXCEEDED_EVENT, &profile.name) {
+            warn!("Cannot report exceeded budget: {:?}", err);
+        }
+        info!("Profile {} disconnected until next month", profile.name);
+    }
+}
+
+/// How much of its monthly budget a profile used, None when it has no budget
+pub fn budget_usage(store: &StatsStore, profile: &Profile) -> anyhow::Result<Option<BudgetUsage>> {
+    let Some(budget_mb) = profile.monthly_budget_mb else {
+        return Ok(None);
+    };
+    let used_bytes = store.month_usage(&profile.name)?;
+    let used_percent = (used_bytes as u128 * 100 / (budget_mb.max(1) as u128 * 1024 * 1024)) as u64;
+    let percent = profile
+        .budget_warning_percents
+        .iter()
+        .copied()
+        .chain([100])
+        .filter(|threshold| *threshold as u64 <= used_percent)
+        .max()
+        .unwrap_or_default();
+    Ok(Some(BudgetUsage {
+        profile_id: profile.name.clone(),
+        used_bytes,
+        budget_mb,
+        percent,
+    }))
+}
+
+/// Report the highest threshold the profile crossed, unless already reported this month
+fn warn_budget(app: &AppHandle, store: &StatsStore, profile: &Profile) -> anyhow::Result<()> {
+    let Some(usage) = budget_usage(store, profile)? else {
+        return Ok(());
+    };
+    if usage.percent == 0 || usage.percent <= store.warned_percent(&profile.name)? {
+        return Ok(());
+    }
+    warn!(
+        "Profile {} used {}% of its {} MB monthly budget",
+        profile.name, usage.percent, usage.budget_mb
+    );
+    store.set_warned_percent(&profile.name, usage.percent)?;
+    app.emit(BUDGET_WARNING_EVENT, usage)?;
+    Ok(())
+}
+
+fn now_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
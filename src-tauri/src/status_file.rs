@@ -0,0 +1,125 @@
+use crate::client::manager::ClientManager;
+use crate::commands;
+use crate::relay::RelayProcesses;
+use log::warn;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tauri::{AppHandle, Manager};
+use url::Url;
+
+/// File of the app data directory read by external widgets (Polybar, Rainmeter, menu bar scripts)
+pub const STATUS_FILE: &str = "status.json";
+const WRITE_INTERVAL: Duration = Duration::from_secs(2);
+/// Bumped on incompatible changes of the file layout
+const STATUS_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WidgetStatus {
+    version: u32,
+    updated_at_sec: u64,
+    profiles: Vec<WidgetProfile>,
+}
+
+/// What a widget shows about a connected profile, without anything secret
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WidgetProfile {
+    profile_id: String,
+    server: String,
+    listeners: Vec<String>,
+    bytes_up: u64,
+    bytes_down: u64,
+    active_connections: u64,
+    uptime_sec: u64,
+}
+
+pub fn status_path(app: &AppHandle) -> tauri::Result<PathBuf> {
+    Ok(app.path().app_data_dir()?.join(STATUS_FILE))
+}
+
+/// Keep the status file up to date for as long as the app runs
+pub fn spawn_writer(app: AppHandle) {
+    tauri::async_runtime::spawn(async move {
+        let mut interval = tokio::time::interval(WRITE_INTERVAL);
+        let mut last_error = None;
+        loop {
+            interval.tick().await;
+            let statuses = commands::profile_statuses(
+                &app.state::<ClientManager>(),
+                &app.state::<RelayProcesses>(),
+            );
+            let profiles = statuses
+                .into_iter()
+                .map(|status| WidgetProfile {
+                    profile_id: status.connection.profile_id,
+                    server: server_name(&status.connection.remote_addr),
+                    listeners: status
+                        .connection
+                        .listeners
+                        .iter()
+                        .map(|l| l.bound.to_string())
+                        .collect(),
+                    bytes_up: status.traffic.bytes_up,
+                    bytes_down: status.traffic.bytes_down,
+                    active_connections: status.traffic.active_connections,
+                    uptime_sec: status.traffic.uptime_sec,
+                })
+                .collect();
+
+            let result = status_path(&app)
+                .map_err(anyhow::Error::from)
+                .and_then(|path| write(&path, profiles));
+            // Reported once, not every couple of seconds
+            match result {
+                Ok(()) => last_error = None,
+                Err(err) => {
+                    let error = format!("{:?}", err);
+                    if last_error.as_ref() != Some(&error) {
+                        warn!("Cannot write status file: {}", error);
+                    }
+                    last_error = Some(error);
+                }
+            }
+        }
+    });
+}
+
+/// Tell the widgets nothing is connected anymore, when the app exits
+pub fn clear(app: &AppHandle) -> anyhow::Result<()> {
+    write(&status_path(app)?, vec![])
+}
+
+/// Write then rename, so a widget never reads a partial file
+fn write(path: &Path, profiles: Vec<WidgetProfile>) -> anyhow::Result<()> {
+    let status = WidgetStatus {
+        version: STATUS_VERSION,
+        updated_at_sec: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        profiles,
+    };
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(&status)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Host and port of the server, leaving out the credentials the url may hold
+fn server_name(remote_addr: &str) -> String {
+    Url::parse(remote_addr)
+        .ok()
+        .and_then(|url| {
+            let host = url.host_str()?.to_string();
+            Some(match url.port_or_known_default() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host,
+            })
+        })
+        .unwrap_or_default()
+}
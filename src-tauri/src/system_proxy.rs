@@ -0,0 +1,542 @@
+use anyhow::{anyhow, Context};
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use url::Url;
+use wstunnel::tunnel::LocalProtocol;
+
+const BACKUP_FILE: &str = "system_proxy_backup.json";
+
+/// Kind of proxy the OS is configured to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+impl ProxyKind {
+    /// Kind of proxy served by a local listener, None if it is not a proxy
+    pub fn of(protocol: &LocalProtocol) -> Option<Self> {
+        match protocol {
+            LocalProtocol::Socks5 { .. } => Some(ProxyKind::Socks5),
+            LocalProtocol::HttpProxy { .. } => Some(ProxyKind::Http),
+            _ => None,
+        }
+    }
+}
+
+/// Outbound proxy the OS settings ask applications to reach the internet through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OsProxy {
+    Direct,
+    /// `host:port` of an http proxy, along with the hosts to reach directly (i.e: `*.corp.example.com`)
+    Manual {
+        proxy: String,
+        bypass: Vec<String>,
+    },
+    /// Url of the PAC file deciding of the proxy per destination
+    Auto(Url),
+}
+
+/// A command to execute, program first
+pub(crate) type CommandLine = Vec<String>;
+
+/// What is needed to put the system proxy settings back as they were before we changed them.
+/// Persisted on disk so they can be restored on next launch if the app crashes while connected.
+#[derive(Debug, Serialize, Deserialize)]
+struct Backup {
+    profile_id: String,
+    restore: Vec<CommandLine>,
+}
+
+/// Point the OS proxy settings to the local listener of a tunnel while its profile is connected
+pub struct SystemProxy {
+    backup_file: PathBuf,
+    owner: Mutex<Option<String>>,
+    /// Set once the settings have been left to a newer version of the app, they must not be restored anymore
+    handed_over: AtomicBool,
+}
+
+impl SystemProxy {
+    pub fn new(data_dir: &Path) -> Self {
+        Self {
+            backup_file: data_dir.join(BACKUP_FILE),
+            owner: Mutex::new(None),
+            handed_over: AtomicBool::new(false),
+        }
+    }
+
+    /// Take over the settings changed by the previous version of the app during an upgrade,
+    /// so they are kept as is when the same profile applies them again
+    pub fn adopt(&self) -> anyhow::Result<()> {
+        if !self.backup_file.exists() {
+            return Ok(());
+        }
+        let backup: Backup = serde_json::from_slice(&std::fs::read(&self.backup_file)?)
+            .with_context(|| "Invalid system proxy backup")?;
+        *self.owner.lock() = Some(backup.profile_id);
+        Ok(())
+    }
+
+    /// Leave the settings and their backup to the newer version of the app taking over
+    pub fn hand_over(&self) {
+        self.handed_over.store(true, Ordering::Relaxed);
+    }
+
+    /// Restore the settings left over by a previous run that did not exit cleanly
+    pub fn recover(&self) -> anyhow::Result<()> {
+        if !self.backup_file.exists() {
+            return Ok(());
+        }
+        warn!("Restoring system proxy settings left over by a previous run");
+        self.restore()
+    }
+
+    pub fn apply(&self, profile_id: &str, kind: ProxyKind, addr: SocketAddr) -> anyhow::Result<()> {
+        let addr = reachable_addr(addr);
+        // The original settings are already saved when the profile applies the proxy again
+        let reapplied =
+            self.owner.lock().as_deref() == Some(profile_id) && self.backup_file.exists();
+        if !reapplied {
+            // Only one profile can own the system proxy at a time, the latest one wins
+            self.restore()?;
+
+            let backup = Backup {
+                profile_id: profile_id.to_string(),
+                restore: platform::snapshot(kind)?,
+            };
+            if let Some(dir) = self.backup_file.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&self.backup_file, serde_json::to_vec(&backup)?)
+                .with_context(|| "Cannot save system proxy backup")?;
+        }
+
+        info!("Setting system {:?} proxy to {}", kind, addr);
+        run_all(&platform::configure(kind, addr)?)?;
+        *self.owner.lock() = Some(profile_id.to_string());
+        Ok(())
+    }
+
+    /// Restore the settings if they have been changed for this profile
+    pub fn release(&self, profile_id: &str) -> anyhow::Result<()> {
+        if self.owner.lock().as_deref() != Some(profile_id) {
+            return Ok(());
+        }
+        self.restore()
+    }
+
+    /// Put back the settings saved before they were changed, if any
+    pub fn restore(&self) -> anyhow::Result<()> {
+        if self.handed_over.load(Ordering::Relaxed) || !self.backup_file.exists() {
+            return Ok(());
+        }
+
+        let backup: Backup = serde_json::from_slice(&std::fs::read(&self.backup_file)?)
+            .with_context(|| "Invalid system proxy backup")?;
+        info!(
+            "Restoring system proxy settings changed for profile {}",
+            backup.profile_id
+        );
+        run_all(&backup.restore)?;
+        std::fs::remove_file(&self.backup_file)?;
+        *self.owner.lock() = None;
+        Ok(())
+    }
+}
+
+/// Read the outbound proxy from the OS settings. They are read again each time, as they change with the network
+pub fn outbound_proxy() -> anyhow::Result<OsProxy> {
+    platform::outbound_proxy()
+}
+
+/// Outbound proxy of the `https_proxy` and `no_proxy` environment variables, for desktops without proxy settings
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn env_proxy() -> OsProxy {
+    let var = |name: &str| {
+        std::env::var(name)
+            .or_else(|_| std::env::var(name.to_ascii_uppercase()))
+            .ok()
+            .filter(|value| !value.is_empty())
+    };
+    match var("https_proxy").or_else(|| var("all_proxy")) {
+        Some(proxy) => OsProxy::Manual {
+            proxy,
+            bypass: var("no_proxy")
+                .map(|hosts| hosts.split(',').map(|h| h.trim().to_string()).collect())
+                .unwrap_or_default(),
+        },
+        None => OsProxy::Direct,
+    }
+}
+
+/// Address to reach a local listener at, a listener bound to every interface is reached through loopback
+pub fn reachable_addr(addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+    } else {
+        addr
+    }
+}
+
+pub(crate) fn run(cmd: &[String]) -> anyhow::Result<String> {
+    let (program, args) = cmd.split_first().ok_or_else(|| anyhow!("Empty command"))?;
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Cannot execute {}", program))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{} failed: {}",
+            cmd.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+fn run_all(cmds: &[CommandLine]) -> anyhow::Result<()> {
+    for cmd in cmds {
+        run(cmd)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn cmd(args: &[&str]) -> CommandLine {
+    args.iter().map(|s| s.to_string()).collect()
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::{cmd, env_proxy, run, CommandLine, OsProxy, ProxyKind};
+    use std::net::SocketAddr;
+    use url::Url;
+
+    const SCHEMA: &str = "org.gnome.system.proxy";
+
+    fn schemas(kind: ProxyKind) -> Vec<String> {
+        match kind {
+            ProxyKind::Socks5 => vec![format!("{}.socks", SCHEMA)],
+            ProxyKind::Http => vec![format!("{}.http", SCHEMA), format!("{}.https", SCHEMA)],
+        }
+    }
+
+    fn get(schema: &str, key: &str) -> anyhow::Result<String> {
+        Ok(run(&cmd(&["gsettings", "get", schema, key]))?
+            .trim()
+            .to_string())
+    }
+
+    pub fn snapshot(kind: ProxyKind) -> anyhow::Result<Vec<CommandLine>> {
+        let mut restore = vec![];
+        for schema in schemas(kind) {
+            for key in ["host", "port"] {
+                let value = get(&schema, key)?;
+                restore.push(cmd(&["gsettings", "set", &schema, key, &value]));
+            }
+        }
+        let mode = get(SCHEMA, "mode")?;
+        restore.push(cmd(&["gsettings", "set", SCHEMA, "mode", &mode]));
+        Ok(restore)
+    }
+
+    pub fn configure(kind: ProxyKind, addr: SocketAddr) -> anyhow::Result<Vec<CommandLine>> {
+        let host = addr.ip().to_string();
+        let port = addr.port().to_string();
+        let mut cmds = vec![];
+        for schema in schemas(kind) {
+            cmds.push(cmd(&["gsettings", "set", &schema, "host", &host]));
+            cmds.push(cmd(&["gsettings", "set", &schema, "port", &port]));
+        }
+        cmds.push(cmd(&["gsettings", "set", SCHEMA, "mode", "manual"]));
+        Ok(cmds)
+    }
+
+    pub fn outbound_proxy() -> anyhow::Result<OsProxy> {
+        // Desktops other than GNOME only set the environment
+        let Ok(mode) = get(SCHEMA, "mode") else {
+            return Ok(env_proxy());
+        };
+        let unquote = |value: String| value.trim_matches('\'').to_string();
+        match unquote(mode).as_str() {
+            "manual" => {
+                let schema = format!("{}.https", SCHEMA);
+                let host = unquote(get(&schema, "host")?);
+                if host.is_empty() {
+                    return Ok(OsProxy::Direct);
+                }
+                // i.e: ['localhost', '127.0.0.0/8', '*.corp'], or @as [] when empty
+                let bypass = get(SCHEMA, "ignore-hosts")?
+                    .trim_start_matches("@as ")
+                    .trim_matches(|c| c == '[' || c == ']')
+                    .split(',')
+                    .map(|h| h.trim().trim_matches('\'').to_string())
+                    .filter(|h| !h.is_empty())
+                    .collect();
+                Ok(OsProxy::Manual {
+                    proxy: format!("{}:{}", host, get(&schema, "port")?),
+                    bypass,
+                })
+            }
+            "auto" => Ok(OsProxy::Auto(Url::parse(&unquote(get(
+                SCHEMA,
+                "autoconfig-url",
+            )?))?)),
+            _ => Ok(env_proxy()),
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod platform {
+    use super::{cmd, run, CommandLine, OsProxy, ProxyKind};
+    use std::net::SocketAddr;
+    use url::Url;
+
+    /// networksetup options names (get, set, set state) of each proxy kind
+    fn proxies(kind: ProxyKind) -> &'static [(&'static str, &'static str, &'static str)] {
+        match kind {
+            ProxyKind::Socks5 => &[(
+                "-getsocksfirewallproxy",
+                "-setsocksfirewallproxy",
+                "-setsocksfirewallproxystate",
+            )],
+            ProxyKind::Http => &[
+                ("-getwebproxy", "-setwebproxy", "-setwebproxystate"),
+                (
+                    "-getsecurewebproxy",
+                    "-setsecurewebproxy",
+                    "-setsecurewebproxystate",
+                ),
+            ],
+        }
+    }
+
+    fn network_services() -> anyhow::Result<Vec<String>> {
+        let output = run(&cmd(&["networksetup", "-listallnetworkservices"]))?;
+        // First line is a notice, and disabled services are prefixed by an asterisk
+        Ok(output
+            .lines()
+            .skip(1)
+            .filter(|line| !line.is_empty() && !line.starts_with('*'))
+            .map(str::to_string)
+            .collect())
+    }
+
+    pub fn snapshot(kind: ProxyKind) -> anyhow::Result<Vec<CommandLine>> {
+        let mut restore = vec![];
+        for service in network_services()? {
+            for (get, set, set_state) in proxies(kind) {
+                let output = run(&cmd(&["networksetup", get, &service]))?;
+                let field = |name: &str| {
+                    output
+                        .lines()
+                        .find_map(|line| line.strip_prefix(name))
+                        .map(|v| v.trim().to_string())
+                        .unwrap_or_default()
+                };
+                let (enabled, server, port) = (field("Enabled:"), field("Server:"), field("Port:"));
+                if !server.is_empty() {
+                    restore.push(cmd(&["networksetup", set, &service, &server, &port]));
+                }
+                let state = if enabled == "Yes" { "on" } else { "off" };
+                restore.push(cmd(&["networksetup", set_state, &service, state]));
+            }
+        }
+        Ok(restore)
+    }
+
+    pub fn configure(kind: ProxyKind, addr: SocketAddr) -> anyhow::Result<Vec<CommandLine>> {
+        let host = addr.ip().to_string();
+        let port = addr.port().to_string();
+        let mut cmds = vec![];
+        for service in network_services()? {
+            for (_, set, set_state) in proxies(kind) {
+                cmds.push(cmd(&["networksetup", set, &service, &host, &port]));
+                cmds.push(cmd(&["networksetup", set_state, &service, "on"]));
+            }
+        }
+        Ok(cmds)
+    }
+    pub fn outbound_proxy() -> anyhow::Result<OsProxy> {
+        // Settings of the primary network service, i.e:
+        //   HTTPSEnable : 1
+        //   HTTPSProxy : proxy.corp.example.com
+        //   ExceptionsList : <array> {
+        //     0 : *.local
+        //   }
+        let output = run(&cmd(&["scutil", "--proxy"]))?;
+        let field = |name: &str| {
+            output.lines().find_map(|line| {
+                let (key, value) = line.split_once(" : ")?;
+                (key.trim() == name).then(|| value.trim().to_string())
+            })
+        };
+        if field("ProxyAutoConfigEnable").as_deref() == Some("1") {
+            if let Some(url) = field("ProxyAutoConfigURLString") {
+                return Ok(OsProxy::Auto(Url::parse(&url)?));
+            }
+        }
+        if field("HTTPSEnable").as_deref() != Some("1") {
+            return Ok(OsProxy::Direct);
+        }
+        let (Some(host), Some(port)) = (field("HTTPSProxy"), field("HTTPSPort")) else {
+            return Ok(OsProxy::Direct);
+        };
+        let bypass = output
+            .lines()
+            .skip_while(|line| !line.contains("ExceptionsList"))
+            .skip(1)
+            .take_while(|line| !line.contains('}'))
+            .filter_map(|line| line.split_once(" : ").map(|(_, h)| h.trim().to_string()))
+            .collect();
+        Ok(OsProxy::Manual {
+            proxy: format!("{}:{}", host, port),
+            bypass,
+        })
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod platform {
+    use super::{cmd, run, CommandLine, OsProxy, ProxyKind};
+    use std::net::SocketAddr;
+    use url::Url;
+
+    const INTERNET_SETTINGS: &str =
+        r"HKCU\Software\Microsoft\Windows\CurrentVersion\Internet Settings";
+
+    /// Read a value of the WinINET settings, None if it does not exist
+    fn query(name: &str) -> Option<(String, String)> {
+        let output = run(&cmd(&["reg", "query", INTERNET_SETTINGS, "/v", name])).ok()?;
+        output.lines().find_map(|line| {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(n), Some(kind)) if n.eq_ignore_ascii_case(name) => {
+                    Some((kind.to_string(), parts.collect::<Vec<_>>().join(" ")))
+                }
+                _ => None,
+            }
+        })
+    }
+
+    fn restore_value(name: &str) -> CommandLine {
+        match query(name) {
+            Some((kind, value)) => {
+                let value = match value.strip_prefix("0x") {
+                    Some(hex) => u32::from_str_radix(hex, 16).unwrap_or(0).to_string(),
+                    None => value,
+                };
+                cmd(&[
+                    "reg",
+                    "add",
+                    INTERNET_SETTINGS,
+                    "/v",
+                    name,
+                    "/t",
+                    &kind,
+                    "/d",
+                    &value,
+                    "/f",
+                ])
+            }
+            None => cmd(&["reg", "delete", INTERNET_SETTINGS, "/v", name, "/f"]),
+        }
+    }
+
+    pub fn snapshot(_kind: ProxyKind) -> anyhow::Result<Vec<CommandLine>> {
+        Ok(vec![
+            restore_value("ProxyServer"),
+            restore_value("ProxyEnable"),
+        ])
+    }
+
+    pub fn configure(kind: ProxyKind, addr: SocketAddr) -> anyhow::Result<Vec<CommandLine>> {
+        let server = match kind {
+            ProxyKind::Socks5 => format!("socks={}", addr),
+            ProxyKind::Http => addr.to_string(),
+        };
+        Ok(vec![
+            cmd(&[
+                "reg",
+                "add",
+                INTERNET_SETTINGS,
+                "/v",
+                "ProxyServer",
+                "/t",
+                "REG_SZ",
+                "/d",
+                &server,
+                "/f",
+            ]),
+            cmd(&[
+                "reg",
+                "add",
+                INTERNET_SETTINGS,
+                "/v",
+                "ProxyEnable",
+                "/t",
+                "REG_DWORD",
+                "/d",
+                "1",
+                "/f",
+            ]),
+        ])
+    }
+    pub fn outbound_proxy() -> anyhow::Result<OsProxy> {
+        if let Some((_, url)) = query("AutoConfigURL") {
+            return Ok(OsProxy::Auto(Url::parse(&url)?));
+        }
+        if !query("ProxyEnable").is_some_and(|(_, enabled)| enabled == "0x1") {
+            return Ok(OsProxy::Direct);
+        }
+        let Some((_, server)) = query("ProxyServer") else {
+            return Ok(OsProxy::Direct);
+        };
+        // Either one proxy for every protocol, or one per protocol as in `http=proxy:80;https=proxy:443`
+        let proxy = if server.contains('=') {
+            let per_protocol = |protocol: &str| {
+                server.split(';').find_map(|entry| {
+                    let (name, proxy) = entry.split_once('=')?;
+                    (name.trim() == protocol).then(|| proxy.trim().to_string())
+                })
+            };
+            match per_protocol("https").or_else(|| per_protocol("http")) {
+                Some(proxy) => proxy,
+                None => return Ok(OsProxy::Direct),
+            }
+        } else {
+            server
+        };
+        let bypass = query("ProxyOverride")
+            .map(|(_, hosts)| hosts.split(';').map(|h| h.trim().to_string()).collect())
+            .unwrap_or_default();
+        Ok(OsProxy::Manual { proxy, bypass })
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+mod platform {
+    use super::{env_proxy, CommandLine, OsProxy, ProxyKind};
+    use std::net::SocketAddr;
+
+    pub fn snapshot(_kind: ProxyKind) -> anyhow::Result<Vec<CommandLine>> {
+        Err(anyhow::anyhow!(
+            "System proxy configuration is not supported on this platform"
+        ))
+    }
+
+    pub fn configure(_kind: ProxyKind, _addr: SocketAddr) -> anyhow::Result<Vec<CommandLine>> {
+        Err(anyhow::anyhow!(
+            "System proxy configuration is not supported on this platform"
+        ))
+    }
+
+    pub fn outbound_proxy() -> anyhow::Result<OsProxy> {
+        Ok(env_proxy())
+    }
+}
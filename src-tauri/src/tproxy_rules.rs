@@ -0,0 +1,362 @@
+use crate::client::platform::Capability;
+use anyhow::{anyhow, Context};
+use ipnet::IpNet;
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+const STATE_FILE: &str = "tproxy_rules.json";
+const TABLE: &str = "wstunnel_tproxy";
+
+/// Firewall and routing rules sending the traffic to a transparent proxy tunnel (`tproxy+tcp://` or
+/// `tproxy+udp://`), which the kernel only delivers to the tunnel once marked and routed to the local machine.
+/// Installed while the profile of the tunnel is connected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TproxyRules {
+    /// Address the tunnel listens on. An unspecified address takes the traffic to any address of its family,
+    /// `[::]` the one of both families
+    pub listen: SocketAddr,
+    #[serde(default = "default_true")]
+    pub tcp: bool,
+    #[serde(default)]
+    pub udp: bool,
+    /// Mark of the packets routed to the local machine, unused by any other rule of the system
+    pub mark: u32,
+    /// Policy routing table delivering the marked packets locally
+    #[serde(default = "default_table")]
+    pub table: u32,
+    /// Only the traffic to these networks goes through the tunnel, all of it when empty
+    #[serde(default)]
+    pub destinations: Vec<IpNet>,
+    /// Mark of the sockets of the app (`socket_so_mark` of the profile), for the traffic of this machine to go
+    /// through the tunnel as well without the connection to the server looping back into it. Only the traffic
+    /// routed through this machine is redirected when not set
+    pub bypass_mark: Option<u32>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_table() -> u32 {
+    100
+}
+
+impl TproxyRules {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.tcp && !self.udp {
+            return Err(anyhow!("Neither tcp nor udp is redirected"));
+        }
+        if self.mark == 0 {
+            return Err(anyhow!("The mark cannot be 0, unmarked packets have it"));
+        }
+        if self.bypass_mark == Some(self.mark) {
+            return Err(anyhow!(
+                "The mark of the app sockets must differ from the one of the redirected packets"
+            ));
+        }
+        // Tables 253 to 255 are the default, main and local ones of the kernel
+        if self.table == 0 || self.table >= 253 {
+            return Err(anyhow!(
+                "Routing table {} is reserved, pick one between 1 and 252",
+                self.table
+            ));
+        }
+        if self.listen.port() == 0 {
+            return Err(anyhow!("The tunnel must listen on a fixed port"));
+        }
+        // The traffic of every process would go through the tunnel, local networks and resolvers included
+        if self.bypass_mark.is_some() && self.destinations.is_empty() {
+            return Err(anyhow!(
+                "Redirecting the traffic of this machine needs destinations, all of it cannot go through the tunnel"
+            ));
+        }
+        if self.families().is_empty() {
+            return Err(anyhow!(
+                "No destination is of the address family the tunnel listens on"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Families redirected: the ones the tunnel listens on which have destinations
+    fn families(&self) -> Vec<Family> {
+        let listened = match self.listen.ip() {
+            IpAddr::V4(_) => vec![Family::V4],
+            IpAddr::V6(ip) if ip.is_unspecified() => vec![Family::V4, Family::V6],
+            IpAddr::V6(_) => vec![Family::V6],
+        };
+        listened
+            .into_iter()
+            .filter(|family| {
+                self.destinations.is_empty()
+                    || self.destinations.iter().any(|net| family.contains(net))
+            })
+            .collect()
+    }
+
+    /// nftables script replacing the table of the rules, declaring it first so deleting it never fails
+    fn nft_script(&self) -> String {
+        let protocols: Vec<&str> = [("tcp", self.tcp), ("udp", self.udp)]
+            .into_iter()
+            .filter_map(|(protocol, enabled)| enabled.then_some(protocol))
+            .collect();
+        let mut prerouting = vec!["fib daddr type local return".to_string()];
+        let mut output = vec![];
+        for family in self.families() {
+            let matched = family.matched(&self.destinations);
+            let target = match self.listen.ip() {
+                ip if ip.is_unspecified() => format!(":{}", self.listen.port()),
+                IpAddr::V4(ip) => format!("{}:{}", ip, self.listen.port()),
+                IpAddr::V6(ip) => format!("[{}]:{}", ip, self.listen.port()),
+            };
+            for protocol in &protocols {
+                prerouting.push(format!(
+                    "{} meta l4proto {} tproxy {} to {} meta mark set {} accept",
+                    matched,
+                    protocol,
+                    family.nft(),
+                    target,
+                    self.mark
+                ));
+                output.push(format!(
+                    "{} meta l4proto {} meta mark set {}",
+                    matched, protocol, self.mark
+                ));
+            }
+        }
+        let output = match self.bypass_mark {
+            Some(bypass) => format!(
+                "\n  chain output {{\n    type route hook output priority mangle; policy accept;\n    meta mark {} return\n    fib daddr type local return\n    {}\n  }}",
+                bypass,
+                output.join("\n    ")
+            ),
+            None => String::new(),
+        };
+        format!(
+            "table inet {table}\ndelete table inet {table}\ntable inet {table} {{\n  chain prerouting {{\n    type filter hook prerouting priority mangle; policy accept;\n    {prerouting}\n  }}{output}\n}}\n",
+            table = TABLE,
+            prerouting = prerouting.join("\n    "),
+            output = output
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn contains(self, net: &IpNet) -> bool {
+        matches!(
+            (self, net),
+            (Family::V4, IpNet::V4(_)) | (Family::V6, IpNet::V6(_))
+        )
+    }
+
+    fn nft(self) -> &'static str {
+        match self {
+            Family::V4 => "ip",
+            Family::V6 => "ip6",
+        }
+    }
+
+    /// `ip` and `ip -6`
+    fn ip_args(self) -> &'static str {
+        match self {
+            Family::V4 => "",
+            Family::V6 => " -6",
+        }
+    }
+
+    fn default_route(self) -> &'static str {
+        match self {
+            Family::V4 => "0.0.0.0/0",
+            Family::V6 => "::/0",
+        }
+    }
+
+    /// Match of the packets of the family to redirect
+    fn matched(self, destinations: &[IpNet]) -> String {
+        let nets: Vec<String> = destinations
+            .iter()
+            .filter(|net| self.contains(net))
+            .map(IpNet::to_string)
+            .collect();
+        if nets.is_empty() {
+            let nfproto = match self {
+                Family::V4 => "ipv4",
+                Family::V6 => "ipv6",
+            };
+            format!("meta nfproto {}", nfproto)
+        } else {
+            format!("{} daddr {{ {} }}", self.nft(), nets.join(", "))
+        }
+    }
+}
+
+/// Rules installed from the app, none once the table is gone, i.e: after a reboot
+pub async fn installed(data_dir: &Path) -> anyhow::Result<Option<TproxyRules>> {
+    let path = data_dir.join(STATE_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let rules = serde_json::from_slice(&std::fs::read(&path)?)
+        .with_context(|| "Invalid transparent proxy rules state")?;
+    // Listing the table needs privileges, without them the state is taken as is
+    if platform::table_listed(TABLE).await == Some(false) {
+        info!("Transparent proxy rules are no longer installed");
+        std::fs::remove_file(&path)?;
+        return Ok(None);
+    }
+    Ok(Some(rules))
+}
+
+/// Install the rules of a connected profile with administrator privileges, replacing the ones installed before
+pub async fn install(data_dir: &Path, rules: &TproxyRules) -> anyhow::Result<()> {
+    Capability::TransparentProxy.require()?;
+    rules.validate()?;
+    let previous = installed(data_dir).await?;
+    if previous.as_ref() == Some(rules) {
+        return Ok(());
+    }
+    let mut script = String::new();
+    if let Some(previous) = previous {
+        script.push_str(&routing_script(&previous, false));
+    }
+    script.push_str(&install_script(rules));
+    platform::run_elevated(&script).await?;
+    std::fs::create_dir_all(data_dir)?;
+    std::fs::write(data_dir.join(STATE_FILE), serde_json::to_vec(rules)?)
+        .with_context(|| "Cannot save transparent proxy rules state")?;
+    info!(
+        "Transparent proxy rules installed for {} with mark {}",
+        rules.listen, rules.mark
+    );
+    Ok(())
+}
+
+/// Remove the rules installed from the app
+pub async fn remove(data_dir: &Path) -> anyhow::Result<()> {
+    let Some(rules) = installed(data_dir).await? else {
+        return Ok(());
+    };
+    platform::run_elevated(&remove_script(&rules)).await?;
+    std::fs::remove_file(data_dir.join(STATE_FILE))?;
+    info!("Transparent proxy rules removed");
+    Ok(())
+}
+
+/// Policy routing delivering the marked packets to the local machine, added or deleted.
+/// Deleting ignores the rules already gone, the script is run again after a partial failure
+fn routing_script(rules: &TproxyRules, add: bool) -> String {
+    let mut script = String::new();
+    for family in rules.families() {
+        let ip = format!("ip{}", family.ip_args());
+        script.push_str(&format!(
+            "{ip} rule del fwmark {mark} lookup {table} 2>/dev/null || true\n{ip} route del local {route} dev lo table {table} 2>/dev/null || true\n",
+            ip = ip,
+            mark = rules.mark,
+            table = rules.table,
+            route = family.default_route()
+        ));
+        if add {
+            script.push_str(&format!(
+                "{ip} rule add fwmark {mark} lookup {table}\n{ip} route add local {route} dev lo table {table}\n",
+                ip = ip,
+                mark = rules.mark,
+                table = rules.table,
+                route = family.default_route()
+            ));
+        }
+    }
+    script
+}
+
+fn install_script(rules: &TproxyRules) -> String {
+    format!(
+        "nft -f - <<'EOF'\n{}EOF\n{}",
+        rules.nft_script(),
+        routing_script(rules, true)
+    )
+}
+
+fn remove_script(rules: &TproxyRules) -> String {
+    format!(
+        "nft delete table inet {} 2>/dev/null || true\n{}",
+        TABLE,
+        routing_script(rules, false)
+    )
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use anyhow::{anyhow, Context};
+    use tokio::process::Command;
+
+    const PKEXEC: &str = "/usr/bin/pkexec";
+
+    /// Whether the nftables table exists, None when it cannot be listed
+    pub async fn table_listed(table: &str) -> Option<bool> {
+        let output = Command::new("nft")
+            .args(["list", "table", "inet", table])
+            .output()
+            .await
+            .ok()?;
+        if output.status.success() {
+            return Some(true);
+        }
+        // nft does not translate its errors
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        stderr
+            .contains("No such file or directory")
+            .then_some(false)
+    }
+
+    /// Run the script as root, polkit asking for an administrator unless the app already runs as root
+    pub async fn run_elevated(script: &str) -> anyhow::Result<()> {
+        let script = format!("set -e\n{}", script);
+        // SAFETY: geteuid cannot fail
+        let is_root = unsafe { libc::geteuid() } == 0;
+        let mut command = if is_root {
+            Command::new("sh")
+        } else {
+            let mut pkexec = Command::new(PKEXEC);
+            pkexec.arg("/bin/sh");
+            pkexec
+        };
+        let output = command
+            .arg("-c")
+            .arg(&script)
+            .output()
+            .await
+            .with_context(|| "Cannot execute the transparent proxy setup")?;
+        match output.status.code() {
+            Some(0) => Ok(()),
+            Some(126) | Some(127) if !is_root => {
+                Err(anyhow!("The administrator authentication was cancelled"))
+            }
+            _ => Err(anyhow!(
+                "Cannot set up the transparent proxy rules: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use crate::client::platform::Capability;
+
+    pub async fn table_listed(_table: &str) -> Option<bool> {
+        None
+    }
+
+    pub async fn run_elevated(_script: &str) -> anyhow::Result<()> {
+        Err(Capability::TransparentProxy.unavailable())
+    }
+}